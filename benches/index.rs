@@ -0,0 +1,53 @@
+//! Benchmarks for reading and writing large indexes
+#![feature(test)]
+extern crate test;
+
+use rgit::index::{self, Index, IndexEntry, IndexMeta};
+use rgit::objects::Id;
+use test::Bencher;
+
+/// Makes an index the size of a large monorepo checkout
+fn big_index(entries: u32) -> Index {
+    (0..entries)
+        .map(|i| {
+            let name = format!("src/module{:03}/file{:06}.rs", i % 1000, i);
+            let mut id = [0u8; 20];
+            id[..4].copy_from_slice(&i.to_be_bytes());
+            IndexEntry {
+                meta: IndexMeta {
+                    ctime: i.into(),
+                    ctime_ns: 0.into(),
+                    mtime: i.into(),
+                    mtime_ns: 0.into(),
+                    dev: 0.into(),
+                    ino: i.into(),
+                    mode: 0o100644.into(),
+                    uid: 1000.into(),
+                    gid: 1000.into(),
+                    size: 1024.into(),
+                    id: Id::from_bytes(id),
+                    flags: (name.len() as u16).into(),
+                },
+                name: name.into(),
+            }
+        })
+        .collect()
+}
+
+#[bench]
+fn parse_100k(b: &mut Bencher) {
+    let mut buf = Vec::new();
+    index::write_to_file(&big_index(100_000), &mut buf).unwrap();
+    b.bytes = buf.len() as u64;
+    b.iter(|| index::parse_bytes(&buf).unwrap());
+}
+
+#[bench]
+fn write_100k(b: &mut Bencher) {
+    let idx = big_index(100_000);
+    b.iter(|| {
+        let mut buf = Vec::new();
+        index::write_to_file(&idx, &mut buf).unwrap();
+        buf
+    });
+}
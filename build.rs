@@ -0,0 +1,17 @@
+//! Build script. Generates the C header for the `ffi` feature.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+#[cfg(feature = "ffi")]
+fn generate_header() {
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+
+    cbindgen::generate(&crate_dir)
+        .expect("failed to generate C bindings")
+        .write_to_file(std::path::Path::new(&crate_dir).join("include/rgit.h"));
+}
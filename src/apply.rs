@@ -0,0 +1,850 @@
+//! Applying patches in the unified diff format, as `git apply` does. Patches
+//! made by git, with `diff --git` headers giving modes and whether files are
+//! new or deleted, are understood as well as plain `---`/`+++` ones.
+//!
+//! Hunks are applied where their header says unless the file has moved on
+//! since the patch was made, in which case the nearest place where all of the
+//! hunk's context and removed lines match is used. Unlike GNU patch there is
+//! no fuzz: every line of context has to match.
+use anyhow::{Context, Error, Result};
+use bstr::{BString, ByteSlice, ByteVec};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::io;
+use thiserror::Error;
+
+use crate::checkout::{self, PathProtection};
+use crate::diff;
+use crate::index::{self, Index, IndexEntry, IndexMeta};
+use crate::objects::{Blob, FileMode, Repo};
+use crate::util::to_platform_path;
+use crate::vfs::FileType;
+
+/// Errors that can arise while reading or applying a patch
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ApplyError {
+    /// The patch isn't in a form we understand
+    #[error("Corrupt patch at line {0}")]
+    Corrupt(usize),
+
+    /// A hunk's context doesn't match the file anywhere
+    #[error("Patch does not apply to {0}")]
+    DoesNotApply(BString),
+
+    /// A file being changed or deleted isn't there
+    #[error("{0} does not exist")]
+    Missing(BString),
+
+    /// A file being created is already there
+    #[error("{0} already exists")]
+    AlreadyExists(BString),
+
+    /// A file being changed has changes in the working tree that would be
+    /// overwritten
+    #[error("{0} has local changes")]
+    LocalChanges(BString),
+
+    /// Binary patches can't be applied yet
+    #[error("Cannot apply binary patch to {0}")]
+    Binary(BString),
+
+    /// A path in the patch could escape the worktree or write into `.git`
+    #[error("Refusing to apply patch to unsafe path {0:?}")]
+    UnsafePath(BString),
+}
+
+/// A line of a hunk, with its line ending
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HunkLine {
+    /// A line that is the same on both sides
+    Context(BString),
+    /// A line that is removed
+    Delete(BString),
+    /// A line that is added
+    Insert(BString),
+}
+
+/// A hunk of a patch to one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hunk {
+    /// Line the hunk starts at in the old file, from 1. Hunks which contain
+    /// no old lines give the line before them.
+    pub old_start: usize,
+    /// Line the hunk starts at in the new file
+    pub new_start: usize,
+    /// The lines of the hunk
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// The lines the hunk expects to find in the old file
+    fn old_lines(&self) -> Vec<&[u8]> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Delete(text) => Some(text.as_slice()),
+                HunkLine::Insert(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// The changes to one file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePatch {
+    /// Path of the file before the patch, or None if it creates the file
+    pub old_path: Option<BString>,
+    /// Path of the file after the patch, or None if it deletes the file.
+    /// Differs from `old_path` if the file was renamed.
+    pub new_path: Option<BString>,
+    /// Mode of the file before, if the patch says
+    pub old_mode: Option<FileMode>,
+    /// Mode of the file after, if the patch says
+    pub new_mode: Option<FileMode>,
+    /// The changes to the file's content
+    pub hunks: Vec<Hunk>,
+    /// The patch changes binary content, which we can't apply
+    pub binary: bool,
+}
+
+/// Takes the leading directory off a path in a patch, as `-p1` does, and
+/// anything after a tab such as the timestamps in traditional diffs. Gives
+/// None for `/dev/null`.
+fn patch_path(name: &[u8]) -> Option<BString> {
+    let name = name.split_str("\t").next().unwrap_or(name);
+    if name == b"/dev/null" {
+        return None;
+    }
+    let name = match name.find_byte(b'/') {
+        Some(slash) => &name[slash + 1..],
+        None => name,
+    };
+    Some(name.into())
+}
+
+/// Gets the path out of a `diff --git a/path b/path` line. Which space
+/// separates the two names is ambiguous, but for all but renames they're the
+/// same so we can tell by the length.
+fn git_header_path(rest: &[u8]) -> Option<BString> {
+    if rest.len() < 5 || rest.len() % 2 == 0 {
+        return None;
+    }
+    let name_len = (rest.len() - 5) / 2;
+    let (a, b) = rest.split_at(name_len + 2);
+    if !a.starts_with(b"a/") || !b.starts_with(b" b/") || a[2..] != b[3..] {
+        return None;
+    }
+    Some(a[2..].into())
+}
+
+fn parse_mode(mode: &[u8], line: usize) -> Result<FileMode, ApplyError> {
+    std::str::from_utf8(mode)
+        .ok()
+        .and_then(|mode| u32::from_str_radix(mode.trim(), 8).ok())
+        .and_then(|mode| FileMode::try_from(mode).ok())
+        .ok_or(ApplyError::Corrupt(line))
+}
+
+/// Parses a number in a hunk header
+fn parse_count(s: &[u8], line: usize) -> Result<usize, ApplyError> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(ApplyError::Corrupt(line))
+}
+
+/// Parses `start[,count]` from a hunk header, where the count defaults to 1
+fn parse_range(range: &[u8], line: usize) -> Result<(usize, usize), ApplyError> {
+    match range.find_byte(b',') {
+        Some(comma) => Ok((
+            parse_count(&range[..comma], line)?,
+            parse_count(&range[comma + 1..], line)?,
+        )),
+        None => Ok((parse_count(range, line)?, 1)),
+    }
+}
+
+/// Parses the hunk starting at `lines[start]`, which is its `@@` line.
+/// Returns the hunk and the index of the line after it.
+fn parse_hunk(lines: &[&[u8]], start: usize) -> Result<(Hunk, usize), ApplyError> {
+    let corrupt = |n: usize| ApplyError::Corrupt(n + 1);
+    // @@ -1,2 +1,3 @@ optional function name
+    let header = lines[start];
+    let mut parts = header.split_str(" ");
+    let (old, new) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(b"@@"), Some(old), Some(new), Some(end)) if end.starts_with(b"@@") => (old, new),
+        _ => return Err(corrupt(start)),
+    };
+    if !old.starts_with(b"-") || !new.starts_with(b"+") {
+        return Err(corrupt(start));
+    }
+    let (old_start, mut old_left) = parse_range(&old[1..], start + 1)?;
+    let (new_start, mut new_left) = parse_range(&new[1..], start + 1)?;
+
+    let mut hunk = Hunk {
+        old_start,
+        new_start,
+        lines: Vec::new(),
+    };
+    let mut n = start + 1;
+    while old_left > 0 || new_left > 0 {
+        let line = *lines.get(n).ok_or_else(|| corrupt(n))?;
+        let (kind, text) = match line.first() {
+            Some(&kind) => (kind, &line[1..]),
+            None => return Err(corrupt(n)),
+        };
+        let text = BString::from(text);
+        match kind {
+            // mailers like to strip the space off empty context lines
+            b' ' | b'\n' if old_left > 0 && new_left > 0 => {
+                let text = if kind == b'\n' { "\n".into() } else { text };
+                hunk.lines.push(HunkLine::Context(text));
+                old_left -= 1;
+                new_left -= 1;
+            }
+            b'-' if old_left > 0 => {
+                hunk.lines.push(HunkLine::Delete(text));
+                old_left -= 1;
+            }
+            b'+' if new_left > 0 => {
+                hunk.lines.push(HunkLine::Insert(text));
+                new_left -= 1;
+            }
+            b'\\' => strip_newline(&mut hunk, n)?,
+            _ => return Err(corrupt(n)),
+        }
+        n += 1;
+    }
+    // the last line may be missing its newline too
+    if lines.get(n).map_or(false, |line| line.starts_with(b"\\")) {
+        strip_newline(&mut hunk, n)?;
+        n += 1;
+    }
+    Ok((hunk, n))
+}
+
+/// Handles `\ No newline at end of file`, which applies to the line before
+fn strip_newline(hunk: &mut Hunk, n: usize) -> Result<(), ApplyError> {
+    let text = match hunk.lines.last_mut() {
+        Some(HunkLine::Context(text))
+        | Some(HunkLine::Delete(text))
+        | Some(HunkLine::Insert(text)) => text,
+        None => return Err(ApplyError::Corrupt(n + 1)),
+    };
+    if text.ends_with(b"\n") {
+        text.pop();
+    }
+    Ok(())
+}
+
+/// Parses the extended headers after a `diff --git` line, filling in what
+/// they say about the file. Returns the index of the first line after them.
+fn parse_git_headers(
+    lines: &[&[u8]],
+    mut n: usize,
+    patch: &mut FilePatch,
+) -> Result<usize, ApplyError> {
+    while let Some(&line) = lines.get(n) {
+        let line_no = n + 1;
+        let line = line.trim_end_with(|c| c == '\n' || c == '\r');
+        let value = |prefix: &str| {
+            if line.starts_with(prefix.as_bytes()) {
+                Some(&line[prefix.len()..])
+            } else {
+                None
+            }
+        };
+        if let Some(mode) = value("old mode ") {
+            patch.old_mode = Some(parse_mode(mode, line_no)?);
+        } else if let Some(mode) = value("new mode ") {
+            patch.new_mode = Some(parse_mode(mode, line_no)?);
+        } else if let Some(mode) = value("new file mode ") {
+            patch.new_mode = Some(parse_mode(mode, line_no)?);
+            patch.old_path = None;
+        } else if let Some(mode) = value("deleted file mode ") {
+            patch.old_mode = Some(parse_mode(mode, line_no)?);
+            patch.new_path = None;
+        } else if let Some(path) = value("rename from ") {
+            patch.old_path = Some(path.into());
+        } else if let Some(path) = value("rename to ") {
+            patch.new_path = Some(path.into());
+        } else if line.starts_with(b"index ")
+            || line.starts_with(b"similarity index ")
+            || line.starts_with(b"dissimilarity index ")
+        {
+            // nothing we need
+        } else if line.starts_with(b"Binary files ") || line == b"GIT binary patch" {
+            patch.binary = true;
+            // skip the binary data
+            n += 1;
+            while lines
+                .get(n)
+                .map_or(false, |l| !l.starts_with(b"diff --git "))
+            {
+                n += 1;
+            }
+            return Ok(n);
+        } else {
+            return Ok(n);
+        }
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Parses a patch, which may contain changes to any number of files. Anything
+/// that isn't part of a diff, such as a commit message, is skipped.
+pub fn parse(text: &[u8]) -> Result<Vec<FilePatch>, ApplyError> {
+    let lines = diff::lines(text);
+    let mut patches = Vec::new();
+    let mut n = 0;
+    while n < lines.len() {
+        let line = lines[n];
+        let is_git = line.starts_with(b"diff --git ");
+        let is_traditional =
+            line.starts_with(b"--- ") && lines.get(n + 1).map_or(false, |l| l.starts_with(b"+++ "));
+        if !is_git && !is_traditional {
+            n += 1;
+            continue;
+        }
+
+        let mut patch = FilePatch {
+            old_path: None,
+            new_path: None,
+            old_mode: None,
+            new_mode: None,
+            hunks: Vec::new(),
+            binary: false,
+        };
+        if is_git {
+            let rest = line[b"diff --git ".len()..].trim_end_with(|c| c == '\n');
+            let path = git_header_path(rest);
+            patch.old_path = path.clone();
+            patch.new_path = path;
+            n = parse_git_headers(&lines, n + 1, &mut patch)?;
+        }
+
+        let at_names = lines.get(n).map_or(false, |l| l.starts_with(b"--- "))
+            && lines.get(n + 1).map_or(false, |l| l.starts_with(b"+++ "));
+        if at_names {
+            let name = |line: &[u8]| patch_path(line[4..].trim_end_with(|c| c == '\n'));
+            patch.old_path = name(lines[n]);
+            patch.new_path = name(lines[n + 1]);
+            n += 2;
+        }
+        if patch.old_path.is_none() && patch.new_path.is_none() {
+            return Err(ApplyError::Corrupt(n));
+        }
+
+        while lines.get(n).map_or(false, |l| l.starts_with(b"@@ ")) {
+            let (hunk, next) = parse_hunk(&lines, n)?;
+            patch.hunks.push(hunk);
+            n = next;
+        }
+        patches.push(patch);
+    }
+    Ok(patches)
+}
+
+/// Finds where `expected` lines are in `lines`, looking outwards from `near`
+/// and no earlier than `min`
+fn find_lines(lines: &[&[u8]], expected: &[&[u8]], min: usize, near: usize) -> Option<usize> {
+    let max = lines.len().checked_sub(expected.len())?;
+    let fits = |at: usize| at >= min && at <= max && lines[at..at + expected.len()] == *expected;
+    (0..=lines.len()).find_map(|distance| {
+        let later = near + distance;
+        if fits(later) {
+            return Some(later);
+        }
+        near.checked_sub(distance).filter(|&earlier| fits(earlier))
+    })
+}
+
+/// Applies hunks to a file's content. Returns None if any of them don't fit.
+pub fn apply_hunks(old: &[u8], hunks: &[Hunk]) -> Option<Vec<u8>> {
+    let lines = diff::lines(old);
+    let mut out = Vec::with_capacity(old.len());
+    let mut pos = 0;
+    // how far the last hunk was from where it said it would be, which later
+    // hunks are likely to be too
+    let mut offset: isize = 0;
+    for hunk in hunks {
+        let expected = hunk.old_lines();
+        let stated = if expected.is_empty() {
+            hunk.old_start
+        } else {
+            hunk.old_start.saturating_sub(1)
+        };
+        let near = (stated as isize + offset).max(pos as isize) as usize;
+        let at = find_lines(&lines, &expected, pos, near)?;
+        offset = at as isize - stated as isize;
+
+        for line in &lines[pos..at] {
+            out.extend_from_slice(line);
+        }
+        for line in &hunk.lines {
+            match line {
+                HunkLine::Context(text) | HunkLine::Insert(text) => out.extend_from_slice(text),
+                HunkLine::Delete(_) => (),
+            }
+        }
+        pos = at + expected.len();
+    }
+    for line in &lines[pos..] {
+        out.extend_from_slice(line);
+    }
+    Some(out)
+}
+
+/// What patches are applied to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyTarget {
+    /// The files in the working tree, as plain `git apply` does
+    Worktree,
+    /// The index, with the working tree updated to match, as `git apply
+    /// --index` does. Files being changed mustn't have changes in the working
+    /// tree.
+    IndexAndWorktree,
+    /// Only the index, as `git apply --cached` does
+    Index,
+}
+
+/// What a path is made by the patches: its content and mode, or None if it is
+/// deleted
+pub type Applied = BTreeMap<BString, Option<(Vec<u8>, FileMode)>>;
+
+impl FilePatch {
+    /// The patch that undoes this one, as `git apply --reverse` applies
+    pub fn reverse(&self) -> FilePatch {
+        let hunks = self
+            .hunks
+            .iter()
+            .map(|hunk| Hunk {
+                old_start: hunk.new_start,
+                new_start: hunk.old_start,
+                lines: hunk
+                    .lines
+                    .iter()
+                    .map(|line| match line {
+                        HunkLine::Context(text) => HunkLine::Context(text.clone()),
+                        HunkLine::Delete(text) => HunkLine::Insert(text.clone()),
+                        HunkLine::Insert(text) => HunkLine::Delete(text.clone()),
+                    })
+                    .collect(),
+            })
+            .collect();
+        FilePatch {
+            old_path: self.new_path.clone(),
+            new_path: self.old_path.clone(),
+            old_mode: self.new_mode,
+            new_mode: self.old_mode,
+            hunks,
+            binary: self.binary,
+        }
+    }
+}
+
+/// Reads a file in the working tree as git would store it, which for a
+/// symlink is where it points. Gives None if there's no file there.
+fn read_worktree_file(path: &[u8], repo: &Repo) -> Result<Option<(Vec<u8>, FileMode)>> {
+    let file = repo.tree_root().join(to_platform_path(path));
+    let vfs = repo.vfs();
+    let meta = match vfs.symlink_metadata(&file) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(match meta.file_type {
+        FileType::Symlink => {
+            let target = vfs.read_link(&file)?;
+            Some((
+                Vec::from_path_lossy(&target).into_owned(),
+                FileMode::Symlink,
+            ))
+        }
+        FileType::File if meta.executable => Some((vfs.read(&file)?, FileMode::Executable)),
+        FileType::File => Some((vfs.read(&file)?, FileMode::Regular)),
+        FileType::Dir => None,
+    })
+}
+
+/// Works out what applying patches to `target` would make each of the files
+/// they touch, without changing anything. Fails if any of them don't apply.
+pub fn check(
+    patches: &[FilePatch],
+    target: ApplyTarget,
+    index: &Index,
+    repo: &Repo,
+) -> Result<Applied> {
+    let protect = PathProtection::from_config(&repo.config()?)?;
+    // what each path will be afterwards, which later patches build on
+    let mut results = Applied::new();
+
+    for patch in patches {
+        for path in patch.old_path.iter().chain(&patch.new_path) {
+            if !checkout::verify_path(path, protect) {
+                return Err(Error::new(ApplyError::UnsafePath(path.clone())));
+            }
+        }
+        let current = |path: &BString| -> Result<Option<(Vec<u8>, FileMode)>> {
+            if let Some(result) = results.get(path) {
+                return Ok(result.clone());
+            }
+            if target == ApplyTarget::Worktree {
+                return read_worktree_file(path, repo);
+            }
+            let entry = index
+                .binary_search_by(|IndexEntry { name, .. }| name.cmp(path))
+                .ok()
+                .map(|found| &index[found]);
+            match entry {
+                Some(entry) => {
+                    if target == ApplyTarget::IndexAndWorktree && !entry.is_same_as_tree(repo)? {
+                        return Err(Error::new(ApplyError::LocalChanges(path.clone())));
+                    }
+                    let content = repo.read_object(&entry.meta.id)?.1;
+                    Ok(Some((content, entry.meta.file_mode())))
+                }
+                None => Ok(None),
+            }
+        };
+
+        let old = match &patch.old_path {
+            Some(path) => Some(current(path)?.ok_or_else(|| ApplyError::Missing(path.clone()))?),
+            None => None,
+        };
+        if let Some(path) = &patch.new_path {
+            if patch.old_path.as_ref() != Some(path) && current(path)?.is_some() {
+                return Err(Error::new(ApplyError::AlreadyExists(path.clone())));
+            }
+        }
+
+        let new_path = match &patch.new_path {
+            Some(path) => path,
+            None => {
+                results.insert(patch.old_path.clone().unwrap(), None);
+                continue;
+            }
+        };
+        if patch.binary {
+            return Err(Error::new(ApplyError::Binary(new_path.clone())));
+        }
+        let (content, old_mode) = match old {
+            Some((content, mode)) => (content, Some(mode)),
+            None => (Vec::new(), None),
+        };
+        let content = apply_hunks(&content, &patch.hunks)
+            .ok_or_else(|| ApplyError::DoesNotApply(new_path.clone()))?;
+        let mode = patch.new_mode.or(old_mode).unwrap_or(FileMode::Regular);
+
+        if let Some(old_path) = &patch.old_path {
+            if old_path != new_path {
+                results.insert(old_path.clone(), None);
+            }
+        }
+        results.insert(new_path.clone(), Some((content, mode)));
+    }
+    Ok(results)
+}
+
+/// Applies patches to `target`. Everything is checked before anything is
+/// written, so either every patch applies or nothing changes. The index is
+/// updated in memory unless only the working tree is the target.
+pub fn apply(
+    patches: &[FilePatch],
+    target: ApplyTarget,
+    index: &mut Index,
+    repo: &Repo,
+) -> Result<()> {
+    let results = check(patches, target, index, repo)?;
+    let tree_root = repo.tree_root();
+    for (path, result) in results {
+        let file = tree_root.join(to_platform_path(&path));
+        match result {
+            Some((content, mode)) => {
+                if target == ApplyTarget::Worktree {
+                    make_parent_dirs(&path, repo)?;
+                    checkout::write_content(&path, &content, mode, repo)?;
+                    continue;
+                }
+                let id = repo.store(&Blob::new(content))?;
+                let entry = if target == ApplyTarget::Index {
+                    IndexEntry {
+                        meta: IndexMeta::for_blob(&path, id, mode),
+                        name: path,
+                    }
+                } else {
+                    make_parent_dirs(&path, repo)?;
+                    checkout::write_file(&path, id, mode, repo)?
+                };
+                index::insert_entry(index, entry);
+            }
+            None => {
+                if target != ApplyTarget::Index {
+                    repo.vfs()
+                        .remove_file(&file)
+                        .or_else(|e| match e.kind() {
+                            io::ErrorKind::NotFound => Ok(()),
+                            _ => Err(e),
+                        })
+                        .with_context(|| format!("failed to remove {}", file.display()))?;
+                }
+                if target != ApplyTarget::Worktree {
+                    index::remove_entry(index, &path);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Makes the directories leading up to a file in the working tree
+fn make_parent_dirs(path: &[u8], repo: &Repo) -> Result<()> {
+    if let Some(dir) = to_platform_path(path).parent() {
+        checkout::remove_leading_symlinks(dir, repo)?;
+        repo.vfs().create_dir_all(&repo.tree_root().join(dir))?;
+    }
+    Ok(())
+}
+
+/// Applies patches to the index and the working tree, as `git apply --index`
+/// does. Everything is checked before anything is written, so either every
+/// patch applies or nothing changes.
+pub fn apply_to_index(patches: &[FilePatch], index: &mut Index, repo: &Repo) -> Result<()> {
+    apply(patches, ApplyTarget::IndexAndWorktree, index, repo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply, apply_hunks, apply_to_index, check, parse, ApplyError, ApplyTarget, FilePatch,
+        HunkLine,
+    };
+    use crate::index::{self, IndexEntry};
+    use crate::objects::{FileMode, Repo};
+    use crate::vfs::{MemoryFs, Vfs};
+    use bstr::BString;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    const PATCH: &[u8] = b"Some message
+---
+ hello | 3 ++-
+ 1 file changed
+
+diff --git a/hello b/hello
+index ce01362..3c6e3a5 100644
+--- a/hello
++++ b/hello
+@@ -1,3 +1,4 @@
+ one
+-two
++2
++2.5
+ three
+\\ No newline at end of file
+diff --git a/new b/new
+new file mode 100755
+index 0000000..3b18e51
+--- /dev/null
++++ b/new
+@@ -0,0 +1 @@
++hello world
+diff --git a/script b/script
+old mode 100644
+new mode 100755
+-- 
+rgit 0.1.0
+";
+
+    #[test]
+    fn test_parse() {
+        let patches = parse(PATCH).unwrap();
+        assert_eq!(patches.len(), 3);
+        assert_eq!(patches[0].old_path, Some("hello".into()));
+        assert_eq!(
+            patches[0].hunks[0].lines,
+            vec![
+                HunkLine::Context("one\n".into()),
+                HunkLine::Delete("two\n".into()),
+                HunkLine::Insert("2\n".into()),
+                HunkLine::Insert("2.5\n".into()),
+                HunkLine::Context("three".into()),
+            ]
+        );
+        assert_eq!(
+            patches[1],
+            FilePatch {
+                old_path: None,
+                new_path: Some("new".into()),
+                old_mode: None,
+                new_mode: Some(FileMode::Executable),
+                hunks: patches[1].hunks.clone(),
+                binary: false,
+            }
+        );
+        assert_eq!(patches[2].new_mode, Some(FileMode::Executable));
+        assert!(patches[2].hunks.is_empty());
+
+        assert_eq!(
+            parse(b"--- a/x\n+++ b/x\n@@ -1 +1 @@\n-x\n").unwrap_err(),
+            ApplyError::Corrupt(5)
+        );
+    }
+
+    #[test]
+    fn test_apply_hunks() {
+        let patches = parse(PATCH).unwrap();
+        let hunks = &patches[0].hunks;
+        assert_eq!(
+            apply_hunks(b"one\ntwo\nthree", hunks).unwrap(),
+            b"one\n2\n2.5\nthree"
+        );
+        // lines were added above, so the hunk has moved
+        assert_eq!(
+            apply_hunks(b"zero\none\ntwo\nthree", hunks).unwrap(),
+            b"zero\none\n2\n2.5\nthree"
+        );
+        assert_eq!(apply_hunks(b"one\ntwo\nthree\n", hunks), None);
+        assert_eq!(
+            apply_hunks(b"", &patches[1].hunks).unwrap(),
+            b"hello world\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_to_index() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        fs.write(Path::new("/repo/hello"), b"one\ntwo\nthree")
+            .unwrap();
+        fs.write(Path::new("/repo/script"), b"#!/bin/sh\n").unwrap();
+        let mut idx = repo.index().unwrap();
+        for name in &["hello", "script"] {
+            let meta = index::IndexMeta::new_from_file(name.as_bytes(), &repo).unwrap();
+            index::insert_entry(
+                &mut idx,
+                IndexEntry {
+                    name: (*name).into(),
+                    meta,
+                },
+            );
+        }
+
+        let patches = parse(PATCH).unwrap();
+        apply_to_index(&patches, &mut idx, &repo).unwrap();
+        assert_eq!(
+            fs.read(Path::new("/repo/hello")).unwrap(),
+            b"one\n2\n2.5\nthree"
+        );
+        assert_eq!(fs.read(Path::new("/repo/new")).unwrap(), b"hello world\n");
+        let names: Vec<_> = idx.iter().map(|e| e.name.to_string()).collect();
+        assert_eq!(names, vec!["hello", "new", "script"]);
+        assert_eq!(idx[2].meta.file_mode(), FileMode::Executable);
+
+        // now the new file exists, so creating it again fails and nothing is
+        // written
+        let err = apply_to_index(&patches[1..], &mut idx, &repo).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ApplyError>(),
+            Some(&ApplyError::AlreadyExists("new".into()))
+        );
+
+        let deletion = parse(b"diff --git a/../x b/../x\ndeleted file mode 100644\n").unwrap();
+        assert!(apply_to_index(&deletion, &mut idx, &repo).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let patches = parse(PATCH).unwrap();
+        let reversed = patches[0].reverse();
+        assert_eq!(
+            reversed.hunks[0].lines,
+            vec![
+                HunkLine::Context("one\n".into()),
+                HunkLine::Insert("two\n".into()),
+                HunkLine::Delete("2\n".into()),
+                HunkLine::Delete("2.5\n".into()),
+                HunkLine::Context("three".into()),
+            ]
+        );
+        assert_eq!(
+            apply_hunks(b"one\n2\n2.5\nthree", &reversed.hunks).unwrap(),
+            b"one\ntwo\nthree"
+        );
+        let deletion = patches[1].reverse();
+        assert_eq!(deletion.old_path, Some("new".into()));
+        assert_eq!(deletion.new_path, None);
+        assert_eq!(deletion.reverse(), patches[1]);
+    }
+
+    #[test]
+    fn test_apply_targets() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        fs.write(Path::new("/repo/hello"), b"one\ntwo\nthree")
+            .unwrap();
+        fs.write(Path::new("/repo/script"), b"#!/bin/sh\n").unwrap();
+        let mut idx = repo.index().unwrap();
+        let patches = parse(PATCH).unwrap();
+
+        // the working tree alone needs nothing in the index
+        let applied = check(&patches, ApplyTarget::Worktree, &idx, &repo).unwrap();
+        assert_eq!(
+            applied.get(&BString::from("new")),
+            Some(&Some((b"hello world\n".to_vec(), FileMode::Executable)))
+        );
+        assert_eq!(applied.len(), 3);
+        assert!(!fs.exists(Path::new("/repo/new")));
+        apply(&patches, ApplyTarget::Worktree, &mut idx, &repo).unwrap();
+        assert_eq!(
+            fs.read(Path::new("/repo/hello")).unwrap(),
+            b"one\n2\n2.5\nthree"
+        );
+        assert!(fs.metadata(Path::new("/repo/script")).unwrap().executable);
+        assert!(fs.metadata(Path::new("/repo/new")).unwrap().executable);
+        assert_eq!(idx.len(), 0);
+
+        // and undoing it puts everything back
+        let reversed = patches
+            .iter()
+            .rev()
+            .map(FilePatch::reverse)
+            .collect::<Vec<_>>();
+        apply(&reversed, ApplyTarget::Worktree, &mut idx, &repo).unwrap();
+        assert_eq!(
+            fs.read(Path::new("/repo/hello")).unwrap(),
+            b"one\ntwo\nthree"
+        );
+        assert!(!fs.metadata(Path::new("/repo/script")).unwrap().executable);
+        assert!(!fs.exists(Path::new("/repo/new")));
+
+        // the index alone leaves the working tree be
+        let meta = index::IndexMeta::new_from_file(b"hello", &repo).unwrap();
+        index::insert_entry(
+            &mut idx,
+            IndexEntry {
+                name: "hello".into(),
+                meta,
+            },
+        );
+        fs.write(Path::new("/repo/hello"), b"changed").unwrap();
+        apply(&patches[..2], ApplyTarget::Index, &mut idx, &repo).unwrap();
+        assert_eq!(fs.read(Path::new("/repo/hello")).unwrap(), b"changed");
+        assert!(!fs.exists(Path::new("/repo/new")));
+        let names: Vec<_> = idx.iter().map(|e| e.name.to_string()).collect();
+        assert_eq!(names, vec!["hello", "new"]);
+        assert_eq!(
+            repo.read_object(&idx[0].meta.id).unwrap().1,
+            b"one\n2\n2.5\nthree"
+        );
+        assert_eq!(idx[1].meta.file_mode(), FileMode::Executable);
+
+        // but the index and working tree together have to match
+        let err = check(&reversed[2..], ApplyTarget::IndexAndWorktree, &idx, &repo).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ApplyError>(),
+            Some(&ApplyError::LocalChanges("hello".into()))
+        );
+    }
+}
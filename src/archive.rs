@@ -0,0 +1,413 @@
+//! Archives of trees, as `git archive` makes them, so that releases can be
+//! produced without a checkout. Only tar archives are written, byte for byte
+//! the same as git's: ustar headers, with pax extended headers holding paths
+//! and link targets that are too long for them, and a pax global header with
+//! the commit ID when a commit is archived.
+use anyhow::{anyhow, Result};
+use std::io::Write;
+
+use crate::objects::{FileMode, Id, Object, Repo};
+use crate::rev;
+use crate::util;
+
+/// tar files are made of blocks of this many bytes
+const BLOCK: usize = 512;
+/// git pads tar files to a multiple of this many bytes
+const RECORD: usize = 20 * BLOCK;
+/// Permissions taken away from every file and directory, git's default
+/// `tar.umask`
+const UMASK: u32 = 0o002;
+/// The biggest size that fits in a ustar header
+const MAX_SIZE: u64 = 0o777_7777_7777;
+
+const TYPE_REGULAR: u8 = b'0';
+const TYPE_SYMLINK: u8 = b'2';
+const TYPE_DIR: u8 = b'5';
+const TYPE_EXTENDED: u8 = b'x';
+const TYPE_GLOBAL: u8 = b'g';
+
+/// Writes a tar archive of the tree-ish `id` to `out`. Every path in it
+/// starts with `prefix`, which gets an entry of its own if it's a directory,
+/// ending in `/`. Entries have the commit's committer time as their
+/// modification time, or the current time if a tree is archived.
+pub fn write_tar(id: &Id, prefix: &[u8], repo: &Repo, out: &mut dyn Write) -> Result<()> {
+    let id = rev::peel_tags(*id, repo)?;
+    let (tree, commit, time) = match repo.open(&id)? {
+        Object::Commit(commit) => (commit.tree, Some(id), commit.committer.time.timestamp()),
+        Object::Tree(_) => (id, None, util::now().timestamp()),
+        _ => return Err(anyhow!("{} is not a tree or commit", id)),
+    };
+    let mut tar = TarWriter {
+        out,
+        written: 0,
+        mtime: time.max(0) as u64,
+    };
+
+    if let Some(commit) = commit {
+        let mut comment = Vec::new();
+        pax_record(&mut comment, "comment", commit.to_string().as_bytes());
+        let mut header = ustar_header(
+            b"pax_global_header",
+            0o100666,
+            comment.len() as u64,
+            TYPE_GLOBAL,
+            tar.mtime,
+        );
+        set_checksum(&mut header);
+        tar.write_header(&header)?;
+        tar.write_content(&comment)?;
+    }
+    if prefix.ends_with(b"/") {
+        let mut len = prefix.len();
+        while len > 1 && prefix[len - 2] == b'/' {
+            len -= 1;
+        }
+        tar.write_entry(&tree, &prefix[..len], FileMode::Directory, b"")?;
+    }
+    write_tree(&mut tar, &tree, prefix, repo)?;
+    tar.finish()
+}
+
+/// Writes the entries of a tree, and those of the trees in it, with their
+/// paths starting with `base`
+fn write_tree(tar: &mut TarWriter, tree: &Id, base: &[u8], repo: &Repo) -> Result<()> {
+    let tree = match repo.open(tree)? {
+        Object::Tree(tree) => tree,
+        _ => return Err(anyhow!("{} is not a tree", tree)),
+    };
+    for file in &tree.files {
+        let mut path = base.to_vec();
+        path.extend_from_slice(&file.name);
+        match file.mode {
+            FileMode::Directory => {
+                path.push(b'/');
+                tar.write_entry(&file.id, &path, file.mode, b"")?;
+                write_tree(tar, &file.id, &path, repo)?;
+            }
+            FileMode::Gitlink => {
+                // submodules aren't included, but they still show up as
+                // empty directories
+                path.push(b'/');
+                tar.write_entry(&file.id, &path, file.mode, b"")?;
+            }
+            FileMode::Regular | FileMode::Executable | FileMode::Symlink => {
+                let (_, content) = repo.read_object(&file.id)?;
+                tar.write_entry(&file.id, &path, file.mode, &content)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+struct TarWriter<'a> {
+    out: &'a mut dyn Write,
+    /// How many bytes have been written, to pad the last record
+    written: usize,
+    /// Modification time of every entry
+    mtime: u64,
+}
+
+impl TarWriter<'_> {
+    fn write_header(&mut self, header: &[u8; BLOCK]) -> Result<()> {
+        self.out.write_all(header)?;
+        self.written += BLOCK;
+        Ok(())
+    }
+
+    /// Writes the content of an entry, padded to a whole number of blocks
+    fn write_content(&mut self, content: &[u8]) -> Result<()> {
+        let padding = (BLOCK - content.len() % BLOCK) % BLOCK;
+        self.out.write_all(content)?;
+        self.out.write_all(&[0; BLOCK][..padding])?;
+        self.written += content.len() + padding;
+        Ok(())
+    }
+
+    /// Writes a file, symlink or directory. Paths of directories end in `/`.
+    /// `id` is used to name the extended header, if one is needed.
+    fn write_entry(&mut self, id: &Id, path: &[u8], mode: FileMode, content: &[u8]) -> Result<()> {
+        let mut extended = Vec::new();
+        let (kind, mode, size, link) = match mode {
+            FileMode::Directory | FileMode::Gitlink => {
+                (TYPE_DIR, (u32::from(mode) | 0o777) & !UMASK, 0, &b""[..])
+            }
+            FileMode::Symlink => (TYPE_SYMLINK, u32::from(mode) | 0o777, 0, content),
+            FileMode::Regular => (TYPE_REGULAR, 0o100666 & !UMASK, content.len(), &b""[..]),
+            FileMode::Executable => (TYPE_REGULAR, 0o100777 & !UMASK, content.len(), &b""[..]),
+        };
+        let mut size = size as u64;
+        if size > MAX_SIZE {
+            pax_record(&mut extended, "size", size.to_string().as_bytes());
+            size = 0;
+        }
+
+        let mut header = ustar_header(b"", mode, size, kind, self.mtime);
+        if path.len() <= 100 {
+            header[..path.len()].copy_from_slice(path);
+        } else {
+            // long paths can be split between the name and prefix fields at a
+            // slash, and otherwise go in an extended header
+            let split = path_prefix_len(path, 155);
+            let rest = path.len() - split - 1;
+            if split > 0 && rest <= 100 {
+                header[345..345 + split].copy_from_slice(&path[..split]);
+                header[..rest].copy_from_slice(&path[split + 1..]);
+            } else {
+                let name = format!("{}.data", id);
+                header[..name.len()].copy_from_slice(name.as_bytes());
+                pax_record(&mut extended, "path", path);
+            }
+        }
+        if link.len() <= 100 {
+            header[157..157 + link.len()].copy_from_slice(link);
+        } else {
+            let name = format!("see {}.paxheader", id);
+            header[157..157 + name.len()].copy_from_slice(name.as_bytes());
+            pax_record(&mut extended, "linkpath", link);
+        }
+        set_checksum(&mut header);
+
+        if !extended.is_empty() {
+            let name = format!("{}.paxheader", id);
+            let size = extended.len() as u64;
+            let mut pax_header =
+                ustar_header(name.as_bytes(), 0o100666, size, TYPE_EXTENDED, self.mtime);
+            set_checksum(&mut pax_header);
+            self.write_header(&pax_header)?;
+            self.write_content(&extended)?;
+        }
+        self.write_header(&header)?;
+        if kind == TYPE_REGULAR {
+            self.write_content(content)?;
+        }
+        Ok(())
+    }
+
+    /// Ends the archive with zeroes up to the end of the record, making sure
+    /// there are at least the two zero blocks that mark the end
+    fn finish(self) -> Result<()> {
+        let tail = RECORD - self.written % RECORD;
+        self.out.write_all(&vec![0; tail])?;
+        if tail < 2 * BLOCK {
+            self.out.write_all(&[0; RECORD])?;
+        }
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+/// How much of a path to put in the prefix field of a header: the part up to
+/// the last slash that fits in `max` bytes, not counting a slash at the end
+fn path_prefix_len(path: &[u8], max: usize) -> usize {
+    let mut i = path.len();
+    if i > 1 && path[i - 1] == b'/' {
+        i -= 1;
+    }
+    i = i.min(max);
+    loop {
+        i -= 1;
+        if i == 0 || path[i] == b'/' {
+            return i;
+        }
+    }
+}
+
+/// Adds a `<length> <key>=<value>` line to the content of a pax header. The
+/// length counts the whole line, its own digits included.
+fn pax_record(out: &mut Vec<u8>, key: &str, value: &[u8]) {
+    let mut len = key.len() + value.len() + 4;
+    let mut digits = 1;
+    while len / 10 >= digits {
+        len += 1;
+        digits *= 10;
+    }
+    out.extend_from_slice(format!("{} {}=", len, key).as_bytes());
+    out.extend_from_slice(value);
+    out.push(b'\n');
+}
+
+/// Writes `value` as zero padded octal filling all but the last byte of
+/// `field`, which is left as a NUL
+fn octal(field: &mut [u8], value: u64) {
+    let text = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..text.len()].copy_from_slice(text.as_bytes());
+}
+
+/// A ustar header without its checksum, so that the caller can fill in more
+/// fields first
+fn ustar_header(name: &[u8], mode: u32, size: u64, kind: u8, mtime: u64) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name);
+    octal(&mut header[100..108], u64::from(mode & 0o7777));
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    // only regular files have content
+    let size = if mode & 0o170000 == 0o100000 { size } else { 0 };
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[265..269].copy_from_slice(b"root");
+    header[297..301].copy_from_slice(b"root");
+    octal(&mut header[329..337], 0);
+    octal(&mut header[337..345], 0);
+    header
+}
+
+/// Fills in the checksum of a header: the sum of its bytes, counting those of
+/// the checksum field as spaces
+fn set_checksum(header: &mut [u8; BLOCK]) {
+    header[148..156].copy_from_slice(b"        ");
+    let sum = header.iter().map(|&b| u64::from(b)).sum();
+    header[148..156].copy_from_slice(&[0; 8]);
+    octal(&mut header[148..156], sum);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_prefix_len, pax_record, BLOCK, RECORD};
+    use crate::objects::{Blob, Commit, File, FileMode, NameEntry, Repo, Tree};
+
+    #[test]
+    fn test_pax_record() {
+        let mut out = Vec::new();
+        pax_record(&mut out, "path", b"abc");
+        assert_eq!(out, b"12 path=abc\n");
+        // the length crosses into two digits by counting itself
+        let mut out = Vec::new();
+        pax_record(&mut out, "path", b"abcd");
+        assert_eq!(out, b"13 path=abcd\n");
+        let mut out = Vec::new();
+        pax_record(&mut out, "k", &[b'v'; 94]);
+        assert_eq!(out.len(), 101);
+        assert!(out.starts_with(b"101 k="));
+    }
+
+    #[test]
+    fn test_path_prefix_len() {
+        assert_eq!(path_prefix_len(b"a/b/c", 155), 3);
+        assert_eq!(path_prefix_len(b"a/b/", 155), 1);
+        assert_eq!(path_prefix_len(b"abc", 155), 0);
+        assert_eq!(path_prefix_len(b"ab/cd/ef", 4), 2);
+    }
+
+    #[test]
+    fn test_write_tar() {
+        let repo = Repo::in_memory();
+        let long_name = "n".repeat(120);
+        let blob = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let link = repo.store(&Blob::new(b"file".to_vec())).unwrap();
+        let sub = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Executable,
+                    name: long_name.clone().into(),
+                    id: blob,
+                }],
+            })
+            .unwrap();
+        let tree = repo
+            .store(&Tree {
+                files: vec![
+                    File {
+                        mode: FileMode::Directory,
+                        name: "dir".into(),
+                        id: sub,
+                    },
+                    File {
+                        mode: FileMode::Regular,
+                        name: "file".into(),
+                        id: blob,
+                    },
+                    File {
+                        mode: FileMode::Symlink,
+                        name: "link".into(),
+                        id: link,
+                    },
+                ],
+            })
+            .unwrap();
+        let who = NameEntry::from("a <a@example.com> 1586391037 +0000").unwrap();
+        let commit = repo
+            .store(&Commit {
+                tree,
+                parents: vec![],
+                author: who.clone(),
+                committer: who,
+                message: "a\n".to_string(),
+            })
+            .unwrap();
+
+        let mut out = Vec::new();
+        super::write_tar(&commit, b"p/", &repo, &mut out).unwrap();
+        assert_eq!(out.len() % RECORD, 0);
+        let blocks = out.chunks(BLOCK).collect::<Vec<_>>();
+        let field = |block: &[u8], start: usize, len: usize| {
+            let field = &block[start..start + len];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+            String::from_utf8(field[..end].to_vec()).unwrap()
+        };
+        let checksum_ok = |block: &[u8]| {
+            let sum: u64 = block
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| {
+                    if (148..156).contains(&i) {
+                        32
+                    } else {
+                        u64::from(b)
+                    }
+                })
+                .sum();
+            field(block, 148, 8) == format!("{:07o}", sum)
+        };
+
+        // the global header, with the commit ID
+        assert_eq!(field(blocks[0], 0, 100), "pax_global_header");
+        assert_eq!(blocks[0][156], b'g');
+        assert_eq!(
+            field(blocks[1], 0, BLOCK),
+            format!("52 comment={}\n", commit)
+        );
+        // then the prefix and the directory
+        let entry = |i: usize, name: &str, mode: &str, size: usize, kind: u8| {
+            let block = blocks[i];
+            assert_eq!(field(block, 0, 100), name);
+            assert_eq!(field(block, 100, 8), mode);
+            assert_eq!(field(block, 124, 12), format!("{:011o}", size));
+            assert_eq!(field(block, 136, 12), "13643463775");
+            assert_eq!(block[156], kind);
+            assert_eq!(field(block, 257, 8), "ustar");
+            assert_eq!(field(block, 265, 32), "root");
+            assert!(checksum_ok(block));
+        };
+        entry(2, "p/", "0000775", 0, b'5');
+        entry(3, "p/dir/", "0000775", 0, b'5');
+        // then the file with the long name, which can't be split at a slash
+        // to fit in the header, so goes in an extended header
+        let path = format!("p/dir/{}", long_name);
+        let record = format!("136 path={}\n", path);
+        entry(
+            4,
+            &format!("{}.paxheader", blob),
+            "0000666",
+            record.len(),
+            b'x',
+        );
+        assert_eq!(field(blocks[5], 0, BLOCK), record);
+        entry(6, &format!("{}.data", blob), "0000775", 6, b'0');
+        assert_eq!(field(blocks[7], 0, BLOCK), "hello\n");
+        entry(8, "p/file", "0000664", 6, b'0');
+        entry(10, "p/link", "0000777", 0, b'2');
+        assert_eq!(field(blocks[10], 157, 100), "file");
+        assert!(out[11 * BLOCK..].iter().all(|&b| b == 0));
+
+        // trees have no commit to take a time or ID from
+        let mut out = Vec::new();
+        super::write_tar(&sub, b"", &repo, &mut out).unwrap();
+        assert_eq!(out.len(), RECORD);
+        let name = format!("{}.paxheader", blob);
+        assert_eq!(&out[..name.len()], name.as_bytes());
+    }
+}
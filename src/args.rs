@@ -7,6 +7,11 @@ pub struct Opts {
     #[clap(short = "v", parse(from_occurrences))]
     pub verbose: usize,
 
+    /// Open objects as they are, rather than as what they are replaced with
+    /// under refs/replace
+    #[clap(long)]
+    pub no_replace_objects: bool,
+
     #[clap(subcommand)]
     pub subcmd: SubCommand,
 }
@@ -16,34 +21,167 @@ pub enum SubCommand {
     /// ➕ adds the given files or directories (recurses!) to the repo
     Add(Add),
 
+    /// 📬 applies patch emails as commits
+    Am(Am),
+
+    /// 🩹 applies patches to the working tree, the index or both
+    Apply(Apply),
+
+    /// 🗃️ writes a tar archive of a commit or tree
+    Archive(Archive),
+
+    /// 🕵️ shows which commit each line of a file came from
+    Blame(Blame),
+
+    /// 📤 checks out a branch or commit into the working tree
+    Checkout(Checkout),
+
+    /// 🍒 shows which commits on a branch are upstream already
+    Cherry(Cherry),
+
     /// 🔃 commits the tree state in the index
     Commit(Commit),
 
+    /// 😈 serves repositories read-only over git://
+    Daemon(Daemon),
+
     /// 🆎 diffs blobs and commits
     Diff(Diff),
 
+    /// 📥 downloads objects and refs from another repository
+    Fetch(Fetch),
+
+    /// ✂️ rewrites history, removing or moving paths in every commit
+    Filter(Filter),
+
+    /// 📧 makes patch emails out of commits
+    FormatPatch(FormatPatch),
+
+    /// 🩺 checks every object, and finds those nothing refers to any more
+    Fsck(Fsck),
+
+    /// 🗑️ packs objects and refs, and deletes what nothing refers to any more
+    Gc(Gc),
+
+    /// 🔦 searches tracked files for lines matching a pattern
+    Grep(Grep),
+
     /// ✨ makes a new repo
     Init,
 
+    /// 🖋️ adds trailers such as Signed-off-by to commit messages
+    InterpretTrailers(InterpretTrailers),
+
+    /// 📖 shows the commit history
+    Log(Log),
+
+    /// 🧹 packs objects and refs and writes the commit-graph
+    Maintenance(Maintenance),
+
+    /// 🤝 joins the history of another branch into this one
+    Merge(Merge),
+
+    /// 🚀 uploads objects and updates refs in another repository
+    Push(Push),
+
+    /// 🪜 replays the commits on this branch on top of another one
+    Rebase(Rebase),
+
+    /// 📜 shows where a ref has pointed, newest first
+    Reflog(Reflog),
+
+    /// 🗜️ packs reachable objects, optionally all into one pack
+    Repack(Repack),
+
+    /// 🔁 replaces objects with others wherever they are read, or lists those
+    /// replaced
+    Replace(Replace),
+
+    /// ⏪ resets HEAD, the index and the working tree to a commit
+    Reset(Reset),
+
+    /// 🩹 undoes changes to files, from the index or a commit
+    Restore(Restore),
+
+    /// 🗑️ removes files from the index and the working tree
+    Rm(Rm),
+
     /// ❓ queries the status of the index vs HEAD and the working tree
-    Status,
+    Status(Status),
+
+    /// 🔍 shows a commit and what it changed
+    Show(Show),
+
+    /// 🔀 switches branches, or starts a new branch with no history
+    Switch(Switch),
+
+    /// 🏷️ lists, makes and annotates tags
+    Tag(Tag),
 
     // ----- Plumbing -----
     /// 🐱 dumps the content of an object file with a given ID
     CatFile(CatFile),
 
+    /// 🙈 shows whether paths are ignored, and by which pattern
+    CheckIgnore(CheckIgnore),
+
+    /// ✅ checks that a ref name is valid
+    CheckRefFormat(CheckRefFormat),
+
     /// 🔃🌳 commits a tree object
     CommitTree(CommitTree),
 
     /// 🐛 dumps debug info about various files
     Debug(Debug),
 
+    /// 🚚 writes every ref and the history behind it as a fast-import stream
+    FastExport(FastExport),
+
+    /// 🏗️ builds objects and refs from a fast-import stream on stdin
+    FastImport(FastImport),
+
+    /// #️⃣ finds the IDs files would have as objects, optionally storing them
+    HashObject(HashObject),
+
+    /// 📇 writes an index for a packfile so its objects can be found
+    IndexPack(IndexPack),
+
+    /// 📜 lists the contents of a tree object
+    LsTree(LsTree),
+
     /// 🌳 makes a tree object from the given file paths
     NewTree(NewTree),
 
+    /// 📦 packs objects listed on stdin into a packfile
+    PackObjects(PackObjects),
+
+    /// 🧳 moves loose refs into the packed-refs file
+    PackRefs(PackRefs),
+
+    /// 🪪 computes IDs of diffs from stdin that stay the same when rebased
+    PatchId(PatchId),
+
+    /// 📋 lists commits, and optionally the objects in them
+    RevList(RevList),
+
     /// 🔎 matches the given reference to an id
     RevParse(RevParse),
 
+    /// 🏷️ lists refs and the ids they point to
+    ShowRef(ShowRef),
+
+    /// 🧹 cleans up a message from stdin the way commit messages are
+    Stripspace(Stripspace),
+
+    /// 🔗 reads or changes which branch HEAD or another symref points to
+    SymbolicRef(SymbolicRef),
+
+    /// 💣 stores the objects in a packfile as loose objects
+    UnpackObjects(UnpackObjects),
+
+    /// 🗂️ changes entries in the index directly
+    UpdateIndex(UpdateIndex),
+
     /// 💥 updates a given reference to a value. Very unsafe.
     UpdateRef(UpdateRef),
 }
@@ -53,6 +191,11 @@ pub struct Add {
     /// Files to add to the repo
     #[clap(index = 1, multiple = true)]
     pub files: Vec<String>,
+
+    /// Only update files already in the index, including removing deleted
+    /// ones. Limited to the given paths if there are any.
+    #[clap(long, short = "u")]
+    pub update: bool,
 }
 
 arg_enum! {
@@ -71,6 +214,102 @@ pub struct CatFile {
     #[clap(long, short = "o", required = false, case_insensitive = true,
            default_value = "Raw", possible_values = &OutputType::variants())]
     pub output: OutputType,
+
+    /// Print the parsed object as JSON
+    #[clap(long)]
+    pub json: bool,
+
+    /// Only print the type of the object
+    #[clap(short = "t", conflicts_with = "show_size")]
+    pub show_type: bool,
+
+    /// Only print the size of the object's content
+    #[clap(short = "s")]
+    pub show_size: bool,
+}
+
+#[derive(Clap)]
+pub struct Am {
+    /// Mailboxes or patch files to apply, in order. Read from stdin if none
+    /// are given.
+    #[clap(index = 1, multiple = true)]
+    pub mailboxes: Vec<String>,
+
+    #[clap(long, case_insensitive = true)]
+    /// Who to commit as, instead of user.name and user.email from the
+    /// config. Format (remember to quote!): your_name <email@example.com>
+    pub who: Option<String>,
+
+    /// Commit the patch that failed to apply, once it has been fixed up and
+    /// added to the index, then carry on with the rest
+    #[clap(long = "continue")]
+    pub resolved: bool,
+
+    /// Skip the patch that failed to apply and carry on with the rest
+    #[clap(long)]
+    pub skip: bool,
+
+    /// Give up, putting HEAD back where it was before
+    #[clap(long)]
+    pub abort: bool,
+}
+
+#[derive(Clap)]
+pub struct Apply {
+    /// Patch files to apply, in order. Read from stdin if none are given.
+    #[clap(index = 1, multiple = true)]
+    pub patches: Vec<String>,
+
+    /// Only check that the patches apply, without changing anything
+    #[clap(long)]
+    pub check: bool,
+
+    /// Undo the patches instead
+    #[clap(long, short = "R")]
+    pub reverse: bool,
+
+    /// Apply to the index as well as the working tree. The files being
+    /// changed mustn't have changes in the working tree.
+    #[clap(long, conflicts_with = "cached")]
+    pub index: bool,
+
+    /// Only apply to the index, leaving the working tree alone
+    #[clap(long)]
+    pub cached: bool,
+}
+
+#[derive(Clap)]
+pub struct Archive {
+    /// The commit or tree to archive
+    #[clap(index = 1)]
+    pub tree_ish: String,
+
+    /// Put this in front of every path in the archive. End it with `/` to
+    /// put everything in a directory.
+    #[clap(long)]
+    pub prefix: Option<String>,
+
+    /// Write the archive to this file instead of stdout
+    #[clap(long, short = "o")]
+    pub output: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Blame {
+    /// The file to blame as of HEAD, or the commit to start from if a file is
+    /// given after it
+    #[clap(index = 1)]
+    pub rev_or_file: String,
+
+    /// The file to blame, when a commit is given first
+    #[clap(index = 2)]
+    pub file: Option<String>,
+
+    /// Only blame lines <start>,<end>, where <end> can be +<count> for count
+    /// lines from start or -<count> for count lines up to start, and is the
+    /// end of the file if it's left out. May be given more than once.
+    #[clap(short = "L", number_of_values = 1)]
+    pub lines: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -81,8 +320,27 @@ pub struct Commit {
     pub who: String,
 
     #[clap(long, short = "m", case_insensitive = true)]
-    /// Commit message
-    pub message: String,
+    /// Commit message. An editor is opened to write one if it's left out.
+    pub message: Option<String>,
+
+    /// Start the message from this file instead of the one commit.template
+    /// names
+    #[clap(long, short = "t")]
+    pub template: Option<String>,
+
+    /// Edit the message given with -m too
+    #[clap(long, short = "e")]
+    pub edit: bool,
+
+    /// How to clean up the message: strip, whitespace, verbatim, scissors or
+    /// default, which is strip if the message was edited and whitespace if
+    /// not. Defaults to commit.cleanup.
+    #[clap(long)]
+    pub cleanup: Option<String>,
+
+    /// Add a Signed-off-by trailer for `who` to the message
+    #[clap(long, short = "s")]
+    pub signoff: bool,
 }
 
 #[derive(Clap)]
@@ -96,6 +354,456 @@ pub struct Diff {
     pub cached: bool,
 }
 
+#[derive(Clap)]
+pub struct Daemon {
+    /// Only serve repositories under these directories
+    #[clap(index = 1, multiple = true)]
+    pub directories: Vec<String>,
+
+    /// Look up requested paths under this directory
+    #[clap(long)]
+    pub base_path: Option<String>,
+
+    /// Serve repositories even without a git-daemon-export-ok file
+    #[clap(long)]
+    pub export_all: bool,
+
+    /// Port to listen on
+    #[clap(long, default_value = "9418")]
+    pub port: u16,
+
+    /// Disconnect clients that leave a read or write waiting this many
+    /// seconds. 0 waits forever.
+    #[clap(long, default_value = "0")]
+    pub timeout: u64,
+
+    /// Turn clients away while this many are being served. 0 means no limit.
+    #[clap(long, default_value = "32")]
+    pub max_connections: usize,
+}
+
+#[derive(Clap)]
+pub struct Fetch {
+    /// The remote to fetch from, which must have remote.<remote>.url set in
+    /// the config
+    #[clap(index = 1, default_value = "origin")]
+    pub remote: String,
+}
+
+#[derive(Clap)]
+pub struct Filter {
+    /// Remove a file or directory from every commit. May be given more than
+    /// once.
+    #[clap(long, number_of_values = 1)]
+    pub remove_path: Vec<String>,
+
+    /// Move a file or directory in every commit, given as `old:new`. May be
+    /// given more than once.
+    #[clap(long, number_of_values = 1)]
+    pub rename_path: Vec<String>,
+
+    /// Remove files bigger than this from every commit, such as `10M`
+    #[clap(long)]
+    pub strip_blobs_bigger_than: Option<String>,
+
+    /// Where to write the old ID of each commit next to its new one. Defaults
+    /// to .git/filter/commit-map
+    #[clap(long)]
+    pub commit_map: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Checkout {
+    /// Branch or commit to check out. HEAD is detached at anything other
+    /// than a branch.
+    #[clap(index = 1)]
+    pub rev: String,
+
+    /// Throw away local changes to tracked files, and overwrite untracked
+    /// files in the way
+    #[clap(long, short = "f")]
+    pub force: bool,
+
+    /// Detach HEAD at the commit a branch points to rather than switching to
+    /// the branch
+    #[clap(long)]
+    pub detach: bool,
+}
+
+#[derive(Clap)]
+pub struct Cherry {
+    /// The branch to look for equivalent commits in
+    #[clap(index = 1)]
+    pub upstream: String,
+
+    /// The branch with the commits to look for. Defaults to HEAD
+    #[clap(index = 2)]
+    pub head: Option<String>,
+
+    /// Leave out commits up to and including this one
+    #[clap(index = 3)]
+    pub limit: Option<String>,
+
+    /// Show each commit's subject too
+    #[clap(short = "v", long)]
+    pub verbose: bool,
+}
+
+#[derive(Clap)]
+pub struct FormatPatch {
+    /// Commits to make patches of: `a..b` for those in b but not a, or just
+    /// `a` for those since a on HEAD
+    #[clap(index = 1)]
+    pub range: String,
+
+    /// Write the patches to this directory instead of the current one
+    #[clap(short = "o", long)]
+    pub output_directory: Option<String>,
+
+    /// Print the patches instead of writing them to files
+    #[clap(long)]
+    pub stdout: bool,
+}
+
+#[derive(Clap)]
+pub struct Fsck {
+    /// List every unreachable object rather than just the dangling ones,
+    /// which nothing else refers to
+    #[clap(long)]
+    pub unreachable: bool,
+
+    /// Don't list dangling objects
+    #[clap(long)]
+    pub no_dangling: bool,
+}
+
+#[derive(Clap)]
+pub struct Gc {
+    /// Delete unreachable objects from before this date rather than
+    /// gc.pruneExpire, which defaults to 2 weeks ago. "now" deletes them all
+    /// and "never" deletes none.
+    #[clap(long)]
+    pub prune: Option<String>,
+
+    /// Don't delete any unreachable objects
+    #[clap(long, conflicts_with = "prune")]
+    pub no_prune: bool,
+
+    /// Only run if there are enough loose objects, as set by gc.auto
+    #[clap(long)]
+    pub auto: bool,
+}
+
+#[derive(Clap)]
+pub struct Repack {
+    /// Pack every reachable object into one pack, rather than only the loose
+    /// ones
+    #[clap(short = "a")]
+    pub all: bool,
+
+    /// Delete the packs and loose objects the new pack makes redundant
+    #[clap(short = "d")]
+    pub delete: bool,
+}
+
+#[derive(Clap)]
+pub struct Replace {
+    /// The object to replace. The replaced objects are listed if this isn't
+    /// given.
+    #[clap(index = 1, requires = "replacement")]
+    pub object: Option<String>,
+
+    /// The object to read in its place
+    #[clap(index = 2)]
+    pub replacement: Option<String>,
+
+    /// Replace the object even if it has been replaced already, or is a
+    /// different type of object to its replacement
+    #[clap(long, short = "f")]
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct InterpretTrailers {
+    /// Files with messages to edit. A message is read from stdin if none are
+    /// given.
+    #[clap(index = 1, multiple = true)]
+    pub files: Vec<String>,
+
+    /// Trailer to add, as `token: value` or `token=value`. May be given more
+    /// than once.
+    #[clap(long, number_of_values = 1)]
+    pub trailer: Vec<String>,
+
+    /// What to do when a trailer with the same token is already there
+    #[clap(long, case_insensitive = true, default_value = "AddIfDifferentNeighbor",
+           possible_values = &IfExists::variants())]
+    pub if_exists: IfExists,
+
+    /// Edit the files rather than printing the results
+    #[clap(long)]
+    pub in_place: bool,
+
+    /// Remove trailers with empty values
+    #[clap(long)]
+    pub trim_empty: bool,
+
+    /// Only print the trailers, not the rest of the message
+    #[clap(long)]
+    pub only_trailers: bool,
+}
+
+arg_enum! {
+pub enum IfExists {
+    AddIfDifferentNeighbor,
+    AddIfDifferent,
+    Add,
+    Replace,
+    DoNothing
+}
+}
+
+#[derive(Clap)]
+pub struct Grep {
+    /// Regular expression to look for
+    #[clap(index = 1)]
+    pub pattern: String,
+
+    /// Search the files in this commit or tree, instead of the tracked files
+    /// in the working tree
+    #[clap(index = 2)]
+    pub rev: Option<String>,
+
+    /// Show the number of each matching line
+    #[clap(long, short = "n")]
+    pub line_number: bool,
+
+    /// Match regardless of case
+    #[clap(long, short = "i")]
+    pub ignore_case: bool,
+
+    /// Only show the names of files with matches
+    #[clap(long, short = "l")]
+    pub files_with_matches: bool,
+
+    /// Only search these files or directories, given after `--`
+    #[clap(last = true)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct Log {
+    /// Commits to start from. `^<rev>` leaves out the commits reachable from
+    /// a commit, `<a>..<b>` shows those reachable from b but not a, and
+    /// `<a>...<b>` those reachable from either but not both.
+    #[clap(index = 1, multiple = true, default_value = "HEAD")]
+    pub revs: Vec<String>,
+
+    /// Show at most this many commits
+    #[clap(short = "n", long)]
+    pub max_count: Option<usize>,
+
+    /// Show each commit on one line, the same as `--pretty=oneline`
+    #[clap(long)]
+    pub oneline: bool,
+
+    /// How to show commits: oneline, short, medium or full, or a template
+    /// such as `format:%h %s`
+    #[clap(long, visible_alias = "format")]
+    pub pretty: Option<String>,
+
+    /// Only show commits made after this date, such as "2 weeks ago"
+    #[clap(long, visible_alias = "after")]
+    pub since: Option<String>,
+
+    /// Only show commits made before this date
+    #[clap(long, visible_alias = "before")]
+    pub until: Option<String>,
+
+    /// Only show commits whose author matches this regular expression
+    #[clap(long)]
+    pub author: Option<String>,
+
+    /// Only show commits whose committer matches this regular expression
+    #[clap(long)]
+    pub committer: Option<String>,
+
+    /// Only show commits whose message matches this regular expression. If
+    /// given more than once, commits matching any of them are shown.
+    #[clap(long, number_of_values = 1)]
+    pub grep: Vec<String>,
+
+    /// Only follow the first parent of merges
+    #[clap(long)]
+    pub first_parent: bool,
+
+    /// Show no commit before all of its children, rather than newest first,
+    /// so lines of history aren't mixed together
+    #[clap(long)]
+    pub topo_order: bool,
+
+    /// Only show merges
+    #[clap(long)]
+    pub merges: bool,
+
+    /// Don't show merges
+    #[clap(long)]
+    pub no_merges: bool,
+
+    /// Keep showing the history of a file from before it was renamed. Needs
+    /// exactly one path.
+    #[clap(long)]
+    pub follow: bool,
+
+    /// Only show commits that change these files or directories, given after
+    /// `--`
+    #[clap(last = true)]
+    pub paths: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct Maintenance {
+    #[clap(subcommand)]
+    pub subcmd: MaintenanceCommand,
+}
+
+#[derive(Clap)]
+pub enum MaintenanceCommand {
+    /// Runs maintenance tasks
+    Run(MaintenanceRun),
+}
+
+#[derive(Clap)]
+pub struct MaintenanceRun {
+    /// Task to run: gc, commit-graph, loose-objects or pack-refs. May be given
+    /// more than once. Defaults to gc, which does all of the others.
+    #[clap(long, number_of_values = 1)]
+    pub task: Vec<String>,
+
+    /// Only run if there are enough loose objects, as set by gc.auto
+    #[clap(long)]
+    pub auto: bool,
+}
+
+#[derive(Clap)]
+pub struct Merge {
+    /// The branch or commit to merge
+    #[clap(index = 1)]
+    pub rev: Option<String>,
+
+    /// Message for the merge commit
+    #[clap(long, short = "m")]
+    pub message: Option<String>,
+
+    /// Make a merge commit even if the branch could just be moved forward
+    #[clap(long)]
+    pub no_ff: bool,
+
+    /// Who to commit as, instead of user.name and user.email from the
+    /// config. Format (remember to quote!): your_name <email@example.com>
+    #[clap(long)]
+    pub who: Option<String>,
+
+    /// Commit the merge that stopped for conflicts, once they have been
+    /// fixed and added to the index
+    #[clap(long = "continue")]
+    pub resolved: bool,
+
+    /// Give up on the merge that stopped for conflicts, putting the index and
+    /// working tree back as they were before it
+    #[clap(long)]
+    pub abort: bool,
+}
+
+#[derive(Clap)]
+pub struct Push {
+    /// The remote to push to, which must have remote.<remote>.url set in the
+    /// config
+    #[clap(index = 1, default_value = "origin")]
+    pub remote: String,
+
+    /// What to push where, as `[+]<src>[:<dst>]`, or `:<dst>` to delete dst.
+    /// Without any, the current branch goes to the branch of the same name,
+    /// unless remote.<remote>.push is set
+    #[clap(index = 2)]
+    pub refspecs: Vec<String>,
+
+    /// Update refs even if that loses commits on the remote
+    #[clap(long, short = "f")]
+    pub force: bool,
+}
+
+#[derive(Clap)]
+pub struct Rebase {
+    /// The branch to move this one's commits on top of
+    #[clap(index = 1)]
+    pub upstream: Option<String>,
+
+    /// Edit the list of commits to replay first, to reorder, reword, squash
+    /// or drop them
+    #[clap(short = "i", long)]
+    pub interactive: bool,
+
+    /// Who to commit as, instead of user.name and user.email from the
+    /// config. Format (remember to quote!): your_name <email@example.com>
+    #[clap(long)]
+    pub who: Option<String>,
+
+    /// Commit the change that stopped for conflicts, once they have been
+    /// fixed and added to the index, then carry on with the rest
+    #[clap(long = "continue")]
+    pub resolved: bool,
+
+    /// Give up, putting the branch back where it was before
+    #[clap(long)]
+    pub abort: bool,
+}
+
+#[derive(Clap)]
+pub struct Reflog {
+    #[clap(subcommand)]
+    pub subcmd: Option<ReflogCommand>,
+}
+
+#[derive(Clap)]
+pub enum ReflogCommand {
+    /// Shows the entries of a reflog. This is what `reflog` does on its own,
+    /// for HEAD.
+    Show(ReflogShow),
+
+    /// Removes old entries from reflogs. Defaults to the times in
+    /// gc.reflogExpire and gc.reflogExpireUnreachable.
+    Expire(ReflogExpire),
+}
+
+#[derive(Clap)]
+pub struct ReflogShow {
+    /// The ref whose reflog to show, which may be a short name like `main`
+    #[clap(index = 1, default_value = "HEAD")]
+    pub name: String,
+}
+
+#[derive(Clap)]
+pub struct ReflogExpire {
+    /// The refs whose reflogs to expire
+    #[clap(index = 1, multiple = true)]
+    pub refs: Vec<String>,
+
+    /// Expire the reflogs of every ref
+    #[clap(long)]
+    pub all: bool,
+
+    /// Remove entries from before this time. `all` removes every entry, and
+    /// `never` none.
+    #[clap(long)]
+    pub expire: Option<String>,
+
+    /// Remove entries from before this time for commits that are no longer in
+    /// the history of their ref
+    #[clap(long)]
+    pub expire_unreachable: Option<String>,
+}
+
 #[derive(Clap)]
 pub struct NewTree {
     /// Paths to add to the new tree
@@ -103,6 +811,156 @@ pub struct NewTree {
     pub paths: Vec<String>,
 }
 
+#[derive(Clap)]
+pub struct PackObjects {
+    /// Write the pack to `<base-name>-<checksum>.pack`, with its index next
+    /// to it in `<base-name>-<checksum>.idx`
+    #[clap(index = 1)]
+    pub base_name: Option<String>,
+
+    /// Write the pack to stdout instead, without an index
+    #[clap(long)]
+    pub stdout: bool,
+
+    /// Read revisions from stdin rather than object IDs, and pack everything
+    /// reachable from them. Anything reachable from revisions starting with
+    /// `^`, or listed after `--not`, is left out.
+    #[clap(long)]
+    pub revs: bool,
+}
+
+#[derive(Clap)]
+pub struct PackRefs {
+    /// Pack branches and every other ref too, not just tags and refs that
+    /// are already packed
+    #[clap(long)]
+    pub all: bool,
+}
+
+#[derive(Clap)]
+pub struct PatchId {
+    /// Add up the IDs of each file's diff, so the order the files are in
+    /// doesn't matter
+    #[clap(long)]
+    pub stable: bool,
+}
+
+#[derive(Clap)]
+pub struct HashObject {
+    /// Files to hash
+    #[clap(index = 1, multiple = true)]
+    pub files: Vec<String>,
+
+    /// The type of object to make: blob, tree, commit or tag
+    #[clap(short = "t", default_value = "blob")]
+    pub objtype: String,
+
+    /// Store the objects in the repository too
+    #[clap(short = "w")]
+    pub write: bool,
+
+    /// Read an object from stdin, ahead of any files
+    #[clap(long)]
+    pub stdin: bool,
+}
+
+#[derive(Clap)]
+pub struct IndexPack {
+    /// The pack to index
+    #[clap(index = 1)]
+    pub pack: String,
+
+    /// Where to write the index. Defaults to the pack's path with `.idx` in
+    /// place of `.pack`.
+    #[clap(long, short = "o")]
+    pub output: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct ShowRef {
+    /// Only list refs named this or ending in these path components, such as
+    /// `main` for `refs/heads/main`. With `--verify`, the full refnames to
+    /// check.
+    #[clap(index = 1, multiple = true)]
+    pub patterns: Vec<String>,
+
+    /// Only list branches
+    #[clap(long)]
+    pub heads: bool,
+
+    /// Only list tags
+    #[clap(long)]
+    pub tags: bool,
+
+    /// Check that each ref exists under exactly the name given
+    #[clap(long)]
+    pub verify: bool,
+}
+
+#[derive(Clap)]
+pub struct SymbolicRef {
+    /// The symref, such as HEAD
+    #[clap(index = 1)]
+    pub name: String,
+
+    /// The ref to point it at, such as `refs/heads/main`. It is printed if
+    /// this isn't given.
+    #[clap(index = 2)]
+    pub target: Option<String>,
+
+    /// Print the ref without `refs/heads/` or the like in front
+    #[clap(long)]
+    pub short: bool,
+}
+
+#[derive(Clap)]
+pub struct UnpackObjects {
+    /// The pack to unpack. Read from stdin if not given.
+    #[clap(index = 1)]
+    pub pack: Option<String>,
+
+    /// Check the pack without storing anything
+    #[clap(long, short = "n")]
+    pub dry_run: bool,
+}
+
+#[derive(Clap)]
+pub struct CheckRefFormat {
+    /// The ref name to check, such as `refs/heads/main`
+    #[clap(index = 1)]
+    pub name: String,
+
+    /// Accept names with only one component, such as `main`
+    #[clap(long)]
+    pub allow_onelevel: bool,
+
+    /// Take out leading and repeated slashes, and print the name if it is
+    /// valid then
+    #[clap(long)]
+    pub normalize: bool,
+}
+
+#[derive(Clap)]
+pub struct CheckIgnore {
+    /// Paths to check
+    #[clap(index = 1, multiple = true, required = true)]
+    pub paths: Vec<String>,
+
+    /// Show the pattern that decided each path and the file it is from,
+    /// including `!` patterns that mean the path is not ignored
+    #[clap(short = "v", long)]
+    pub verbose: bool,
+
+    /// With --verbose, also show paths that no pattern matched
+    #[clap(short = "n", long)]
+    pub non_matching: bool,
+
+    /// Check files in the index too. Otherwise they are never ignored, since
+    /// ignoring only applies to untracked files.
+    #[clap(long)]
+    pub no_index: bool,
+}
+
 #[derive(Clap)]
 pub struct CommitTree {
     #[clap(index = 1)]
@@ -125,6 +983,10 @@ pub struct Debug {
         possible_values = &DebugType::variants())]
     /// Which file to debug
     pub what: DebugType,
+
+    /// Print as JSON rather than Rust debug format
+    #[clap(long)]
+    pub json: bool,
 }
 
 arg_enum! {
@@ -136,11 +998,261 @@ pub enum DebugType {
 }
 }
 
+#[derive(Clap)]
+pub struct FastExport {}
+
+#[derive(Clap)]
+pub struct FastImport {
+    /// Move branches even when that would lose commits
+    #[clap(short = "f", long)]
+    pub force: bool,
+
+    /// Read marks from this file before importing
+    #[clap(long)]
+    pub import_marks: Option<String>,
+
+    /// Write the marks to this file after importing
+    #[clap(long)]
+    pub export_marks: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Reset {
+    /// Commit to reset to
+    #[clap(index = 1, default_value = "HEAD")]
+    pub rev: String,
+
+    /// Only move the branch, leaving the index and working tree alone
+    #[clap(long, conflicts_with_all = &["mixed", "hard"])]
+    pub soft: bool,
+
+    /// Move the branch and make the index match the commit, leaving the
+    /// working tree alone. This is the default.
+    #[clap(long, conflicts_with = "hard")]
+    pub mixed: bool,
+
+    /// Move the branch and overwrite the index and tracked files in the
+    /// working tree
+    #[clap(long)]
+    pub hard: bool,
+}
+
+#[derive(Clap)]
+pub struct RevList {
+    /// Commits to list from. Commits given as ^<commit> are left out, along
+    /// with everything reachable from them, <a>..<b> lists those reachable
+    /// from b but not a, and <a>...<b> those reachable from either but not
+    /// both.
+    #[clap(index = 1, multiple = true, required = true)]
+    pub revs: Vec<String>,
+
+    /// List at most this many commits
+    #[clap(short = "n", long)]
+    pub max_count: Option<usize>,
+
+    /// Also list the trees and blobs in the listed commits, with their paths
+    #[clap(long)]
+    pub objects: bool,
+
+    /// List no commit before all of its children, rather than newest first
+    #[clap(long)]
+    pub topo_order: bool,
+
+    /// Print how many commits, or objects with --objects, there are rather
+    /// than listing them
+    #[clap(long)]
+    pub count: bool,
+
+    /// Use the pack's bitmap if there is one, so that less history has to be
+    /// walked. Objects are listed in pack order, without their paths.
+    #[clap(long)]
+    pub use_bitmap_index: bool,
+}
+
 #[derive(Clap)]
 pub struct RevParse {
     /// Revision to find
     #[clap(index = 1)]
     pub rev: String,
+
+    /// Print the shortest unique abbreviation of the ID, with at least
+    /// core.abbrev digits
+    #[clap(long)]
+    pub short: bool,
+
+    /// Print the result as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Clap)]
+pub struct Switch {
+    /// Branch to switch to
+    #[clap(index = 1)]
+    pub branch: String,
+
+    /// Where to start a branch made with -c, instead of HEAD
+    #[clap(index = 2)]
+    pub start_point: Option<String>,
+
+    /// Make the branch first, at HEAD or the given start point
+    #[clap(short = "c", long, conflicts_with = "orphan")]
+    pub create: bool,
+
+    /// Make a new branch with no history. The index is emptied, so the first
+    /// commit on it has only what is added before then.
+    #[clap(long)]
+    pub orphan: bool,
+
+    /// With --orphan, leave tracked files in the working tree rather than
+    /// removing them
+    #[clap(long)]
+    pub keep_worktree: bool,
+
+    /// Throw away changes to tracked files and overwrite untracked ones in
+    /// the way, rather than refusing to switch. Changes can't be carried
+    /// across to another commit yet.
+    #[clap(long)]
+    pub discard_changes: bool,
+}
+
+#[derive(Clap)]
+pub struct Tag {
+    /// Name of the tag to make. Tags are listed if it's left out.
+    #[clap(index = 1)]
+    pub name: Option<String>,
+
+    /// What to tag, instead of HEAD
+    #[clap(index = 2)]
+    pub object: Option<String>,
+
+    /// Make an annotated tag object rather than a lightweight tag. An editor
+    /// is opened to write its message if -m is left out.
+    #[clap(long, short = "a")]
+    pub annotate: bool,
+
+    /// Message for an annotated tag. Implies -a.
+    #[clap(long, short = "m")]
+    pub message: Option<String>,
+
+    /// Replace the tag if it already exists
+    #[clap(long, short = "f")]
+    pub force: bool,
+
+    /// Who to tag as, instead of user.name and user.email from the config.
+    /// Format (remember to quote!): your_name <email@example.com>
+    #[clap(long)]
+    pub who: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Restore {
+    /// Files or directories to restore
+    #[clap(index = 1, required = true)]
+    pub paths: Vec<String>,
+
+    /// Restore from this commit, instead of the index for the working tree
+    /// and HEAD for the index
+    #[clap(long, short = "s")]
+    pub source: Option<String>,
+
+    /// Restore the index
+    #[clap(long, short = "S")]
+    pub staged: bool,
+
+    /// Restore the working tree. This is the default unless --staged is
+    /// given.
+    #[clap(long, short = "W")]
+    pub worktree: bool,
+}
+
+#[derive(Clap)]
+pub struct Rm {
+    /// Files to remove
+    #[clap(index = 1, required = true)]
+    pub paths: Vec<String>,
+
+    /// Only remove the files from the index, keeping them in the working tree
+    #[clap(long)]
+    pub cached: bool,
+
+    /// Remove files even if they have changes that would be lost
+    #[clap(long, short = "f")]
+    pub force: bool,
+
+    /// Remove everything in directories that are given
+    #[clap(short = "r")]
+    pub recursive: bool,
+}
+
+#[derive(Clap)]
+pub struct Show {
+    /// The commit to show
+    #[clap(index = 1, default_value = "HEAD")]
+    pub rev: String,
+
+    /// How to show the commit, as for log
+    #[clap(long, visible_alias = "format")]
+    pub pretty: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Status {
+    /// Print one `XY path` line per changed file, in a format that won't
+    /// change between versions
+    #[clap(long)]
+    pub porcelain: bool,
+
+    /// Print the changed files as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Clap)]
+pub struct Stripspace {
+    /// Also remove lines starting with `#`
+    #[clap(long, short = "s")]
+    pub strip_comments: bool,
+}
+
+#[derive(Clap)]
+pub struct LsTree {
+    /// Tree or commit to list
+    #[clap(index = 1)]
+    pub tree_ish: String,
+
+    /// Recurse into subtrees, listing only files
+    #[clap(short = "r")]
+    pub recursive: bool,
+
+    /// Print the entries as JSON
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Clap)]
+pub struct UpdateIndex {
+    /// Files to update the entries of from the working tree
+    #[clap(index = 1, multiple = true)]
+    pub files: Vec<String>,
+
+    /// Add files that aren't in the index yet
+    #[clap(long)]
+    pub add: bool,
+
+    /// Remove the entries of files that are gone from the working tree
+    #[clap(long)]
+    pub remove: bool,
+
+    /// Update the stats of entries whose files haven't changed, and list
+    /// those that have
+    #[clap(long)]
+    pub refresh: bool,
+
+    /// Put a blob that is already stored in the index without looking at the
+    /// working tree, given as `mode,id,path`. May be given more than once.
+    #[clap(long, number_of_values = 1)]
+    pub cacheinfo: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -151,4 +1263,8 @@ pub struct UpdateRef {
 
     #[clap(index = 2)]
     pub new_id: String,
+
+    /// Why the ref is being updated, for its reflog
+    #[clap(short = "m", default_value = "")]
+    pub message: String,
 }
@@ -0,0 +1,526 @@
+//! Reading pack bitmaps: the `.bitmap` files git can write next to a pack.
+//! For some of the commits in the pack, a bitmap holds the set of every object
+//! reachable from that commit, with one bit per object in the pack, in the
+//! order the objects are in the pack. What is reachable from a set of commits
+//! can then be found by walking only as far as the nearest commits with
+//! bitmaps, rather than through the whole history.
+//!
+//! The file is in version 1 of git's format: a header, bitmaps of which
+//! objects are commits, trees, blobs and tags, then the bitmaps of the
+//! commits. Each bitmap is compressed with EWAH, and the bitmap of a commit
+//! may be stored XORed with one of the commits before it.
+use anyhow::{anyhow, Context, Error, Result};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::objects::{FileMode, Id, Object, ObjectType, Repo};
+use crate::packs::Pack;
+use crate::revwalk;
+
+const SIGNATURE: &[u8; 4] = b"BITM";
+/// Set when the file has a name hash for every object after the bitmaps
+const OPT_HASH_CACHE: u16 = 0x4;
+/// XORs may only refer this many commit bitmaps back
+const MAX_XOR_OFFSET: usize = 160;
+
+/// Errors from reading bitmaps
+#[derive(Error, Debug)]
+pub enum BitmapError {
+    /// The magic bytes at the top of the file are wrong
+    #[error("Bad magic in {0}")]
+    BadMagic(PathBuf),
+
+    /// The file is in a version we can't read
+    #[error("Unsupported version {1} of {0}")]
+    UnsupportedVersion(PathBuf, u16),
+
+    /// The file ended early
+    #[error("{0} is truncated")]
+    Truncated(PathBuf),
+
+    /// The bitmap was written for another version of its pack
+    #[error("{0} doesn't match its pack")]
+    WrongPack(PathBuf),
+}
+
+/// A set of objects in a pack, as a bit for each
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    /// Makes an empty bitmap
+    pub fn new() -> Bitmap {
+        Bitmap::default()
+    }
+
+    /// Is bit `n` set?
+    pub fn get(&self, n: usize) -> bool {
+        self.words
+            .get(n / 64)
+            .map_or(false, |word| word & (1 << (n % 64)) != 0)
+    }
+
+    /// Sets bit `n`
+    pub fn set(&mut self, n: usize) {
+        if self.words.len() <= n / 64 {
+            self.words.resize(n / 64 + 1, 0);
+        }
+        self.words[n / 64] |= 1 << (n % 64);
+    }
+
+    /// Sets every bit that is set in `other`
+    pub fn or(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Clears every bit that is set in `other`
+    pub fn and_not(&mut self, other: &Bitmap) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    /// Flips every bit that is set in `other`
+    fn xor(&mut self, other: &Bitmap) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word ^= other;
+        }
+    }
+
+    /// Counts the bits that are set
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    /// Iterates over the bits that are set, lowest first
+    pub fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(n, &word)| {
+            (0..64)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| n * 64 + bit)
+        })
+    }
+}
+
+/// Reads the bitmap at `*at` in `data`, moving `*at` past it.
+///
+/// EWAH stores the bit count, the number of 64-bit words, the words, then
+/// where the last marker word is, which is only needed for appending. Each
+/// marker word holds a bit to repeat, how many words of it to repeat in the
+/// next 32 bits, then how many words to take as they are in the top 31 bits.
+fn read_ewah(data: &[u8], at: &mut usize) -> Option<Bitmap> {
+    let word_count = u32::from_be_bytes(data.get(*at + 4..*at + 8)?.try_into().unwrap()) as usize;
+    let words = data.get(*at + 8..*at + 8 + word_count * 8)?;
+    *at += 8 + word_count * 8 + 4;
+    if data.len() < *at {
+        return None;
+    }
+
+    let word = |n: usize| u64::from_be_bytes(words[n * 8..n * 8 + 8].try_into().unwrap());
+    let mut bitmap = Bitmap::new();
+    let mut n = 0;
+    while n < word_count {
+        let marker = word(n);
+        let fill = if marker & 1 != 0 { !0 } else { 0 };
+        let run = ((marker >> 1) & 0xffff_ffff) as usize;
+        let literals = (marker >> 33) as usize;
+        if n + literals >= word_count {
+            return None;
+        }
+        let len = bitmap.words.len();
+        bitmap.words.resize(len + run, fill);
+        bitmap.words.extend((n + 1..n + 1 + literals).map(word));
+        n += 1 + literals;
+    }
+    Some(bitmap)
+}
+
+/// The bitmap of a pack, along with what it needs from the pack's index
+pub struct PackBitmap {
+    /// IDs of the objects in the pack, in the order they are stored in it
+    ids: Vec<Id>,
+    /// where each ID is in `ids`
+    positions: HashMap<Id, usize>,
+    /// which objects are commits, trees, blobs and tags
+    types: [(ObjectType, Bitmap); 4],
+    /// what is reachable from each of the commits with bitmaps
+    commits: HashMap<Id, Bitmap>,
+}
+
+impl PackBitmap {
+    /// Reads the bitmap at `path` of `pack`
+    pub fn load(path: &Path, data: &[u8], pack: &Pack) -> Result<PackBitmap> {
+        let truncated = || Error::new(BitmapError::Truncated(path.to_owned()));
+        if data.len() < 32 || &data[..4] != SIGNATURE {
+            return Err(Error::new(BitmapError::BadMagic(path.to_owned())));
+        }
+        let version = u16::from_be_bytes(data[4..6].try_into().unwrap());
+        if version != 1 {
+            return Err(Error::new(BitmapError::UnsupportedVersion(
+                path.to_owned(),
+                version,
+            )));
+        }
+        let flags = u16::from_be_bytes(data[6..8].try_into().unwrap());
+        let count = u32::from_be_bytes(data[8..12].try_into().unwrap()) as usize;
+        if data[12..32] != pack.checksum().as_bytes()[..] {
+            return Err(Error::new(BitmapError::WrongPack(path.to_owned())));
+        }
+
+        // bits are in the order objects are in the pack, while the index has
+        // them sorted by ID
+        let mut order: Vec<usize> = (0..pack.len()).collect();
+        order.sort_unstable_by_key(|&n| pack.offset_at(n));
+        let ids: Vec<Id> = order.iter().map(|&n| pack.id_at(n)).collect();
+        let positions = ids.iter().enumerate().map(|(n, id)| (*id, n)).collect();
+
+        let mut at = 32;
+        let mut types = [
+            (ObjectType::Commit, Bitmap::new()),
+            (ObjectType::Tree, Bitmap::new()),
+            (ObjectType::Blob, Bitmap::new()),
+            (ObjectType::Tag, Bitmap::new()),
+        ];
+        for (_, bitmap) in types.iter_mut() {
+            *bitmap = read_ewah(data, &mut at).ok_or_else(truncated)?;
+        }
+
+        let mut entries: Vec<(Id, Bitmap)> = Vec::with_capacity(count.min(data.len() / 6));
+        for _ in 0..count {
+            let header = data.get(at..at + 6).ok_or_else(truncated)?;
+            let n = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+            let xor_offset = header[4] as usize;
+            at += 6;
+            let mut bitmap = read_ewah(data, &mut at).ok_or_else(truncated)?;
+            if n >= pack.len() || xor_offset > MAX_XOR_OFFSET || xor_offset > entries.len() {
+                return Err(anyhow!("{} has a corrupt entry", path.display()));
+            }
+            if xor_offset != 0 {
+                bitmap.xor(&entries[entries.len() - xor_offset].1);
+            }
+            entries.push((pack.id_at(n), bitmap));
+        }
+        if flags & OPT_HASH_CACHE != 0 && data.len() < at + pack.len() * 4 + 20 {
+            return Err(truncated());
+        }
+
+        Ok(PackBitmap {
+            ids,
+            positions,
+            types,
+            commits: entries.into_iter().collect(),
+        })
+    }
+
+    /// Finds the pack in the repository that has a bitmap, and reads it
+    pub fn open(repo: &Repo) -> Result<Option<PackBitmap>> {
+        let vfs = repo.vfs();
+        let dir = repo.objects_dir().join("pack");
+        let names = match vfs.read_dir(&dir) {
+            Ok(names) => names,
+            Err(_) => return Ok(None),
+        };
+        for name in names {
+            let path = dir.join(name);
+            if path.extension().map_or(true, |ext| ext != "bitmap") {
+                continue;
+            }
+            let idx_path = path.with_extension("idx");
+            if !vfs.exists(&idx_path) {
+                continue;
+            }
+            let pack = Pack::open(&idx_path, vfs)?;
+            let data = vfs
+                .read(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            return PackBitmap::load(&path, &data, &pack).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Number of commits that have bitmaps
+    pub fn commit_count(&self) -> usize {
+        self.commits.len()
+    }
+
+    fn kind_at(&self, n: usize) -> Option<ObjectType> {
+        self.types
+            .iter()
+            .find(|(_, bitmap)| bitmap.get(n))
+            .map(|(kind, _)| *kind)
+    }
+
+    /// Finds every object reachable from `tips`, walking from them until
+    /// commits with bitmaps are reached. Gives nothing if something reachable
+    /// isn't in the pack, so can't be in a bitmap.
+    pub fn reachable(&self, repo: &Repo, tips: &[Id]) -> Result<Option<Bitmap>> {
+        let mut found = Bitmap::new();
+        // commits and tags first, so that as many trees as possible are
+        // already in the bitmaps of older commits
+        let mut stack = tips.to_vec();
+        let mut trees = Vec::new();
+        while let Some(id) = stack.pop() {
+            let n = match self.positions.get(&id) {
+                Some(&n) => n,
+                None => return Ok(None),
+            };
+            if found.get(n) {
+                continue;
+            }
+            if let Some(bitmap) = self.commits.get(&id) {
+                found.or(bitmap);
+                continue;
+            }
+            found.set(n);
+            match self.kind_at(n) {
+                Some(ObjectType::Commit) => match &*repo.open_shared(&id)? {
+                    Object::Commit(commit) => {
+                        stack.extend(&commit.parents);
+                        trees.push(commit.tree);
+                    }
+                    _ => return Err(anyhow!("{} is not a commit", id)),
+                },
+                Some(ObjectType::Tag) => {
+                    let (_, content) = repo.read_object(&id)?;
+                    stack.push(
+                        revwalk::tag_target(&content)
+                            .with_context(|| format!("tag {} has no target", id))?,
+                    );
+                }
+                Some(ObjectType::Tree) => trees.push(id),
+                Some(ObjectType::Blob) => {}
+                None => return Err(anyhow!("{} has no type in the bitmap", id)),
+            }
+        }
+
+        while let Some(id) = trees.pop() {
+            let n = match self.positions.get(&id) {
+                Some(&n) => n,
+                None => return Ok(None),
+            };
+            if found.get(n) {
+                continue;
+            }
+            found.set(n);
+            if let Object::Tree(tree) = &*repo.open_shared(&id)? {
+                for file in &tree.files {
+                    match file.mode {
+                        FileMode::Directory => trees.push(file.id),
+                        // submodule commits live in another repository
+                        FileMode::Gitlink => {}
+                        _ => match self.positions.get(&file.id) {
+                            Some(&n) => found.set(n),
+                            None => return Ok(None),
+                        },
+                    }
+                }
+            }
+        }
+        Ok(Some(found))
+    }
+
+    /// Counts the objects of a type in a bitmap
+    pub fn count_of(&self, bitmap: &Bitmap, kind: ObjectType) -> usize {
+        let mut of_kind = bitmap.clone();
+        for (other, type_bitmap) in &self.types {
+            if *other != kind {
+                of_kind.and_not(type_bitmap);
+            }
+        }
+        of_kind.count_ones()
+    }
+
+    /// Lists the objects in a bitmap, in the order they are in the pack
+    pub fn objects<'a>(
+        &'a self,
+        bitmap: &'a Bitmap,
+    ) -> impl Iterator<Item = (Id, ObjectType)> + 'a {
+        bitmap
+            .ones()
+            .filter_map(move |n| Some((*self.ids.get(n)?, self.kind_at(n)?)))
+    }
+}
+
+/// Finds every object reachable from `tips` but not from `exclude` using the
+/// repository's bitmap. Gives nothing if there is no bitmap or it doesn't
+/// cover everything, in which case the objects have to be found by walking.
+pub fn reachable_objects(
+    repo: &Repo,
+    tips: &[Id],
+    exclude: &[Id],
+) -> Result<Option<(PackBitmap, Bitmap)>> {
+    let bitmap = match PackBitmap::open(repo)? {
+        Some(bitmap) => bitmap,
+        None => return Ok(None),
+    };
+    let mut found = match bitmap.reachable(repo, tips)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    match bitmap.reachable(repo, exclude)? {
+        Some(excluded) => found.and_not(&excluded),
+        None => return Ok(None),
+    }
+    Ok(Some((bitmap, found)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reachable_objects, read_ewah, Bitmap, PackBitmap};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, ObjectType, Repo, Tree};
+    use crate::packs::{store_pack, Pack};
+    use crate::revwalk::reachable_objects as walk_reachable;
+    use crate::vfs::{MemoryFs, Vfs};
+    use std::sync::Arc;
+
+    /// Encodes a bitmap in EWAH, as one marker followed by literal words
+    fn ewah(bitmap: &Bitmap) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(&((bitmap.words.len() * 64) as u32).to_be_bytes());
+        out.extend(&(bitmap.words.len() as u32 + 1).to_be_bytes());
+        out.extend(&((bitmap.words.len() as u64) << 33).to_be_bytes());
+        for word in &bitmap.words {
+            out.extend(&word.to_be_bytes());
+        }
+        out.extend(&0u32.to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn test_read_ewah() {
+        // a run of two words of ones, then a literal word
+        let mut data = vec![0, 0, 0, 192, 0, 0, 0, 2];
+        data.extend(&((1u64 << 33) | (2 << 1) | 1).to_be_bytes());
+        data.extend(&5u64.to_be_bytes());
+        data.extend(&[0; 4]);
+        let mut at = 0;
+        let bitmap = read_ewah(&data, &mut at).unwrap();
+        assert_eq!(at, data.len());
+        assert_eq!(bitmap.count_ones(), 130);
+        assert!(bitmap.get(127) && bitmap.get(128) && !bitmap.get(129) && bitmap.get(130));
+
+        let mut at = 0;
+        assert_eq!(read_ewah(&ewah(&bitmap), &mut at), Some(bitmap));
+        assert_eq!(read_ewah(&data[..20], &mut 0), None);
+    }
+
+    #[test]
+    fn test_reachable() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        // each commit adds a file
+        let mut commits = Vec::new();
+        let mut blobs = Vec::new();
+        for n in 0..4 {
+            blobs.push(
+                repo.store(&Blob::new(format!("file {}\n", n).into_bytes()))
+                    .unwrap(),
+            );
+            let files = blobs
+                .iter()
+                .enumerate()
+                .map(|(n, &id)| File {
+                    mode: FileMode::Regular,
+                    name: format!("file{}", n).into(),
+                    id,
+                })
+                .collect();
+            let tree = repo.store(&Tree { files }).unwrap();
+            let commit = repo
+                .store(&Commit {
+                    tree,
+                    parents: commits.last().copied().into_iter().collect(),
+                    author: who.clone(),
+                    committer: who.clone(),
+                    message: format!("commit {}\n", n),
+                })
+                .unwrap();
+            commits.push(commit);
+        }
+        let everything = walk_reachable(&repo, &commits[3..], &[]).unwrap();
+        let name = store_pack(&repo, &everything).unwrap();
+        let dir = repo.objects_dir().join("pack");
+        let idx = dir.join(format!("pack-{}.idx", name));
+        let pack = Pack::open(&idx, &*fs).unwrap();
+
+        // bitmaps for the second commit, and the fourth XORed with it
+        let mut order: Vec<usize> = (0..pack.len()).collect();
+        order.sort_by_key(|&n| pack.offset_at(n));
+        let position = |id: &Id| order.iter().position(|&n| pack.id_at(n) == *id).unwrap();
+        let bitmap_of = |objects: &[crate::revwalk::FoundObject]| {
+            let mut bitmap = Bitmap::new();
+            for obj in objects {
+                bitmap.set(position(&obj.id));
+            }
+            bitmap
+        };
+        let second = bitmap_of(&walk_reachable(&repo, &commits[1..2], &[]).unwrap());
+        let mut fourth = bitmap_of(&everything);
+        fourth.xor(&second);
+
+        let mut data = b"BITM\0\x01\0\0\0\0\0\x02".to_vec();
+        data.extend(pack.checksum().as_bytes());
+        for kind in &[
+            ObjectType::Commit,
+            ObjectType::Tree,
+            ObjectType::Blob,
+            ObjectType::Tag,
+        ] {
+            let of_kind: Vec<_> = everything
+                .iter()
+                .filter(|obj| obj.kind == *kind)
+                .cloned()
+                .collect();
+            data.extend(ewah(&bitmap_of(&of_kind)));
+        }
+        let sorted_position = |id: &Id| pack.ids().position(|other| other == *id).unwrap();
+        data.extend(&(sorted_position(&commits[1]) as u32).to_be_bytes());
+        data.extend(&[0, 0]);
+        data.extend(ewah(&second));
+        data.extend(&(sorted_position(&commits[3]) as u32).to_be_bytes());
+        data.extend(&[1, 0]);
+        data.extend(ewah(&fourth));
+        data.extend(&[0; 20]);
+        fs.write(&idx.with_extension("bitmap"), &data).unwrap();
+
+        let bitmap = PackBitmap::open(&repo).unwrap().unwrap();
+        assert_eq!(bitmap.commit_count(), 2);
+        let (bitmap, found) = reachable_objects(&repo, &commits[2..3], &commits[..1])
+            .unwrap()
+            .unwrap();
+        let mut ids: Vec<_> = bitmap.objects(&found).map(|(id, _)| id).collect();
+        let mut expected: Vec<_> = walk_reachable(&repo, &commits[2..3], &commits[..1])
+            .unwrap()
+            .into_iter()
+            .map(|obj| obj.id)
+            .collect();
+        ids.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(ids, expected);
+        assert_eq!(bitmap.count_of(&found, ObjectType::Commit), 2);
+
+        // the XORed bitmap gives everything
+        let found = bitmap.reachable(&repo, &commits[3..]).unwrap().unwrap();
+        assert_eq!(found.count_ones(), everything.len());
+
+        // objects outside the pack can't be answered for
+        let loose = repo.store(&Blob::new(b"loose\n".to_vec())).unwrap();
+        assert!(bitmap.reachable(&repo, &[loose]).unwrap().is_none());
+    }
+}
@@ -0,0 +1,269 @@
+//! Blame, which finds the commit each line of a file came from. Starting from
+//! a commit, each line is passed back to a parent that has it too, going by
+//! the diff between the parent's version of the file and ours, until it
+//! reaches a commit none of whose parents have it. That commit added it.
+use anyhow::{anyhow, Result};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use crate::diff::{self, Edit};
+use crate::objects::{Commit, FileMode, Id, Object, Repo};
+use crate::tree;
+
+/// A line of the blamed file, and where it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlameLine {
+    /// Line number in the blamed file, from 1
+    pub line: usize,
+    /// The commit that added the line
+    pub commit: Id,
+    /// Line number in the file as of that commit, from 1
+    pub orig_line: usize,
+}
+
+/// A line that is still being traced back: its index in the blamed file and
+/// in the version of the file at the commit it's waiting at
+type Pending = (usize, usize);
+
+/// Finds where the lines of `path` as of the commit `start` came from.
+/// `ranges` limit which lines are blamed to inclusive ranges of line numbers
+/// from 1, which go to the end of the file if they have no end, or blame all
+/// of them if it is empty. Lines are returned in order.
+pub fn blame(
+    start: &Id,
+    path: &[u8],
+    ranges: &[(usize, Option<usize>)],
+    repo: &Repo,
+) -> Result<Vec<BlameLine>> {
+    let commit = open_commit(start, repo)?;
+    let blob = blob_at(&commit, path, repo)?.ok_or_else(|| {
+        anyhow!(
+            "no such path {} in {}",
+            String::from_utf8_lossy(path),
+            start
+        )
+    })?;
+    let count = diff::lines(&repo.read_object(&blob)?.1).len();
+    let mut lines = Vec::new();
+    for &(first, last) in ranges {
+        let last = last.unwrap_or(count);
+        if first > count || last > count {
+            return Err(anyhow!(
+                "file {} has only {} lines",
+                String::from_utf8_lossy(path),
+                count
+            ));
+        }
+        if first == 0 || first > last {
+            return Err(anyhow!("invalid line range {},{}", first, last));
+        }
+        lines.extend(first - 1..last);
+    }
+    if ranges.is_empty() {
+        lines.extend(0..count);
+    }
+    lines.sort();
+    lines.dedup();
+
+    let mut blamed = Vec::with_capacity(lines.len());
+    let mut pending = HashMap::<Id, Vec<Pending>>::new();
+    pending.insert(*start, lines.iter().map(|&line| (line, line)).collect());
+    // the newest commit goes first, so that all of a commit's children have
+    // passed it their lines by the time it is looked at
+    let mut queue = BinaryHeap::new();
+    let mut queued = HashSet::new();
+    let mut order = vec![*start];
+    queue.push((commit.committer.time.timestamp(), Reverse(0)));
+    queued.insert(*start);
+
+    while let Some((_, Reverse(index))) = queue.pop() {
+        let id = order[index];
+        queued.remove(&id);
+        let mut waiting = pending.remove(&id).unwrap_or_default();
+        let commit = open_commit(&id, repo)?;
+        let ours =
+            blob_at(&commit, path, repo)?.expect("lines were passed to a commit without the file");
+
+        let mut parents = Vec::new();
+        for parent in &commit.parents {
+            let parent_commit = open_commit(parent, repo)?;
+            if let Some(blob) = blob_at(&parent_commit, path, repo)? {
+                parents.push((*parent, parent_commit.committer.time.timestamp(), blob));
+            }
+        }
+        let mut passed = Vec::new();
+        if let Some(&(parent, time, _)) = parents.iter().find(|(_, _, blob)| *blob == ours) {
+            // a parent with the very same file gets every line
+            passed.push((parent, time, waiting));
+            waiting = Vec::new();
+        } else {
+            let content = repo.read_object(&ours)?.1;
+            let new = diff::lines(&content);
+            for (parent, time, blob) in parents {
+                if waiting.is_empty() {
+                    break;
+                }
+                let content = repo.read_object(&blob)?.1;
+                let map = line_map(&diff::lines(&content), &new);
+                let (found, kept) = waiting
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|&(_, line)| map[line].is_some());
+                let found = found
+                    .into_iter()
+                    .map(|(line, ours)| (line, map[ours].unwrap()))
+                    .collect();
+                passed.push((parent, time, found));
+                waiting = kept;
+            }
+        }
+
+        // whatever no parent had, this commit added
+        blamed.extend(waiting.into_iter().map(|(line, orig)| BlameLine {
+            line: line + 1,
+            commit: id,
+            orig_line: orig + 1,
+        }));
+        for (parent, time, lines) in passed {
+            if lines.is_empty() {
+                continue;
+            }
+            pending.entry(parent).or_default().extend(lines);
+            if queued.insert(parent) {
+                order.push(parent);
+                queue.push((time, Reverse(order.len() - 1)));
+            }
+        }
+    }
+
+    blamed.sort_by_key(|line| line.line);
+    Ok(blamed)
+}
+
+fn open_commit(id: &Id, repo: &Repo) -> Result<Commit> {
+    match repo.open(id)? {
+        Object::Commit(commit) => Ok(commit),
+        _ => Err(anyhow!("{} is not a commit", id)),
+    }
+}
+
+/// The blob at `path` in a commit, if it has a file there
+fn blob_at(commit: &Commit, path: &[u8], repo: &Repo) -> Result<Option<Id>> {
+    Ok(match tree::entry_at(&commit.tree, path, repo)? {
+        Some((id, mode)) if !mode.is_dir() && mode != FileMode::Gitlink => Some(id),
+        _ => None,
+    })
+}
+
+/// For each of the new lines, the index of the old line it is if it was
+/// kept from the old lines
+fn line_map(old: &[&[u8]], new: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut map = Vec::with_capacity(new.len());
+    let mut old_index = 0;
+    for edit in diff::myers_diff(old, new) {
+        match edit {
+            Edit::Nop(_) => {
+                map.push(Some(old_index));
+                old_index += 1;
+            }
+            Edit::Del(_) => old_index += 1,
+            Edit::Ins(_) => map.push(None),
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlameLine;
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+
+    #[test]
+    fn test_blame() {
+        let repo = Repo::in_memory();
+        let commit = |parents: Vec<Id>, content: &str, time: i64| {
+            let blob = repo.store(&Blob::new(content.as_bytes().to_vec())).unwrap();
+            let sub = repo
+                .store(&Tree {
+                    files: vec![File {
+                        mode: FileMode::Regular,
+                        name: "file".into(),
+                        id: blob,
+                    }],
+                })
+                .unwrap();
+            let tree = repo
+                .store(&Tree {
+                    files: vec![File {
+                        mode: FileMode::Directory,
+                        name: "dir".into(),
+                        id: sub,
+                    }],
+                })
+                .unwrap();
+            let who = NameEntry::from(&format!("a <a@example.com> {} +0000", time)).unwrap();
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: format!("{}\n", time),
+            })
+            .unwrap()
+        };
+        let one = commit(vec![], "a\nb\nc\n", 1);
+        let two = commit(vec![one], "a\nB\nc\nd\n", 2);
+        let three = commit(vec![two], "x\na\nB\nc\nd\n", 3);
+        let side = commit(vec![one], "a\nb\nC\n", 4);
+        let merge = commit(vec![three, side], "x\na\nB\nC\nd\n", 5);
+        // a merge that changes nothing passes everything to its parent
+        let same = commit(vec![side, merge], "x\na\nB\nC\nd\n", 6);
+
+        let blame = |id: &Id, ranges: &[(usize, Option<usize>)]| {
+            super::blame(id, b"dir/file", ranges, &repo)
+                .unwrap()
+                .into_iter()
+                .map(
+                    |BlameLine {
+                         line,
+                         commit,
+                         orig_line,
+                     }| (line, commit, orig_line),
+                )
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            blame(&three, &[]),
+            vec![
+                (1, three, 1),
+                (2, one, 1),
+                (3, two, 2),
+                (4, one, 3),
+                (5, two, 4)
+            ]
+        );
+        let merged = vec![
+            (1, three, 1),
+            (2, one, 1),
+            (3, two, 2),
+            (4, side, 3),
+            (5, two, 4),
+        ];
+        assert_eq!(blame(&merge, &[]), merged);
+        assert_eq!(blame(&same, &[]), merged);
+        assert_eq!(
+            blame(&merge, &[(4, Some(5)), (3, Some(4))]),
+            merged[2..].to_vec()
+        );
+        assert_eq!(blame(&merge, &[(4, None)]), merged[3..].to_vec());
+
+        let error = |ranges: &[(usize, Option<usize>)]| {
+            super::blame(&three, b"dir/file", ranges, &repo)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error(&[(4, Some(6))]), "file dir/file has only 5 lines");
+        assert_eq!(error(&[(6, None)]), "file dir/file has only 5 lines");
+        assert_eq!(error(&[(3, Some(2))]), "invalid line range 3,2");
+        assert!(super::blame(&three, b"dir", &[], &repo).is_err());
+    }
+}
@@ -0,0 +1,634 @@
+//! Writing the files of a tree out to the working tree. Inflating and writing
+//! blobs is done by a pool of workers, since on repositories with tens of
+//! thousands of files that is where nearly all the time goes.
+//!
+//! Trees can come from anywhere, so every path is checked before anything is
+//! written, the same way git does, to make sure a malicious tree can't write
+//! outside the worktree or into `.git`.
+use anyhow::{anyhow, Context, Error, Result};
+use bstr::{BString, ByteSlice};
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+use crate::config::Config;
+use crate::index::{Index, IndexEntry, IndexMeta};
+use crate::objects::{FileMode, Id, ObjectType, Repo, Tree};
+use crate::tree::load_tree_from_disk;
+use crate::util::to_platform_path;
+use crate::vfs::FileType;
+
+/// Errors from checking out files
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CheckoutError {
+    /// A path in the tree could escape the worktree or write into `.git`
+    #[error("Refusing to check out unsafe path {0:?}")]
+    UnsafePath(BString),
+
+    /// Checking out would lose changes to these files that haven't been
+    /// committed, or overwrite untracked files
+    #[error("Your local changes to these files would be overwritten: {}", .0.iter().map(|p| p.to_str_lossy()).collect::<Vec<_>>().join(", "))]
+    WouldOverwrite(Vec<BString>),
+}
+
+/// Which filesystems' quirks to guard against when checking paths, as git
+/// does with core.protectNTFS and core.protectHFS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathProtection {
+    /// Reject names that NTFS would treat as `.git` or that use alternate
+    /// data streams or backslashes
+    pub ntfs: bool,
+    /// Reject names that HFS+ would treat as `.git` because it ignores some
+    /// Unicode code points
+    pub hfs: bool,
+}
+
+impl PathProtection {
+    /// Reads the protections from the config. Like git, NTFS protection is on
+    /// everywhere by default, since repositories get shared with Windows
+    /// machines, and HFS protection is on by default on macOS.
+    pub fn from_config(config: &Config) -> Result<PathProtection> {
+        Ok(PathProtection {
+            ntfs: config.get_bool("core.protectntfs")?.unwrap_or(true),
+            hfs: config
+                .get_bool("core.protecthfs")?
+                .unwrap_or(cfg!(target_os = "macos")),
+        })
+    }
+}
+
+/// Is this path component `.git` if you ignore case, as every filesystem we
+/// care about can be made to?
+fn is_dotgit(component: &[u8]) -> bool {
+    component.eq_ignore_ascii_case(b".git")
+}
+
+/// Is this path component `.git` to NTFS, which drops trailing dots and
+/// spaces and also knows it by its 8.3 short name?
+fn is_ntfs_dotgit(component: &[u8]) -> bool {
+    let trimmed = component.trim_end_with(|c| c == '.' || c == ' ');
+    is_dotgit(trimmed) || trimmed.eq_ignore_ascii_case(b"git~1")
+}
+
+/// Is this one of the zero width or directional formatting code points that
+/// HFS+ ignores in file names?
+fn is_hfs_ignorable(c: char) -> bool {
+    match c {
+        '\u{200c}'..='\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{206a}'..='\u{206f}' => true,
+        '\u{feff}' => true,
+        _ => false,
+    }
+}
+
+/// Is this path component `.git` to HFS+?
+fn is_hfs_dotgit(component: &[u8]) -> bool {
+    let stripped = component
+        .chars()
+        .filter(|&c| !is_hfs_ignorable(c))
+        .collect::<String>();
+    is_dotgit(stripped.as_bytes())
+}
+
+/// Is this path component a name Windows reserves for a device, such as `NUL`
+/// or `com1.txt`? Writing to these writes to the device instead of a file.
+fn is_windows_device_name(component: &[u8]) -> bool {
+    // extensions and trailing spaces don't stop them being devices
+    let base = match component.find_byte(b'.') {
+        Some(dot) => &component[..dot],
+        None => component,
+    };
+    let base = base.trim_end_with(|c| c == ' ');
+    match base.len() {
+        3 => [&b"con"[..], b"prn", b"aux", b"nul"]
+            .iter()
+            .any(|name| base.eq_ignore_ascii_case(name)),
+        4 => {
+            (base[..3].eq_ignore_ascii_case(b"com") || base[..3].eq_ignore_ascii_case(b"lpt"))
+                && (b'1'..=b'9').contains(&base[3])
+        }
+        _ => false,
+    }
+}
+
+/// Checks that a repo-relative path from a tree is safe to write: it must be
+/// relative, stay inside the worktree and not go into `.git`.
+pub fn verify_path(path: &[u8], protect: PathProtection) -> bool {
+    if path.is_empty() {
+        return false;
+    }
+    path.split(|&b| b == b'/').all(|component| {
+        if component.is_empty() || component == b"." || component == b".." {
+            return false;
+        }
+        if is_dotgit(component) {
+            return false;
+        }
+        if protect.ntfs {
+            // alternate data streams and drive letters, other separators, and
+            // names that are only dots once NTFS trims them
+            if component.contains(&b':') || component.contains(&b'\\') {
+                return false;
+            }
+            if component.iter().all(|&b| b == b'.' || b == b' ') || is_ntfs_dotgit(component) {
+                return false;
+            }
+        }
+        if cfg!(windows) && is_windows_device_name(component) {
+            return false;
+        }
+        !(protect.hfs && is_hfs_dotgit(component))
+    })
+}
+
+/// Below this many files, starting workers costs more than it saves. Same as
+/// git's default checkout.thresholdForParallelism.
+const PARALLEL_THRESHOLD: usize = 100;
+
+/// How many workers to write files with, from `checkout.workers`. Like git, a
+/// value below one means one per CPU, but we also use one per CPU if it isn't
+/// set at all.
+pub fn workers(config: &Config) -> Result<usize> {
+    match config.get_int("checkout.workers")? {
+        Some(n) if n >= 1 => Ok(n as usize),
+        _ => Ok(rayon::current_num_threads()),
+    }
+}
+
+/// Writes one blob to a repo-relative path, replacing whatever was there.
+/// Symlinks are made as links to the blob's content. Submodules aren't
+/// checked out: like git, they get an empty directory and keep the commit in
+/// the index.
+pub(crate) fn write_file(name: &[u8], id: Id, mode: FileMode, repo: &Repo) -> Result<IndexEntry> {
+    if mode == FileMode::Gitlink {
+        let path = repo.tree_root().join(to_platform_path(name));
+        repo.vfs()
+            .create_dir_all(&path)
+            .with_context(|| format!("failed to make directory {}", path.display()))?;
+        return Ok(IndexEntry {
+            name: name.into(),
+            meta: IndexMeta::for_blob(name, id, mode),
+        });
+    }
+    let (objtype, content) = repo.read_object(&id)?;
+    if objtype != ObjectType::Blob {
+        return Err(anyhow!("{} is a {:?}, not a blob", id, objtype));
+    }
+    write_content(name, &content, mode, repo)?;
+    Ok(IndexEntry {
+        name: name.into(),
+        meta: IndexMeta::for_stored_file(name, id, repo)?,
+    })
+}
+
+/// Writes content to a repo-relative path as a file of the given mode,
+/// replacing whatever was there
+pub(crate) fn write_content(
+    name: &[u8],
+    content: &[u8],
+    mode: FileMode,
+    repo: &Repo,
+) -> Result<()> {
+    let path = repo.tree_root().join(to_platform_path(name));
+    let vfs = repo.vfs();
+    // writing through an existing symlink would change its target instead
+    vfs.remove_file(&path)
+        .or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Ok(()),
+            _ => Err(e),
+        })
+        .with_context(|| format!("failed to remove {}", path.display()))?;
+    if mode == FileMode::Symlink {
+        vfs.symlink(&to_platform_path(content), &path)
+            .with_context(|| format!("failed to make symlink {}", path.display()))?;
+    } else {
+        vfs.write(&path, content)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        if mode == FileMode::Executable {
+            vfs.set_executable(&path, true)
+                .with_context(|| format!("failed to make {} executable", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes any symlinks on disk where the tree has the directories leading up
+/// to `dir`, since making the directories would follow them and files would be
+/// written wherever they point
+pub(crate) fn remove_leading_symlinks(dir: &Path, repo: &Repo) -> Result<()> {
+    let mut leading = dir.ancestors().collect::<Vec<_>>();
+    leading.reverse();
+    for path in leading.into_iter().skip(1) {
+        let path = repo.tree_root().join(path);
+        match repo.vfs().symlink_metadata(&path) {
+            Ok(meta) if meta.file_type == FileType::Symlink => repo
+                .vfs()
+                .remove_file(&path)
+                .with_context(|| format!("failed to remove symlink {}", path.display()))?,
+            Ok(_) => (),
+            // nothing further down can exist either
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Writes every file in `tree` to the working tree, overwriting any that are
+/// already there, and returns an index describing them. Files that aren't in
+/// the tree are left alone.
+pub fn checkout_tree(tree: &Tree, workers: usize, repo: &Repo) -> Result<Index> {
+    let mut files = Vec::new();
+    load_tree_from_disk(tree, repo, b"", &mut files)?;
+    Ok(checkout_files(&files, workers, repo)?.into())
+}
+
+/// Writes blobs to repo-relative paths in the working tree, overwriting any
+/// files already there, and returns index entries for them sorted by name.
+/// Submodules are left as empty directories.
+pub fn checkout_files(
+    files: &[(BString, Id, FileMode)],
+    workers: usize,
+    repo: &Repo,
+) -> Result<Vec<IndexEntry>> {
+    // nothing is written unless every path is safe
+    let protect = PathProtection::from_config(&repo.config()?)?;
+    if let Some((name, _, _)) = files
+        .iter()
+        .find(|(name, _, _)| !verify_path(name, protect))
+    {
+        return Err(Error::new(CheckoutError::UnsafePath(name.clone())));
+    }
+
+    // directories are made up front so the workers don't race to make them
+    let dirs = files
+        .iter()
+        .filter_map(|(name, _, _)| to_platform_path(name).parent().map(Path::to_path_buf))
+        .filter(|dir| dir != Path::new(""))
+        .collect::<BTreeSet<_>>();
+    let tree_root = repo.tree_root();
+    for dir in dirs {
+        remove_leading_symlinks(&dir, repo)?;
+        repo.vfs().create_dir_all(&tree_root.join(dir))?;
+    }
+
+    let workers = if files.len() < PARALLEL_THRESHOLD {
+        1
+    } else {
+        workers
+    };
+    debug!(
+        "checking out {} files with {} workers",
+        files.len(),
+        workers
+    );
+
+    let pool = ThreadPoolBuilder::new().num_threads(workers).build()?;
+    let mut entries = pool.install(|| {
+        files
+            .par_iter()
+            .map(|(name, id, mode)| write_file(name, *id, *mode, repo))
+            .collect::<Result<Vec<_>>>()
+    })?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// Finds the files that checking out `tree` over the index and working tree
+/// would lose something in. Files are all rewritten on checkout, so that is
+/// any tracked file that is changed in the working tree, or in the index from
+/// `head` unless `tree` has what's in the index. Untracked files where `tree`
+/// has one count too.
+pub fn overwritten_files(
+    index: &Index,
+    head: Option<&Tree>,
+    tree: &Tree,
+    repo: &Repo,
+) -> Result<Vec<BString>> {
+    let mut head_files = Vec::new();
+    if let Some(head) = head {
+        load_tree_from_disk(head, repo, b"", &mut head_files)?;
+    }
+    let head_files = head_files
+        .into_iter()
+        .map(|(name, id, _)| (name, id))
+        .collect::<HashMap<_, _>>();
+    let mut files = Vec::new();
+    load_tree_from_disk(tree, repo, b"", &mut files)?;
+
+    let tree_root = repo.tree_root();
+    let on_disk = |name: &[u8]| {
+        repo.vfs()
+            .symlink_metadata(&tree_root.join(to_platform_path(name)))
+            .map_or(false, |meta| meta.file_type != FileType::Dir)
+    };
+    let mut overwritten = Vec::new();
+    for entry in index.iter() {
+        let staged = head_files.get(&entry.name) != Some(&entry.meta.id);
+        let in_tree = files
+            .binary_search_by(|(name, _, _)| name.cmp(&entry.name))
+            .map_or(false, |n| files[n].1 == entry.meta.id);
+        // a file deleted from the working tree has nothing to lose
+        let modified = on_disk(&entry.name) && !entry.is_same_as_tree(repo)?;
+        if modified || (staged && !in_tree) {
+            overwritten.push(entry.name.clone());
+        }
+    }
+    for (name, _, _) in &files {
+        if index.binary_search_by(|e| e.name.cmp(name)).is_err() && on_disk(name) {
+            overwritten.push(name.clone());
+        }
+    }
+    overwritten.sort();
+    Ok(overwritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        checkout_tree, is_windows_device_name, overwritten_files, verify_path, PathProtection,
+    };
+    use crate::objects::{Blob, File, FileMode, Id, Repo, Tree};
+    use crate::tree::{index_to_tree, TreeEntry};
+    use crate::vfs::{MemoryFs, Vfs};
+    use bstr::{BString, ByteSlice};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_checkout_tree() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+
+        let mut files = Vec::new();
+        for n in 0..150 {
+            let id = repo
+                .store(&Blob::new(format!("{}\n", n).into_bytes()))
+                .unwrap();
+            files.push(File {
+                mode: FileMode::Regular,
+                name: format!("{:03}", n).into(),
+                id,
+            });
+        }
+        let sub = repo.store(&Tree { files }).unwrap();
+        let top = repo.store(&Blob::new(b"top\n".to_vec())).unwrap();
+        let tree = Tree {
+            files: vec![
+                File {
+                    mode: FileMode::Directory,
+                    name: "sub".into(),
+                    id: sub,
+                },
+                File {
+                    mode: FileMode::Regular,
+                    name: "top".into(),
+                    id: top,
+                },
+            ],
+        };
+
+        let index = checkout_tree(&tree, 4, &repo).unwrap();
+        assert_eq!(index.len(), 151);
+        assert!(index.is_sorted_by_key(|e| e.name.clone()));
+        assert_eq!(fs.read(Path::new("/repo/sub/042")).unwrap(), b"42\n");
+        assert_eq!(fs.read(Path::new("/repo/top")).unwrap(), b"top\n");
+        assert_eq!(index.last().unwrap().meta.id, top);
+    }
+
+    #[test]
+    fn test_checkout_modes() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+
+        let script = repo.store(&Blob::new(b"#!/bin/sh\n".to_vec())).unwrap();
+        let target = repo.store(&Blob::new(b"script".to_vec())).unwrap();
+        // a submodule's commit is in another repository
+        let commit = Id::from("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let tree = Tree {
+            files: vec![
+                File {
+                    mode: FileMode::Symlink,
+                    name: "link".into(),
+                    id: target,
+                },
+                File {
+                    mode: FileMode::Gitlink,
+                    name: "module".into(),
+                    id: commit,
+                },
+                File {
+                    mode: FileMode::Executable,
+                    name: "script".into(),
+                    id: script,
+                },
+            ],
+        };
+
+        let index = checkout_tree(&tree, 1, &repo).unwrap();
+        assert_eq!(
+            fs.read_link(Path::new("/repo/link")).unwrap(),
+            Path::new("script")
+        );
+        assert!(fs.metadata(Path::new("/repo/script")).unwrap().executable);
+        assert!(fs.read_dir(Path::new("/repo/module")).unwrap().is_empty());
+
+        // the modes make it back into a tree made from the index
+        let st = index_to_tree(&index);
+        assert_eq!(
+            st[b"link".as_bstr()],
+            TreeEntry::Blob(target, FileMode::Symlink)
+        );
+        assert_eq!(
+            st[b"script".as_bstr()],
+            TreeEntry::Blob(script, FileMode::Executable)
+        );
+        assert_eq!(
+            st[b"module".as_bstr()],
+            TreeEntry::Blob(commit, FileMode::Gitlink)
+        );
+        for entry in &index {
+            assert!(entry.is_same_as_tree(&repo).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_verify_path() {
+        let all = PathProtection {
+            ntfs: true,
+            hfs: true,
+        };
+        let none = PathProtection {
+            ntfs: false,
+            hfs: false,
+        };
+        for &ok in &[&b"a"[..], b"a/b.txt", b".gitignore", b"x/.github/y", b"..a"] {
+            assert!(verify_path(ok, all), "{:?}", ok.as_bstr());
+        }
+        for &bad in &[
+            &b""[..],
+            b"/etc/passwd",
+            b"../outside",
+            b"a/../../outside",
+            b"a//b",
+            b"./a",
+            b".git/config",
+            b"sub/.GIT/hooks/post-checkout",
+        ] {
+            assert!(!verify_path(bad, none), "{:?}", bad.as_bstr());
+        }
+        for &ntfs in &[
+            &b".git./config"[..],
+            b".git . /x",
+            b"GIT~1/config",
+            b"a:stream",
+            b"C:/x",
+            b"a\\..\\b",
+            b"... /x",
+        ] {
+            assert!(verify_path(ntfs, none), "{:?}", ntfs.as_bstr());
+            assert!(!verify_path(ntfs, all), "{:?}", ntfs.as_bstr());
+        }
+        let hfs = ".g\u{200c}it/config".as_bytes();
+        assert!(verify_path(hfs, none));
+        assert!(!verify_path(hfs, all));
+    }
+
+    #[test]
+    fn test_windows_device_names() {
+        for &device in &[
+            &b"CON"[..],
+            b"nul",
+            b"aux.txt",
+            b"Com1",
+            b"lpt9.tar.gz",
+            b"prn .x",
+        ] {
+            assert!(is_windows_device_name(device), "{:?}", device.as_bstr());
+        }
+        for &file in &[&b"console"[..], b"com0", b"com", b"lpt10", b"nul_", b"xaux"] {
+            assert!(!is_windows_device_name(file), "{:?}", file.as_bstr());
+        }
+    }
+
+    #[test]
+    fn test_checkout_unsafe() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+
+        let hook = repo
+            .store(&Blob::new(b"#!/bin/sh\nevil\n".to_vec()))
+            .unwrap();
+        let hooks = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Executable,
+                    name: "post-checkout".into(),
+                    id: hook,
+                }],
+            })
+            .unwrap();
+        let dotgit = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Directory,
+                    name: "hooks".into(),
+                    id: hooks,
+                }],
+            })
+            .unwrap();
+        let tree = Tree {
+            files: vec![
+                File {
+                    mode: FileMode::Directory,
+                    name: ".Git".into(),
+                    id: dotgit,
+                },
+                File {
+                    mode: FileMode::Regular,
+                    name: "innocent".into(),
+                    id: hook,
+                },
+            ],
+        };
+
+        assert!(checkout_tree(&tree, 1, &repo).is_err());
+        assert!(!fs.exists(Path::new("/repo/innocent")));
+        assert!(!fs.exists(Path::new("/repo/.Git")));
+    }
+
+    #[test]
+    fn test_checkout_through_symlink() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        fs.create_dir(Path::new("/elsewhere")).unwrap();
+        fs.symlink(Path::new("/elsewhere"), Path::new("/repo/dir"))
+            .unwrap();
+
+        let blob = repo.store(&Blob::new(b"hi\n".to_vec())).unwrap();
+        let dir = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Regular,
+                    name: "file".into(),
+                    id: blob,
+                }],
+            })
+            .unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Directory,
+                name: "dir".into(),
+                id: dir,
+            }],
+        };
+
+        checkout_tree(&tree, 1, &repo).unwrap();
+        assert!(!fs.exists(Path::new("/elsewhere/file")));
+        assert_eq!(fs.read(Path::new("/repo/dir/file")).unwrap(), b"hi\n");
+    }
+
+    #[test]
+    fn test_overwritten_files() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+
+        let tree = |files: &[(&str, &[u8])]| Tree {
+            files: files
+                .iter()
+                .map(|(name, content)| File {
+                    mode: FileMode::Regular,
+                    name: (*name).into(),
+                    id: repo.store(&Blob::new(content.to_vec())).unwrap(),
+                })
+                .collect(),
+        };
+        let head = tree(&[("a", b"1\n"), ("b", b"1\n")]);
+        let target = tree(&[("a", b"2\n"), ("b", b"1\n"), ("c", b"new\n")]);
+        let index = checkout_tree(&head, 1, &repo).unwrap();
+        let overwritten = || overwritten_files(&index, Some(&head), &target, &repo).unwrap();
+        assert!(overwritten().is_empty());
+
+        // even files that are the same in both lose their changes
+        fs.write(Path::new("/repo/b"), b"changed\n").unwrap();
+        fs.write(Path::new("/repo/c"), b"untracked\n").unwrap();
+        assert_eq!(overwritten(), vec!["b", "c"]);
+        // but deleting is fine
+        fs.remove_file(Path::new("/repo/b")).unwrap();
+        fs.remove_file(Path::new("/repo/c")).unwrap();
+        assert!(overwritten().is_empty());
+
+        // staged changes are lost unless the target has them
+        let staged = checkout_tree(&target, 1, &repo).unwrap();
+        assert_eq!(
+            overwritten_files(&staged, Some(&head), &target, &repo).unwrap(),
+            Vec::<BString>::new()
+        );
+        assert_eq!(
+            overwritten_files(&staged, Some(&head), &head, &repo).unwrap(),
+            vec!["a", "c"]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Removing lock files and partly written temporary files that would otherwise
+//! be left behind when an operation fails or the process is interrupted. A
+//! stale `index.lock` stops git from touching the index until someone deletes
+//! it by hand, so anything that creates one registers it here first.
+//!
+//! Files are registered by making a [`TempFile`], which removes its file when
+//! dropped unless it is kept. That covers errors and panics; [`remove_all`]
+//! covers signals, and is called by the command line frontend's SIGINT and
+//! SIGTERM handler before it exits.
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::util;
+use crate::vfs::Vfs;
+
+lazy_static! {
+    /// Files belonging to in-flight operations, by the ID of their `TempFile`
+    static ref PENDING: Mutex<HashMap<usize, (Arc<dyn Vfs>, PathBuf)>> = Default::default();
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A lock or temporary file that is removed if the operation creating it
+/// doesn't finish. Register the path before creating the file, so there is no
+/// window where it exists without being cleaned up.
+pub struct TempFile {
+    id: usize,
+    vfs: Arc<dyn Vfs>,
+    path: PathBuf,
+    kept: bool,
+}
+
+impl TempFile {
+    /// Registers `path` on `vfs` for cleanup. The file doesn't have to exist
+    /// yet.
+    pub fn new(vfs: Arc<dyn Vfs>, path: PathBuf) -> TempFile {
+        TempFile::register(NEXT_ID.fetch_add(1, Ordering::Relaxed), vfs, path)
+    }
+
+    /// Registers `path` under an ID already taken from `NEXT_ID`
+    fn register(id: usize, vfs: Arc<dyn Vfs>, path: PathBuf) -> TempFile {
+        lock_pending().insert(id, (vfs.clone(), path.clone()));
+        TempFile {
+            id,
+            vfs,
+            path,
+            kept: false,
+        }
+    }
+
+    /// Registers a new temporary file in `dir`, named from `prefix` with this
+    /// process's ID and a counter, so no other process or thread writing
+    /// there picks the same name
+    pub fn unique_in(vfs: Arc<dyn Vfs>, dir: &Path, prefix: &str) -> TempFile {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("{}_{}_{}", prefix, util::process_id(), id));
+        TempFile::register(id, vfs, path)
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stops tracking the file without removing it, once it has been renamed
+    /// into place or otherwise doesn't need cleaning up any more
+    pub fn keep(mut self) {
+        self.kept = true;
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        // the registry entry goes first so an interrupt can't race us to it
+        lock_pending().remove(&self.id);
+        if !self.kept {
+            // it may never have been created
+            let _ = self.vfs.remove_file(&self.path);
+        }
+    }
+}
+
+/// Locks the registry. A panic while holding the lock can't leave the map in
+/// a bad state, so poisoning is ignored, which matters when unwinding drops
+/// `TempFile`s.
+fn lock_pending() -> std::sync::MutexGuard<'static, HashMap<usize, (Arc<dyn Vfs>, PathBuf)>> {
+    PENDING.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Removes every registered file. This is for when the process is about to
+/// exit without running destructors, such as from a signal handler.
+pub fn remove_all() {
+    let pending: Vec<_> = lock_pending().drain().collect();
+    for (_, (vfs, path)) in pending {
+        trace!("cleaning up {}", path.display());
+        let _ = vfs.remove_file(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TempFile;
+    use crate::vfs::{MemoryFs, Vfs};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_temp_file() {
+        let fs = Arc::new(MemoryFs::new());
+        fs.create_dir(Path::new("/repo")).unwrap();
+
+        let temp = TempFile::new(fs.clone(), PathBuf::from("/repo/index.lock"));
+        fs.write(temp.path(), b"new").unwrap();
+        drop(temp);
+        assert!(!fs.exists(Path::new("/repo/index.lock")));
+
+        let temp = TempFile::new(fs.clone(), PathBuf::from("/repo/tmp_obj"));
+        fs.write(temp.path(), b"done").unwrap();
+        temp.keep();
+        assert!(fs.exists(Path::new("/repo/tmp_obj")));
+
+        // dropping one that was never created is fine
+        drop(TempFile::new(fs.clone(), PathBuf::from("/repo/never")));
+
+        let a = TempFile::unique_in(fs.clone(), Path::new("/repo"), "tmp_obj");
+        let b = TempFile::unique_in(fs.clone(), Path::new("/repo"), "tmp_obj");
+        assert_ne!(a.path(), b.path());
+        assert!(a.path().starts_with("/repo"));
+    }
+}
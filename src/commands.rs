@@ -1,25 +1,299 @@
 use anyhow::{anyhow, Context, Result};
+use bstr::{BString, ByteSlice, ByteVec};
 use chrono::{DateTime, FixedOffset, Local};
+use rayon::prelude::*;
+use serde::Serialize;
 use std::ascii;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::env;
-use std::fs::OpenOptions;
+use std::ffi::OsStr;
 use std::io;
-use std::io::{BufReader, Read, Write};
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 use walkdir::WalkDir;
 
 use crate::args;
 use crate::args::OutputType;
-use crate::index;
-use crate::objects::{Blob, Commit, Id, NameEntry, Object, Repo};
-use crate::rev;
-use crate::tree::{
-    diff_file_lists, diff_trees, index_to_tree, load_tree_from_disk, save_subtree, Diff, SubTree,
-    TreeEntry,
-};
-use crate::util::GitPath;
 use index::IndexEntry;
+use regex::Regex;
+use rgit::apply::{self, ApplyTarget, FilePatch};
+use rgit::archive;
+use rgit::bitmap;
+use rgit::blame;
+use rgit::checkout;
+use rgit::cleanup::TempFile;
+use rgit::daemon::{self, DaemonOptions};
+use rgit::date::approxidate;
+use rgit::diff;
+use rgit::fast_export;
+use rgit::fast_import::{self, Marks};
+use rgit::fetch::{self, UpdateKind};
+use rgit::filter::{self, PathFilter, Rewriter};
+use rgit::fsck;
+use rgit::fsmonitor::Fsmonitor;
+use rgit::hooks;
+use rgit::ignore::Ignores;
+use rgit::index;
+use rgit::maintenance::{self, RepackOptions, Task};
+use rgit::merge;
+use rgit::objects::{
+    Blob, Commit, File, FileMode, Id, NameEntry, Object, ObjectType, Repo, Tag, Tree,
+};
+use rgit::packs;
+use rgit::patch;
+use rgit::patch_id;
+use rgit::pretty::{Format, Formatter};
+use rgit::push;
+use rgit::rebase::{self, Action, Step};
+use rgit::reflog;
+use rgit::remote::Callbacks;
+use rgit::rev;
+use rgit::rev::Head;
+use rgit::revwalk::{self, CommitFilter, FoundObject, RevWalk};
+use rgit::trailers::{self, IfExists, Trailer};
+use rgit::tree::{
+    diff_file_lists, diff_trees, entry_at, load_tree_from_disk, load_tree_from_disk_cached,
+    save_subtree, tree_to_index, write_index_tree, Diff, SubTree, TreeEntry,
+};
+use rgit::util::{self, join_git_path, precompose_unicode, to_platform_path, Cleanup, GitPath};
+use rgit::vfs;
+
+/// A command finished with an exit status other than 0 without anything
+/// going wrong, such as check-ignore when nothing is ignored. The frontend
+/// exits with the status and prints no error.
+#[derive(Debug, Error)]
+#[error("exited with status {0}")]
+pub struct Exit(pub i32);
+
+/// prints something as pretty JSON on stdout
+fn print_json(value: &impl Serialize) -> Result<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    serde_json::to_writer_pretty(&mut out, value).context("failed to write JSON")?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// asks the filesystem monitor what changed, if one is configured. Failures are
+/// not fatal since we can always look at every file instead
+fn query_fsmonitor(repo: &Repo) -> Option<Fsmonitor> {
+    repo.config()
+        .and_then(|config| Fsmonitor::query(repo, &config))
+        .unwrap_or_else(|e| {
+            warn!("not using fsmonitor: {:#}", e);
+            None
+        })
+}
+
+/// downloads objects and refs from a remote, printing what moved like `git
+/// fetch` does
+pub fn fetch(args::Fetch { remote }: args::Fetch) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    let mut callbacks = Callbacks::new();
+    callbacks.progress(|message| {
+        eprint!("remote: {}", message);
+        true
+    });
+    let updates = fetch::fetch(&mut repo, &remote, &mut callbacks)?;
+
+    let mut printed = false;
+    let mut rejected = false;
+    for update in updates {
+        let (summary, flag, note) = match (update.kind, update.old) {
+            (UpdateKind::UpToDate, _) => continue,
+            (UpdateKind::New, _) | (_, None) => ("[new branch]".to_owned(), '*', ""),
+            (kind, Some(old)) => {
+                let abbrev = |id: &Id| -> Result<String> {
+                    let len = rev::abbrev_len(id, 7, &repo)?;
+                    Ok(id.to_string()[..len].to_owned())
+                };
+                let (old, new) = (abbrev(&old)?, abbrev(&update.new)?);
+                match kind {
+                    UpdateKind::FastForward => (format!("{}..{}", old, new), ' ', ""),
+                    UpdateKind::Forced => (format!("{}...{}", old, new), '+', "  (forced update)"),
+                    _ => {
+                        rejected = true;
+                        ("[rejected]".to_owned(), '!', "  (non-fast-forward)")
+                    }
+                }
+            }
+        };
+        if !printed {
+            let url = repo
+                .config()?
+                .get(&format!("remote.{}.url", remote))
+                .map(str::to_owned);
+            eprintln!("From {}", url.unwrap_or(remote.clone()));
+            printed = true;
+        }
+        let short = |name: &str| {
+            let name = name.strip_prefix("refs/").unwrap_or(name);
+            let name = name.strip_prefix("heads/").unwrap_or(name);
+            name.strip_prefix("remotes/").unwrap_or(name).to_owned()
+        };
+        eprintln!(
+            " {} {:<17} {:<10} -> {}{}",
+            flag,
+            summary,
+            short(&update.remote),
+            short(&update.local),
+            note
+        );
+    }
+    if rejected {
+        return Err(anyhow!(
+            "some refs weren't updated, since that would lose commits"
+        ));
+    }
+    Ok(())
+}
+
+/// rewrites the history of every branch and tag through path filters
+pub fn filter(
+    args::Filter {
+        remove_path,
+        rename_path,
+        strip_blobs_bigger_than,
+        commit_map,
+    }: args::Filter,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+
+    let mut filters: Vec<PathFilter> = remove_path
+        .iter()
+        .map(|path| PathFilter::Remove(path.trim_end_matches('/').into()))
+        .collect();
+    for rename in &rename_path {
+        let mut parts = rename.splitn(2, ':');
+        match (parts.next(), parts.next()) {
+            (Some(from), Some(to)) if !from.is_empty() && !to.is_empty() => {
+                filters.push(PathFilter::Rename(
+                    from.trim_end_matches('/').into(),
+                    to.trim_end_matches('/').into(),
+                ))
+            }
+            _ => return Err(anyhow!("bad --rename-path {:?}, expected old:new", rename)),
+        }
+    }
+    if let Some(size) = strip_blobs_bigger_than {
+        let size = filter::parse_size(&size).ok_or_else(|| anyhow!("bad size {:?}", size))?;
+        filters.push(PathFilter::MaxBlobSize(size));
+    }
+    if filters.is_empty() {
+        return Err(anyhow!(
+            "nothing to do; give --remove-path, --rename-path or --strip-blobs-bigger-than"
+        ));
+    }
+
+    // the working tree is reset to the new HEAD afterwards, so anything staged
+    // would be lost
+    let head = match repo.read_head()? {
+        Head::Commit(id) => Some(id),
+        Head::Unborn(_) => None,
+    };
+    if head.is_some() {
+        let mut index = repo.index()?;
+        if write_index_tree(&mut index, &repo)? != head_commit(&repo)?.1 {
+            return Err(anyhow!(
+                "the index has changes in it; commit or reset them first"
+            ));
+        }
+    }
+
+    let mut rewriter = Rewriter::new(&repo, &filters);
+    for (name, id) in rev::list_refs(&repo)? {
+        if !name.starts_with("refs/heads/") && !name.starts_with("refs/tags/") {
+            continue;
+        }
+        if repo.open(&id)?.commit().is_none() {
+            warn!("not rewriting {}, which isn't a commit", name);
+            continue;
+        }
+        match rewriter.rewrite(&id)? {
+            Some(new) if new != id => {
+                rev::update_ref(Path::new(&name), &new, "filter: rewrite", &repo)?;
+                println!("rewrote {}", name);
+            }
+            Some(_) => (),
+            None => warn!("not rewriting {}, which would have no commits left", name),
+        }
+    }
+    if let Some(id) = head {
+        if let Some(new) = rewriter.rewrite(&id)? {
+            reset_hard(&new, "filter: rewrite", &repo)?;
+        }
+    }
+
+    let map_path = commit_map
+        .map(PathBuf::from)
+        .unwrap_or_else(|| repo.root.join("filter").join("commit-map"));
+    if let Some(parent) = map_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&map_path, filter::format_commit_map(rewriter.commit_map()))
+        .with_context(|| format!("failed to write {}", map_path.display()))?;
+    println!("commit map written to {}", map_path.display());
+    Ok(())
+}
+
+/// makes patch emails out of a range of commits, oldest first
+pub fn format_patch(
+    args::FormatPatch {
+        range,
+        output_directory,
+        stdout,
+    }: args::FormatPatch,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find git repo")?;
+    let (since, until) = match range.find("..") {
+        Some(dots) => (&range[..dots], &range[dots + 2..]),
+        None => (&range[..], "HEAD"),
+    };
+    // either side may be left out, as in `origin..`
+    let parse = |rev: &str| rev::parse(if rev.is_empty() { "HEAD" } else { rev }, &repo);
+    let (since, until) = (parse(since)?, parse(until)?);
+
+    // merges can't be written as a patch, so like git we leave them out
+    let filter = CommitFilter {
+        max_parents: Some(1),
+        ..Default::default()
+    };
+    let mut commits = RevWalk::new(&repo, &[until])?
+        .hide(&[since])?
+        .with_filter(filter)
+        .collect::<Result<Vec<_>>>()?;
+    commits.reverse();
+
+    let dir = PathBuf::from(output_directory.unwrap_or_else(|| ".".to_string()));
+    if !stdout {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    let total = commits.len();
+    for (n, id) in commits.iter().enumerate() {
+        let email = patch::format_email(id, n + 1, total, &repo)?;
+        if stdout {
+            io::stdout().write_all(&email)?;
+            continue;
+        }
+        let commit = repo
+            .open(id)?
+            .commit()
+            .context("the walk only yields commits")?;
+        let (subject, _) = patch::split_message(&commit.message);
+        let path = dir.join(patch::patch_file_name(n + 1, &subject));
+        std::fs::write(&path, email)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
 
 /// initialize a repo in the working directory
 pub fn init() -> Result<()> {
@@ -35,18 +309,122 @@ pub fn init() -> Result<()> {
     Ok(())
 }
 
+/// Gets the name git stores for a path in the worktree. Names read from the
+/// filesystem are precomposed if `precompose` (from core.precomposeUnicode) is
+/// set.
+fn worktree_name(path: &Path, repo: &Repo, precompose: bool) -> Result<BString> {
+    let name = repo.repo_relative(path)?.to_git_bytes();
+    Ok(if precompose {
+        precompose_unicode(name)
+    } else {
+        name
+    })
+}
+
+/// Reads core.precomposeUnicode
+fn precomposes_unicode(repo: &Repo) -> Result<bool> {
+    Ok(repo
+        .config()?
+        .get_bool("core.precomposeunicode")?
+        .unwrap_or(false))
+}
+
+/// updates index entries for files that are already in the index, for
+/// `add -u`. `pathspecs` limit which entries are updated if there are any.
+fn add_update(pathspecs: Vec<String>, repo: &Repo, index: &mut index::Index) -> Result<()> {
+    let precompose = precomposes_unicode(repo)?;
+    let prefixes = pathspecs
+        .iter()
+        .map(|p| worktree_name(Path::new(p), repo, precompose))
+        .collect::<Result<Vec<_>>>()?;
+    let in_pathspec = |name: &[u8]| {
+        prefixes.is_empty()
+            || prefixes.iter().any(|prefix| {
+                prefix.is_empty()
+                    || name == prefix.as_slice()
+                    || (name.starts_with(prefix) && name.get(prefix.len()) == Some(&b'/'))
+            })
+    };
+
+    // if a filesystem monitor says nothing happened to a file, we don't need
+    // to even stat it. We don't save the monitor's new token since only
+    // some files may have been looked at
+    let fsmonitor = query_fsmonitor(repo);
+    let tree_root = repo.tree_root();
+
+    let updates = index
+        .entries
+        .par_iter()
+        .filter(|ie| in_pathspec(ie.name.as_slice()))
+        .filter(|ie| {
+            fsmonitor
+                .as_ref()
+                .map_or(true, |f| f.may_have_changed(&ie.name))
+        })
+        .map(|ie| {
+            if repo
+                .vfs()
+                .symlink_metadata(&tree_root.join(to_platform_path(&ie.name)))
+                .is_err()
+            {
+                // deleted
+                return Ok(Some((ie.name.clone(), None)));
+            }
+            Ok(index::entry_for_file(index, &ie.name, repo)?
+                .map(|meta| (ie.name.clone(), Some(meta))))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (name, meta) in updates.into_iter().flatten() {
+        match meta {
+            Some(meta) => index::insert_entry(index, IndexEntry { name, meta }),
+            None => {
+                index::remove_entry(index, &name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Is the directory at `path` a git directory, or the root of a repository
+/// other than `repo`? Neither of these can be added to the index.
+fn is_other_repo(path: &Path, repo: &Repo) -> bool {
+    if !repo.is_other_repo(path) {
+        return false;
+    }
+    if path.file_name().map_or(true, |name| name != ".git") {
+        warn!(
+            "not adding {}, which is another git repository",
+            path.display()
+        );
+    }
+    true
+}
+
 /// add files to the index
-pub fn add(files: Vec<String>) -> Result<()> {
+pub fn add(files: Vec<String>, update: bool) -> Result<()> {
     let repo = Repo::new().context("failed to find repo")?;
     let mut my_index = repo.index()?;
 
+    if update {
+        add_update(files, &repo, &mut my_index)?;
+        return repo.write_index(&my_index);
+    }
+
+    // walk everything first so that the expensive part (hashing and
+    // compressing) can be spread across cores
+    let precompose = precomposes_unicode(&repo)?;
+    let mut paths = Vec::new();
     for file in files {
         let file = Path::new(&file);
         if !file.exists() {
             return Err(anyhow!("Path {} does not exist!", file.display()));
         }
 
-        let wd = WalkDir::new(file).follow_links(false);
+        let wd = WalkDir::new(file)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|f| !f.file_type().is_dir() || !is_other_repo(f.path(), &repo));
 
         'inner: for f in wd {
             let f: walkdir::DirEntry = f?;
@@ -54,21 +432,29 @@ pub fn add(files: Vec<String>) -> Result<()> {
                 continue 'inner;
             }
 
-            let path = repo.repo_relative(f.path())?;
-
-            let path = path.to_git_path();
-            if path.is_none() {
-                warn!(
-                    "Skipping adding {:?} because it contains invalid UTF-8",
-                    f.path()
-                );
+            let name = worktree_name(f.path(), &repo, precompose)?;
+            // files given by name can still be inside a .git directory
+            if name.split(|&b| b == b'/').any(|c| c == b".git") {
+                warn!("not adding {}, which is inside a .git directory", name);
                 continue 'inner;
             }
-            let path = path.unwrap();
-
-            index::add_to_index(&mut my_index, &path, &repo)?;
+            paths.push(name);
         }
     }
+    paths.sort();
+    paths.dedup();
+
+    let new_entries = paths
+        .into_par_iter()
+        .map(|path| {
+            Ok(index::entry_for_file(&my_index, &path, &repo)?
+                .map(|meta| IndexEntry { name: path, meta }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for entry in new_entries.into_iter().flatten() {
+        index::insert_entry(&mut my_index, entry);
+    }
     let unsorted = my_index.clone();
     my_index.sort_by(|IndexEntry { name, .. }, IndexEntry { name: name2, .. }| name.cmp(name2));
     assert_eq!(unsorted, my_index);
@@ -78,25 +464,652 @@ pub fn add(files: Vec<String>) -> Result<()> {
     Ok(())
 }
 
+/// opens the user's editor on a file, picking the editor the way git does
+fn launch_editor(path: &Path, repo: &Repo) -> Result<()> {
+    let config = repo.config()?;
+    let dumb_terminal = env::var("TERM").map_or(true, |term| term == "dumb");
+    let editor = env::var("GIT_EDITOR")
+        .ok()
+        .or_else(|| config.get("core.editor").map(str::to_owned))
+        .or_else(|| env::var("VISUAL").ok().filter(|_| !dumb_terminal))
+        .or_else(|| env::var("EDITOR").ok())
+        .filter(|editor| !editor.is_empty());
+    let editor = match editor {
+        Some(editor) => editor,
+        None if dumb_terminal => {
+            return Err(anyhow!(
+                "the terminal is dumb and EDITOR is unset; use -m to give the message"
+            ))
+        }
+        None => "vi".to_string(),
+    };
+    // `:` is the usual way of saying not to edit
+    if editor == ":" {
+        return Ok(());
+    }
+    // editors may be given with arguments, so the shell has to split them
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(path)
+        .status()
+        .with_context(|| format!("unable to start editor '{}'", editor))?;
+    if !status.success() {
+        return Err(anyhow!("there was a problem with the editor '{}'", editor));
+    }
+    Ok(())
+}
+
+/// what is put under the message being edited to explain how it is cleaned up
+fn edit_help(cleanup: Cleanup) -> String {
+    match cleanup {
+        Cleanup::Strip => "\
+# Please enter the commit message for your changes. Lines starting
+# with '#' will be ignored, and an empty message aborts the commit.
+"
+        .to_string(),
+        Cleanup::Scissors => format!(
+            "{}\n# Do not modify or remove the line above.\n\
+             # Everything below it will be ignored.\n",
+            util::SCISSORS
+        ),
+        _ => "\
+# Please enter the commit message for your changes. Lines starting
+# with '#' will be kept; you may remove them yourself if you want to.
+# An empty message aborts the commit.
+"
+        .to_string(),
+    }
+}
+
 /// commit the changes staged in the index
-pub fn commit(who: String, message: String) -> Result<()> {
+pub fn commit(
+    args::Commit {
+        who,
+        message,
+        template,
+        edit,
+        cleanup,
+        signoff,
+    }: args::Commit,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let config = repo.config()?;
+    if let Some(entry) = repo.index()?.iter().find(|e| e.meta.stage() != 0) {
+        return Err(anyhow!(
+            "{} has merge conflicts; fix them and `rgit add` it before committing",
+            entry.name
+        ));
+    }
+    // a merge that stopped for conflicts left its message
+    let merge_message = match repo.vfs().read(&repo.root.join("MERGE_MSG")) {
+        Ok(merge_message) => Some(String::from_utf8_lossy(&merge_message).into_owned()),
+        Err(_) => None,
+    };
+
+    let use_editor = edit || message.is_none();
+    let cleanup = match cleanup.as_deref().or_else(|| config.get("commit.cleanup")) {
+        Some(mode) => mode.parse::<Cleanup>()?,
+        None => Cleanup::Default,
+    }
+    .resolve(use_editor);
+
+    // the template is only used when there's no message to start from
+    let template = match template
+        .as_deref()
+        .or_else(|| config.get("commit.template"))
+    {
+        Some(path) if message.is_none() && merge_message.is_none() => {
+            let path = util::expand_user_path(path);
+            let template = std::fs::read_to_string(&path).with_context(|| {
+                format!("could not read commit message template {}", path.display())
+            })?;
+            Some(template)
+        }
+        _ => None,
+    };
+    let (mut text, source) = match (message, &template, merge_message) {
+        (Some(message), _, _) => (message, Some("message")),
+        (None, _, Some(merge_message)) => (merge_message, Some("merge")),
+        (None, Some(template), None) => (template.clone(), Some("template")),
+        (None, None, None) => (String::new(), None),
+    };
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    if use_editor {
+        text.push('\n');
+        text.push_str(&edit_help(cleanup));
+    }
+
+    // the hook can change the message in the file before it is edited
+    let path = repo.root.join("COMMIT_EDITMSG");
+    repo.vfs().write(&path, text.as_bytes())?;
+    let mut hook_args = vec![path.as_os_str()];
+    hook_args.extend(source.map(OsStr::new));
+    hooks::run("prepare-commit-msg", &hook_args, &repo)?;
+    if use_editor {
+        launch_editor(&path, &repo)?;
+    }
+    let written = String::from_utf8(repo.vfs().read(&path)?)
+        .context("the commit message is not valid UTF-8")?;
+
+    let message = cleanup.apply(&written);
+    if message.trim().is_empty() {
+        return Err(anyhow!("aborting commit due to empty commit message"));
+    }
+    if let Some(template) = template {
+        if cleanup != Cleanup::Verbatim && cleanup.apply(&template) == message {
+            return Err(anyhow!("aborting commit; you did not edit the message"));
+        }
+    }
+    let message = if signoff {
+        let trailer = Trailer {
+            token: "Signed-off-by".to_string(),
+            value: who.trim().to_string(),
+        };
+        trailers::add(&message, &[trailer], IfExists::default(), false)
+    } else {
+        message
+    };
+
+    let mut index = repo.index()?;
+    let id = write_index_tree(&mut index, &repo)?;
+    // save the trees we just wrote in the index so they can be reused
+    repo.write_index(&index)?;
+    commit_tree(id, who, message)?;
+    auto_maintenance(&repo);
+    Ok(())
+}
+
+/// adds trailers to commit messages, or prints the ones they have
+pub fn interpret_trailers(
+    args::InterpretTrailers {
+        files,
+        trailer,
+        if_exists,
+        in_place,
+        trim_empty,
+        only_trailers,
+    }: args::InterpretTrailers,
+) -> Result<()> {
+    let new = trailer
+        .iter()
+        .map(|t| Trailer::parse(t, ":=").ok_or_else(|| anyhow!("bad trailer {:?}", t)))
+        .collect::<Result<Vec<_>>>()?;
+    let if_exists = match if_exists {
+        args::IfExists::AddIfDifferentNeighbor => IfExists::AddIfDifferentNeighbor,
+        args::IfExists::AddIfDifferent => IfExists::AddIfDifferent,
+        args::IfExists::Add => IfExists::Add,
+        args::IfExists::Replace => IfExists::Replace,
+        args::IfExists::DoNothing => IfExists::DoNothing,
+    };
+    if in_place && files.is_empty() {
+        return Err(anyhow!("--in-place needs files to edit"));
+    }
+
+    let edit = |message: &str| {
+        let edited = trailers::add(message, &new, if_exists, trim_empty);
+        if only_trailers {
+            trailers::parse(&edited)
+                .iter()
+                .map(|t| format!("{}: {}\n", t.token, t.value))
+                .collect()
+        } else {
+            edited
+        }
+    };
+
+    if files.is_empty() {
+        let mut message = String::new();
+        io::stdin().read_to_string(&mut message)?;
+        print!("{}", edit(&message));
+    }
+    for file in &files {
+        let message =
+            std::fs::read_to_string(file).with_context(|| format!("failed to read {}", file))?;
+        if in_place {
+            std::fs::write(file, edit(&message))
+                .with_context(|| format!("failed to write {}", file))?;
+        } else {
+            print!("{}", edit(&message));
+        }
+    }
+    Ok(())
+}
+
+/// who to commit as: `who` if it's given, otherwise user.name and user.email
+/// from the config
+fn identity(who: Option<String>, repo: &Repo) -> Result<NameEntry> {
+    let who = match who {
+        Some(who) => who,
+        None => {
+            let config = repo.config()?;
+            match (config.get("user.name"), config.get("user.email")) {
+                (Some(name), Some(email)) => format!("{} <{}>", name, email),
+                _ => {
+                    return Err(anyhow!(
+                        "no --who given, and user.name and user.email are not set"
+                    ))
+                }
+            }
+        }
+    };
+    let now = Local::now();
+    NameEntry::with_time(&who, now.with_timezone(now.offset())).context("invalid `who`")
+}
+
+/// the commit HEAD is at, and its tree
+fn head_commit(repo: &Repo) -> Result<(Id, Id)> {
+    match repo.read_head()? {
+        Head::Commit(id) => {
+            let commit = repo
+                .open(&id)?
+                .commit()
+                .ok_or_else(|| anyhow!("HEAD is not a commit"))?;
+            Ok((id, commit.tree))
+        }
+        Head::Unborn(_) => Err(anyhow!("there are no commits yet")),
+    }
+}
+
+/// How far `am` has got through a series of patches. It's kept in
+/// .git/rebase-apply, with each email in a numbered file, so that it can carry
+/// on after the user fixes up a patch that didn't apply.
+struct AmState {
+    /// .git/rebase-apply
+    dir: PathBuf,
+    /// number of the patch being applied, from 1
+    next: usize,
+    /// number of the last patch
+    last: usize,
+}
+
+impl AmState {
+    /// saves the emails and where HEAD was before any were applied
+    fn start(dir: PathBuf, mails: &[&[u8]], orig_head: &Id) -> Result<AmState> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        for (n, mail) in mails.iter().enumerate() {
+            std::fs::write(dir.join(format!("{:04}", n + 1)), mail)?;
+        }
+        std::fs::write(dir.join("last"), format!("{}\n", mails.len()))?;
+        std::fs::write(dir.join("orig-head"), format!("{}\n", orig_head))?;
+        let state = AmState {
+            dir,
+            next: 1,
+            last: mails.len(),
+        };
+        state.save()?;
+        Ok(state)
+    }
+
+    /// picks up an `am` that stopped partway
+    fn load(dir: PathBuf) -> Result<AmState> {
+        let number = |name: &str| -> Result<usize> {
+            let path = dir.join(name);
+            std::fs::read_to_string(&path)?
+                .trim()
+                .parse()
+                .with_context(|| format!("bad number in {}", path.display()))
+        };
+        Ok(AmState {
+            next: number("next")?,
+            last: number("last")?,
+            dir,
+        })
+    }
+
+    fn save(&self) -> Result<()> {
+        std::fs::write(self.dir.join("next"), format!("{}\n", self.next))?;
+        Ok(())
+    }
+
+    fn orig_head(&self) -> Result<Id> {
+        let path = self.dir.join("orig-head");
+        Id::from(std::fs::read_to_string(&path)?.trim())
+            .ok_or_else(|| anyhow!("bad commit ID in {}", path.display()))
+    }
+
+    /// the email being applied
+    fn mail(&self) -> Result<patch::Mail> {
+        let path = self.dir.join(format!("{:04}", self.next));
+        let data =
+            std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+        patch::parse_mail(&data).with_context(|| format!("in patch {:04}", self.next))
+    }
+
+    /// forgets about the `am`
+    fn finish(self) -> Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("failed to remove {}", self.dir.display()))
+    }
+}
+
+/// commits `tree` on top of HEAD with the author and message from a patch email
+fn commit_mail(mail: patch::Mail, tree: Id, committer: &NameEntry, repo: &Repo) -> Result<()> {
+    let (head, _) = head_commit(repo)?;
+    let reason = format!("am: {}", patch::split_message(&mail.message).0);
+    let id = repo.store(&Commit {
+        tree,
+        parents: vec![head],
+        author: mail.author,
+        committer: committer.clone(),
+        message: mail.message,
+    })?;
+    repo.set_head(&id, &reason)
+}
+
+/// applies a patch email to the index and working tree, and commits it
+fn apply_mail(mail: patch::Mail, committer: &NameEntry, repo: &Repo) -> Result<()> {
+    let patches = apply::parse(&mail.patch)?;
+    if patches.is_empty() {
+        return Err(anyhow!("patch is empty"));
+    }
+    let mut index = repo.index()?;
+    apply::apply_to_index(&patches, &mut index, repo)?;
+    let tree = write_index_tree(&mut index, repo)?;
+    repo.write_index(&index)?;
+    commit_mail(mail, tree, committer, repo)
+}
+
+/// applies patch emails as commits, stopping to let the user fix up any that
+/// don't apply
+pub fn am(
+    args::Am {
+        mailboxes,
+        who,
+        resolved,
+        skip,
+        abort,
+    }: args::Am,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let dir = repo.root.join("rebase-apply");
+    let in_progress = dir.exists();
+    if resolved || skip || abort {
+        if !in_progress {
+            return Err(anyhow!("no am in progress"));
+        }
+        if !mailboxes.is_empty() {
+            return Err(anyhow!(
+                "patches can't be given with --continue, --skip or --abort"
+            ));
+        }
+    } else if in_progress {
+        return Err(anyhow!(
+            "an am is already in progress in {}; use --continue, --skip or --abort",
+            dir.display()
+        ));
+    }
+
+    let mut state = if in_progress {
+        AmState::load(dir)?
+    } else {
+        let (head, head_tree) = head_commit(&repo)?;
+        // patches are applied to the index, which had better not have anything
+        // else in it
+        let mut index = repo.index()?;
+        if write_index_tree(&mut index, &repo)? != head_tree {
+            return Err(anyhow!(
+                "the index has changes in it; commit or reset them first"
+            ));
+        }
+
+        let mut inputs = Vec::new();
+        if mailboxes.is_empty() {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            inputs.push(data);
+        }
+        for mailbox in &mailboxes {
+            inputs.push(
+                std::fs::read(mailbox).with_context(|| format!("failed to read {}", mailbox))?,
+            );
+        }
+        let mails: Vec<&[u8]> = inputs.iter().flat_map(|d| patch::split_mbox(d)).collect();
+        if mails.is_empty() {
+            return Err(anyhow!("no patches found"));
+        }
+        AmState::start(dir, &mails, &head)?
+    };
+
+    if abort {
+        reset_hard(&state.orig_head()?, "am --abort", &repo)?;
+        return state.finish();
+    }
+
+    let committer = identity(who, &repo)?;
+    if resolved {
+        // the user applied the patch themselves and added the result
+        let mail = state.mail()?;
+        let mut index = repo.index()?;
+        let tree = write_index_tree(&mut index, &repo)?;
+        repo.write_index(&index)?;
+        if tree == head_commit(&repo)?.1 {
+            return Err(anyhow!(
+                "no changes in the index; did you forget to `rgit add`? If nothing is left of \
+                 the patch, use --skip"
+            ));
+        }
+        commit_mail(mail, tree, &committer, &repo)?;
+        state.next += 1;
+    } else if skip {
+        reset_hard(&head_commit(&repo)?.0, "am --skip", &repo)?;
+        state.next += 1;
+    }
+
+    while state.next <= state.last {
+        state.save()?;
+        let mail = state.mail()?;
+        let subject = mail.message.lines().next().unwrap_or("").to_string();
+        println!("Applying: {}", subject);
+        let patch_path = state.dir.join("patch");
+        std::fs::write(&patch_path, &mail.patch)?;
+        if let Err(e) = apply_mail(mail, &committer, &repo) {
+            eprintln!("Patch failed at {:04} {}", state.next, subject);
+            eprintln!(
+                "The patch is in {}. Fix it up and `rgit add` the result, then run \
+                 `rgit am --continue`.\nUse `rgit am --skip` to leave it out, or \
+                 `rgit am --abort` to go back to where you started.",
+                patch_path.display()
+            );
+            return Err(e);
+        }
+        state.next += 1;
+    }
+    state.finish()?;
+    auto_maintenance(&repo);
+    Ok(())
+}
+
+/// applies patches to the working tree, the index or both
+pub fn apply(
+    args::Apply {
+        patches,
+        check,
+        reverse,
+        index,
+        cached,
+    }: args::Apply,
+) -> Result<()> {
     let repo = Repo::new().context("failed to find repo")?;
+    let mut inputs = Vec::new();
+    if patches.is_empty() {
+        let mut data = Vec::new();
+        io::stdin().read_to_end(&mut data)?;
+        inputs.push(data);
+    }
+    for patch in &patches {
+        inputs.push(std::fs::read(patch).with_context(|| format!("failed to read {}", patch))?);
+    }
+    let mut file_patches = Vec::new();
+    for input in &inputs {
+        file_patches.extend(apply::parse(input)?);
+    }
+    if file_patches.is_empty() {
+        return Err(anyhow!("no valid patches in input"));
+    }
+    if reverse {
+        // later changes to a file are undone first
+        file_patches = file_patches.iter().rev().map(FilePatch::reverse).collect();
+    }
+
+    let target = if cached {
+        ApplyTarget::Index
+    } else if index {
+        ApplyTarget::IndexAndWorktree
+    } else {
+        ApplyTarget::Worktree
+    };
+    let mut index = repo.index()?;
+    if check {
+        apply::check(&file_patches, target, &index, &repo)?;
+        return Ok(());
+    }
+    apply::apply(&file_patches, target, &mut index, &repo)?;
+    if target != ApplyTarget::Worktree {
+        repo.write_index(&index)?;
+    }
+    Ok(())
+}
+
+/// writes a tar archive of a commit or tree
+pub fn archive(
+    args::Archive {
+        tree_ish,
+        prefix,
+        output,
+    }: args::Archive,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let id = rev::parse(&tree_ish, &repo)?;
+    let prefix = prefix.unwrap_or_default();
+    match output {
+        Some(output) => {
+            let file = std::fs::File::create(&output)
+                .with_context(|| format!("failed to create {}", output))?;
+            archive::write_tar(&id, prefix.as_bytes(), &repo, &mut BufWriter::new(file))
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            archive::write_tar(&id, prefix.as_bytes(), &repo, &mut out)
+        }
+    }
+}
+
+/// parses a `-L` line range for blame: `<start>,<end>`, `<start>,+<count>`,
+/// `<start>,-<count>`, or just `<start>` for the rest of the file
+fn parse_line_range(spec: &str) -> Result<(usize, Option<usize>)> {
+    let bad = || anyhow!("invalid line range {:?}", spec);
+    let number = |n: &str| n.parse::<usize>().map_err(|_| bad());
+    let mut parts = spec.splitn(2, ',');
+    let start = number(parts.next().unwrap_or_default())?;
+    let end = match parts.next().unwrap_or_default() {
+        "" => return Ok((start, None)),
+        end => end,
+    };
+    let (start, end) = if let Some(count) = end.strip_prefix('+') {
+        (start, start + number(count)?.max(1) - 1)
+    } else if let Some(count) = end.strip_prefix('-') {
+        let first = (start + 1).saturating_sub(number(count)?.max(1)).max(1);
+        (first, start)
+    } else {
+        // like git, the ends can be either way around
+        let end = number(end)?;
+        (start.min(end), start.max(end))
+    };
+    Ok((start, Some(end)))
+}
+
+/// shows which commit each line of a file came from
+pub fn blame(
+    args::Blame {
+        rev_or_file,
+        file,
+        lines,
+    }: args::Blame,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let (rev, file) = match file {
+        Some(file) => (rev_or_file, file),
+        None => ("HEAD".to_owned(), rev_or_file),
+    };
+    let start = rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?;
+    let path = worktree_name_of_any(Path::new(&file), &repo, precomposes_unicode(&repo)?)?;
+    let ranges = lines
+        .iter()
+        .map(|spec| parse_line_range(spec))
+        .collect::<Result<Vec<_>>>()?;
+    let blamed = blame::blame(&start, &path, &ranges, &repo)?;
+
+    let tree = commit_tree_id_of(&start, &repo)?;
+    let (blob, _) = entry_at(&tree, &path, &repo)?.context("file went missing")?;
+    let content = repo.read_object(&blob)?.1;
+    let content = diff::lines(&content);
+
+    let mut commits = HashMap::new();
+    let mut abbrev = 0;
+    for line in &blamed {
+        if !commits.contains_key(&line.commit) {
+            let commit = repo
+                .open(&line.commit)?
+                .commit()
+                .context("blamed a line on something that isn't a commit")?;
+            commits.insert(line.commit, commit);
+            abbrev = abbrev.max(rev::abbrev_len(&line.commit, 7, &repo)?);
+        }
+    }
+    let author_width = commits
+        .values()
+        .map(|commit| commit.author.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let line_width = content.len().to_string().len();
 
-    let index_tree = index_to_tree(&repo.index()?);
-    let id = save_subtree(&mut TreeEntry::SubTree(index_tree), &repo)?;
-    commit_tree(id, who, message)
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in &blamed {
+        let commit = &commits[&line.commit];
+        // like git, root commits are marked as the boundary of the history,
+        // which leaves a digit less of their ID
+        let id = line.commit.to_string();
+        let id = if commit.parents.is_empty() {
+            format!("^{}", &id[..abbrev])
+        } else {
+            id[..abbrev + 1].to_owned()
+        };
+        write!(
+            out,
+            "{} ({:<author_width$} {} {:>line_width$}) ",
+            id,
+            commit.author.name,
+            commit.author.time.format("%Y-%m-%d %H:%M:%S %z"),
+            line.line,
+            author_width = author_width,
+            line_width = line_width,
+        )?;
+        let text = content[line.line - 1];
+        out.write_all(text)?;
+        if !text.ends_with(b"\n") {
+            out.write_all(b"\n")?;
+        }
+    }
+    Ok(())
 }
 
 /// A Thing in the git repo
 enum DiffTarget {
     /// Canonical path to the file
-    File(String),
+    File(BString),
     /// Commit ID
     Commit(Id),
 }
 
 /// Finds what `name` is referencing
-fn diff_what_is<'a>(name: &'a str, repo: &Repo) -> (&'a str, Option<DiffTarget>) {
+fn diff_what_is<'a>(name: &'a str, repo: &Repo, precompose: bool) -> (&'a str, Option<DiffTarget>) {
     // first try interpreting it as a file name
     let file = Path::new(name);
     let fname = if file.exists() { Some(file) } else { None };
@@ -105,7 +1118,7 @@ fn diff_what_is<'a>(name: &'a str, repo: &Repo) -> (&'a str, Option<DiffTarget>)
         return (
             name,
             Some(DiffTarget::File(
-                repo.repo_relative(path).unwrap().to_git_path().unwrap(),
+                worktree_name(path, repo, precompose).unwrap(),
             )),
         );
     }
@@ -117,11 +1130,97 @@ fn diff_what_is<'a>(name: &'a str, repo: &Repo) -> (&'a str, Option<DiffTarget>)
     (name, None)
 }
 
+/// serve repositories over git://
+/// checks out a branch, or a commit with HEAD detached at it
+pub fn checkout(args::Checkout { rev, force, detach }: args::Checkout) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let refname = format!("refs/heads/{}", rev);
+    let branch = !detach && rev::list_refs(&repo)?.iter().any(|(r, _)| *r == refname);
+
+    let id = rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?;
+    if !force {
+        ensure_nothing_lost(&commit_tree_of(&id, &repo)?, &repo)?;
+    }
+    let reason = format!("checkout: moving from {} to {}", head_label(&repo)?, rev);
+    checkout_commit(&id, &repo)?;
+    if branch {
+        rev::set_head_ref(&refname, &reason, &repo)?;
+        eprintln!("Switched to branch '{}'", rev);
+    } else {
+        rev::detach_head(&id, &reason, &repo)?;
+        eprintln!("HEAD is now at {}", id);
+    }
+    Ok(())
+}
+
+/// shows which commits on a branch are upstream already, as `+` for those
+/// that aren't and `-` for those that are
+pub fn cherry(
+    args::Cherry {
+        upstream,
+        head,
+        limit,
+        verbose,
+    }: args::Cherry,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find git repo")?;
+    let upstream = rev::parse(&upstream, &repo)?;
+    let head = rev::parse(head.as_deref().unwrap_or("HEAD"), &repo)?;
+    let limit = limit.map(|l| rev::parse(&l, &repo)).transpose()?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (id, applied) in patch_id::cherry(&upstream, &head, limit.as_ref(), &repo)? {
+        let sign = if applied { '-' } else { '+' };
+        if verbose {
+            let commit = repo
+                .open(&id)?
+                .commit()
+                .context("cherry only finds commits")?;
+            let (subject, _) = patch::split_message(&commit.message);
+            writeln!(out, "{} {} {}", sign, id, subject)?;
+        } else {
+            writeln!(out, "{} {}", sign, id)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn daemon(
+    args::Daemon {
+        directories,
+        base_path,
+        export_all,
+        port,
+        timeout,
+        max_connections,
+    }: args::Daemon,
+) -> Result<()> {
+    // repositories are canonicalized before being checked against these
+    let allowed = directories
+        .iter()
+        .map(|dir| std::fs::canonicalize(dir).with_context(|| format!("bad directory {}", dir)))
+        .collect::<Result<Vec<_>>>()?;
+    daemon::run(DaemonOptions {
+        port,
+        base_path: base_path.map(PathBuf::from),
+        export_all,
+        allowed,
+        timeout: Some(timeout)
+            .filter(|&secs| secs != 0)
+            .map(Duration::from_secs),
+        max_connections,
+    })
+}
+
 /// diff two references.
 pub fn diff(args::Diff { things, cached }: args::Diff) -> Result<()> {
     let repo = Repo::new().context("failed to find git repo")?;
 
-    let typed_things = things.iter().map(|thing| diff_what_is(thing, &repo));
+    let precompose = precomposes_unicode(&repo)?;
+    let typed_things = things
+        .iter()
+        .map(|thing| diff_what_is(thing, &repo, precompose));
 
     let mut commits = Vec::with_capacity(2);
     let mut files = Vec::new();
@@ -141,83 +1240,1879 @@ pub fn diff(args::Diff { things, cached }: args::Diff) -> Result<()> {
                     return Err(anyhow!("Got a file prior to commits"));
                 }
             }
-            DiffTarget::File(relative) => {
-                files.push(relative);
-            }
+            DiffTarget::File(relative) => {
+                files.push(relative);
+            }
+        }
+    }
+
+    // If we have no commits, we should compare working tree to HEAD
+    if commits.len() == 0 {
+        // default to looking at HEAD
+        // we don't support diffing against a nonexistent HEAD because my HEAD
+        // hurts too much for this right now
+        commits.push(rev::parse("HEAD", &repo)?);
+    }
+    debug!("diffing {:?} commits for {:?} files", &commits, &files);
+    diff_trees(&commits[0], &commits[1], "", &repo)?;
+
+    // let id_a = rev::parse(&ref_a, &repo).context("Finding A reference")?;
+    // let id_b = rev::parse(&ref_b, &repo).context("Finding B reference")?;
+
+    // let tree_a = repo
+    //     .open(&id_a)
+    //     .context("Opening tree A")?
+    //     .commit()
+    //     .with_context(|| format!("Tree A ref {} did not point to a commit", &ref_a))?
+    //     .tree;
+    // let tree_a = repo
+    //     .open(&tree_a)
+    //     .context("Opening tree A")?
+    //     .tree()
+    //     .context("Tree A is not a tree")?;
+
+    // let tree_b = repo
+    //     .open(&id_b)
+    //     .context("Opening tree B")?
+    //     .commit()
+    //     .with_context(|| format!("Tree B ref {} did not point to a commit", &ref_b))?
+    //     .tree;
+    // let tree_b = repo
+    //     .open(&tree_b)
+    //     .context("Opening tree B")?
+    //     .tree()
+    //     .context("Tree B is not a tree")?;
+
+    Ok(())
+}
+
+/// parses a regular expression given on the command line
+fn parse_regex(re: &str) -> Result<Regex> {
+    Regex::new(re).with_context(|| format!("bad regular expression {:?}", re))
+}
+
+/// show the commit history
+pub fn log(
+    args::Log {
+        revs,
+        max_count,
+        oneline,
+        pretty,
+        since,
+        until,
+        author,
+        committer,
+        grep,
+        first_parent,
+        topo_order,
+        merges,
+        no_merges,
+        follow,
+        paths,
+    }: args::Log,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find git repo")?;
+    let (tips, hidden) = rev::parse_revs(&revs, &repo)?;
+    if follow && paths.len() != 1 {
+        return Err(anyhow!("--follow needs exactly one path"));
+    }
+    let precompose = precomposes_unicode(&repo)?;
+    let paths = paths
+        .iter()
+        .map(|p| worktree_name_of_any(Path::new(p), &repo, precompose))
+        .collect::<Result<Vec<_>>>()?;
+
+    let now = Local::now();
+    let now = now.with_timezone(now.offset());
+    let date = |date: Option<String>| date.map(|date| approxidate(&date, now)).transpose();
+    let filter = CommitFilter {
+        since: date(since)?,
+        until: date(until)?,
+        author: author.as_deref().map(parse_regex).transpose()?,
+        committer: committer.as_deref().map(parse_regex).transpose()?,
+        grep: grep
+            .iter()
+            .map(|re| parse_regex(re))
+            .collect::<Result<_>>()?,
+        min_parents: if merges { 2 } else { 0 },
+        max_parents: if no_merges { Some(1) } else { None },
+    };
+
+    let format = match pretty {
+        Some(pretty) => pretty.parse()?,
+        None if oneline => Format::Oneline,
+        None => Format::Medium,
+    };
+    let mut formatter = Formatter::new(&repo, format)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut walk = RevWalk::new(&repo, &tips)?
+        .hide(&hidden)?
+        .with_filter(filter);
+    if first_parent {
+        walk = walk.first_parent_only();
+    }
+    if topo_order {
+        walk = walk.topo_order();
+    }
+    if !paths.is_empty() {
+        walk = walk.limit_to_paths(paths);
+    }
+    if follow {
+        walk = walk.follow_renames();
+    }
+    for id in walk.take(max_count.unwrap_or(usize::MAX)) {
+        let id = id?;
+        let obj = repo.open_shared(&id)?;
+        let commit = match &*obj {
+            Object::Commit(commit) => commit,
+            _ => unreachable!("the walk only yields commits"),
+        };
+        formatter.write(&id, commit, &mut out)?;
+    }
+    Ok(())
+}
+
+/// How a file differs between two of HEAD, the index and the working tree
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Change {
+    Added,
+    Deleted,
+    Modified,
+    /// only in the working tree
+    Untracked,
+}
+
+impl Change {
+    /// letter used for this change in `status --porcelain`
+    fn letter(self) -> char {
+        match self {
+            Change::Added => 'A',
+            Change::Deleted => 'D',
+            Change::Modified => 'M',
+            Change::Untracked => '?',
+        }
+    }
+}
+
+/// A changed file as shown by `status --json`
+#[derive(Serialize)]
+struct StatusEntry<'a> {
+    path: Cow<'a, str>,
+    /// change in the index relative to HEAD
+    staged: Option<Change>,
+    /// change in the working tree relative to the index
+    worktree: Option<Change>,
+}
+
+/// packs objects and refs and writes the commit-graph
+/// show a commit and its diff against its parent
+pub fn show(args::Show { rev, pretty }: args::Show) -> Result<()> {
+    let repo = Repo::new().context("failed to find git repo")?;
+    let id = rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?;
+    let commit = repo
+        .open(&id)?
+        .commit()
+        .ok_or_else(|| anyhow!("{} is not a commit", id))?;
+    let format = match pretty {
+        Some(pretty) => pretty.parse()?,
+        None => Format::Medium,
+    };
+    let separate = match format {
+        Format::Oneline | Format::Custom { .. } => false,
+        _ => true,
+    };
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    Formatter::new(&repo, format)?.write(&id, &commit, &mut out)?;
+    // like git without --cc, merges are shown without a diff
+    if commit.parents.len() > 1 {
+        return Ok(());
+    }
+    let parent = match commit.parents.first() {
+        Some(parent) => {
+            let parent = repo.open(parent)?.commit();
+            Some(
+                parent
+                    .ok_or_else(|| anyhow!("parent of {} is not a commit", id))?
+                    .tree,
+            )
+        }
+        None => None,
+    };
+    let diffs = patch::diff_trees(parent.as_ref(), &commit.tree, &repo)?;
+    if separate && !diffs.is_empty() {
+        writeln!(out)?;
+    }
+    for d in &diffs {
+        out.write_all(&d.text)?;
+    }
+    Ok(())
+}
+
+pub fn maintenance(args::Maintenance { subcmd }: args::Maintenance) -> Result<()> {
+    let args::MaintenanceCommand::Run(args::MaintenanceRun { task, auto }) = subcmd;
+    let mut repo = Repo::new().context("failed to find repo")?;
+    repo.set_replace_objects(false);
+    if auto && !maintenance::needs_auto(&repo)? {
+        return Ok(());
+    }
+
+    let tasks = if task.is_empty() {
+        vec![Task::Gc]
+    } else {
+        task.iter()
+            .map(|t| t.parse::<Task>())
+            .collect::<Result<Vec<_>, _>>()?
+    };
+    for task in tasks {
+        maintenance::run_task(&repo, task)?;
+    }
+    Ok(())
+}
+
+/// joins another line of history into the current branch, stopping with
+/// conflict markers in the working tree where both changed the same lines
+pub fn merge(
+    args::Merge {
+        rev,
+        message,
+        no_ff,
+        who,
+        resolved,
+        abort,
+    }: args::Merge,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let in_progress = repo.vfs().exists(&repo.root.join("MERGE_HEAD"));
+    let rev = if resolved || abort {
+        if !in_progress {
+            return Err(anyhow!("no merge in progress"));
+        }
+        if rev.is_some() || message.is_some() || no_ff {
+            return Err(anyhow!(
+                "a branch, -m or --no-ff can't be given with --continue or --abort"
+            ));
+        }
+        if abort {
+            // HEAD hasn't moved since the merge started
+            let (head, _) = head_commit(&repo)?;
+            reset_hard(&head, "merge --abort", &repo)?;
+            return clear_merge_state(&repo);
+        }
+        return continue_merge(who, &repo);
+    } else if in_progress {
+        return Err(anyhow!(
+            "a merge is already in progress; use --continue or --abort"
+        ));
+    } else {
+        rev.ok_or_else(|| anyhow!("nothing to merge; give a branch or commit"))?
+    };
+    let (ours, our_tree) = head_commit(&repo)?;
+    repo.vfs().write(
+        &repo.root.join("ORIG_HEAD"),
+        format!("{}\n", ours).as_bytes(),
+    )?;
+    let theirs = rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?;
+    let their_tree = commit_tree_id_of(&theirs, &repo)?;
+
+    let base = merge::merge_bases(&ours, &theirs, &repo)?
+        .into_iter()
+        .next();
+    if base == Some(theirs) {
+        println!("Already up to date.");
+        return Ok(());
+    }
+    if base == Some(ours) && !no_ff {
+        ensure_nothing_lost(&commit_tree_of(&theirs, &repo)?, &repo)?;
+        println!("Fast-forward to {}", theirs);
+        return reset_hard(&theirs, &format!("merge {}: Fast-forward", rev), &repo);
+    }
+
+    let base_tree = base
+        .map(|base| commit_tree_id_of(&base, &repo))
+        .transpose()?;
+    let (tree_id, conflicts) = merge_into_worktree(
+        base_tree.as_ref(),
+        &our_tree,
+        &their_tree,
+        ("HEAD", &rev),
+        &repo,
+    )?;
+
+    let mut message = message.unwrap_or_else(|| {
+        let is_ref = |prefix| {
+            rev::list_refs(&repo).map_or(false, |refs| {
+                refs.iter()
+                    .any(|(name, _)| *name == format!("{}{}", prefix, rev))
+            })
+        };
+        let kind = if is_ref("refs/heads/") {
+            "branch"
+        } else if is_ref("refs/tags/") {
+            "tag"
+        } else {
+            "commit"
+        };
+        format!("Merge {} '{}'", kind, rev)
+    });
+    if !message.ends_with('\n') {
+        message.push('\n');
+    }
+    if conflicts.is_empty() {
+        let who = identity(who, &repo)?;
+        let id = repo.store(&Commit {
+            tree: tree_id,
+            parents: vec![ours, theirs],
+            author: who.clone(),
+            committer: who,
+            message,
+        })?;
+        repo.set_head(&id, &format!("merge {}: Merge made", rev))?;
+        println!("Merged {} as {}", rev, id);
+        auto_maintenance(&repo);
+        return Ok(());
+    }
+
+    repo.vfs().write(
+        &repo.root.join("MERGE_HEAD"),
+        format!("{}\n", theirs).as_bytes(),
+    )?;
+    message.push_str("\n# Conflicts:\n");
+    for (path, _) in &conflicts {
+        message.push_str(&format!("#\t{}\n", path));
+    }
+    repo.vfs()
+        .write(&repo.root.join("MERGE_MSG"), message.as_bytes())?;
+    Err(anyhow!(
+        "automatic merge failed; fix the conflicts, `rgit add` the files and run \
+         `rgit merge --continue`"
+    ))
+}
+
+/// merges the changes from the tree `base` to `theirs` into `ours` and checks
+/// out the result, with conflict markers in files that didn't merge cleanly.
+/// The index is left up to date with the working tree, except that
+/// conflicted files have a stage for each side instead. Returns the tree that
+/// was checked out and the conflicts.
+fn merge_into_worktree(
+    base: Option<&Id>,
+    ours: &Id,
+    theirs: &Id,
+    labels: (&str, &str),
+    repo: &Repo,
+) -> Result<(Id, Vec<(BString, merge::ConflictKind)>)> {
+    let merged = merge::merge_trees(base, ours, theirs, labels, repo)?;
+    // the working tree gets the merged files, conflict markers and all
+    let mut worktree = merged
+        .worktree_files()
+        .into_iter()
+        .map(|(name, id, mode)| IndexEntry {
+            meta: index::IndexMeta::for_blob(&name, id, mode),
+            name,
+        })
+        .collect::<index::Index>();
+    let tree_id = write_index_tree(&mut worktree, repo)?;
+    let tree = match repo.open(&tree_id)? {
+        Object::Tree(tree) => tree,
+        _ => unreachable!("just wrote a tree"),
+    };
+    ensure_nothing_lost(&tree, repo)?;
+    let old_index = repo.index()?;
+    let workers = checkout::workers(&repo.config()?)?;
+    let checked_out = checkout::checkout_tree(&tree, workers, repo)?;
+    remove_dropped_files(&old_index, &checked_out, repo)?;
+
+    let conflicts = merged
+        .conflicts()
+        .into_iter()
+        .map(|(path, kind)| (path.clone(), kind))
+        .collect::<Vec<_>>();
+    if conflicts.is_empty() {
+        repo.write_index(&checked_out)?;
+        return Ok((tree_id, conflicts));
+    }
+
+    // conflicted files keep a stage for each side, and the rest are up to
+    // date with the working tree
+    let mut index = merged.to_index();
+    for entry in index.iter_mut().filter(|entry| entry.meta.stage() == 0) {
+        if let Ok(found) = checked_out.binary_search_by(|e| e.name.cmp(&entry.name)) {
+            *entry = checked_out[found].clone();
+        }
+    }
+    repo.write_index(&index)?;
+    for (path, kind) in &conflicts {
+        println!("CONFLICT ({}): Merge conflict in {}", kind, path);
+    }
+    Ok((tree_id, conflicts))
+}
+
+/// commits a merge that stopped for conflicts with the message it left
+fn continue_merge(who: Option<String>, repo: &Repo) -> Result<()> {
+    let mut index = repo.index()?;
+    if let Some(entry) = index.iter().find(|e| e.meta.stage() != 0) {
+        return Err(anyhow!(
+            "{} still has merge conflicts; fix them and `rgit add` it first",
+            entry.name
+        ));
+    }
+    let tree = write_index_tree(&mut index, repo)?;
+    repo.write_index(&index)?;
+
+    let (ours, _) = head_commit(repo)?;
+    let merge_head =
+        String::from_utf8_lossy(&repo.vfs().read(&repo.root.join("MERGE_HEAD"))?).into_owned();
+    let theirs = Id::from(merge_head.trim()).context("MERGE_HEAD is not an ID")?;
+    let message =
+        String::from_utf8_lossy(&repo.vfs().read(&repo.root.join("MERGE_MSG"))?).into_owned();
+    // the list of conflicts is left out, like when it is edited in `commit`
+    let message = Cleanup::Strip.apply(&message);
+    if message.trim().is_empty() {
+        return Err(anyhow!("aborting merge due to empty commit message"));
+    }
+
+    let who = identity(who, repo)?;
+    let reason = format!("commit (merge): {}", patch::split_message(&message).0);
+    let id = repo.store(&Commit {
+        tree,
+        parents: vec![ours, theirs],
+        author: who.clone(),
+        committer: who,
+        message,
+    })?;
+    repo.set_head(&id, &reason)?;
+    clear_merge_state(repo)?;
+    println!("Merged {} as {}", theirs, id);
+    auto_maintenance(repo);
+    Ok(())
+}
+
+/// the state of a rebase, kept in .git/rebase-merge so that one that stopped
+/// for conflicts can be carried on with
+struct RebaseState {
+    /// .git/rebase-merge
+    dir: PathBuf,
+    /// the branch being rebased, or None if HEAD was detached
+    head_name: Option<String>,
+    /// where HEAD was before the rebase
+    orig_head: Id,
+    /// the steps still to be done
+    todo: Vec<Step>,
+    /// the steps that have been done. If the rebase stopped, it was for the
+    /// last of them.
+    done: Vec<Step>,
+}
+
+impl RebaseState {
+    /// saves what is to be done, before anything is
+    fn start(
+        dir: PathBuf,
+        head_name: Option<String>,
+        orig_head: Id,
+        onto: &Id,
+        todo: Vec<Step>,
+        repo: &Repo,
+    ) -> Result<RebaseState> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let head_name_text = head_name.as_deref().unwrap_or("detached HEAD");
+        std::fs::write(dir.join("head-name"), format!("{}\n", head_name_text))?;
+        std::fs::write(dir.join("orig-head"), format!("{}\n", orig_head))?;
+        // only for git to show in `status`
+        std::fs::write(dir.join("onto"), format!("{}\n", onto))?;
+        let state = RebaseState {
+            dir,
+            head_name,
+            orig_head,
+            todo,
+            done: Vec::new(),
+        };
+        state.save(repo)?;
+        Ok(state)
+    }
+
+    /// picks up a rebase that stopped partway
+    fn load(dir: PathBuf, repo: &Repo) -> Result<RebaseState> {
+        let read = |name: &str| -> Result<String> {
+            let path = dir.join(name);
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))
+        };
+        let head_name = match read("head-name")?.trim() {
+            "detached HEAD" => None,
+            name => Some(name.to_owned()),
+        };
+        let orig_head = read("orig-head")?;
+        let orig_head = Id::from(orig_head.trim())
+            .ok_or_else(|| anyhow!("bad commit ID in {}", dir.join("orig-head").display()))?;
+        Ok(RebaseState {
+            head_name,
+            orig_head,
+            todo: rebase::parse_todo(&read("git-rebase-todo")?, repo)?,
+            done: rebase::parse_todo(&read("done")?, repo)?,
+            dir,
+        })
+    }
+
+    fn save(&self, repo: &Repo) -> Result<()> {
+        std::fs::write(self.todo_path(), rebase::format_todo(&self.todo, repo)?)?;
+        std::fs::write(
+            self.dir.join("done"),
+            rebase::format_todo(&self.done, repo)?,
+        )?;
+        Ok(())
+    }
+
+    /// the todo list, which `rebase -i` has the user edit
+    fn todo_path(&self) -> PathBuf {
+        self.dir.join("git-rebase-todo")
+    }
+
+    /// puts HEAD back on the branch being rebased, pointing it at `id`.
+    /// `action` is what the reflog says the rebase did, such as `finish`.
+    fn finish(self, id: &Id, action: &str, repo: &Repo) -> Result<()> {
+        if let Some(head_name) = &self.head_name {
+            let reason = format!("rebase ({}): returning to {}", action, head_name);
+            // an aborted rebase never moved the branch
+            if rev::parse(head_name, repo)? != *id {
+                let branch_reason = format!("rebase ({}): {} onto {}", action, head_name, id);
+                rev::update_ref(Path::new(head_name), id, &branch_reason, repo)?;
+            }
+            rev::set_head_ref(head_name, &reason, repo)?;
+        } else {
+            rev::detach_head(id, &format!("rebase ({})", action), repo)?;
+        }
+        std::fs::remove_dir_all(&self.dir)
+            .with_context(|| format!("failed to remove {}", self.dir.display()))
+    }
+}
+
+/// has the user edit the todo list of a rebase that is starting
+fn edit_todo(state: &mut RebaseState, onto: &Id, repo: &Repo) -> Result<()> {
+    let short = |id: &Id| id.to_string()[..7].to_owned();
+    let text = format!(
+        "{}\n# Rebase {}..{} onto {} ({} commands)\n#\n{}",
+        rebase::format_todo(&state.todo, repo)?,
+        short(onto),
+        short(&state.orig_head),
+        short(onto),
+        state.todo.len(),
+        rebase::TODO_HELP
+    );
+    let path = state.todo_path();
+    std::fs::write(&path, text)?;
+    launch_editor(&path, repo)?;
+    let todo = rebase::parse_todo(&std::fs::read_to_string(&path)?, repo)?;
+    if todo.is_empty() {
+        return Err(anyhow!("nothing to do"));
+    }
+    if let Some(step) = todo.iter().find(|step| step.action != Action::Drop) {
+        if step.action.melds() {
+            return Err(anyhow!(
+                "cannot {} {} without a previous commit",
+                step.action,
+                step.commit
+            ));
+        }
+    }
+    state.todo = todo;
+    state.save(repo)
+}
+
+/// replays the changes a commit made on top of HEAD, returning the tree that
+/// gives and any conflicts
+fn replay(id: &Id, repo: &Repo) -> Result<(Id, Vec<(BString, merge::ConflictKind)>)> {
+    let commit = repo
+        .open(id)?
+        .commit()
+        .ok_or_else(|| anyhow!("{} is not a commit", id))?;
+    let base = commit
+        .parents
+        .first()
+        .map(|parent| commit_tree_id_of(parent, repo))
+        .transpose()?;
+    let (_, head_tree) = head_commit(repo)?;
+    let (subject, _) = patch::split_message(&commit.message);
+    let label = format!("{} ({})", &id.to_string()[..7], subject);
+    merge_into_worktree(
+        base.as_ref(),
+        &head_tree,
+        &commit.tree,
+        ("HEAD", &label),
+        repo,
+    )
+}
+
+/// commits the tree a rebase step replayed its commit as, which keeps its
+/// author and message. A pick of a change that is already there is dropped.
+fn commit_step(step: &Step, tree: Id, committer: &NameEntry, repo: &Repo) -> Result<()> {
+    let commit = repo
+        .open(&step.commit)?
+        .commit()
+        .ok_or_else(|| anyhow!("{} is not a commit", step.commit))?;
+    let (head, head_tree) = head_commit(repo)?;
+    let new = match step.action {
+        Action::Drop => return Ok(()),
+        Action::Pick | Action::Reword if tree == head_tree => {
+            println!("dropping {}, its changes are already there", step.commit);
+            return Ok(());
+        }
+        Action::Pick | Action::Reword => Commit {
+            tree,
+            parents: vec![head],
+            message: if step.action == Action::Reword {
+                edit_rebase_message(&commit.message, repo)?
+            } else {
+                commit.message
+            },
+            author: commit.author,
+            committer: committer.clone(),
+        },
+        // melding replaces the commit before
+        Action::Squash | Action::Fixup => {
+            let previous = repo
+                .open(&head)?
+                .commit()
+                .ok_or_else(|| anyhow!("HEAD is not a commit"))?;
+            let message = if step.action == Action::Squash {
+                let both = format!(
+                    "# This is a combination of 2 commits.\n\
+                     # This is the first commit message:\n\n{}\n\
+                     # This is the commit message to squash in:\n\n{}",
+                    previous.message, commit.message
+                );
+                edit_rebase_message(&both, repo)?
+            } else {
+                previous.message
+            };
+            Commit {
+                tree,
+                parents: previous.parents,
+                author: previous.author,
+                committer: committer.clone(),
+                message,
+            }
+        }
+    };
+    let reason = format!(
+        "rebase ({}): {}",
+        step.action,
+        patch::split_message(&new.message).0
+    );
+    let id = repo.store(&new)?;
+    repo.set_head(&id, &reason)
+}
+
+/// has the user edit a commit message during a rebase
+fn edit_rebase_message(message: &str, repo: &Repo) -> Result<String> {
+    let path = repo.root.join("COMMIT_EDITMSG");
+    let text = format!("{}\n{}", message, edit_help(Cleanup::Strip));
+    repo.vfs().write(&path, text.as_bytes())?;
+    launch_editor(&path, repo)?;
+    let written = String::from_utf8(repo.vfs().read(&path)?)
+        .context("the commit message is not valid UTF-8")?;
+    let message = Cleanup::Strip.apply(&written);
+    if message.trim().is_empty() {
+        return Err(anyhow!("aborting commit due to empty commit message"));
+    }
+    Ok(message)
+}
+
+/// the ID of a commit's tree
+fn commit_tree_id_of(id: &Id, repo: &Repo) -> Result<Id> {
+    Ok(repo
+        .open(id)?
+        .commit()
+        .ok_or_else(|| anyhow!("{} is not a commit", id))?
+        .tree)
+}
+
+/// uploads refs and the objects they need to a remote, printing what happened
+/// to each ref as git does
+pub fn push(
+    args::Push {
+        remote,
+        refspecs,
+        force,
+    }: args::Push,
+) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    // the remote gets history as it really is
+    repo.set_replace_objects(false);
+    let refspecs = if refspecs.is_empty() {
+        push::default_refspecs(&remote, &repo)?
+    } else {
+        refspecs
+            .iter()
+            .map(|spec| push::parse_refspec(spec).ok_or_else(|| anyhow!("bad refspec {:?}", spec)))
+            .collect::<Result<Vec<_>>>()?
+    };
+    let mut callbacks = Callbacks::new();
+    callbacks.progress(|message| {
+        eprint!("remote: {}", message);
+        true
+    });
+    let updates = push::push(&mut repo, &remote, &refspecs, force, &mut callbacks)?;
+
+    let url = repo
+        .config()?
+        .get(&format!("remote.{}.url", remote))
+        .map(str::to_owned)
+        .unwrap_or_else(|| remote.clone());
+    if updates
+        .iter()
+        .all(|update| update.kind == UpdateKind::UpToDate)
+    {
+        eprintln!("Everything up-to-date");
+        return Ok(());
+    }
+    eprintln!("To {}", url);
+    let abbrev = |id: &Id| -> Result<String> {
+        let len = rev::abbrev_len(id, 7, &repo)?;
+        Ok(id.to_string()[..len].to_owned())
+    };
+    let short = |name: &str| {
+        let name = name.strip_prefix("refs/").unwrap_or(name);
+        let name = name.strip_prefix("heads/").unwrap_or(name);
+        name.strip_prefix("tags/").unwrap_or(name).to_owned()
+    };
+    let mut failed = false;
+    for update in &updates {
+        let (flag, summary, note) = match (update.kind, update.old, update.new) {
+            (UpdateKind::UpToDate, _, _) => continue,
+            (UpdateKind::Rejected, Some(old), _) => {
+                let note = if repo.has_id(&old) {
+                    "non-fast-forward"
+                } else {
+                    "fetch first"
+                };
+                ('!', "[rejected]".to_owned(), Some(note.to_owned()))
+            }
+            _ if update.refused.is_some() => {
+                ('!', "[remote rejected]".to_owned(), update.refused.clone())
+            }
+            (_, _, None) => ('-', "[deleted]".to_owned(), None),
+            (UpdateKind::New, _, _) | (_, None, _) => {
+                let what = if update.remote.starts_with("refs/tags/") {
+                    "[new tag]"
+                } else {
+                    "[new branch]"
+                };
+                ('*', what.to_owned(), None)
+            }
+            (UpdateKind::Forced, Some(old), Some(new)) => (
+                '+',
+                format!("{}...{}", abbrev(&old)?, abbrev(&new)?),
+                Some("forced update".to_owned()),
+            ),
+            (_, Some(old), Some(new)) => {
+                (' ', format!("{}..{}", abbrev(&old)?, abbrev(&new)?), None)
+            }
+        };
+        failed |= flag == '!';
+        // deletions have no source to show
+        let refs = if update.local.is_empty() {
+            short(&update.remote)
+        } else {
+            format!("{} -> {}", short(&update.local), short(&update.remote))
+        };
+        eprintln!(
+            " {} {:<17} {}{}",
+            flag,
+            summary,
+            refs,
+            note.map(|note| format!(" ({})", note)).unwrap_or_default()
+        );
+    }
+    if failed {
+        return Err(anyhow!("failed to push some refs to '{}'", url));
+    }
+    Ok(())
+}
+
+/// replays the commits on the current branch that upstream doesn't have on
+/// top of it, stopping to let the user fix up any that conflict
+pub fn rebase(
+    args::Rebase {
+        upstream,
+        interactive,
+        who,
+        resolved,
+        abort,
+    }: args::Rebase,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let dir = repo.root.join("rebase-merge");
+    let in_progress = dir.exists();
+    let mut state = if resolved || abort {
+        if !in_progress {
+            return Err(anyhow!("no rebase in progress"));
+        }
+        if upstream.is_some() || interactive {
+            return Err(anyhow!(
+                "an upstream or -i can't be given with --continue or --abort"
+            ));
+        }
+        RebaseState::load(dir, &repo)?
+    } else if in_progress {
+        return Err(anyhow!(
+            "a rebase is already in progress in {}; use --continue or --abort",
+            dir.display()
+        ));
+    } else {
+        let upstream = upstream.ok_or_else(|| anyhow!("no upstream given to rebase onto"))?;
+        let onto = rev::peel_tags(rev::parse(&upstream, &repo)?, &repo)?;
+        let (head, _) = head_commit(&repo)?;
+        // rebasing onto an ancestor only does anything if the commits since
+        // are edited
+        if !interactive && merge::merge_bases(&head, &onto, &repo)?.contains(&onto) {
+            println!("Current branch is up to date.");
+            return Ok(());
+        }
+        // commits upstream already has a copy of are left out
+        let todo = patch_id::cherry(&onto, &head, None, &repo)?
+            .into_iter()
+            .filter(|(_, applied)| !applied)
+            .map(|(commit, _)| Step {
+                action: Action::Pick,
+                commit,
+            })
+            .collect::<Vec<_>>();
+        ensure_nothing_lost(&commit_tree_of(&onto, &repo)?, &repo)?;
+        let mut state =
+            RebaseState::start(dir, rev::head_branch(&repo)?, head, &onto, todo, &repo)?;
+        if interactive {
+            if let Err(e) = edit_todo(&mut state, &onto, &repo) {
+                std::fs::remove_dir_all(&state.dir)?;
+                return Err(e);
+            }
+        }
+        // the branch only moves once everything has been replayed
+        rev::detach_head(
+            &onto,
+            &format!("rebase (start): checkout {}", upstream),
+            &repo,
+        )?;
+        checkout_commit(&onto, &repo)?;
+        state
+    };
+
+    if abort {
+        let orig_head = state.orig_head;
+        reset_hard(&orig_head, "rebase (abort)", &repo)?;
+        return state.finish(&orig_head, "abort", &repo);
+    }
+
+    let committer = identity(who, &repo)?;
+    if resolved {
+        // the user fixed up the step that stopped, and added the result
+        let step = *state
+            .done
+            .last()
+            .ok_or_else(|| anyhow!("the rebase hasn't stopped anywhere to continue from"))?;
+        let mut index = repo.index()?;
+        if let Some(entry) = index.iter().find(|e| e.meta.stage() != 0) {
+            return Err(anyhow!(
+                "{} still has merge conflicts; fix them and `rgit add` it first",
+                entry.name
+            ));
+        }
+        let tree = write_index_tree(&mut index, &repo)?;
+        repo.write_index(&index)?;
+        commit_step(&step, tree, &committer, &repo)?;
+    }
+
+    while !state.todo.is_empty() {
+        let step = state.todo.remove(0);
+        state.done.push(step);
+        state.save(&repo)?;
+        if step.action == Action::Drop {
+            continue;
+        }
+        let (tree, conflicts) = replay(&step.commit, &repo)?;
+        if !conflicts.is_empty() {
+            return Err(anyhow!(
+                "could not apply {}; fix the conflicts, `rgit add` the files and run \
+                 `rgit rebase --continue`, or give up with `rgit rebase --abort`",
+                step.commit
+            ));
+        }
+        commit_step(&step, tree, &committer, &repo)?;
+    }
+
+    let (head, _) = head_commit(&repo)?;
+    let name = state
+        .head_name
+        .clone()
+        .unwrap_or_else(|| "detached HEAD".to_owned());
+    state.finish(&head, "finish", &repo)?;
+    println!("Successfully rebased and updated {}.", name);
+    auto_maintenance(&repo);
+    Ok(())
+}
+
+/// collects garbage: packs, expires reflogs and prunes unreachable objects
+pub fn fsck(
+    args::Fsck {
+        unreachable,
+        no_dangling,
+    }: args::Fsck,
+) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    repo.set_replace_objects(false);
+    let report = fsck::fsck(&repo)?;
+    for problem in &report.problems {
+        eprintln!("error: {}", problem);
+    }
+    if unreachable {
+        for (id, kind) in &report.unreachable {
+            println!("unreachable {} {}", kind, id);
+        }
+    } else if !no_dangling {
+        for (id, kind) in &report.dangling {
+            println!("dangling {} {}", kind, id);
+        }
+    }
+    if !report.problems.is_empty() {
+        return Err(anyhow!("found {} problems", report.problems.len()));
+    }
+    Ok(())
+}
+
+pub fn gc(
+    args::Gc {
+        prune,
+        no_prune,
+        auto,
+    }: args::Gc,
+) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    repo.set_replace_objects(false);
+    if auto && !maintenance::needs_auto(&repo)? {
+        return Ok(());
+    }
+    let now = Local::now();
+    let now = now.with_timezone(now.offset());
+    let prune_expire = match prune.as_deref() {
+        _ if no_prune => None,
+        Some("never") => None,
+        Some(date) => Some(approxidate(date, now)?),
+        None => maintenance::prune_expiry(&repo, now)?,
+    };
+    maintenance::gc(&repo, prune_expire, now)
+}
+
+/// where grep reads a file from
+enum GrepSource {
+    /// a blob in a tree
+    Blob(Id),
+    /// a file in the working tree
+    Worktree(PathBuf),
+}
+
+/// searches one file's content for grep, returning what to print for it
+fn grep_file(
+    name: &str,
+    content: &[u8],
+    re: &regex::bytes::Regex,
+    line_number: bool,
+    files_with_matches: bool,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    if !re.is_match(content) {
+        return out;
+    }
+    if files_with_matches {
+        out.extend(name.as_bytes());
+        out.push(b'\n');
+    } else if diff::is_binary(content) {
+        out.extend(format!("Binary file {} matches\n", name).into_bytes());
+    } else {
+        for (n, line) in diff::lines(content).into_iter().enumerate() {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            if !re.is_match(line) {
+                continue;
+            }
+            out.extend(name.as_bytes());
+            if line_number {
+                out.extend(format!(":{}", n + 1).into_bytes());
+            }
+            out.push(b':');
+            out.extend(line);
+            out.push(b'\n');
+        }
+    }
+    out
+}
+
+/// searches the tracked files in the working tree, or the files in a commit
+/// or tree, for lines matching a regex
+pub fn grep(
+    args::Grep {
+        pattern,
+        rev,
+        line_number,
+        ignore_case,
+        files_with_matches,
+        paths,
+    }: args::Grep,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let re = regex::bytes::RegexBuilder::new(&pattern)
+        .case_insensitive(ignore_case)
+        .build()
+        .with_context(|| format!("bad regular expression {:?}", pattern))?;
+    let precompose = precomposes_unicode(&repo)?;
+    let prefixes = paths
+        .iter()
+        .map(|p| worktree_name_of_any(Path::new(p), &repo, precompose))
+        .collect::<Result<Vec<_>>>()?;
+    let in_pathspec = |name: &[u8]| {
+        prefixes.is_empty()
+            || prefixes.iter().any(|prefix| {
+                prefix.is_empty()
+                    || name == prefix.as_slice()
+                    || (name.starts_with(prefix) && name.get(prefix.len()) == Some(&b'/'))
+            })
+    };
+
+    // like git, symlinks and submodules aren't searched
+    let is_regular_file = |mode| mode == FileMode::Regular || mode == FileMode::Executable;
+    let tree_root = repo.tree_root();
+    let files = match &rev {
+        Some(rev) => {
+            let id = rev::peel_tags(rev::parse(rev, &repo)?, &repo)?;
+            let tree = match repo.object_info(&id)?.kind {
+                ObjectType::Tree => match repo.open(&id)? {
+                    Object::Tree(tree) => tree,
+                    _ => unreachable!(),
+                },
+                _ => commit_tree_of(&id, &repo)?,
+            };
+            let mut files = Vec::new();
+            load_tree_from_disk(&tree, &repo, b"", &mut files)?;
+            let separator = if rev.ends_with(':') || rev.ends_with('/') {
+                ""
+            } else {
+                ":"
+            };
+            files
+                .into_iter()
+                .filter(|(name, _, mode)| is_regular_file(*mode) && in_pathspec(name))
+                .map(|(name, id, _)| {
+                    let name = format!("{}{}{}", rev, separator, name);
+                    (name, GrepSource::Blob(id))
+                })
+                .collect::<Vec<_>>()
+        }
+        None => {
+            let index = repo.index()?;
+            let mut files = Vec::new();
+            for entry in index.iter() {
+                let mode = entry.meta.file_mode();
+                let name = entry.name.to_string();
+                // conflicted files are in the index more than once
+                let seen = files.last().map_or(false, |(last, _)| *last == name);
+                if !is_regular_file(mode) || !in_pathspec(&entry.name) || seen {
+                    continue;
+                }
+                let path = tree_root.join(to_platform_path(&entry.name));
+                files.push((name, GrepSource::Worktree(path)));
+            }
+            files
+        }
+    };
+
+    let found = files
+        .par_iter()
+        .map(|(name, source)| {
+            let content = match source {
+                GrepSource::Blob(id) => repo.read_object(id)?.1,
+                GrepSource::Worktree(path) => match repo.vfs().read(path) {
+                    Ok(content) => content,
+                    // deleted but not yet staged
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+                    Err(e) => return Err(e).with_context(|| format!("failed to read {}", name)),
+                },
+            };
+            Ok(grep_file(
+                name,
+                &content,
+                &re,
+                line_number,
+                files_with_matches,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for found in found {
+        out.write_all(&found)?;
+    }
+    Ok(())
+}
+
+/// runs gc if there are enough loose objects, after commands that make them.
+/// Failing is not worth failing the command over.
+fn auto_maintenance(repo: &Repo) {
+    match maintenance::needs_auto(repo) {
+        Ok(true) => {
+            eprintln!("Auto packing the repository for optimum performance.");
+            // what is unreachable is decided by the history as it really is
+            let mut repo = Repo::at(repo.root.clone());
+            repo.set_replace_objects(false);
+            if let Err(e) = maintenance::run_task(&repo, Task::Gc) {
+                warn!("auto maintenance failed: {:?}", e);
+            }
+        }
+        Ok(false) => (),
+        Err(e) => warn!("couldn't check whether maintenance is needed: {:?}", e),
+    }
+}
+
+/// lists where a ref has pointed, newest first, like `git reflog`, or
+/// expires old entries
+pub fn reflog(args::Reflog { subcmd }: args::Reflog) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let name = match subcmd {
+        Some(args::ReflogCommand::Show(args::ReflogShow { name })) => name,
+        Some(args::ReflogCommand::Expire(expire)) => return reflog_expire(expire, &repo),
+        None => "HEAD".to_owned(),
+    };
+    let refname = rev::reflog_refname(&name, &repo)?;
+    for (n, entry) in reflog::read(&refname, &repo)?.iter().rev().enumerate() {
+        let len = rev::abbrev_len(&entry.new, 7, &repo)?;
+        println!(
+            "{} {}@{{{}}}: {}",
+            &entry.new.to_string()[..len],
+            name,
+            n,
+            entry.message
+        );
+    }
+    Ok(())
+}
+
+/// removes old reflog entries
+fn reflog_expire(
+    args::ReflogExpire {
+        refs,
+        all,
+        expire,
+        expire_unreachable,
+    }: args::ReflogExpire,
+    repo: &Repo,
+) -> Result<()> {
+    let names = if all {
+        maintenance::reflog_names(repo)?
+    } else if refs.is_empty() {
+        return Err(anyhow!(
+            "no reflog given to expire; give some refs or --all"
+        ));
+    } else {
+        refs.iter()
+            .map(|name| rev::reflog_refname(name, repo))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let now = Local::now();
+    let now = now.with_timezone(now.offset());
+    let (default_expire, default_unreachable) = maintenance::reflog_expiry(repo, now)?;
+    let expire = match expire {
+        Some(expire) => maintenance::parse_expiry(&expire, now)?,
+        None => default_expire,
+    };
+    let expire_unreachable = match expire_unreachable {
+        Some(expire) => maintenance::parse_expiry(&expire, now)?,
+        None => default_unreachable,
+    };
+    maintenance::expire_reflogs(repo, &names, expire, expire_unreachable)?;
+    Ok(())
+}
+
+/// packs reachable objects, and with -a -d replaces every pack with one
+pub fn repack(args::Repack { all, delete }: args::Repack) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    repo.set_replace_objects(false);
+    let options = RepackOptions {
+        all,
+        delete,
+        loosen_since: None,
+    };
+    if maintenance::repack(&repo, options)?.is_none() {
+        println!("Nothing new to pack.");
+    }
+    Ok(())
+}
+
+/// replaces an object with another wherever it is read, or lists the objects
+/// that have been replaced
+pub fn replace(
+    args::Replace {
+        object,
+        replacement,
+        force,
+    }: args::Replace,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let (object, replacement) = match (object, replacement) {
+        (Some(object), Some(replacement)) => (object, replacement),
+        _ => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            for (name, _) in rev::list_refs(&repo)? {
+                if let Some(old) = name.strip_prefix("refs/replace/") {
+                    writeln!(out, "{}", old)?;
+                }
+            }
+            return Ok(());
+        }
+    };
+
+    let old = rev::parse(&object, &repo)?;
+    let new = rev::parse(&replacement, &repo)?;
+    if old == new {
+        return Err(anyhow!("{} can't be replaced with itself", old));
+    }
+    let refname = format!("refs/replace/{}", old);
+    if !force && rev::parse(&refname, &repo).is_ok() {
+        return Err(anyhow!(
+            "{} has been replaced already; use --force to replace it anyway",
+            old
+        ));
+    }
+    let (old_kind, new_kind) = (repo.object_info(&old)?.kind, repo.object_info(&new)?.kind);
+    if !force && old_kind != new_kind {
+        return Err(anyhow!(
+            "{} is a {} but {} is a {}; use --force to replace it anyway",
+            old,
+            old_kind,
+            new,
+            new_kind
+        ));
+    }
+    rev::update_ref(Path::new(&refname), &new, "replace", &repo)
+}
+
+/// point HEAD at a commit, making the index match it unless --soft is given
+/// and the working tree too with --hard
+pub fn reset(
+    args::Reset {
+        rev,
+        soft,
+        mixed: _,
+        hard,
+    }: args::Reset,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+
+    let id = rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?;
+    // resetting gives up on a merge
+    clear_merge_state(&repo)?;
+    let reason = format!("reset: moving to {}", rev);
+    if hard {
+        return reset_hard(&id, &reason, &repo);
+    }
+    let tree = commit_tree_of(&id, &repo)?;
+    if !soft {
+        let index = tree_to_index(&tree, &repo.index()?, &repo)?;
+        repo.write_index(&index)?;
+    }
+    repo.set_head(&id, &reason)
+}
+
+/// forgets about a merge that stopped for conflicts
+fn clear_merge_state(repo: &Repo) -> Result<()> {
+    for name in &["MERGE_HEAD", "MERGE_MSG"] {
+        match repo.vfs().remove_file(&repo.root.join(name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// points HEAD at the commit `id`, overwriting the index and working tree.
+/// The move is logged with `reason`.
+fn reset_hard(id: &Id, reason: &str, repo: &Repo) -> Result<()> {
+    checkout_commit(id, repo)?;
+    repo.set_head(id, reason)
+}
+
+/// where HEAD is, as `checkout: moving from ...` in the reflog says it: the
+/// name of its branch, or its commit if it's detached
+fn head_label(repo: &Repo) -> Result<String> {
+    Ok(match rev::head_branch(repo)? {
+        Some(branch) => branch
+            .strip_prefix("refs/heads/")
+            .unwrap_or(&branch)
+            .to_owned(),
+        None => repo.head()?.to_string(),
+    })
+}
+
+/// the tree of the commit `id`
+fn commit_tree_of(id: &Id, repo: &Repo) -> Result<Tree> {
+    let cmt = match repo.open(id)? {
+        Object::Commit(cmt) => cmt,
+        _ => return Err(anyhow!("{} is not a commit", id)),
+    };
+    match repo.open(&cmt.tree)? {
+        Object::Tree(t) => Ok(t),
+        _ => Err(anyhow!("commit tree was not a tree")),
+    }
+}
+
+/// refuses to go on if checking out `tree` would lose uncommitted changes or
+/// overwrite untracked files
+fn ensure_nothing_lost(tree: &Tree, repo: &Repo) -> Result<()> {
+    let head = match repo.read_head()? {
+        Head::Commit(head) => Some(commit_tree_of(&head, repo)?),
+        Head::Unborn(_) => None,
+    };
+    let overwritten = checkout::overwritten_files(&repo.index()?, head.as_ref(), tree, repo)?;
+    if !overwritten.is_empty() {
+        return Err(checkout::CheckoutError::WouldOverwrite(overwritten).into());
+    }
+    Ok(())
+}
+
+/// makes the index and working tree match a commit, without moving HEAD
+fn checkout_commit(id: &Id, repo: &Repo) -> Result<()> {
+    let tree = commit_tree_of(id, repo)?;
+
+    let old_index = repo.index()?;
+    let workers = checkout::workers(&repo.config()?)?;
+    let new_index = checkout::checkout_tree(&tree, workers, repo)?;
+    remove_dropped_files(&old_index, &new_index, repo)?;
+    repo.write_index(&new_index)
+}
+
+/// deletes tracked files that aren't in the new index
+fn remove_dropped_files(
+    old_index: &index::Index,
+    new_index: &index::Index,
+    repo: &Repo,
+) -> Result<()> {
+    let tree_root = repo.tree_root();
+    for IndexEntry { name, .. } in old_index {
+        if new_index.binary_search_by(|e| e.name.cmp(name)).is_err() {
+            repo.vfs()
+                .remove_file(&tree_root.join(to_platform_path(name)))
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })
+                .with_context(|| format!("failed to remove {}", name))?;
+        }
+    }
+    Ok(())
+}
+
+/// copies files from the index or a commit back into the working tree, or from
+/// HEAD or a commit into the index
+pub fn restore(
+    args::Restore {
+        paths,
+        source,
+        staged,
+        worktree,
+    }: args::Restore,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let worktree = worktree || !staged;
+    let precompose = precomposes_unicode(&repo)?;
+    let prefixes = paths
+        .iter()
+        .map(|p| worktree_name_of_any(Path::new(p), &repo, precompose))
+        .collect::<Result<Vec<_>>>()?;
+    let matches = |prefix: &[u8], name: &[u8]| {
+        prefix.is_empty()
+            || name == prefix
+            || (name.starts_with(prefix) && name.get(prefix.len()) == Some(&b'/'))
+    };
+    let in_pathspec = |name: &[u8]| prefixes.iter().any(|prefix| matches(prefix, name));
+
+    let mut index = repo.index()?;
+    // the working tree is restored from the index unless a commit is given,
+    // but the index can only be restored from a commit
+    let from_index = source.is_none() && !staged;
+    let files = if from_index {
+        index
+            .iter()
+            .filter(|e| in_pathspec(&e.name))
+            .map(|e| (e.name.clone(), e.meta.id, e.meta.file_mode()))
+            .collect::<Vec<_>>()
+    } else {
+        let commit = match source {
+            Some(rev) => Some(rev::peel_tags(rev::parse(&rev, &repo)?, &repo)?),
+            None => match repo.read_head()? {
+                Head::Commit(id) => Some(id),
+                Head::Unborn(_) => None,
+            },
+        };
+        let mut files = Vec::new();
+        if let Some(commit) = commit {
+            load_tree_from_disk(&commit_tree_of(&commit, &repo)?, &repo, b"", &mut files)?;
+        }
+        files.retain(|(name, _, _)| in_pathspec(name));
+        files
+    };
+
+    for (path, prefix) in paths.iter().zip(&prefixes) {
+        let known = files
+            .iter()
+            .map(|(name, _, _)| name)
+            .chain(index.iter().map(|e| &e.name))
+            .any(|name| matches(prefix, name));
+        if !known {
+            return Err(anyhow!(
+                "pathspec '{}' did not match any file(s) known to git",
+                path
+            ));
+        }
+    }
+
+    // tracked files that the source doesn't have are removed
+    let dropped = index
+        .iter()
+        .filter(|e| in_pathspec(&e.name))
+        .filter(|e| {
+            files
+                .binary_search_by(|(name, _, _)| name.cmp(&e.name))
+                .is_err()
+        })
+        .map(|e| e.name.clone())
+        .collect::<Vec<_>>();
+    if staged {
+        for name in &dropped {
+            index::remove_entry(&mut index, name);
+        }
+        for (name, id, mode) in &files {
+            index::insert_entry(
+                &mut index,
+                IndexEntry {
+                    name: name.clone(),
+                    meta: index::IndexMeta::for_blob(name, *id, *mode),
+                },
+            );
+        }
+    }
+    if worktree {
+        let tree_root = repo.tree_root();
+        for name in &dropped {
+            repo.vfs()
+                .remove_file(&tree_root.join(to_platform_path(name)))
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })
+                .with_context(|| format!("failed to remove {}", name))?;
+        }
+        // submodules have nothing to write
+        let blobs = files
+            .into_iter()
+            .filter(|(_, _, mode)| mode.is_blob())
+            .collect::<Vec<_>>();
+        let workers = checkout::workers(&repo.config()?)?;
+        let written = checkout::checkout_files(&blobs, workers, &repo)?;
+        // where the index has what was written, its stats can be updated
+        if from_index || staged {
+            for entry in written {
+                index::insert_entry(&mut index, entry);
+            }
+        }
+    }
+    repo.write_index(&index)
+}
+
+/// removes files from the index, and from the working tree unless --cached
+/// is given
+pub fn rm(
+    args::Rm {
+        paths,
+        cached,
+        force,
+        recursive,
+    }: args::Rm,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let precompose = precomposes_unicode(&repo)?;
+    let mut index = repo.index()?;
+
+    let mut names = Vec::new();
+    for path in &paths {
+        let prefix = worktree_name_of_any(Path::new(path), &repo, precompose)?;
+        let in_dir = |name: &[u8]| {
+            prefix.is_empty()
+                || (name.starts_with(&prefix) && name.get(prefix.len()) == Some(&b'/'))
+        };
+        if index.iter().any(|e| e.name == prefix) {
+            names.push(prefix.clone());
+        } else if index.iter().any(|e| in_dir(&e.name)) {
+            if !recursive {
+                return Err(anyhow!("not removing '{}' recursively without -r", path));
+            }
+            names.extend(
+                index
+                    .iter()
+                    .filter(|e| in_dir(&e.name))
+                    .map(|e| e.name.clone()),
+            );
+        } else {
+            return Err(anyhow!("pathspec '{}' did not match any files", path));
+        }
+    }
+    names.sort();
+    names.dedup();
+
+    let tree_root = repo.tree_root();
+    if !force {
+        let head_tree = match repo.read_head()? {
+            Head::Commit(head) => Some(commit_tree_id_of(&head, &repo)?),
+            Head::Unborn(_) => None,
+        };
+        for name in &names {
+            // either side of a conflict can be removed
+            let entry = match index.iter().find(|e| e.name == *name) {
+                Some(entry) if entry.meta.stage() == 0 => entry,
+                _ => continue,
+            };
+            let in_head = match &head_tree {
+                Some(tree) => entry_at(tree, name, &repo)?,
+                None => None,
+            };
+            let staged = in_head != Some((entry.meta.id, entry.meta.file_mode()));
+            let exists = repo
+                .vfs()
+                .symlink_metadata(&tree_root.join(to_platform_path(name)))
+                .is_ok();
+            let modified = exists && !entry.is_same_as_tree(&repo)?;
+            let problem = if staged && modified {
+                "has staged content different from both the file and HEAD"
+            } else if cached {
+                continue;
+            } else if staged {
+                "has changes staged in the index"
+            } else if modified {
+                "has local modifications"
+            } else {
+                continue;
+            };
+            return Err(anyhow!(
+                "{} {}; use --cached to keep the file, or -f to remove it anyway",
+                name,
+                problem
+            ));
+        }
+    }
+
+    for name in &names {
+        index::remove_entry(&mut index, name);
+        if !cached {
+            let path = tree_root.join(to_platform_path(name));
+            repo.vfs()
+                .remove_file(&path)
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })
+                .with_context(|| format!("failed to remove {}", name))?;
+            // directories left empty go too, as git doesn't track them
+            for dir in path.ancestors().skip(1) {
+                if dir == tree_root || std::fs::remove_dir(dir).is_err() {
+                    break;
+                }
+            }
         }
+        println!("rm '{}'", name);
     }
+    repo.write_index(&index)
+}
 
-    // If we have no commits, we should compare working tree to HEAD
-    if commits.len() == 0 {
-        // default to looking at HEAD
-        // we don't support diffing against a nonexistent HEAD because my HEAD
-        // hurts too much for this right now
-        commits.push(rev::parse("HEAD", &repo)?);
+/// switches to another branch, or to a new one with no history
+pub fn switch(
+    args::Switch {
+        branch,
+        start_point,
+        create,
+        orphan,
+        keep_worktree,
+        discard_changes,
+    }: args::Switch,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let refname = format!("refs/heads/{}", branch);
+    let reason = format!("checkout: moving from {} to {}", head_label(&repo)?, branch);
+
+    if orphan {
+        if rev::list_refs(&repo)?
+            .iter()
+            .any(|(name, _)| *name == refname)
+        {
+            return Err(anyhow!("a branch named '{}' already exists", branch));
+        }
+        // the next commit starts the branch from scratch, with whatever is
+        // added by then
+        let old_index = repo.index()?;
+        let new_index = index::Index::default();
+        if !keep_worktree {
+            remove_dropped_files(&old_index, &new_index, &repo)?;
+        }
+        repo.write_index(&new_index)?;
+        return rev::set_head_ref(&refname, &reason, &repo);
+    }
+    if keep_worktree {
+        return Err(anyhow!("--keep-worktree only makes sense with --orphan"));
     }
-    debug!("diffing {:?} commits for {:?} files", &commits, &files);
-    diff_trees(&commits[0], &commits[1], "", &repo)?;
 
-    // let id_a = rev::parse(&ref_a, &repo).context("Finding A reference")?;
-    // let id_b = rev::parse(&ref_b, &repo).context("Finding B reference")?;
+    let exists = rev::list_refs(&repo)?
+        .iter()
+        .any(|(name, _)| *name == refname);
+    let id = if create {
+        if exists {
+            return Err(anyhow!("a branch named '{}' already exists", branch));
+        }
+        rev::peel_tags(
+            rev::parse(start_point.as_deref().unwrap_or("HEAD"), &repo)?,
+            &repo,
+        )?
+    } else if start_point.is_some() {
+        return Err(anyhow!("a start point only makes sense with -c"));
+    } else if exists {
+        rev::parse(&refname, &repo)?
+    } else {
+        return Err(anyhow!("no branch named '{}'", branch));
+    };
 
-    // let tree_a = repo
-    //     .open(&id_a)
-    //     .context("Opening tree A")?
-    //     .commit()
-    //     .with_context(|| format!("Tree A ref {} did not point to a commit", &ref_a))?
-    //     .tree;
-    // let tree_a = repo
-    //     .open(&tree_a)
-    //     .context("Opening tree A")?
-    //     .tree()
-    //     .context("Tree A is not a tree")?;
+    // a new branch at HEAD has the same files, so changes can stay
+    if repo.read_head()? != Head::Commit(id) {
+        if !discard_changes {
+            ensure_nothing_lost(&commit_tree_of(&id, &repo)?, &repo)?;
+        }
+        checkout_commit(&id, &repo)?;
+    }
+    if create {
+        let created = format!(
+            "branch: Created from {}",
+            start_point.as_deref().unwrap_or("HEAD")
+        );
+        rev::update_ref(Path::new(&refname), &id, &created, &repo)?;
+    }
+    rev::set_head_ref(&refname, &reason, &repo)
+}
 
-    // let tree_b = repo
-    //     .open(&id_b)
-    //     .context("Opening tree B")?
-    //     .commit()
-    //     .with_context(|| format!("Tree B ref {} did not point to a commit", &ref_b))?
-    //     .tree;
-    // let tree_b = repo
-    //     .open(&tree_b)
-    //     .context("Opening tree B")?
-    //     .tree()
-    //     .context("Tree B is not a tree")?;
+/// lists tags, or makes a lightweight or annotated one
+pub fn tag(
+    args::Tag {
+        name,
+        object,
+        annotate,
+        message,
+        force,
+        who,
+    }: args::Tag,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let name = match name {
+        Some(name) => name,
+        None => {
+            for (refname, _) in rev::list_refs(&repo)? {
+                if let Some(tag) = refname.strip_prefix("refs/tags/") {
+                    println!("{}", tag);
+                }
+            }
+            return Ok(());
+        }
+    };
+    let refname = format!("refs/tags/{}", name);
+    if !force && rev::list_refs(&repo)?.iter().any(|(r, _)| *r == refname) {
+        return Err(anyhow!("tag '{}' already exists", name));
+    }
+    let target = rev::parse(object.as_deref().unwrap_or("HEAD"), &repo)?;
 
-    Ok(())
+    if !annotate && message.is_none() {
+        let reason = format!("tag: tagging {}", target);
+        return rev::update_ref(Path::new(&refname), &target, &reason, &repo);
+    }
+    let tagger = identity(who, &repo)?;
+    let message = match message {
+        Some(message) => Cleanup::Whitespace.apply(&message),
+        None => {
+            let path = repo.root.join("TAG_EDITMSG");
+            let help = format!(
+                "\n#\n# Write a message for tag:\n#   {}\n\
+                 # Lines starting with '#' will be ignored.\n",
+                name
+            );
+            repo.vfs().write(&path, help.as_bytes())?;
+            launch_editor(&path, &repo)?;
+            let written = String::from_utf8(repo.vfs().read(&path)?)
+                .context("the tag message is not valid UTF-8")?;
+            Cleanup::Strip.apply(&written)
+        }
+    };
+    if message.trim().is_empty() {
+        return Err(anyhow!("aborting tag due to empty tag message"));
+    }
+    let (kind, _) = repo.object_header(&target)?;
+    let tag = repo.store(&Tag {
+        object: target,
+        kind,
+        name,
+        tagger: Some(tagger),
+        message,
+    })?;
+    rev::update_ref(
+        Path::new(&refname),
+        &tag,
+        &format!("tag: tagging {}", target),
+        &repo,
+    )
 }
 
 /// get the changes between the working directory ~ index and the index ~ HEAD
-pub fn status() -> Result<()> {
+pub fn status(args::Status { porcelain, json }: args::Status) -> Result<()> {
     let repo = Repo::new().context("failed to find repo")?;
 
-    let head = repo.head()?;
-
-    let cmt = match repo.open(&head)? {
-        Object::Commit(cmt) => cmt,
-        _ => return Err(anyhow!("HEAD was not a commit")),
+    // on a branch with no commits yet, everything in the index is new
+    let (head_tree_id, head_tree) = match repo.read_head()? {
+        Head::Commit(head) => {
+            let cmt = match repo.open(&head)? {
+                Object::Commit(cmt) => cmt,
+                _ => return Err(anyhow!("HEAD was not a commit")),
+            };
+            match repo.open(&cmt.tree)? {
+                Object::Tree(t) => (Some(cmt.tree), t),
+                _ => return Err(anyhow!("commit tree was not a tree")),
+            }
+        }
+        Head::Unborn(_) => (None, Tree { files: Vec::new() }),
     };
 
-    let head_tree = match repo.open(&cmt.tree)? {
-        Object::Tree(t) => t,
-        _ => return Err(anyhow!("commit tree was not a tree")),
-    };
+    let index_filelist = repo.index()?;
 
-    // Future optimization: use the cached subtree extension
+    // directories the index's cached tree says are the same as in HEAD can't
+    // have anything staged in them, so we don't load them at all
+    let cache_tree = index_filelist.cache_tree.as_ref();
     let mut head_filelist = Vec::new();
-    load_tree_from_disk(head_tree, &repo, "", &mut head_filelist)?;
+    let mut unchanged_dirs = Vec::new();
+    let all_unchanged = head_tree_id.is_some() && cache_tree.and_then(|c| c.id) == head_tree_id;
+    if !all_unchanged {
+        load_tree_from_disk_cached(
+            &head_tree,
+            &repo,
+            b"",
+            cache_tree,
+            &mut head_filelist,
+            &mut unchanged_dirs,
+        )?;
+    }
+    let unchanged_dirs = unchanged_dirs.into_iter().collect::<HashSet<_>>();
+    let in_unchanged_dir = |name: &[u8]| {
+        all_unchanged
+            || name
+                .iter()
+                .enumerate()
+                .any(|(n, &b)| b == b'/' && unchanged_dirs.contains(name[..n].as_bstr()))
+    };
 
     let mut diff_head = head_filelist
         .iter()
-        .map(|(ref name, ref id)| (name.as_str(), id));
+        .map(|(ref name, ref id, _)| (name.as_bstr(), id));
 
-    let index_filelist = repo.index()?;
     let mut diff_index = index_filelist
         .iter()
-        .map(|IndexEntry { ref name, meta: ie }| (name.as_str(), &ie.id));
+        .filter(|ie| !in_unchanged_dir(ie.name.as_slice()))
+        .map(|IndexEntry { ref name, meta: ie }| (name.as_bstr(), &ie.id));
 
     let diffs = diff_file_lists(&mut diff_head, &mut diff_index);
 
+    // stat (and, if the stats changed, hash) the files in the index across
+    // threads. Entries are handed out in batches since a stat alone is too
+    // little work to be worth a task, and collect keeps them in index order
+    // so the output is deterministic
+    const BATCH_SIZE: usize = 64;
+    let fsmonitor = query_fsmonitor(&repo);
+    let modified = index_filelist
+        .entries
+        .par_iter()
+        .with_min_len(BATCH_SIZE)
+        .map(|ie| match &fsmonitor {
+            // the filesystem monitor says nothing touched this file
+            Some(f) if !f.may_have_changed(&ie.name) => Ok((ie, None)),
+            _ if ie.is_deleted(&repo)? => Ok((ie, Some(Change::Deleted))),
+            _ if ie.is_same_as_tree(&repo)? => Ok((ie, None)),
+            _ => Ok((ie, Some(Change::Modified))),
+        })
+        .collect::<Result<Vec<_>>>()
+        .context("hecked up while checking if files are the same as they are in the tree")?
+        .into_iter()
+        .filter_map(|(ie, change)| change.map(|c| (ie, c)))
+        .collect::<Vec<_>>();
+    if let Some(f) = fsmonitor {
+        f.save(&repo, modified.iter().map(|(ie, _)| ie.name.as_slice()))?;
+    }
+
+    if porcelain || json {
+        let untracked = index::untracked_files(&index_filelist, &repo)?;
+        let mut entries = BTreeMap::new();
+        for (name, diff) in diffs {
+            let staged = match diff {
+                Diff::Different(_, _) => Change::Modified,
+                Diff::ExtraInLeft(_) => Change::Deleted,
+                Diff::ExtraInRight(_) => Change::Added,
+            };
+            entries.insert(
+                name,
+                StatusEntry {
+                    path: name.to_str_lossy(),
+                    staged: Some(staged),
+                    worktree: None,
+                },
+            );
+        }
+        for &(ie, change) in &modified {
+            entries
+                .entry(ie.name.as_bstr())
+                .or_insert(StatusEntry {
+                    path: ie.name.to_str_lossy(),
+                    staged: None,
+                    worktree: None,
+                })
+                .worktree = Some(change);
+        }
+        for name in &untracked {
+            entries.insert(
+                name.as_bstr(),
+                StatusEntry {
+                    path: name.to_str_lossy(),
+                    staged: None,
+                    worktree: Some(Change::Untracked),
+                },
+            );
+        }
+
+        if json {
+            return print_json(&entries.values().collect::<Vec<_>>());
+        }
+        let letter = |c: Option<Change>| c.map_or(' ', Change::letter);
+        for entry in entries.values() {
+            // untracked files are `??` rather than being unchanged in the index
+            let staged = match entry.worktree {
+                Some(Change::Untracked) => '?',
+                _ => letter(entry.staged),
+            };
+            println!("{}{} {}", staged, letter(entry.worktree), entry.path);
+        }
+        return Ok(());
+    }
+
     let sigil = |d| match d {
         // change in index
         Diff::Different(_, _) => "~",
@@ -232,15 +3127,10 @@ pub fn status() -> Result<()> {
         println!("{} {}", sigil(diff), name);
     }
 
-    let modified = index_filelist.iter().filter(|ie| {
-        !ie.is_same_as_tree(&repo)
-            .expect("hecked up while checking if files are the same as they are in the tree")
-    });
-
     // TODO: show untracked files
     println!("\nModified files in working tree");
-    for f in modified {
-        println!("~ {}", f.name);
+    for (ie, _) in modified {
+        println!("~ {}", ie.name);
     }
 
     Ok(())
@@ -250,7 +3140,8 @@ pub fn status() -> Result<()> {
 // Plumbing Commands
 // -----------------------------------------
 
-/// makes a commit of a tree
+/// makes a commit of a tree on top of HEAD, and of the commit being merged
+/// if a merge stopped for conflicts
 pub fn commit_tree(id: Id, who: String, message: String) -> Result<()> {
     let repo = Repo::new().context("couldn't find repo")?;
     if !repo.has_id(&id) {
@@ -268,10 +3159,21 @@ pub fn commit_tree(id: Id, who: String, message: String) -> Result<()> {
     let time = DateTime::<FixedOffset>::from_utc(time.naive_utc(), offs.clone());
     let who = NameEntry::with_time(&who, time).context("invalid `who`")?;
 
-    let mut parents = Vec::new();
-    if let Ok(head) = repo.head() {
-        parents.push(head);
+    // the first commit on a branch has no parents
+    let mut parents = match repo.read_head()? {
+        Head::Commit(head) => vec![head],
+        Head::Unborn(_) => Vec::new(),
+    };
+    if let Ok(merge_head) = repo.vfs().read(&repo.root.join("MERGE_HEAD")) {
+        let merge_head = String::from_utf8_lossy(&merge_head);
+        parents.push(Id::from(merge_head.trim()).context("MERGE_HEAD is not an ID")?);
     }
+    let kind = match parents.len() {
+        0 => " (initial)",
+        1 => "",
+        _ => " (merge)",
+    };
+    let reason = format!("commit{}: {}", kind, patch::split_message(&message).0);
 
     let commit_object = Commit {
         author: who.clone(),
@@ -282,7 +3184,8 @@ pub fn commit_tree(id: Id, who: String, message: String) -> Result<()> {
     };
 
     let commit_id = repo.store(&commit_object)?;
-    repo.set_head(&commit_id)?;
+    repo.set_head(&commit_id, &reason)?;
+    clear_merge_state(&repo)?;
     println!("HEAD is now {}", &commit_id);
 
     Ok(())
@@ -305,34 +3208,33 @@ pub fn new_tree(paths: Vec<String>) -> Result<()> {
     for &path in &paths {
         let repo_relative = repo.repo_relative(path)?;
 
-        let blob = Blob::new_from_disk(path)
+        let blob = repo
+            .store_file(path)
             .context(anyhow!("failed to read blob {} from disk", &path.display()))?;
-        let blob = repo.store(&blob)?;
+        let mode = if repo.vfs().metadata(path)?.executable {
+            FileMode::Executable
+        } else {
+            FileMode::Regular
+        };
 
         let mut next_tree = &mut tree;
 
         for part in repo_relative.parent().unwrap() {
-            let part = part
-                .to_str()
-                .context("XXX: only unicode paths are supported")?;
+            let part = Vec::from_os_str_lossy(part).into_owned();
 
             next_tree = next_tree
                 .subtree_mut()
                 .unwrap()
-                .entry(part.to_owned())
+                .entry(part.into())
                 .or_insert_with(|| TreeEntry::SubTree(SubTree::new()));
         }
 
-        let filename = path
-            .file_name()
-            .unwrap()
-            .to_str()
-            .context("XXX: only unicode filenames are supported")?;
+        let filename = Vec::from_os_str_lossy(path.file_name().unwrap()).into_owned();
 
         next_tree
             .subtree_mut()
             .unwrap()
-            .insert(filename.to_owned(), TreeEntry::Blob(blob));
+            .insert(filename.into(), TreeEntry::Blob(blob, mode));
     }
 
     let id = save_subtree(&mut tree, &repo)?;
@@ -341,10 +3243,122 @@ pub fn new_tree(paths: Vec<String>) -> Result<()> {
     Ok(())
 }
 
-/// dumps the content of an object in the database for debugging purposes
-pub fn catfile(id: &str, output: OutputType) -> Result<()> {
-    let id = Id::from(id).context("invalid ID format")?;
+/// finds a path's name in the worktree. Unlike `worktree_name`, the path
+/// doesn't have to exist, as long as the directory it would be in does.
+fn worktree_name_of_any(path: &Path, repo: &Repo, precompose: bool) -> Result<BString> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    while !existing.as_os_str().is_empty() && !existing.exists() {
+        rest.push(existing.file_name().context("path has no file name")?);
+        existing = existing.parent().unwrap_or_else(|| Path::new(""));
+    }
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+    let mut name = worktree_name(existing, repo, precompose)?;
+    for component in rest.iter().rev() {
+        if !name.is_empty() {
+            name.push(b'/');
+        }
+        name.push_str(Path::new(component).to_git_bytes());
+    }
+    Ok(name)
+}
+
+/// shows whether paths are ignored, and by which pattern
+pub fn check_ignore(
+    args::CheckIgnore {
+        paths,
+        verbose,
+        non_matching,
+        no_index,
+    }: args::CheckIgnore,
+) -> Result<()> {
+    if non_matching && !verbose {
+        return Err(anyhow!("--non-matching only makes sense with --verbose"));
+    }
+    let repo = Repo::new().context("failed to find repo")?;
+    let precompose = precomposes_unicode(&repo)?;
+    let index = repo.index()?;
+    let mut ignores = Ignores::new(&repo)?;
+
+    let mut any_ignored = false;
+    for path in &paths {
+        let name = worktree_name_of_any(Path::new(path), &repo, precompose)?;
+        let tracked = !no_index && index.iter().any(|ie| ie.name == name);
+        let is_dir = path.ends_with('/') || Path::new(path).is_dir();
+        let found = if tracked {
+            None
+        } else {
+            ignores.matching(&name, is_dir)
+        };
+        any_ignored |= found.map_or(false, |m| !m.pattern.is_negated());
+        match found {
+            Some(m) if verbose => println!(
+                "{}:{}:{}\t{}",
+                m.source, m.pattern.line, m.pattern.text, path
+            ),
+            Some(m) if !m.pattern.is_negated() => println!("{}", path),
+            None if non_matching => println!("::\t{}", path),
+            _ => (),
+        }
+    }
+    if !any_ignored {
+        return Err(Exit(1).into());
+    }
+    Ok(())
+}
+
+/// exits with 1, printing nothing, if a ref name isn't valid, which doesn't
+/// need a repository
+pub fn check_ref_format(
+    args::CheckRefFormat {
+        name,
+        allow_onelevel,
+        normalize,
+    }: args::CheckRefFormat,
+) -> Result<()> {
+    let name = if normalize {
+        rev::normalize_refname(&name)
+    } else {
+        name
+    };
+    if !rev::is_valid_refname(&name, allow_onelevel) {
+        return Err(Exit(1).into());
+    }
+    if normalize {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+/// dumps the content of an object in the database for debugging purposes. The
+/// object can be given as any revision, such as `HEAD:src/main.rs`
+pub fn catfile(
+    args::CatFile {
+        git_ref,
+        output,
+        json,
+        show_type,
+        show_size,
+    }: args::CatFile,
+) -> Result<()> {
     let repo = Repo::new().context("failed to find repo")?;
+    let id = rev::parse(&git_ref, &repo)?;
+    // these only need the header, so the object isn't read in full
+    if show_type {
+        println!("{}", repo.object_info(&id)?.kind);
+        return Ok(());
+    }
+    if show_size {
+        println!("{}", repo.object_info(&id)?.size);
+        return Ok(());
+    }
+    if json {
+        return print_json(&repo.open(&id)?);
+    }
     let mut h = repo.open_object_raw(&id)?;
     match output {
         OutputType::Raw => {
@@ -366,19 +3380,60 @@ pub fn catfile(id: &str, output: OutputType) -> Result<()> {
     Ok(())
 }
 
+/// writes every ref and the history behind it as a fast-import stream
+pub fn fast_export() -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    fast_export::fast_export(&repo, &mut out)
+}
+
+/// builds objects and refs from a fast-import stream on stdin
+pub fn fast_import(args: args::FastImport) -> Result<()> {
+    let args::FastImport {
+        force,
+        import_marks,
+        export_marks,
+    } = args;
+    let repo = Repo::new().context("failed to find repo")?;
+    let marks = match import_marks {
+        Some(path) => fast_import::read_marks(
+            &std::fs::read_to_string(&path).with_context(|| format!("failed to read {}", path))?,
+        )?,
+        None => Marks::new(),
+    };
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let marks = fast_import::fast_import(&input, marks, force, &repo, &mut out)?;
+    out.flush()?;
+    if let Some(path) = export_marks {
+        std::fs::write(&path, fast_import::write_marks(&marks))
+            .with_context(|| format!("failed to write {}", path))?;
+    }
+    Ok(())
+}
+
 /// parses and prints various objects in debug format
-pub fn debug(what: args::DebugType) -> Result<()> {
+pub fn debug(what: args::DebugType, json: bool) -> Result<()> {
     let repo = Repo::new().context("failed to find repo")?;
 
     match what {
         args::DebugType::Index => {
             let indexfile = repo.root.join("index");
 
-            let h = OpenOptions::new()
-                .read(true)
-                .open(indexfile)
+            let h = repo
+                .vfs()
+                .open(&indexfile)
                 .context("failed opening index file")?;
-            println!("{:#x?}", index::parse(BufReader::new(h))?);
+            let index = index::parse(BufReader::new(h))?;
+            if json {
+                print_json(&index)?;
+            } else {
+                println!("{:#x?}", index);
+            }
         }
         args::DebugType::Test => {
             // a debug entry point
@@ -387,17 +3442,578 @@ pub fn debug(what: args::DebugType) -> Result<()> {
     Ok(())
 }
 
-pub fn rev_parse(find_rev: String) -> Result<()> {
+/// A resolved revision as shown by `rev-parse --json`
+#[derive(Serialize)]
+struct ParsedRev<'a> {
+    rev: &'a str,
+    id: Id,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    short: Option<String>,
+}
+
+/// list commits reachable from some and not others, like `git rev-list`
+pub fn rev_list(
+    args::RevList {
+        revs,
+        max_count,
+        objects,
+        topo_order,
+        count,
+        use_bitmap_index,
+    }: args::RevList,
+) -> Result<()> {
+    let repo = Repo::new().context("Failed to find the repo")?;
+    let (tips, hidden) = rev::parse_revs(&revs, &repo)?;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    // bitmaps can't say which commits come first, so are no help for -n
+    if use_bitmap_index && max_count.is_none() && !topo_order {
+        if let Some((bitmap, found)) = bitmap::reachable_objects(&repo, &tips, &hidden)? {
+            let listed = bitmap
+                .objects(&found)
+                .filter(|(_, kind)| objects || *kind == ObjectType::Commit);
+            if count {
+                writeln!(out, "{}", listed.count())?;
+            } else {
+                for (id, _) in listed {
+                    writeln!(out, "{}", id)?;
+                }
+            }
+            return Ok(());
+        }
+    }
+
+    let mut walk = RevWalk::new(&repo, &tips)?.hide(&hidden)?;
+    if topo_order {
+        walk = walk.topo_order();
+    }
+    let commits = walk
+        .take(max_count.unwrap_or(usize::MAX))
+        .collect::<Result<Vec<_>>>()?;
+    if count {
+        let mut total = commits.len();
+        if objects {
+            total += revwalk::tree_objects(&repo, &commits, &hidden)?.len();
+        }
+        writeln!(out, "{}", total)?;
+        return Ok(());
+    }
+    for id in &commits {
+        writeln!(out, "{}", id)?;
+    }
+    if objects {
+        for obj in revwalk::tree_objects(&repo, &commits, &hidden)? {
+            out.write_all(format!("{} ", obj.id).as_bytes())?;
+            out.write_all(&obj.path)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn rev_parse(args::RevParse { rev, short, json }: args::RevParse) -> Result<()> {
+    let repo = Repo::new().context("Failed to find the repo")?;
+    let id = rev::parse(&rev, &repo)?;
+    let short = if short {
+        // same default and minimum as git. "auto" isn't a number and gets the
+        // default too
+        let config = repo.config()?;
+        let min_len = config.get_int("core.abbrev").ok().flatten().unwrap_or(7);
+        let len = rev::abbrev_len(&id, min_len.max(4) as usize, &repo)?;
+        Some(id.to_string()[..len].to_owned())
+    } else {
+        None
+    };
+
+    if json {
+        print_json(&ParsedRev {
+            rev: &rev,
+            id,
+            short,
+        })
+    } else {
+        match short {
+            Some(short) => println!("{}", short),
+            None => println!("{}", id),
+        }
+        Ok(())
+    }
+}
+
+/// cleans up a message from stdin the way commit messages are
+pub fn stripspace(args::Stripspace { strip_comments }: args::Stripspace) -> Result<()> {
+    let mut text = String::new();
+    io::stdin().read_to_string(&mut text)?;
+    print!("{}", util::stripspace(&text, strip_comments));
+    Ok(())
+}
+
+/// prints the ref a symref points to, or points it at another
+/// lists refs, like `git show-ref`. Fails if nothing is listed
+pub fn show_ref(
+    args::ShowRef {
+        patterns,
+        heads,
+        tags,
+        verify,
+    }: args::ShowRef,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let refs = rev::list_refs(&repo)?;
+
+    if verify {
+        if patterns.is_empty() {
+            return Err(anyhow!("--verify needs a ref"));
+        }
+        for name in patterns {
+            let (_, id) = refs
+                .iter()
+                .find(|(refname, _)| *refname == name)
+                .ok_or_else(|| anyhow!("'{}' - not a valid ref", name))?;
+            println!("{} {}", id, name);
+        }
+        return Ok(());
+    }
+
+    let mut found = false;
+    for (name, id) in &refs {
+        let kind_matches = match (heads, tags) {
+            (false, false) => true,
+            _ => {
+                (heads && name.starts_with("refs/heads/"))
+                    || (tags && name.starts_with("refs/tags/"))
+            }
+        };
+        // patterns match whole path components from the end
+        let name_matches = patterns.is_empty()
+            || patterns.iter().any(|pattern| {
+                name == pattern
+                    || name
+                        .strip_suffix(pattern.as_str())
+                        .map_or(false, |rest| rest.ends_with('/'))
+            });
+        if kind_matches && name_matches {
+            println!("{} {}", id, name);
+            found = true;
+        }
+    }
+    if found {
+        Ok(())
+    } else {
+        Err(anyhow!("no matching refs"))
+    }
+}
+
+pub fn symbolic_ref(
+    args::SymbolicRef {
+        name,
+        target,
+        short,
+    }: args::SymbolicRef,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    if let Some(target) = target {
+        return rev::set_symref(&name, &target, &repo);
+    }
+    let target =
+        rev::read_symref(&name, &repo)?.ok_or_else(|| anyhow!("{} is not a symbolic ref", name))?;
+    let target = if short {
+        ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+            .iter()
+            .find_map(|prefix| target.strip_prefix(prefix))
+            .unwrap_or(&target)
+    } else {
+        &target
+    };
+    println!("{}", target);
+    Ok(())
+}
+
+/// packs the objects listed on stdin, or everything reachable from the
+/// revisions listed there with `--revs`
+pub fn pack_objects(
+    args::PackObjects {
+        base_name,
+        stdout,
+        revs,
+    }: args::PackObjects,
+) -> Result<()> {
+    let mut repo = Repo::new().context("failed to find repo")?;
+    repo.set_replace_objects(false);
+    match (&base_name, stdout) {
+        (Some(_), true) => return Err(anyhow!("a base name can't be given with --stdout")),
+        (None, false) => return Err(anyhow!("a base name or --stdout is needed")),
+        _ => (),
+    }
+
+    let mut input = String::new();
+    io::stdin().read_to_string(&mut input)?;
+    let lines = input.lines().map(str::trim).filter(|line| !line.is_empty());
+    let objects = if revs {
+        let (mut tips, mut hidden) = (Vec::new(), Vec::new());
+        let mut not = false;
+        for line in lines {
+            if line == "--not" {
+                not = !not;
+                continue;
+            }
+            let (hide, rev) = if line.starts_with('^') {
+                (!not, &line[1..])
+            } else {
+                (not, line)
+            };
+            let id = rev::parse(rev, &repo)?;
+            if hide {
+                hidden.push(id);
+            } else {
+                tips.push(id);
+            }
+        }
+        revwalk::reachable_objects(&repo, &tips, &hidden)?
+    } else {
+        // each line is an ID, which may be followed by the path it's at,
+        // which helps find objects worth storing as deltas of each other
+        let mut seen = HashSet::new();
+        let mut objects = Vec::new();
+        for line in lines {
+            let (word, path) = match line.find(' ') {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (line, ""),
+            };
+            let id =
+                Id::from(word).ok_or_else(|| anyhow!("expected an object ID, got '{}'", line))?;
+            if seen.insert(id) {
+                objects.push(FoundObject {
+                    id,
+                    kind: repo.object_header(&id)?.0,
+                    path: path.into(),
+                });
+            }
+        }
+        objects
+    };
+
+    let base_name = match base_name {
+        Some(base_name) => base_name,
+        None => {
+            let stdout = io::stdout();
+            packs::write_pack(&repo, &objects, BufWriter::new(stdout.lock()))?;
+            return Ok(());
+        }
+    };
+    // the name has the checksum in it, which isn't known until the end
+    let temp_pack = PathBuf::from(format!("{}-tmp-{}.pack", base_name, std::process::id()));
+    let temp_idx = temp_pack.with_extension("idx");
+    let create = |path: &Path| {
+        std::fs::File::create(path)
+            .map(BufWriter::new)
+            .with_context(|| format!("failed to create {}", path.display()))
+    };
+    let checksum =
+        packs::write_pack_with_index(&repo, &objects, create(&temp_pack)?, create(&temp_idx)?)?;
+    std::fs::rename(&temp_pack, format!("{}-{}.pack", base_name, checksum))?;
+    std::fs::rename(&temp_idx, format!("{}-{}.idx", base_name, checksum))?;
+    println!("{}", checksum);
+    Ok(())
+}
+
+/// prints the IDs files would have as objects, storing them if asked to
+pub fn hash_object(
+    args::HashObject {
+        files,
+        objtype,
+        write,
+        stdin,
+    }: args::HashObject,
+) -> Result<()> {
+    let objtype = ObjectType::from_tag(objtype.as_bytes())
+        .ok_or_else(|| anyhow!("invalid object type {:?}", objtype))?;
+    // only storing objects needs a repository
+    let repo = if write {
+        Some(Repo::new().context("failed to find repo")?)
+    } else {
+        None
+    };
+
+    let mut contents = Vec::new();
+    if stdin {
+        let mut content = Vec::new();
+        io::stdin().read_to_end(&mut content)?;
+        contents.push(content);
+    }
+    for file in &files {
+        contents.push(std::fs::read(file).with_context(|| format!("failed to read {}", file))?);
+    }
+
+    for content in contents {
+        Object::check_raw(objtype, &content)
+            .with_context(|| format!("not a valid {} object", objtype))?;
+        let id = match (&repo, objtype) {
+            (Some(repo), ObjectType::Blob) => repo.store(&Blob::new(content))?,
+            (Some(repo), _) => repo.store_raw(objtype, &content)?,
+            (None, _) => Object::hash_raw(objtype, &content)?,
+        };
+        println!("{}", id);
+    }
+    Ok(())
+}
+
+/// writes the index for a pack, printing the pack's checksum
+pub fn index_pack(args::IndexPack { pack, output }: args::IndexPack) -> Result<()> {
+    let pack = PathBuf::from(pack);
+    if pack.extension().map_or(true, |ext| ext != "pack") && output.is_none() {
+        return Err(anyhow!(
+            "{} doesn't end in .pack, so the index needs a name given with -o",
+            pack.display()
+        ));
+    }
+    let output = output.map_or_else(|| pack.with_extension("idx"), PathBuf::from);
+    let data =
+        std::fs::read(&pack).with_context(|| format!("failed to read {}", pack.display()))?;
+
+    // the index isn't put in place until it is complete, as it is what
+    // makes the pack visible
+    let mut temp = output.clone().into_os_string();
+    temp.push(format!(".tmp-{}", std::process::id()));
+    let temp = TempFile::new(Arc::new(vfs::RealFs), PathBuf::from(temp));
+    let mut idx = Vec::new();
+    let checksum = packs::index_pack(&pack, &data, &mut idx)?;
+    std::fs::write(temp.path(), &idx)
+        .and_then(|_| std::fs::rename(temp.path(), &output))
+        .with_context(|| format!("failed to write {}", output.display()))?;
+    temp.keep();
+    println!("{}", checksum);
+    Ok(())
+}
+
+/// stores the objects in a pack as loose objects
+pub fn unpack_objects(args::UnpackObjects { pack, dry_run }: args::UnpackObjects) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let (path, data) = match pack {
+        Some(pack) => {
+            let path = PathBuf::from(pack);
+            let data = std::fs::read(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            (path, data)
+        }
+        None => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            (PathBuf::from("<stdin>"), data)
+        }
+    };
+    packs::unpack_objects(&repo, &path, &data, dry_run)?;
+    Ok(())
+}
+
+/// moves loose refs into packed-refs
+pub fn pack_refs(args::PackRefs { all }: args::PackRefs) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    rev::pack_refs(all, &repo)?;
+    Ok(())
+}
+
+pub fn patch_id(args::PatchId { stable }: args::PatchId) -> Result<()> {
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input)?;
+    let no_commit = Id::from_bytes([0; 20]);
+    for (id, commit) in patch_id::patch_ids(&input, stable) {
+        println!("{} {}", id, commit.unwrap_or(no_commit));
+    }
+    Ok(())
+}
+
+/// A tree entry as shown by `ls-tree`
+#[derive(Serialize)]
+struct LsTreeEntry {
+    mode: FileMode,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: Id,
+    path: String,
+}
+
+/// collects the entries of `tree` into `out`, recursing into subtrees if asked
+fn ls_tree_entries(
+    tree: &Tree,
+    base_path: &[u8],
+    recursive: bool,
+    repo: &Repo,
+    out: &mut Vec<LsTreeEntry>,
+) -> Result<()> {
+    for File { mode, name, id } in &tree.files {
+        let path = join_git_path(base_path, name);
+        let kind = match mode {
+            FileMode::Directory => "tree",
+            FileMode::Gitlink => "commit",
+            _ => "blob",
+        };
+
+        if kind == "tree" && recursive {
+            let subtree = repo
+                .open(id)?
+                .tree()
+                .with_context(|| format!("{} is not a tree", path))?;
+            ls_tree_entries(&subtree, &path, recursive, repo, out)?;
+            continue;
+        }
+        out.push(LsTreeEntry {
+            mode: *mode,
+            kind,
+            id: *id,
+            path: path.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// lists the contents of a tree, or the tree of a commit
+pub fn ls_tree(
+    args::LsTree {
+        tree_ish,
+        recursive,
+        json,
+    }: args::LsTree,
+) -> Result<()> {
     let repo = Repo::new().context("Failed to find the repo")?;
-    println!("{}", rev::parse(&find_rev, &repo)?);
+    let id = rev::parse(&tree_ish, &repo)?;
+    let tree = match repo.open(&id)? {
+        Object::Tree(t) => t,
+        Object::Commit(c) => repo
+            .open(&c.tree)?
+            .tree()
+            .context("commit tree was not a tree")?,
+        Object::Blob(_) => return Err(anyhow!("{} is not a tree or commit", tree_ish)),
+    };
+
+    let mut entries = Vec::new();
+    ls_tree_entries(&tree, b"", recursive, &repo, &mut entries)?;
+
+    if json {
+        return print_json(&entries);
+    }
+    for e in entries {
+        println!("{:06o} {} {}\t{}", u32::from(e.mode), e.kind, e.id, e.path);
+    }
+    Ok(())
+}
+
+/// changes index entries: from files in the working tree, from blobs that are
+/// already stored, or just the stats of files that haven't changed
+pub fn update_index(
+    args::UpdateIndex {
+        files,
+        add,
+        remove,
+        refresh,
+        cacheinfo,
+    }: args::UpdateIndex,
+) -> Result<()> {
+    let repo = Repo::new().context("failed to find repo")?;
+    let mut index = repo.index()?;
+    let tracked = |index: &index::Index, name: &[u8]| {
+        index
+            .binary_search_by(|e| e.name.as_slice().cmp(name))
+            .is_ok()
+    };
+
+    for info in &cacheinfo {
+        let mut fields = info.splitn(3, ',');
+        let (mode, id, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(mode), Some(id), Some(name)) => (mode, id, name),
+            _ => return Err(anyhow!("--cacheinfo takes mode,id,path, not {:?}", info)),
+        };
+        let mode = u32::from_str_radix(mode, 8)
+            .ok()
+            .and_then(|mode| FileMode::try_from(mode).ok())
+            .ok_or_else(|| anyhow!("bad mode {} for {}", mode, name))?;
+        let id = Id::from(id).ok_or_else(|| anyhow!("bad object ID {} for {}", id, name))?;
+        if !repo.has_id(&id) {
+            return Err(anyhow!(
+                "object {} for {} is not in the repository",
+                id,
+                name
+            ));
+        }
+        if !add && !tracked(&index, name.as_bytes()) {
+            return Err(anyhow!("{} is not in the index; use --add to add it", name));
+        }
+        let name = BString::from(name);
+        index::insert_entry(
+            &mut index,
+            IndexEntry {
+                meta: index::IndexMeta::for_blob(&name, id, mode),
+                name,
+            },
+        );
+    }
+
+    let precompose = precomposes_unicode(&repo)?;
+    let tree_root = repo.tree_root();
+    for file in &files {
+        let name = worktree_name_of_any(Path::new(file), &repo, precompose)?;
+        let path = tree_root.join(to_platform_path(&name));
+        match repo.vfs().symlink_metadata(&path) {
+            Err(_) if remove => {
+                index::remove_entry(&mut index, &name);
+            }
+            Err(_) => {
+                return Err(anyhow!(
+                    "{} does not exist; use --remove to remove it from the index",
+                    file
+                ))
+            }
+            Ok(meta) if meta.file_type == vfs::FileType::Dir => {
+                return Err(anyhow!("{} is a directory; give the files in it", file))
+            }
+            Ok(_) if !add && !tracked(&index, &name) => {
+                return Err(anyhow!("{} is not in the index; use --add to add it", file))
+            }
+            Ok(_) => {
+                if let Some(meta) = index::entry_for_file(&index, &name, &repo)? {
+                    index::insert_entry(&mut index, IndexEntry { name, meta });
+                }
+            }
+        }
+    }
+
+    let mut needs_update = Vec::new();
+    if refresh {
+        for entry in index.iter_mut().filter(|e| e.meta.stage() == 0) {
+            let path = tree_root.join(to_platform_path(&entry.name));
+            if repo.vfs().symlink_metadata(&path).is_err() {
+                needs_update.push(entry.name.clone());
+                continue;
+            }
+            let meta = index::IndexMeta::for_stored_file(&entry.name, entry.meta.id, &repo)?;
+            if meta.statinfo() == entry.meta.statinfo() {
+                continue;
+            }
+            // if the content is the same, only the stats need updating
+            if meta.file_mode() == entry.meta.file_mode() && entry.is_same_as_tree(&repo)? {
+                entry.meta = meta;
+            } else {
+                needs_update.push(entry.name.clone());
+            }
+        }
+    }
+
+    repo.write_index(&index)?;
+    if !needs_update.is_empty() {
+        for name in &needs_update {
+            println!("{}: needs update", name);
+        }
+        return Err(anyhow!("some files in the index have changed"));
+    }
     Ok(())
 }
 
 /// Like git update-ref if it was really badly coded and evil.
 /// Your Repo May Vary.
-pub fn update_ref(target: String, new_id: String) -> Result<()> {
+pub fn update_ref(target: String, new_id: String, message: String) -> Result<()> {
     let repo = Repo::new().context("Failed to find the repo")?;
     let new_id = rev::parse(&new_id, &repo)?;
-    rev::update_ref(Path::new(&target), &new_id, &repo.root)?;
+    rev::update_ref(Path::new(&target), &new_id, &message, &repo)?;
     Ok(())
 }
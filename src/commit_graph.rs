@@ -0,0 +1,254 @@
+//! Writing the commit-graph file, `objects/info/commit-graph`, which git reads
+//! to walk history without inflating and parsing every commit. For each commit
+//! it holds the tree, the parents, the commit date and the generation number:
+//! one more than the highest generation of the commit's parents, starting at 1
+//! for root commits.
+//!
+//! The file is in version 1 of git's format: a header, a table of chunks, the
+//! chunks themselves, then a SHA-1 of all of that.
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+
+use crate::objects::{Id, Object, Repo};
+
+const SIGNATURE: &[u8; 4] = b"CGPH";
+const CHUNK_FANOUT: &[u8; 4] = b"OIDF";
+const CHUNK_IDS: &[u8; 4] = b"OIDL";
+const CHUNK_DATA: &[u8; 4] = b"CDAT";
+const CHUNK_EDGES: &[u8; 4] = b"EDGE";
+
+/// Parent position meaning there is no parent
+const NO_PARENT: u32 = 0x7000_0000;
+/// Set on a second parent position that is really an index into the list of
+/// extra parents of octopus merges, and on the last parent in that list
+const EDGE_FLAG: u32 = 0x8000_0000;
+/// Generation numbers only have 30 bits
+const MAX_GENERATION: u32 = 0x3fff_ffff;
+
+/// What the commit-graph records about a commit
+struct GraphCommit {
+    tree: Id,
+    parents: Vec<Id>,
+    time: u64,
+}
+
+/// Finds every commit reachable from `tips`
+fn collect_commits(repo: &Repo, tips: &[Id]) -> Result<HashMap<Id, GraphCommit>> {
+    let mut commits = HashMap::new();
+    let mut stack = tips.to_vec();
+    while let Some(id) = stack.pop() {
+        if commits.contains_key(&id) {
+            continue;
+        }
+        let commit = match &*repo.open_shared(&id)? {
+            Object::Commit(commit) => GraphCommit {
+                tree: commit.tree,
+                parents: commit.parents.clone(),
+                // dates before 1970 don't fit
+                time: commit.committer.time.timestamp().max(0) as u64,
+            },
+            _ => return Err(anyhow!("{} is not a commit", id)),
+        };
+        stack.extend(&commit.parents);
+        commits.insert(id, commit);
+    }
+    Ok(commits)
+}
+
+/// Works out the generation number of every commit
+fn generations(commits: &HashMap<Id, GraphCommit>) -> HashMap<Id, u32> {
+    let mut generations = HashMap::with_capacity(commits.len());
+    for start in commits.keys() {
+        // parents before children, without recursing down long histories
+        let mut stack = vec![*start];
+        while let Some(&id) = stack.last() {
+            if generations.contains_key(&id) {
+                stack.pop();
+                continue;
+            }
+            let parents = &commits[&id].parents;
+            let pending: Vec<Id> = parents
+                .iter()
+                .filter(|p| !generations.contains_key(*p))
+                .copied()
+                .collect();
+            if pending.is_empty() {
+                let highest = parents.iter().map(|p| generations[p]).max().unwrap_or(0);
+                generations.insert(id, (highest + 1).min(MAX_GENERATION));
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    generations
+}
+
+/// Builds the commit-graph of the commits reachable from `tips`
+pub fn build(repo: &Repo, tips: &[Id]) -> Result<Vec<u8>> {
+    let commits = collect_commits(repo, tips)?;
+    let generations = generations(&commits);
+    let mut ids: Vec<Id> = commits.keys().copied().collect();
+    ids.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    let position: HashMap<Id, u32> = ids
+        .iter()
+        .enumerate()
+        .map(|(n, id)| (*id, n as u32))
+        .collect();
+
+    let mut fanout = Vec::with_capacity(256 * 4);
+    let mut count = 0;
+    for byte in 0..=255u8 {
+        while count < ids.len() && ids[count].as_bytes()[0] <= byte {
+            count += 1;
+        }
+        fanout.extend_from_slice(&(count as u32).to_be_bytes());
+    }
+
+    let id_list: Vec<u8> = ids.iter().flat_map(|id| id.as_bytes().to_vec()).collect();
+
+    let mut data = Vec::with_capacity(ids.len() * 36);
+    let mut edges: Vec<u32> = Vec::new();
+    for id in &ids {
+        let commit = &commits[id];
+        let parents: Vec<u32> = commit.parents.iter().map(|p| position[p]).collect();
+        let first = parents.first().copied().unwrap_or(NO_PARENT);
+        let second = match parents.len() {
+            0 | 1 => NO_PARENT,
+            2 => parents[1],
+            _ => {
+                let start = edges.len() as u32;
+                edges.extend(&parents[1..]);
+                *edges.last_mut().unwrap() |= EDGE_FLAG;
+                EDGE_FLAG | start
+            }
+        };
+        data.extend_from_slice(commit.tree.as_bytes());
+        data.extend_from_slice(&first.to_be_bytes());
+        data.extend_from_slice(&second.to_be_bytes());
+        // the generation and the top two bits of the 34-bit date, then the
+        // rest of the date
+        let high = generations[id] << 2 | ((commit.time >> 32) & 0b11) as u32;
+        data.extend_from_slice(&high.to_be_bytes());
+        data.extend_from_slice(&(commit.time as u32).to_be_bytes());
+    }
+    let edges: Vec<u8> = edges
+        .iter()
+        .flat_map(|e| e.to_be_bytes().to_vec())
+        .collect();
+
+    let mut chunks: Vec<(&[u8; 4], Vec<u8>)> = vec![
+        (CHUNK_FANOUT, fanout),
+        (CHUNK_IDS, id_list),
+        (CHUNK_DATA, data),
+    ];
+    if !edges.is_empty() {
+        chunks.push((CHUNK_EDGES, edges));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(SIGNATURE);
+    // version 1, SHA-1, number of chunks, no base graphs
+    out.extend_from_slice(&[1, 1, chunks.len() as u8, 0]);
+    // the table of contents ends with a zero ID at the end of the last chunk
+    let mut offset = (out.len() + (chunks.len() + 1) * 12) as u64;
+    for (name, chunk) in &chunks {
+        out.extend_from_slice(*name);
+        out.extend_from_slice(&offset.to_be_bytes());
+        offset += chunk.len() as u64;
+    }
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(&offset.to_be_bytes());
+    for (_, chunk) in &chunks {
+        out.extend_from_slice(chunk);
+    }
+    let checksum: [u8; 20] = Sha1::digest(&out).into();
+    out.extend_from_slice(&checksum);
+    Ok(out)
+}
+
+/// Writes the commit-graph of the commits reachable from `tips` to
+/// `objects/info/commit-graph`, returning how many commits are in it
+pub fn write(repo: &Repo, tips: &[Id]) -> Result<usize> {
+    let graph = build(repo, tips)?;
+    let dir = repo.objects_dir().join("info");
+    repo.vfs().create_dir_all(&dir)?;
+    repo.replace_file(&dir.join("commit-graph"), &graph)?;
+    // the number of commits is the last entry of the fanout, which comes
+    // straight after the table of contents
+    let fanout = 8 + (graph[6] as usize + 1) * 12;
+    let mut count = [0; 4];
+    count.copy_from_slice(&graph[fanout + 255 * 4..fanout + 256 * 4]);
+    Ok(u32::from_be_bytes(count) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build, write};
+    use crate::objects::{Commit, Id, NameEntry, Repo, Tree};
+    use std::path::Path;
+
+    fn word(graph: &[u8], at: usize) -> u32 {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(&graph[at..at + 4]);
+        u32::from_be_bytes(bytes)
+    }
+
+    #[test]
+    fn test_commit_graph() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = repo.store(&Tree { files: Vec::new() }).unwrap();
+        let commit = |parents: Vec<Id>, message: &str| {
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who.clone(),
+                message: message.to_string(),
+            })
+            .unwrap()
+        };
+        let root = commit(vec![], "root\n");
+        let a = commit(vec![root], "a\n");
+        let b = commit(vec![root], "b\n");
+        let c = commit(vec![root], "c\n");
+        let octopus = commit(vec![a, b, c], "octopus\n");
+
+        assert_eq!(write(&repo, &[octopus, a]).unwrap(), 5);
+        let graph = repo
+            .vfs()
+            .read(Path::new("/repo/.git/objects/info/commit-graph"))
+            .unwrap();
+        assert_eq!(graph, build(&repo, &[octopus]).unwrap());
+        assert_eq!(&graph[..8], b"CGPH\x01\x01\x04\x00");
+        assert_eq!(&graph[8..12], b"OIDF");
+
+        let fanout = word(&graph, 16) as usize;
+        let ids = word(&graph, 28) as usize;
+        let data = word(&graph, 40) as usize;
+        let mut sorted = vec![root, a, b, c, octopus];
+        sorted.sort_unstable_by(|x, y| x.as_bytes().cmp(y.as_bytes()));
+        assert_eq!(word(&graph, fanout + 255 * 4), 5);
+        let position = |id: &Id| sorted.iter().position(|s| s == id).unwrap();
+        assert_eq!(
+            &graph[ids + position(&c) * 20..ids + position(&c) * 20 + 20],
+            c.as_bytes()
+        );
+
+        let entry = |id: &Id| data + position(id) * 36;
+        assert_eq!(&graph[entry(&root)..entry(&root) + 20], tree.as_bytes());
+        assert_eq!(word(&graph, entry(&root) + 20), 0x7000_0000);
+        assert_eq!(word(&graph, entry(&root) + 28) >> 2, 1);
+        assert_eq!(word(&graph, entry(&root) + 32), 1586391037);
+        assert_eq!(word(&graph, entry(&a) + 20), position(&root) as u32);
+        assert_eq!(word(&graph, entry(&a) + 28) >> 2, 2);
+        // the octopus's other parents are in the edge list
+        assert_eq!(word(&graph, entry(&octopus) + 24), 0x8000_0000);
+        assert_eq!(word(&graph, entry(&octopus) + 28) >> 2, 3);
+        let edges = word(&graph, 52) as usize;
+        assert_eq!(word(&graph, edges), position(&b) as u32);
+        assert_eq!(word(&graph, edges + 4), 0x8000_0000 | position(&c) as u32);
+    }
+}
@@ -0,0 +1,328 @@
+//! Reading git-config files such as `.git/config` and `~/.gitconfig`
+use anyhow::{Error, Result};
+use std::env;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::objects::Repo;
+
+/// Errors that can arise while reading configuration
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The file could not be parsed
+    #[error("Bad config syntax on line {0}: {1}")]
+    Syntax(usize, &'static str),
+
+    /// A value could not be interpreted as the type asked for
+    #[error("Bad {kind} value {value:?} for {key}")]
+    BadValue {
+        /// Key the value belongs to
+        key: String,
+        /// Type it was being interpreted as
+        kind: &'static str,
+        /// The offending value
+        value: String,
+    },
+}
+
+/// Configuration variables from one or more config files. Keys are of the form
+/// `section.subsection.name` where the section and name are lowercased, since
+/// they are case insensitive, but the subsection is kept as written.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Config {
+    /// Entries in the order they were read. A value of None means the key was
+    /// given without an `=`, which is an implicit boolean true
+    entries: Vec<(String, Option<String>)>,
+}
+
+/// Splits a key into its section, optional subsection and name, normalizing the
+/// case of the parts that are case insensitive
+fn normalize_key(key: &str) -> String {
+    let first = key.find('.');
+    let last = key.rfind('.');
+    match (first, last) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_lowercase(),
+            &key[first..last],
+            key[last..].to_lowercase()
+        ),
+        _ => key.to_lowercase(),
+    }
+}
+
+/// Parses a value, handling quotes, escapes and comments. Returns the value
+/// and whether the line ended with a continuation backslash.
+fn parse_value(s: &str, lineno: usize) -> Result<(String, bool), ConfigError> {
+    let mut value = String::new();
+    // whitespace is only kept if there is something after it
+    let mut pending_space = String::new();
+    let mut quoted = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                quoted = !quoted;
+            }
+            '\\' => {
+                let escaped = match chars.next() {
+                    None => return Ok((value + &pending_space, true)),
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('b') => '\x08',
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some(_) => return Err(ConfigError::Syntax(lineno, "bad escape")),
+                };
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(escaped);
+            }
+            '#' | ';' if !quoted => break,
+            c if c.is_whitespace() && !quoted => {
+                if !value.is_empty() {
+                    pending_space.push(c);
+                }
+            }
+            c => {
+                value.push_str(&pending_space);
+                pending_space.clear();
+                value.push(c);
+            }
+        }
+    }
+    if quoted {
+        return Err(ConfigError::Syntax(lineno, "unterminated quote"));
+    }
+    Ok((value, false))
+}
+
+/// Parses a section header line (without the brackets) into a key prefix
+fn parse_section(header: &str, lineno: usize) -> Result<String, ConfigError> {
+    let header = header.trim();
+    match header.find(|c: char| c.is_whitespace()) {
+        // [section "subsection"]
+        Some(space) => {
+            let name = &header[..space];
+            let sub = header[space..].trim();
+            let sub = sub
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .ok_or(ConfigError::Syntax(lineno, "bad subsection"))?;
+            let mut subsection = String::new();
+            let mut chars = sub.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => subsection.extend(chars.next()),
+                    c => subsection.push(c),
+                }
+            }
+            Ok(format!("{}.{}", name.to_lowercase(), subsection))
+        }
+        // [section] or the deprecated [section.subsection]
+        None => Ok(header.to_lowercase()),
+    }
+}
+
+impl Config {
+    /// Makes an empty configuration
+    pub fn new() -> Config {
+        Default::default()
+    }
+
+    /// Parses the text of a config file, adding its entries to this
+    /// configuration. Entries read later take precedence.
+    pub fn parse(&mut self, text: &str) -> Result<()> {
+        let mut section: Option<String> = None;
+        let mut lines = text.lines().enumerate();
+
+        while let Some((lineno, line)) = lines.next() {
+            let lineno = lineno + 1;
+            let mut line = line.trim_start();
+
+            if line.starts_with('[') {
+                let end = line
+                    .find(']')
+                    .ok_or(ConfigError::Syntax(lineno, "unterminated section header"))?;
+                section = Some(parse_section(&line[1..end], lineno)?);
+                line = line[end + 1..].trim_start();
+                // a variable may follow the header on the same line
+            }
+
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+
+            let section = section
+                .as_ref()
+                .ok_or(ConfigError::Syntax(lineno, "variable outside of a section"))?;
+            let name_end = line
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+                .unwrap_or_else(|| line.len());
+            let name = &line[..name_end];
+            if name.is_empty() {
+                return Err(Error::new(ConfigError::Syntax(lineno, "bad variable name")));
+            }
+            let key = format!("{}.{}", section, name.to_lowercase());
+
+            let rest = line[name_end..].trim_start();
+            let value = if rest.is_empty() || rest.starts_with('#') || rest.starts_with(';') {
+                None
+            } else if let Some(rest) = rest.strip_prefix('=') {
+                let (mut value, mut continued) = parse_value(rest, lineno)?;
+                while continued {
+                    let (_, next) = lines
+                        .next()
+                        .ok_or(ConfigError::Syntax(lineno, "continuation at end of file"))?;
+                    let (more, cont) = parse_value(next, lineno)?;
+                    value.push_str(&more);
+                    continued = cont;
+                }
+                Some(value)
+            } else {
+                return Err(Error::new(ConfigError::Syntax(lineno, "expected '='")));
+            };
+            self.entries.push((key, value));
+        }
+        Ok(())
+    }
+
+    /// Loads the configuration that applies to a repository: the user's global
+    /// config followed by the repository's own
+    pub fn load(repo: &Repo) -> Result<Config> {
+        let mut config = Config::new();
+        let mut paths = Vec::new();
+        if let Some(home) = env::var_os("HOME") {
+            paths.push(PathBuf::from(home).join(".gitconfig"));
+        }
+        paths.push(repo.root.join("config"));
+
+        for path in paths {
+            match repo.vfs().read(&path) {
+                Ok(content) => config.parse(&String::from_utf8_lossy(&content))?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(config)
+    }
+
+    /// Gets the last value given for a key. Keys given without a value read as
+    /// an empty string.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    /// Gets every value given for a multi-valued key, in order
+    pub fn get_all<'a>(&'a self, key: &str) -> impl Iterator<Item = &'a str> + 'a {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .filter(move |(k, _)| *k == key)
+            .map(|(_, v)| v.as_deref().unwrap_or(""))
+    }
+
+    /// Gets a boolean value. Accepts the same spellings as git.
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>> {
+        let norm = normalize_key(key);
+        let value = match self.entries.iter().rev().find(|(k, _)| *k == norm) {
+            Some((_, value)) => value,
+            None => return Ok(None),
+        };
+        match value.as_deref().map(str::to_lowercase).as_deref() {
+            None | Some("true") | Some("yes") | Some("on") | Some("1") => Ok(Some(true)),
+            Some("false") | Some("no") | Some("off") | Some("0") | Some("") => Ok(Some(false)),
+            Some(_) => Err(Error::new(ConfigError::BadValue {
+                key: key.to_owned(),
+                kind: "boolean",
+                value: value.clone().unwrap_or_default(),
+            })),
+        }
+    }
+
+    /// Gets an integer value, which may have a k, m or g suffix
+    pub fn get_int(&self, key: &str) -> Result<Option<i64>> {
+        let value = match self.get(key) {
+            Some(value) => value.trim(),
+            None => return Ok(None),
+        };
+        let bad = || {
+            Error::new(ConfigError::BadValue {
+                key: key.to_owned(),
+                kind: "integer",
+                value: value.to_owned(),
+            })
+        };
+
+        let (digits, scale) = match value.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some('k') => (&value[..value.len() - 1], 1 << 10),
+            Some('m') => (&value[..value.len() - 1], 1 << 20),
+            Some('g') => (&value[..value.len() - 1], 1 << 30),
+            _ => (value, 1),
+        };
+        let n: i64 = digits.parse().map_err(|_| bad())?;
+        n.checked_mul(scale).map(Some).ok_or_else(bad)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn test_parse_config() {
+        let mut config = Config::new();
+        config
+            .parse(
+                r#"
+# a comment
+[core]
+    repositoryformatversion = 0
+    FileMode = true
+    bare
+    editor = "vim -c \"set tw=72\"" ; trailing comment
+[remote "Origin"]
+    url = https://example.com/repo.git
+    fetch = +refs/heads/*:refs/remotes/origin/*
+    fetch = +refs/tags/*:refs/tags/*
+[pack]
+    windowMemory = 10m
+[alias]
+    lg = log \
+        --oneline
+"#,
+            )
+            .unwrap();
+
+        assert_eq!(config.get("core.repositoryformatversion"), Some("0"));
+        assert_eq!(config.get_bool("core.filemode").unwrap(), Some(true));
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(true));
+        assert_eq!(config.get("core.editor"), Some("vim -c \"set tw=72\""));
+        assert_eq!(
+            config.get("remote.Origin.url"),
+            Some("https://example.com/repo.git")
+        );
+        // subsections are case sensitive
+        assert_eq!(config.get("remote.origin.url"), None);
+        assert_eq!(config.get_all("REMOTE.Origin.FETCH").count(), 2);
+        assert_eq!(config.get_int("pack.windowmemory").unwrap(), Some(10 << 20));
+        assert_eq!(config.get("alias.lg"), Some("log --oneline"));
+        assert!(config.get_int("core.editor").is_err());
+        assert_eq!(config.get("core.missing"), None);
+
+        // later values win
+        config.parse("[core]\nbare = false").unwrap();
+        assert_eq!(config.get_bool("core.bare").unwrap(), Some(false));
+
+        assert!(Config::new().parse("no = section").is_err());
+        assert!(Config::new().parse("[core]\nx = \"unterminated").is_err());
+    }
+}
@@ -0,0 +1,322 @@
+//! A tiny read-only git server, like `git daemon`. It accepts `git://`
+//! connections, reads the request line naming a service and a repository, and
+//! serves fetches and clones with [`upload_pack`](crate::upload_pack).
+//!
+//! Like git, only repositories containing a `git-daemon-export-ok` file are
+//! served unless told otherwise, and serving can be limited to repositories
+//! under a list of directories. Clients that go quiet can be timed out, and
+//! only so many are served at once.
+use anyhow::{Context, Result};
+use std::io::{BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+
+use crate::objects::Repo;
+use crate::pktline::{self, Packet};
+use crate::upload_pack::upload_pack;
+use crate::vfs::{RealFs, Vfs};
+
+/// Port git:// uses unless told otherwise
+pub const DEFAULT_PORT: u16 = 9418;
+
+/// How many clients are served at once unless told otherwise, as in git
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+/// Errors sent back to clients whose requests can't be served
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DaemonError {
+    /// The request line couldn't be understood
+    #[error("bad request line {0:?}")]
+    BadRequest(String),
+
+    /// A service other than upload-pack was asked for
+    #[error("service not enabled: {0}")]
+    ServiceNotEnabled(String),
+
+    /// The repository doesn't exist or may not be served. These are the same
+    /// error so clients can't probe for repositories they can't fetch.
+    #[error("access denied or repository not exported: {0}")]
+    NotExported(String),
+}
+
+/// How the daemon decides which repositories to serve
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Port to listen on
+    pub port: u16,
+    /// Requested paths are looked up under this directory instead of the root
+    pub base_path: Option<PathBuf>,
+    /// Serve repositories without a `git-daemon-export-ok` file
+    pub export_all: bool,
+    /// If not empty, only repositories under these directories are served
+    pub allowed: Vec<PathBuf>,
+    /// How long a client may leave a read or write waiting before it is
+    /// disconnected. None waits forever.
+    pub timeout: Option<Duration>,
+    /// How many clients may be served at once; more are turned away. Zero
+    /// means no limit.
+    pub max_connections: usize,
+}
+
+impl Default for DaemonOptions {
+    fn default() -> DaemonOptions {
+        DaemonOptions {
+            port: DEFAULT_PORT,
+            base_path: None,
+            export_all: false,
+            allowed: Vec::new(),
+            timeout: None,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+        }
+    }
+}
+
+/// A request line, which looks like `git-upload-pack /path\0host=example.com\0`
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    service: String,
+    path: String,
+}
+
+fn parse_request(line: &[u8]) -> Result<Request, DaemonError> {
+    let bad = || DaemonError::BadRequest(String::from_utf8_lossy(line).into_owned());
+    // the extra parameters after the path don't change what we do
+    let command = line.split(|&b| b == 0).next().ok_or_else(bad)?;
+    let command = std::str::from_utf8(command).map_err(|_| bad())?;
+    let space = command.find(' ').ok_or_else(bad)?;
+    Ok(Request {
+        service: command[..space].to_owned(),
+        path: command[space + 1..].to_owned(),
+    })
+}
+
+/// Is this a .git directory, or a bare repository?
+pub(crate) fn is_git_dir(dir: &Path, vfs: &dyn Vfs) -> bool {
+    vfs.is_dir(&dir.join("objects")) && vfs.exists(&dir.join("HEAD"))
+}
+
+impl DaemonOptions {
+    /// Finds the .git directory to serve for a requested path, checking that
+    /// it may be served. As with git, `/path` may name `/path.git` or the
+    /// working tree at `/path` as well as the repository itself.
+    pub fn resolve(&self, path: &str, vfs: &dyn Vfs) -> Result<PathBuf, DaemonError> {
+        let denied = || DaemonError::NotExported(path.to_owned());
+
+        let requested = Path::new(path);
+        // ~user paths are not supported
+        if !path.starts_with('/')
+            || requested
+                .components()
+                .any(|c| c == Component::ParentDir || c == Component::CurDir)
+        {
+            return Err(denied());
+        }
+        let full = match &self.base_path {
+            Some(base) => base.join(path.trim_start_matches('/')),
+            None => requested.to_owned(),
+        };
+
+        let mut with_suffix = full.clone().into_os_string();
+        with_suffix.push(".git");
+        let candidates = [full.join(".git"), PathBuf::from(with_suffix), full];
+        let dir = candidates
+            .iter()
+            .find(|dir| is_git_dir(dir, vfs))
+            .ok_or_else(denied)?;
+        // symlinks mustn't lead out of the allowed directories
+        let dir = vfs.canonicalize(dir).map_err(|_| denied())?;
+
+        if !self.allowed.is_empty() && !self.allowed.iter().any(|a| dir.starts_with(a)) {
+            return Err(denied());
+        }
+        if !self.export_all && !vfs.exists(&dir.join("git-daemon-export-ok")) {
+            return Err(denied());
+        }
+        Ok(dir)
+    }
+}
+
+/// A client being served, counted against `max_connections` until dropped
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    /// Takes a slot if fewer than `max` are taken, or there is no limit
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Slot> {
+        let taken = active.fetch_add(1, Ordering::SeqCst);
+        let slot = Slot(active.clone());
+        if max != 0 && taken >= max {
+            return None;
+        }
+        Some(slot)
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Handles one connection
+fn serve(stream: TcpStream, options: &DaemonOptions) -> Result<()> {
+    // a client that goes quiet mustn't hold on to its thread forever
+    stream.set_read_timeout(options.timeout)?;
+    stream.set_write_timeout(options.timeout)?;
+    let mut input = stream.try_clone()?;
+    let mut output = BufWriter::new(stream);
+
+    let request = match pktline::read(&mut input)? {
+        Some(Packet::Data(line)) => parse_request(&line),
+        _ => return Ok(()),
+    };
+    let request = request.and_then(|request| {
+        if request.service != "git-upload-pack" {
+            return Err(DaemonError::ServiceNotEnabled(request.service));
+        }
+        let dir = options.resolve(&request.path, &RealFs)?;
+        Ok((request, dir))
+    });
+    let (request, dir) = match request {
+        Ok(found) => found,
+        Err(e) => {
+            pktline::write(&mut output, format!("ERR {}", e).as_bytes())?;
+            output.flush()?;
+            return Err(e.into());
+        }
+    };
+
+    info!("serving {} for {}", request.service, dir.display());
+    let mut repo = Repo::with_vfs(dir, Arc::new(RealFs));
+    // clients get the history as it really is, and can fetch the
+    // replacements themselves
+    repo.set_replace_objects(false);
+    upload_pack(&repo, &mut input, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Listens for connections forever, serving each on its own thread
+pub fn run(options: DaemonOptions) -> Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", options.port))
+        .with_context(|| format!("failed to listen on port {}", options.port))?;
+    info!("listening on port {}", options.port);
+    let options = Arc::new(options);
+    let active = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let slot = match Slot::take(&active, options.max_connections) {
+            Some(slot) => slot,
+            None => {
+                warn!("too many connections, turning one away");
+                continue;
+            }
+        };
+        let options = options.clone();
+        thread::spawn(move || {
+            let _slot = slot;
+            let peer = stream
+                .peer_addr()
+                .map(|a| a.to_string())
+                .unwrap_or_else(|_| "unknown peer".to_string());
+            if let Err(e) = serve(stream, &options) {
+                warn!("{}: {:#}", peer, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_request, DaemonError, DaemonOptions, Request, Slot};
+    use crate::vfs::{MemoryFs, Vfs};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(
+            parse_request(b"git-upload-pack /project.git\0host=example.com\0"),
+            Ok(Request {
+                service: "git-upload-pack".to_string(),
+                path: "/project.git".to_string(),
+            })
+        );
+        assert!(parse_request(b"nonsense").is_err());
+    }
+
+    #[test]
+    fn test_slots() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let first = Slot::take(&active, 2).unwrap();
+        let second = Slot::take(&active, 2).unwrap();
+        assert!(Slot::take(&active, 2).is_none());
+        drop(first);
+        let third = Slot::take(&active, 2).unwrap();
+        drop((second, third));
+        assert_eq!(active.load(Ordering::SeqCst), 0);
+
+        // zero is no limit
+        let many = (0..100).map(|_| Slot::take(&active, 0)).collect::<Vec<_>>();
+        assert!(many.iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let fs = MemoryFs::new();
+        for dir in &["/srv/git/bare.git/objects", "/srv/git/work/.git/objects"] {
+            fs.create_dir_all(Path::new(dir)).unwrap();
+        }
+        fs.write(
+            Path::new("/srv/git/bare.git/HEAD"),
+            b"ref: refs/heads/master",
+        )
+        .unwrap();
+        fs.write(
+            Path::new("/srv/git/work/.git/HEAD"),
+            b"ref: refs/heads/master",
+        )
+        .unwrap();
+        fs.write(Path::new("/srv/git/bare.git/git-daemon-export-ok"), b"")
+            .unwrap();
+
+        let options = DaemonOptions {
+            base_path: Some(PathBuf::from("/srv/git")),
+            ..Default::default()
+        };
+        let bare = PathBuf::from("/srv/git/bare.git");
+        assert_eq!(options.resolve("/bare.git", &fs), Ok(bare.clone()));
+        assert_eq!(options.resolve("/bare", &fs), Ok(bare.clone()));
+        // not exported
+        assert_eq!(
+            options.resolve("/work", &fs),
+            Err(DaemonError::NotExported("/work".to_string()))
+        );
+        assert!(options.resolve("/../git/bare", &fs).is_err());
+        assert!(options.resolve("bare", &fs).is_err());
+
+        let options = DaemonOptions {
+            export_all: true,
+            allowed: vec![PathBuf::from("/srv/git/work")],
+            ..options
+        };
+        assert_eq!(
+            options.resolve("/work", &fs),
+            Ok(PathBuf::from("/srv/git/work/.git"))
+        );
+        assert!(options.resolve("/bare", &fs).is_err());
+    }
+}
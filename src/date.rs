@@ -0,0 +1,214 @@
+//! Parsing the dates people type on the command line, in the spirit of git's
+//! approxidate. Absolute dates such as `2020-04-09 17:10` and relative ones
+//! such as `2 weeks ago`, `yesterday` or `last friday` are understood.
+//!
+//! Like git, parts of the time that aren't given are taken from the current
+//! time, so `2020-04-09` is that day at the current time of day.
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike,
+};
+use thiserror::Error;
+
+/// Errors that can arise while parsing dates
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum DateError {
+    /// Some word in the date isn't one we know
+    #[error("Could not understand {word:?} in date {date:?}")]
+    Unrecognized {
+        /// The date given
+        date: String,
+        /// The part of it that wasn't understood
+        word: String,
+    },
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Dates with a time of day
+const DATETIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y.%m.%d %H:%M:%S",
+];
+
+/// Dates that get the current time of day
+const DATE_FORMATS: [&str; 4] = ["%Y-%m-%d", "%Y.%m.%d", "%Y/%m/%d", "%m/%d/%Y"];
+
+/// Moves a date back by some number of months, keeping the day of the month
+/// where it exists
+fn months_ago(date: DateTime<FixedOffset>, months: i64) -> DateTime<FixedOffset> {
+    let total = i64::from(date.year()) * 12 + i64::from(date.month0()) - months;
+    let (year, month0) = (total.div_euclid(12) as i32, total.rem_euclid(12) as u32);
+    // the 31st of a short month becomes the last day of that month
+    (0..4)
+        .filter_map(|back| {
+            let day = date.day().checked_sub(back)?;
+            date.with_day(1)?
+                .with_year(year)?
+                .with_month0(month0)?
+                .with_day(day)
+        })
+        .next()
+        .unwrap_or(date)
+}
+
+/// Seconds in a unit of relative time, or None for months and years, which
+/// vary in length
+fn unit_seconds(unit: &str) -> Option<Option<i64>> {
+    let unit = unit.trim_end_matches('s');
+    Some(match unit {
+        "sec" | "second" => Some(1),
+        "min" | "minute" => Some(60),
+        "hour" => Some(60 * 60),
+        "day" => Some(24 * 60 * 60),
+        "week" => Some(7 * 24 * 60 * 60),
+        "month" | "year" => None,
+        _ => return None,
+    })
+}
+
+/// Parses an absolute date, in the given time zone unless it says otherwise
+fn parse_absolute(s: &str, now: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+    if s.starts_with('@') {
+        let secs = s[1..].parse().ok()?;
+        return Some(now.timezone().timestamp(secs, 0));
+    }
+    if let Ok(date) = DateTime::parse_from_rfc2822(s) {
+        return Some(date);
+    }
+    if let Ok(date) = DateTime::parse_from_rfc3339(s) {
+        return Some(date);
+    }
+    // the offset is the one at `now`, which is wrong across daylight saving
+    // changes, but then so is git
+    for format in &DATETIME_FORMATS {
+        if let Ok(date) = NaiveDateTime::parse_from_str(s, format) {
+            return now.timezone().from_local_datetime(&date).single();
+        }
+    }
+    for format in &DATE_FORMATS {
+        if let Ok(date) = NaiveDate::parse_from_str(s, format) {
+            return now
+                .timezone()
+                .from_local_datetime(&date.and_time(now.time()))
+                .single();
+        }
+    }
+    None
+}
+
+/// Parses a date relative to `now`
+pub fn approxidate(
+    s: &str,
+    now: DateTime<FixedOffset>,
+) -> Result<DateTime<FixedOffset>, DateError> {
+    let s = s.trim();
+    if let Some(date) = parse_absolute(s, now) {
+        return Ok(date);
+    }
+
+    let unrecognized = |word: &str| DateError::Unrecognized {
+        date: s.to_owned(),
+        word: word.to_owned(),
+    };
+    let at_time =
+        |date: DateTime<FixedOffset>, hour| date.date().and_time(NaiveTime::from_hms(hour, 0, 0));
+
+    let mut date = now;
+    let mut number: Option<i64> = None;
+    let lower = s.to_lowercase();
+    let words = lower
+        .split(|c: char| c.is_whitespace() || c == '.' || c == ',')
+        .filter(|w| !w.is_empty());
+    for word in words {
+        if let Ok(n) = word.parse() {
+            number = Some(n);
+            continue;
+        }
+        if let Some(unit) = unit_seconds(word) {
+            let n = number.take().unwrap_or(1);
+            date = match (unit, word.trim_end_matches('s')) {
+                (Some(secs), _) => date - Duration::seconds(n * secs),
+                (None, "month") => months_ago(date, n),
+                (None, _) => months_ago(date, n * 12),
+            };
+            continue;
+        }
+        if let Some(weekday) = WEEKDAYS.iter().position(|day| word.starts_with(day)) {
+            // the most recent one, going back a week if it's today. "last" is
+            // allowed before it but doesn't change anything
+            number = None;
+            let today = i64::from(date.weekday().num_days_from_monday());
+            let back = (today - weekday as i64).rem_euclid(7);
+            let back = if back == 0 { 7 } else { back };
+            date = date - Duration::days(back);
+            continue;
+        }
+        if number.is_some() {
+            return Err(unrecognized(word));
+        }
+        match word {
+            "ago" | "now" | "today" => (),
+            // "last week" is one week ago
+            "last" => number = Some(1),
+            "yesterday" => date = date - Duration::days(1),
+            "midnight" => date = at_time(date, 0).ok_or_else(|| unrecognized(word))?,
+            // noon before it is noon means yesterday's
+            "noon" => {
+                if date.hour() < 12 {
+                    date = date - Duration::days(1);
+                }
+                date = at_time(date, 12).ok_or_else(|| unrecognized(word))?;
+            }
+            _ => return Err(unrecognized(word)),
+        }
+    }
+    if let Some(n) = number {
+        return Err(unrecognized(&n.to_string()));
+    }
+    Ok(date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::approxidate;
+    use chrono::DateTime;
+
+    #[test]
+    fn test_approxidate() {
+        // a Thursday afternoon
+        let now = DateTime::parse_from_rfc3339("2020-04-09T17:10:37-07:00").unwrap();
+        let parse = |s| approxidate(s, now).unwrap().to_rfc3339();
+
+        assert_eq!(parse("2020-01-02"), "2020-01-02T17:10:37-07:00");
+        assert_eq!(parse("2020-01-02 03:04"), "2020-01-02T03:04:00-07:00");
+        assert_eq!(parse("2020-01-02T03:04:05Z"), "2020-01-02T03:04:05+00:00");
+        assert_eq!(
+            parse("Thu, 9 Apr 2020 10:00:00 +0200"),
+            "2020-04-09T10:00:00+02:00"
+        );
+        assert_eq!(parse("@0"), "1969-12-31T17:00:00-07:00");
+
+        assert_eq!(parse("now"), now.to_rfc3339());
+        assert_eq!(parse("2 weeks ago"), "2020-03-26T17:10:37-07:00");
+        assert_eq!(parse("3.hours.ago"), "2020-04-09T14:10:37-07:00");
+        assert_eq!(parse("1 month ago"), "2020-03-09T17:10:37-07:00");
+        assert_eq!(parse("last year"), "2019-04-09T17:10:37-07:00");
+        assert_eq!(parse("yesterday"), "2020-04-08T17:10:37-07:00");
+        assert_eq!(parse("yesterday noon"), "2020-04-08T12:00:00-07:00");
+        assert_eq!(parse("midnight"), "2020-04-09T00:00:00-07:00");
+        assert_eq!(parse("last friday"), "2020-04-03T17:10:37-07:00");
+        assert_eq!(parse("thursday"), "2020-04-02T17:10:37-07:00");
+
+        let march31 = DateTime::parse_from_rfc3339("2020-03-31T00:00:00Z").unwrap();
+        assert_eq!(
+            approxidate("1 month ago", march31).unwrap().to_rfc3339(),
+            "2020-02-29T00:00:00+00:00"
+        );
+
+        assert!(approxidate("next tuesday", now).is_err());
+        assert!(approxidate("5", now).is_err());
+    }
+}
@@ -1,6 +1,7 @@
-//! Myers diff algorithm
+//! Myers diff algorithm, and the unified diffs built from it
 use std::convert::TryFrom;
 use std::fmt;
+use std::io::{self, Write};
 
 /// Allows negative indexing into slices in a similar fashion to Python and Ruby
 trait NegIndex<T> {
@@ -38,6 +39,15 @@ pub enum Edit<'a, T> {
     Nop(&'a T),
 }
 
+// derived Clone and Copy would require them of T, but we only hold references
+impl<'a, T> Clone for Edit<'a, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, T> Copy for Edit<'a, T> {}
+
 /// Perform a diff on two slices of arbitrary objects using the Myers algorithm.
 /// Returns a list of edits to `a` that would produce `b`.
 pub fn myers_diff<'a, T>(a: &'a [T], b: &'a [T]) -> Vec<Edit<'a, T>>
@@ -83,7 +93,7 @@ where
         let k = isize::try_from(x).unwrap() - isize::try_from(y).unwrap();
         let d = isize::try_from(d).unwrap();
 
-        println!("k: {}\td: {}\tv: {:?}", k, d, &v);
+        trace!("k: {}\td: {}\tv: {:?}", k, d, &v);
 
         // find what the previous k would have been using the same logic as the
         // forward direction
@@ -96,7 +106,7 @@ where
         // Previous x and y may be negative at d = 0 (first edit step)
         let x_was = isize::try_from(v.nindex(k_was).unwrap()).unwrap();
         let y_was = x_was - k_was;
-        println!("({}, {}) -> ({}, {})", x_was, y_was, x, y);
+        trace!("({}, {}) -> ({}, {})", x_was, y_was, x, y);
 
         while isize::try_from(x).unwrap() > x_was && isize::try_from(y).unwrap() > y_was {
             // diagonal move
@@ -194,6 +204,151 @@ where
     unreachable!("failed to diff??")
 }
 
+/// Lines of context shown around changes by default
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// Splits text into lines, keeping their line endings. The last line has none
+/// if the text doesn't end with a newline.
+pub fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |pos| pos + 1);
+        lines.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    lines
+}
+
+/// Does this look like binary data? Like git, we only look for a NUL in the
+/// first few kilobytes.
+pub fn is_binary(data: &[u8]) -> bool {
+    data[..data.len().min(8000)].contains(&0)
+}
+
+/// A run of changes along with the unchanged lines around them, as shown in a
+/// unified diff
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hunk<'a, T> {
+    /// Line the hunk starts at in the old text, from 1. Hunks which contain no
+    /// old lines give the line before them, which may be 0.
+    pub old_start: usize,
+    /// Number of old lines in the hunk
+    pub old_len: usize,
+    /// Line the hunk starts at in the new text, as for `old_start`
+    pub new_start: usize,
+    /// Number of new lines in the hunk
+    pub new_len: usize,
+    /// The changes, with context
+    pub edits: Vec<Edit<'a, T>>,
+}
+
+/// Groups the edits from [`myers_diff`] into hunks with `context` unchanged
+/// lines around each change. Changes close enough that their context would
+/// meet go in the same hunk.
+pub fn hunks<'a, T>(edits: &[Edit<'a, T>], context: usize) -> Vec<Hunk<'a, T>> {
+    // line numbers before each edit
+    let mut positions = Vec::with_capacity(edits.len() + 1);
+    let (mut old, mut new) = (0, 0);
+    for edit in edits {
+        positions.push((old, new));
+        match edit {
+            Edit::Ins(_) => new += 1,
+            Edit::Del(_) => old += 1,
+            Edit::Nop(_) => {
+                old += 1;
+                new += 1;
+            }
+        }
+    }
+    positions.push((old, new));
+
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Nop(_)))
+        .map(|(i, _)| i)
+        .collect();
+
+    let mut hunks = Vec::new();
+    let mut i = 0;
+    while i < changes.len() {
+        let start = changes[i].saturating_sub(context);
+        while i + 1 < changes.len() && changes[i + 1] - changes[i] - 1 <= 2 * context {
+            i += 1;
+        }
+        let end = (changes[i] + context + 1).min(edits.len());
+        i += 1;
+
+        let (old_before, new_before) = positions[start];
+        let (old_after, new_after) = positions[end];
+        let start_line = |before, len| if len == 0 { before } else { before + 1 };
+        hunks.push(Hunk {
+            old_start: start_line(old_before, old_after - old_before),
+            old_len: old_after - old_before,
+            new_start: start_line(new_before, new_after - new_before),
+            new_len: new_after - new_before,
+            edits: edits[start..end].to_vec(),
+        });
+    }
+    hunks
+}
+
+impl<'a> Hunk<'a, &'a [u8]> {
+    /// Writes the hunk as it appears in a unified diff, header included
+    pub fn write_to(&self, out: &mut dyn Write) -> io::Result<()> {
+        // lengths of 1 are left out
+        let range = |start, len| match len {
+            1 => format!("{}", start),
+            _ => format!("{},{}", start, len),
+        };
+        writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(self.old_start, self.old_len),
+            range(self.new_start, self.new_len)
+        )?;
+        for edit in &self.edits {
+            let (prefix, line) = match edit {
+                Edit::Ins(line) => (b'+', line),
+                Edit::Del(line) => (b'-', line),
+                Edit::Nop(line) => (b' ', line),
+            };
+            out.write_all(&[prefix])?;
+            out.write_all(line)?;
+            if !line.ends_with(b"\n") {
+                out.write_all(b"\n\\ No newline at end of file\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Counts the lines added and removed by some edits
+pub fn count_changes<T>(edits: &[Edit<T>]) -> (usize, usize) {
+    edits.iter().fold((0, 0), |(ins, del), edit| match edit {
+        Edit::Ins(_) => (ins + 1, del),
+        Edit::Del(_) => (ins, del + 1),
+        Edit::Nop(_) => (ins, del),
+    })
+}
+
+/// How alike two texts are, as the percentage of the lines of the longer one
+/// that the other has too. Renames are detected by this, like git's
+/// similarity index.
+pub fn similarity(old: &[u8], new: &[u8]) -> usize {
+    let (old, new) = (lines(old), lines(new));
+    let longest = old.len().max(new.len());
+    if longest == 0 {
+        return 100;
+    }
+    let (_, deleted) = count_changes(&myers_diff(&old, &new));
+    (old.len() - deleted) * 100 / longest
+}
+
 #[cfg(test)]
 mod test {
     use super::Edit;
@@ -245,6 +400,50 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_hunks() {
+        let old = super::lines(b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16");
+        let new = super::lines(b"1\n2\n3\nfour\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15\n16\n");
+        assert_eq!(old.len(), 16);
+        assert_eq!(old[15], b"16");
+
+        let edits = super::myers_diff(&old, &new);
+        assert_eq!(super::count_changes(&edits), (2, 2));
+        let hunks = super::hunks(&edits, 3);
+        assert_eq!(hunks.len(), 2);
+
+        let mut out = Vec::new();
+        for hunk in &hunks {
+            hunk.write_to(&mut out).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "@@ -1,7 +1,7 @@\n 1\n 2\n 3\n-4\n+four\n 5\n 6\n 7\n\
+             @@ -13,4 +13,4 @@\n 13\n 14\n 15\n-16\n\\ No newline at end of file\n+16\n"
+        );
+
+        // changes with no more than twice the context between them are merged
+        let new = super::lines(b"1\n2\n3\nfour\n5\n6\n7\n8\n9\nten\n11\n12\n13\n14\n15\n16");
+        let edits = super::myers_diff(&old, &new);
+        let hunks = super::hunks(&edits, 3);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len), (1, 13));
+
+        // everything is new
+        let edits = super::myers_diff(&[], &new);
+        let hunks = super::hunks(&edits, 3);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len), (0, 0));
+        assert_eq!((hunks[0].new_start, hunks[0].new_len), (1, 16));
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(super::similarity(b"1\n2\n3\n4\n", b"1\n2\n3\n4\n"), 100);
+        assert_eq!(super::similarity(b"1\n2\n3\n4\n", b"1\n2\nthree\n"), 50);
+        assert_eq!(super::similarity(b"1\n", b"2\n"), 0);
+        assert_eq!(super::similarity(b"", b""), 100);
+    }
+
     #[test]
     fn test_nindex() {
         let v = vec![1, 2, 3, 4];
@@ -0,0 +1,322 @@
+//! fast-export, which writes history out as a stream of commands for
+//! `git fast-import`, so that a repository can be moved into other tools, or
+//! compared with what git exports from it. The stream is the same as git's:
+//!
+//! - each blob, the first time a commit needs it
+//! - each commit, oldest first, on the first ref found to reach it, with the
+//!   files it changed from its first parent
+//! - `reset`s for refs that the commits didn't leave where they belong
+//! - annotated tags
+//!
+//! Objects are referred to by marks, `:<n>`, numbered as they are written.
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::objects::{Commit, FileMode, Id, Object, ObjectType, Repo, Tag};
+use crate::rev::{self, Head};
+use crate::revwalk::RevWalk;
+use crate::tree::{self, Change};
+use crate::util::quote_c_style;
+
+struct Exporter<'a> {
+    repo: &'a Repo,
+    out: &'a mut dyn Write,
+    marks: HashMap<Id, usize>,
+}
+
+impl Exporter<'_> {
+    fn mark(&mut self, id: &Id) -> usize {
+        let mark = self.marks.len() + 1;
+        self.marks.insert(*id, mark);
+        mark
+    }
+
+    /// Writes a blob, unless it has been already
+    fn blob(&mut self, id: &Id) -> Result<()> {
+        if self.marks.contains_key(id) {
+            return Ok(());
+        }
+        let (_, content) = self.repo.read_object(id)?;
+        let mark = self.mark(id);
+        write!(self.out, "blob\nmark :{}\ndata {}\n", mark, content.len())?;
+        self.out.write_all(&content)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Writes a commit, and the blobs it adds, on the ref `refname`
+    fn commit(&mut self, id: &Id, refname: &str) -> Result<()> {
+        let commit = open_commit(id, self.repo)?;
+        let parent_tree = match commit.parents.first() {
+            Some(parent) => Some(open_commit(parent, self.repo)?.tree),
+            None => None,
+        };
+        let mut changes = tree::changed_files(parent_tree.as_ref(), Some(&commit.tree), self.repo)?;
+        for change in &changes {
+            match change.new {
+                Some((_, FileMode::Gitlink)) | None => {}
+                Some((id, _)) => self.blob(&id)?,
+            }
+        }
+
+        let mark = self.mark(id);
+        if commit.parents.is_empty() {
+            writeln!(self.out, "reset {}", refname)?;
+        }
+        writeln!(self.out, "commit {}\nmark :{}", refname, mark)?;
+        self.out.write_all(b"author ")?;
+        self.out.write_all(&commit.author.encode())?;
+        self.out.write_all(b"\ncommitter ")?;
+        self.out.write_all(&commit.committer.encode())?;
+        write!(
+            self.out,
+            "\ndata {}\n{}",
+            commit.message.len(),
+            commit.message
+        )?;
+        for (i, parent) in commit.parents.iter().enumerate() {
+            let command = if i == 0 { "from" } else { "merge" };
+            writeln!(self.out, "{} {}", command, self.reference(parent))?;
+        }
+
+        changes.sort_by(depth_first);
+        for Change { path, new, .. } in changes {
+            let path = quote_path(&path);
+            match new {
+                None => writeln!(self.out, "D {}", path)?,
+                Some((id, FileMode::Gitlink)) => writeln!(
+                    self.out,
+                    "M {:06o} {} {}",
+                    u32::from(FileMode::Gitlink),
+                    id,
+                    path
+                )?,
+                Some((id, mode)) => {
+                    let mark = self.marks[&id];
+                    writeln!(self.out, "M {:06o} :{} {}", u32::from(mode), mark, path)?
+                }
+            }
+        }
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Writes an annotated tag, as the tag `refname`
+    fn tag(&mut self, refname: &str, id: &Id, tag: &Tag) -> Result<()> {
+        match tag.kind {
+            ObjectType::Tag => {
+                return Err(anyhow!(
+                    "{} is a tag of a tag, which can't be exported",
+                    refname
+                ))
+            }
+            ObjectType::Tree => {
+                warn!("leaving out {}, since it tags the tree {}", id, tag.object);
+                return Ok(());
+            }
+            ObjectType::Commit | ObjectType::Blob => {}
+        }
+        let name = refname.strip_prefix("refs/tags/").unwrap_or(refname);
+        writeln!(self.out, "tag {}", name)?;
+        writeln!(self.out, "from {}", self.reference(&tag.object))?;
+        if let Some(tagger) = &tag.tagger {
+            self.out.write_all(b"tagger ")?;
+            self.out.write_all(&tagger.encode())?;
+            self.out.write_all(b"\n")?;
+        }
+        writeln!(self.out, "data {}\n{}", tag.message.len(), tag.message)?;
+        Ok(())
+    }
+
+    /// How to refer to an object: by its mark if it has been written, and
+    /// otherwise by its ID
+    fn reference(&self, id: &Id) -> String {
+        match self.marks.get(id) {
+            Some(mark) => format!(":{}", mark),
+            None => id.to_string(),
+        }
+    }
+}
+
+/// Writes all the refs, and HEAD if it is detached, as a fast-import stream
+pub fn fast_export(repo: &Repo, out: &mut dyn Write) -> Result<()> {
+    let mut refs = rev::list_refs(repo)?;
+    if rev::head_branch(repo)?.is_none() {
+        if let Head::Commit(id) = rev::read_head(repo)? {
+            refs.push(("HEAD".to_owned(), id));
+        }
+    }
+
+    let mut export = Exporter {
+        repo,
+        out,
+        marks: HashMap::new(),
+    };
+    // the ref each commit is written on, which is the first one found to
+    // reach it
+    let mut sources = HashMap::<Id, String>::new();
+    let mut tips = Vec::new();
+    // refs to move to their commits at the end, if writing the commits
+    // doesn't put them there
+    let mut moved = Vec::new();
+    let mut tags = Vec::new();
+    for (name, id) in refs {
+        let mut target = id;
+        let mut kind = repo.object_info(&target)?.kind;
+        while kind == ObjectType::Tag {
+            let tag = Tag::load(&repo.read_object(&target)?.1)?;
+            let tag_id = target;
+            target = tag.object;
+            kind = tag.kind;
+            tags.push((name.clone(), tag_id, tag));
+        }
+        match kind {
+            ObjectType::Commit => {
+                if target == id {
+                    moved.push((name.clone(), target));
+                }
+                sources.entry(target).or_insert(name);
+                tips.push(target);
+            }
+            // only tags of blobs get them written, like git
+            ObjectType::Blob if target != id => export.blob(&target)?,
+            _ => warn!("{} points to the {} {}, skipping it", name, kind, target),
+        }
+    }
+    moved.sort_by(|a, b| a.0.cmp(&b.0));
+    moved.dedup_by(|a, b| a.0 == b.0);
+
+    // commits are on the ref of the newest child to reach them
+    for id in RevWalk::new(repo, &tips)? {
+        let id = id?;
+        let source = sources[&id].clone();
+        for parent in open_commit(&id, repo)?.parents {
+            sources.entry(parent).or_insert_with(|| source.clone());
+        }
+    }
+
+    let mut commits = RevWalk::new(repo, &tips)?
+        .topo_order()
+        .collect::<Result<Vec<_>>>()?;
+    commits.reverse();
+    for id in &commits {
+        let refname = &sources[id];
+        moved.retain(|(name, _)| name != refname);
+        export.commit(id, refname)?;
+    }
+
+    for (name, id) in moved.iter().rev() {
+        writeln!(
+            export.out,
+            "reset {}\nfrom {}\n",
+            name,
+            export.reference(id)
+        )?;
+    }
+    for (name, id, tag) in tags.iter().rev() {
+        export.tag(name, id, tag)?;
+    }
+    export.out.flush()?;
+    Ok(())
+}
+
+fn open_commit(id: &Id, repo: &Repo) -> Result<Commit> {
+    match repo.open(id)? {
+        Object::Commit(commit) => Ok(commit),
+        _ => Err(anyhow!("{} is not a commit", id)),
+    }
+}
+
+/// Orders changes by path, but with paths inside a directory before the path
+/// of the directory itself, so that a file replacing a directory is written
+/// after the directory's files are deleted
+fn depth_first(a: &Change, b: &Change) -> Ordering {
+    let len = a.path.len().min(b.path.len());
+    a.path[..len]
+        .cmp(&b.path[..len])
+        .then(b.path.len().cmp(&a.path.len()))
+}
+
+/// Quotes a path if it has special characters in it, or spaces, which would
+/// otherwise make it ambiguous where the path ends
+fn quote_path(path: &[u8]) -> String {
+    match quote_c_style(path) {
+        Some(quoted) => quoted,
+        None if path.contains(&b' ') => format!("\"{}\"", String::from_utf8_lossy(path)),
+        None => String::from_utf8_lossy(path).into_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::objects::{Blob, Commit, File, FileMode, NameEntry, Repo, Tree};
+    use crate::rev;
+    use std::path::Path;
+
+    #[test]
+    fn test_fast_export() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("A U Thor <a@example.com> 1586391037 -0700").unwrap();
+        let a = repo.store(&Blob::new(b"a\n".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b\n".to_vec())).unwrap();
+        let commit = |files: Vec<(&str, FileMode, _)>, parents, message: &str| {
+            let tree = repo
+                .store(&Tree {
+                    files: files
+                        .into_iter()
+                        .map(|(name, mode, id)| File {
+                            mode,
+                            name: name.into(),
+                            id,
+                        })
+                        .collect(),
+                })
+                .unwrap();
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who.clone(),
+                message: message.to_owned(),
+            })
+            .unwrap()
+        };
+        let first = commit(vec![("a", FileMode::Regular, a)], vec![], "first\n");
+        let second = commit(
+            vec![
+                ("a b", FileMode::Executable, b),
+                ("sub", FileMode::Gitlink, first),
+            ],
+            vec![first],
+            "second",
+        );
+        rev::update_ref(Path::new("refs/heads/main"), &second, "", &repo).unwrap();
+        rev::update_ref(Path::new("refs/heads/old"), &first, "", &repo).unwrap();
+        rev::update_ref(Path::new("refs/heads/same"), &second, "", &repo).unwrap();
+
+        let mut out = Vec::new();
+        super::fast_export(&repo, &mut out).unwrap();
+        let expected = format!(
+            "blob\nmark :1\ndata 2\na\n\n\
+             reset refs/heads/old\n\
+             commit refs/heads/old\nmark :2\n\
+             author A U Thor <a@example.com> 1586391037 -0700\n\
+             committer A U Thor <a@example.com> 1586391037 -0700\n\
+             data 6\nfirst\n\
+             M 100644 :1 a\n\n\
+             blob\nmark :3\ndata 2\nb\n\n\
+             commit refs/heads/main\nmark :4\n\
+             author A U Thor <a@example.com> 1586391037 -0700\n\
+             committer A U Thor <a@example.com> 1586391037 -0700\n\
+             data 6\nsecondfrom :2\n\
+             M 100755 :3 \"a b\"\n\
+             D a\n\
+             M 160000 {} sub\n\n\
+             reset refs/heads/same\nfrom :4\n\n",
+            first
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,771 @@
+//! fast-import, which builds history from a stream of commands such as
+//! [`crate::fast_export`] writes, for bulk imports from other tools and for
+//! round trips through fast-export. The commands are those of
+//! `git fast-import`:
+//!
+//! - `blob`, with its content in a `data` command
+//! - `commit <ref>`, with its author, committer, message and parents, then
+//!   the changes to the files of its first parent: `M`odify, `D`elete,
+//!   `C`opy, `R`ename and `deleteall`
+//! - `reset <ref>`, which moves a branch, or starts it over
+//! - `tag <name>`, which makes an annotated tag
+//! - `checkpoint`, `progress`, `feature`, `option` and `done`
+//!
+//! Objects can be given marks, `mark :<n>`, to refer to them by later on.
+//! Refs are only updated once the stream has been read. Branches may only
+//! move forward, unless forced.
+use anyhow::{anyhow, Context, Result};
+use bstr::{BString, ByteSlice};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::mem;
+use std::path::Path;
+
+use crate::merge;
+use crate::objects::{Blob, Commit, FileMode, Id, NameEntry, Object, Repo, Tag};
+use crate::rev;
+use crate::tree::{self, SubTree, TreeEntry};
+use crate::util::unquote_c_style;
+
+/// Objects by the marks the stream gave them
+pub type Marks = BTreeMap<usize, Id>;
+
+/// Reads marks written by [`write_marks`], one `:<mark> <id>` per line
+pub fn read_marks(text: &str) -> Result<Marks> {
+    let mut marks = Marks::new();
+    for line in text.lines().filter(|line| !line.is_empty()) {
+        let mut parts = line.splitn(2, ' ');
+        let mark = parts.next().and_then(|mark| mark.strip_prefix(':'));
+        let mark = mark.and_then(|mark| mark.parse().ok());
+        let id = parts.next().and_then(Id::from);
+        match (mark, id) {
+            (Some(mark), Some(id)) => marks.insert(mark, id),
+            _ => return Err(anyhow!("bad line in marks: {}", line)),
+        };
+    }
+    Ok(marks)
+}
+
+/// Writes marks to be read back by [`read_marks`], or `git fast-import`
+pub fn write_marks(marks: &Marks) -> String {
+    marks
+        .iter()
+        .map(|(mark, id)| format!(":{} {}\n", mark, id))
+        .collect()
+}
+
+/// Imports the fast-import stream `input`, starting with the marks `marks`,
+/// and updates the refs it names. Branches that already exist are only
+/// moved if the commit they end up at contains the one they were at, unless
+/// `force` is given. Lines of `progress` commands are written to `out`.
+/// Returns the marks, with those the stream set.
+pub fn fast_import(
+    input: &[u8],
+    marks: Marks,
+    force: bool,
+    repo: &Repo,
+    out: &mut dyn Write,
+) -> Result<Marks> {
+    let mut import = Importer {
+        repo,
+        input: Parser { input, pos: 0 },
+        marks,
+        branches: HashMap::new(),
+        tags: BTreeMap::new(),
+        force,
+    };
+    import.run(out)?;
+    import.update_refs()?;
+    Ok(import.marks)
+}
+
+/// What a branch being imported into is at so far
+#[derive(Clone, Default)]
+struct Branch {
+    tip: Option<Id>,
+    /// The files of the tip, with the changes of the commit being imported
+    tree: SubTree,
+}
+
+struct Importer<'a> {
+    repo: &'a Repo,
+    input: Parser<'a>,
+    marks: Marks,
+    branches: HashMap<String, Branch>,
+    tags: BTreeMap<String, Id>,
+    force: bool,
+}
+
+impl Importer<'_> {
+    fn run(&mut self, out: &mut dyn Write) -> Result<()> {
+        while let Some(line) = self.input.next_line() {
+            let (command, arg) = split_command(line);
+            match command {
+                b"" => {}
+                b"blob" => self.blob()?,
+                b"commit" => self.commit(utf8(arg)?)?,
+                b"reset" => self.reset(utf8(arg)?)?,
+                b"tag" => self.tag(utf8(arg)?)?,
+                b"checkpoint" => self.update_refs()?,
+                b"progress" => {
+                    out.write_all(line)?;
+                    out.write_all(b"\n")?;
+                }
+                b"feature" => match arg {
+                    b"done" | b"date-format=raw" => {}
+                    b"force" => self.force = true,
+                    _ => {
+                        return Err(self
+                            .input
+                            .error(format!("unsupported feature {}", arg.as_bstr())))
+                    }
+                },
+                // options are for whichever tool reads the stream to make of
+                // what it will
+                b"option" => {}
+                b"done" => return Ok(()),
+                _ => {
+                    return Err(self
+                        .input
+                        .error(format!("unsupported command {}", line.as_bstr())))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn blob(&mut self) -> Result<()> {
+        let mark = self.mark()?;
+        self.input.optional(b"original-oid ");
+        let content = self.input.data()?;
+        let id = self.repo.store(&Blob::new(content))?;
+        self.set_mark(mark, id);
+        Ok(())
+    }
+
+    fn commit(&mut self, refname: &str) -> Result<()> {
+        let mark = self.mark()?;
+        self.input.optional(b"original-oid ");
+        let author = match self.input.optional(b"author ") {
+            Some(author) => Some(self.person(author)?),
+            None => None,
+        };
+        let committer = self
+            .input
+            .optional(b"committer ")
+            .ok_or_else(|| self.input.error("expected committer".to_owned()))?;
+        let committer = self.person(committer)?;
+        if self.input.optional(b"encoding ").is_some() {
+            warn!("ignoring the encoding of a commit on {}", refname);
+        }
+        let message = String::from_utf8(self.input.data()?)
+            .map_err(|_| self.input.error("commit message isn't UTF-8".to_owned()))?;
+
+        let mut branch = self.branches.get(refname).cloned().unwrap_or_default();
+        if let Some(from) = self.input.optional(b"from ") {
+            branch = self.branch_at(from)?;
+        }
+        let mut parents = branch.tip.into_iter().collect::<Vec<_>>();
+        while let Some(merge) = self.input.optional(b"merge ") {
+            let merge = self.commit_ish(merge)?;
+            parents.push(merge);
+        }
+
+        while let Some(line) = self.input.peek_line() {
+            let (command, arg) = split_command(line);
+            match command {
+                b"M" => self.modify(&mut branch.tree, arg)?,
+                b"D" => {
+                    let path = self.path(arg)?;
+                    self.remove(&mut branch.tree, &path)?;
+                }
+                b"C" | b"R" => {
+                    let (source, rest) = self.source_path(arg)?;
+                    let dest = self.path(rest.trim_start())?;
+                    let entry = self
+                        .entry(&mut branch.tree, &source)?
+                        .ok_or_else(|| self.input.error(format!("no path {} to copy", source)))?;
+                    if command == b"R" {
+                        self.remove(&mut branch.tree, &source)?;
+                    }
+                    self.insert(&mut branch.tree, &dest, entry)?;
+                }
+                b"deleteall" => branch.tree.clear(),
+                _ => break,
+            }
+            self.input.next_line();
+        }
+
+        let mut root = TreeEntry::SubTree(mem::take(&mut branch.tree));
+        let tree = tree::save_subtree(&mut root, self.repo)?;
+        if let TreeEntry::SubTree(files) = root {
+            branch.tree = files;
+        }
+        let id = self.repo.store(&Commit {
+            tree,
+            parents,
+            author: author.unwrap_or_else(|| committer.clone()),
+            committer,
+            message,
+        })?;
+        branch.tip = Some(id);
+        self.branches.insert(refname.to_owned(), branch);
+        self.set_mark(mark, id);
+        Ok(())
+    }
+
+    fn reset(&mut self, refname: &str) -> Result<()> {
+        let branch = match self.input.optional(b"from ") {
+            Some(from) => self.branch_at(from)?,
+            None => Branch::default(),
+        };
+        self.branches.insert(refname.to_owned(), branch);
+        Ok(())
+    }
+
+    fn tag(&mut self, name: &str) -> Result<()> {
+        let mark = self.mark()?;
+        let from = self
+            .input
+            .optional(b"from ")
+            .ok_or_else(|| self.input.error("expected from".to_owned()))?;
+        let object = self.object_ish(from)?;
+        self.input.optional(b"original-oid ");
+        let tagger = match self.input.optional(b"tagger ") {
+            Some(tagger) => Some(self.person(tagger)?),
+            None => None,
+        };
+        let message = String::from_utf8(self.input.data()?)
+            .map_err(|_| self.input.error("tag message isn't UTF-8".to_owned()))?;
+        let id = self.repo.store(&Tag {
+            object,
+            kind: self.repo.object_info(&object)?.kind,
+            name: name.to_owned(),
+            tagger,
+            message,
+        })?;
+        self.tags.insert(format!("refs/tags/{}", name), id);
+        self.set_mark(mark, id);
+        Ok(())
+    }
+
+    /// `M <mode> <blob> <path>`, where the blob may be `inline`, in a `data`
+    /// command on the next line
+    fn modify(&mut self, files: &mut SubTree, arg: &[u8]) -> Result<()> {
+        let mut parts = arg.splitn_str(3, " ");
+        let (mode, object, path) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(mode), Some(object), Some(path)) => (mode, object, path),
+            _ => {
+                return Err(self
+                    .input
+                    .error("expected M <mode> <blob> <path>".to_owned()))
+            }
+        };
+        let mode = match mode {
+            b"100644" | b"644" => FileMode::Regular,
+            b"100755" | b"755" => FileMode::Executable,
+            b"120000" => FileMode::Symlink,
+            b"160000" => FileMode::Gitlink,
+            b"040000" => FileMode::Directory,
+            _ => return Err(self.input.error(format!("bad mode {}", mode.as_bstr()))),
+        };
+        let path = self.path(path)?;
+        let id = if object == b"inline" {
+            self.input.next_line();
+            let content = self.input.data()?;
+            self.repo.store(&Blob::new(content))?
+        } else {
+            self.object_ish(object)?
+        };
+        let entry = match mode {
+            FileMode::Directory => TreeEntry::Tree(id),
+            mode => TreeEntry::Blob(id, mode),
+        };
+        if path.is_empty() {
+            // a whole tree replacing the root
+            files.clear();
+            files.extend(load_tree(&id, self.repo)?);
+            return Ok(());
+        }
+        self.insert(files, &path, entry)
+    }
+
+    /// Reads a path that is quoted, or otherwise the rest of the line
+    fn path(&self, text: &[u8]) -> Result<BString> {
+        if text.starts_with(b"\"") {
+            self.quoted_path(text).map(|(path, _)| path)
+        } else {
+            Ok(text.into())
+        }
+    }
+
+    /// Reads the source path of a copy or rename, which is quoted or ends at
+    /// a space, returning it and what follows it
+    fn source_path<'t>(&self, text: &'t [u8]) -> Result<(BString, &'t [u8])> {
+        if text.starts_with(b"\"") {
+            self.quoted_path(text)
+        } else {
+            let space = text.find_byte(b' ').unwrap_or(text.len());
+            Ok((text[..space].into(), &text[space..]))
+        }
+    }
+
+    fn quoted_path<'t>(&self, text: &'t [u8]) -> Result<(BString, &'t [u8])> {
+        let (path, len) = unquote_c_style(text).ok_or_else(|| {
+            self.input
+                .error(format!("bad quoting in {}", text.as_bstr()))
+        })?;
+        Ok((path, &text[len..]))
+    }
+
+    /// The entry at `path`, loading the trees on the way to it
+    fn entry(&self, files: &mut SubTree, path: &[u8]) -> Result<Option<TreeEntry>> {
+        let (dirs, name) = split_path(path);
+        Ok(dir_mut(files, &dirs, false, self.repo)?
+            .and_then(|dir| dir.get(name.as_bstr()).cloned()))
+    }
+
+    fn insert(&self, files: &mut SubTree, path: &[u8], entry: TreeEntry) -> Result<()> {
+        let (dirs, name) = split_path(path);
+        if let Some(dir) = dir_mut(files, &dirs, true, self.repo)? {
+            dir.insert(name.into(), entry);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, files: &mut SubTree, path: &[u8]) -> Result<()> {
+        let (dirs, name) = split_path(path);
+        if let Some(dir) = dir_mut(files, &dirs, false, self.repo)? {
+            dir.remove(name.as_bstr());
+        }
+        Ok(())
+    }
+
+    /// Reads an optional `mark :<n>` line
+    fn mark(&mut self) -> Result<Option<usize>> {
+        match self.input.optional(b"mark :") {
+            Some(mark) => match utf8(mark)?.parse() {
+                Ok(mark) => Ok(Some(mark)),
+                Err(_) => Err(self.input.error(format!("bad mark :{}", mark.as_bstr()))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    fn set_mark(&mut self, mark: Option<usize>, id: Id) {
+        if let Some(mark) = mark {
+            self.marks.insert(mark, id);
+        }
+    }
+
+    fn person(&self, text: &[u8]) -> Result<NameEntry> {
+        utf8(text)
+            .ok()
+            .and_then(NameEntry::from)
+            .ok_or_else(|| self.input.error(format!("bad identity {}", text.as_bstr())))
+    }
+
+    /// Finds an object by mark or ID, or a commit by the branch or ref it is
+    /// on
+    fn object_ish(&self, text: &[u8]) -> Result<Id> {
+        let text = utf8(text)?;
+        if let Some(mark) = text.strip_prefix(':') {
+            return mark
+                .parse()
+                .ok()
+                .and_then(|mark| self.marks.get(&mark))
+                .copied()
+                .ok_or_else(|| self.input.error(format!("no mark {}", text)));
+        }
+        if let Some(id) = Id::from(text) {
+            return Ok(id);
+        }
+        if let Some(tip) = self.branches.get(text).and_then(|branch| branch.tip) {
+            return Ok(tip);
+        }
+        rev::parse(text, self.repo).with_context(|| self.input.error(format!("no object {}", text)))
+    }
+
+    fn commit_ish(&self, text: &[u8]) -> Result<Id> {
+        let id = rev::peel_tags(self.object_ish(text)?, self.repo)?;
+        match self.repo.open(&id)? {
+            Object::Commit(_) => Ok(id),
+            _ => Err(self
+                .input
+                .error(format!("{} is not a commit", text.as_bstr()))),
+        }
+    }
+
+    /// A branch starting from the commit `from`, or from nothing if it is
+    /// all zeroes
+    fn branch_at(&self, from: &[u8]) -> Result<Branch> {
+        let text = utf8(from)?;
+        if text.len() == 40 && text.bytes().all(|c| c == b'0') {
+            return Ok(Branch::default());
+        }
+        if let Some(branch) = self.branches.get(text) {
+            return Ok(branch.clone());
+        }
+        let tip = self.commit_ish(from)?;
+        let tree = match self.repo.open(&tip)? {
+            Object::Commit(commit) => load_tree(&commit.tree, self.repo)?,
+            _ => unreachable!(),
+        };
+        Ok(Branch {
+            tip: Some(tip),
+            tree,
+        })
+    }
+
+    /// Moves the refs of the branches and tags to where they are now
+    fn update_refs(&mut self) -> Result<()> {
+        let mut refused = Vec::new();
+        // commits may be made on a tag's ref before the tag itself, which
+        // then takes its place
+        let mut refs = self
+            .branches
+            .iter()
+            .filter(|(name, _)| !self.tags.contains_key(*name))
+            .filter_map(|(name, branch)| Some((name.clone(), branch.tip?)))
+            .collect::<Vec<_>>();
+        refs.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, id) in refs {
+            if !self.force {
+                if let Ok(old) = rev::parse(&name, self.repo) {
+                    if old != id && !merge::merge_bases(&old, &id, self.repo)?.contains(&old) {
+                        warn!(
+                            "not updating {} (new tip {} does not contain {})",
+                            name, id, old
+                        );
+                        refused.push(name);
+                        continue;
+                    }
+                }
+            }
+            rev::update_ref(Path::new(&name), &id, "fast-import", self.repo)?;
+        }
+        for (name, id) in &self.tags {
+            rev::update_ref(Path::new(name), id, "fast-import", self.repo)?;
+        }
+        if !refused.is_empty() {
+            return Err(anyhow!(
+                "refused to move {}, which would lose commits; use --force to move them anyway",
+                refused.join(", ")
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the lines of the stream
+#[derive(Clone, Copy)]
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// The next line, without its newline
+    fn raw_line(&mut self) -> Option<&'a [u8]> {
+        if self.pos >= self.input.len() {
+            return None;
+        }
+        let rest = &self.input[self.pos..];
+        let end = rest.find_byte(b'\n').unwrap_or(rest.len());
+        self.pos += (end + 1).min(rest.len());
+        Some(&rest[..end])
+    }
+
+    /// The next line that isn't a comment
+    fn next_line(&mut self) -> Option<&'a [u8]> {
+        loop {
+            let line = self.raw_line()?;
+            if !line.starts_with(b"#") {
+                return Some(line);
+            }
+        }
+    }
+
+    fn peek_line(&self) -> Option<&'a [u8]> {
+        let mut peek = *self;
+        peek.next_line()
+    }
+
+    /// Reads the next line if it starts with `prefix`, returning the rest
+    fn optional(&mut self, prefix: &[u8]) -> Option<&'a [u8]> {
+        let mut peek = *self;
+        let line = peek.next_line()?;
+        if line.starts_with(prefix) {
+            *self = peek;
+            Some(&line[prefix.len()..])
+        } else {
+            None
+        }
+    }
+
+    /// Reads a `data <length>` command and the bytes after it, or a
+    /// `data <<<delimiter>` command and the lines up to the delimiter
+    fn data(&mut self) -> Result<Vec<u8>> {
+        let arg = self
+            .optional(b"data ")
+            .ok_or_else(|| self.error("expected data".to_owned()))?;
+        let content = if arg.starts_with(b"<<") {
+            let delimiter = &arg[2..];
+            let mut content = Vec::new();
+            loop {
+                match self.raw_line() {
+                    Some(line) if line == delimiter => break,
+                    Some(line) => {
+                        content.extend_from_slice(line);
+                        content.push(b'\n');
+                    }
+                    None => return Err(self.error("data never ended".to_owned())),
+                }
+            }
+            content
+        } else {
+            let len = std::str::from_utf8(arg)
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok())
+                .ok_or_else(|| self.error(format!("bad data length {}", arg.as_bstr())))?;
+            if self.input.len() - self.pos < len {
+                return Err(self.error("data runs past the end".to_owned()));
+            }
+            self.pos += len;
+            self.input[self.pos - len..self.pos].to_vec()
+        };
+        // the newline after data is optional
+        if self.input.get(self.pos) == Some(&b'\n') {
+            self.pos += 1;
+        }
+        Ok(content)
+    }
+
+    /// An error about the line just read
+    fn error(&self, message: String) -> anyhow::Error {
+        let done = &self.input[..self.pos.min(self.input.len())];
+        let line = done.trim_end_with(|c| c == '\n').find_iter("\n").count() + 1;
+        anyhow!("line {} of the stream: {}", line, message)
+    }
+}
+
+fn split_command(line: &[u8]) -> (&[u8], &[u8]) {
+    match line.find_byte(b' ') {
+        Some(space) => (&line[..space], &line[space + 1..]),
+        None => (line, b""),
+    }
+}
+
+fn utf8(text: &[u8]) -> Result<&str> {
+    std::str::from_utf8(text).map_err(|_| anyhow!("{} isn't UTF-8", text.as_bstr()))
+}
+
+/// Splits a path into its directories and its file name
+fn split_path(path: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    let mut dirs = path
+        .split(|&c| c == b'/')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>();
+    let name = dirs.pop().unwrap_or_default();
+    (dirs, name)
+}
+
+/// The entries of a stored tree, with its subtrees left unloaded
+fn load_tree(id: &Id, repo: &Repo) -> Result<SubTree> {
+    let tree = match repo.open(id)? {
+        Object::Tree(tree) => tree,
+        _ => return Err(anyhow!("{} is not a tree", id)),
+    };
+    Ok(tree
+        .files
+        .into_iter()
+        .map(|file| {
+            let entry = match file.mode {
+                FileMode::Directory => TreeEntry::Tree(file.id),
+                mode => TreeEntry::Blob(file.id, mode),
+            };
+            (file.name, entry)
+        })
+        .collect())
+}
+
+/// The directory at `dirs` in a tree, loading stored trees on the way to it.
+/// With `create`, directories that don't exist are made, replacing any files
+/// in the way.
+fn dir_mut<'t>(
+    root: &'t mut SubTree,
+    dirs: &[&[u8]],
+    create: bool,
+    repo: &Repo,
+) -> Result<Option<&'t mut SubTree>> {
+    let mut dir = root;
+    for &name in dirs {
+        let name = BString::from(name);
+        if let Some(TreeEntry::Tree(id)) = dir.get(&name) {
+            let loaded = load_tree(&id.clone(), repo)?;
+            dir.insert(name.clone(), TreeEntry::SubTree(loaded));
+        }
+        if create && !matches!(dir.get(&name), Some(TreeEntry::SubTree(_))) {
+            dir.insert(name.clone(), TreeEntry::SubTree(SubTree::new()));
+        }
+        dir = match dir.get_mut(&name) {
+            Some(TreeEntry::SubTree(subtree)) => subtree,
+            _ => return Ok(None),
+        };
+    }
+    Ok(Some(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Marks;
+    use crate::fast_export::fast_export;
+    use crate::objects::{FileMode, Id, Object, Repo};
+    use crate::rev;
+    use crate::tree;
+
+    #[test]
+    fn test_marks() {
+        let id = Id::from("94546d68dc6002b85cc2d7df077c7c6bb080abb0").unwrap();
+        let mut marks = Marks::new();
+        marks.insert(2, id);
+        let text = super::write_marks(&marks);
+        assert_eq!(text, format!(":2 {}\n", id));
+        assert_eq!(super::read_marks(&text).unwrap(), marks);
+        assert!(super::read_marks("2 nonsense\n").is_err());
+    }
+
+    #[test]
+    fn test_fast_import() {
+        let repo = Repo::in_memory();
+        let stream = b"# a comment\n\
+            blob\nmark :1\ndata 6\nhello\n\n\
+            commit refs/heads/main\nmark :2\n\
+            author A U Thor <a@example.com> 1586391037 -0700\n\
+            committer C O Mitter <c@example.com> 1586391040 -0700\n\
+            data <<EOF\nfirst\nEOF\n\
+            M 100644 :1 dir/a\n\
+            M 755 inline \"sp ace\"\ndata 3\nrun\n\
+            \n\
+            commit refs/heads/main\nmark :3\n\
+            committer C O Mitter <c@example.com> 1586391050 -0700\n\
+            data 6\nsecond\
+            C dir/a dir/b\n\
+            R \"sp ace\" c\n\
+            M 644 :1 x y\n\
+            \n\
+            reset refs/heads/side\nfrom :2\n\n\
+            commit refs/heads/side\nmark :4\n\
+            committer C O Mitter <c@example.com> 1586391060 -0700\n\
+            data 5\nside\n\
+            deleteall\n\
+            M 120000 :1 link\n\
+            commit refs/heads/main\nmark :5\n\
+            committer C O Mitter <c@example.com> 1586391070 -0700\n\
+            data 6\nmerge\n\
+            merge :4\n\
+            D dir/a\n\
+            D x y\n\
+            tag v1\nfrom :3\n\
+            tagger C O Mitter <c@example.com> 1586391080 -0700\n\
+            data 5\nv1.0\n\
+            progress done\n\
+            done\n\
+            commit refs/heads/ignored\n";
+
+        let mut out = Vec::new();
+        let marks = super::fast_import(stream, Marks::new(), false, &repo, &mut out).unwrap();
+        assert_eq!(out, b"progress done\n");
+        assert_eq!(
+            marks.keys().copied().collect::<Vec<_>>(),
+            vec![1, 2, 3, 4, 5]
+        );
+        let commit = |mark| match repo.open(&marks[&mark]).unwrap() {
+            Object::Commit(commit) => commit,
+            _ => panic!("mark {} is not a commit", mark),
+        };
+        let files = |mark| {
+            let tree = repo.open(&commit(mark).tree).unwrap().tree().unwrap();
+            let mut files = Vec::new();
+            tree::load_tree_from_disk(&tree, &repo, b"", &mut files).unwrap();
+            files
+                .into_iter()
+                .map(|(name, id, mode)| (name.to_string(), id, mode))
+                .collect::<Vec<_>>()
+        };
+        let hello = marks[&1];
+
+        let first = commit(2);
+        assert_eq!(first.author.name, "A U Thor");
+        assert_eq!(first.committer.name, "C O Mitter");
+        assert_eq!(first.message, "first\n");
+        assert_eq!(first.parents, vec![]);
+        let files_2 = files(2);
+        assert_eq!(files_2[0], ("dir/a".to_owned(), hello, FileMode::Regular));
+        assert_eq!(files_2[1].0, "sp ace");
+        assert_eq!(files_2[1].2, FileMode::Executable);
+
+        let second = commit(3);
+        assert_eq!(second.author, second.committer);
+        assert_eq!(second.message, "second");
+        assert_eq!(second.parents, vec![marks[&2]]);
+        let run = files_2[1].1;
+        assert_eq!(
+            files(3),
+            vec![
+                ("c".to_owned(), run, FileMode::Executable),
+                ("dir/a".to_owned(), hello, FileMode::Regular),
+                ("dir/b".to_owned(), hello, FileMode::Regular),
+                ("x y".to_owned(), hello, FileMode::Regular),
+            ]
+        );
+        assert_eq!(commit(4).parents, vec![marks[&2]]);
+        assert_eq!(
+            files(4),
+            vec![("link".to_owned(), hello, FileMode::Symlink)]
+        );
+        assert_eq!(commit(5).parents, vec![marks[&3], marks[&4]]);
+        assert_eq!(
+            files(5),
+            vec![
+                ("c".to_owned(), run, FileMode::Executable),
+                ("dir/b".to_owned(), hello, FileMode::Regular),
+            ]
+        );
+
+        assert_eq!(rev::parse("refs/heads/main", &repo).unwrap(), marks[&5]);
+        assert_eq!(rev::parse("refs/heads/side", &repo).unwrap(), marks[&4]);
+        assert!(rev::parse("refs/heads/ignored", &repo).is_err());
+        let tag = rev::parse("refs/tags/v1", &repo).unwrap();
+        assert_eq!(rev::peel_tags(tag, &repo).unwrap(), marks[&3]);
+
+        // exporting it and importing that somewhere else makes the same
+        // commits
+        let mut exported = Vec::new();
+        fast_export(&repo, &mut exported).unwrap();
+        let copy = Repo::in_memory();
+        let mut out = Vec::new();
+        super::fast_import(&exported, Marks::new(), false, &copy, &mut out).unwrap();
+        for name in &["refs/heads/main", "refs/heads/side", "refs/tags/v1"] {
+            assert_eq!(
+                rev::parse(name, &copy).unwrap(),
+                rev::parse(name, &repo).unwrap()
+            );
+        }
+
+        // branches only move forward unless forced
+        let stream = b"reset refs/heads/main\nfrom :4\n";
+        assert!(super::fast_import(stream, marks.clone(), false, &repo, &mut out).is_err());
+        assert_eq!(rev::parse("refs/heads/main", &repo).unwrap(), marks[&5]);
+        super::fast_import(stream, marks.clone(), true, &repo, &mut out).unwrap();
+        assert_eq!(rev::parse("refs/heads/main", &repo).unwrap(), marks[&4]);
+
+        // commits on a tag's ref are replaced by the tag, as git writes them
+        let stream = b"reset refs/tags/v2\nfrom :3\n\n\
+            tag v2\nfrom refs/tags/v2\ndata 0\n";
+        super::fast_import(stream, marks.clone(), false, &repo, &mut out).unwrap();
+        let tag = rev::parse("refs/tags/v2", &repo).unwrap();
+        assert_ne!(tag, marks[&3]);
+        assert_eq!(rev::peel_tags(tag, &repo).unwrap(), marks[&3]);
+    }
+}
@@ -0,0 +1,622 @@
+//! The client side of fetch: version 0 of git's pack protocol, as served by
+//! [`upload_pack`](crate::upload_pack) and `git upload-pack`. The remote
+//! advertises its refs, we say which of their commits we want and which we
+//! have, then it sends a pack of what we are missing. The pack is indexed
+//! into the repository and the refs are copied into ours through refspecs,
+//! `refs/heads/*` going to `refs/remotes/<remote>/*` unless the remote is
+//! configured otherwise.
+//!
+//! Like the server, we keep to the simplest form of the protocol: no
+//! multi_ack, so negotiation stops at the first commit the remote has too.
+use anyhow::{Error, Result};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::merge;
+use crate::objects::{Id, ObjectType, Repo};
+use crate::packs;
+use crate::pktline::{self, Packet};
+use crate::remote::{Callbacks, TransferProgress};
+use crate::rev;
+use crate::revwalk::RevWalk;
+use crate::trace;
+
+/// Haves sent before waiting to hear whether the remote has any of them
+const HAVES_PER_ROUND: usize = 32;
+
+/// Errors that can arise while fetching
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FetchError {
+    /// The remote sent something other than what the protocol allows here
+    #[error("Protocol error: expected {expected}, got {got:?}")]
+    Protocol {
+        /// What was expected
+        expected: &'static str,
+        /// The offending line
+        got: String,
+    },
+
+    /// The remote reported an error
+    #[error("remote error: {0}")]
+    Remote(String),
+
+    /// The remote hung up partway through
+    #[error("The remote end hung up unexpectedly")]
+    Disconnected,
+
+    /// There is no URL configured for the remote
+    #[error("No URL configured for remote {0}")]
+    NoUrl(String),
+
+    /// A refspec in the configuration can't be understood
+    #[error("Bad refspec {0:?}")]
+    BadRefspec(String),
+}
+
+/// Which refs to fetch and where to put them, such as
+/// `+refs/heads/*:refs/remotes/origin/*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refspec {
+    /// Whether refs are updated even when that loses commits, which a
+    /// leading `+` asks for
+    pub force: bool,
+    /// The remote refs, with at most one `*`
+    pub src: String,
+    /// Where they go, with a `*` if the source has one
+    pub dst: String,
+}
+
+impl Refspec {
+    /// Parses a refspec of the form `[+]<src>:<dst>`
+    pub fn parse(spec: &str) -> Option<Refspec> {
+        let (force, rest) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let colon = rest.find(':')?;
+        let (src, dst) = (&rest[..colon], &rest[colon + 1..]);
+        let stars = |s: &str| s.matches('*').count();
+        if src.is_empty() || dst.is_empty() || stars(src) > 1 || stars(src) != stars(dst) {
+            return None;
+        }
+        Some(Refspec {
+            force,
+            src: src.to_owned(),
+            dst: dst.to_owned(),
+        })
+    }
+
+    /// The refspec git uses for a remote unless told otherwise
+    pub fn default_for(remote: &str) -> Refspec {
+        Refspec {
+            force: true,
+            src: "refs/heads/*".to_owned(),
+            dst: format!("refs/remotes/{}/*", remote),
+        }
+    }
+
+    /// Where the remote ref `name` goes, if this refspec takes it
+    pub fn map(&self, name: &str) -> Option<String> {
+        match self.src.find('*') {
+            Some(star) => {
+                let (prefix, suffix) = (&self.src[..star], &self.src[star + 1..]);
+                if name.len() < prefix.len() + suffix.len()
+                    || !name.starts_with(prefix)
+                    || !name.ends_with(suffix)
+                {
+                    return None;
+                }
+                let matched = &name[prefix.len()..name.len() - suffix.len()];
+                Some(self.dst.replacen('*', matched, 1))
+            }
+            None if name == self.src => Some(self.dst.clone()),
+            None => None,
+        }
+    }
+}
+
+/// What happened to a ref
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateKind {
+    /// It didn't exist before
+    New,
+    /// It moved forward
+    FastForward,
+    /// It moved somewhere that doesn't contain where it was, which the
+    /// refspec allowed
+    Forced,
+    /// It would have lost commits, so it was left alone
+    Rejected,
+    /// It was where the remote has it already
+    UpToDate,
+    /// It was deleted, which only a push does
+    Deleted,
+}
+
+/// A ref updated, or not, by a fetch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdate {
+    /// The ref's name on the remote
+    pub remote: String,
+    /// The ref it was copied to
+    pub local: String,
+    /// Where the local ref was before
+    pub old: Option<Id>,
+    /// Where the remote ref is
+    pub new: Id,
+    /// What happened
+    pub kind: UpdateKind,
+}
+
+/// The refs and capabilities a remote advertised
+#[derive(Debug, Default)]
+pub struct Advertisement {
+    /// Refs by name, in the order they were sent
+    pub refs: Vec<(String, Id)>,
+    /// Capabilities the remote offered
+    pub capabilities: Vec<String>,
+}
+
+impl Advertisement {
+    /// Whether the remote offered a capability, such as `ofs-delta`
+    pub fn offers(&self, capability: &str) -> bool {
+        self.capabilities
+            .iter()
+            .any(|offered| offered == capability)
+    }
+}
+
+fn protocol_error(expected: &'static str, got: &[u8]) -> Error {
+    Error::new(FetchError::Protocol {
+        expected,
+        got: String::from_utf8_lossy(got).into_owned(),
+    })
+}
+
+/// Reads a packet, treating the remote hanging up as an error, and an `ERR`
+/// packet as the error it reports
+fn read_packet(input: &mut impl Read) -> Result<Packet> {
+    let packet = pktline::read(input)?.ok_or(FetchError::Disconnected)?;
+    match packet.line() {
+        Some(line) if line.starts_with(b"ERR ") => Err(Error::new(FetchError::Remote(
+            String::from_utf8_lossy(&line[4..]).into_owned(),
+        ))),
+        _ => Ok(packet),
+    }
+}
+
+/// Reads the refs a remote advertises, and the capabilities that come with
+/// the first of them
+pub fn read_advertisement(input: &mut impl Read) -> Result<Advertisement> {
+    let mut advertisement = Advertisement::default();
+    while let Some(line) = read_packet(input)?.line() {
+        let (line, capabilities) = match line.iter().position(|&b| b == 0) {
+            Some(nul) => (&line[..nul], Some(&line[nul + 1..])),
+            None => (line, None),
+        };
+        if let Some(capabilities) = capabilities {
+            advertisement.capabilities = String::from_utf8_lossy(capabilities)
+                .split(' ')
+                .filter(|capability| !capability.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+
+        let bad = || protocol_error("a ref", line);
+        let text = std::str::from_utf8(line).map_err(|_| bad())?;
+        let mut parts = text.splitn(2, ' ');
+        let id = parts.next().and_then(Id::from).ok_or_else(bad)?;
+        let name = parts.next().ok_or_else(bad)?;
+        // an empty repository only sends its capabilities
+        if name != "capabilities^{}" {
+            advertisement.refs.push((name.to_owned(), id));
+        }
+    }
+    Ok(advertisement)
+}
+
+/// Our commits to tell the remote we have, newest first
+fn haves(repo: &Repo) -> Result<Vec<Id>> {
+    let mut tips = Vec::new();
+    for (_, id) in rev::list_refs(repo)? {
+        let id = rev::peel_tags(id, repo)?;
+        if repo.object_info(&id)?.kind == ObjectType::Commit {
+            tips.push(id);
+        }
+    }
+    RevWalk::new(repo, &tips)?.collect()
+}
+
+/// Sends our haves until the remote says it has one of them, then says
+/// we're done
+fn negotiate(haves: &[Id], input: &mut impl Read, output: &mut impl Write) -> Result<Option<Id>> {
+    for round in haves.chunks(HAVES_PER_ROUND) {
+        for have in round {
+            pktline::write(output, format!("have {}\n", have).as_bytes())?;
+        }
+        pktline::flush(output)?;
+        output.flush()?;
+
+        // without multi_ack, each round is answered with the first have the
+        // remote has, or a NAK
+        let packet = read_packet(input)?;
+        let line = packet.line().unwrap_or_default();
+        if line == b"NAK" {
+            continue;
+        }
+        let common = std::str::from_utf8(line)
+            .ok()
+            .and_then(|line| line.strip_prefix("ACK "))
+            .and_then(Id::from)
+            .ok_or_else(|| protocol_error("ACK or NAK", line))?;
+        pktline::write(output, b"done\n")?;
+        output.flush()?;
+        return Ok(Some(common));
+    }
+
+    pktline::write(output, b"done\n")?;
+    output.flush()?;
+    let packet = read_packet(input)?;
+    match packet.line() {
+        Some(b"NAK") => Ok(None),
+        line => Err(protocol_error("NAK", line.unwrap_or_default())),
+    }
+}
+
+/// Whether a packet is an acknowledgement left over from negotiation. Without
+/// multi_ack, git's upload-pack goes on acknowledging haves in the last round
+/// that it knows are common from the first one, so these can come after we
+/// have said we're done, ahead of the pack.
+fn is_ack(line: &[u8]) -> bool {
+    line.starts_with(b"ACK ") || line.starts_with(b"NAK")
+}
+
+/// Reads the pack, which comes in band 1 of the side-band if that was asked
+/// for, with progress messages in band 2 and errors in band 3
+fn receive_pack(
+    side_band: bool,
+    input: &mut impl Read,
+    callbacks: &mut Callbacks,
+) -> Result<Vec<u8>> {
+    let mut pack = Vec::new();
+    let mut stats = TransferProgress::default();
+    let mut report = |pack: &[u8], callbacks: &mut Callbacks| {
+        stats.received_bytes = pack.len() as u64;
+        if stats.total_objects == 0 && pack.len() >= 12 {
+            let mut count = [0; 4];
+            count.copy_from_slice(&pack[8..12]);
+            stats.total_objects = u32::from_be_bytes(count) as usize;
+        }
+        callbacks.report_transfer(&stats)
+    };
+
+    if !side_band {
+        // the pack starts with its signature, where a packet would have its
+        // length
+        loop {
+            let mut start = [0; 4];
+            input.read_exact(&mut start)?;
+            if &start == b"PACK" {
+                pack.extend_from_slice(&start);
+                break;
+            }
+            let packet = read_packet(&mut (&start[..]).chain(&mut *input))?;
+            let line = packet.line().unwrap_or_default();
+            if !is_ack(line) {
+                return Err(protocol_error("a pack", line));
+            }
+        }
+        let mut chunk = [0; 65536];
+        loop {
+            let len = input.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(pack);
+            }
+            pack.extend_from_slice(&chunk[..len]);
+            report(&pack, callbacks)?;
+        }
+    }
+    loop {
+        let data = match read_packet(input)? {
+            Packet::Data(data) => data,
+            Packet::Flush => return Ok(pack),
+        };
+        match data.split_first() {
+            Some((1, data)) => {
+                pack.extend_from_slice(data);
+                report(&pack, callbacks)?;
+            }
+            Some((2, message)) => {
+                callbacks.report_progress(&String::from_utf8_lossy(message))?;
+            }
+            Some((3, message)) => {
+                return Err(Error::new(FetchError::Remote(
+                    String::from_utf8_lossy(message).trim_end().to_owned(),
+                )))
+            }
+            _ if pack.is_empty() && is_ack(&data) => {}
+            _ => return Err(protocol_error("a side-band packet", &data)),
+        }
+    }
+}
+
+/// Fetches from the remote on the other end of `input` and `output`, which
+/// has just been connected to its upload-pack, and copies its refs into
+/// ours through `refspecs`. The remote's objects are stored in a new pack.
+pub fn fetch_from(
+    repo: &mut Repo,
+    refspecs: &[Refspec],
+    input: &mut impl Read,
+    output: &mut impl Write,
+    callbacks: &mut Callbacks,
+) -> Result<Vec<RefUpdate>> {
+    let _region = trace::region("transport", "fetch");
+    // what we have is told to the remote as it really is
+    repo.set_replace_objects(false);
+    let advertisement = read_advertisement(input)?;
+
+    let mut updates = Vec::new();
+    for (name, id) in &advertisement.refs {
+        if name.ends_with("^{}") {
+            continue;
+        }
+        if let Some(refspec) = refspecs.iter().find(|refspec| refspec.map(name).is_some()) {
+            let local = refspec.map(name).unwrap();
+            let old = rev::parse(&local, repo).ok();
+            updates.push((refspec.force, name.clone(), local, old, *id));
+        }
+    }
+
+    let mut wants = Vec::new();
+    let mut seen = HashSet::new();
+    for (_, _, _, _, id) in &updates {
+        if !repo.has_id(id) && seen.insert(*id) {
+            wants.push(*id);
+        }
+    }
+
+    if wants.is_empty() {
+        pktline::flush(output)?;
+        output.flush()?;
+    } else {
+        let side_band = advertisement.offers("side-band-64k");
+        let capabilities = ["ofs-delta", "side-band-64k"]
+            .iter()
+            .filter(|capability| advertisement.offers(capability))
+            .map(|capability| capability.to_string())
+            .chain(
+                advertisement
+                    .capabilities
+                    .iter()
+                    .find(|capability| capability.starts_with("agent="))
+                    .map(|_| format!("agent=rgit/{}", env!("CARGO_PKG_VERSION"))),
+            )
+            .collect::<Vec<_>>()
+            .join(" ");
+        for (n, want) in wants.iter().enumerate() {
+            let line = if n == 0 && !capabilities.is_empty() {
+                format!("want {} {}\n", want, capabilities)
+            } else {
+                format!("want {}\n", want)
+            };
+            pktline::write(output, line.as_bytes())?;
+        }
+        pktline::flush(output)?;
+
+        let common = {
+            let _region = trace::region("transport", "negotiate");
+            negotiate(&haves(repo)?, input, output)?
+        };
+        trace::message(format_args!(
+            "fetch: {} wants, common commit {:?}",
+            wants.len(),
+            common
+        ));
+
+        let pack = receive_pack(side_band, input, callbacks)?;
+        packs::store_received_pack(repo, &pack)?;
+    }
+
+    let mut results = Vec::new();
+    for (force, remote, local, old, new) in updates {
+        let kind = match old {
+            None => UpdateKind::New,
+            Some(old) if old == new => UpdateKind::UpToDate,
+            Some(old) if merge::merge_bases(&old, &new, repo)?.contains(&old) => {
+                UpdateKind::FastForward
+            }
+            Some(_) if force => UpdateKind::Forced,
+            Some(_) => UpdateKind::Rejected,
+        };
+        let message = match kind {
+            UpdateKind::New => Some("fetch: storing head"),
+            UpdateKind::FastForward => Some("fetch: fast-forward"),
+            UpdateKind::Forced => Some("fetch: forced-update"),
+            UpdateKind::Rejected | UpdateKind::UpToDate | UpdateKind::Deleted => None,
+        };
+        if let Some(message) = message {
+            rev::update_ref(Path::new(&local), &new, message, repo)?;
+        }
+        results.push(RefUpdate {
+            remote,
+            local,
+            old,
+            new,
+            kind,
+        });
+    }
+    Ok(results)
+}
+
+/// The refspecs to fetch `remote` with, from `remote.<remote>.fetch` in the
+/// config, or the default ones
+pub fn refspecs_for(remote: &str, repo: &Repo) -> Result<Vec<Refspec>> {
+    let config = repo.config()?;
+    let configured = config
+        .get_all(&format!("remote.{}.fetch", remote))
+        .map(|spec| {
+            Refspec::parse(spec).ok_or_else(|| FetchError::BadRefspec(spec.to_owned()).into())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if configured.is_empty() {
+        Ok(vec![Refspec::default_for(remote)])
+    } else {
+        Ok(configured)
+    }
+}
+
+/// Fetches from the remote named `remote`, at `remote.<remote>.url` in the
+/// config
+#[cfg(not(target_arch = "wasm32"))]
+pub fn fetch(repo: &mut Repo, remote: &str, callbacks: &mut Callbacks) -> Result<Vec<RefUpdate>> {
+    let url = repo
+        .config()?
+        .get(&format!("remote.{}.url", remote))
+        .map(str::to_owned)
+        .ok_or_else(|| FetchError::NoUrl(remote.to_owned()))?;
+    let refspecs = refspecs_for(remote, repo)?;
+    let mut connection = crate::transport::connect(&url, "git-upload-pack", callbacks)?;
+    let updates = fetch_from(
+        repo,
+        &refspecs,
+        &mut connection.input,
+        &mut connection.output,
+        callbacks,
+    )?;
+    connection.close()?;
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fetch_from, Refspec, UpdateKind};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+    use crate::remote::Callbacks;
+    use crate::rev;
+    use crate::transport::pipe;
+    use crate::upload_pack::upload_pack;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn commit(repo: &Repo, content: &str, parents: Vec<Id>) -> Id {
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "file".into(),
+                id: repo.store(&Blob::new(content.as_bytes().to_vec())).unwrap(),
+            }],
+        };
+        repo.store(&Commit {
+            tree: repo.store(&tree).unwrap(),
+            parents,
+            author: who.clone(),
+            committer: who,
+            message: format!("{}\n", content),
+        })
+        .unwrap()
+    }
+
+    /// Fetches from `remote`, served by upload_pack on a thread
+    fn fetch(repo: &mut Repo, remote: Arc<Repo>) -> Vec<(String, UpdateKind)> {
+        let (to_server, mut server_input) = pipe();
+        let (mut server_output, mut from_server) = pipe();
+        let server = thread::spawn(move || {
+            upload_pack(&remote, &mut server_input, &mut server_output).unwrap();
+        });
+        let mut to_server = to_server;
+        let refspecs = vec![Refspec::default_for("origin")];
+        let updates = fetch_from(
+            repo,
+            &refspecs,
+            &mut from_server,
+            &mut to_server,
+            &mut Callbacks::new(),
+        )
+        .unwrap();
+        drop(to_server);
+        server.join().unwrap();
+        updates
+            .into_iter()
+            .map(|update| (update.local, update.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_refspec() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert_eq!(spec, Refspec::default_for("origin"));
+        assert_eq!(
+            spec.map("refs/heads/a/b"),
+            Some("refs/remotes/origin/a/b".to_owned())
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+
+        let spec = Refspec::parse("refs/heads/main:refs/heads/upstream").unwrap();
+        assert!(!spec.force);
+        assert_eq!(
+            spec.map("refs/heads/main"),
+            Some("refs/heads/upstream".to_owned())
+        );
+        assert_eq!(spec.map("refs/heads/mainline"), None);
+
+        assert_eq!(Refspec::parse("refs/heads/*"), None);
+        assert_eq!(Refspec::parse("refs/heads/*:refs/heads/main"), None);
+        assert_eq!(Refspec::parse("refs/*/*:refs/*/*"), None);
+    }
+
+    #[test]
+    fn test_fetch() {
+        let remote = Arc::new(Repo::in_memory());
+        let first = commit(&remote, "first", vec![]);
+        let second = commit(&remote, "second", vec![first]);
+        rev::update_ref(Path::new("refs/heads/master"), &second, "", &remote).unwrap();
+        rev::update_ref(Path::new("refs/heads/old"), &first, "", &remote).unwrap();
+
+        let mut repo = Repo::in_memory();
+        assert_eq!(
+            fetch(&mut repo, remote.clone()),
+            vec![
+                ("refs/remotes/origin/master".to_owned(), UpdateKind::New),
+                ("refs/remotes/origin/old".to_owned(), UpdateKind::New),
+            ]
+        );
+        assert_eq!(
+            rev::parse("refs/remotes/origin/master", &repo).unwrap(),
+            second
+        );
+        assert!(repo.open(&first).is_ok());
+
+        // only what's new comes over the second time, against what we have
+        let third = commit(&remote, "third", vec![second]);
+        let other = commit(&remote, "other", vec![]);
+        rev::update_ref(Path::new("refs/heads/master"), &third, "", &remote).unwrap();
+        rev::update_ref(Path::new("refs/heads/old"), &other, "", &remote).unwrap();
+        assert_eq!(
+            fetch(&mut repo, remote.clone()),
+            vec![
+                (
+                    "refs/remotes/origin/master".to_owned(),
+                    UpdateKind::FastForward
+                ),
+                ("refs/remotes/origin/old".to_owned(), UpdateKind::Forced),
+            ]
+        );
+        assert_eq!(rev::parse("refs/remotes/origin/old", &repo).unwrap(), other);
+        assert!(repo.open(&third).is_ok());
+
+        assert_eq!(
+            fetch(&mut repo, remote),
+            vec![
+                (
+                    "refs/remotes/origin/master".to_owned(),
+                    UpdateKind::UpToDate
+                ),
+                ("refs/remotes/origin/old".to_owned(), UpdateKind::UpToDate),
+            ]
+        );
+    }
+}
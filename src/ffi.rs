@@ -0,0 +1,252 @@
+//! A small C ABI over the library, so programs in other languages can embed
+//! rgit. The header is generated by cbindgen into `include/rgit.h` when
+//! building with the `ffi` feature.
+//!
+//! Functions returning `int` return 0 on success and -1 on failure, in which
+//! case `rgit_last_error` describes what went wrong. Everything handed out by
+//! this library must be released with the matching `rgit_*_free` function.
+#![allow(non_camel_case_types)]
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::ptr;
+use std::slice;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::objects::{Id, ObjectType, Repo};
+use crate::rev;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+/// An open repository
+pub struct rgit_repo {
+    repo: Repo,
+}
+
+/// A binary object ID
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct rgit_oid {
+    /// Raw SHA-1 of the object
+    pub id: [u8; 20],
+}
+
+/// A ref and the object it points to
+#[repr(C)]
+pub struct rgit_ref {
+    /// Full name of the ref, such as refs/heads/master
+    pub name: *mut c_char,
+    /// Object the ref points to
+    pub oid: rgit_oid,
+}
+
+/// Object type of a commit
+pub const RGIT_OBJECT_COMMIT: c_int = 1;
+/// Object type of a tree
+pub const RGIT_OBJECT_TREE: c_int = 2;
+/// Object type of a blob
+pub const RGIT_OBJECT_BLOB: c_int = 3;
+/// Object type of an annotated tag
+pub const RGIT_OBJECT_TAG: c_int = 4;
+
+impl From<Id> for rgit_oid {
+    fn from(id: Id) -> rgit_oid {
+        rgit_oid { id: *id.as_bytes() }
+    }
+}
+
+fn set_last_error(err: anyhow::Error) {
+    let msg = format!("{:#}", err).replace('\0', "");
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(msg).ok());
+}
+
+/// Runs `f`, stashing any error or panic for `rgit_last_error` since neither
+/// may cross the FFI boundary
+fn ffi_try<T>(f: impl FnOnce() -> Result<T>) -> Option<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(e)) => {
+            set_last_error(e);
+            None
+        }
+        Err(_) => {
+            set_last_error(anyhow!("rgit panicked"));
+            None
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char, what: &str) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(anyhow!("{} is null", what));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .with_context(|| format!("{} is not UTF-8", what))
+}
+
+unsafe fn repo_arg<'a>(repo: *const rgit_repo) -> Result<&'a Repo> {
+    repo.as_ref()
+        .map(|r| &r.repo)
+        .ok_or_else(|| anyhow!("repo is null"))
+}
+
+/// Gets a description of the last error on this thread, or NULL if there was
+/// none. The string is owned by the library and is valid until the next call
+/// into it.
+#[no_mangle]
+pub extern "C" fn rgit_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match &*e.borrow() {
+        Some(msg) => msg.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Opens the repository containing `path`. Returns NULL on failure.
+///
+/// # Safety
+/// `path` must be a valid null-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_repo_open(path: *const c_char) -> *mut rgit_repo {
+    ffi_try(|| {
+        let path = str_arg(path, "path")?;
+        let repo = Repo::discover(Path::new(path))
+            .with_context(|| format!("no repository found at {}", path))?;
+        Ok(Box::into_raw(Box::new(rgit_repo { repo })))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Closes a repository opened with `rgit_repo_open`.
+///
+/// # Safety
+/// `repo` must have come from `rgit_repo_open` and not been freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_repo_free(repo: *mut rgit_repo) {
+    if !repo.is_null() {
+        drop(Box::from_raw(repo));
+    }
+}
+
+/// Resolves a revision such as `HEAD` or an abbreviated hash to an object ID.
+///
+/// # Safety
+/// `repo` must be an open repository, `rev` a valid null-terminated string and
+/// `out` valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_revparse(
+    repo: *const rgit_repo,
+    rev: *const c_char,
+    out: *mut rgit_oid,
+) -> c_int {
+    ffi_try(|| {
+        let repo = repo_arg(repo)?;
+        let rev = str_arg(rev, "rev")?;
+        let out = out.as_mut().context("out is null")?;
+        *out = rev::parse(rev, repo)?.into();
+        Ok(())
+    })
+    .map_or(-1, |_| 0)
+}
+
+/// Reads the content of an object. On success, `*type_out` is one of the
+/// `RGIT_OBJECT_*` constants and `*data_out`/`*len_out` hold the content,
+/// which must be released with `rgit_buf_free`.
+///
+/// # Safety
+/// `repo` must be an open repository, `oid` valid for reads and the out
+/// pointers valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_object_read(
+    repo: *const rgit_repo,
+    oid: *const rgit_oid,
+    type_out: *mut c_int,
+    data_out: *mut *mut u8,
+    len_out: *mut usize,
+) -> c_int {
+    ffi_try(|| {
+        let repo = repo_arg(repo)?;
+        let oid = oid.as_ref().context("oid is null")?;
+        if type_out.is_null() || data_out.is_null() || len_out.is_null() {
+            return Err(anyhow!("output pointer is null"));
+        }
+
+        let (objtype, content) = repo.read_object(&Id::from_bytes(oid.id))?;
+        let content = content.into_boxed_slice();
+        *type_out = match objtype {
+            ObjectType::Commit => RGIT_OBJECT_COMMIT,
+            ObjectType::Tree => RGIT_OBJECT_TREE,
+            ObjectType::Blob => RGIT_OBJECT_BLOB,
+            ObjectType::Tag => RGIT_OBJECT_TAG,
+        };
+        *len_out = content.len();
+        *data_out = Box::into_raw(content) as *mut u8;
+        Ok(())
+    })
+    .map_or(-1, |_| 0)
+}
+
+/// Releases a buffer returned by `rgit_object_read`.
+///
+/// # Safety
+/// `data` and `len` must be exactly as returned by `rgit_object_read`.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_buf_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Lists the refs in the repository, sorted by name. The list must be
+/// released with `rgit_refs_free`.
+///
+/// # Safety
+/// `repo` must be an open repository and the out pointers valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_refs_list(
+    repo: *const rgit_repo,
+    refs_out: *mut *mut rgit_ref,
+    count_out: *mut usize,
+) -> c_int {
+    ffi_try(|| {
+        let repo = repo_arg(repo)?;
+        if refs_out.is_null() || count_out.is_null() {
+            return Err(anyhow!("output pointer is null"));
+        }
+
+        let refs = rev::list_refs(repo)?
+            .into_iter()
+            .map(|(name, id)| {
+                Ok(rgit_ref {
+                    name: CString::new(name)?.into_raw(),
+                    oid: id.into(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_boxed_slice();
+        *count_out = refs.len();
+        *refs_out = Box::into_raw(refs) as *mut rgit_ref;
+        Ok(())
+    })
+    .map_or(-1, |_| 0)
+}
+
+/// Releases a list returned by `rgit_refs_list`.
+///
+/// # Safety
+/// `refs` and `count` must be exactly as returned by `rgit_refs_list`.
+#[no_mangle]
+pub unsafe extern "C" fn rgit_refs_free(refs: *mut rgit_ref, count: usize) {
+    if refs.is_null() {
+        return;
+    }
+    let refs = Box::from_raw(slice::from_raw_parts_mut(refs, count));
+    for r in refs.iter() {
+        drop(CString::from_raw(r.name));
+    }
+}
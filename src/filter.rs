@@ -0,0 +1,345 @@
+//! Rewriting history in the spirit of git-filter-repo. The tree of every
+//! commit is passed through some path filters, and the commits are made again
+//! on top of their rewritten parents.
+//!
+//! Commits that only touched paths that were filtered away are left out, as
+//! they would be empty. Commits that come out the same as they went in keep
+//! their IDs.
+use anyhow::{anyhow, Result};
+use bstr::{BString, ByteVec};
+use std::collections::HashMap;
+use thiserror::Error;
+
+use crate::objects::{Commit, FileMode, Id, Object, Repo, Tree};
+use crate::tree::{self, SubTree, TreeEntry};
+
+/// Errors that can arise while rewriting history
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum FilterError {
+    /// Renaming put a file where a directory is, or the other way around
+    #[error("Filtered paths collide at {0}")]
+    Collision(BString),
+}
+
+/// Something to do to the paths of every commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathFilter {
+    /// Drop a file or directory
+    Remove(BString),
+    /// Move a file or directory to another path
+    Rename(BString, BString),
+    /// Drop files bigger than this many bytes
+    MaxBlobSize(u64),
+}
+
+/// If `path` is `dir` or something in it, the rest of the path after `dir`
+fn under<'a>(path: &'a [u8], dir: &[u8]) -> Option<&'a [u8]> {
+    if !path.starts_with(dir) {
+        return None;
+    }
+    let rest = &path[dir.len()..];
+    if rest.is_empty() || rest[0] == b'/' {
+        Some(rest)
+    } else {
+        None
+    }
+}
+
+/// Parses a size such as `500`, `10k`, `5M` or `1G`
+pub fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => size.split_at(pos),
+        None => (size, ""),
+    };
+    let multiplier = match unit.to_ascii_lowercase().as_str() {
+        "" => 1,
+        "k" => 1024,
+        "m" => 1024 * 1024,
+        "g" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Rewrites commits, remembering what each became
+pub struct Rewriter<'a> {
+    repo: &'a Repo,
+    filters: &'a [PathFilter],
+    /// new IDs of rewritten commits, or None for root commits that were left
+    /// out
+    commits: HashMap<Id, Option<Id>>,
+    /// rewritten root trees
+    trees: HashMap<Id, Id>,
+    /// blob sizes, for MaxBlobSize
+    sizes: HashMap<Id, u64>,
+    empty_tree: Option<Id>,
+}
+
+impl<'a> Rewriter<'a> {
+    /// Prepares to rewrite commits in `repo` with `filters`, which are applied
+    /// to each path in order
+    pub fn new(repo: &'a Repo, filters: &'a [PathFilter]) -> Rewriter<'a> {
+        Rewriter {
+            repo,
+            filters,
+            commits: HashMap::new(),
+            trees: HashMap::new(),
+            sizes: HashMap::new(),
+            empty_tree: None,
+        }
+    }
+
+    /// The commits rewritten so far, with what they became. Commits that were
+    /// left out become their rewritten parent, or None if they had none.
+    pub fn commit_map(&self) -> &HashMap<Id, Option<Id>> {
+        &self.commits
+    }
+
+    fn blob_size(&mut self, id: &Id) -> Result<u64> {
+        if let Some(&size) = self.sizes.get(id) {
+            return Ok(size);
+        }
+        let (_, size) = self.repo.object_header(id)?;
+        self.sizes.insert(*id, size);
+        Ok(size)
+    }
+
+    /// Where a file ends up, if anywhere
+    fn filter_path(&mut self, path: BString, id: &Id, mode: FileMode) -> Result<Option<BString>> {
+        let mut path = path;
+        for filter in self.filters {
+            match filter {
+                PathFilter::Remove(dir) => {
+                    if under(&path, dir).is_some() {
+                        return Ok(None);
+                    }
+                }
+                PathFilter::Rename(from, to) => {
+                    if let Some(rest) = under(&path, from) {
+                        let mut renamed = to.clone();
+                        renamed.push_str(rest);
+                        path = renamed;
+                    }
+                }
+                PathFilter::MaxBlobSize(max) => {
+                    // submodule commits aren't in this repo to be measured
+                    if mode != FileMode::Gitlink && self.blob_size(id)? > *max {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        Ok(Some(path))
+    }
+
+    /// Rewrites a root tree
+    fn rewrite_tree(&mut self, id: &Id) -> Result<Id> {
+        if let Some(&new) = self.trees.get(id) {
+            return Ok(new);
+        }
+        let tree = match self.repo.open(id)? {
+            Object::Tree(tree) => tree,
+            _ => return Err(anyhow!("{} is not a tree", id)),
+        };
+        let mut files = Vec::new();
+        tree::load_tree_from_disk(&tree, self.repo, b"", &mut files)?;
+
+        let mut root = SubTree::new();
+        for (path, id, mode) in files {
+            if let Some(path) = self.filter_path(path, &id, mode)? {
+                if !tree::insert_path(&mut root, &path, TreeEntry::Blob(id, mode)) {
+                    return Err(FilterError::Collision(path).into());
+                }
+            }
+        }
+        let new = tree::save_subtree(&mut TreeEntry::SubTree(root), self.repo)?;
+        self.trees.insert(*id, new);
+        Ok(new)
+    }
+
+    fn open_commit(&self, id: &Id) -> Result<Commit> {
+        self.repo
+            .open(id)?
+            .commit()
+            .ok_or_else(|| anyhow!("{} is not a commit", id))
+    }
+
+    /// The tree of a commit, or the empty tree for no commit
+    fn tree_of(&mut self, commit: Option<&Id>) -> Result<Id> {
+        match commit {
+            Some(id) => Ok(self.open_commit(id)?.tree),
+            None => match self.empty_tree {
+                Some(id) => Ok(id),
+                None => {
+                    let id = self.repo.store(&Tree { files: Vec::new() })?;
+                    self.empty_tree = Some(id);
+                    Ok(id)
+                }
+            },
+        }
+    }
+
+    /// Rewrites one commit whose parents have all been rewritten already
+    fn rewrite_one(&mut self, id: &Id) -> Result<Option<Id>> {
+        let commit = self.open_commit(id)?;
+        let mut parents: Vec<Id> = Vec::new();
+        for parent in &commit.parents {
+            if let Some(new) = self.commits[parent] {
+                if !parents.contains(&new) {
+                    parents.push(new);
+                }
+            }
+        }
+        let tree = self.rewrite_tree(&commit.tree)?;
+
+        // leave out commits that have become empty, but not merges or
+        // commits that were empty to begin with
+        if parents.len() <= 1 && commit.parents.len() <= 1 {
+            let was_empty = commit.tree == self.tree_of(commit.parents.first())?;
+            if !was_empty && tree == self.tree_of(parents.first())? {
+                return Ok(parents.first().copied());
+            }
+        }
+
+        if tree == commit.tree && parents == commit.parents {
+            return Ok(Some(*id));
+        }
+        let new = self.repo.store(&Commit {
+            tree,
+            parents,
+            ..commit
+        })?;
+        Ok(Some(new))
+    }
+
+    /// Rewrites a commit and all of its history, returning what it became
+    pub fn rewrite(&mut self, id: &Id) -> Result<Option<Id>> {
+        // parents have to be done before their children, so this goes depth
+        // first without recursing, since history can be very deep
+        let mut stack = vec![*id];
+        while let Some(&top) = stack.last() {
+            if self.commits.contains_key(&top) {
+                stack.pop();
+                continue;
+            }
+            let pending: Vec<Id> = self
+                .open_commit(&top)?
+                .parents
+                .into_iter()
+                .filter(|p| !self.commits.contains_key(p))
+                .collect();
+            if pending.is_empty() {
+                let new = self.rewrite_one(&top)?;
+                self.commits.insert(top, new);
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+        Ok(self.commits[id])
+    }
+}
+
+/// Formats a commit map as filter-repo does, with a header and one
+/// `old new` line per commit. Commits that were left out map to all zeroes.
+pub fn format_commit_map(map: &HashMap<Id, Option<Id>>) -> String {
+    let zero = Id::from_bytes([0; 20]);
+    let mut lines: Vec<String> = map
+        .iter()
+        .map(|(old, new)| format!("{} {}\n", old, new.unwrap_or(zero)))
+        .collect();
+    lines.sort();
+    let mut out = format!("{:<40} {}\n", "old", "new");
+    for line in lines {
+        out.push_str(&line);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_size, PathFilter, Rewriter};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+    use crate::tree::changed_files;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("500"), Some(500));
+        assert_eq!(parse_size("10k"), Some(10 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("1x"), None);
+        assert_eq!(parse_size("M"), None);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+
+        let blob = |content: &[u8]| repo.store(&Blob::new(content.to_vec())).unwrap();
+        let tree = |files: Vec<(&str, Id)>| {
+            let files = files
+                .into_iter()
+                .map(|(name, id)| File {
+                    mode: if name == "dir" {
+                        FileMode::Directory
+                    } else {
+                        FileMode::Regular
+                    },
+                    name: name.into(),
+                    id,
+                })
+                .collect();
+            repo.store(&Tree { files }).unwrap()
+        };
+        let commit = |tree: Id, parents: Vec<Id>| {
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who.clone(),
+                message: "commit\n".to_string(),
+            })
+            .unwrap()
+        };
+
+        let small = blob(b"small\n");
+        let big = blob(&[b'x'; 100]);
+        let secret = tree(vec![("key", blob(b"hunter2\n"))]);
+        let first = commit(tree(vec![("a", small)]), vec![]);
+        // only touches the directory that gets removed
+        let second = commit(tree(vec![("a", small), ("dir", secret)]), vec![first]);
+        let third = commit(
+            tree(vec![("a", small), ("big", big), ("dir", secret)]),
+            vec![second],
+        );
+
+        let filters = [
+            PathFilter::Remove("dir".into()),
+            PathFilter::Rename("a".into(), "docs/a".into()),
+            PathFilter::MaxBlobSize(50),
+        ];
+        let mut rewriter = Rewriter::new(&repo, &filters);
+        let new_third = rewriter.rewrite(&third).unwrap().unwrap();
+        let map = rewriter.commit_map();
+        assert_eq!(map.len(), 3);
+        let new_first = map[&first].unwrap();
+        assert_ne!(new_first, first);
+        // both later commits are empty now
+        assert_eq!(map[&second], Some(new_first));
+        assert_eq!(new_third, new_first);
+
+        let new_tree = repo.open(&new_first).unwrap().commit().unwrap().tree;
+        let files: Vec<_> = changed_files(None, Some(&new_tree), &repo)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.path)
+            .collect();
+        assert_eq!(files, vec!["docs/a"]);
+
+        // nothing to do keeps the same IDs
+        let mut rewriter = Rewriter::new(&repo, &[]);
+        assert_eq!(rewriter.rewrite(&third).unwrap(), Some(third));
+    }
+}
@@ -0,0 +1,516 @@
+//! Checking a repository for corruption, as `fsck` does. Every object is
+//! read back and hashed again to check its ID, trees, commits and tags are
+//! checked for structure, and everything they point to has to exist and be of
+//! the right type. Objects that nothing reachable points to are reported too:
+//! those that no other object points to either are dangling, as they are the
+//! tips of what could be recovered.
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::str;
+use thiserror::Error;
+
+use crate::maintenance::{self, Root};
+use crate::objects::{Id, Object, ObjectType, Repo};
+
+/// Something wrong with the repository
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// An object's content doesn't hash to its ID
+    #[error("hash mismatch for {id}: its content hashes to {actual}")]
+    HashMismatch {
+        /// The ID the object is stored under
+        id: Id,
+        /// The ID of what it holds
+        actual: Id,
+    },
+
+    /// An object couldn't be read at all
+    #[error("failed to read {id}: {message}")]
+    Unreadable {
+        /// The object
+        id: Id,
+        /// What went wrong
+        message: String,
+    },
+
+    /// A tree, commit or tag isn't laid out as it should be
+    #[error("error in {kind} {id}: {message}")]
+    Malformed {
+        /// The object
+        id: Id,
+        /// What kind of object it is
+        kind: ObjectType,
+        /// What is wrong with it
+        message: &'static str,
+    },
+
+    /// An object points to one that doesn't exist
+    #[error("broken link from {from_kind} {from} to {kind} {to}")]
+    BrokenLink {
+        /// The object doing the pointing
+        from: Id,
+        /// What kind of object that is
+        from_kind: ObjectType,
+        /// The missing object
+        to: Id,
+        /// What kind of object it should be
+        kind: ObjectType,
+    },
+
+    /// An object points to one that is of another type than it says
+    #[error("{from_kind} {from} points to {to} as a {expected}, but it is a {actual}")]
+    WrongType {
+        /// The object doing the pointing
+        from: Id,
+        /// What kind of object that is
+        from_kind: ObjectType,
+        /// The object pointed to
+        to: Id,
+        /// What it should be
+        expected: ObjectType,
+        /// What it is
+        actual: ObjectType,
+    },
+
+    /// A ref or the index points to an object that doesn't exist
+    #[error("{root}: invalid object {id}")]
+    MissingRoot {
+        /// What is doing the pointing
+        root: Root,
+        /// The missing object
+        id: Id,
+    },
+}
+
+/// What `fsck` found
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Everything wrong, in the order it was found
+    pub problems: Vec<Problem>,
+    /// Objects that can't be reached from the refs, HEAD, the reflogs or the
+    /// index, in ID order
+    pub unreachable: Vec<(Id, ObjectType)>,
+    /// The unreachable objects that no other object points to
+    pub dangling: Vec<(Id, ObjectType)>,
+}
+
+/// What an object points to, and what type each of those should be
+type Links = Vec<(Id, ObjectType)>;
+
+/// Returns the rest of a header line if it starts with `key`
+fn field<'a>(line: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    if line.starts_with(key) {
+        Some(&line[key.len()..])
+    } else {
+        None
+    }
+}
+
+/// Parses the hex ID a header line ends with
+fn line_id(line: &[u8]) -> Option<Id> {
+    Id::from(str::from_utf8(line).ok()?)
+}
+
+/// Checks a `<name> <<email>> <time> <zone>` line of a commit or tag
+fn valid_ident(ident: &[u8]) -> bool {
+    let ident = match str::from_utf8(ident) {
+        Ok(ident) => ident,
+        Err(_) => return true,
+    };
+    let close = match ident.rfind('>') {
+        Some(close) => close,
+        None => return false,
+    };
+    if !ident[..close].contains('<') {
+        return false;
+    }
+    let mut when = ident[close + 1..].split_whitespace();
+    let time_ok = when.next().map_or(false, |t| {
+        !t.is_empty() && t.bytes().all(|b| b.is_ascii_digit())
+    });
+    let zone_ok = when.next().map_or(false, |z| {
+        z.len() == 5
+            && (z.starts_with('+') || z.starts_with('-'))
+            && z[1..].bytes().all(|b| b.is_ascii_digit())
+    });
+    time_ok && zone_ok && when.next().is_none()
+}
+
+/// Checks a tree, returning what it points to
+fn check_tree(content: &[u8]) -> Result<Links, &'static str> {
+    let mut links = Vec::new();
+    let mut rest = content;
+    let mut last: Option<(&[u8], Vec<u8>)> = None;
+    while !rest.is_empty() {
+        let space = rest
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or("entry has no mode")?;
+        let nul = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("entry has no name")?;
+        if nul < space || rest.len() < nul + 21 {
+            return Err("entry is truncated");
+        }
+        let (mode, name) = (&rest[..space], &rest[space + 1..nul]);
+        let id = Id::from_bytes(rest[nul + 1..nul + 21].try_into().unwrap());
+        rest = &rest[nul + 21..];
+
+        let kind = match mode {
+            b"100644" | b"100755" | b"120000" | b"100664" => Some(ObjectType::Blob),
+            b"40000" => Some(ObjectType::Tree),
+            // submodule commits live in another repository
+            b"160000" => None,
+            _ => return Err("entry has a bad mode"),
+        };
+        if name.is_empty() {
+            return Err("entry has an empty name");
+        }
+        if name.contains(&b'/') {
+            return Err("entry has a slash in its name");
+        }
+        if name == b"." || name == b".." || name.eq_ignore_ascii_case(b".git") {
+            return Err("entry has a name that isn't allowed");
+        }
+
+        // trees sort as though directories end in a slash
+        let mut key = name.to_vec();
+        if kind == Some(ObjectType::Tree) {
+            key.push(b'/');
+        }
+        if let Some((last_name, last_key)) = &last {
+            if *last_name == name {
+                return Err("has duplicate entries");
+            }
+            if last_key[..] > key[..] {
+                return Err("is not sorted");
+            }
+        }
+        last = Some((name, key));
+        if let Some(kind) = kind {
+            links.push((id, kind));
+        }
+    }
+    Ok(links)
+}
+
+/// Checks a commit, returning what it points to
+fn check_commit(content: &[u8]) -> Result<Links, &'static str> {
+    let header_end = content
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map_or(content.len(), |n| n + 1);
+    let mut lines = content[..header_end].split(|&b| b == b'\n');
+    let mut links = Vec::new();
+
+    let tree = lines
+        .next()
+        .and_then(|line| field(line, b"tree "))
+        .ok_or("doesn't start with a tree")?;
+    links.push((
+        line_id(tree).ok_or("has a bad tree line")?,
+        ObjectType::Tree,
+    ));
+    let mut line = lines.next().ok_or("has no author")?;
+    while let Some(parent) = field(line, b"parent ") {
+        links.push((
+            line_id(parent).ok_or("has a bad parent line")?,
+            ObjectType::Commit,
+        ));
+        line = lines.next().ok_or("has no author")?;
+    }
+    let author = field(line, b"author ").ok_or("has no author")?;
+    if !valid_ident(author) {
+        return Err("has a bad author line");
+    }
+    let committer = lines
+        .next()
+        .and_then(|line| field(line, b"committer "))
+        .ok_or("has no committer")?;
+    if !valid_ident(committer) {
+        return Err("has a bad committer line");
+    }
+    Ok(links)
+}
+
+/// Checks a tag, returning what it points to
+fn check_tag(content: &[u8]) -> Result<Links, &'static str> {
+    let mut lines = content.split(|&b| b == b'\n');
+    let object = lines
+        .next()
+        .and_then(|line| field(line, b"object "))
+        .and_then(line_id)
+        .ok_or("doesn't start with an object")?;
+    let kind = lines
+        .next()
+        .and_then(|line| field(line, b"type "))
+        .and_then(ObjectType::from_tag)
+        .ok_or("has a bad type line")?;
+    let name = lines
+        .next()
+        .and_then(|line| field(line, b"tag "))
+        .ok_or("has no tag name")?;
+    if name.is_empty() {
+        return Err("has an empty tag name");
+    }
+    if let Some(tagger) = lines.next().and_then(|line| field(line, b"tagger ")) {
+        if !valid_ident(tagger) {
+            return Err("has a bad tagger line");
+        }
+    }
+    Ok(vec![(object, kind)])
+}
+
+/// Checks every object in the repository, and finds which are unreachable
+pub fn fsck(repo: &Repo) -> Result<Report> {
+    let mut report = Report::default();
+    let ids = repo.all_ids()?;
+    let mut kinds: HashMap<Id, ObjectType> = HashMap::with_capacity(ids.len());
+    let mut links: HashMap<Id, Links> = HashMap::new();
+    for id in ids.iter() {
+        let (kind, content) = match repo.read_object(id) {
+            Ok(object) => object,
+            Err(e) => {
+                report.problems.push(Problem::Unreadable {
+                    id: *id,
+                    message: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        let actual = Object::hash_raw(kind, &content)?;
+        if actual != *id {
+            report
+                .problems
+                .push(Problem::HashMismatch { id: *id, actual });
+            continue;
+        }
+        kinds.insert(*id, kind);
+
+        let checked = match kind {
+            ObjectType::Blob => continue,
+            ObjectType::Tree => check_tree(&content),
+            ObjectType::Commit => check_commit(&content),
+            ObjectType::Tag => check_tag(&content),
+        };
+        match checked {
+            Ok(to) => {
+                links.insert(*id, to);
+            }
+            Err(message) => report.problems.push(Problem::Malformed {
+                id: *id,
+                kind,
+                message,
+            }),
+        }
+    }
+
+    // every link has to go to an object of the right type
+    let mut referenced = HashSet::new();
+    for id in ids.iter() {
+        for &(to, expected) in links.get(id).map_or(&[][..], |to| &to[..]) {
+            referenced.insert(to);
+            match kinds.get(&to) {
+                None => report.problems.push(Problem::BrokenLink {
+                    from: *id,
+                    from_kind: kinds[id],
+                    to,
+                    kind: expected,
+                }),
+                Some(&actual) if actual != expected => report.problems.push(Problem::WrongType {
+                    from: *id,
+                    from_kind: kinds[id],
+                    to,
+                    expected,
+                    actual,
+                }),
+                Some(_) => {}
+            }
+        }
+    }
+
+    let mut reachable = HashSet::new();
+    let mut stack = Vec::new();
+    for (root, id) in maintenance::roots(repo)? {
+        if kinds.contains_key(&id) {
+            stack.push(id);
+        } else if let Root::Reflog(_) = root {
+            // pruning leaves reflogs naming objects that are gone
+        } else {
+            report.problems.push(Problem::MissingRoot { root, id });
+        }
+    }
+    while let Some(id) = stack.pop() {
+        if !reachable.insert(id) {
+            continue;
+        }
+        if let Some(to) = links.get(&id) {
+            stack.extend(
+                to.iter()
+                    .map(|(to, _)| *to)
+                    .filter(|to| kinds.contains_key(to)),
+            );
+        }
+    }
+
+    for id in ids.iter() {
+        let kind = match kinds.get(id) {
+            Some(&kind) => kind,
+            None => continue,
+        };
+        if !reachable.contains(id) {
+            report.unreachable.push((*id, kind));
+            if !referenced.contains(id) {
+                report.dangling.push((*id, kind));
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_commit, check_tag, check_tree, fsck, Problem};
+    use crate::maintenance::Root;
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, ObjectType, Repo, Tree};
+    use crate::rev;
+    use crate::vfs::{MemoryFs, Vfs};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Encodes a tree entry
+    fn entry(mode: &str, name: &str, id: &Id) -> Vec<u8> {
+        let mut out = format!("{} {}\0", mode, name).into_bytes();
+        out.extend(id.as_bytes());
+        out
+    }
+
+    #[test]
+    fn test_check_objects() {
+        let id = Id::from("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let tree = [entry("100644", "a", &id), entry("40000", "b", &id)].concat();
+        assert_eq!(
+            check_tree(&tree),
+            Ok(vec![(id, ObjectType::Blob), (id, ObjectType::Tree)])
+        );
+        // "a-" sorts before "a/"
+        let tree = [entry("40000", "a", &id), entry("100644", "a-", &id)].concat();
+        assert_eq!(check_tree(&tree), Err("is not sorted"));
+        let tree = [entry("100644", "a", &id), entry("40000", "a", &id)].concat();
+        assert_eq!(check_tree(&tree), Err("has duplicate entries"));
+        assert!(check_tree(&entry("100000", "a", &id)).is_err());
+        assert!(check_tree(&entry("100644", "..", &id)).is_err());
+        assert!(check_tree(&entry("100644", "a", &id)[..10]).is_err());
+        // submodules aren't followed
+        assert_eq!(check_tree(&entry("160000", "sub", &id)), Ok(vec![]));
+
+        let commit = format!(
+            "tree {0}\nparent {0}\nauthor a <a@example.com> 1586391037 -0700\n\
+             committer a <a@example.com> 1586391037 -0700\n\nmessage\n",
+            id
+        );
+        assert_eq!(
+            check_commit(commit.as_bytes()),
+            Ok(vec![(id, ObjectType::Tree), (id, ObjectType::Commit)])
+        );
+        let no_committer = commit.replace("committer", "commiter");
+        assert_eq!(
+            check_commit(no_committer.as_bytes()),
+            Err("has no committer")
+        );
+        let bad_date = commit.replace("1586391037 -0700\n\n", "yesterday\n\n");
+        assert_eq!(
+            check_commit(bad_date.as_bytes()),
+            Err("has a bad committer line")
+        );
+
+        let tag = format!(
+            "object {}\ntype commit\ntag v1\ntagger a <a@example.com> 1586391037 -0700\n\nv1\n",
+            id
+        );
+        assert_eq!(
+            check_tag(tag.as_bytes()),
+            Ok(vec![(id, ObjectType::Commit)])
+        );
+        assert!(check_tag(tag.replace("type commit", "type car").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_fsck() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let blob = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let missing = Id::from("deadbeefdeadbeefdeadbeefdeadbeefdeadbeef").unwrap();
+        let tree = repo
+            .store(&Tree {
+                files: vec![
+                    File {
+                        mode: FileMode::Regular,
+                        name: "hello".into(),
+                        id: blob,
+                    },
+                    File {
+                        mode: FileMode::Regular,
+                        name: "missing".into(),
+                        id: missing,
+                    },
+                ],
+            })
+            .unwrap();
+        let commit = |tree, parents, message: &str| {
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who.clone(),
+                message: message.to_string(),
+            })
+            .unwrap()
+        };
+        let head = commit(tree, vec![], "head\n");
+        rev::update_ref(Path::new("refs/heads/master"), &head, "", &repo).unwrap();
+        // an unreachable commit and the tree only it has
+        let lost_tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let lost = commit(lost_tree, vec![head], "lost\n");
+        // a corrupt blob: the content of another stored under this ID
+        let corrupt = Id::from("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let mut squisher = ZlibEncoder::new(Vec::new(), Compression::default());
+        squisher.write_all(b"blob 4\0oops").unwrap();
+        fs.create_dir_all(repo.path_for_object(&corrupt).parent().unwrap())
+            .unwrap();
+        fs.write(&repo.path_for_object(&corrupt), &squisher.finish().unwrap())
+            .unwrap();
+        rev::update_ref(Path::new("refs/heads/gone"), &missing, "", &repo).unwrap();
+
+        let report = fsck(&repo).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                Problem::HashMismatch {
+                    id: corrupt,
+                    actual: Id::from("17843c7c94da88f77a6cb6cab21d6d48d9130cb5").unwrap(),
+                },
+                Problem::BrokenLink {
+                    from: tree,
+                    from_kind: ObjectType::Tree,
+                    to: missing,
+                    kind: ObjectType::Blob,
+                },
+                Problem::MissingRoot {
+                    root: Root::Ref("refs/heads/gone".into()),
+                    id: missing,
+                },
+            ]
+        );
+        let mut unreachable = vec![(lost, ObjectType::Commit), (lost_tree, ObjectType::Tree)];
+        unreachable.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        assert_eq!(report.unreachable, unreachable);
+        assert_eq!(report.dangling, vec![(lost, ObjectType::Commit)]);
+    }
+}
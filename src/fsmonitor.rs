@@ -0,0 +1,201 @@
+//! Asking a filesystem monitor which files have changed, so commands that
+//! compare the working tree against the index can skip stat calls on files
+//! nobody has touched. This speaks git's fsmonitor hook protocol, which is
+//! what `core.fsmonitor` pointing at a hook such as the stock
+//! `fsmonitor-watchman` script uses to query Watchman.
+//!
+//! Git keeps the monitor's token in an index extension. Until we write index
+//! extensions, the token lives in `.git/rgit-fsmonitor` along with the paths
+//! that were still modified when it was saved, since those stay modified even
+//! if the monitor doesn't mention them again.
+use anyhow::{anyhow, Context, Result};
+use bstr::{BString, ByteSlice};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::objects::Repo;
+use crate::trace;
+
+const STATE_FILE: &str = "rgit-fsmonitor";
+
+/// The result of asking the filesystem monitor what changed
+pub struct Fsmonitor {
+    /// None if the monitor couldn't tell us, so everything must be checked
+    changed: Option<HashSet<BString>>,
+    /// token to ask the monitor with next time
+    token: String,
+    state_path: PathBuf,
+}
+
+/// Nanoseconds since the epoch, which the monitor accepts as a token when
+/// there isn't one from a previous query
+fn now_token() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_nanos().to_string()
+}
+
+/// Splits the NUL separated output of the hook into paths. A path of "/" means
+/// the monitor lost track and everything has to be checked.
+fn parse_paths<'a>(paths: impl Iterator<Item = &'a [u8]>) -> Option<HashSet<BString>> {
+    let mut changed = HashSet::new();
+    for path in paths {
+        if path.is_empty() {
+            continue;
+        }
+        if path == b"/" {
+            return None;
+        }
+        // directories are reported with a trailing slash
+        let len = path.len() - path.iter().rev().take_while(|&&b| b == b'/').count();
+        changed.insert(path[..len].into());
+    }
+    Some(changed)
+}
+
+impl Fsmonitor {
+    /// Queries the monitor configured in `core.fsmonitor`. Returns None if
+    /// there isn't one.
+    pub fn query(repo: &Repo, config: &Config) -> Result<Option<Fsmonitor>> {
+        let hook = match config.get("core.fsmonitor") {
+            Some(hook) => hook,
+            None => return Ok(None),
+        };
+        // anything that isn't a boolean is the path to a hook
+        match config.get_bool("core.fsmonitor") {
+            Ok(Some(false)) => return Ok(None),
+            Ok(Some(true)) => {
+                warn!("the builtin fsmonitor daemon is not supported; checking every file");
+                return Ok(None);
+            }
+            _ => (),
+        }
+        // hooks given as relative paths are relative to the worktree
+        let hook_path = if hook.contains('/') {
+            repo.tree_root().join(hook)
+        } else {
+            PathBuf::from(hook)
+        };
+        let version = config.get_int("core.fsmonitorhookversion")?.unwrap_or(2);
+
+        let state_path = repo.root.join(STATE_FILE);
+        let (last_token, dirty) = match repo.vfs().read(&state_path) {
+            Ok(state) => {
+                let mut parts = state.split(|&b| b == 0);
+                let token = String::from_utf8_lossy(parts.next().unwrap_or_default());
+                (Some(token.into_owned()), parse_paths(parts))
+            }
+            Err(_) => (None, None),
+        };
+
+        let _region = trace::region("fsmonitor", "query");
+        let query_time = now_token();
+        let token_arg = last_token.clone().unwrap_or_else(|| query_time.clone());
+        trace::message(format_args!(
+            "run_command: {} {} {}",
+            hook, version, token_arg
+        ));
+        let output = Command::new(&hook_path)
+            .arg(version.to_string())
+            .arg(&token_arg)
+            .current_dir(repo.tree_root())
+            .output()
+            .with_context(|| format!("failed to run fsmonitor hook {}", hook))?;
+        if !output.status.success() {
+            return Err(anyhow!("fsmonitor hook {} failed", hook));
+        }
+
+        let (token, changed) = match version {
+            // version 1 takes a timestamp and prints paths
+            1 => (query_time, parse_paths(output.stdout.split(|&b| b == 0))),
+            // version 2 prints a token for next time, then paths
+            2 => {
+                let mut parts = output.stdout.split(|&b| b == 0);
+                let token = String::from_utf8_lossy(parts.next().unwrap_or_default());
+                (token.into_owned(), parse_paths(parts))
+            }
+            v => return Err(anyhow!("unsupported fsmonitor hook version {}", v)),
+        };
+        trace!("fsmonitor token {:?} -> {:?}", &token_arg, &token);
+
+        // without a previous token we don't know what happened before now
+        let changed = match (last_token, changed, dirty) {
+            (Some(_), Some(mut changed), Some(dirty)) => {
+                changed.extend(dirty);
+                Some(changed)
+            }
+            _ => None,
+        };
+
+        Ok(Some(Fsmonitor {
+            changed,
+            token,
+            state_path,
+        }))
+    }
+
+    /// Could the file at this repo-relative path have changed? If not, its
+    /// index entry can be trusted without looking at the file.
+    pub fn may_have_changed(&self, path: &[u8]) -> bool {
+        let changed = match &self.changed {
+            Some(changed) => changed,
+            None => return true,
+        };
+        // a change to a directory means anything in it may have changed
+        let mut prefix = path;
+        loop {
+            if changed.contains(prefix.as_bstr()) {
+                return true;
+            }
+            match prefix.rfind_byte(b'/') {
+                Some(slash) => prefix = &prefix[..slash],
+                None => return false,
+            }
+        }
+    }
+
+    /// Records the new token once the caller has finished checking files.
+    /// `still_modified` are the paths that were found to differ from the index,
+    /// which need checking again next time regardless of what the monitor says.
+    pub fn save<'a>(
+        self,
+        repo: &Repo,
+        still_modified: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<()> {
+        let mut state = self.token.into_bytes();
+        for path in still_modified {
+            state.push(0);
+            state.extend_from_slice(path);
+        }
+        repo.vfs()
+            .write(&self.state_path, &state)
+            .context("failed to save fsmonitor token")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_paths, Fsmonitor};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_may_have_changed() {
+        let output: &[u8] = b"src/main.rs\0docs/\0";
+        let fsm = Fsmonitor {
+            changed: parse_paths(output.split(|&b| b == 0)),
+            token: String::new(),
+            state_path: PathBuf::new(),
+        };
+        assert!(fsm.may_have_changed(b"src/main.rs"));
+        assert!(fsm.may_have_changed(b"docs/a/b.md"));
+        assert!(!fsm.may_have_changed(b"src/lib.rs"));
+        assert!(!fsm.may_have_changed(b"README"));
+
+        let output: &[u8] = b"a\0/\0";
+        assert_eq!(parse_paths(output.split(|&b| b == 0)), None);
+    }
+}
@@ -0,0 +1,97 @@
+//! Hooks: programs in `.git/hooks`, or the directory `core.hooksPath` names,
+//! which are run at points in some commands. They can stop what is about to
+//! happen by failing, or change it, as `prepare-commit-msg` does by editing
+//! the commit message.
+use anyhow::{anyhow, Context, Result};
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::config::Config;
+use crate::objects::Repo;
+use crate::trace;
+use crate::util;
+
+/// Finds the hook with the given name. Hooks that aren't executable are
+/// skipped with a warning, as git does, except on Windows where nothing is.
+pub fn find(name: &str, repo: &Repo, config: &Config) -> Option<PathBuf> {
+    let dir = match config.get("core.hooksPath") {
+        // relative paths are relative to the worktree, which is where hooks
+        // are run
+        Some(dir) => repo.tree_root().join(util::expand_user_path(dir)),
+        None => repo.root.join("hooks"),
+    };
+    let path = dir.join(name);
+    let meta = repo.vfs().metadata(&path).ok()?;
+    if !meta.executable && !cfg!(windows) {
+        warn!(
+            "the '{}' hook was ignored because it's not set as executable",
+            path.display()
+        );
+        return None;
+    }
+    Some(path)
+}
+
+/// Runs a hook from the top of the worktree. Returns whether there was one to
+/// run; a hook that fails is an error.
+pub fn run<S: AsRef<OsStr>>(name: &str, args: &[S], repo: &Repo) -> Result<bool> {
+    let config = repo.config()?;
+    let path = match find(name, repo, &config) {
+        Some(path) => path,
+        None => return Ok(false),
+    };
+    trace::message(format_args!(
+        "run_command: {} {}",
+        path.display(),
+        args.iter()
+            .map(|a| a.as_ref().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    ));
+    let status = Command::new(&path)
+        .args(args)
+        .env("GIT_DIR", &repo.root)
+        .current_dir(repo.tree_root())
+        .status()
+        .with_context(|| format!("failed to run the {} hook", name))?;
+    if !status.success() {
+        return Err(anyhow!("the {} hook failed", name));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::find;
+    use crate::config::Config;
+    use crate::objects::Repo;
+    use std::path::Path;
+
+    #[test]
+    fn test_find() {
+        let repo = Repo::in_memory();
+        let vfs = repo.vfs();
+        let config = Config::new();
+        assert_eq!(find("pre-commit", &repo, &config), None);
+
+        let hook = Path::new("/repo/.git/hooks/pre-commit");
+        vfs.create_dir_all(hook.parent().unwrap()).unwrap();
+        vfs.write(hook, b"#!/bin/sh\n").unwrap();
+        if !cfg!(windows) {
+            assert_eq!(find("pre-commit", &repo, &config), None);
+        }
+        vfs.set_executable(hook, true).unwrap();
+        assert_eq!(find("pre-commit", &repo, &config), Some(hook.into()));
+
+        // core.hooksPath is relative to the worktree
+        let mut config = Config::new();
+        config.parse("[core]\n\thooksPath = my-hooks\n").unwrap();
+        assert_eq!(find("pre-commit", &repo, &config), None);
+        let hook = Path::new("/repo/my-hooks/pre-commit");
+        vfs.create_dir_all(hook.parent().unwrap()).unwrap();
+        vfs.write(hook, b"#!/bin/sh\n").unwrap();
+        vfs.set_executable(hook, true).unwrap();
+        assert_eq!(find("pre-commit", &repo, &config), Some(hook.into()));
+    }
+}
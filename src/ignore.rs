@@ -0,0 +1,620 @@
+//! Deciding which untracked files are ignored, from `.gitignore` files,
+//! `.git/info/exclude` and the file named by `core.excludesFile`, following
+//! the rules in `man gitignore`.
+//!
+//! A `.gitignore` applies to the directory it is in and everything below it,
+//! and ones further down win over ones further up, which in turn win over
+//! `info/exclude` and then `core.excludesFile`. Within a file, the last
+//! pattern that matches wins, so a later `!pattern` can bring a file back.
+//! Nothing can bring back a file in a directory that is ignored, since git
+//! never looks inside those.
+use anyhow::Result;
+use bstr::{BString, ByteSlice};
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+use crate::objects::Repo;
+use crate::util::{self, to_platform_path};
+
+/// How a glob matched, or didn't. The aborts let a failed match stop trying
+/// other places a `*` could end, as git's wildmatch does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Wild {
+    Match,
+    NoMatch,
+    AbortAll,
+    AbortToStarstar,
+}
+
+fn is_glob_special(c: u8) -> bool {
+    b"*?[\\".contains(&c)
+}
+
+/// Matches a `[:class:]` name against a character
+fn in_class(class: &[u8], c: u8) -> Option<bool> {
+    Some(match class {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase(),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c.is_ascii_whitespace(),
+        b"upper" => c.is_ascii_uppercase(),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => return None,
+    })
+}
+
+/// A port of git's `dowild`. With `pathname`, wildcards other than `**`
+/// don't match `/`.
+fn dowild(pattern: &[u8], text: &[u8], pathname: bool) -> Wild {
+    let pat = |i: usize| pattern.get(i).copied().unwrap_or(0);
+    let txt = |i: usize| text.get(i).copied().unwrap_or(0);
+    let (mut p, mut t) = (0, 0);
+    while p < pattern.len() {
+        let mut p_ch = pattern[p];
+        let mut t_ch = txt(t);
+        if t_ch == 0 && p_ch != b'*' {
+            return Wild::AbortAll;
+        }
+        match p_ch {
+            b'?' => {
+                if pathname && t_ch == b'/' {
+                    return Wild::NoMatch;
+                }
+            }
+            b'*' => {
+                p += 1;
+                let match_slash = if pat(p) == b'*' {
+                    // the character before the stars
+                    let before = p.checked_sub(2).map(|i| pattern[i]);
+                    while pat(p) == b'*' {
+                        p += 1;
+                    }
+                    let ends_component =
+                        pat(p) == 0 || pat(p) == b'/' || (pat(p) == b'\\' && pat(p + 1) == b'/');
+                    if before.map_or(true, |c| c == b'/') && ends_component {
+                        // `**/` may match no directories at all
+                        if pat(p) == b'/'
+                            && dowild(&pattern[p + 1..], &text[t..], pathname) == Wild::Match
+                        {
+                            return Wild::Match;
+                        }
+                        true
+                    } else {
+                        false
+                    }
+                } else {
+                    !pathname
+                };
+
+                if p >= pattern.len() {
+                    // a trailing `*` only matches within the last component
+                    if !match_slash && text[t..].contains(&b'/') {
+                        return Wild::NoMatch;
+                    }
+                    return Wild::Match;
+                } else if !match_slash && pat(p) == b'/' {
+                    // `*/` matches the rest of this component
+                    match text[t..].iter().position(|&c| c == b'/') {
+                        Some(slash) => t += slash,
+                        None => return Wild::NoMatch,
+                    }
+                    p += 1;
+                    t += 1;
+                    continue;
+                }
+
+                while t_ch != 0 {
+                    // skip straight to where the literal after the `*` is
+                    if !is_glob_special(pat(p)) {
+                        let literal = pat(p);
+                        loop {
+                            t_ch = txt(t);
+                            if t_ch == 0 || t_ch == literal || (!match_slash && t_ch == b'/') {
+                                break;
+                            }
+                            t += 1;
+                        }
+                        if t_ch != literal {
+                            return Wild::NoMatch;
+                        }
+                    }
+                    match dowild(&pattern[p..], &text[t..], pathname) {
+                        Wild::NoMatch => {
+                            if !match_slash && t_ch == b'/' {
+                                return Wild::AbortToStarstar;
+                            }
+                        }
+                        Wild::AbortToStarstar if match_slash => (),
+                        matched => return matched,
+                    }
+                    t += 1;
+                    t_ch = txt(t);
+                }
+                return Wild::AbortAll;
+            }
+            b'[' => {
+                p += 1;
+                p_ch = pat(p);
+                let negated = p_ch == b'!' || p_ch == b'^';
+                if negated {
+                    p += 1;
+                    p_ch = pat(p);
+                }
+                let mut prev_ch = 0;
+                let mut matched = false;
+                loop {
+                    if p_ch == 0 {
+                        return Wild::AbortAll;
+                    }
+                    if p_ch == b'\\' {
+                        p += 1;
+                        p_ch = pat(p);
+                        if p_ch == 0 {
+                            return Wild::AbortAll;
+                        }
+                        matched |= t_ch == p_ch;
+                    } else if p_ch == b'-' && prev_ch != 0 && pat(p + 1) != 0 && pat(p + 1) != b']'
+                    {
+                        p += 1;
+                        p_ch = pat(p);
+                        if p_ch == b'\\' {
+                            p += 1;
+                            p_ch = pat(p);
+                            if p_ch == 0 {
+                                return Wild::AbortAll;
+                            }
+                        }
+                        matched |= prev_ch <= t_ch && t_ch <= p_ch;
+                        // a range can't start another range
+                        p_ch = 0;
+                    } else if p_ch == b'[' && pat(p + 1) == b':' {
+                        p += 2;
+                        let start = p;
+                        while pat(p) != 0 && pat(p) != b']' {
+                            p += 1;
+                        }
+                        if pat(p) == 0 {
+                            return Wild::AbortAll;
+                        }
+                        if p == start || pattern[p - 1] != b':' {
+                            // not a class after all, just a `[`
+                            p = start - 2;
+                            p_ch = b'[';
+                            matched |= t_ch == p_ch;
+                        } else {
+                            match in_class(&pattern[start..p - 1], t_ch) {
+                                Some(is) => matched |= is,
+                                None => return Wild::AbortAll,
+                            }
+                            p_ch = 0;
+                        }
+                    } else {
+                        matched |= t_ch == p_ch;
+                    }
+                    prev_ch = p_ch;
+                    p += 1;
+                    p_ch = pat(p);
+                    if p_ch == b']' {
+                        break;
+                    }
+                }
+                if matched == negated || (pathname && t_ch == b'/') {
+                    return Wild::NoMatch;
+                }
+            }
+            _ => {
+                if p_ch == b'\\' {
+                    p += 1;
+                    p_ch = pat(p);
+                }
+                if t_ch != p_ch {
+                    return Wild::NoMatch;
+                }
+            }
+        }
+        p += 1;
+        t += 1;
+    }
+    if t < text.len() {
+        Wild::NoMatch
+    } else {
+        Wild::Match
+    }
+}
+
+/// Matches text against a glob as git does. With `pathname`, `*`, `?` and
+/// `[...]` don't match `/`, while `**` between slashes matches any number of
+/// directories.
+pub fn wildmatch(pattern: &[u8], text: &[u8], pathname: bool) -> bool {
+    dowild(pattern, text, pathname) == Wild::Match
+}
+
+/// One pattern from an ignore file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// The pattern as written, less trailing spaces
+    pub text: BString,
+    /// Line of the file it is on, from 1
+    pub line: usize,
+    /// The glob itself, without any `!`, leading `/` or trailing `/`
+    glob: BString,
+    /// Starts with `!`, so brings back what earlier patterns ignored
+    negated: bool,
+    /// Ends with `/`, so only matches directories
+    dir_only: bool,
+    /// Has no `/`, so matches the last component of paths at any depth
+    basename: bool,
+}
+
+impl Pattern {
+    /// Parses a line of an ignore file, unless it is blank or a comment
+    fn parse(line: &[u8], number: usize) -> Option<Pattern> {
+        let line = if line.ends_with(b"\r") {
+            &line[..line.len() - 1]
+        } else {
+            line
+        };
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // trailing spaces don't count unless they are escaped
+        let mut end = line.len();
+        while end > 0 && line[end - 1] == b' ' {
+            if end > 1 && line[end - 2] == b'\\' {
+                break;
+            }
+            end -= 1;
+        }
+        let text = &line[..end];
+        if text.is_empty() {
+            return None;
+        }
+
+        let mut glob = text;
+        let negated = glob[0] == b'!';
+        if negated {
+            glob = &glob[1..];
+        }
+        let dir_only = glob.ends_with(b"/");
+        if dir_only {
+            glob = &glob[..glob.len() - 1];
+        }
+        let basename = !glob.contains(&b'/');
+        if glob.starts_with(b"/") {
+            glob = &glob[1..];
+        }
+        Some(Pattern {
+            text: text.into(),
+            line: number,
+            glob: glob.into(),
+            negated,
+            dir_only,
+            basename,
+        })
+    }
+
+    /// Whether this is a `!` pattern, matching which means the path is not
+    /// ignored
+    pub fn is_negated(&self) -> bool {
+        self.negated
+    }
+
+    /// Matches a path relative to the pattern's directory
+    fn matches(&self, path: &[u8], is_dir: bool, ignore_case: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = if self.basename {
+            path.rsplit(|&c| c == b'/').next().unwrap_or(path)
+        } else {
+            path
+        };
+        if ignore_case {
+            wildmatch(
+                &self.glob.to_ascii_lowercase(),
+                &text.to_ascii_lowercase(),
+                !self.basename,
+            )
+        } else {
+            wildmatch(&self.glob, text, !self.basename)
+        }
+    }
+}
+
+/// The patterns from one ignore file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatternList {
+    /// Where the patterns are from, such as `src/.gitignore`
+    pub source: String,
+    /// The directory the patterns are relative to, or empty for the top of
+    /// the worktree
+    base: BString,
+    patterns: Vec<Pattern>,
+}
+
+impl PatternList {
+    /// Parses an ignore file whose patterns are relative to `base`
+    pub fn parse(text: &[u8], base: &[u8], source: String) -> PatternList {
+        let patterns = text
+            .split(|&c| c == b'\n')
+            .enumerate()
+            .filter_map(|(n, line)| Pattern::parse(line, n + 1))
+            .collect();
+        PatternList {
+            source,
+            base: base.into(),
+            patterns,
+        }
+    }
+
+    /// Finds the last pattern that matches a worktree path
+    fn last_match(&self, path: &[u8], is_dir: bool, ignore_case: bool) -> Option<&Pattern> {
+        let base = self.base.as_slice();
+        let relative = if base.is_empty() {
+            path
+        } else if path.starts_with(base) && path.get(base.len()) == Some(&b'/') {
+            &path[base.len() + 1..]
+        } else {
+            return None;
+        };
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(relative, is_dir, ignore_case))
+    }
+}
+
+/// The pattern that decided whether a path is ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IgnoreMatch<'a> {
+    /// Where the pattern is from
+    pub source: &'a str,
+    /// The pattern. The path is ignored unless it is negated.
+    pub pattern: &'a Pattern,
+}
+
+/// The ignore rules of a repository. `.gitignore` files are read as they are
+/// needed and kept.
+pub struct Ignores<'a> {
+    repo: &'a Repo,
+    /// `.gitignore` files by the directory they are in
+    dirs: HashMap<BString, Option<PatternList>>,
+    /// `info/exclude` then `core.excludesFile`
+    global: Vec<PatternList>,
+    ignore_case: bool,
+}
+
+/// Where `core.excludesFile` is by default
+fn default_excludes_file() -> Option<PathBuf> {
+    match env::var_os("XDG_CONFIG_HOME") {
+        Some(config) if !config.is_empty() => Some(PathBuf::from(config)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    }
+    .map(|config| config.join("git").join("ignore"))
+}
+
+impl<'a> Ignores<'a> {
+    /// Reads the ignore rules that aren't in the worktree
+    pub fn new(repo: &'a Repo) -> Result<Ignores<'a>> {
+        let config = repo.config()?;
+        let ignore_case = config.get_bool("core.ignoreCase")?.unwrap_or(false);
+        let excludes_file = match config.get("core.excludesFile") {
+            Some(path) => Some(util::expand_user_path(path)),
+            None => default_excludes_file(),
+        };
+
+        let mut global = Vec::new();
+        let sources = [
+            (
+                Some(repo.root.join("info").join("exclude")),
+                ".git/info/exclude".to_owned(),
+            ),
+            (
+                excludes_file.clone(),
+                excludes_file.map_or_else(String::new, |p| p.display().to_string()),
+            ),
+        ];
+        for (path, source) in sources.iter() {
+            if let Some(text) = path.as_ref().and_then(|p| repo.vfs().read(p).ok()) {
+                global.push(PatternList::parse(&text, b"", source.clone()));
+            }
+        }
+        Ok(Ignores {
+            repo,
+            dirs: HashMap::new(),
+            global,
+            ignore_case,
+        })
+    }
+
+    /// Reads the `.gitignore` in a worktree directory, if there is one
+    fn load_dir(&mut self, dir: &[u8]) {
+        if self.dirs.contains_key(dir.as_bstr()) {
+            return;
+        }
+        let source: BString = if dir.is_empty() {
+            ".gitignore".into()
+        } else {
+            [dir, b"/.gitignore"].concat().into()
+        };
+        let path = self.repo.tree_root().join(to_platform_path(&source));
+        let list = self
+            .repo
+            .vfs()
+            .read(&path)
+            .ok()
+            .map(|text| PatternList::parse(&text, dir, source.to_string()));
+        self.dirs.insert(dir.into(), list);
+    }
+
+    /// The last matching pattern for a path, looking at the `.gitignore` in
+    /// each directory above it, deepest first, then at the global files.
+    /// Directories above the path have to have been loaded.
+    fn last_match(&self, path: &[u8], is_dir: bool) -> Option<IgnoreMatch<'_>> {
+        let dirs = path
+            .iter()
+            .enumerate()
+            .filter(|&(_, &c)| c == b'/')
+            .map(|(n, _)| &path[..n])
+            .rev()
+            .chain(Some(&b""[..]));
+        let lists = dirs
+            .filter_map(|dir| self.dirs.get(dir.as_bstr()).and_then(Option::as_ref))
+            .chain(self.global.iter());
+        for list in lists {
+            if let Some(pattern) = list.last_match(path, is_dir, self.ignore_case) {
+                return Some(IgnoreMatch {
+                    source: &list.source,
+                    pattern,
+                });
+            }
+        }
+        None
+    }
+
+    /// Finds the pattern that decides whether a worktree path is ignored, if
+    /// any does. A path in an ignored directory is decided by the pattern
+    /// that ignored the directory.
+    pub fn matching(&mut self, path: &[u8], is_dir: bool) -> Option<IgnoreMatch<'_>> {
+        let path = if path.ends_with(b"/") {
+            &path[..path.len() - 1]
+        } else {
+            path
+        };
+        self.load_dir(b"");
+        for (n, _) in path.iter().enumerate().filter(|&(_, &c)| c == b'/') {
+            self.load_dir(&path[..n]);
+        }
+
+        let mut ignored_dir = None;
+        for (n, _) in path.iter().enumerate().filter(|&(_, &c)| c == b'/') {
+            match self.last_match(&path[..n], true) {
+                Some(m) if !m.pattern.negated => {
+                    ignored_dir = Some(n);
+                    break;
+                }
+                _ => (),
+            }
+        }
+        match ignored_dir {
+            Some(n) => self.last_match(&path[..n], true),
+            None => self.last_match(path, is_dir),
+        }
+    }
+
+    /// Whether a worktree path is ignored
+    pub fn is_ignored(&mut self, path: &[u8], is_dir: bool) -> bool {
+        self.matching(path, is_dir)
+            .map_or(false, |m| !m.pattern.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wildmatch, Ignores};
+    use crate::objects::Repo;
+    use std::path::Path;
+
+    #[test]
+    fn test_wildmatch() {
+        let cases: &[(&str, &str, bool, bool)] = &[
+            // pattern, text, pathname, matches
+            ("foo", "foo", true, true),
+            ("foo", "bar", true, false),
+            ("*.o", "a.o", true, true),
+            ("*.o", "dir/a.o", true, false),
+            ("*.o", "dir/a.o", false, true),
+            ("f?o", "foo", true, true),
+            ("f?o", "f/o", true, false),
+            ("a/*/c", "a/b/c", true, true),
+            ("a/*/c", "a/b/x/c", true, false),
+            ("**/foo", "foo", true, true),
+            ("**/foo", "a/b/foo", true, true),
+            ("a/**", "a/b/c", true, true),
+            ("a/**/b", "a/b", true, true),
+            ("a/**/b", "a/x/y/b", true, true),
+            ("a**b", "a/b", true, false),
+            ("[a-c]x", "bx", true, true),
+            ("[!a-c]x", "bx", true, false),
+            ("[^a-c]x", "dx", true, true),
+            ("[[:digit:]]", "7", true, true),
+            ("[[:upper:]]", "a", true, false),
+            ("[]]", "]", true, true),
+            ("\\*", "*", true, true),
+            ("\\*", "a", true, false),
+            ("[a", "a", true, false),
+            ("*", "", true, true),
+            ("a*", "abc/", true, false),
+        ];
+        for &(pattern, text, pathname, expected) in cases {
+            assert_eq!(
+                wildmatch(pattern.as_bytes(), text.as_bytes(), pathname),
+                expected,
+                "{} against {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_ignores() {
+        let repo = Repo::in_memory();
+        let vfs = repo.vfs();
+        vfs.write(
+            Path::new("/repo/.gitignore"),
+            b"# build output\n*.o\n!keep.o\nbuild/\n/top\n\\#hash\ndocs/*.html\ntrailing  \n",
+        )
+        .unwrap();
+        vfs.create_dir_all(Path::new("/repo/src")).unwrap();
+        vfs.write(Path::new("/repo/src/.gitignore"), b"!*.o\ngen\n")
+            .unwrap();
+        vfs.create_dir_all(Path::new("/repo/.git/info")).unwrap();
+        vfs.write(Path::new("/repo/.git/info/exclude"), b"*.log\n")
+            .unwrap();
+
+        let mut ignores = Ignores::new(&repo).unwrap();
+        let mut check = |path: &str, is_dir: bool| {
+            ignores
+                .matching(path.as_bytes(), is_dir)
+                .map(|m| (m.source.to_owned(), m.pattern.line, !m.pattern.is_negated()))
+        };
+        let gitignore = |line, ignored| Some((".gitignore".to_string(), line, ignored));
+
+        assert_eq!(check("a.o", false), gitignore(2, true));
+        assert_eq!(check("deep/dir/a.o", false), gitignore(2, true));
+        assert_eq!(check("keep.o", false), gitignore(3, false));
+        assert_eq!(check("a.c", false), None);
+        // only directories match `build/`, but everything in them is ignored
+        assert_eq!(check("build", false), None);
+        assert_eq!(check("build", true), gitignore(4, true));
+        assert_eq!(check("build/x/y.c", false), gitignore(4, true));
+        // anchored to the top
+        assert_eq!(check("top", false), gitignore(5, true));
+        assert_eq!(check("src/top", false), None);
+        assert_eq!(check("#hash", false), gitignore(6, true));
+        assert_eq!(check("docs/a.html", false), gitignore(7, true));
+        assert_eq!(check("docs/api/a.html", false), None);
+        assert_eq!(check("trailing", false), gitignore(8, true));
+        // deeper files win
+        assert_eq!(
+            check("src/a.o", false),
+            Some(("src/.gitignore".to_string(), 1, false))
+        );
+        assert_eq!(
+            check("src/gen/out.c", false),
+            Some(("src/.gitignore".to_string(), 2, true))
+        );
+        assert_eq!(
+            check("src/debug.log", false),
+            Some((".git/info/exclude".to_string(), 1, true))
+        );
+    }
+}
@@ -1,21 +1,63 @@
 //! Low-level functions for working with an index
-use crate::objects::{Blob, Id, Object, Repo};
+use crate::ignore::Ignores;
+use crate::objects::{Blob, FileMode, Id, Object, Repo};
+use crate::util::{join_git_path, serialize_lossy, to_platform_path};
+use crate::vfs::{FileType, Metadata, Vfs};
 use anyhow::{Context, Error, Result};
+use bstr::{BString, ByteSlice, ByteVec};
 use safecast::Safecast;
+use serde::{Serialize, Serializer};
 use sha1::{Digest, Sha1};
+use std::convert::TryFrom;
 use std::fmt;
-use std::fs;
 use std::io;
+use std::iter::FromIterator;
 use std::mem;
+use std::ops::{Deref, DerefMut};
 use std::path::Path;
+use std::slice;
+use std::str;
 use std::time;
 use thiserror::Error;
 
 const SIGNATURE: [u8; 4] = *b"DIRC";
 const VERSION: u32 = 2;
 
-/// Files indexed in this index. Must be kept sorted.
-pub type Index = Vec<IndexEntry>;
+/// An index: the files staged for the next commit along with data cached
+/// about them. Derefs to its entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Index {
+    /// Files indexed in this index. Must be kept sorted.
+    pub entries: Vec<IndexEntry>,
+    /// Trees of directories whose contents haven't changed since they were
+    /// last written, from the TREE extension
+    pub cache_tree: Option<CacheTree>,
+}
+
+/// A directory in the cached tree extension. If it is valid, the index entries
+/// under it are exactly the contents of the tree with its ID.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CacheTree {
+    /// ID of the tree, or None if something in this directory has changed
+    pub id: Option<Id>,
+    /// Number of index entries under this directory, if valid
+    pub entry_count: u32,
+    /// Subdirectories and their names, ordered by length then bytes like git
+    #[serde(serialize_with = "serialize_children")]
+    pub children: Vec<(BString, CacheTree)>,
+}
+
+/// Serializes the children of a cached tree with their names as strings
+fn serialize_children<S: Serializer>(
+    children: &[(BString, CacheTree)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(
+        children
+            .iter()
+            .map(|(name, child)| (name.to_str_lossy(), child)),
+    )
+}
 
 /// Errors that can be returned by working with an index
 #[derive(Error, Debug)]
@@ -27,6 +69,14 @@ pub enum IndexError {
     /// The magic bytes at the top of the header are wrong
     #[error("Bad header magic")]
     BadMagic,
+
+    /// The file ended in the middle of an entry
+    #[error("Index is truncated")]
+    Truncated,
+
+    /// An extension we need to understand is malformed or unknown
+    #[error("Bad or unsupported index extension {}", String::from_utf8_lossy(.0))]
+    BadExtension([u8; 4]),
 }
 
 /// Big endian u32 with From/Into to normal u32. Used for casting index data
@@ -55,6 +105,12 @@ impl fmt::Debug for u32be {
     }
 }
 
+impl Serialize for u32be {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(self.clone().into())
+    }
+}
+
 /// Big endian u16 with From/Into to normal u16. Used for casting index data
 /// in-place
 #[derive(Safecast, Clone, Copy, PartialEq, Eq)]
@@ -81,6 +137,12 @@ impl fmt::Debug for u16be {
     }
 }
 
+impl Serialize for u16be {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.clone().into())
+    }
+}
+
 #[derive(Safecast, Debug)]
 #[repr(C)]
 struct Header {
@@ -94,18 +156,177 @@ struct Header {
     num_entries: u32be,
 }
 
+impl Index {
+    /// Makes an empty index
+    pub fn new() -> Index {
+        Default::default()
+    }
+
+    /// Marks the cached trees containing a repo-relative path as changed
+    pub fn invalidate_path(&mut self, path: &[u8]) {
+        if let Some(tree) = &mut self.cache_tree {
+            tree.invalidate(path);
+        }
+    }
+}
+
+impl Deref for Index {
+    type Target = Vec<IndexEntry>;
+
+    fn deref(&self) -> &Vec<IndexEntry> {
+        &self.entries
+    }
+}
+
+impl DerefMut for Index {
+    fn deref_mut(&mut self) -> &mut Vec<IndexEntry> {
+        &mut self.entries
+    }
+}
+
+impl From<Vec<IndexEntry>> for Index {
+    fn from(entries: Vec<IndexEntry>) -> Index {
+        Index {
+            entries,
+            cache_tree: None,
+        }
+    }
+}
+
+impl FromIterator<IndexEntry> for Index {
+    fn from_iter<I: IntoIterator<Item = IndexEntry>>(iter: I) -> Index {
+        Index::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl<'a> IntoIterator for &'a Index {
+    type Item = &'a IndexEntry;
+    type IntoIter = slice::Iter<'a, IndexEntry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+/// Git orders cached subtrees by name length first
+fn subtree_order(a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+impl CacheTree {
+    /// Finds the cached tree for a repo-relative directory path. The empty
+    /// path is this tree.
+    pub fn find(&self, path: &[u8]) -> Option<&CacheTree> {
+        if path.is_empty() {
+            return Some(self);
+        }
+        let (first, rest) = match path.find_byte(b'/') {
+            Some(slash) => (&path[..slash], &path[slash + 1..]),
+            None => (path, &b""[..]),
+        };
+        self.children
+            .iter()
+            .find(|(name, _)| name == first)
+            .and_then(|(_, child)| child.find(rest))
+    }
+
+    /// Gets the cached tree for a subdirectory, making an invalid one if it
+    /// doesn't exist
+    pub fn child_mut(&mut self, name: &[u8]) -> &mut CacheTree {
+        let pos = match self
+            .children
+            .binary_search_by(|(n, _)| subtree_order(n, name))
+        {
+            Ok(pos) => pos,
+            Err(pos) => {
+                self.children
+                    .insert(pos, (name.into(), CacheTree::default()));
+                pos
+            }
+        };
+        &mut self.children[pos].1
+    }
+
+    /// Marks this tree and the subtrees containing `path` as changed
+    pub fn invalidate(&mut self, path: &[u8]) {
+        self.id = None;
+        if let Some(slash) = path.find_byte(b'/') {
+            let (first, rest) = (&path[..slash], &path[slash + 1..]);
+            if let Some((_, child)) = self.children.iter_mut().find(|(name, _)| name == first) {
+                child.invalidate(rest);
+            }
+        }
+    }
+
+    /// Parses one node of the TREE extension along with its children
+    fn parse(data: &mut &[u8]) -> Result<(BString, CacheTree)> {
+        let bad = || Error::new(IndexError::BadExtension(*b"TREE"));
+
+        let nul = data.iter().position(|&b| b == 0).ok_or_else(bad)?;
+        let name = BString::from(&data[..nul]);
+        *data = &data[nul + 1..];
+
+        let newline = data.iter().position(|&b| b == b'\n').ok_or_else(bad)?;
+        let counts = str::from_utf8(&data[..newline])?;
+        *data = &data[newline + 1..];
+        let mut counts = counts.split(' ');
+        let entry_count: i32 = counts.next().ok_or_else(bad)?.parse()?;
+        let subtrees: usize = counts.next().ok_or_else(bad)?.parse()?;
+
+        // invalid trees have a count of -1 and no ID
+        let id = if entry_count >= 0 {
+            let mut id = [0u8; 20];
+            id.copy_from_slice(take(data, 20)?);
+            Some(Id::from_bytes(id))
+        } else {
+            None
+        };
+
+        let mut children = Vec::with_capacity(subtrees);
+        for _ in 0..subtrees {
+            children.push(CacheTree::parse(data)?);
+        }
+        Ok((
+            name,
+            CacheTree {
+                id,
+                entry_count: entry_count.max(0) as u32,
+                children,
+            },
+        ))
+    }
+
+    /// Encodes this node and its children in the TREE extension format
+    fn encode(&self, name: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(name);
+        out.push(0);
+        match &self.id {
+            Some(id) => {
+                out.extend(format!("{} {}\n", self.entry_count, self.children.len()).bytes());
+                out.extend_from_slice(id.as_bytes());
+            }
+            None => out.extend(format!("-1 {}\n", self.children.len()).bytes()),
+        }
+        for (name, child) in &self.children {
+            child.encode(name, out);
+        }
+    }
+}
+
 /// Entry in the Git index
 /// order: sorted in ascending order on name field, sorted in byte comparison order
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
 pub struct IndexEntry {
-    /// Filename represented
-    pub name: String,
+    /// Repo-relative path of the file, as bytes since git doesn't require
+    /// paths to be UTF-8
+    #[serde(serialize_with = "serialize_lossy")]
+    pub name: BString,
     /// Filesytem metadata
     pub meta: IndexMeta,
 }
 
 /// Metadata on an index entry
-#[derive(Safecast, Clone, PartialEq, Eq, Debug)]
+#[derive(Safecast, Clone, PartialEq, Eq, Debug, Serialize)]
 #[repr(C)]
 pub struct IndexMeta {
     /// Creation time
@@ -156,14 +377,15 @@ pub struct IndexMeta {
 }
 
 /// Information from filesystem stat operations
-#[derive(Debug, PartialEq)]
+#[derive(Debug)]
 pub struct StatInfo {
     /// (sec, nsec) tuple of metadata change time
     pub ctime: (u32, u32),
     /// (sec, nsec) tuple of modification time
     pub mtime: (u32, u32),
-    /// Size in bytes
-    pub size: u32,
+    /// Size in bytes. The index only has room for the bottom 32 bits of this,
+    /// so only those are compared.
+    pub size: u64,
     /// Unix-specific stat entries. These are zero and ignored on Windows.
     pub unix_stat: UnixStat,
 }
@@ -182,38 +404,46 @@ pub struct UnixStat {
     pub gid: u32,
     /// Is the file executable?
     pub executable: bool,
+    /// Is the file a symbolic link?
+    pub symlink: bool,
 }
 
 impl UnixStat {
-    /// Gets the unix-specific stat stuff. Not implemented on Unix yet but zero
-    /// is an acceptable value
-    fn get(_meta: &fs::Metadata) -> UnixStat {
-        Default::default()
+    /// Gets the unix-specific stat stuff. The numeric fields are not
+    /// implemented yet but zero is an acceptable value
+    fn get(meta: &Metadata) -> UnixStat {
+        UnixStat {
+            executable: meta.executable,
+            symlink: meta.file_type == FileType::Symlink,
+            ..Default::default()
+        }
     }
 
-    fn mode(&self) -> u32 {
-        if self.executable {
-            0o100755
+    fn mode(&self) -> FileMode {
+        if self.symlink {
+            FileMode::Symlink
+        } else if self.executable {
+            FileMode::Executable
         } else {
-            0o100644
+            FileMode::Regular
         }
     }
 }
 
 impl StatInfo {
-    fn get(path: &Path) -> Result<StatInfo> {
-        // XXX: these u32 timestamps will break after 2038 but git will break too 🤷‍♀️
-        let meta = fs::metadata(path).with_context(|| {
+    fn get(path: &Path, vfs: &dyn Vfs) -> Result<StatInfo> {
+        // symlinks are stored as links, so we look at the link itself
+        let meta = vfs.symlink_metadata(path).with_context(|| {
             format!(
                 "failed to find metadata for {} while making index",
                 path.display()
             )
         })?;
 
-        let mtime = system_time_to_epoch(meta.modified()?)?;
-        let ctime = system_time_to_epoch(meta.modified()?)?;
+        let mtime = system_time_to_epoch(meta.modified);
+        let ctime = system_time_to_epoch(meta.changed);
 
-        let size = meta.len() as u32;
+        let size = meta.len;
 
         let unix_stat = UnixStat::get(&meta);
         Ok(StatInfo {
@@ -225,6 +455,15 @@ impl StatInfo {
     }
 }
 
+impl PartialEq for StatInfo {
+    fn eq(&self, other: &StatInfo) -> bool {
+        self.ctime == other.ctime
+            && self.mtime == other.mtime
+            && self.size as u32 == other.size as u32
+            && self.unix_stat == other.unix_stat
+    }
+}
+
 impl PartialEq for UnixStat {
     /// Compares a UnixStat, ignoring fields if they are zero
     fn eq(&self, other: &UnixStat) -> bool {
@@ -234,14 +473,40 @@ impl PartialEq for UnixStat {
             && (self.ino == 0 || other.ino == 0 || self.ino == other.ino)
             && (self.dev == 0 || other.dev == 0 || self.dev == other.dev)
             && (cfg!(not(target_family = "unix")) || self.executable == other.executable)
+            && self.symlink == other.symlink
+    }
+}
+
+/// Makes a blob of the target of the symlink at `path`, which is what git
+/// stores for symlinks. Returns None if it isn't a symlink, in which case the
+/// file's content is stored.
+fn symlink_blob(path: &Path, vfs: &dyn Vfs) -> io::Result<Option<Blob>> {
+    if vfs.symlink_metadata(path)?.file_type != FileType::Symlink {
+        return Ok(None);
     }
+    let target = vfs.read_link(path)?;
+    Ok(Some(Blob::new(Vec::from_path_lossy(&target).into_owned())))
 }
 
 impl IndexEntry {
-    /// Checks if a file in the index has changed since it was added to the index
+    /// Checks if the file of this entry is gone from the working tree
+    pub fn is_deleted(&self, repo: &Repo) -> io::Result<bool> {
+        let path = repo.tree_root().join(to_platform_path(&self.name));
+        match repo.vfs().symlink_metadata(&path) {
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks if a file in the index has changed since it was added to the
+    /// index. Submodules aren't looked into, so they never have.
     pub fn is_same_as_tree(&self, repo: &Repo) -> Result<bool> {
-        let filepath = &repo.tree_root().join(&self.name);
-        let si = StatInfo::get(&filepath)
+        if self.meta.file_mode() == FileMode::Gitlink {
+            return Ok(true);
+        }
+        let filepath = &repo.tree_root().join(to_platform_path(&self.name));
+        let si = StatInfo::get(&filepath, repo.vfs())
             .with_context(|| format!("finding filesystem stats for {}", filepath.display()))?;
 
         if self.meta.statinfo() == si {
@@ -253,9 +518,10 @@ impl IndexEntry {
 
         // if they are in fact different, we need to expensively check whether
         // the hashes of the files are the same
-        let blob = Blob::new_from_disk(&filepath)
-            .with_context(|| format!("making a blob of {}", filepath.display()))?;
-        let (id, _) = Object::prepare_store(&blob);
+        let id = match symlink_blob(filepath, repo.vfs())? {
+            Some(blob) => Object::hash(&blob)?,
+            None => repo.hash_file(filepath)?,
+        };
         Ok(id == self.meta.id)
     }
 }
@@ -263,16 +529,28 @@ impl IndexEntry {
 impl IndexMeta {
     /// Generates the metadata for a given file as it would be if it were added
     /// to the index
-    pub fn new_from_file(filename: &str, repo: &Repo) -> Result<IndexMeta> {
-        let path = repo.tree_root().join(filename);
+    pub fn new_from_file(filename: &[u8], repo: &Repo) -> Result<IndexMeta> {
+        let path = repo.tree_root().join(to_platform_path(filename));
+
+        let id = match symlink_blob(&path, repo.vfs())? {
+            Some(blob) => repo.store(&blob)?,
+            None => repo
+                .store_file(&path)
+                .with_context(|| format!("making blob from {}", path.display()))?,
+        };
+        IndexMeta::for_stored_file(filename, id, repo)
+    }
 
-        let id = repo.store(&Blob::new_from_disk(&path)?)?;
-        let statinfo = StatInfo::get(&path)?;
+    /// Generates the metadata for a file whose content is already stored as
+    /// the blob `id`, such as one that was just checked out
+    pub fn for_stored_file(filename: &[u8], id: Id, repo: &Repo) -> Result<IndexMeta> {
+        let path = repo.tree_root().join(to_platform_path(filename));
+        let statinfo = StatInfo::get(&path, repo.vfs())?;
 
         // bottom 12 bits of the name length are flags
         let flags = (filename.len() & 0xfff) as u16;
 
-        trace!("making index entry for {}", filename);
+        trace!("making index entry for {}", filename.as_bstr());
 
         Ok(IndexMeta {
             ctime: statinfo.ctime.0.into(),
@@ -280,11 +558,12 @@ impl IndexMeta {
             mtime: statinfo.mtime.0.into(),
             mtime_ns: statinfo.mtime.1.into(),
 
-            size: statinfo.size.into(),
+            // like git, files of 4GiB and over have their size truncated
+            size: (statinfo.size as u32).into(),
             id,
             flags: flags.into(),
 
-            mode: statinfo.unix_stat.mode().into(),
+            mode: u32::from(statinfo.unix_stat.mode()).into(),
             dev: statinfo.unix_stat.dev.into(),
             ino: statinfo.unix_stat.ino.into(),
             uid: statinfo.unix_stat.uid.into(),
@@ -292,59 +571,203 @@ impl IndexMeta {
         })
     }
 
+    /// Generates the metadata for a blob that isn't in the working tree, such
+    /// as one taken from a commit. There are no stats, so the entry never
+    /// looks up to date and the file is hashed when it is compared.
+    pub fn for_blob(filename: &[u8], id: Id, mode: FileMode) -> IndexMeta {
+        let zero = u32be::from(0);
+        IndexMeta {
+            ctime: zero,
+            ctime_ns: zero,
+            mtime: zero,
+            mtime_ns: zero,
+            size: zero,
+            id,
+            flags: ((filename.len() & 0xfff) as u16).into(),
+            mode: u32::from(mode).into(),
+            dev: zero,
+            ino: zero,
+            uid: zero,
+            gid: zero,
+        }
+    }
+
+    /// Gets the merge stage of the entry. It is 0 except for files with merge
+    /// conflicts, which have an entry for each version: 1 for the merge base,
+    /// 2 for ours and 3 for theirs.
+    pub fn stage(&self) -> u16 {
+        (u16::from(self.flags) >> 12) & 0b11
+    }
+
+    /// Sets the merge stage of the entry, see [`IndexMeta::stage`]
+    pub fn set_stage(&mut self, stage: u16) {
+        let flags = (u16::from(self.flags) & !0x3000) | ((stage & 0b11) << 12);
+        self.flags = flags.into();
+    }
+
+    /// Gets what kind of file the entry is. Modes git wouldn't have written
+    /// are read as regular files, as git does.
+    pub fn file_mode(&self) -> FileMode {
+        FileMode::try_from(u32::from(self.mode)).unwrap_or(FileMode::Regular)
+    }
+
     /// Gets the statinfo of an index entry for use in comparisons
     pub fn statinfo(&self) -> StatInfo {
         StatInfo {
             ctime: (self.ctime.into(), self.ctime_ns.into()),
             mtime: (self.mtime.into(), self.mtime_ns.into()),
-            size: self.size.into(),
+            size: u64::from(u32::from(self.size)),
             unix_stat: UnixStat {
                 dev: self.dev.into(),
                 ino: self.ino.into(),
                 uid: self.uid.into(),
                 gid: self.gid.into(),
-                executable: (u32::from(self.mode) & ((1 << 9) - 1)) == 0o755,
+                executable: self.file_mode() == FileMode::Executable,
+                symlink: self.file_mode() == FileMode::Symlink,
             },
         }
     }
 }
 
-/// Ensure a file is in an index. `filename` is a repo-relative path.
-pub fn add_to_index(index: &mut Index, filename: &str, repo: &Repo) -> Result<Id> {
+/// Makes a new index entry for a file, storing its blob, unless the index
+/// already has an up to date entry for it. `filename` is a repo-relative path.
+///
+/// This only reads the index, so it can be run for many files in parallel with
+/// the results applied afterwards using [`insert_entry`].
+pub fn entry_for_file(index: &Index, filename: &[u8], repo: &Repo) -> Result<Option<IndexMeta>> {
     let existing_entry =
-        index.binary_search_by(|IndexEntry { name, .. }| name.as_str().cmp(filename));
+        index.binary_search_by(|IndexEntry { name, .. }| name.as_slice().cmp(filename));
 
-    let path = repo.tree_root().join(filename);
-    let filestats = StatInfo::get(&path)?;
+    let path = repo.tree_root().join(to_platform_path(filename));
+    let filestats = StatInfo::get(&path, repo.vfs())?;
 
-    Ok(match existing_entry {
+    match existing_entry {
         // If it's in the index and all the stats are the same, we can assume
         // it's the same and no-op
-        Ok(found) if index[found].meta.statinfo() == filestats => index[found].meta.id.clone(),
+        Ok(found) if index[found].meta.statinfo() == filestats => Ok(None),
 
-        // It's in the index but the entry is old. Replace the entry. This will
-        // no-op if the file has been modified but has the same contents
+        // Either it's in the index but the entry is old, or it's not in the
+        // index at all. If the file has been modified but has the same
+        // contents, storing the blob is a no-op
+        _ => Ok(Some(IndexMeta::new_from_file(filename, repo)?)),
+    }
+}
+
+/// Finds the files in the working tree that are neither in the index nor
+/// ignored, sorted. Like in `git status`, a directory with nothing from the
+/// index in it is given once, as its name with a `/` on the end, and so is
+/// another repository inside the working tree.
+pub fn untracked_files(index: &Index, repo: &Repo) -> Result<Vec<BString>> {
+    let mut ignores = Ignores::new(repo)?;
+    let mut found = Vec::new();
+    find_untracked(b"", index, repo, &mut ignores, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+/// Adds the untracked files in a worktree directory to `found`
+fn find_untracked(
+    dir: &[u8],
+    index: &Index,
+    repo: &Repo,
+    ignores: &mut Ignores<'_>,
+    found: &mut Vec<BString>,
+) -> Result<()> {
+    let vfs = repo.vfs();
+    let dir_path = repo.tree_root().join(to_platform_path(dir));
+    for name in vfs.read_dir(&dir_path)? {
+        if name == ".git" {
+            continue;
+        }
+        let path = join_git_path(dir, &Vec::from_os_str_lossy(&name));
+        let is_dir = vfs.symlink_metadata(&dir_path.join(&name))?.file_type == FileType::Dir;
+        let tracked = index.binary_search_by(|ie| ie.name.cmp(&path));
+        if tracked.is_ok() || ignores.is_ignored(&path, is_dir) {
+            continue;
+        }
+        if !is_dir {
+            found.push(path);
+            continue;
+        }
+
+        // siblings such as `dir.c` sort between `dir` and `dir/`, so it's
+        // where `dir/` would go that there has to be a tracked file
+        let prefix = [&path[..], b"/"].concat();
+        let first_inside = index
+            .binary_search_by(|ie| ie.name.as_slice().cmp(&prefix))
+            .unwrap_or_else(|at| at);
+        let has_tracked = index
+            .get(first_inside)
+            .map_or(false, |ie| ie.name.starts_with(&prefix));
+        if has_tracked {
+            find_untracked(&path, index, repo, ignores, found)?;
+        } else if vfs.exists(&dir_path.join(&name).join(".git")) {
+            found.push(prefix.into());
+        } else {
+            // a directory is only worth mentioning if something in it is
+            let mut inside = Vec::new();
+            find_untracked(&path, index, repo, ignores, &mut inside)?;
+            if !inside.is_empty() {
+                found.push(prefix.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds the entries for a file, of which there is one for each side of a
+/// merge conflict, or where they would go
+fn entries_named(index: &Index, filename: &[u8]) -> std::ops::Range<usize> {
+    match index.binary_search_by(|IndexEntry { name, .. }| name.as_slice().cmp(filename)) {
         Ok(found) => {
-            let new_entry = IndexMeta::new_from_file(filename, repo)?;
-            let id = new_entry.id.clone();
-            index[found].meta = new_entry;
-            id
+            let same = |i: &usize| index[*i].name == filename;
+            let start = (0..found).rev().take_while(same).last().unwrap_or(found);
+            let end = (found..index.len())
+                .take_while(same)
+                .last()
+                .unwrap_or(found);
+            start..end + 1
         }
+        Err(idx) => idx..idx,
+    }
+}
+
+/// Puts an entry into a sorted index, replacing any existing entries with the
+/// same name. This resolves a merge conflict in the file.
+pub fn insert_entry(index: &mut Index, entry: IndexEntry) {
+    index.invalidate_path(&entry.name);
+    let range = entries_named(index, &entry.name);
+    index.splice(range, std::iter::once(entry));
+}
 
-        // Not in the index
-        Err(idx) => {
-            let new_entry = IndexMeta::new_from_file(filename, repo)?;
-            let id = new_entry.id.clone();
-            index.insert(
-                idx,
+/// Takes a file out of an index, if it is there
+pub fn remove_entry(index: &mut Index, filename: &[u8]) -> Option<IndexEntry> {
+    index.invalidate_path(filename);
+    let range = entries_named(index, filename);
+    index.drain(range).next()
+}
+
+/// Ensure a file is in an index. `filename` is a repo-relative path.
+pub fn add_to_index(index: &mut Index, filename: &[u8], repo: &Repo) -> Result<Id> {
+    match entry_for_file(index, filename, repo)? {
+        Some(meta) => {
+            let id = meta.id.clone();
+            insert_entry(
+                index,
                 IndexEntry {
-                    name: filename.to_string(),
-                    meta: new_entry,
+                    name: filename.into(),
+                    meta,
                 },
             );
-            id
+            Ok(id)
         }
-    })
+        None => {
+            let found = index
+                .binary_search_by(|IndexEntry { name, .. }| name.as_slice().cmp(filename))
+                .expect("up to date entry vanished from the index");
+            Ok(index[found].meta.id.clone())
+        }
+    }
 }
 
 /// Write out an index to the given Write-implementing object such as a file
@@ -359,7 +782,7 @@ pub fn write_to_file(index: &Index, mut file: impl io::Write) -> Result<()> {
     file.write_all(header_buf)?;
     hash.input(header_buf);
 
-    for IndexEntry { name, meta } in index {
+    for IndexEntry { name, meta } in &index.entries {
         let entry_buf = meta.cast();
         file.write_all(entry_buf)?;
         hash.input(entry_buf);
@@ -369,12 +792,23 @@ pub fn write_to_file(index: &Index, mut file: impl io::Write) -> Result<()> {
         let namerecsz = name_record_size(name.len());
         let padding_zeros = vec![0u8; namerecsz - name.len()];
 
-        file.write_all(name.as_bytes())?;
+        file.write_all(name)?;
         file.write_all(&padding_zeros)?;
-        hash.input(&name);
+        hash.input(name.as_slice());
         hash.input(&padding_zeros);
     }
 
+    if let Some(tree) = &index.cache_tree {
+        let mut ext = Vec::new();
+        tree.encode(b"", &mut ext);
+        let mut header = b"TREE".to_vec();
+        header.extend_from_slice(&(ext.len() as u32).to_be_bytes());
+        file.write_all(&header)?;
+        file.write_all(&ext)?;
+        hash.input(&header);
+        hash.input(&ext);
+    }
+
     // write a hash of the contents at the end of the file
     let res: [u8; 20] = hash.result().into();
     file.write_all(&res)?;
@@ -390,12 +824,27 @@ fn name_record_size(name_length: usize) -> usize {
 }
 
 /// Reads an index out of a file
-pub(crate) fn parse(mut file: impl io::Read) -> Result<Index> {
-    let mut buf = [0u8; mem::size_of::<Header>()];
-    file.read_exact(&mut buf)?;
+pub fn parse(mut file: impl io::Read) -> Result<Index> {
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    parse_bytes(&buf)
+}
 
-    let header = buf.cast::<Header>();
-    let header = &header[0];
+/// Splits `len` bytes off the front of `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if data.len() < len {
+        return Err(Error::new(IndexError::Truncated));
+    }
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+    Ok(taken)
+}
+
+/// Reads an index out of a buffer holding the entire file, such as a memory
+/// map of it. Entry metadata is cast in place rather than copied through
+/// intermediate buffers.
+pub fn parse_bytes(mut data: &[u8]) -> Result<Index> {
+    let header = &take(&mut data, mem::size_of::<Header>())?.cast::<Header>()[0];
 
     trace!("index header {:?}", &header);
 
@@ -409,15 +858,10 @@ pub(crate) fn parse(mut file: impl io::Read) -> Result<Index> {
     }
 
     let num_entries = u32::from(header.num_entries) as usize;
-    let mut name = Vec::new();
-
-    let mut buf = [0u8; mem::size_of::<IndexMeta>()];
     let mut files = Vec::with_capacity(num_entries);
 
     for _ in 0..num_entries {
-        file.read_exact(&mut buf)?;
-        let entry = buf.cast::<IndexMeta>();
-        let meta: &IndexMeta = &entry[0];
+        let meta = &take(&mut data, mem::size_of::<IndexMeta>())?.cast::<IndexMeta>()[0];
 
         // bottom 12 bits of flags is name size
         let flags: u16 = meta.flags.into();
@@ -427,41 +871,81 @@ pub(crate) fn parse(mut file: impl io::Read) -> Result<Index> {
             unimplemented!("name is >0xfff characters long. unsupported");
         }
 
-        let record_sz = name_record_size(name_length);
-
-        // we deliberately choose to keep the vector at the size of the longest name
-        if name.len() < record_sz {
-            name.resize_with(record_sz, Default::default);
-        }
-
-        file.read_exact(&mut name[..record_sz])?;
+        let name = take(&mut data, name_record_size(name_length))?;
         files.push(IndexEntry {
-            name: std::str::from_utf8(&name[..name_length])?.to_string(),
+            name: name[..name_length].into(),
             meta: meta.clone(),
         });
     }
 
-    Ok(files)
+    // extensions follow the entries, up to the trailing checksum
+    let mut cache_tree = None;
+    while data.len() > 20 {
+        let mut signature = [0u8; 4];
+        signature.copy_from_slice(take(&mut data, 4)?);
+        let mut size = [0u8; 4];
+        size.copy_from_slice(take(&mut data, 4)?);
+        let mut ext = take(&mut data, u32::from_be_bytes(size) as usize)?;
+
+        match &signature {
+            b"TREE" => {
+                let (_, tree) = CacheTree::parse(&mut ext)?;
+                cache_tree = Some(tree);
+            }
+            // extensions starting with a capital letter are optional and may
+            // be ignored
+            [b'A'..=b'Z', ..] => trace!("skipping index extension {:?}", &signature),
+            _ => return Err(Error::new(IndexError::BadExtension(signature))),
+        }
+    }
+
+    Ok(Index {
+        entries: files,
+        cache_tree,
+    })
 }
 
 /// Converts a SystemTime object to a (secs, nsecs) tuple of time since the Unix
 /// epoch
-fn system_time_to_epoch(systime: time::SystemTime) -> Result<(u32, u32)> {
-    let dur = systime.duration_since(time::UNIX_EPOCH)?;
-    Ok((dur.as_secs() as u32, dur.subsec_nanos()))
+fn system_time_to_epoch(systime: time::SystemTime) -> (u32, u32) {
+    // like git, only the bottom 32 bits of the seconds are kept, so times after
+    // 2106 or before 1970 wrap around. The index is only ever compared with
+    // times truncated the same way, so that's fine as long as nobody has files
+    // exactly 136 years apart.
+    match systime.duration_since(time::UNIX_EPOCH) {
+        Ok(dur) => (dur.as_secs() as u32, dur.subsec_nanos()),
+        Err(e) => {
+            // -1.25s is stored as -2s + 0.75s, as it would be in a timespec
+            let before = e.duration();
+            let mut secs = -(before.as_secs() as i64);
+            let mut nanos = before.subsec_nanos();
+            if nanos != 0 {
+                secs -= 1;
+                nanos = 1_000_000_000 - nanos;
+            }
+            (secs as u32, nanos)
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{IndexEntry, IndexMeta};
+    use super::{
+        insert_entry, remove_entry, system_time_to_epoch, CacheTree, Index, IndexEntry, IndexMeta,
+    };
+    use crate::objects::{Blob, FileMode, Id, Repo};
+    use crate::vfs::{MemoryFs, Vfs};
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
     const TEST_INDEX: &[u8] = include_bytes!("testdata/test_index");
     const TEST_INDEX_TREE: &[u8] = include_bytes!("testdata/test_index_tree");
 
     #[test]
     fn test_index() {
-        let index = vec![
+        let index = Index::from(vec![
             IndexEntry {
-                name: "item1".to_string(),
+                name: "item1".into(),
                 meta: IndexMeta {
                     ctime: 0x5e9bf1c6.into(),
                     ctime_ns: 0x26545c10.into(),
@@ -478,7 +962,7 @@ mod tests {
                 },
             },
             IndexEntry {
-                name: "item2".to_string(),
+                name: "item2".into(),
                 meta: IndexMeta {
                     ctime: 0x5e9bf1c9.into(),
                     ctime_ns: 0xb204508.into(),
@@ -494,7 +978,7 @@ mod tests {
                     flags: 0x5.into(),
                 },
             },
-        ];
+        ]);
 
         let mut idx_buf = Vec::new();
 
@@ -504,14 +988,16 @@ mod tests {
 
         let parsed = super::parse(TEST_INDEX).unwrap();
         assert_eq!(index, parsed);
+
+        // cutting off the last entry's name should be caught
+        assert!(super::parse_bytes(&TEST_INDEX[..TEST_INDEX.len() - 30]).is_err());
     }
 
     #[test]
-    #[ignore = "not yet implemented; need to add TREE extension first"]
     fn test_index_tree() {
-        let index = vec![
+        let mut index = Index::from(vec![
             IndexEntry {
-                name: "dir/item".to_string(),
+                name: "dir/item".into(),
                 meta: IndexMeta {
                     ctime: 0x5e9bc19e.into(),
                     ctime_ns: 0x217b3358.into(),
@@ -528,7 +1014,7 @@ mod tests {
                 },
             },
             IndexEntry {
-                name: "file2".to_string(),
+                name: "file2".into(),
                 meta: IndexMeta {
                     ctime: 0x5e9bbee2.into(),
                     ctime_ns: 0x1b0f3be0.into(),
@@ -544,11 +1030,162 @@ mod tests {
                     flags: 0x5.into(),
                 },
             },
-        ];
+        ]);
+        // git writes an invalid root after adding files
+        index.cache_tree = Some(CacheTree::default());
 
         let mut idx_buf = Vec::new();
 
         super::write_to_file(&index, &mut idx_buf).unwrap();
         assert_eq!(idx_buf, TEST_INDEX_TREE);
+        assert_eq!(super::parse(TEST_INDEX_TREE).unwrap(), index);
+    }
+
+    #[test]
+    fn test_cache_tree() {
+        let mut root = CacheTree::default();
+        let id = super::Id::from("107f41d5f9e9ea48ff6a312917c9bb029cf9d2b6").unwrap();
+        root.id = Some(id);
+        root.entry_count = 3;
+        for name in &["src", "a", "docs"] {
+            let child = root.child_mut(name.as_bytes());
+            child.id = Some(id);
+            child.entry_count = 1;
+        }
+        root.child_mut(b"src").child_mut(b"bin").id = Some(id);
+        let names = root
+            .children
+            .iter()
+            .map(|(n, _)| n.as_slice())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![&b"a"[..], b"src", b"docs"]);
+
+        let mut buf = Vec::new();
+        root.encode(b"", &mut buf);
+        let (name, parsed) = CacheTree::parse(&mut &buf[..]).unwrap();
+        assert_eq!(name, "");
+        assert_eq!(parsed, root);
+
+        root.invalidate(b"src/bin/main.rs");
+        assert_eq!(root.id, None);
+        assert_eq!(root.find(b"src").unwrap().id, None);
+        assert_eq!(root.find(b"src/bin").unwrap().id, None);
+        assert_eq!(root.find(b"docs").unwrap().id, Some(id));
+    }
+
+    #[test]
+    fn test_for_blob() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let id = repo.store(&Blob::new(b"hi\n".to_vec())).unwrap();
+
+        let mut entry = IndexEntry {
+            name: "script".into(),
+            meta: IndexMeta::for_blob(b"script", id, FileMode::Executable),
+        };
+        assert_eq!(entry.meta.file_mode(), FileMode::Executable);
+        assert_eq!(u16::from(entry.meta.flags), 6);
+        assert_eq!(entry.meta.stage(), 0);
+        entry.meta.set_stage(3);
+        assert_eq!(
+            (entry.meta.stage(), u16::from(entry.meta.flags)),
+            (3, 0x3006)
+        );
+        entry.meta.set_stage(0);
+        // with no stats to go on, the file is hashed
+        fs.write(Path::new("/repo/script"), b"hi\n").unwrap();
+        assert!(entry.is_same_as_tree(&repo).unwrap());
+        fs.write(Path::new("/repo/script"), b"bye\n").unwrap();
+        assert!(!entry.is_same_as_tree(&repo).unwrap());
+    }
+
+    #[test]
+    fn test_conflict_stages() {
+        let id = Id::from("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let entry = |name: &str, stage| {
+            let mut meta = IndexMeta::for_blob(name.as_bytes(), id, FileMode::Regular);
+            meta.set_stage(stage);
+            IndexEntry {
+                name: name.into(),
+                meta,
+            }
+        };
+        let mut index = Index::from(vec![
+            entry("a", 0),
+            entry("b", 1),
+            entry("b", 2),
+            entry("b", 3),
+            entry("c", 0),
+        ]);
+        let stages = |index: &Index| {
+            index
+                .iter()
+                .map(|e| (e.name.to_string(), e.meta.stage()))
+                .collect::<Vec<_>>()
+        };
+
+        // adding the file resolves the conflict
+        insert_entry(&mut index, entry("b", 0));
+        assert_eq!(
+            stages(&index),
+            vec![("a".into(), 0), ("b".into(), 0), ("c".into(), 0)]
+        );
+        let mut index = Index::from(vec![entry("a", 2), entry("a", 3), entry("c", 0)]);
+        assert_eq!(remove_entry(&mut index, b"a").unwrap().meta.stage(), 2);
+        assert_eq!(stages(&index), vec![("c".into(), 0)]);
+        assert!(remove_entry(&mut index, b"b").is_none());
+    }
+
+    #[test]
+    fn test_untracked_files() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        for dir in &[
+            "/repo/src",
+            "/repo/new/deeper",
+            "/repo/empty",
+            "/repo/target",
+        ] {
+            fs.create_dir_all(Path::new(dir)).unwrap();
+        }
+        for file in &[
+            "/repo/tracked",
+            "/repo/src.c",
+            "/repo/src/lib.rs",
+            "/repo/src/main.rs",
+            "/repo/new/deeper/file",
+            "/repo/target/out",
+        ] {
+            fs.write(Path::new(file), b"content\n").unwrap();
+        }
+        fs.write(Path::new("/repo/.gitignore"), b"target/\n*.o\n")
+            .unwrap();
+        fs.write(Path::new("/repo/src/lib.o"), b"").unwrap();
+        let mut index = Index::new();
+        for name in &["tracked", "src.c", "src/lib.rs", ".gitignore"] {
+            super::add_to_index(&mut index, name.as_bytes(), &repo).unwrap();
+        }
+
+        let untracked = super::untracked_files(&index, &repo).unwrap();
+        assert_eq!(untracked, vec!["new/", "src/main.rs"]);
+
+        fs.remove_file(Path::new("/repo/tracked")).unwrap();
+        let deleted = index
+            .iter()
+            .filter(|ie| ie.is_deleted(&repo).unwrap())
+            .map(|ie| ie.name.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(deleted, vec!["tracked"]);
+    }
+
+    #[test]
+    fn test_system_time_to_epoch() {
+        let t = UNIX_EPOCH + Duration::new(0x5e9bf1c6, 123);
+        assert_eq!(system_time_to_epoch(t), (0x5e9bf1c6, 123));
+        // the year 2200 wraps around
+        let t = UNIX_EPOCH + Duration::new((1 << 32) + 5, 0);
+        assert_eq!(system_time_to_epoch(t), (5, 0));
+        let t = UNIX_EPOCH - Duration::new(1, 250_000_000);
+        assert_eq!(system_time_to_epoch(t), (-2i32 as u32, 750_000_000));
     }
 }
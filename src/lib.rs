@@ -0,0 +1,64 @@
+//! A Git implementation in Rust, mostly for fun
+//!
+//! The object, index, tree and diff logic lives in this library so it can be
+//! used without the command line frontend, including on `wasm32` targets where
+//! repositories have to be accessed through an in-memory [`vfs::Vfs`].
+#![feature(is_sorted)]
+#![feature(str_strip)]
+#![deny(missing_docs, unused_qualifications)]
+#[cfg(not(target_arch = "wasm32"))]
+pub mod apply;
+pub mod archive;
+pub mod bitmap;
+pub mod blame;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod checkout;
+pub mod cleanup;
+pub mod commit_graph;
+pub mod config;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod daemon;
+pub mod date;
+pub mod diff;
+pub mod fast_export;
+pub mod fast_import;
+pub mod fetch;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod fsck;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod fsmonitor;
+pub mod hooks;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod http;
+pub mod ignore;
+pub mod index;
+pub mod maintenance;
+pub mod merge;
+pub mod num;
+pub mod objects;
+pub mod packs;
+pub mod patch;
+pub mod patch_id;
+pub mod pktline;
+pub mod pretty;
+pub mod push;
+pub mod rebase;
+pub mod receive_pack;
+pub mod reflog;
+pub mod reftable;
+pub mod remote;
+pub mod rev;
+pub mod revwalk;
+pub mod trace;
+pub mod trailers;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod transport;
+pub mod tree;
+pub mod upload_pack;
+pub mod util;
+pub mod vfs;
+
+#[macro_use]
+extern crate log;
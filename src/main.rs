@@ -1,22 +1,15 @@
-//! A Git implementation in Rust, mostly for fun
-#![feature(is_sorted)]
-#![feature(str_strip)]
+//! A Git implementation in Rust, mostly for fun. This is the command line
+//! frontend to the rgit library.
 #![deny(missing_docs, unused_qualifications)]
 mod args;
 mod commands;
-mod diff;
-pub mod index;
-pub mod num;
-pub mod objects;
-pub mod rev;
-pub mod tree;
-pub mod util;
 
 use anyhow::{Context, Result};
 use args::SubCommand;
 use clap::Clap;
 
-use crate::objects::Id;
+use rgit::objects::Id;
+use rgit::trace;
 
 #[macro_use]
 extern crate log;
@@ -24,21 +17,65 @@ extern crate log;
 /// The actual main function, wrapped to use results.
 fn do_main(opts: args::Opts) -> Result<()> {
     match opts.subcmd {
-        SubCommand::Add(a) => commands::add(a.files),
-        SubCommand::Commit(c) => commands::commit(c.who, c.message),
+        SubCommand::Add(a) => commands::add(a.files, a.update),
+        SubCommand::Am(a) => commands::am(a),
+        SubCommand::Apply(a) => commands::apply(a),
+        SubCommand::Archive(a) => commands::archive(a),
+        SubCommand::Blame(b) => commands::blame(b),
+        SubCommand::Checkout(c) => commands::checkout(c),
+        SubCommand::Cherry(c) => commands::cherry(c),
+        SubCommand::Commit(c) => commands::commit(c),
+        SubCommand::Daemon(d) => commands::daemon(d),
         SubCommand::Diff(d) => commands::diff(d),
+        SubCommand::Fetch(f) => commands::fetch(f),
+        SubCommand::Filter(f) => commands::filter(f),
+        SubCommand::FormatPatch(f) => commands::format_patch(f),
+        SubCommand::Fsck(f) => commands::fsck(f),
+        SubCommand::Gc(g) => commands::gc(g),
+        SubCommand::Grep(g) => commands::grep(g),
         SubCommand::Init => commands::init(),
-        SubCommand::Status => commands::status(),
+        SubCommand::InterpretTrailers(i) => commands::interpret_trailers(i),
+        SubCommand::Log(l) => commands::log(l),
+        SubCommand::Maintenance(m) => commands::maintenance(m),
+        SubCommand::Merge(m) => commands::merge(m),
+        SubCommand::Push(p) => commands::push(p),
+        SubCommand::Rebase(r) => commands::rebase(r),
+        SubCommand::Reflog(r) => commands::reflog(r),
+        SubCommand::Repack(r) => commands::repack(r),
+        SubCommand::Replace(r) => commands::replace(r),
+        SubCommand::Reset(r) => commands::reset(r),
+        SubCommand::Restore(r) => commands::restore(r),
+        SubCommand::Rm(r) => commands::rm(r),
+        SubCommand::Show(s) => commands::show(s),
+        SubCommand::Status(s) => commands::status(s),
+        SubCommand::Switch(s) => commands::switch(s),
+        SubCommand::Tag(t) => commands::tag(t),
         // plumbing
-        SubCommand::CatFile(cf) => commands::catfile(&cf.git_ref, cf.output),
+        SubCommand::CatFile(cf) => commands::catfile(cf),
+        SubCommand::CheckIgnore(c) => commands::check_ignore(c),
+        SubCommand::CheckRefFormat(c) => commands::check_ref_format(c),
         SubCommand::CommitTree(c) => {
             let id = Id::from(&c.id).context("invalid ID format")?;
             commands::commit_tree(id, c.who, c.message)
         }
-        SubCommand::Debug(ty) => commands::debug(ty.what),
+        SubCommand::Debug(ty) => commands::debug(ty.what, ty.json),
+        SubCommand::FastExport(_) => commands::fast_export(),
+        SubCommand::FastImport(f) => commands::fast_import(f),
+        SubCommand::HashObject(h) => commands::hash_object(h),
+        SubCommand::IndexPack(i) => commands::index_pack(i),
+        SubCommand::LsTree(l) => commands::ls_tree(l),
         SubCommand::NewTree(m) => commands::new_tree(m.paths),
-        SubCommand::RevParse(r) => commands::rev_parse(r.rev),
-        SubCommand::UpdateRef(ur) => commands::update_ref(ur.target_ref, ur.new_id),
+        SubCommand::PackObjects(p) => commands::pack_objects(p),
+        SubCommand::PackRefs(p) => commands::pack_refs(p),
+        SubCommand::PatchId(p) => commands::patch_id(p),
+        SubCommand::RevList(r) => commands::rev_list(r),
+        SubCommand::RevParse(r) => commands::rev_parse(r),
+        SubCommand::Stripspace(s) => commands::stripspace(s),
+        SubCommand::ShowRef(s) => commands::show_ref(s),
+        SubCommand::SymbolicRef(s) => commands::symbolic_ref(s),
+        SubCommand::UnpackObjects(u) => commands::unpack_objects(u),
+        SubCommand::UpdateIndex(u) => commands::update_index(u),
+        SubCommand::UpdateRef(ur) => commands::update_ref(ur.target_ref, ur.new_id, ur.message),
     }
 }
 
@@ -56,14 +93,38 @@ fn main() {
         .init()
         .unwrap();
 
+    // don't leave lock files behind that would stop the next command
+    if let Err(e) = ctrlc::set_handler(|| {
+        rgit::cleanup::remove_all();
+        trace::exit(130);
+        // what a shell reports for a process killed by SIGINT
+        std::process::exit(130);
+    }) {
+        warn!("failed to install interrupt handler: {}", e);
+    }
+
+    // set for the whole process, hooks included, like git does
+    if opts.no_replace_objects {
+        std::env::set_var("GIT_NO_REPLACE_OBJECTS", "1");
+    }
+
+    trace::start(&std::env::args().collect::<Vec<_>>());
     match do_main(opts) {
-        Ok(_) => (), // success
+        Ok(_) => trace::exit(0),
         Err(e) => {
-            if verbose < 1 {
-                eprintln!("Error: {:#}", e);
-            } else {
-                eprintln!("Error verbose: {:?}", e);
-            }
+            let code = match e.downcast_ref::<commands::Exit>() {
+                Some(commands::Exit(code)) => *code,
+                None if verbose < 1 => {
+                    eprintln!("Error: {:#}", e);
+                    1
+                }
+                None => {
+                    eprintln!("Error verbose: {:?}", e);
+                    1
+                }
+            };
+            trace::exit(code);
+            std::process::exit(code);
         }
     }
 }
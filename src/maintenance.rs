@@ -0,0 +1,881 @@
+//! Housekeeping that keeps a repository fast as it grows: packing loose
+//! objects and refs, writing the commit-graph, expiring old reflog entries
+//! and pruning objects nothing refers to any more. These are run by hand with
+//! `gc` or `maintenance run`, or automatically after commands that make
+//! objects once there are enough loose ones to be worth packing, as decided
+//! by `gc.auto`.
+use anyhow::{Context, Result};
+use bstr::BString;
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::time::SystemTime;
+use thiserror::Error;
+
+use crate::commit_graph;
+use crate::date::approxidate;
+use crate::objects::{Id, ObjectType, Repo};
+use crate::packs;
+use crate::reflog::ReflogEntry;
+use crate::rev;
+use crate::revwalk::{self, FoundObject, RevWalk};
+use crate::util;
+
+/// How many loose objects there may be before automatic maintenance packs
+/// them, if `gc.auto` doesn't say
+const DEFAULT_AUTO_LIMIT: i64 = 6700;
+/// How long unreachable objects are kept, if `gc.pruneExpire` doesn't say
+const DEFAULT_PRUNE_EXPIRE: &str = "2.weeks.ago";
+/// How long reflog entries are kept, if `gc.reflogExpire` doesn't say
+const DEFAULT_REFLOG_EXPIRE: &str = "90.days.ago";
+/// How long reflog entries for commits that are no longer in the history of
+/// their ref are kept, if `gc.reflogExpireUnreachable` doesn't say
+const DEFAULT_REFLOG_EXPIRE_UNREACHABLE: &str = "30.days.ago";
+/// Mode of submodule commits in the index
+const GITLINK_MODE: u32 = 0o160000;
+
+/// Errors from running maintenance
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MaintenanceError {
+    /// A task name that isn't one of the tasks
+    #[error("'{0}' is not a valid task")]
+    UnknownTask(String),
+}
+
+/// Something maintenance can do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// All of the other tasks, then expire reflog entries and prune old
+    /// unreachable objects
+    Gc,
+    /// Write the commit-graph of every commit reachable from a ref
+    CommitGraph,
+    /// Pack loose objects, and remove those that are packed already
+    LooseObjects,
+    /// Move loose refs into `packed-refs`
+    PackRefs,
+}
+
+impl FromStr for Task {
+    type Err = MaintenanceError;
+
+    fn from_str(s: &str) -> Result<Task, MaintenanceError> {
+        match s {
+            "gc" => Ok(Task::Gc),
+            "commit-graph" => Ok(Task::CommitGraph),
+            "loose-objects" => Ok(Task::LooseObjects),
+            "pack-refs" => Ok(Task::PackRefs),
+            _ => Err(MaintenanceError::UnknownTask(s.to_string())),
+        }
+    }
+}
+
+/// Lists the loose objects in the repository
+fn loose_ids(repo: &Repo) -> Result<Vec<Id>> {
+    let vfs = repo.vfs();
+    let objects_dir = repo.objects_dir();
+    let mut ids = Vec::new();
+    for dir in vfs.read_dir(&objects_dir)? {
+        let prefix = match dir.to_str() {
+            Some(prefix) if prefix.len() == 2 => prefix,
+            _ => continue,
+        };
+        let dir_path = objects_dir.join(prefix);
+        if !vfs.is_dir(&dir_path) {
+            continue;
+        }
+        for name in vfs.read_dir(&dir_path)? {
+            if let Some(id) = name.to_str().and_then(|n| Id::from(&[prefix, n].concat())) {
+                ids.push(id);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// Packs the loose objects into a new pack and removes them, along with any
+/// that were already packed. Returns how many objects were newly packed.
+pub fn pack_loose_objects(repo: &Repo) -> Result<usize> {
+    let vfs = repo.vfs();
+    let objects_dir = repo.objects_dir();
+    let loose = loose_ids(repo)?;
+    let mut unpacked = Vec::new();
+    for id in loose {
+        if repo.packs().contains(&id, &objects_dir, vfs)? {
+            vfs.remove_file(&repo.path_for_object(&id))?;
+        } else {
+            unpacked.push(id);
+        }
+    }
+
+    if !unpacked.is_empty() {
+        let mut objects = Vec::with_capacity(unpacked.len());
+        for id in &unpacked {
+            objects.push(FoundObject {
+                id: *id,
+                kind: repo.object_header(id)?.0,
+                path: Default::default(),
+            });
+        }
+        packs::store_pack(repo, &objects)?;
+        for id in &unpacked {
+            vfs.remove_file(&repo.path_for_object(id))?;
+        }
+    }
+    repo.objects_changed();
+    Ok(unpacked.len())
+}
+
+/// The commits the refs and HEAD point to
+fn ref_tips(repo: &Repo) -> Result<Vec<Id>> {
+    let mut tips: Vec<Id> = rev::list_refs(repo)?
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect();
+    // HEAD may be detached, or on a branch with no commits yet
+    if let Ok(head) = repo.head() {
+        tips.push(head);
+    }
+    tips.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    tips.dedup();
+
+    let mut commits = Vec::with_capacity(tips.len());
+    for id in tips {
+        if repo.object_header(&id)?.0 == ObjectType::Commit {
+            commits.push(id);
+        }
+    }
+    Ok(commits)
+}
+
+/// Writes the commit-graph of everything reachable from the refs, returning
+/// how many commits are in it. Nothing is written if there are no commits.
+pub fn write_commit_graph(repo: &Repo) -> Result<usize> {
+    let tips = ref_tips(repo)?;
+    if tips.is_empty() {
+        return Ok(0);
+    }
+    commit_graph::write(repo, &tips)
+}
+
+/// Works out when things older than the expiry time in the config key `key`
+/// should go. Gives nothing if they should never go.
+pub fn expiry(
+    repo: &Repo,
+    key: &str,
+    default: &str,
+    now: DateTime<FixedOffset>,
+) -> Result<Option<DateTime<FixedOffset>>> {
+    let config = repo.config()?;
+    let value = config.get(key).unwrap_or(default);
+    parse_expiry(value, now).with_context(|| format!("bad value for {}", key))
+}
+
+/// Parses an expiry time as the config and `--expire` options take it: a
+/// date, `all` or `now` for everything, or `never` or `false` for nothing
+pub fn parse_expiry(
+    value: &str,
+    now: DateTime<FixedOffset>,
+) -> Result<Option<DateTime<FixedOffset>>> {
+    match value {
+        "never" | "false" => Ok(None),
+        "all" | "now" => Ok(Some(now)),
+        _ => Ok(Some(approxidate(value, now)?)),
+    }
+}
+
+/// When reflog entries should be expired, from `gc.reflogExpire`, and when
+/// entries for commits that have left the history of their ref should be,
+/// from `gc.reflogExpireUnreachable`
+pub fn reflog_expiry(
+    repo: &Repo,
+    now: DateTime<FixedOffset>,
+) -> Result<(Option<DateTime<FixedOffset>>, Option<DateTime<FixedOffset>>)> {
+    Ok((
+        expiry(repo, "gc.reflogExpire", DEFAULT_REFLOG_EXPIRE, now)?,
+        expiry(
+            repo,
+            "gc.reflogExpireUnreachable",
+            DEFAULT_REFLOG_EXPIRE_UNREACHABLE,
+            now,
+        )?,
+    ))
+}
+
+/// When unreachable objects should be pruned, from `gc.pruneExpire`
+pub fn prune_expiry(
+    repo: &Repo,
+    now: DateTime<FixedOffset>,
+) -> Result<Option<DateTime<FixedOffset>>> {
+    expiry(repo, "gc.pruneExpire", DEFAULT_PRUNE_EXPIRE, now)
+}
+
+/// Lists the reflogs in the repository, by the name of their ref
+pub fn reflog_names(repo: &Repo) -> Result<Vec<String>> {
+    let vfs = repo.vfs();
+    let logs = repo.root.join("logs");
+    let mut names = Vec::new();
+    if vfs.exists(&logs.join("HEAD")) {
+        names.push("HEAD".to_string());
+    }
+    let mut dirs = vec!["refs".to_string()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match vfs.read_dir(&logs.join(&dir)) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let name = match entry.to_str() {
+                Some(name) => format!("{}/{}", dir, name),
+                None => continue,
+            };
+            if vfs.is_dir(&logs.join(&name)) {
+                dirs.push(name);
+            } else if !name.ends_with(".lock") {
+                names.push(name);
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Reads the entries of a reflog, along with the lines they are from.
+/// Lines that can't be parsed have no entry.
+fn read_reflog(repo: &Repo, name: &str) -> Result<Vec<(Vec<u8>, Option<ReflogEntry>)>> {
+    let content = repo.vfs().read(&repo.root.join("logs").join(name))?;
+    let mut lines = Vec::new();
+    let mut rest = &content[..];
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(rest.len(), |n| n + 1);
+        let (line, next) = rest.split_at(end);
+        lines.push((line.to_vec(), ReflogEntry::parse(line)));
+        rest = next;
+    }
+    Ok(lines)
+}
+
+/// Removes entries from the reflogs of the refs `names` from before `expire`,
+/// and entries from before `expire_unreachable` that are for commits no
+/// longer in the history of their ref. Returns how many entries were removed.
+pub fn expire_reflogs(
+    repo: &Repo,
+    names: &[String],
+    expire: Option<DateTime<FixedOffset>>,
+    expire_unreachable: Option<DateTime<FixedOffset>>,
+) -> Result<usize> {
+    let mut removed = 0;
+    for name in names {
+        let lines = read_reflog(repo, name)?;
+        // the history is only walked if there is something old enough to
+        // need it
+        let mut history: Option<HashSet<Id>> = None;
+        let mut kept = Vec::new();
+        for (line, entry) in &lines {
+            let entry = match entry {
+                Some(entry) => entry,
+                None => {
+                    kept.extend_from_slice(line);
+                    continue;
+                }
+            };
+            if expire.map_or(false, |expire| entry.who.time < expire) {
+                removed += 1;
+                continue;
+            }
+            if expire_unreachable.map_or(false, |expire| entry.who.time < expire) {
+                if history.is_none() {
+                    let tip = if name == "HEAD" {
+                        repo.head().ok()
+                    } else {
+                        rev::parse(name, repo).ok()
+                    };
+                    history = Some(match tip {
+                        Some(tip) if repo.has_id(&tip) => {
+                            RevWalk::new(repo, &[tip])?.collect::<Result<_>>()?
+                        }
+                        _ => HashSet::new(),
+                    });
+                }
+                if !history.as_ref().unwrap().contains(&entry.new) {
+                    removed += 1;
+                    continue;
+                }
+            }
+            kept.extend_from_slice(line);
+        }
+        if kept.len() != lines.iter().map(|(line, _)| line.len()).sum::<usize>() {
+            repo.replace_file(&repo.root.join("logs").join(name), &kept)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// Something that keeps the objects reachable from it from being pruned
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Root {
+    /// A ref, or HEAD
+    Ref(String),
+    /// An entry in the reflog of a ref
+    Reflog(String),
+    /// A file in the index
+    Index(BString),
+}
+
+impl fmt::Display for Root {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Root::Ref(name) => write!(f, "{}", name),
+            Root::Reflog(name) => write!(f, "reflog of {}", name),
+            Root::Index(path) => write!(f, "{} in the index", path),
+        }
+    }
+}
+
+/// Lists what the refs, HEAD, the reflogs and the index point to, which are
+/// the objects everything that must be kept is reachable from. Reflogs can
+/// name objects that are long gone, so what is listed may not exist.
+pub fn roots(repo: &Repo) -> Result<Vec<(Root, Id)>> {
+    let mut roots: Vec<(Root, Id)> = rev::list_refs(repo)?
+        .into_iter()
+        .map(|(name, id)| (Root::Ref(name), id))
+        .collect();
+    if let Ok(head) = repo.head() {
+        roots.push((Root::Ref("HEAD".to_string()), head));
+    }
+    let zero = Id::from_bytes([0; 20]);
+    for name in reflog_names(repo)? {
+        for (_, entry) in read_reflog(repo, &name)? {
+            if let Some(entry) = entry {
+                for id in &[entry.old, entry.new] {
+                    if *id != zero {
+                        roots.push((Root::Reflog(name.clone()), *id));
+                    }
+                }
+            }
+        }
+    }
+    if let Ok(index) = repo.index() {
+        roots.extend(
+            index
+                .entries
+                .iter()
+                .filter(|entry| u32::from(entry.meta.mode) != GITLINK_MODE)
+                .map(|entry| (Root::Index(entry.name.clone()), entry.meta.id)),
+        );
+    }
+    Ok(roots)
+}
+
+/// Finds every object reachable from the refs, HEAD, the reflogs and the
+/// index, which are the objects that must never be pruned
+fn reachable_objects(repo: &Repo) -> Result<Vec<FoundObject>> {
+    let mut tips: Vec<Id> = roots(repo)?.into_iter().map(|(_, id)| id).collect();
+    tips.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+    tips.dedup();
+    tips.retain(|id| repo.has_id(id));
+    revwalk::reachable_objects(repo, &tips, &[])
+}
+
+/// Deletes the unreachable loose objects from before `expire`, and temporary
+/// files left behind by commands that were interrupted
+fn prune_unreachable(
+    repo: &Repo,
+    reachable: &HashSet<Id>,
+    expire: DateTime<FixedOffset>,
+) -> Result<usize> {
+    let vfs = repo.vfs();
+    let expire = SystemTime::from(expire);
+    let mut pruned = 0;
+    for id in loose_ids(repo)? {
+        if reachable.contains(&id) {
+            continue;
+        }
+        let path = repo.path_for_object(&id);
+        if vfs.metadata(&path)?.modified < expire {
+            vfs.remove_file(&path)?;
+            pruned += 1;
+        }
+    }
+    for dir in &[repo.objects_dir(), repo.objects_dir().join("pack")] {
+        for name in vfs.read_dir(dir).unwrap_or_default() {
+            let path = dir.join(&name);
+            let is_temp = name.to_str().map_or(false, |n| n.starts_with("tmp_"));
+            if is_temp && vfs.metadata(&path)?.modified < expire {
+                vfs.remove_file(&path)?;
+            }
+        }
+    }
+    repo.objects_changed();
+    Ok(pruned)
+}
+
+/// Deletes loose objects that aren't reachable from anything and were last
+/// changed before `expire`. Objects that are newer may be in use by a command
+/// that is running, which hasn't pointed a ref at them yet. Returns how many
+/// objects were deleted.
+pub fn prune(repo: &Repo, expire: DateTime<FixedOffset>) -> Result<usize> {
+    let reachable = reachable_objects(repo)?
+        .into_iter()
+        .map(|obj| obj.id)
+        .collect();
+    prune_unreachable(repo, &reachable, expire)
+}
+
+/// What [`repack`] packs, and what it deletes once it has
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepackOptions {
+    /// Pack every reachable object into the new pack, rather than only those
+    /// that are loose
+    pub all: bool,
+    /// Delete what the new pack makes redundant: with `all`, every other
+    /// pack, except those with a `.keep` file, and otherwise just loose
+    /// objects that are packed. Unreachable objects in deleted packs are lost.
+    pub delete: bool,
+    /// Keep the unreachable objects in packs deleted by `all` and `delete`
+    /// that were changed since this time, as loose objects, so that they are
+    /// only pruned once they are old enough
+    pub loosen_since: Option<DateTime<FixedOffset>>,
+}
+
+/// Packs some of the `reachable` objects into a new pack, as `options` say.
+/// Returns the name of the pack, if one was made.
+fn repack_objects(
+    repo: &Repo,
+    reachable: &[FoundObject],
+    options: RepackOptions,
+) -> Result<Option<Id>> {
+    let vfs = repo.vfs();
+    let objects_dir = repo.objects_dir();
+    let dir = objects_dir.join("pack");
+    let reachable_ids: HashSet<Id> = reachable.iter().map(|obj| obj.id).collect();
+    let mut old_packs = Vec::new();
+    let mut kept = HashSet::new();
+    if options.all {
+        for name in vfs.read_dir(&dir).unwrap_or_default() {
+            let idx = dir.join(name);
+            if idx.extension().map_or(true, |ext| ext != "idx") {
+                continue;
+            }
+            let pack = packs::Pack::open(&idx, vfs)?;
+            if vfs.exists(&idx.with_extension("keep")) {
+                kept.extend(pack.ids());
+                continue;
+            }
+            if let (true, Some(since)) = (options.delete, options.loosen_since) {
+                if vfs.metadata(pack.path())?.modified >= SystemTime::from(since) {
+                    for id in pack.ids() {
+                        if !reachable_ids.contains(&id) {
+                            let (kind, content) = repo.read_object(&id)?;
+                            repo.store_raw(kind, &content)?;
+                        }
+                    }
+                }
+            }
+            old_packs.push(idx);
+        }
+    }
+
+    let mut objects = Vec::new();
+    for obj in reachable {
+        let packed = if options.all {
+            kept.contains(&obj.id)
+        } else {
+            repo.packs().contains(&obj.id, &objects_dir, vfs)?
+        };
+        if !packed {
+            objects.push(obj.clone());
+        }
+    }
+    let name = if objects.is_empty() {
+        None
+    } else {
+        Some(packs::store_pack(repo, &objects)?)
+    };
+    if !options.delete {
+        return Ok(name);
+    }
+
+    let new_idx = name.map(|name| dir.join(format!("pack-{}.idx", name)));
+    for idx in old_packs {
+        // the same objects make the same pack
+        if Some(&idx) == new_idx.as_ref() {
+            continue;
+        }
+        // the index goes first, since packs are found by their indexes
+        for ext in &["idx", "pack", "bitmap", "rev"] {
+            let path = idx.with_extension(ext);
+            if vfs.exists(&path) {
+                vfs.remove_file(&path)?;
+            }
+        }
+    }
+    repo.objects_changed();
+    for id in loose_ids(repo)? {
+        if repo.packs().contains(&id, &objects_dir, vfs)? {
+            vfs.remove_file(&repo.path_for_object(&id))?;
+        }
+    }
+    repo.objects_changed();
+    Ok(name)
+}
+
+/// Packs the objects reachable from the refs, HEAD, the reflogs and the
+/// index into a new pack, as `options` say. Unreachable objects are never
+/// packed. Returns the name of the pack, if there was anything to pack.
+pub fn repack(repo: &Repo, options: RepackOptions) -> Result<Option<Id>> {
+    let reachable = reachable_objects(repo)?;
+    repack_objects(repo, &reachable, options)
+}
+
+/// Collects garbage: packs refs, expires reflog entries as set by
+/// `gc.reflogExpire` and `gc.reflogExpireUnreachable`, and writes the
+/// commit-graph. With `prune_expire`, everything reachable is repacked into
+/// one pack and unreachable objects from before then are deleted. Without
+/// it, nothing is deleted and only loose objects are packed.
+pub fn gc(
+    repo: &Repo,
+    prune_expire: Option<DateTime<FixedOffset>>,
+    now: DateTime<FixedOffset>,
+) -> Result<()> {
+    rev::pack_refs(true, repo)?;
+    let (expire, expire_unreachable) = reflog_expiry(repo, now)?;
+    expire_reflogs(repo, &reflog_names(repo)?, expire, expire_unreachable)?;
+    match prune_expire {
+        Some(expire) => {
+            let reachable = reachable_objects(repo)?;
+            let ids = reachable.iter().map(|obj| obj.id).collect();
+            prune_unreachable(repo, &ids, expire)?;
+            repack_objects(
+                repo,
+                &reachable,
+                RepackOptions {
+                    all: true,
+                    delete: true,
+                    loosen_since: Some(expire),
+                },
+            )?;
+        }
+        None => {
+            pack_loose_objects(repo)?;
+        }
+    }
+    write_commit_graph(repo)?;
+    Ok(())
+}
+
+/// Runs one maintenance task
+pub fn run_task(repo: &Repo, task: Task) -> Result<()> {
+    match task {
+        Task::Gc => {
+            let now = util::now();
+            gc(repo, prune_expiry(repo, now)?, now)?;
+        }
+        Task::CommitGraph => {
+            write_commit_graph(repo)?;
+        }
+        Task::LooseObjects => {
+            pack_loose_objects(repo)?;
+        }
+        Task::PackRefs => {
+            rev::pack_refs(true, repo)?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether there are enough loose objects for automatic maintenance to run.
+/// Like git, this guesses from how many there are in `objects/17`, which
+/// should hold about 1/256 of them. Setting `gc.auto` to 0 or
+/// `maintenance.auto` to false turns automatic maintenance off.
+pub fn needs_auto(repo: &Repo) -> Result<bool> {
+    let config = repo.config()?;
+    if config.get_bool("maintenance.auto")? == Some(false) {
+        return Ok(false);
+    }
+    let limit = config.get_int("gc.auto")?.unwrap_or(DEFAULT_AUTO_LIMIT);
+    if limit <= 0 {
+        return Ok(false);
+    }
+    let threshold = ((limit + 255) / 256) as usize;
+
+    let sample = repo.objects_dir().join("17");
+    if !repo.vfs().is_dir(&sample) {
+        return Ok(false);
+    }
+    let count = repo
+        .vfs()
+        .read_dir(&sample)?
+        .iter()
+        .filter(|name| name.to_str().map_or(false, |n| n.len() == 38))
+        .count();
+    Ok(count > threshold)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        expire_reflogs, gc, needs_auto, pack_loose_objects, prune, repack, run_task, RepackOptions,
+        Task,
+    };
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+    use crate::packs::Pack;
+    use crate::rev;
+    use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+    use std::ffi::OsString;
+    use std::path::Path;
+
+    #[test]
+    fn test_task_names() {
+        assert_eq!("commit-graph".parse::<Task>(), Ok(Task::CommitGraph));
+        assert_eq!("pack-refs".parse::<Task>(), Ok(Task::PackRefs));
+        assert!("incremental-repack".parse::<Task>().is_err());
+    }
+
+    #[test]
+    fn test_gc() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let blob = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let tree = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Regular,
+                    name: "hello".into(),
+                    id: blob,
+                }],
+            })
+            .unwrap();
+        let commit = repo
+            .store(&Commit {
+                tree,
+                parents: vec![],
+                author: who.clone(),
+                committer: who,
+                message: "commit\n".to_string(),
+            })
+            .unwrap();
+        rev::update_ref(Path::new("refs/heads/master"), &commit, "", &repo).unwrap();
+
+        run_task(&repo, Task::Gc).unwrap();
+        let vfs = repo.vfs();
+        for id in &[blob, tree, commit] {
+            assert!(!vfs.exists(&repo.path_for_object(id)));
+            assert!(repo.has_id(id));
+        }
+        assert_eq!(repo.read_object(&blob).unwrap().1, b"hello\n");
+        assert!(vfs.exists(Path::new("/repo/.git/objects/info/commit-graph")));
+        assert!(vfs.exists(Path::new("/repo/.git/packed-refs")));
+        assert!(!vfs.exists(Path::new("/repo/.git/refs/heads/master")));
+        assert_eq!(rev::parse("master", &repo).unwrap(), commit);
+
+        // nothing left to pack
+        assert_eq!(pack_loose_objects(&repo).unwrap(), 0);
+    }
+
+    /// Stores a commit of an empty tree
+    fn commit(repo: &Repo, parents: Vec<Id>, message: &str) -> Id {
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = repo.store(&Tree { files: Vec::new() }).unwrap();
+        repo.store(&Commit {
+            tree,
+            parents,
+            author: who.clone(),
+            committer: who,
+            message: message.to_string(),
+        })
+        .unwrap()
+    }
+
+    /// A time this many seconds after 1970, which is when MemoryFs's clock
+    /// starts
+    fn seconds(n: i64) -> DateTime<FixedOffset> {
+        FixedOffset::east(0).timestamp(n, 0)
+    }
+
+    #[test]
+    fn test_prune() {
+        let repo = Repo::in_memory();
+        let vfs = repo.vfs();
+        let head = commit(&repo, vec![], "head\n");
+        rev::update_ref(Path::new("refs/heads/master"), &head, "", &repo).unwrap();
+        // only a reflog knows about this one
+        let lost = commit(&repo, vec![], "lost\n");
+        vfs.create_dir_all(Path::new("/repo/.git/logs")).unwrap();
+        vfs.write(
+            Path::new("/repo/.git/logs/HEAD"),
+            format!(
+                "{} {} a <a@example.com> 1586391037 -0700\tcommit: lost\n",
+                Id::from_bytes([0; 20]),
+                lost
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let old = repo.store(&Blob::new(b"old\n".to_vec())).unwrap();
+        let old_path = repo.path_for_object(&old);
+        let old_time = DateTime::<Utc>::from(vfs.metadata(&old_path).unwrap().modified);
+        let new = repo.store(&Blob::new(b"new\n".to_vec())).unwrap();
+
+        let expire = seconds(old_time.timestamp() + 1);
+        assert_eq!(prune(&repo, expire).unwrap(), 1);
+        assert!(!repo.has_id(&old));
+        for id in &[new, head, lost] {
+            assert!(repo.has_id(id));
+        }
+
+        // pruning everything that's unreachable, then packing the rest. The
+        // reflog entry is recent enough to be kept
+        gc(&repo, Some(seconds(1 << 40)), seconds(1586391037)).unwrap();
+        assert!(!repo.has_id(&new));
+        assert!(repo.has_id(&lost));
+        assert!(!vfs.exists(&repo.path_for_object(&head)));
+
+        // unreachable objects in packs that are still new are kept loose
+        let packed = repo.store(&Blob::new(b"packed\n".to_vec())).unwrap();
+        pack_loose_objects(&repo).unwrap();
+        gc(&repo, Some(seconds(1)), seconds(1586391037)).unwrap();
+        assert!(vfs.exists(&repo.path_for_object(&packed)));
+
+        // once it expires, so does the commit
+        gc(&repo, Some(seconds(1 << 40)), seconds(1 << 40)).unwrap();
+        assert!(!repo.has_id(&lost));
+        assert!(!repo.has_id(&packed));
+    }
+
+    #[test]
+    fn test_repack() {
+        let repo = Repo::in_memory();
+        let vfs = repo.vfs();
+        let pack_dir = repo.objects_dir().join("pack");
+        let indexes = || {
+            let mut names: Vec<_> = vfs
+                .read_dir(&pack_dir)
+                .unwrap()
+                .into_iter()
+                .filter(|name| name.to_str().unwrap().ends_with(".idx"))
+                .collect();
+            names.sort();
+            names
+        };
+        let first = commit(&repo, vec![], "first\n");
+        rev::update_ref(Path::new("refs/heads/master"), &first, "", &repo).unwrap();
+        pack_loose_objects(&repo).unwrap();
+        let second = commit(&repo, vec![first], "second\n");
+        rev::update_ref(Path::new("refs/heads/master"), &second, "", &repo).unwrap();
+        let unreachable = repo.store(&Blob::new(b"nobody\n".to_vec())).unwrap();
+
+        // only the loose commit is packed, and nothing is deleted
+        let name = repack(&repo, RepackOptions::default()).unwrap().unwrap();
+        assert_eq!(indexes().len(), 2);
+        let idx = pack_dir.join(format!("pack-{}.idx", name));
+        let pack = Pack::open(&idx, vfs).unwrap();
+        assert_eq!(pack.ids().collect::<Vec<_>>(), vec![second]);
+        assert!(vfs.exists(&repo.path_for_object(&second)));
+
+        let options = RepackOptions {
+            all: true,
+            delete: true,
+            loosen_since: None,
+        };
+        let name = repack(&repo, options).unwrap().unwrap();
+        assert_eq!(
+            indexes(),
+            vec![OsString::from(format!("pack-{}.idx", name))]
+        );
+        let pack = Pack::open(&pack_dir.join(format!("pack-{}.idx", name)), vfs).unwrap();
+        // two commits and their shared tree
+        assert_eq!(pack.len(), 3);
+        assert!(!vfs.exists(&repo.path_for_object(&second)));
+        assert!(vfs.exists(&repo.path_for_object(&unreachable)));
+
+        // packing the same objects again makes the same pack, which is kept
+        assert_eq!(repack(&repo, options).unwrap(), Some(name));
+        assert_eq!(indexes().len(), 1);
+        assert!(repo.has_id(&first));
+    }
+
+    #[test]
+    fn test_expire_reflogs() {
+        let repo = Repo::in_memory();
+        let vfs = repo.vfs();
+        let a = commit(&repo, vec![], "a\n");
+        let b = commit(&repo, vec![a], "b\n");
+        let other = commit(&repo, vec![], "other\n");
+        rev::update_ref(Path::new("refs/heads/master"), &b, "", &repo).unwrap();
+
+        let line = |old: &Id, new: &Id, time: i64| {
+            format!(
+                "{} {} a <a@example.com> {} +0000\tmessage\n",
+                old, new, time
+            )
+        };
+        let zero = Id::from_bytes([0; 20]);
+        let log = Path::new("/repo/.git/logs/refs/heads/master");
+        vfs.create_dir_all(log.parent().unwrap()).unwrap();
+        let content = [
+            line(&zero, &a, 100),
+            line(&a, &b, 5000),
+            // was reset away from, so isn't in the history of master
+            line(&b, &other, 200),
+            line(&other, &b, 9000),
+        ];
+        vfs.write(log, content.concat().as_bytes()).unwrap();
+        let name = "refs/heads/master";
+        let names = super::reflog_names(&repo).unwrap();
+        assert_eq!(names, vec!["HEAD".to_string(), name.to_string()]);
+
+        assert_eq!(
+            expire_reflogs(&repo, &names, Some(seconds(150)), Some(seconds(300))).unwrap(),
+            2
+        );
+        assert_eq!(
+            vfs.read(log).unwrap(),
+            [content[1].clone(), content[3].clone()].concat().as_bytes()
+        );
+        assert_eq!(expire_reflogs(&repo, &names, None, None).unwrap(), 0);
+
+        // only the reflogs given are expired
+        let head_log = repo.vfs().read(Path::new("/repo/.git/logs/HEAD")).unwrap();
+        assert_eq!(
+            expire_reflogs(&repo, &["HEAD".to_string()], Some(seconds(9001)), None).unwrap(),
+            0
+        );
+        assert_eq!(
+            vfs.read(log).unwrap().len(),
+            content[1].len() + content[3].len()
+        );
+        assert_eq!(
+            vfs.read(Path::new("/repo/.git/logs/HEAD")).unwrap(),
+            head_log
+        );
+        assert_eq!(
+            expire_reflogs(&repo, &[name.to_string()], Some(seconds(9001)), None).unwrap(),
+            2
+        );
+        assert!(vfs.read(log).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_needs_auto() {
+        let repo = Repo::in_memory();
+        repo.vfs()
+            .write(Path::new("/repo/.git/config"), b"[gc]\n\tauto = 512\n")
+            .unwrap();
+        assert!(!needs_auto(&repo).unwrap());
+
+        // two objects are allowed in objects/17, but not three
+        let dir = repo.objects_dir().join("17");
+        repo.vfs().create_dir_all(&dir).unwrap();
+        for n in 0..3 {
+            let id = Id::from(&format!("17{:038}", n)).unwrap();
+            repo.vfs().write(&repo.path_for_object(&id), b"").unwrap();
+            assert_eq!(needs_auto(&repo).unwrap(), n == 2);
+        }
+    }
+}
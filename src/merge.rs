@@ -0,0 +1,535 @@
+//! Three-way merges: finding the commit two lines of history split from, and
+//! putting together the changes made on each side since. Trees are merged
+//! file by file, and files changed on both sides line by line. Changes that
+//! overlap are conflicts, which are written out between conflict markers for
+//! someone to sort out, while the index keeps each side's version of the file
+//! as stages 1 to 3.
+//!
+//! Where there is more than one best merge base, as after merging two
+//! branches into each other, the newest is used rather than merging them
+//! together first as git does.
+use anyhow::{anyhow, Result};
+use bstr::{BString, ByteSlice};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use crate::diff::{self, Edit};
+use crate::index::{Index, IndexEntry, IndexMeta};
+use crate::objects::{Blob, FileMode, Id, Object, Repo};
+use crate::revwalk::RevWalk;
+use crate::tree;
+
+/// Finds the best common ancestors of two commits: those both can reach that
+/// aren't ancestors of another such commit. There are none if the histories
+/// are unrelated, and usually one. They are sorted newest first.
+pub fn merge_bases(a: &Id, b: &Id, repo: &Repo) -> Result<Vec<Id>> {
+    let ours = RevWalk::new(repo, &[*a])?.collect::<Result<HashSet<_>>>()?;
+    let mut common = Vec::new();
+    for id in RevWalk::new(repo, &[*b])? {
+        let id = id?;
+        if ours.contains(&id) {
+            common.push(id);
+        }
+    }
+    // everything an ancestor of either can reach is common too, so a common
+    // commit is only beaten by being the parent of another
+    let mut beaten = HashSet::new();
+    for id in &common {
+        match &*repo.open_shared(id)? {
+            Object::Commit(commit) => beaten.extend(commit.parents.iter().copied()),
+            _ => return Err(anyhow!("{} is not a commit", id)),
+        }
+    }
+    common.retain(|id| !beaten.contains(id));
+    Ok(common)
+}
+
+/// The result of merging the content of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMerge {
+    /// The merged file, with conflict markers around the parts that conflict
+    pub content: Vec<u8>,
+    /// How many conflicts there are
+    pub conflicts: usize,
+}
+
+/// Maps each line of `base` to the line of `other` it is kept as, if it is
+fn matching_lines<'a>(base: &'a [&'a [u8]], other: &'a [&'a [u8]]) -> Vec<Option<usize>> {
+    let mut matches = vec![None; base.len()];
+    let (mut i, mut j) = (0, 0);
+    for edit in diff::myers_diff(base, other) {
+        match edit {
+            Edit::Nop(_) => {
+                matches[i] = Some(j);
+                i += 1;
+                j += 1;
+            }
+            Edit::Del(_) => i += 1,
+            Edit::Ins(_) => j += 1,
+        }
+    }
+    matches
+}
+
+/// Adds a newline to a merged file unless it is empty or ends in one, so
+/// that a conflict marker after a last line with no newline is on its own
+fn end_line(out: &mut Vec<u8>) {
+    if !out.is_empty() && !out.ends_with(b"\n") {
+        out.push(b'\n');
+    }
+}
+
+/// Merges the changes made to the text `base` in `ours` and in `theirs`.
+/// Where the two both changed the same lines differently, both versions are
+/// kept between conflict markers labelled with `labels`, as git does:
+///
+/// ```text
+/// <<<<<<< ours
+/// our lines
+/// =======
+/// their lines
+/// >>>>>>> theirs
+/// ```
+pub fn merge_text(base: &[u8], ours: &[u8], theirs: &[u8], labels: (&str, &str)) -> TextMerge {
+    let (base, ours, theirs) = (diff::lines(base), diff::lines(ours), diff::lines(theirs));
+    let in_ours = matching_lines(&base, &ours);
+    let in_theirs = matching_lines(&base, &theirs);
+
+    let mut out = Vec::new();
+    let mut conflicts = 0;
+    let (mut i, mut j, mut k) = (0, 0, 0);
+    loop {
+        // lines neither side changed
+        while i < base.len() && in_ours[i] == Some(j) && in_theirs[i] == Some(k) {
+            out.extend_from_slice(base[i]);
+            i += 1;
+            j += 1;
+            k += 1;
+        }
+        if i == base.len() && j == ours.len() && k == theirs.len() {
+            break;
+        }
+
+        // the changes run up to the next line both sides kept
+        let (next_i, next_j, next_k) =
+            match (i..base.len()).find_map(|n| Some((n, in_ours[n]?, in_theirs[n]?))) {
+                Some(next) => next,
+                None => (base.len(), ours.len(), theirs.len()),
+            };
+        let (old, mine, yours) = (&base[i..next_i], &ours[j..next_j], &theirs[k..next_k]);
+        if mine == old {
+            out.extend(yours.concat());
+        } else if yours == old || mine == yours {
+            out.extend(mine.concat());
+        } else {
+            conflicts += 1;
+            out.extend_from_slice(format!("<<<<<<< {}\n", labels.0).as_bytes());
+            out.extend(mine.concat());
+            end_line(&mut out);
+            out.extend_from_slice(b"=======\n");
+            out.extend(yours.concat());
+            end_line(&mut out);
+            out.extend_from_slice(format!(">>>>>>> {}\n", labels.1).as_bytes());
+        }
+        i = next_i;
+        j = next_j;
+        k = next_k;
+    }
+    TextMerge {
+        content: out,
+        conflicts,
+    }
+}
+
+/// Why a path couldn't be merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// Both sides changed the same part of the file
+    Content,
+    /// Both sides added the file, with different content
+    AddAdd,
+    /// One side changed the file and the other deleted it
+    ModifyDelete,
+}
+
+impl fmt::Display for ConflictKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ConflictKind::Content => "content",
+            ConflictKind::AddAdd => "add/add",
+            ConflictKind::ModifyDelete => "modify/delete",
+        })
+    }
+}
+
+/// A file's ID and mode, or None where there is no such file
+type Side = Option<(Id, FileMode)>;
+
+/// How a path came out of a merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Merged {
+    /// The path merged cleanly to this, or was deleted
+    Clean(Side),
+    /// The path has to be merged by hand
+    Conflict {
+        /// Why it couldn't be merged
+        kind: ConflictKind,
+        /// The file in the merge base, ours and theirs
+        stages: [Side; 3],
+        /// What is left in the working tree: the file with conflict markers,
+        /// or the side that wasn't deleted
+        worktree: Side,
+    },
+}
+
+/// The result of merging two trees
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeMerge {
+    /// Every path the merge leaves a file at or has a conflict for, sorted
+    pub files: Vec<(BString, Merged)>,
+}
+
+impl TreeMerge {
+    /// The paths with conflicts, and why
+    pub fn conflicts(&self) -> Vec<(&BString, ConflictKind)> {
+        self.files
+            .iter()
+            .filter_map(|(path, merged)| match merged {
+                Merged::Conflict { kind, .. } => Some((path, *kind)),
+                Merged::Clean(_) => None,
+            })
+            .collect()
+    }
+
+    /// The files to put in the working tree, sorted
+    pub fn worktree_files(&self) -> Vec<(BString, Id, FileMode)> {
+        self.files
+            .iter()
+            .filter_map(|(path, merged)| match merged {
+                Merged::Clean(side) | Merged::Conflict { worktree: side, .. } => {
+                    side.map(|(id, mode)| (path.clone(), id, mode))
+                }
+            })
+            .collect()
+    }
+
+    /// Makes an index of the result, where conflicts have an entry for each
+    /// side that has the file
+    pub fn to_index(&self) -> Index {
+        let mut entries = Vec::new();
+        let mut add = |path: &BString, (id, mode): (Id, FileMode), stage| {
+            let mut meta = IndexMeta::for_blob(path, id, mode);
+            meta.set_stage(stage);
+            entries.push(IndexEntry {
+                name: path.clone(),
+                meta,
+            });
+        };
+        for (path, merged) in &self.files {
+            match merged {
+                Merged::Clean(Some(side)) => add(path, *side, 0),
+                Merged::Clean(None) => (),
+                Merged::Conflict { stages, .. } => {
+                    for (stage, side) in (1..).zip(stages.iter()) {
+                        if let Some(side) = side {
+                            add(path, *side, stage);
+                        }
+                    }
+                }
+            }
+        }
+        entries.into()
+    }
+}
+
+/// Lists the files in a tree by path
+fn files(tree: Option<&Id>, repo: &Repo) -> Result<BTreeMap<BString, (Id, FileMode)>> {
+    Ok(tree::changed_files(None, tree, repo)?
+        .into_iter()
+        .filter_map(|change| Some((change.path, change.new?)))
+        .collect())
+}
+
+/// Merges one path
+fn merge_file(
+    [base, ours, theirs]: [Side; 3],
+    labels: (&str, &str),
+    repo: &Repo,
+) -> Result<Merged> {
+    if ours == theirs || base == theirs {
+        return Ok(Merged::Clean(ours));
+    }
+    if base == ours {
+        return Ok(Merged::Clean(theirs));
+    }
+    let stages = [base, ours, theirs];
+    let (ours, theirs) = match (ours, theirs) {
+        (Some(ours), Some(theirs)) => (ours, theirs),
+        // the one left is what was changed
+        _ => {
+            return Ok(Merged::Conflict {
+                kind: ConflictKind::ModifyDelete,
+                stages,
+                worktree: ours.or(theirs),
+            })
+        }
+    };
+    let kind = match base {
+        Some(_) => ConflictKind::Content,
+        None => ConflictKind::AddAdd,
+    };
+    let conflict = Merged::Conflict {
+        kind,
+        stages,
+        worktree: Some(ours),
+    };
+
+    // only the content of plain files can be merged
+    let text = |mode| mode == FileMode::Regular || mode == FileMode::Executable;
+    if !text(ours.1) || !text(theirs.1) || base.map_or(false, |(_, mode)| !text(mode)) {
+        return Ok(conflict);
+    }
+    let read = |id: &Id| -> Result<Vec<u8>> { Ok(repo.read_object(id)?.1) };
+    let old = match base {
+        Some((id, _)) => read(&id)?,
+        None => Vec::new(),
+    };
+    let (mine, yours) = (read(&ours.0)?, read(&theirs.0)?);
+    if diff::is_binary(&old) || diff::is_binary(&mine) || diff::is_binary(&yours) {
+        return Ok(conflict);
+    }
+
+    // a change of mode on one side wins, as for content
+    let mode = match base {
+        Some((_, mode)) if mode == ours.1 => theirs.1,
+        _ => ours.1,
+    };
+    let merged = merge_text(&old, &mine, &yours, labels);
+    let id = repo.store(&Blob::new(merged.content))?;
+    if merged.conflicts == 0 {
+        Ok(Merged::Clean(Some((id, mode))))
+    } else {
+        Ok(Merged::Conflict {
+            kind,
+            stages,
+            worktree: Some((id, mode)),
+        })
+    }
+}
+
+/// Merges the changes made from the tree `base` in `ours` and in `theirs`.
+/// With no base, as for histories that share no commits, everything both
+/// sides have is taken as added by both. `labels` name the two sides in
+/// conflict markers. Merged files with or without conflict markers are
+/// stored as blobs.
+pub fn merge_trees(
+    base: Option<&Id>,
+    ours: &Id,
+    theirs: &Id,
+    labels: (&str, &str),
+    repo: &Repo,
+) -> Result<TreeMerge> {
+    let sides = [
+        files(base, repo)?,
+        files(Some(ours), repo)?,
+        files(Some(theirs), repo)?,
+    ];
+    let paths = sides
+        .iter()
+        .flat_map(|side| side.keys())
+        .collect::<HashSet<_>>();
+    let mut paths = paths.into_iter().collect::<Vec<_>>();
+    paths.sort();
+
+    let mut merged = Vec::with_capacity(paths.len());
+    for path in paths {
+        let versions = [
+            sides[0].get(path).copied(),
+            sides[1].get(path).copied(),
+            sides[2].get(path).copied(),
+        ];
+        let file = merge_file(versions, labels, repo)?;
+        if file != Merged::Clean(None) {
+            merged.push((path.clone(), file));
+        }
+    }
+
+    // a file on one side can't also be a directory on the other
+    let result = TreeMerge { files: merged };
+    let kept = result.worktree_files();
+    for pair in kept.windows(2) {
+        let (file, next) = (&pair[0].0, &pair[1].0);
+        if next.starts_with(file) && next.get(file.len()) == Some(&b'/') {
+            return Err(anyhow!(
+                "can't merge {}, which is a file on one side and a directory on the other",
+                file.as_bstr()
+            ));
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_bases, merge_text, merge_trees, ConflictKind, Merged};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+
+    #[test]
+    fn test_merge_bases() {
+        let repo = Repo::in_memory();
+        let tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let commit = |parents: Vec<Id>, time: u32| {
+            let who = NameEntry::from(&format!("a <a@example.com> {} +0000", time)).unwrap();
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: format!("{}\n", time),
+            })
+            .unwrap()
+        };
+        let root = commit(vec![], 100);
+        let a1 = commit(vec![root], 200);
+        let b1 = commit(vec![root], 210);
+        assert_eq!(merge_bases(&a1, &b1, &repo).unwrap(), vec![root]);
+        assert_eq!(merge_bases(&a1, &root, &repo).unwrap(), vec![root]);
+
+        // each merged into the other leaves two bases
+        let a2 = commit(vec![a1, b1], 300);
+        let b2 = commit(vec![b1, a1], 310);
+        assert_eq!(merge_bases(&a2, &b2, &repo).unwrap(), vec![b1, a1]);
+
+        let unrelated = commit(vec![], 400);
+        assert!(merge_bases(&a2, &unrelated, &repo).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_merge_text() {
+        let base = b"1\n2\n3\n4\n5\n6\n7\n";
+        let merge = |ours: &[u8], theirs: &[u8]| {
+            let merged = merge_text(base, ours, theirs, ("HEAD", "topic"));
+            (String::from_utf8(merged.content).unwrap(), merged.conflicts)
+        };
+
+        // changes apart from each other
+        assert_eq!(
+            merge(
+                b"one\n2\n3\n4\n5\n6\n7\n",
+                b"1\n2\n3\n4\n5\n6\nseven\neight\n"
+            ),
+            ("one\n2\n3\n4\n5\n6\nseven\neight\n".to_string(), 0)
+        );
+        assert_eq!(
+            merge(b"1\n3\n4\n5\n6\n7\n", b"1\n2\n3\n4\n5\nsix\n7\n"),
+            ("1\n3\n4\n5\nsix\n7\n".to_string(), 0)
+        );
+        // the same change on both sides
+        assert_eq!(
+            merge(b"1\n2\nthree\n4\n5\n6\n7\n", b"1\n2\nthree\n4\n5\n6\n7\n"),
+            ("1\n2\nthree\n4\n5\n6\n7\n".to_string(), 0)
+        );
+        assert_eq!(
+            merge(b"1\n2\n3\nfour\n5\n6\n7\n", b"1\n2\n3\nFOUR\n5\n6\n7\n"),
+            (
+                "1\n2\n3\n<<<<<<< HEAD\nfour\n=======\nFOUR\n>>>>>>> topic\n5\n6\n7\n".to_string(),
+                1
+            )
+        );
+        // markers go on lines of their own
+        let merged = merge_text(b"a\n", b"b", b"c", ("HEAD", "topic"));
+        assert_eq!(
+            merged.content.as_slice(),
+            &b"<<<<<<< HEAD\nb\n=======\nc\n>>>>>>> topic\n"[..]
+        );
+    }
+
+    #[test]
+    fn test_merge_trees() {
+        let repo = Repo::in_memory();
+        let blob = |content: &str| repo.store(&Blob::new(content.as_bytes().to_vec())).unwrap();
+        let tree = |files: &[(&str, Id)]| {
+            let files = files
+                .iter()
+                .map(|(name, id)| File {
+                    mode: FileMode::Regular,
+                    name: (*name).into(),
+                    id: *id,
+                })
+                .collect();
+            repo.store(&Tree { files }).unwrap()
+        };
+        let file = |id| Some((id, FileMode::Regular));
+
+        let (lines, ours_lines) = (blob("1\n2\n3\n"), blob("one\n2\n3\n"));
+        let theirs_lines = blob("1\n2\nthree\n");
+        let (same, changed, other) = (blob("same\n"), blob("changed\n"), blob("other\n"));
+        let base = tree(&[("gone", same), ("lines", lines), ("kept", same)]);
+        let ours = tree(&[("lines", ours_lines), ("kept", changed), ("new", other)]);
+        let theirs = tree(&[("lines", theirs_lines), ("new", same)]);
+        let merged = merge_trees(Some(&base), &ours, &theirs, ("HEAD", "topic"), &repo).unwrap();
+
+        let both = blob("one\n2\nthree\n");
+        let new = blob("<<<<<<< HEAD\nother\n=======\nsame\n>>>>>>> topic\n");
+        assert_eq!(
+            merged.files,
+            vec![
+                (
+                    "kept".into(),
+                    Merged::Conflict {
+                        kind: ConflictKind::ModifyDelete,
+                        stages: [file(same), file(changed), None],
+                        worktree: file(changed),
+                    }
+                ),
+                ("lines".into(), Merged::Clean(file(both))),
+                (
+                    "new".into(),
+                    Merged::Conflict {
+                        kind: ConflictKind::AddAdd,
+                        stages: [None, file(other), file(same)],
+                        worktree: file(new),
+                    }
+                ),
+            ]
+        );
+        assert_eq!(
+            merged.conflicts(),
+            vec![
+                (&"kept".into(), ConflictKind::ModifyDelete),
+                (&"new".into(), ConflictKind::AddAdd)
+            ]
+        );
+
+        let index = merged.to_index();
+        let stages = index
+            .iter()
+            .map(|entry| (entry.name.to_string(), entry.meta.stage()))
+            .collect::<Vec<_>>();
+        let stage = |name: &str, stage| (name.to_string(), stage);
+        assert_eq!(
+            stages,
+            vec![
+                stage("kept", 1),
+                stage("kept", 2),
+                stage("lines", 0),
+                stage("new", 2),
+                stage("new", 3)
+            ]
+        );
+        assert_eq!(merged.worktree_files().len(), 3);
+
+        // a file where the other side has a directory
+        let dir = tree(&[("inner", same)]);
+        let with_dir = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Directory,
+                    name: "new".into(),
+                    id: dir,
+                }],
+            })
+            .unwrap();
+        assert!(merge_trees(Some(&base), &ours, &with_dir, ("HEAD", "topic"), &repo).is_err());
+    }
+}
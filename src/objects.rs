@@ -1,51 +1,249 @@
 //! A module to handle the on-disk storage of Git objects in a database
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, Context, Error, Result};
+use bstr::BString;
 use chrono::{DateTime, FixedOffset};
 use flate2::bufread::ZlibDecoder;
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use safecast::Safecast;
-use sha1::{Digest, Sha1};
+use serde::{Serialize, Serializer};
+use sha1collisiondetection::{Output, Sha1CD};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+#[cfg(not(target_arch = "wasm32"))]
 use std::env;
 use std::fmt;
+#[cfg(not(target_arch = "wasm32"))]
 use std::fs;
-use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
 use std::str;
+use std::sync::{Arc, RwLock};
+use thiserror::Error;
 
+use crate::cleanup::TempFile;
+use crate::config::Config;
 use crate::index;
 use crate::num;
+use crate::packs::Packs;
 use crate::rev;
-
-fn open_compressed(path: &Path) -> Result<impl Read> {
-    let file = fs::File::open(path).context("Failed to open compressed file")?;
-    let decoder = ZlibDecoder::new(BufReader::new(file));
-    Ok(decoder)
+use crate::trace;
+use crate::trailers::{self, Trailer};
+use crate::util::serialize_lossy;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::vfs::RealFs;
+use crate::vfs::Vfs;
+
+/// Opens a compressed file, or returns None if it doesn't exist
+fn open_compressed(path: &Path, vfs: &dyn Vfs) -> Result<Option<impl Read>> {
+    match vfs.open(path) {
+        Ok(file) => Ok(Some(ZlibDecoder::new(BufReader::new(file)))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("Failed to open compressed file"),
+    }
 }
 
 /// A Git on-disk object
 pub trait GitObject {
-    /// Encodes an object for storage.
-    fn encode(&self) -> Vec<u8>;
+    /// Encodes an object for storage, writing it to `out`. This is only the
+    /// content; the header is written separately.
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// Length in bytes of the encoded object. This goes in the header ahead of
+    /// the content so it must be exact. The default encodes the object to
+    /// count it, so objects that can cheaply know their length should override
+    /// it.
+    fn len_hint(&self) -> usize {
+        let mut counter = ByteCounter(0);
+        self.encode(&mut counter)
+            .expect("writing to a byte counter failed. wat.");
+        counter.0
+    }
+
+    /// Encodes an object into a new buffer
+    fn encode_to_vec(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(self.len_hint());
+        self.encode(&mut v)
+            .expect("writing to an in-memory buffer failed. wat.");
+        v
+    }
 
     /// Returns the tag for this object on-disk. For example, b"blob" for Blob
     /// objects.
     fn tag(&self) -> Vec<u8>;
 }
 
+/// A writer that throws away everything but how much was written
+struct ByteCounter(usize);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer that hashes everything passing through it
+struct HashingWriter<W: Write> {
+    hasher: Sha1CD,
+    inner: W,
+}
+
+impl<W: Write> HashingWriter<W> {
+    /// Starts hashing an object by writing its header
+    fn start(tag: &[u8], len: u64, inner: W) -> io::Result<HashingWriter<W>> {
+        let mut out = HashingWriter {
+            hasher: Sha1CD::default(),
+            inner,
+        };
+        out.write_all(tag)?;
+        write!(out, " {}\0", len)?;
+        Ok(out)
+    }
+
+    /// Finishes hashing, giving back the inner writer.
+    ///
+    /// Like C git, the object is hashed with collision detection, and objects
+    /// that look like they were made for a SHA-1 collision attack are rejected.
+    fn finish(self) -> Result<(Id, W)> {
+        let mut digest = Output::default();
+        let collision = self.hasher.finalize_cd(&mut digest);
+        let id = Id(digest.into());
+        if collision.is_err() {
+            return Err(Error::new(ObjectError::Collision(id)));
+        }
+        Ok((id, self.inner))
+    }
+}
+
+/// Hashes an object in its loose form of a header followed by the content
+fn hash_loose(raw: &[u8]) -> Result<Id> {
+    let mut out = HashingWriter {
+        hasher: Sha1CD::default(),
+        inner: io::sink(),
+    };
+    out.write_all(raw)?;
+    let (id, _) = out.finish()?;
+    Ok(id)
+}
+
+/// Writes a blob of `len` bytes read from `content` with its header to `out`,
+/// returning its ID
+fn write_blob_stream<W: Write>(len: u64, content: impl Read, out: W) -> Result<(Id, W)> {
+    let mut out = HashingWriter::start(b"blob", len, out)?;
+    let copied = io::copy(&mut content.take(len), &mut out)?;
+    if copied != len {
+        return Err(anyhow!(
+            "file shrank from {} to {} bytes while being read",
+            len,
+            copied
+        ));
+    }
+    out.finish()
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.write_all(&buf[..written])?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Errors that can arise while hashing objects
+#[derive(Error, Debug)]
+pub enum ObjectError {
+    /// The object has the characteristics of one half of a SHA-1 collision,
+    /// so it could be swapped for a different object with the same ID
+    #[error("SHA-1 of object {0} appears to be part of a collision attack")]
+    Collision(Id),
+
+    /// A tree entry has a mode that isn't any kind of file git knows about
+    #[error("Unknown file mode {0:o}")]
+    BadMode(u32),
+
+    /// An object read from disk doesn't hash to the ID it was asked for by
+    #[error("object {id} is corrupt: its content hashes to {actual}")]
+    CorruptObject {
+        /// The ID the object was read as
+        id: Id,
+        /// What its content hashes to
+        actual: Id,
+    },
+
+    /// Following the replacements of an object under refs/replace went on
+    /// for too long, most likely because they go round in a loop
+    #[error("replacements of object {0} nest too deeply")]
+    ReplaceDepth(Id),
+}
+
 /// The hash-based ID of a Git object. Can be used to find it on disk.
-#[derive(Safecast, Clone, Copy, PartialEq, Eq)]
+#[derive(Safecast, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct Id([u8; 20]);
 
-/// A repository, specifically, a .git directory
+/// A repository, specifically, a .git directory. This is `Send + Sync` so one
+/// instance can be shared between threads.
 pub struct Repo {
     /// path to the root of the .git directory
     pub root: PathBuf,
+    /// filesystem the repo and its working tree live on
+    vfs: Arc<dyn Vfs>,
+    /// parsed objects, shared between all users of this repo
+    cache: ObjectCache,
+    /// packfiles in the object database
+    packs: Packs,
+    /// sorted IDs of every object, listed the first time they're needed
+    all_ids: RwLock<Option<Arc<[Id]>>>,
+    /// whether objects are hashed again as they are read, to check they are
+    /// what was asked for
+    verify_objects: bool,
+    /// whether objects with a replacement under refs/replace are opened as
+    /// their replacement
+    replace_objects: bool,
+    /// replacements by the ID of the object they replace, read from
+    /// refs/replace the first time they're needed
+    replacements: RwLock<Option<Arc<HashMap<Id, Id>>>>,
+}
+
+/// A cache of parsed trees and commits. Objects are immutable once stored so
+/// entries never need invalidating, but the cache is dropped wholesale when it
+/// gets too big so walks over huge histories don't eat all the memory.
+#[derive(Default)]
+struct ObjectCache {
+    objects: RwLock<HashMap<Id, Arc<Object>>>,
+}
+
+impl ObjectCache {
+    const MAX_ENTRIES: usize = 4096;
+
+    fn get(&self, id: &Id) -> Option<Arc<Object>> {
+        self.objects
+            .read()
+            .expect("object cache lock poisoned")
+            .get(id)
+            .cloned()
+    }
+
+    fn insert(&self, id: Id, obj: Arc<Object>) {
+        let mut objects = self.objects.write().expect("object cache lock poisoned");
+        if objects.len() >= Self::MAX_ENTRIES {
+            objects.clear();
+        }
+        objects.insert(id, obj);
+    }
 }
 
 /// Parsed Author/Committer field on a commit
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 pub struct NameEntry {
     /// Name of the author
     pub name: String,
@@ -56,7 +254,7 @@ pub struct NameEntry {
 }
 
 /// An in-memory commit
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Commit {
     /// Id of the tree at this commit
     pub tree: Id,
@@ -72,16 +270,100 @@ pub struct Commit {
     pub message: String,
 }
 
+/// An in-memory annotated tag
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Tag {
+    /// Id of the tagged object, usually a commit
+    pub object: Id,
+    /// What kind of object is tagged
+    pub kind: ObjectType,
+    /// Name of the tag, without refs/tags/
+    pub name: String,
+    /// Who made the tag. Very old tags don't say.
+    pub tagger: Option<NameEntry>,
+    /// Tag message
+    pub message: String,
+}
+
+/// The kinds of thing git can store in a tree, which it encodes as a Unix file
+/// mode. The top bits are the file type and the bottom nine are permissions,
+/// but only 644 and 755 are permitted for files and the rest have none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileMode {
+    /// A normal file, 0o100644
+    Regular,
+    /// An executable file, 0o100755
+    Executable,
+    /// A symbolic link whose blob is the link target, 0o120000
+    Symlink,
+    /// A directory, 0o040000
+    Directory,
+    /// A submodule, referenced by its commit, 0o160000
+    Gitlink,
+}
+
+impl FileMode {
+    /// Is this a directory?
+    pub fn is_dir(self) -> bool {
+        self == FileMode::Directory
+    }
+
+    /// Is this something with its content in a blob, as opposed to a
+    /// directory or submodule?
+    pub fn is_blob(self) -> bool {
+        match self {
+            FileMode::Regular | FileMode::Executable | FileMode::Symlink => true,
+            FileMode::Directory | FileMode::Gitlink => false,
+        }
+    }
+}
+
+impl From<FileMode> for u32 {
+    fn from(mode: FileMode) -> u32 {
+        match mode {
+            FileMode::Regular => 0o100644,
+            FileMode::Executable => 0o100755,
+            FileMode::Symlink => 0o120000,
+            FileMode::Directory => 0o040000,
+            FileMode::Gitlink => 0o160000,
+        }
+    }
+}
+
+impl TryFrom<u32> for FileMode {
+    type Error = ObjectError;
+
+    /// Reads a mode as stored in a tree or the index. Like git, files with any
+    /// permissions are accepted, since old versions of git wrote modes such as
+    /// 0o100664, and are executable if the owner can execute them.
+    fn try_from(mode: u32) -> Result<FileMode, ObjectError> {
+        match mode >> 12 {
+            0o10 if mode & 0o100 != 0 => Ok(FileMode::Executable),
+            0o10 => Ok(FileMode::Regular),
+            0o12 => Ok(FileMode::Symlink),
+            0o04 => Ok(FileMode::Directory),
+            0o16 => Ok(FileMode::Gitlink),
+            _ => Err(ObjectError::BadMode(mode)),
+        }
+    }
+}
+
+impl Serialize for FileMode {
+    /// Serializes a file mode as an octal string like "100644", as git shows it
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:06o}", u32::from(*self)))
+    }
+}
+
 /// A file or directory in a Tree
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct File {
-    /// Mode of the file. Example: 0o100644. Only 644 and 755 are permitted. The
-    /// leading bits are git attributes related to symbolic links and other
-    /// special files. Normal files have a leading 0o100 and Unix permissions
-    /// depending on if they are executable. Directories have mode 0o040000
-    pub mode: u32,
-    /// UTF-8 encoded file name
-    pub name: String,
+    /// What kind of file this is
+    pub mode: FileMode,
+    /// File name. Usually UTF-8, but git allows any bytes other than NUL and
+    /// slash
+    #[serde(serialize_with = "serialize_lossy")]
+    pub name: BString,
     /// Id referencing the blob backing this file
     pub id: Id,
 }
@@ -89,21 +371,47 @@ pub struct File {
 /// In-memory tree. This is a Merkle tree of the actual filesystem tree where
 /// every directory is represented as a File object containing its entire
 /// subtree of arbitrary depth.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Tree {
     /// List of files/subtrees in this tree
     pub files: Vec<File>,
 }
 
 /// In-memory blob object. It's just a vector of bytes.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
 pub struct Blob {
-    /// Bytes of the represented blob
+    /// Bytes of the represented blob. Serialized as (lossy) UTF-8 text since
+    /// that is what consumers of serialized blobs want nearly all the time.
+    #[serde(serialize_with = "serialize_lossy")]
     content: Vec<u8>,
 }
 
+/// The type of an object as recorded in the header of its on-disk form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ObjectType {
+    /// A Tree of blobs and subtrees
+    Tree,
+    /// A blob (ordinary file)
+    Blob,
+    /// A commit with associated tree, message and author/committer
+    Commit,
+    /// An annotated tag
+    Tag,
+}
+
+/// What an object is, as found from its header alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectInfo {
+    /// The type of object
+    pub kind: ObjectType,
+    /// Length in bytes of its content
+    pub size: u64,
+}
+
 /// One of the object types resulting from loading an object from disk.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum Object {
     /// A Tree of blobs and subtrees
     Tree(Tree),
@@ -113,36 +421,175 @@ pub enum Object {
     Commit(Commit),
 }
 
+// There is no process environment or real filesystem on wasm32, so
+// repositories there must be opened through `with_vfs`/`init_with_vfs`
+#[cfg(not(target_arch = "wasm32"))]
 impl Repo {
-    /// Makes a new repo, trying to find a .git directory in children
+    /// Makes a new repo, trying to find a .git directory in the working
+    /// directory or its parents
     pub fn new() -> Option<Repo> {
         let cwd = env::current_dir().ok()?;
-        for dir in cwd.as_path().ancestors() {
+        Repo::discover(&cwd)
+    }
+
+    /// Finds the repo containing `start` by looking for a .git directory in it
+    /// or its parents
+    pub fn discover(start: &Path) -> Option<Repo> {
+        for dir in start.ancestors() {
             let dotgit = dir.join(".git");
             if dotgit.is_dir() {
                 trace!("found git repo {:?}", &dotgit);
-                return Some(Repo { root: dotgit });
+                return Some(Repo::at(dotgit));
             }
         }
         None
     }
 
+    /// Makes a repo for the given .git directory without checking that it is
+    /// one
+    pub fn at(root: PathBuf) -> Repo {
+        Repo::with_vfs(root, Arc::new(RealFs))
+    }
+
     /// Initializes a repo at `root/.git`
     pub fn init(tree_root: &Path) -> Result<Repo> {
+        Repo::init_with_vfs(tree_root, Arc::new(RealFs))
+    }
+}
+
+impl Repo {
+    /// Makes a repo for the given .git directory on some filesystem other
+    /// than the real one
+    pub fn with_vfs(root: PathBuf, vfs: Arc<dyn Vfs>) -> Repo {
+        Repo {
+            root,
+            vfs,
+            cache: Default::default(),
+            packs: Default::default(),
+            all_ids: Default::default(),
+            verify_objects: false,
+            replace_objects: env::var_os("GIT_NO_REPLACE_OBJECTS").is_none(),
+            replacements: Default::default(),
+        }
+    }
+
+    /// Sets whether objects are hashed again as they are read, so corruption
+    /// on disk is caught rather than handed out as though it were the object
+    /// asked for. This is off by default since hashing is not free.
+    pub fn set_verify_objects(&mut self, verify: bool) {
+        self.verify_objects = verify;
+    }
+
+    /// Sets whether objects that have a replacement under refs/replace are
+    /// read as the replacement. This is on unless GIT_NO_REPLACE_OBJECTS is set,
+    /// and should be turned off by anything that has to see history as it
+    /// really is, such as finding which objects are unreachable.
+    pub fn set_replace_objects(&mut self, replace: bool) {
+        self.replace_objects = replace;
+        // the cache may have replacements in it under the IDs they replace
+        self.cache = Default::default();
+    }
+
+    /// The object to open in place of `id`, which is `id` itself unless it
+    /// has been replaced under refs/replace. Replacements of replacements
+    /// are followed.
+    pub fn replacement(&self, id: &Id) -> Result<Id> {
+        // like git, which gives up after this many
+        const MAX_DEPTH: usize = 5;
+
+        if !self.replace_objects {
+            return Ok(*id);
+        }
+        let replacements = self.replacements()?;
+        let mut current = *id;
+        for _ in 0..MAX_DEPTH {
+            match replacements.get(&current) {
+                Some(next) => current = *next,
+                None => return Ok(current),
+            }
+        }
+        Err(Error::new(ObjectError::ReplaceDepth(*id)))
+    }
+
+    /// Reads the replacements under refs/replace, or gives the ones read
+    /// already
+    fn replacements(&self) -> Result<Arc<HashMap<Id, Id>>> {
+        if let Some(replacements) = &*self
+            .replacements
+            .read()
+            .expect("replacements lock poisoned")
+        {
+            return Ok(Arc::clone(replacements));
+        }
+
+        let replacements = Arc::new(
+            rev::list_refs(self)?
+                .into_iter()
+                .filter_map(|(name, new)| {
+                    let old = Id::from(name.strip_prefix("refs/replace/")?)?;
+                    Some((old, new))
+                })
+                .collect::<HashMap<_, _>>(),
+        );
+        *self
+            .replacements
+            .write()
+            .expect("replacements lock poisoned") = Some(Arc::clone(&replacements));
+        Ok(replacements)
+    }
+
+    /// Checks an object that was read against the ID it was read as, if
+    /// verification is on. `hash` hashes what was read.
+    fn verify(&self, id: &Id, hash: impl FnOnce() -> Result<Id>) -> Result<()> {
+        if !self.verify_objects {
+            return Ok(());
+        }
+        let actual = hash()?;
+        if actual != *id {
+            return Err(Error::new(ObjectError::CorruptObject { id: *id, actual }));
+        }
+        Ok(())
+    }
+
+    /// Initializes a repo at `root/.git` on the given filesystem
+    pub fn init_with_vfs(tree_root: &Path, vfs: Arc<dyn Vfs>) -> Result<Repo> {
         let root = tree_root.join(".git");
-        fs::create_dir(&root)?;
+        vfs.create_dir(&root)?;
 
-        fs::create_dir(root.join("refs"))?;
-        fs::create_dir(root.join("refs/heads"))?;
-        fs::create_dir(root.join("objects"))?;
+        vfs.create_dir(&root.join("refs"))?;
+        vfs.create_dir(&root.join("refs/heads"))?;
+        vfs.create_dir(&root.join("objects"))?;
 
-        fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(root.join("HEAD"))
-            .context("failed creating HEAD")?
-            .write_all(b"ref: refs/heads/master")?;
-        Ok(Repo { root: root.into() })
+        vfs.write(&root.join("HEAD"), b"ref: refs/heads/master")
+            .context("failed creating HEAD")?;
+        Ok(Repo::with_vfs(root, vfs))
+    }
+
+    /// Initializes a repo at `/repo/.git` on an empty filesystem in memory,
+    /// for tests
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> Repo {
+        Repo::in_memory_at(&Arc::new(crate::vfs::MemoryFs::new()), "/repo")
+    }
+
+    /// Initializes a repo at `tree_root/.git` on a filesystem in memory, which
+    /// other repos in the same test can share
+    #[cfg(test)]
+    pub(crate) fn in_memory_at(fs: &Arc<crate::vfs::MemoryFs>, tree_root: &str) -> Repo {
+        let tree_root = Path::new(tree_root);
+        fs.create_dir_all(tree_root).unwrap();
+        Repo::init_with_vfs(tree_root, fs.clone()).unwrap()
+    }
+
+    /// The filesystem this repo lives on
+    pub fn vfs(&self) -> &dyn Vfs {
+        &*self.vfs
+    }
+
+    /// Reads the configuration for this repo. This isn't cached, so callers
+    /// should hang on to it rather than calling this repeatedly.
+    pub fn config(&self) -> Result<Config> {
+        Config::load(self)
     }
 
     /// Get the path in the .git directory to access a given file.
@@ -155,9 +602,116 @@ impl Repo {
         path
     }
 
-    /// Opens an object of given ID for reading
-    pub fn open_object_raw(&self, id: &Id) -> Result<impl Read> {
-        open_compressed(&self.path_for_object(id))
+    /// The objects directory holding loose objects and packs
+    pub fn objects_dir(&self) -> PathBuf {
+        self.root.join("objects")
+    }
+
+    /// Finds the object to read for `id`, which is its replacement if it has
+    /// one (see [`Repo::replacement`]). Gives the ID of the object found, and
+    /// a stream of it if it is loose; otherwise it can only be in a pack.
+    /// Every reader goes through here, so they all agree on what an ID reads
+    /// as.
+    fn find_object(&self, id: &Id) -> Result<(Id, Option<impl Read + Send>)> {
+        let id = self.replacement(id)?;
+        let loose = open_compressed(&self.path_for_object(&id), self.vfs())
+            .with_context(|| format!("Failed to open object {} on disk", id))?;
+        Ok((id, loose))
+    }
+
+    /// Opens an object for reading like `open_object_raw`, also giving the ID
+    /// of the object read in its place if it has been replaced
+    fn open_object_found(&self, id: &Id) -> Result<(Id, Box<dyn Read + Send>)> {
+        let (id, loose) = self.find_object(id)?;
+        if let Some(loose) = loose {
+            return Ok((id, Box::new(loose)));
+        }
+
+        let (objtype, content) = self
+            .packs
+            .read(&id, &self.objects_dir(), self.vfs())?
+            .ok_or_else(|| anyhow!("Object {} does not exist", id))?;
+        let mut buf = format!("{} {}\0", objtype, content.len()).into_bytes();
+        buf.extend_from_slice(&content);
+        Ok((id, Box::new(io::Cursor::new(buf))))
+    }
+
+    /// Opens an object of given ID for reading. The stream is of the object
+    /// as it is stored loose: a header followed by the content. Like all the
+    /// ways of reading objects, this gives the replacement of an object that
+    /// has one, unless that has been turned off with
+    /// [`Repo::set_replace_objects`].
+    pub fn open_object_raw(&self, id: &Id) -> Result<Box<dyn Read + Send>> {
+        Ok(self.open_object_found(id)?.1)
+    }
+
+    /// Finds the type and content size of an object without parsing it. Only
+    /// the header at the start of the object is decompressed.
+    pub fn object_header(&self, id: &Id) -> Result<(ObjectType, u64)> {
+        // the header is a type name, a space and a decimal size, so there is
+        // no need to buffer: we pull bytes out of the decoder until the null
+        const MAX_HEADER_LEN: usize = 32;
+
+        let (id, stream) = match self.find_object(id)? {
+            (id, Some(stream)) => (id, stream),
+            (id, None) => {
+                return self
+                    .packs
+                    .header(&id, &self.objects_dir(), self.vfs())?
+                    .ok_or_else(|| anyhow!("Object {} does not exist", id))
+            }
+        };
+
+        let mut header = Vec::new();
+        for byte in stream.bytes() {
+            let byte = byte.with_context(|| format!("Failed reading header of object {}", id))?;
+            if byte == 0x00 {
+                return parse_header(&header)
+                    .with_context(|| format!("Failed to parse header of object {}", id));
+            }
+            if header.len() >= MAX_HEADER_LEN {
+                break;
+            }
+            header.push(byte);
+        }
+        Err(anyhow!("Malformed header in object {}", id))
+    }
+
+    /// Finds the type and content size of an object, like
+    /// [`object_header`](Repo::object_header) but with them named
+    pub fn object_info(&self, id: &Id) -> Result<ObjectInfo> {
+        let (kind, size) = self.object_header(id)?;
+        Ok(ObjectInfo { kind, size })
+    }
+
+    /// Reads an object's type and content without parsing the content
+    pub fn read_object(&self, id: &Id) -> Result<(ObjectType, Vec<u8>)> {
+        let (id, mut stream) = match self.find_object(id)? {
+            (id, Some(stream)) => (id, stream),
+            (id, None) => {
+                let (objtype, content) = self
+                    .packs
+                    .read(&id, &self.objects_dir(), self.vfs())?
+                    .ok_or_else(|| anyhow!("Object {} does not exist", id))?;
+                self.verify(&id, || Object::hash_raw(objtype, &content))?;
+                return Ok((objtype, content.to_vec()));
+            }
+        };
+
+        let mut buf = Vec::new();
+        stream
+            .read_to_end(&mut buf)
+            .with_context(|| format!("Failed reading decompressed stream from object {}", id))?;
+        self.verify(&id, || hash_loose(&buf))?;
+
+        let nul = buf
+            .iter()
+            .position(|&b| b == 0x00)
+            .context("Missing null termination after object size")?;
+        let (objtype, _) = parse_header(&buf[..nul])
+            .with_context(|| format!("Failed to parse header of object {}", id))?;
+        buf.drain(..=nul);
+        Ok((objtype, buf))
     }
 
     /// Gets the current value of the HEAD pointer
@@ -165,15 +719,76 @@ impl Repo {
         rev::parse("HEAD", self)
     }
 
+    /// Finds what HEAD points to, which unlike `head` works in a repository
+    /// with no commits yet
+    pub fn read_head(&self) -> Result<rev::Head> {
+        rev::read_head(self)
+    }
+
     /// Set the HEAD pointer to a new value
-    pub fn set_head(&self, new_head: &Id) -> Result<()> {
+    pub fn set_head(&self, new_head: &Id, message: &str) -> Result<()> {
         // Find where the HEAD pointer points then check that one.
-        rev::update_ref(Path::new("HEAD"), new_head, &self.root)
+        rev::update_ref(Path::new("HEAD"), new_head, message, self)
     }
 
     /// Checks if this Id is in the database
     pub fn has_id(&self, id: &Id) -> bool {
-        self.path_for_object(id).exists()
+        self.vfs.exists(&self.path_for_object(id))
+            || self
+                .packs
+                .contains(id, &self.objects_dir(), self.vfs())
+                .unwrap_or(false)
+    }
+
+    /// Lists the IDs of every object in the repository, loose and packed, in
+    /// sorted order. The list is built once and kept until an object is stored.
+    pub fn all_ids(&self) -> Result<Arc<[Id]>> {
+        if let Some(ids) = &*self.all_ids.read().expect("id list lock poisoned") {
+            return Ok(Arc::clone(ids));
+        }
+
+        let objects_dir = self.objects_dir();
+        let mut ids = self.packs.all_ids(&objects_dir, self.vfs())?;
+        for dir in self.vfs.read_dir(&objects_dir)? {
+            // loose objects are in directories named for their first byte
+            let prefix = match dir.to_str() {
+                Some(prefix) if prefix.len() == 2 => prefix,
+                _ => continue,
+            };
+            let dir_path = objects_dir.join(prefix);
+            if !self.vfs.is_dir(&dir_path) {
+                continue;
+            }
+            for name in self.vfs.read_dir(&dir_path)? {
+                if let Some(id) = name.to_str().and_then(|n| Id::from(&[prefix, n].concat())) {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        ids.dedup();
+
+        let ids: Arc<[Id]> = ids.into();
+        *self.all_ids.write().expect("id list lock poisoned") = Some(Arc::clone(&ids));
+        Ok(ids)
+    }
+
+    /// The packfiles in this repository
+    pub fn packs(&self) -> &Packs {
+        &self.packs
+    }
+
+    /// Forgets what is known about which objects exist and where, after
+    /// objects have been packed or removed behind the repo's back
+    pub(crate) fn objects_changed(&self) {
+        *self.all_ids.write().expect("id list lock poisoned") = None;
+        self.packs.forget();
+    }
+
+    /// Registers a temporary file on the repo's filesystem, which is removed
+    /// unless it is kept
+    pub(crate) fn temp_file(&self, path: PathBuf) -> TempFile {
+        TempFile::new(self.vfs.clone(), path)
     }
 
     /// Get the root of the repo's tree
@@ -193,37 +808,134 @@ impl Repo {
         // Windows: canonicalize on the path we're looking at will put a \\?\ on
         // the start, which we need to replicate on the repo root as well; the
         // easiest way to do this is by calling `.canonicalize()` on it as well
-        let tree = self.tree_root().canonicalize()?;
+        let tree = self.vfs.canonicalize(&self.tree_root())?;
 
-        let canonical = path.as_ref().canonicalize()?;
+        let canonical = self.vfs.canonicalize(path.as_ref())?;
         Ok(canonical.strip_prefix(tree)?.to_path_buf())
     }
 
+    /// Is the directory at `path` a git directory, or the root of a repository
+    /// other than this one? Neither of these can be added to the index.
+    pub fn is_other_repo(&self, path: &Path) -> bool {
+        if path.file_name().map_or(false, |name| name == ".git") {
+            return true;
+        }
+        if !self.vfs.exists(&path.join(".git")) {
+            return false;
+        }
+        match self.repo_relative(path) {
+            // the root of our own repository
+            Ok(relative) => relative != Path::new(""),
+            Err(_) => true,
+        }
+    }
+
     /// Stores a git object to disk and gives you its ID.
     pub fn store(&self, obj: &dyn GitObject) -> Result<Id> {
-        let (id, content) = Object::prepare_store(obj);
-
+        let _region = trace::region("object", "write");
+        let (id, content) = Object::prepare_store(obj)?;
         if self.has_id(&id) {
             // don't store IDs that already exist
             return Ok(id);
         }
+        self.write_loose(id, &content)
+    }
+
+    /// Stores an object of the given type holding `content` as it is, without
+    /// parsing it, and gives you its ID. This is for objects that come from
+    /// elsewhere, such as out of a pack. The object is stored loose even if a
+    /// pack has it already.
+    pub fn store_raw(&self, objtype: ObjectType, content: &[u8]) -> Result<Id> {
+        let _region = trace::region("object", "write");
+        let squisher = ZlibEncoder::new(Vec::new(), Compression::best());
+        let mut out = HashingWriter::start(objtype.tag(), content.len() as u64, squisher)?;
+        out.write_all(content)?;
+        let (id, squisher) = out.finish()?;
+        if self.vfs.exists(&self.path_for_object(&id)) {
+            return Ok(id);
+        }
+        self.write_loose(id, &squisher.finish()?)
+    }
 
+    /// Writes an object that has been compressed to its place on disk
+    fn write_loose(&self, id: Id, content: &[u8]) -> Result<Id> {
         let path = self.path_for_object(&id);
-        fs::create_dir_all(
-            path.as_path()
-                .parent()
-                .context("unexpected filesystem boundary found in your .git directory")?,
-        )?;
+        let dir = path
+            .parent()
+            .context("unexpected filesystem boundary found in your .git directory")?;
+        self.vfs.create_dir_all(dir)?;
+
+        // nobody may see the object until it is complete
+        let temp = TempFile::unique_in(self.vfs.clone(), dir, "tmp_obj");
+        self.vfs.write(temp.path(), content)?;
+        self.vfs.rename(temp.path(), &path)?;
+        temp.keep();
+        // the list of IDs is missing this object now
+        *self.all_ids.write().expect("id list lock poisoned") = None;
+        Ok(id)
+    }
 
-        fs::write(&path, content)?;
+    /// Finds the ID the file at `path` would have as a blob. The file is read a
+    /// piece at a time, so it can be of any size.
+    pub fn hash_file(&self, path: &Path) -> Result<Id> {
+        let len = self.vfs.metadata(path)?.len;
+        let (id, _) = write_blob_stream(len, self.vfs.open(path)?, io::sink())
+            .with_context(|| format!("failed to hash {}", path.display()))?;
+        Ok(id)
+    }
+
+    /// Stores the file at `path` as a blob and gives you its ID. Unlike
+    /// `store`, the file is never in memory all at once, so it can be larger
+    /// than memory. It is read twice: once to find its ID, and again to
+    /// compress it if it isn't stored already.
+    pub fn store_file(&self, path: &Path) -> Result<Id> {
+        let _region = trace::region("object", "write_file");
+        let id = self.hash_file(path)?;
+        if self.has_id(&id) {
+            return Ok(id);
+        }
+
+        let object_path = self.path_for_object(&id);
+        let dir = object_path
+            .parent()
+            .context("unexpected filesystem boundary found in your .git directory")?;
+        self.vfs.create_dir_all(dir)?;
+        // nobody may see the object until it is complete
+        let temp = TempFile::unique_in(self.vfs.clone(), dir, "tmp_obj");
+        match self.compress_file(path, temp.path()) {
+            Ok(stored_id) if stored_id == id => {
+                self.vfs.rename(temp.path(), &object_path)?;
+                temp.keep();
+            }
+            // dropping the temp file removes it
+            result => {
+                result?;
+                return Err(anyhow!("{} changed while being stored", path.display()));
+            }
+        }
+
+        *self.all_ids.write().expect("id list lock poisoned") = None;
+        Ok(id)
+    }
+
+    /// Compresses the file at `path` as a loose blob into `out_path`,
+    /// returning its ID
+    fn compress_file(&self, path: &Path, out_path: &Path) -> Result<Id> {
+        let len = self.vfs.metadata(path)?.len;
+        let squisher = ZlibEncoder::new(self.vfs.create(out_path)?, Compression::best());
+        let (id, squisher) = write_blob_stream(len, self.vfs.open(path)?, squisher)?;
+        squisher.finish()?.flush()?;
         Ok(id)
     }
 
     /// Opens an existing object on disk and parses it into an Object
-    /// structure
+    /// structure. If the object has been replaced under refs/replace, this
+    /// gives the replacement instead, unless that has been turned off with
+    /// [`Repo::set_replace_objects`].
     pub fn open(&self, id: &Id) -> Result<Object> {
-        let mut stream = self
-            .open_object_raw(&id)
+        let _region = trace::region("object", "read");
+        let (id, mut stream) = self
+            .open_object_found(id)
             .context(format!("Failed to open object {} on disk", id))?;
 
         let mut buf = Default::default();
@@ -232,14 +944,31 @@ impl Repo {
             "Failed reading decompressed stream from object {}",
             id
         ))?;
+        self.verify(&id, || hash_loose(&buf))?;
         // question mark operator *inside* an Ok is possibly evil
         Ok(Object::parse(buf).context(format!("Failed to parse object {}", id))?)
     }
 
+    /// Opens an object like `open`, but shares the parsed object through the
+    /// repo's cache. Blobs are not cached since they can be arbitrarily large.
+    pub fn open_shared(&self, id: &Id) -> Result<Arc<Object>> {
+        if let Some(obj) = self.cache.get(id) {
+            return Ok(obj);
+        }
+
+        let obj = Arc::new(self.open(id)?);
+        match *obj {
+            Object::Blob(_) => (),
+            _ => self.cache.insert(*id, obj.clone()),
+        }
+        Ok(obj)
+    }
+
     /// Returns the current index of this repository.
     pub fn index(&self) -> Result<index::Index> {
+        let _region = trace::region("index", "read");
         let indexfile = self.root.join("index");
-        let file = fs::OpenOptions::new().read(true).open(indexfile);
+        let file = self.vfs.map(&indexfile);
 
         if let Err(e) = file {
             match e.kind() {
@@ -251,29 +980,48 @@ impl Repo {
             }
         }
 
-        let reader = BufReader::new(file.unwrap());
-        index::parse(reader)
+        index::parse_bytes(&file.unwrap())
     }
 
     /// Write an in-memory index to the index file for this repository. Handles
     /// file IO for you.
     pub fn write_index(&self, new_index: &index::Index) -> Result<()> {
-        // TODO: do this safely with no races
+        let _region = trace::region("index", "write");
         let indexfile = self.root.join("index");
-        let file = fs::OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(indexfile)?;
-        index::write_to_file(new_index, BufWriter::new(file))
+        let mut buf = Vec::new();
+        index::write_to_file(new_index, &mut buf)?;
+        self.replace_file(&indexfile, &buf)
     }
+
+    /// Replaces a file in the .git directory so that anyone reading it sees
+    /// either the old or the new content, even if we are interrupted. The new
+    /// content is written to `<file>.lock`, as git does, synced to disk and
+    /// then renamed over the old file.
+    pub(crate) fn replace_file(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let mut lock_path = path.as_os_str().to_owned();
+        lock_path.push(".lock");
+        // the lock is removed if we fail or are interrupted before the rename
+        let lock = TempFile::new(self.vfs.clone(), PathBuf::from(lock_path));
+
+        self.vfs
+            .write_synced(lock.path(), content)
+            .and_then(|()| self.vfs.rename(lock.path(), path))
+            .with_context(|| format!("failed to replace {}", path.display()))?;
+        lock.keep();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_repo_is_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Repo>();
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 #[test]
 fn test_path_for_object() {
-    let repo = Repo {
-        root: "/path/to/root/.git".into(),
-    };
+    let repo = Repo::at("/path/to/root/.git".into());
     assert_eq!(
         repo.path_for_object(&Id::from("0096cfbd9d1001af3731d9ab5de79450fe031719").unwrap()),
         Path::new("/path/to/root/.git/objects/00/96cfbd9d1001af3731d9ab5de79450fe031719")
@@ -343,6 +1091,16 @@ fn test_load_parse_name_entry() {
 }
 
 impl Id {
+    /// Makes an ID out of its raw binary form
+    pub fn from_bytes(bytes: [u8; 20]) -> Id {
+        Id(bytes)
+    }
+
+    /// Gets the raw binary form of this ID
+    pub fn as_bytes(&self) -> &[u8; 20] {
+        &self.0
+    }
+
     /// Decode an ID from hex representation
     pub fn from(s: &str) -> Option<Id> {
         let decoded = num::parse_hex(s.as_bytes())?;
@@ -372,6 +1130,12 @@ impl fmt::Debug for Id {
     }
 }
 
+impl Serialize for Id {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
 #[test]
 fn test_id_as_hex() {
     assert_eq!(
@@ -389,6 +1153,11 @@ fn test_id_as_hex() {
 }
 
 impl Blob {
+    /// Makes a Blob out of the given bytes
+    pub fn new(content: Vec<u8>) -> Blob {
+        Blob { content }
+    }
+
     /// Creates a new in-memory Blob object, ready to store
     pub fn load(content: &[u8]) -> Result<Box<Blob>> {
         // it is probably a bad idea to copy the full file content into memory
@@ -400,8 +1169,12 @@ impl Blob {
 }
 
 impl GitObject for Blob {
-    fn encode(&self) -> Vec<u8> {
-        self.content.clone()
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.content)
+    }
+
+    fn len_hint(&self) -> usize {
+        self.content.len()
     }
 
     fn tag(&self) -> Vec<u8> {
@@ -409,6 +1182,7 @@ impl GitObject for Blob {
     }
 }
 
+#[cfg(not(target_arch = "wasm32"))]
 impl Blob {
     /// Loads a file from disk and turns it into a Blob
     pub fn new_from_disk(path: &Path) -> Result<Blob> {
@@ -422,33 +1196,27 @@ impl Blob {
 impl File {
     /// Is this File a directory?
     pub fn is_dir(&self) -> bool {
-        // XXX: refactor: we should store these as enums since they actually
-        // just encode object type and executable status
-
-        (self.mode >> 9) & ((1 << 9) - 1) == 0o040
+        self.mode.is_dir()
     }
 
-    fn encode(&self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.extend(format!("{:o}", self.mode).into_bytes());
-        v.push(b' ');
-        v.extend(self.name.as_bytes());
-        v.push(0x00);
-        v.extend(&self.id.0);
-        v
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "{:o} ", u32::from(self.mode))?;
+        out.write_all(&self.name)?;
+        out.write_all(b"\0")?;
+        out.write_all(&self.id.0)
     }
 }
 
 #[test]
 fn test_file_is() {
     let d = File {
-        name: "d".to_string(),
-        mode: 0o40000,
+        name: "d".into(),
+        mode: FileMode::Directory,
         id: Id(*b"00000000000000000000"),
     };
     let f = File {
-        name: "f".to_string(),
-        mode: 0o100644,
+        name: "f".into(),
+        mode: FileMode::Regular,
         id: Id(*b"00000000000000000000"),
     };
 
@@ -456,14 +1224,32 @@ fn test_file_is() {
     assert!(!f.is_dir());
 }
 
+#[test]
+fn test_file_mode() {
+    for &mode in &[
+        FileMode::Regular,
+        FileMode::Executable,
+        FileMode::Symlink,
+        FileMode::Directory,
+        FileMode::Gitlink,
+    ] {
+        assert_eq!(FileMode::try_from(u32::from(mode)).unwrap(), mode);
+    }
+    assert_eq!(FileMode::try_from(0o100664).unwrap(), FileMode::Regular);
+    assert_eq!(FileMode::try_from(0o100744).unwrap(), FileMode::Executable);
+    assert!(FileMode::try_from(0o020000).is_err());
+}
+
 #[test]
 fn test_file_encoding() {
     let f = File {
-        name: "d".to_string(),
-        mode: 0o40000,
+        name: "d".into(),
+        mode: FileMode::Directory,
         id: Id(*b"??\x1d_tbl?/?}7?Ar??\x1c\x7f?"),
     };
-    assert_eq!(f.encode(), b"40000 d\x00??\x1d_tbl?/?}7?Ar??\x1c\x7f?");
+    let mut v = Vec::new();
+    f.encode(&mut v).unwrap();
+    assert_eq!(v, b"40000 d\x00??\x1d_tbl?/?}7?Ar??\x1c\x7f?");
 }
 
 impl Tree {
@@ -479,6 +1265,7 @@ impl Tree {
             let mut split = rest.splitn(2, |&b| b == ' ' as u8);
             let mode = num::parse_octal(split.next().context("corrupt Tree records")?)
                 .context("corrupt Tree record mode")?;
+            let mode = FileMode::try_from(mode)?;
             rest = split.next().context("corrupt Tree structure")?;
 
             // <name><0x00><...>
@@ -493,7 +1280,7 @@ impl Tree {
             hash.clone_from_slice(&rest[..20]);
 
             files.push(File {
-                name: String::from(str::from_utf8(name).context("filename not UTF-8 compliant")?),
+                name: name.into(),
                 id: Id(hash),
                 mode,
             });
@@ -504,13 +1291,11 @@ impl Tree {
 }
 
 impl GitObject for Tree {
-    fn encode(&self) -> Vec<u8> {
-        // there is probably a sin here: we should be using iterators somehow
-        let mut v = Vec::new();
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()> {
         for f in &self.files {
-            v.extend(f.encode());
+            f.encode(out)?;
         }
-        v
+        Ok(())
     }
 
     fn tag(&self) -> Vec<u8> {
@@ -529,13 +1314,13 @@ fn test_tree_parsing() {
         Tree {
             files: vec![
                 File {
-                    name: "d".to_string(),
-                    mode: 0o40000,
+                    name: "d".into(),
+                    mode: FileMode::Directory,
                     id: Id(*b"??\x1d_tbl?/?}7?Ar??\x1c\x7f?"),
                 },
                 File {
-                    name: "hello.txt".to_string(),
-                    mode: 0o100644,
+                    name: "hello.txt".into(),
+                    mode: FileMode::Regular,
                     id: Id(*b"?\x016%\x03\x0b???\x06?V?\x7f????FJ"),
                 }
             ]
@@ -601,24 +1386,35 @@ impl Commit {
             parents,
         }))
     }
+
+    /// The trailers at the end of the commit message, such as `Signed-off-by`
+    pub fn trailers(&self) -> Vec<Trailer> {
+        trailers::parse(&self.message)
+    }
+
+    /// The values of the trailers with the token `token`, compared without
+    /// regard to case, such as everyone in `Co-authored-by` trailers
+    pub fn trailer_values(&self, token: &str) -> Vec<String> {
+        self.trailers()
+            .into_iter()
+            .filter(|trailer| trailer.token.eq_ignore_ascii_case(token))
+            .map(|trailer| trailer.value)
+            .collect()
+    }
 }
 
 impl GitObject for Commit {
-    fn encode(&self) -> Vec<u8> {
-        let mut v = Vec::new();
-        v.extend(b"tree ");
-        v.extend(format!("{}", self.tree).as_bytes());
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "tree {}", self.tree)?;
         for parent in &self.parents {
-            v.extend(b"\nparent ");
-            v.extend(format!("{}", parent).as_bytes());
-        }
-        v.extend(b"\nauthor ");
-        v.extend(self.author.encode());
-        v.extend(b"\ncommitter ");
-        v.extend(self.committer.encode());
-        v.extend(b"\n\n");
-        v.extend(self.message.as_bytes());
-        v
+            write!(out, "\nparent {}", parent)?;
+        }
+        out.write_all(b"\nauthor ")?;
+        out.write_all(&self.author.encode())?;
+        out.write_all(b"\ncommitter ")?;
+        out.write_all(&self.committer.encode())?;
+        out.write_all(b"\n\n")?;
+        out.write_all(self.message.as_bytes())
     }
 
     fn tag(&self) -> Vec<u8> {
@@ -626,6 +1422,85 @@ impl GitObject for Commit {
     }
 }
 
+impl Tag {
+    /// Parses a tag from on-disk representation
+    pub fn load(content: &[u8]) -> Result<Box<Tag>> {
+        let (header, message) = match content.windows(2).position(|w| w == b"\n\n") {
+            Some(end) => (&content[..end], &content[end + 2..]),
+            None => (content, &b""[..]),
+        };
+        let header = str::from_utf8(header).context("tag header is not UTF-8")?;
+
+        let mut object = None;
+        let mut kind = None;
+        let mut name = None;
+        let mut tagger = None;
+        for line in header.lines() {
+            let mut iter = line.splitn(2, ' ');
+            let typ = iter.next().unwrap_or_default();
+            let rest = iter.next().context("got confused reading tag metadata")?;
+            match typ {
+                "object" => object = Some(Id::from(rest).context("object was not an id")?),
+                "type" => {
+                    kind = Some(
+                        ObjectType::from_tag(rest.as_bytes())
+                            .context("unknown tagged object type")?,
+                    )
+                }
+                "tag" => name = Some(rest.to_string()),
+                "tagger" => tagger = Some(NameEntry::from(rest).context("failed to parse tagger")?),
+                _ => (),
+            }
+        }
+        Ok(Box::new(Tag {
+            object: object.context("object missing when parsing tag header")?,
+            kind: kind.context("type missing when parsing tag header")?,
+            name: name.context("name missing when parsing tag header")?,
+            tagger,
+            message: str::from_utf8(message)?.to_string(),
+        }))
+    }
+}
+
+impl GitObject for Tag {
+    fn encode(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(
+            out,
+            "object {}\ntype {}\ntag {}",
+            self.object, self.kind, self.name
+        )?;
+        if let Some(tagger) = &self.tagger {
+            out.write_all(b"\ntagger ")?;
+            out.write_all(&tagger.encode())?;
+        }
+        out.write_all(b"\n\n")?;
+        out.write_all(self.message.as_bytes())
+    }
+
+    fn tag(&self) -> Vec<u8> {
+        Vec::from(*b"tag")
+    }
+}
+
+#[test]
+fn test_tag_parse_encode() {
+    let tag = b"object d55912e4475329fde95d52d619abd413e4001d68\n\
+                type commit\n\
+                tag v1.0\n\
+                tagger lf- <lf-@users.noreply.github.com> 1586391037 -0700\n\n\
+                Version 1.0\n"
+        .to_vec();
+    let decoded = Tag {
+        object: Id::from("d55912e4475329fde95d52d619abd413e4001d68").unwrap(),
+        kind: ObjectType::Commit,
+        name: "v1.0".to_string(),
+        tagger: NameEntry::from("lf- <lf-@users.noreply.github.com> 1586391037 -0700"),
+        message: "Version 1.0\n".to_string(),
+    };
+    assert_eq!(*Tag::load(&tag).unwrap(), decoded);
+    assert_eq!(decoded.encode_to_vec(), tag);
+}
+
 #[test]
 fn test_commit_parse_encode() {
     let commit = b"tree 94546d68dc6002b85cc2d7df077c7c6bb080abb0\n\
@@ -647,7 +1522,101 @@ fn test_commit_parse_encode() {
         message: "Merge branch \'branch2\'\n".to_string(),
     };
     assert_eq!(*Commit::load(&commit).unwrap(), decoded);
-    assert_eq!(decoded.encode(), commit);
+    assert_eq!(decoded.encode_to_vec(), commit);
+    assert_eq!(decoded.trailers(), vec![]);
+}
+
+#[test]
+fn test_commit_trailers() {
+    let commit = Commit {
+        tree: Id::from("94546d68dc6002b85cc2d7df077c7c6bb080abb0").unwrap(),
+        parents: vec![],
+        author: NameEntry::from("lf- <lf-@users.noreply.github.com> 1586391037 -0700").unwrap(),
+        committer: NameEntry::from("lf- <lf-@users.noreply.github.com> 1586391037 -0700").unwrap(),
+        message: "Fix it\n\nReviewed-by: no one\n\n\
+                  Co-authored-by: A <a@example.com>\n\
+                  Signed-off-by: lf- <lf-@users.noreply.github.com>\n\
+                  co-authored-by: B <b@example.com>\n"
+            .to_string(),
+    };
+    assert_eq!(commit.trailers().len(), 3);
+    assert_eq!(commit.trailers()[1].token, "Signed-off-by");
+    assert_eq!(
+        commit.trailer_values("Co-Authored-By"),
+        vec!["A <a@example.com>", "B <b@example.com>"]
+    );
+    assert_eq!(commit.trailer_values("Reviewed-by"), Vec::<String>::new());
+}
+
+impl ObjectType {
+    /// Finds the object type corresponding to an on-disk tag such as b"blob"
+    pub fn from_tag(tag: &[u8]) -> Option<ObjectType> {
+        Some(match tag {
+            b"tree" => ObjectType::Tree,
+            b"blob" => ObjectType::Blob,
+            b"commit" => ObjectType::Commit,
+            b"tag" => ObjectType::Tag,
+            _ => return None,
+        })
+    }
+
+    /// Returns the on-disk tag for this object type
+    pub fn tag(&self) -> &'static [u8] {
+        match self {
+            ObjectType::Tree => b"tree",
+            ObjectType::Blob => b"blob",
+            ObjectType::Commit => b"commit",
+            ObjectType::Tag => b"tag",
+        }
+    }
+}
+
+impl fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // tags are always ASCII
+        f.write_str(str::from_utf8(self.tag()).unwrap())
+    }
+}
+
+/// Parses an object header of the format `<type> <decimal size>` (without the
+/// trailing null)
+fn parse_header(header: &[u8]) -> Result<(ObjectType, u64)> {
+    let mut split = header.splitn(2, |&e| e == b' ');
+    let tag = split.next().context("Failed to parse object type")?;
+    let objtype = ObjectType::from_tag(tag)
+        .ok_or_else(|| anyhow!("unsupported object type {}", String::from_utf8_lossy(tag)))?;
+
+    let size = split.next().context("Missing object size")?;
+    let size = str::from_utf8(size)
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .context("Failed to parse object size")?;
+    Ok((objtype, size))
+}
+
+#[test]
+fn test_check_raw() {
+    let commit = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                   author A U Thor <author@example.com> 1586391037 -0700\n\
+                   committer A U Thor <author@example.com> 1586391037 -0700\n\nhi\n";
+    assert!(Object::check_raw(ObjectType::Commit, commit).is_ok());
+    assert!(Object::check_raw(ObjectType::Blob, commit).is_ok());
+    assert!(Object::check_raw(ObjectType::Commit, b"not a commit\n").is_err());
+    assert!(Object::check_raw(ObjectType::Tree, b"").is_ok());
+    assert!(Object::check_raw(ObjectType::Tree, b"100644 file").is_err());
+}
+
+#[test]
+fn test_parse_header() {
+    assert_eq!(parse_header(b"blob 6").unwrap(), (ObjectType::Blob, 6));
+    assert_eq!(
+        parse_header(b"commit 287").unwrap(),
+        (ObjectType::Commit, 287)
+    );
+    assert_eq!(parse_header(b"tag 0").unwrap(), (ObjectType::Tag, 0));
+    assert!(parse_header(b"sadface 1").is_err());
+    assert!(parse_header(b"blob").is_err());
+    assert!(parse_header(b"blob -1").is_err());
 }
 
 impl Object {
@@ -661,49 +1630,61 @@ impl Object {
             .next()
             .context(format!("Missing null termination after object size"))?;
 
-        let objtype = str::from_utf8(
-            header
-                .split(|&e| e == ' ' as u8)
-                .next()
-                .context("Failed to parse object type")?,
-        )?;
+        let (objtype, _) = parse_header(header)?;
 
         Ok(match objtype {
-            "tree" => Object::Tree(*Tree::load(content)?),
-            "blob" => Object::Blob(*Blob::load(content).unwrap()),
-            "commit" => Object::Commit(*Commit::load(content)?),
-            _ => return Err(anyhow!("unsupported object type {}", objtype)),
+            ObjectType::Tree => Object::Tree(*Tree::load(content)?),
+            ObjectType::Blob => Object::Blob(*Blob::load(content).unwrap()),
+            ObjectType::Commit => Object::Commit(*Commit::load(content)?),
+            ObjectType::Tag => return Err(anyhow!("unsupported object type {}", objtype)),
         })
     }
 
+    /// Writes an object with its header to `out`, returning its ID
+    fn write_with_header<W: Write>(obj: &dyn GitObject, out: W) -> Result<(Id, W)> {
+        let mut out = HashingWriter::start(&obj.tag(), obj.len_hint() as u64, out)?;
+        obj.encode(&mut out)?;
+        out.finish()
+    }
+
+    /// Finds the ID an object would have, without compressing it
+    pub fn hash(obj: &dyn GitObject) -> Result<Id> {
+        let (id, _) = Object::write_with_header(obj, io::sink())?;
+        Ok(id)
+    }
+
+    /// Finds the ID of an object of the given type holding `content`, which
+    /// need not parse
+    pub fn hash_raw(objtype: ObjectType, content: &[u8]) -> Result<Id> {
+        let mut out = HashingWriter::start(objtype.tag(), content.len() as u64, io::sink())?;
+        out.write_all(content)?;
+        let (id, _) = out.finish()?;
+        Ok(id)
+    }
+
+    /// Checks that `content` parses as an object of the given type. Any
+    /// content is a blob.
+    pub fn check_raw(objtype: ObjectType, content: &[u8]) -> Result<()> {
+        match objtype {
+            ObjectType::Tree => drop(Tree::load(content)?),
+            ObjectType::Blob => (),
+            ObjectType::Commit => drop(Commit::load(content)?),
+            ObjectType::Tag => drop(Tag::load(content)?),
+        }
+        Ok(())
+    }
+
     /// Prepares an object for storage, getting its ID and content to store to
-    /// disk
-    pub fn prepare_store(obj: &dyn GitObject) -> (Id, Vec<u8>) {
-        let typ = obj.tag();
-        let encoded = obj.encode();
-
-        let size = encoded.len();
-        let mut to_store = Vec::new();
-        to_store.extend(typ);
-        to_store.push(b' ');
-        to_store.extend(format!("{}", size).as_bytes());
-        to_store.push(0x00);
-        to_store.extend(encoded);
-
-        let mut hasher = Sha1::new();
-        hasher.input(&to_store);
-        let id = Id(hasher.result().into());
-
-        let mut squished = Vec::new();
-        let mut squisher = ZlibEncoder::new(&mut squished, Compression::best());
-        squisher
-            .write_all(&to_store[..])
-            .expect("writing to in-memory compression stream failed. wat.");
-        squisher
+    /// disk. The object is hashed and compressed as it is encoded, so it is
+    /// never copied in full.
+    pub fn prepare_store(obj: &dyn GitObject) -> Result<(Id, Vec<u8>)> {
+        let squisher = ZlibEncoder::new(Vec::new(), Compression::best());
+        let (id, squisher) = Object::write_with_header(obj, squisher)?;
+        let squished = squisher
             .finish()
             .expect("compression finalization failed. wat");
 
-        (id, squished)
+        Ok((id, squished))
     }
 
     /// Turns an Object into a Tree or nothing
@@ -744,7 +1725,7 @@ fn test_object_encoding() {
         committer: NameEntry::from("lf- <lf-@users.noreply.github.com> 1586391037 -0700").unwrap(),
         message: "Merge branch \'branch2\'\n".to_string(),
     };
-    let (id, squished_content) = Object::prepare_store(&decoded);
+    let (id, squished_content) = Object::prepare_store(&decoded).unwrap();
 
     let mut unsquisher = flate2::read::ZlibDecoder::new(&squished_content[..]);
 
@@ -776,18 +1757,18 @@ fn test_object_parsing() {
         Object::Tree(Tree {
             files: vec![
                 File {
-                    name: "d".to_string(),
-                    mode: 0o40000,
+                    name: "d".into(),
+                    mode: FileMode::Directory,
                     id: Id(*b"??\x1d_tbl?/?}7?Ar??\x1c\x7f?"),
                 },
                 File {
-                    name: "hello.txt".to_string(),
-                    mode: 0o100644,
+                    name: "hello.txt".into(),
+                    mode: FileMode::Regular,
                     id: Id(*b"?\x016%\x03\x0b???\x06?V?\x7f????FJ"),
                 },
                 File {
-                    name: "world.txt".to_string(),
-                    mode: 0o100644,
+                    name: "world.txt".into(),
+                    mode: FileMode::Regular,
                     id: Id(*b"?b??\x10t+??$\x1cY$??+\\\x01?q"),
                 }
             ]
@@ -807,3 +1788,138 @@ fn test_object_parsing() {
     let sadface = b"sadface 1\x00";
     assert!(Object::parse(sadface.to_vec()).is_err());
 }
+
+#[test]
+fn test_store_file() {
+    use crate::vfs::MemoryFs;
+
+    let fs = Arc::new(MemoryFs::new());
+    let repo = Repo::in_memory_at(&fs, "/repo");
+
+    let content = (0..100_000u32).map(|n| n as u8).collect::<Vec<_>>();
+    fs.write(Path::new("/repo/big"), &content).unwrap();
+    let blob = Blob::new(content);
+
+    let id = repo.store_file(Path::new("/repo/big")).unwrap();
+    assert_eq!(id, Object::hash(&blob).unwrap());
+    assert_eq!(repo.hash_file(Path::new("/repo/big")).unwrap(), id);
+    assert_eq!(
+        repo.object_info(&id).unwrap(),
+        ObjectInfo {
+            kind: ObjectType::Blob,
+            size: 100_000,
+        }
+    );
+    assert_eq!(repo.open(&id).unwrap(), Object::Blob(blob));
+    // the temporary file is gone
+    let dir = repo.path_for_object(&id);
+    assert_eq!(fs.read_dir(dir.parent().unwrap()).unwrap().len(), 1);
+}
+
+#[test]
+fn test_is_other_repo() {
+    use crate::vfs::MemoryFs;
+
+    let fs = Arc::new(MemoryFs::new());
+    let repo = Repo::in_memory_at(&fs, "/repo");
+    Repo::in_memory_at(&fs, "/repo/nested");
+    fs.create_dir_all(Path::new("/repo/plain")).unwrap();
+
+    assert!(!repo.is_other_repo(Path::new("/repo")));
+    assert!(!repo.is_other_repo(Path::new("/repo/plain")));
+    assert!(repo.is_other_repo(Path::new("/repo/.git")));
+    assert!(repo.is_other_repo(Path::new("/repo/nested")));
+}
+
+#[test]
+fn test_write_index() {
+    use crate::vfs::MemoryFs;
+
+    let fs = Arc::new(MemoryFs::new());
+    let repo = Repo::in_memory_at(&fs, "/repo");
+    fs.write(Path::new("/repo/file"), b"content").unwrap();
+
+    let mut index = repo.index().unwrap();
+    index::insert_entry(
+        &mut index,
+        index::IndexEntry {
+            name: "file".into(),
+            meta: index::IndexMeta::new_from_file(b"file", &repo).unwrap(),
+        },
+    );
+    repo.write_index(&index).unwrap();
+    assert_eq!(repo.index().unwrap(), index);
+    assert!(!fs.exists(Path::new("/repo/.git/index.lock")));
+}
+
+#[test]
+fn test_verify_objects() {
+    use crate::vfs::MemoryFs;
+
+    let fs = Arc::new(MemoryFs::new());
+    let mut repo = Repo::in_memory_at(&fs, "/repo");
+    let id = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+    // swap another blob in under its ID
+    let (other, content) = Object::prepare_store(&Blob::new(b"bye\n".to_vec())).unwrap();
+    fs.write(&repo.path_for_object(&id), &content).unwrap();
+
+    assert_eq!(repo.read_object(&id).unwrap().1, b"bye\n");
+    repo.set_verify_objects(true);
+    for err in vec![
+        repo.open(&id).unwrap_err(),
+        repo.read_object(&id).unwrap_err(),
+    ] {
+        match err.downcast_ref::<ObjectError>() {
+            Some(ObjectError::CorruptObject { id: bad, actual }) => {
+                assert_eq!((bad, actual), (&id, &other));
+            }
+            _ => panic!("unexpected error {:?}", err),
+        }
+    }
+}
+
+#[test]
+fn test_replace_objects() {
+    use crate::vfs::MemoryFs;
+
+    let fs = Arc::new(MemoryFs::new());
+    let mut repo = Repo::in_memory_at(&fs, "/repo");
+    let old = repo.store(&Blob::new(b"old\n".to_vec())).unwrap();
+    let new = repo.store(&Blob::new(b"new\n".to_vec())).unwrap();
+    let newer = repo.store(&Blob::new(b"newer\n".to_vec())).unwrap();
+    let replace = |repo: &Repo, old: &Id, new: &Id| {
+        let name = format!("refs/replace/{}", old);
+        rev::update_ref(Path::new(&name), new, "", repo).unwrap();
+    };
+    replace(&repo, &old, &new);
+    replace(&repo, &new, &newer);
+
+    let content = |repo: &Repo, id| match repo.open_shared(id).unwrap().as_ref() {
+        Object::Blob(blob) => blob.content.clone(),
+        _ => panic!("{} is not a blob", id),
+    };
+    assert_eq!(repo.replacement(&old).unwrap(), newer);
+    assert_eq!(content(&repo, &old), b"newer\n");
+    assert_eq!(content(&repo, &newer), b"newer\n");
+    // every way of reading an object agrees
+    assert_eq!(repo.read_object(&old).unwrap().1, b"newer\n");
+    assert_eq!(repo.object_header(&old).unwrap(), (ObjectType::Blob, 6));
+    let mut raw = Vec::new();
+    repo.open_object_raw(&old)
+        .unwrap()
+        .read_to_end(&mut raw)
+        .unwrap();
+    assert_eq!(raw, b"blob 6\0newer\n");
+    repo.set_replace_objects(false);
+    assert_eq!(content(&repo, &old), b"old\n");
+    assert_eq!(content(&repo, &new), b"new\n");
+    assert_eq!(repo.read_object(&old).unwrap().1, b"old\n");
+
+    // a loop of replacements can't be followed
+    replace(&repo, &newer, &old);
+    let repo = Repo::with_vfs(repo.root.clone(), fs);
+    match repo.open(&old).unwrap_err().downcast_ref::<ObjectError>() {
+        Some(ObjectError::ReplaceDepth(id)) => assert_eq!(id, &old),
+        err => panic!("unexpected error {:?}", err),
+    }
+}
@@ -0,0 +1,2209 @@
+//! Reading objects out of packfiles, the compressed archives git uses to store
+//! most objects in real repositories. Packs and their `.idx` indexes are
+//! memory-mapped, so a lookup only touches the pages it needs, and recently
+//! inflated objects are kept in a size-bounded cache.
+//!
+//! Most objects in a pack are stored as deltas: instructions for making the
+//! object out of pieces of another one, its base, which may be a delta itself.
+//! Every object resolved along such a chain goes in the cache, since objects
+//! near each other in history tend to share bases.
+//!
+//! Packs can be written too, along with their indexes. Objects that are alike
+//! are found the way git finds them, by sorting the objects so that those of
+//! the same type and file name are near each other, then trying each as a
+//! delta against the few before it.
+use anyhow::{anyhow, Context, Error, Result};
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use sha1::{Digest, Sha1};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{self, BufRead, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+use thiserror::Error;
+
+use crate::objects::{Id, Object, ObjectType, Repo};
+use crate::revwalk::FoundObject;
+use crate::trace;
+use crate::util;
+use crate::vfs::{FileData, Vfs};
+
+const PACK_SIGNATURE: &[u8; 4] = b"PACK";
+const IDX_SIGNATURE: &[u8; 4] = b"\xfftOc";
+const IDX_HEADER_LEN: usize = 8;
+const FANOUT_LEN: usize = 256 * 4;
+
+/// How many bytes of inflated objects to keep around. Same as git's default
+/// core.deltaBaseCacheLimit.
+const CACHE_LIMIT: usize = 96 * 1024 * 1024;
+
+/// Most that is set aside up front for an object being inflated. Its size
+/// comes from the pack, so it isn't trusted with an allocation; objects
+/// bigger than this grow into their space as they are inflated.
+const MAX_INFLATE_RESERVE: u64 = 16 * 1024 * 1024;
+
+/// Longest delta chain we follow. git never makes longer ones, so a chain
+/// this long is most likely a loop of ref-deltas in a corrupt pack.
+const MAX_DELTA_CHAIN: usize = 4095;
+
+/// How many objects before each one are tried as its delta base when writing
+/// a pack, unless pack.window says otherwise
+const DEFAULT_WINDOW: usize = 10;
+/// Longest delta chain made when writing a pack, unless pack.depth says
+/// otherwise
+const DEFAULT_DEPTH: usize = 50;
+/// Objects bigger than this are stored whole, like git's default
+/// core.bigFileThreshold
+const BIG_FILE_THRESHOLD: usize = 512 * 1024 * 1024;
+/// Size of the blocks of a base that matches are looked for from
+const DELTA_BLOCK: usize = 16;
+/// How many places a block is remembered at, so that finding deltas against
+/// repetitive data doesn't take forever
+const MAX_BLOCK_PLACES: usize = 64;
+/// Most bytes copied by one delta instruction
+const MAX_COPY: usize = 0x10000;
+/// Most bytes inserted by one delta instruction
+const MAX_INSERT: usize = 0x7f;
+
+/// Errors that can arise while reading packs
+#[derive(Error, Debug)]
+pub enum PackError {
+    /// The magic bytes at the top of a pack or index are wrong
+    #[error("Bad magic in {0}")]
+    BadMagic(PathBuf),
+
+    /// The pack or index is in a version we can't read
+    #[error("Unsupported version {1} of {0}")]
+    UnsupportedVersion(PathBuf, u32),
+
+    /// The pack or index ended early or points outside itself
+    #[error("{0} is truncated")]
+    Truncated(PathBuf),
+
+    /// An entry has a type number that doesn't exist
+    #[error("Unknown object type {1} at offset {2} in {0}")]
+    BadType(PathBuf, u8, u64),
+
+    /// An entry's header goes on for longer than any size could
+    #[error("Malformed header of object at offset {1} in {0}")]
+    BadHeader(PathBuf, u64),
+
+    /// A delta's chain of bases is too long, or goes round in a loop
+    #[error("Delta chain of object at offset {1} in {0} is too long")]
+    DeltaChainTooLong(PathBuf, u64),
+
+    /// The base of a ref-delta isn't in any pack
+    #[error("Delta base {1} of object at offset {2} in {0} is missing")]
+    MissingBase(PathBuf, Id, u64),
+
+    /// A delta doesn't make sense, or doesn't fit its base
+    #[error("Corrupt delta: {0}")]
+    BadDelta(&'static str),
+
+    /// The entry inflated to a different size than its header says
+    #[error("Object at offset {1} in {0} has the wrong size")]
+    BadSize(PathBuf, u64),
+
+    /// The checksum at the end of the pack doesn't match its content
+    #[error("{0} is corrupt: its checksum doesn't match")]
+    BadChecksum(PathBuf),
+
+    /// Some deltas are against objects that aren't in the pack
+    #[error("{0} has {1} deltas against objects it doesn't contain")]
+    UnresolvedDeltas(PathBuf, usize),
+}
+
+/// The kind of an entry in a pack, as stored in its header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryType {
+    /// A whole object
+    Object(ObjectType),
+    /// A delta against the entry this many bytes before this one
+    OfsDelta,
+    /// A delta against the object with a given ID
+    RefDelta,
+}
+
+impl EntryType {
+    fn number(self) -> u8 {
+        match self {
+            EntryType::Object(ObjectType::Commit) => 1,
+            EntryType::Object(ObjectType::Tree) => 2,
+            EntryType::Object(ObjectType::Blob) => 3,
+            EntryType::Object(ObjectType::Tag) => 4,
+            EntryType::OfsDelta => 6,
+            EntryType::RefDelta => 7,
+        }
+    }
+
+    fn from_number(n: u8) -> Option<EntryType> {
+        Some(match n {
+            1 => EntryType::Object(ObjectType::Commit),
+            2 => EntryType::Object(ObjectType::Tree),
+            3 => EntryType::Object(ObjectType::Blob),
+            4 => EntryType::Object(ObjectType::Tag),
+            6 => EntryType::OfsDelta,
+            7 => EntryType::RefDelta,
+            _ => return None,
+        })
+    }
+}
+
+/// The header of an entry in a pack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryHeader {
+    /// What is stored in the entry
+    pub kind: EntryType,
+    /// Inflated size of the entry's data
+    pub size: u64,
+    /// Length of the header itself in bytes
+    pub len: usize,
+}
+
+/// What a delta is against
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaBase {
+    /// The entry at this offset in the same pack
+    Offset(u64),
+    /// The object with this ID
+    Id(Id),
+}
+
+/// Reads one of the sizes at the start of a delta: 7 bits at a time, lowest
+/// first, with the top bit set on all but the last byte
+fn delta_size(delta: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*pos)?;
+        *pos += 1;
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 || shift > 63 {
+            return Some(size);
+        }
+    }
+}
+
+/// Makes an object out of its base and a delta against it. The delta starts
+/// with the sizes of the base and the result, then has instructions to either
+/// copy a range of the base or insert bytes given in the delta.
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, PackError> {
+    let mut pos = 0;
+    let truncated = PackError::BadDelta("truncated");
+    let base_size = delta_size(delta, &mut pos).ok_or(truncated)?;
+    if base_size != base.len() as u64 {
+        return Err(PackError::BadDelta("base is the wrong size"));
+    }
+    let result_size = delta_size(delta, &mut pos).ok_or(PackError::BadDelta("truncated"))?;
+
+    // the size comes from the pack, so it isn't trusted with an allocation
+    // larger than the base and the delta together. Results bigger than that
+    // grow into their space as they are made.
+    let guess = base.len().saturating_add(delta.len()) as u64;
+    let mut out = Vec::with_capacity(result_size.min(guess) as usize);
+    while let Some(&op) = delta.get(pos) {
+        pos += 1;
+        if op & 0x80 != 0 {
+            // copy: the low 4 bits say which bytes of the offset follow, the
+            // next 3 which bytes of the size do
+            let mut arg = |bit: u8, shift: usize| -> Result<usize, PackError> {
+                if op & bit == 0 {
+                    return Ok(0);
+                }
+                let byte = *delta.get(pos).ok_or(PackError::BadDelta("truncated"))?;
+                pos += 1;
+                Ok((byte as usize) << shift)
+            };
+            let offset = arg(0x01, 0)? | arg(0x02, 8)? | arg(0x04, 16)? | arg(0x08, 24)?;
+            let size = match arg(0x10, 0)? | arg(0x20, 8)? | arg(0x40, 16)? {
+                0 => 0x10000,
+                size => size,
+            };
+            let chunk = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or(PackError::BadDelta("copy from outside the base"))?;
+            out.extend_from_slice(chunk);
+        } else if op != 0 {
+            // insert the next `op` bytes
+            let chunk = delta
+                .get(pos..pos + op as usize)
+                .ok_or(PackError::BadDelta("truncated"))?;
+            out.extend_from_slice(chunk);
+            pos += op as usize;
+        } else {
+            return Err(PackError::BadDelta("reserved instruction"));
+        }
+        if out.len() as u64 > result_size {
+            return Err(PackError::BadDelta("result is the wrong size"));
+        }
+    }
+    if out.len() as u64 != result_size {
+        return Err(PackError::BadDelta("result is the wrong size"));
+    }
+    Ok(out)
+}
+
+/// Writes one of the sizes at the start of a delta
+fn put_delta_size(mut size: usize, out: &mut Vec<u8>) {
+    while size >= 0x80 {
+        out.push((size & 0x7f) as u8 | 0x80);
+        size >>= 7;
+    }
+    out.push(size as u8);
+}
+
+/// Writes instructions to insert `data`
+fn put_inserts(data: &[u8], out: &mut Vec<u8>) {
+    for chunk in data.chunks(MAX_INSERT) {
+        out.push(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+/// Writes an instruction to copy `size` bytes of the base from `offset`. Only
+/// the bytes of each that aren't zero are written.
+fn put_copy(offset: usize, size: usize, out: &mut Vec<u8>) {
+    let at = out.len();
+    let mut op = 0x80;
+    out.push(op);
+    for (n, shift) in [0, 8, 16, 24].iter().enumerate() {
+        let byte = (offset >> shift) as u8;
+        if byte != 0 {
+            op |= 1 << n;
+            out.push(byte);
+        }
+    }
+    for (n, shift) in [0, 8, 16].iter().enumerate() {
+        let byte = (size >> shift) as u8;
+        if byte != 0 {
+            op |= 0x10 << n;
+            out.push(byte);
+        }
+    }
+    out[at] = op;
+}
+
+fn block_key(block: &[u8]) -> u128 {
+    u128::from_le_bytes(block.try_into().unwrap())
+}
+
+/// Where each block of an object is, for making deltas against it
+struct DeltaIndex {
+    blocks: HashMap<u128, Vec<u32>>,
+}
+
+impl DeltaIndex {
+    fn new(base: &[u8]) -> DeltaIndex {
+        let mut blocks: HashMap<u128, Vec<u32>> = HashMap::new();
+        for (n, block) in base.chunks_exact(DELTA_BLOCK).enumerate() {
+            let places = blocks.entry(block_key(block)).or_default();
+            if places.len() < MAX_BLOCK_PLACES {
+                places.push((n * DELTA_BLOCK) as u32);
+            }
+        }
+        DeltaIndex { blocks }
+    }
+}
+
+/// Makes a delta that turns `base` into `target`, as long as it comes out no
+/// bigger than `max_size`. `index` has to be the index of `base`.
+fn create_delta(
+    base: &[u8],
+    index: &DeltaIndex,
+    target: &[u8],
+    max_size: usize,
+) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    put_delta_size(base.len(), &mut out);
+    put_delta_size(target.len(), &mut out);
+
+    // everything from `pending` to `at` is yet to be inserted
+    let mut pending = 0;
+    let mut at = 0;
+    while at + DELTA_BLOCK <= target.len() {
+        let key = block_key(&target[at..at + DELTA_BLOCK]);
+        let longest = index.blocks.get(&key).and_then(|places| {
+            places
+                .iter()
+                .map(|&offset| {
+                    let offset = offset as usize;
+                    let len = base[offset..]
+                        .iter()
+                        .zip(&target[at..])
+                        .take_while(|(a, b)| a == b)
+                        .count();
+                    (offset, len)
+                })
+                .max_by_key(|&(_, len)| len)
+        });
+        let (mut offset, mut len) = match longest {
+            Some(found) if found.1 >= DELTA_BLOCK => found,
+            _ => {
+                at += 1;
+                continue;
+            }
+        };
+        // the match may start before the block did
+        let mut start = at;
+        while start > pending && offset > 0 && base[offset - 1] == target[start - 1] {
+            start -= 1;
+            offset -= 1;
+            len += 1;
+        }
+
+        put_inserts(&target[pending..start], &mut out);
+        let mut copied = 0;
+        while copied < len {
+            let size = (len - copied).min(MAX_COPY);
+            put_copy(offset + copied, size, &mut out);
+            copied += size;
+        }
+        at = start + len;
+        pending = at;
+        if out.len() > max_size {
+            return None;
+        }
+    }
+    put_inserts(&target[pending..], &mut out);
+    if out.len() > max_size {
+        return None;
+    }
+    Some(out)
+}
+
+fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn be64(data: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(data[at..at + 8].try_into().unwrap())
+}
+
+/// Reads the header of the entry at `offset` in the pack at `path`
+fn read_entry_header(path: &Path, pack: &[u8], offset: u64) -> Result<EntryHeader> {
+    let truncated = || Error::new(PackError::Truncated(path.to_owned()));
+    let data = pack.get(offset as usize..).ok_or_else(truncated)?;
+
+    // first byte: continuation bit, 3 bits of type, low 4 bits of size.
+    // following bytes: continuation bit, 7 more bits of size
+    let mut bytes = data.iter();
+    let first = *bytes.next().ok_or_else(truncated)?;
+    let kind = EntryType::from_number((first >> 4) & 0b111)
+        .ok_or_else(|| PackError::BadType(path.to_owned(), (first >> 4) & 0b111, offset))?;
+
+    let mut size = (first & 0x0f) as u64;
+    let mut shift = 4;
+    let mut len = 1;
+    let mut byte = first;
+    while byte & 0x80 != 0 {
+        byte = *bytes.next().ok_or_else(truncated)?;
+        if shift > 63 {
+            return Err(Error::new(PackError::BadHeader(path.to_owned(), offset)));
+        }
+        size |= ((byte & 0x7f) as u64) << shift;
+        shift += 7;
+        len += 1;
+    }
+    Ok(EntryHeader { kind, size, len })
+}
+
+/// Reads what a delta entry is against, returning that along with the
+/// offset of the delta's data, which follows it
+fn read_delta_base(
+    path: &Path,
+    pack: &[u8],
+    offset: u64,
+    header: &EntryHeader,
+) -> Result<(DeltaBase, u64)> {
+    let truncated = || Error::new(PackError::Truncated(path.to_owned()));
+    let at = offset + header.len as u64;
+    let data = pack.get(at as usize..).ok_or_else(truncated)?;
+    match header.kind {
+        EntryType::OfsDelta => {
+            // big-endian 7 bit groups, with one added to each group but
+            // the last so that every length has its own range
+            let mut bytes = data.iter();
+            let mut byte = *bytes.next().ok_or_else(truncated)?;
+            let out_of_range = || {
+                Err(Error::new(PackError::BadDelta("base offset out of range")))
+                    .with_context(|| format!("at offset {} in {}", offset, path.display()))
+            };
+            let mut distance = (byte & 0x7f) as u64;
+            let mut len = 1;
+            while byte & 0x80 != 0 {
+                byte = *bytes.next().ok_or_else(truncated)?;
+                distance = match distance.checked_add(1).and_then(|d| d.checked_mul(1 << 7)) {
+                    Some(distance) => distance | (byte & 0x7f) as u64,
+                    None => return out_of_range(),
+                };
+                len += 1;
+            }
+            if distance == 0 || distance > offset {
+                return out_of_range();
+            }
+            Ok((DeltaBase::Offset(offset - distance), at + len))
+        }
+        EntryType::RefDelta => {
+            let id: [u8; 20] = data.get(..20).ok_or_else(truncated)?.try_into().unwrap();
+            Ok((DeltaBase::Id(Id::from_bytes(id)), at + 20))
+        }
+        EntryType::Object(_) => Ok((DeltaBase::Offset(offset), at)),
+    }
+}
+
+/// A single packfile along with its index
+pub struct Pack {
+    /// path to the .pack file
+    path: PathBuf,
+    pack: FileData,
+    idx: FileData,
+    /// number of objects in the pack
+    count: usize,
+}
+
+impl Pack {
+    /// Opens a pack given the path to its `.idx` file
+    pub fn open(idx_path: &Path, vfs: &dyn Vfs) -> Result<Pack> {
+        let _region = trace::region("pack", "open");
+        let path = idx_path.with_extension("pack");
+        let idx = vfs
+            .map(idx_path)
+            .with_context(|| format!("failed to open {}", idx_path.display()))?;
+        let pack = vfs
+            .map(&path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        // we only read v2 indexes. v1 hasn't been written by default since 2008
+        if idx.len() < IDX_HEADER_LEN + FANOUT_LEN || &idx[..4] != IDX_SIGNATURE {
+            return Err(Error::new(PackError::BadMagic(idx_path.to_owned())));
+        }
+        let version = be32(&idx, 4);
+        if version != 2 {
+            return Err(Error::new(PackError::UnsupportedVersion(
+                idx_path.to_owned(),
+                version,
+            )));
+        }
+
+        if pack.len() < 12 || &pack[..4] != PACK_SIGNATURE {
+            return Err(Error::new(PackError::BadMagic(path)));
+        }
+        let version = be32(&pack, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::new(PackError::UnsupportedVersion(path, version)));
+        }
+
+        let count = be32(&idx, IDX_HEADER_LEN + 255 * 4) as usize;
+        // ids, crcs and small offsets, then the two trailing checksums
+        if idx.len() < IDX_HEADER_LEN + FANOUT_LEN + count * 28 + 40 {
+            return Err(Error::new(PackError::Truncated(idx_path.to_owned())));
+        }
+
+        Ok(Pack {
+            path,
+            pack,
+            idx,
+            count,
+        })
+    }
+
+    /// Path to the .pack file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Number of objects in this pack
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Is the pack empty?
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn fanout(&self, byte: u8) -> usize {
+        be32(&self.idx, IDX_HEADER_LEN + byte as usize * 4) as usize
+    }
+
+    /// Gets the nth ID in the pack, in sorted order
+    pub fn id_at(&self, n: usize) -> Id {
+        let start = IDX_HEADER_LEN + FANOUT_LEN + n * 20;
+        Id::from_bytes(self.idx[start..start + 20].try_into().unwrap())
+    }
+
+    /// Iterates over the IDs of all objects in the pack, in sorted order
+    pub fn ids(&self) -> impl Iterator<Item = Id> + '_ {
+        (0..self.count).map(move |n| self.id_at(n))
+    }
+
+    /// Finds where an object is in the pack
+    pub fn find_offset(&self, id: &Id) -> Option<u64> {
+        // binary search the ids that share a first byte
+        let first = id.as_bytes()[0];
+        let mut lo = if first == 0 {
+            0
+        } else {
+            self.fanout(first - 1)
+        };
+        let mut hi = self.fanout(first);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.id_at(mid).as_bytes().cmp(id.as_bytes()) {
+                Ordering::Less => lo = mid + 1,
+                Ordering::Greater => hi = mid,
+                Ordering::Equal => return Some(self.offset_at(mid)),
+            }
+        }
+        None
+    }
+
+    /// Gets the offset of the nth object in the pack, in sorted order
+    pub fn offset_at(&self, n: usize) -> u64 {
+        let offsets = IDX_HEADER_LEN + FANOUT_LEN + self.count * 24;
+        let small = be32(&self.idx, offsets + n * 4);
+        if small & 0x8000_0000 == 0 {
+            return small as u64;
+        }
+        // large offsets are stored in a separate table of u64s
+        let large = offsets + self.count * 4 + (small & 0x7fff_ffff) as usize * 8;
+        be64(&self.idx, large)
+    }
+
+    /// The checksum of the pack, which is also its name
+    pub fn checksum(&self) -> Id {
+        let at = self.idx.len() - 40;
+        Id::from_bytes(self.idx[at..at + 20].try_into().unwrap())
+    }
+
+    /// Reads the header of the entry at `offset`
+    pub fn entry_header(&self, offset: u64) -> Result<EntryHeader> {
+        read_entry_header(&self.path, &self.pack, offset)
+    }
+
+    /// Inflates `size` bytes of zlib data starting at `offset`
+    fn inflate(&self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let data = self
+            .pack
+            .get(offset as usize..)
+            .ok_or_else(|| PackError::Truncated(self.path.clone()))?;
+        let mut buf = Vec::with_capacity(size.min(MAX_INFLATE_RESERVE) as usize);
+        ZlibDecoder::new(data)
+            .take(size + 1)
+            .read_to_end(&mut buf)
+            .with_context(|| format!("failed to inflate object in {}", self.path.display()))?;
+        if buf.len() as u64 != size {
+            return Err(Error::new(PackError::BadSize(self.path.clone(), offset)));
+        }
+        Ok(buf)
+    }
+
+    /// Reads what a delta entry is against, returning that along with the
+    /// offset of the delta's data, which follows it
+    pub fn delta_base(&self, offset: u64, header: &EntryHeader) -> Result<(DeltaBase, u64)> {
+        read_delta_base(&self.path, &self.pack, offset, header)
+    }
+
+    /// Reads the size of the object a delta makes, from the start of the delta
+    /// at `data_offset`, without inflating all of it
+    fn delta_result_size(&self, data_offset: u64) -> Result<u64> {
+        let data = self
+            .pack
+            .get(data_offset as usize..)
+            .ok_or_else(|| PackError::Truncated(self.path.clone()))?;
+        // two sizes of at most 10 bytes each
+        let mut start = Vec::with_capacity(20);
+        ZlibDecoder::new(data)
+            .take(20)
+            .read_to_end(&mut start)
+            .with_context(|| format!("failed to inflate delta in {}", self.path.display()))?;
+        let mut pos = 0;
+        delta_size(&start, &mut pos)
+            .and_then(|_| delta_size(&start, &mut pos))
+            .ok_or_else(|| Error::new(PackError::BadDelta("truncated")))
+    }
+
+    /// Reads the whole object at `offset`, resolving deltas against other
+    /// objects in this pack
+    pub fn read_at(&self, offset: u64) -> Result<(ObjectType, Vec<u8>)> {
+        // (data offset, size) of each delta down to the whole object
+        let mut deltas = Vec::new();
+        let mut at = offset;
+        let (objtype, mut data) = loop {
+            let header = self.entry_header(at)?;
+            if let EntryType::Object(objtype) = header.kind {
+                break (objtype, self.inflate(at + header.len as u64, header.size)?);
+            }
+            if deltas.len() == MAX_DELTA_CHAIN {
+                return Err(Error::new(PackError::DeltaChainTooLong(
+                    self.path.clone(),
+                    offset,
+                )));
+            }
+            let (base, data_offset) = self.delta_base(at, &header)?;
+            deltas.push((data_offset, header.size));
+            at = match base {
+                DeltaBase::Offset(base) => base,
+                DeltaBase::Id(id) => self
+                    .find_offset(&id)
+                    .ok_or_else(|| PackError::MissingBase(self.path.clone(), id, at))?,
+            };
+        };
+        for (data_offset, size) in deltas.into_iter().rev() {
+            let delta = self.inflate(data_offset, size)?;
+            data = apply_delta(&data, &delta)
+                .with_context(|| format!("in {}", self.path.display()))?;
+        }
+        Ok((objtype, data))
+    }
+}
+
+/// When a cached object was last used, its type and its data
+type CacheEntry = (u64, ObjectType, Arc<[u8]>);
+
+/// A cache of inflated objects keyed by where they are in which pack. When it
+/// goes over its size limit, the least recently used objects are dropped.
+#[derive(Default)]
+struct ObjectCache {
+    entries: HashMap<(usize, u64), CacheEntry>,
+    /// total size of the cached objects
+    size: usize,
+    /// ticks on each access, for finding the least recently used entries
+    clock: u64,
+}
+
+impl ObjectCache {
+    fn get(&mut self, key: (usize, u64)) -> Option<(ObjectType, Arc<[u8]>)> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(&key).map(|(used, objtype, data)| {
+            *used = clock;
+            (*objtype, data.clone())
+        })
+    }
+
+    fn insert(&mut self, key: (usize, u64), objtype: ObjectType, data: Arc<[u8]>) {
+        if data.len() > CACHE_LIMIT / 4 {
+            // caching this would push out everything else
+            return;
+        }
+        self.clock += 1;
+        self.size += data.len();
+        if let Some((_, _, old)) = self.entries.insert(key, (self.clock, objtype, data)) {
+            self.size -= old.len();
+        }
+
+        if self.size > CACHE_LIMIT {
+            let mut by_age = self
+                .entries
+                .iter()
+                .map(|(&key, &(used, _, ref data))| (used, key, data.len()))
+                .collect::<Vec<_>>();
+            by_age.sort_unstable();
+            // evict down to 3/4 so we don't do this on every insert
+            for (_, key, len) in by_age {
+                if self.size <= CACHE_LIMIT / 4 * 3 {
+                    break;
+                }
+                self.entries.remove(&key);
+                self.size -= len;
+            }
+        }
+    }
+}
+
+/// All the packs in a repository. Packs are found lazily on first use and the
+/// pack directory is scanned again when an object can't be found, in case a
+/// new pack has appeared.
+#[derive(Default)]
+pub struct Packs {
+    packs: RwLock<Vec<Arc<Pack>>>,
+    cache: Mutex<ObjectCache>,
+    /// modification time of the pack directory when we last scanned it
+    scanned: Mutex<Option<SystemTime>>,
+}
+
+impl Packs {
+    /// Loads any packs in `objects/pack` that haven't been loaded yet
+    fn rescan(&self, objects_dir: &Path, vfs: &dyn Vfs) -> Result<()> {
+        let dir = objects_dir.join("pack");
+
+        // adding or removing a pack changes the directory's mtime, so we
+        // don't need to list it on every missing object
+        let modified = vfs.metadata(&dir).ok().map(|m| m.modified);
+        {
+            let mut scanned = self.scanned.lock().unwrap();
+            if modified.is_some() && *scanned == modified {
+                return Ok(());
+            }
+            *scanned = modified;
+        }
+
+        let names = match vfs.read_dir(&dir) {
+            Ok(names) => names,
+            // no packs at all
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut packs = self.packs.write().unwrap();
+        for name in names {
+            let path = dir.join(name);
+            if path.extension().map_or(true, |ext| ext != "idx") {
+                continue;
+            }
+            let pack_path = path.with_extension("pack");
+            if packs.iter().any(|p| p.path == pack_path) {
+                continue;
+            }
+            trace!("loading pack {:?}", &pack_path);
+            packs.push(Arc::new(Pack::open(&path, vfs)?));
+        }
+        Ok(())
+    }
+
+    /// Forgets every pack, so that the pack directory is scanned again. Packs
+    /// that have been deleted would otherwise still be used.
+    pub(crate) fn forget(&self) {
+        self.packs.write().unwrap().clear();
+        *self.cache.lock().unwrap() = Default::default();
+        *self.scanned.lock().unwrap() = None;
+    }
+
+    /// Finds which pack an object is in and where
+    fn find(&self, id: &Id) -> Option<(usize, Arc<Pack>, u64)> {
+        let packs = self.packs.read().unwrap();
+        packs.iter().enumerate().find_map(|(n, pack)| {
+            pack.find_offset(id)
+                .map(|offset| (n, Arc::clone(pack), offset))
+        })
+    }
+
+    fn locate(
+        &self,
+        id: &Id,
+        objects_dir: &Path,
+        vfs: &dyn Vfs,
+    ) -> Result<Option<(usize, Arc<Pack>, u64)>> {
+        if let Some(found) = self.find(id) {
+            return Ok(Some(found));
+        }
+        self.rescan(objects_dir, vfs)?;
+        Ok(self.find(id))
+    }
+
+    /// Is the object with this ID in a pack?
+    pub fn contains(&self, id: &Id, objects_dir: &Path, vfs: &dyn Vfs) -> Result<bool> {
+        Ok(self.locate(id, objects_dir, vfs)?.is_some())
+    }
+
+    /// Finds where the base of a delta is, which for ref-deltas may be in
+    /// another pack
+    fn locate_base(
+        &self,
+        (n, pack, offset): (usize, Arc<Pack>, u64),
+        base: DeltaBase,
+        objects_dir: &Path,
+        vfs: &dyn Vfs,
+    ) -> Result<(usize, Arc<Pack>, u64)> {
+        match base {
+            DeltaBase::Offset(base) => Ok((n, pack, base)),
+            DeltaBase::Id(id) => self
+                .locate(&id, objects_dir, vfs)?
+                .ok_or_else(|| Error::new(PackError::MissingBase(pack.path.clone(), id, offset))),
+        }
+    }
+
+    /// Finds the type and size of a packed object without inflating it. The
+    /// type of a delta is that of the whole object at the end of its chain.
+    pub fn header(
+        &self,
+        id: &Id,
+        objects_dir: &Path,
+        vfs: &dyn Vfs,
+    ) -> Result<Option<(ObjectType, u64)>> {
+        let mut entry = match self.locate(id, objects_dir, vfs)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let (_, start_pack, start) = entry.clone();
+        let mut size = None;
+        for _ in 0..=MAX_DELTA_CHAIN {
+            let (_, pack, offset) = &entry;
+            let header = pack.entry_header(*offset)?;
+            if let EntryType::Object(objtype) = header.kind {
+                return Ok(Some((objtype, size.unwrap_or(header.size))));
+            }
+            let (base, data_offset) = pack.delta_base(*offset, &header)?;
+            if size.is_none() {
+                size = Some(pack.delta_result_size(data_offset)?);
+            }
+            entry = self.locate_base(entry, base, objects_dir, vfs)?;
+        }
+        Err(Error::new(PackError::DeltaChainTooLong(
+            start_pack.path.clone(),
+            start,
+        )))
+    }
+
+    /// Reads a packed object, or returns None if it is not in any pack
+    pub fn read(
+        &self,
+        id: &Id,
+        objects_dir: &Path,
+        vfs: &dyn Vfs,
+    ) -> Result<Option<(ObjectType, Arc<[u8]>)>> {
+        let entry = match self.locate(id, objects_dir, vfs)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        self.resolve(entry, objects_dir, vfs)
+            .with_context(|| format!("failed to read packed object {}", id))
+            .map(Some)
+    }
+
+    /// Reads the entry at an offset in a pack, following its delta chain down
+    /// to a whole object or one that's cached, then applying the deltas back
+    /// up. Everything made along the way is cached.
+    fn resolve(
+        &self,
+        mut entry: (usize, Arc<Pack>, u64),
+        objects_dir: &Path,
+        vfs: &dyn Vfs,
+    ) -> Result<(ObjectType, Arc<[u8]>)> {
+        let start = entry.2;
+        // each delta and where it is, down to the base
+        let mut deltas = Vec::new();
+        let (objtype, mut data) = loop {
+            let (n, pack, offset) = &entry;
+            if let Some(found) = self.cache.lock().unwrap().get((*n, *offset)) {
+                break found;
+            }
+            let header = pack.entry_header(*offset)?;
+            if let EntryType::Object(objtype) = header.kind {
+                let data: Arc<[u8]> = pack
+                    .inflate(offset + header.len as u64, header.size)?
+                    .into();
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert((*n, *offset), objtype, data.clone());
+                break (objtype, data);
+            }
+            if deltas.len() == MAX_DELTA_CHAIN {
+                return Err(Error::new(PackError::DeltaChainTooLong(
+                    pack.path.clone(),
+                    start,
+                )));
+            }
+            let (base, data_offset) = pack.delta_base(*offset, &header)?;
+            deltas.push((entry.clone(), data_offset, header.size));
+            entry = self.locate_base(entry, base, objects_dir, vfs)?;
+        };
+
+        for ((n, pack, offset), data_offset, size) in deltas.into_iter().rev() {
+            let delta = pack.inflate(data_offset, size)?;
+            data = apply_delta(&data, &delta)
+                .with_context(|| format!("at offset {} in {}", offset, pack.path.display()))?
+                .into();
+            self.cache
+                .lock()
+                .unwrap()
+                .insert((n, offset), objtype, data.clone());
+        }
+        Ok((objtype, data))
+    }
+
+    /// Lists the IDs of every packed object. Unsorted if there is more than
+    /// one pack.
+    pub fn all_ids(&self, objects_dir: &Path, vfs: &dyn Vfs) -> Result<Vec<Id>> {
+        self.rescan(objects_dir, vfs)?;
+        let packs = self.packs.read().unwrap();
+        Ok(packs.iter().flat_map(|p| p.ids()).collect())
+    }
+}
+
+/// A writer that keeps a SHA-1 of everything written, for pack checksums
+struct ChecksumWriter<W: Write> {
+    hasher: Sha1,
+    inner: W,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encodes the header of a pack entry: the type and the low four bits of the
+/// size, then the rest of the size seven bits at a time
+fn encode_entry_header(kind: EntryType, size: u64) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut byte = (kind.number() << 4) | (size & 0x0f) as u8;
+    let mut size = size >> 4;
+    while size != 0 {
+        out.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    out.push(byte);
+    out
+}
+
+/// Encodes how far back an ofs-delta's base is, the reverse of what
+/// [`Pack::delta_base`] reads
+fn encode_base_distance(mut distance: u64) -> Vec<u8> {
+    let mut out = vec![(distance & 0x7f) as u8];
+    distance >>= 7;
+    while distance != 0 {
+        distance -= 1;
+        out.push(0x80 | (distance & 0x7f) as u8);
+        distance >>= 7;
+    }
+    out.reverse();
+    out
+}
+
+/// Sums up a path so that objects with the same file name, or the same end
+/// of it such as `.c`, sort together. The last characters count the most.
+fn name_hash(path: &[u8]) -> u32 {
+    path.iter()
+        .filter(|c| !c.is_ascii_whitespace())
+        .fold(0, |hash: u32, &c| {
+            (hash >> 2).wrapping_add((c as u32) << 24)
+        })
+}
+
+fn type_order(kind: ObjectType) -> u8 {
+    match kind {
+        ObjectType::Commit => 0,
+        ObjectType::Tree => 1,
+        ObjectType::Blob => 2,
+        ObjectType::Tag => 3,
+    }
+}
+
+/// An object to be written as a delta
+struct Delta {
+    /// which of the objects being written the delta is against
+    base: usize,
+    data: Vec<u8>,
+}
+
+/// Reads pack.window and pack.depth
+fn delta_settings(repo: &Repo) -> Result<(usize, usize)> {
+    let config = repo.config()?;
+    let get = |key, default| -> Result<usize> {
+        Ok(config
+            .get_int(key)?
+            .map_or(default, |n: i64| n.max(0) as usize))
+    };
+    Ok((
+        get("pack.window", DEFAULT_WINDOW)?,
+        get("pack.depth", DEFAULT_DEPTH)?.min(MAX_DELTA_CHAIN),
+    ))
+}
+
+/// Picks which objects to store as deltas, and against what. Each object is
+/// tried against the `window` objects before it once they are sorted by type,
+/// name and size, keeping the smallest delta that is under half the size of
+/// the object, so long as that makes a chain no longer than `depth`. Objects
+/// from `written` on are only there to be bases, and get no deltas of their
+/// own.
+fn find_deltas(
+    repo: &Repo,
+    objects: &[FoundObject],
+    written: usize,
+    window: usize,
+    depth: usize,
+) -> Result<Vec<Option<Delta>>> {
+    let mut deltas: Vec<Option<Delta>> = objects.iter().map(|_| None).collect();
+    if window == 0 || depth == 0 {
+        return Ok(deltas);
+    }
+    let _region = trace::region("pack", "deltas");
+    let mut sizes = Vec::with_capacity(objects.len());
+    for obj in objects {
+        sizes.push(repo.object_header(&obj.id)?.1);
+    }
+    let mut order: Vec<usize> = (0..objects.len()).collect();
+    // bigger objects first, so smaller ones are made from them by deleting,
+    // though bases come before everything written since nothing is made
+    // from what is written
+    order.sort_by_key(|&n| {
+        let obj = &objects[n];
+        (
+            type_order(obj.kind),
+            Reverse(name_hash(&obj.path)),
+            n < written,
+            Reverse(sizes[n]),
+            n,
+        )
+    });
+
+    let mut chain = vec![0; objects.len()];
+    let mut recent: VecDeque<(usize, Vec<u8>, DeltaIndex)> = VecDeque::with_capacity(window);
+    for n in order {
+        if sizes[n] as usize > BIG_FILE_THRESHOLD {
+            continue;
+        }
+        let content = repo.read_object(&objects[n].id)?.1;
+        let mut best: Option<Delta> = None;
+        let candidates = if n < written { recent.len() } else { 0 };
+        for (base, base_content, index) in recent.iter().rev().take(candidates) {
+            if objects[*base].kind != objects[n].kind || chain[*base] >= depth {
+                continue;
+            }
+            // the delta needs to be smaller than the best one so far, and it
+            // isn't worth it unless it's at most half the size of the object
+            let max_size = match &best {
+                Some(delta) => delta.data.len() - 1,
+                None => (content.len() / 2).saturating_sub(20),
+            };
+            let size_difference = if base_content.len() > content.len() {
+                base_content.len() - content.len()
+            } else {
+                content.len() - base_content.len()
+            };
+            if size_difference >= max_size {
+                continue;
+            }
+            if let Some(data) = create_delta(base_content, index, &content, max_size) {
+                best = Some(Delta { base: *base, data });
+            }
+        }
+        if let Some(delta) = &best {
+            chain[n] = chain[delta.base] + 1;
+        }
+        deltas[n] = best;
+
+        if recent.len() == window {
+            recent.pop_front();
+        }
+        let index = DeltaIndex::new(&content);
+        recent.push_back((n, content, index));
+    }
+    Ok(deltas)
+}
+
+/// Where an object went in a pack being written, for the pack's index
+struct WrittenEntry {
+    id: Id,
+    /// CRC-32 of the entry as stored, header included
+    crc: u32,
+    offset: u64,
+}
+
+/// Writes a pack, returning its checksum and where each object went. Objects
+/// are written in the order given, except that the base of a delta always
+/// comes before the delta. Objects can also be stored as deltas against
+/// `bases`, which aren't written, making a thin pack.
+fn write_pack_entries(
+    repo: &Repo,
+    objects: &[FoundObject],
+    bases: &[FoundObject],
+    out: impl Write,
+) -> Result<(Id, Vec<WrittenEntry>)> {
+    let (window, depth) = delta_settings(repo)?;
+    let candidates: Cow<[FoundObject]> = if bases.is_empty() {
+        Cow::Borrowed(objects)
+    } else {
+        Cow::Owned(objects.iter().chain(bases).cloned().collect())
+    };
+    let deltas = find_deltas(repo, &candidates, objects.len(), window, depth)?;
+
+    let _region = trace::region("pack", "write");
+    let mut out = ChecksumWriter {
+        hasher: Sha1::new(),
+        inner: out,
+    };
+    out.write_all(PACK_SIGNATURE)?;
+    out.write_all(&2u32.to_be_bytes())?;
+    out.write_all(&(objects.len() as u32).to_be_bytes())?;
+
+    let mut offset = 12;
+    let mut offsets: Vec<Option<u64>> = vec![None; objects.len()];
+    let mut entries = Vec::with_capacity(objects.len());
+    for first in 0..objects.len() {
+        let mut stack = vec![first];
+        while let Some(&n) = stack.last() {
+            if offsets[n].is_some() {
+                stack.pop();
+                continue;
+            }
+            let entry = match &deltas[n] {
+                Some(delta) if delta.base >= objects.len() => {
+                    let mut header =
+                        encode_entry_header(EntryType::RefDelta, delta.data.len() as u64);
+                    header.extend_from_slice(candidates[delta.base].id.as_bytes());
+                    let mut squisher = ZlibEncoder::new(header, Compression::default());
+                    squisher.write_all(&delta.data)?;
+                    squisher.finish()?
+                }
+                Some(delta) => match offsets[delta.base] {
+                    Some(base_offset) => {
+                        let mut header =
+                            encode_entry_header(EntryType::OfsDelta, delta.data.len() as u64);
+                        header.extend(encode_base_distance(offset - base_offset));
+                        let mut squisher = ZlibEncoder::new(header, Compression::default());
+                        squisher.write_all(&delta.data)?;
+                        squisher.finish()?
+                    }
+                    None => {
+                        stack.push(delta.base);
+                        continue;
+                    }
+                },
+                None => {
+                    let (objtype, content) = repo.read_object(&objects[n].id)?;
+                    let header =
+                        encode_entry_header(EntryType::Object(objtype), content.len() as u64);
+                    let mut squisher = ZlibEncoder::new(header, Compression::default());
+                    squisher.write_all(&content)?;
+                    squisher.finish()?
+                }
+            };
+            stack.pop();
+
+            let mut crc = Crc::new();
+            crc.update(&entry);
+            entries.push(WrittenEntry {
+                id: objects[n].id,
+                crc: crc.sum(),
+                offset,
+            });
+            offsets[n] = Some(offset);
+            out.write_all(&entry)?;
+            offset += entry.len() as u64;
+        }
+    }
+
+    let checksum: [u8; 20] = out.hasher.result().into();
+    out.inner.write_all(&checksum)?;
+    out.inner.flush()?;
+    Ok((Id::from_bytes(checksum), entries))
+}
+
+/// Writes a version 2 pack holding the given objects to `out`, returning the
+/// pack's checksum, which is also its name. Objects that are alike are stored
+/// as deltas, as set by pack.window and pack.depth.
+pub fn write_pack(repo: &Repo, objects: &[FoundObject], out: impl Write) -> Result<Id> {
+    let (checksum, _) = write_pack_entries(repo, objects, &[], out)?;
+    Ok(checksum)
+}
+
+/// Writes a thin pack, as push sends: like [`write_pack`], but objects can be
+/// stored as deltas against `bases`, which the receiver already has, without
+/// the bases being in the pack
+pub fn write_thin_pack(
+    repo: &Repo,
+    objects: &[FoundObject],
+    bases: &[FoundObject],
+    out: impl Write,
+) -> Result<Id> {
+    let (checksum, _) = write_pack_entries(repo, objects, bases, out)?;
+    Ok(checksum)
+}
+
+/// Writes a version 2 index for a pack
+fn write_index(entries: &mut [WrittenEntry], pack_checksum: &Id, out: impl Write) -> Result<()> {
+    entries.sort_unstable_by(|a, b| a.id.as_bytes().cmp(b.id.as_bytes()));
+    let mut out = ChecksumWriter {
+        hasher: Sha1::new(),
+        inner: out,
+    };
+    out.write_all(IDX_SIGNATURE)?;
+    out.write_all(&2u32.to_be_bytes())?;
+
+    // how many IDs start with each byte or less
+    let mut count = 0;
+    for byte in 0..=255u8 {
+        while count < entries.len() && entries[count].id.as_bytes()[0] <= byte {
+            count += 1;
+        }
+        out.write_all(&(count as u32).to_be_bytes())?;
+    }
+    for entry in entries.iter() {
+        out.write_all(entry.id.as_bytes())?;
+    }
+    for entry in entries.iter() {
+        out.write_all(&entry.crc.to_be_bytes())?;
+    }
+    // offsets that don't fit in 31 bits go in a table of their own
+    let mut large = Vec::new();
+    for entry in entries.iter() {
+        let small = if entry.offset < 0x8000_0000 {
+            entry.offset as u32
+        } else {
+            large.push(entry.offset);
+            0x8000_0000 | (large.len() - 1) as u32
+        };
+        out.write_all(&small.to_be_bytes())?;
+    }
+    for offset in large {
+        out.write_all(&offset.to_be_bytes())?;
+    }
+    out.write_all(pack_checksum.as_bytes())?;
+
+    let checksum: [u8; 20] = out.hasher.result().into();
+    out.inner.write_all(&checksum)?;
+    out.inner.flush()?;
+    Ok(())
+}
+
+/// Writes a pack holding the given objects to `pack`, and its index to `idx`,
+/// returning the pack's checksum
+pub fn write_pack_with_index(
+    repo: &Repo,
+    objects: &[FoundObject],
+    pack: impl Write,
+    idx: impl Write,
+) -> Result<Id> {
+    let (checksum, mut entries) = write_pack_entries(repo, objects, &[], pack)?;
+    write_index(&mut entries, &checksum, idx)?;
+    Ok(checksum)
+}
+
+/// An entry of a pack being read from start to end
+struct ScannedEntry {
+    offset: u64,
+    header: EntryHeader,
+    /// what the entry is a delta against, if it is one
+    base: Option<DeltaBase>,
+    /// where the entry's compressed data starts
+    data_offset: u64,
+    crc: u32,
+}
+
+/// A pack whose entries have all been found, by reading it from start to
+/// end, though not yet what objects they hold
+struct ScannedPack<'a> {
+    path: &'a Path,
+    pack: &'a [u8],
+    checksum: Id,
+    entries: Vec<ScannedEntry>,
+    /// the deltas against the entry at each offset
+    by_offset: HashMap<u64, Vec<usize>>,
+    /// the deltas against each object ID
+    by_id: HashMap<Id, Vec<usize>>,
+}
+
+impl<'a> ScannedPack<'a> {
+    /// Checks `pack`, read from `path`, and finds each of its entries
+    fn scan(path: &'a Path, pack: &'a [u8]) -> Result<ScannedPack<'a>> {
+        let _region = trace::region("pack", "scan");
+        if pack.len() < 32 {
+            return Err(Error::new(PackError::Truncated(path.to_owned())));
+        }
+        if &pack[..4] != PACK_SIGNATURE {
+            return Err(Error::new(PackError::BadMagic(path.to_owned())));
+        }
+        let version = be32(pack, 4);
+        if version != 2 && version != 3 {
+            return Err(Error::new(PackError::UnsupportedVersion(
+                path.to_owned(),
+                version,
+            )));
+        }
+        let count = be32(pack, 8) as usize;
+        let (body, trailer) = pack.split_at(pack.len() - 20);
+        let checksum: [u8; 20] = Sha1::digest(body).into();
+        if checksum[..] != *trailer {
+            return Err(Error::new(PackError::BadChecksum(path.to_owned())));
+        }
+
+        // entries only say how big they are inflated, so the only way to find
+        // the end of one is to inflate it
+        let mut entries = Vec::with_capacity(count.min(body.len() / 2));
+        let mut offset = 12;
+        for _ in 0..count {
+            let header = read_entry_header(path, body, offset)?;
+            let (base, data_offset) = read_delta_base(path, body, offset, &header)?;
+            let base = match header.kind {
+                EntryType::Object(_) => None,
+                _ => Some(base),
+            };
+            let data = body
+                .get(data_offset as usize..)
+                .ok_or_else(|| PackError::Truncated(path.to_owned()))?;
+            let mut decoder = ZlibDecoder::new(data);
+            let size = io::copy(&mut decoder, &mut io::sink())
+                .with_context(|| format!("at offset {} in {}", offset, path.display()))?;
+            if size != header.size {
+                return Err(Error::new(PackError::BadSize(path.to_owned(), offset)));
+            }
+            let end = data_offset + decoder.total_in();
+
+            let mut crc = Crc::new();
+            crc.update(&body[offset as usize..end as usize]);
+            entries.push(ScannedEntry {
+                offset,
+                header,
+                base,
+                data_offset,
+                crc: crc.sum(),
+            });
+            offset = end;
+        }
+        if offset as usize != body.len() {
+            return Err(anyhow!("{} has data after its last object", path.display()));
+        }
+
+        let mut by_offset: HashMap<u64, Vec<usize>> = HashMap::new();
+        let mut by_id: HashMap<Id, Vec<usize>> = HashMap::new();
+        for (n, entry) in entries.iter().enumerate() {
+            match entry.base {
+                Some(DeltaBase::Offset(base)) => by_offset.entry(base).or_default().push(n),
+                Some(DeltaBase::Id(base)) => by_id.entry(base).or_default().push(n),
+                None => {}
+            }
+        }
+        Ok(ScannedPack {
+            path,
+            pack,
+            checksum: Id::from_bytes(checksum),
+            entries,
+            by_offset,
+            by_id,
+        })
+    }
+
+    /// Inflates the data of the `n`th entry
+    fn inflate(&self, n: usize) -> Result<Vec<u8>> {
+        let entry = &self.entries[n];
+        let mut data = Vec::with_capacity(entry.header.size.min(MAX_INFLATE_RESERVE) as usize);
+        ZlibDecoder::new(&self.pack[entry.data_offset as usize..])
+            .take(entry.header.size + 1)
+            .read_to_end(&mut data)
+            .with_context(|| format!("at offset {} in {}", entry.offset, self.path.display()))?;
+        if data.len() as u64 != entry.header.size {
+            return Err(Error::new(PackError::BadSize(
+                self.path.to_owned(),
+                entry.offset,
+            )));
+        }
+        Ok(data)
+    }
+
+    /// Finds the ID of each entry, calling `found` with the type, ID and
+    /// content of each object as it goes, bases before their deltas. Deltas
+    /// against objects that aren't in the pack are resolved against `repo`
+    /// if there is one.
+    fn resolve(
+        &self,
+        repo: Option<&Repo>,
+        mut found: impl FnMut(ObjectType, &Id, &[u8]) -> Result<()>,
+    ) -> Result<Vec<Id>> {
+        let _region = trace::region("pack", "resolve");
+        let mut ids: Vec<Option<Id>> = vec![None; self.entries.len()];
+        for (n, entry) in self.entries.iter().enumerate() {
+            if let EntryType::Object(objtype) = entry.header.kind {
+                let content = self.inflate(n)?;
+                self.resolve_from(Some(n), objtype, content, &mut ids, &mut found)?;
+            }
+        }
+        if let Some(repo) = repo {
+            for (base, deltas) in &self.by_id {
+                if deltas.iter().any(|&n| ids[n].is_none()) && repo.has_id(base) {
+                    let (objtype, content) = repo.read_object(base)?;
+                    self.resolve_from(None, objtype, content, &mut ids, &mut found)?;
+                }
+            }
+        }
+
+        let unresolved = ids.iter().filter(|id| id.is_none()).count();
+        if unresolved != 0 {
+            return Err(Error::new(PackError::UnresolvedDeltas(
+                self.path.to_owned(),
+                unresolved,
+            )));
+        }
+        Ok(ids.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Resolves the deltas against an object, and those against them, and so
+    /// on, so that each base is only inflated once. `entry` is the object's
+    /// entry, if it is in the pack.
+    fn resolve_from(
+        &self,
+        entry: Option<usize>,
+        objtype: ObjectType,
+        content: Vec<u8>,
+        ids: &mut [Option<Id>],
+        found: &mut impl FnMut(ObjectType, &Id, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let mut stack = vec![(entry, content)];
+        while let Some((n, content)) = stack.pop() {
+            let id = Object::hash_raw(objtype, &content)?;
+            let offset = match n {
+                Some(n) => {
+                    ids[n] = Some(id);
+                    found(objtype, &id, &content)?;
+                    Some(self.entries[n].offset)
+                }
+                None => None,
+            };
+            let deltas = offset
+                .and_then(|offset| self.by_offset.get(&offset))
+                .into_iter()
+                .chain(self.by_id.get(&id))
+                .flatten();
+            for &delta in deltas {
+                if ids[delta].is_some() {
+                    continue;
+                }
+                let result = apply_delta(&content, &self.inflate(delta)?).with_context(|| {
+                    format!(
+                        "at offset {} in {}",
+                        self.entries[delta].offset,
+                        self.path.display()
+                    )
+                })?;
+                stack.push((Some(delta), result));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the ID of every object in `pack`, read from `path`, and writes a
+/// version 2 index for it to `out`, returning the pack's checksum. Every
+/// delta must be against an object in the same pack.
+pub fn index_pack(path: &Path, pack: &[u8], out: impl Write) -> Result<Id> {
+    let _region = trace::region("pack", "index");
+    let scanned = ScannedPack::scan(path, pack)?;
+    let ids = scanned.resolve(None, |_, _, _| Ok(()))?;
+    let mut entries: Vec<WrittenEntry> = scanned
+        .entries
+        .iter()
+        .zip(ids)
+        .map(|(entry, id)| WrittenEntry {
+            id,
+            crc: entry.crc,
+            offset: entry.offset,
+        })
+        .collect();
+    write_index(&mut entries, &scanned.checksum, out)?;
+    Ok(scanned.checksum)
+}
+
+/// A reader that keeps a copy of everything read through it
+struct Recorder<'a, R> {
+    inner: &'a mut R,
+    recorded: Vec<u8>,
+}
+
+impl<R: BufRead> Read for Recorder<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(out)?;
+        self.recorded.extend_from_slice(&out[..len]);
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for Recorder<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // what is consumed is the start of what fill_buf returned, which is
+        // still buffered
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.recorded.extend_from_slice(&buf[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+/// Reads a pack from a stream that goes on after it, as a push does, and
+/// returns it. `path` names the stream in errors. Entries are only inflated
+/// to find where they end; nothing is resolved.
+pub fn read_pack_stream(path: &Path, input: &mut impl BufRead) -> Result<Vec<u8>> {
+    let mut input = Recorder {
+        inner: input,
+        recorded: Vec::new(),
+    };
+    let mut header = [0; 12];
+    input.read_exact(&mut header)?;
+    if &header[..4] != PACK_SIGNATURE {
+        return Err(Error::new(PackError::BadMagic(path.to_owned())));
+    }
+    let count = be32(&header, 8);
+
+    let mut byte = [0; 1];
+    for _ in 0..count {
+        let offset = input.recorded.len() as u64;
+        input.read_exact(&mut byte)?;
+        let number = (byte[0] >> 4) & 0b111;
+        let kind = EntryType::from_number(number)
+            .ok_or_else(|| PackError::BadType(path.to_owned(), number, offset))?;
+        while byte[0] & 0x80 != 0 {
+            input.read_exact(&mut byte)?;
+        }
+        match kind {
+            EntryType::Object(_) => {}
+            EntryType::OfsDelta => {
+                input.read_exact(&mut byte)?;
+                while byte[0] & 0x80 != 0 {
+                    input.read_exact(&mut byte)?;
+                }
+            }
+            EntryType::RefDelta => input.read_exact(&mut [0; 20])?,
+        }
+        io::copy(&mut ZlibDecoder::new(&mut input), &mut io::sink())
+            .with_context(|| format!("at offset {} in {}", offset, path.display()))?;
+    }
+    input.read_exact(&mut [0; 20])?;
+    Ok(input.recorded)
+}
+
+/// Stores every object in `pack`, read from `path`, as a loose object,
+/// returning how many objects it held. Deltas can be against objects already
+/// in the repository, as they are in the thin packs sent by push and fetch.
+/// With `dry_run`, the pack is only checked.
+pub fn unpack_objects(repo: &Repo, path: &Path, pack: &[u8], dry_run: bool) -> Result<usize> {
+    let _region = trace::region("pack", "unpack");
+    let scanned = ScannedPack::scan(path, pack)?;
+    let ids = scanned.resolve(Some(repo), |objtype, id, content| {
+        if !dry_run && !repo.has_id(id) {
+            repo.store_raw(objtype, content)?;
+        }
+        Ok(())
+    })?;
+    Ok(ids.len())
+}
+
+/// Packs the given objects into a new pack in the repository, with an index
+/// so they can be read from it, and returns the pack's name. Nothing sees the
+/// pack until both files are complete.
+pub fn store_pack(repo: &Repo, objects: &[FoundObject]) -> Result<Id> {
+    let vfs = repo.vfs();
+    let dir = repo.objects_dir().join("pack");
+    vfs.create_dir_all(&dir)?;
+
+    let temp = repo.temp_file(dir.join(format!("tmp_pack_{}", util::process_id())));
+    let temp_idx = repo.temp_file(dir.join(format!("tmp_idx_{}", util::process_id())));
+    let checksum = write_pack_with_index(
+        repo,
+        objects,
+        vfs.create(temp.path())?,
+        vfs.create(temp_idx.path())?,
+    )?;
+
+    // the index goes last, since packs are found by their indexes
+    let name = dir.join(format!("pack-{}", checksum));
+    vfs.rename(temp.path(), &name.with_extension("pack"))?;
+    temp.keep();
+    vfs.rename(temp_idx.path(), &name.with_extension("idx"))?;
+    temp_idx.keep();
+    repo.objects_changed();
+    Ok(checksum)
+}
+
+/// Indexes a pack that came from elsewhere, such as from a fetch, and puts it
+/// in the repository with its index, returning the pack's name. Every delta
+/// must be against an object in the same pack.
+pub fn store_received_pack(repo: &Repo, pack: &[u8]) -> Result<Id> {
+    let vfs = repo.vfs();
+    let dir = repo.objects_dir().join("pack");
+    vfs.create_dir_all(&dir)?;
+
+    let temp = repo.temp_file(dir.join(format!("tmp_pack_{}", util::process_id())));
+    let temp_idx = repo.temp_file(dir.join(format!("tmp_idx_{}", util::process_id())));
+    vfs.write(temp.path(), pack)?;
+    let checksum = index_pack(temp.path(), pack, vfs.create(temp_idx.path())?)?;
+
+    let name = dir.join(format!("pack-{}", checksum));
+    vfs.rename(temp.path(), &name.with_extension("pack"))?;
+    temp.keep();
+    vfs.rename(temp_idx.path(), &name.with_extension("idx"))?;
+    temp_idx.keep();
+    repo.objects_changed();
+    Ok(checksum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_delta, create_delta, encode_base_distance, index_pack, read_delta_base,
+        read_entry_header, read_pack_stream, store_pack, unpack_objects, write_pack,
+        write_pack_with_index, write_thin_pack, DeltaBase, DeltaIndex, EntryHeader, EntryType,
+        Pack,
+    };
+    use crate::objects::{Blob, Id, ObjectType, Repo};
+    use crate::revwalk::FoundObject;
+    use crate::vfs::{MemoryFs, Vfs};
+    use flate2::write::ZlibEncoder;
+    use flate2::Compression;
+    use sha1::{Digest, Sha1};
+    use std::io::{self, Write};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// Blobs to write to a pack, all found at `path`
+    fn blobs(ids: &[Id], path: &str) -> Vec<FoundObject> {
+        ids.iter()
+            .map(|&id| FoundObject {
+                id,
+                kind: ObjectType::Blob,
+                path: path.into(),
+            })
+            .collect()
+    }
+
+    fn blob_id(blob: &[u8]) -> Id {
+        let mut hasher = Sha1::new();
+        hasher.input(format!("blob {}\0", blob.len()));
+        hasher.input(blob);
+        Id::from_bytes(hasher.result().into())
+    }
+
+    /// Encodes a pack entry: the type and size, in 4 + 7 bit chunks, then
+    /// the delta base if there is one, then the compressed data
+    fn entry(kind: u8, base: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut size = data.len();
+        let mut byte = (kind << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        while size != 0 {
+            out.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        out.push(byte);
+        out.extend_from_slice(base);
+
+        let mut enc = ZlibEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(data).unwrap();
+        out.extend(enc.finish().unwrap());
+        out
+    }
+
+    /// Builds a pack and v2 index holding the given blobs
+    fn make_pack(blobs: &[&[u8]]) -> (Vec<u8>, Vec<u8>, Vec<Id>) {
+        let entries = blobs
+            .iter()
+            .map(|blob| (blob_id(blob), entry(3, &[], blob)))
+            .collect();
+        build_pack(entries)
+    }
+
+    /// Builds a pack and v2 index out of encoded entries and the IDs of the
+    /// objects in them
+    fn build_pack(raw: Vec<(Id, Vec<u8>)>) -> (Vec<u8>, Vec<u8>, Vec<Id>) {
+        let mut pack = b"PACK\0\0\0\x02".to_vec();
+        pack.extend(&(raw.len() as u32).to_be_bytes());
+        let mut entries = Vec::new();
+        for (id, data) in raw {
+            entries.push((id, pack.len() as u32));
+            pack.extend(data);
+        }
+        let checksum: [u8; 20] = Sha1::digest(&pack).into();
+        pack.extend(&checksum);
+
+        entries.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+        let mut idx = b"\xfftOc\0\0\0\x02".to_vec();
+        for byte in 0..=255u8 {
+            let n = entries
+                .iter()
+                .filter(|(id, _)| id.as_bytes()[0] <= byte)
+                .count() as u32;
+            idx.extend(&n.to_be_bytes());
+        }
+        for (id, _) in &entries {
+            idx.extend(id.as_bytes());
+        }
+        for _ in &entries {
+            // crcs are not checked when reading
+            idx.extend(&[0u8; 4]);
+        }
+        for (_, offset) in &entries {
+            idx.extend(&offset.to_be_bytes());
+        }
+        idx.extend(&checksum);
+        idx.extend(&[0u8; 20]);
+
+        let ids = entries.into_iter().map(|(id, _)| id).collect();
+        (pack, idx, ids)
+    }
+
+    #[test]
+    fn test_read_pack() {
+        let big = vec![b'x'; 1000];
+        let (pack, idx, ids) = make_pack(&[b"hello\n", &big]);
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/pack")).unwrap();
+        fs.write(Path::new("/pack/test.pack"), &pack).unwrap();
+        fs.write(Path::new("/pack/test.idx"), &idx).unwrap();
+
+        let pack = Pack::open(Path::new("/pack/test.idx"), &fs).unwrap();
+        assert_eq!(pack.len(), 2);
+        assert_eq!(pack.ids().collect::<Vec<_>>(), ids);
+
+        // `git hash-object` of "hello\n"
+        let hello = Id::from("ce013625030ba8dba906f756967f9e9ca394464a").unwrap();
+        let offset = pack.find_offset(&hello).unwrap();
+        assert_eq!(offset, 12);
+        assert_eq!(
+            pack.entry_header(offset).unwrap().kind,
+            EntryType::Object(ObjectType::Blob)
+        );
+        assert_eq!(
+            pack.read_at(offset).unwrap(),
+            (ObjectType::Blob, b"hello\n".to_vec())
+        );
+
+        let other = ids.iter().find(|&&id| id != hello).unwrap();
+        let (_, data) = pack.read_at(pack.find_offset(other).unwrap()).unwrap();
+        assert_eq!(data, big);
+
+        assert_eq!(pack.find_offset(&Id::from_bytes([0xff; 20])), None);
+
+        // a size that goes on past 64 bits is refused rather than overflowing
+        let mut long = vec![0xb0];
+        long.extend(&[0xff; 9]);
+        long.push(1);
+        assert!(read_entry_header(Path::new("/pack/test.pack"), &long, 0).is_err());
+    }
+
+    /// Encodes a size at the start of a delta
+    fn delta_size(mut n: usize, out: &mut Vec<u8>) {
+        while n >= 0x80 {
+            out.push((n & 0x7f) as u8 | 0x80);
+            n >>= 7;
+        }
+        out.push(n as u8);
+    }
+
+    /// Makes a delta that copies the start of the base, then inserts `tail`
+    fn make_delta(base: &[u8], keep: usize, tail: &[u8]) -> Vec<u8> {
+        let mut delta = Vec::new();
+        delta_size(base.len(), &mut delta);
+        delta_size(keep + tail.len(), &mut delta);
+        // copy from offset 0, with one byte of size
+        delta.extend(&[0x90, keep as u8]);
+        delta.push(tail.len() as u8);
+        delta.extend_from_slice(tail);
+        delta
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let base = b"hello world\n";
+        let delta = make_delta(base, 6, b"there\n");
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"hello there\n");
+
+        // copying from the middle, with offset and size bytes
+        let delta = [12, 5, 0x91, 6, 5];
+        assert_eq!(apply_delta(base, &delta).unwrap(), b"world");
+
+        assert!(apply_delta(b"short", &make_delta(base, 6, b"")).is_err());
+        assert!(apply_delta(base, &[12, 20, 0x91, 6, 20]).is_err());
+        assert!(apply_delta(base, &[12, 1, 0]).is_err());
+        assert!(apply_delta(base, &[12, 9, 5, b'a']).is_err());
+        // a result far bigger than the delta could make isn't allocated
+        let huge = [
+            12, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x0f, 1, b'a',
+        ];
+        assert!(apply_delta(base, &huge).is_err());
+        // making more than the delta said is caught
+        assert!(apply_delta(base, &[12, 1, 2, b'a', b'b']).is_err());
+    }
+
+    #[test]
+    fn test_read_deltas() {
+        // a blob, an ofs-delta against it and a ref-delta against that
+        let base = b"hello world, this is the base object\n".to_vec();
+        let first = b"hello world, this is a delta\n".to_vec();
+        let second = b"hello world, this is a delta\nand another one\n".to_vec();
+        let base_entry = entry(3, &[], &base);
+        let ofs = entry(
+            6,
+            &[base_entry.len() as u8],
+            &make_delta(&base, 21, b"a delta\n"),
+        );
+        let first_id = blob_id(&first);
+        let refd = entry(
+            7,
+            first_id.as_bytes(),
+            &make_delta(&first, first.len(), b"and another one\n"),
+        );
+        let ofs_offset = 12 + base_entry.len() as u64;
+        let refd_offset = ofs_offset + ofs.len() as u64;
+        let (pack, idx, _) = build_pack(vec![
+            (blob_id(&base), base_entry),
+            (first_id, ofs),
+            (blob_id(&second), refd),
+        ]);
+
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let dir = repo.objects_dir().join("pack");
+        fs.create_dir_all(&dir).unwrap();
+        fs.write(&dir.join("pack-test.pack"), &pack).unwrap();
+        fs.write(&dir.join("pack-test.idx"), &idx).unwrap();
+
+        let pack = Pack::open(&dir.join("pack-test.idx"), &*fs).unwrap();
+        let header = pack.entry_header(ofs_offset).unwrap();
+        assert_eq!(header.kind, EntryType::OfsDelta);
+        assert_eq!(
+            pack.delta_base(ofs_offset, &header).unwrap().0,
+            DeltaBase::Offset(12)
+        );
+        let header = pack.entry_header(refd_offset).unwrap();
+        assert_eq!(
+            pack.delta_base(refd_offset, &header).unwrap().0,
+            DeltaBase::Id(first_id)
+        );
+        assert_eq!(
+            pack.read_at(refd_offset).unwrap(),
+            (ObjectType::Blob, second.clone())
+        );
+
+        let second_id = blob_id(&second);
+        assert_eq!(
+            repo.object_header(&second_id).unwrap(),
+            (ObjectType::Blob, second.len() as u64)
+        );
+        // once from scratch, then with the bases cached
+        for _ in 0..2 {
+            assert_eq!(
+                repo.read_object(&second_id).unwrap(),
+                (ObjectType::Blob, second.clone())
+            );
+            assert_eq!(
+                repo.read_object(&first_id).unwrap(),
+                (ObjectType::Blob, first.clone())
+            );
+        }
+
+        // a base distance too big for 64 bits is refused rather than wrapping
+        let header = EntryHeader {
+            kind: EntryType::OfsDelta,
+            size: 0,
+            len: 1,
+        };
+        let mut long = vec![0x60];
+        long.extend(&[0xff; 10]);
+        long.push(1);
+        assert!(read_delta_base(Path::new("/pack/test.pack"), &long, 0, &header).is_err());
+    }
+
+    #[test]
+    fn test_create_delta() {
+        let base: Vec<u8> = (0..2000)
+            .flat_map(|n| format!("line {}\n", n).into_bytes())
+            .collect();
+        let index = DeltaIndex::new(&base);
+        let mut target = base.clone();
+        target.splice(100..120, b"something else".iter().copied());
+        target.extend_from_slice(b"a new line at the end\n");
+
+        let delta = create_delta(&base, &index, &target, usize::MAX).unwrap();
+        assert!(delta.len() < 100);
+        assert_eq!(apply_delta(&base, &delta).unwrap(), target);
+        assert_eq!(create_delta(&base, &index, &target, 10), None);
+
+        // copies are split up, and things with nothing in common still work
+        let long = vec![b'x'; 300_000];
+        let delta = create_delta(&long, &DeltaIndex::new(&long), &long, usize::MAX).unwrap();
+        assert_eq!(apply_delta(&long, &delta).unwrap(), long);
+        let delta = create_delta(&base, &index, b"unrelated", usize::MAX).unwrap();
+        assert_eq!(apply_delta(&base, &delta).unwrap(), b"unrelated");
+        let delta = create_delta(&base, &index, b"", usize::MAX).unwrap();
+        assert_eq!(apply_delta(&base, &delta).unwrap(), b"");
+
+        assert_eq!(encode_base_distance(100), [100]);
+        assert_eq!(encode_base_distance(128), [0x80, 0]);
+        assert_eq!(encode_base_distance(300), [0x81, 0x2c]);
+    }
+
+    #[test]
+    fn test_write_deltas() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        // versions of a file that each add a line
+        let mut ids = Vec::new();
+        let mut content = Vec::new();
+        for n in 0..30 {
+            content.extend(format!("this is line number {} of the file\n", n).into_bytes());
+            ids.push(repo.store(&Blob::new(content.clone())).unwrap());
+        }
+        ids.reverse();
+        let objects = blobs(&ids, "file.txt");
+
+        let (mut pack, mut idx) = (Vec::new(), Vec::new());
+        write_pack_with_index(&repo, &objects, &mut pack, &mut idx).unwrap();
+        fs.create_dir(Path::new("/out")).unwrap();
+        fs.write(Path::new("/out/test.pack"), &pack).unwrap();
+        fs.write(Path::new("/out/test.idx"), &idx).unwrap();
+        let written = Pack::open(Path::new("/out/test.idx"), &*fs).unwrap();
+        let kinds: Vec<_> = ids
+            .iter()
+            .map(|id| {
+                let offset = written.find_offset(id).unwrap();
+                written.entry_header(offset).unwrap().kind
+            })
+            .collect();
+        assert_eq!(kinds[0], EntryType::Object(ObjectType::Blob));
+        // the smallest versions aren't worth it
+        assert!(kinds[1..25].iter().all(|&kind| kind == EntryType::OfsDelta));
+        for id in &ids {
+            let (_, data) = written.read_at(written.find_offset(id).unwrap()).unwrap();
+            assert_eq!(data, repo.read_object(id).unwrap().1);
+        }
+
+        // pack.depth limits how long the chains get, and a window of 0 turns
+        // deltas off, which makes for a much bigger pack
+        fs.write(
+            Path::new("/repo/.git/config"),
+            b"[pack]\n\tdepth = 1\n\twindow = 30\n",
+        )
+        .unwrap();
+        let mut shallow = Vec::new();
+        write_pack(&repo, &objects, &mut shallow).unwrap();
+        assert!(shallow.len() < pack.len() * 3);
+        fs.write(Path::new("/repo/.git/config"), b"[pack]\n\twindow = 0\n")
+            .unwrap();
+        let mut whole = Vec::new();
+        write_pack(&repo, &objects, &mut whole).unwrap();
+        assert!(whole.len() > pack.len() * 3);
+    }
+
+    #[test]
+    fn test_write_pack() {
+        let repo = Repo::in_memory();
+        let hello = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+
+        let mut written = Vec::new();
+        let checksum = write_pack(&repo, &blobs(&[hello], ""), &mut written).unwrap();
+        let (pack, _, _) = make_pack(&[b"hello\n"]);
+        assert_eq!(written, pack);
+        assert_eq!(checksum.as_bytes()[..], pack[pack.len() - 20..]);
+    }
+
+    #[test]
+    fn test_write_pack_with_index() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let hello = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let big = repo.store(&Blob::new(vec![b'x'; 1000])).unwrap();
+
+        let (mut pack, mut idx) = (Vec::new(), Vec::new());
+        let checksum =
+            write_pack_with_index(&repo, &blobs(&[big, hello], ""), &mut pack, &mut idx).unwrap();
+        assert_eq!(checksum.as_bytes()[..], pack[pack.len() - 20..]);
+        // the index ends with the pack's checksum, then its own
+        assert_eq!(idx[idx.len() - 40..idx.len() - 20], pack[pack.len() - 20..]);
+
+        fs.create_dir(Path::new("/out")).unwrap();
+        fs.write(Path::new("/out/test.pack"), &pack).unwrap();
+        fs.write(Path::new("/out/test.idx"), &idx).unwrap();
+        let pack = Pack::open(Path::new("/out/test.idx"), &*fs).unwrap();
+        assert_eq!(pack.len(), 2);
+        assert_eq!(
+            pack.read_at(pack.find_offset(&hello).unwrap()).unwrap(),
+            (ObjectType::Blob, b"hello\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_index_pack() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let mut ids = Vec::new();
+        let mut content = Vec::new();
+        for n in 0..20 {
+            content.extend(format!("this is line number {} of the file\n", n).into_bytes());
+            ids.push(repo.store(&Blob::new(content.clone())).unwrap());
+        }
+        ids.reverse();
+
+        // an index of a pack with deltas is the same as the one written with
+        // it
+        let (mut pack, mut idx) = (Vec::new(), Vec::new());
+        let checksum =
+            write_pack_with_index(&repo, &blobs(&ids, "file.txt"), &mut pack, &mut idx).unwrap();
+        let mut indexed = Vec::new();
+        let path = Path::new("test.pack");
+        assert_eq!(index_pack(path, &pack, &mut indexed).unwrap(), checksum);
+        assert_eq!(indexed, idx);
+
+        // ref-deltas can come before their bases
+        let base = b"hello world, this is the base object\n".to_vec();
+        let derived = b"hello world, this is a delta\n".to_vec();
+        let refd = entry(
+            7,
+            blob_id(&base).as_bytes(),
+            &make_delta(&base, 21, b"a delta\n"),
+        );
+        let (pack, _, mut expected) = build_pack(vec![
+            (blob_id(&derived), refd),
+            (blob_id(&base), entry(3, &[], &base)),
+        ]);
+        let mut indexed = Vec::new();
+        index_pack(path, &pack, &mut indexed).unwrap();
+        fs.create_dir(Path::new("/out")).unwrap();
+        fs.write(Path::new("/out/test.pack"), &pack).unwrap();
+        fs.write(Path::new("/out/test.idx"), &indexed).unwrap();
+        let opened = Pack::open(Path::new("/out/test.idx"), &*fs).unwrap();
+        expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(opened.ids().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            opened
+                .read_at(opened.find_offset(&blob_id(&derived)).unwrap())
+                .unwrap()
+                .1,
+            derived
+        );
+
+        // a thin pack can't be indexed on its own
+        let refd = entry(
+            7,
+            blob_id(&base).as_bytes(),
+            &make_delta(&base, 21, b"a delta\n"),
+        );
+        let (thin, _, _) = build_pack(vec![(blob_id(&derived), refd)]);
+        assert!(index_pack(path, &thin, io::sink()).is_err());
+
+        let mut corrupt = pack.clone();
+        corrupt[20] ^= 1;
+        assert!(index_pack(path, &corrupt, io::sink()).is_err());
+    }
+
+    #[test]
+    fn test_unpack_objects() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let mut ids = Vec::new();
+        let mut content = Vec::new();
+        for n in 0..20 {
+            content.extend(format!("this is line number {} of the file\n", n).into_bytes());
+            ids.push(repo.store(&Blob::new(content.clone())).unwrap());
+        }
+        let mut pack = Vec::new();
+        write_pack(&repo, &blobs(&ids, "file.txt"), &mut pack).unwrap();
+
+        let path = Path::new("test.pack");
+        let other = Repo::in_memory_at(&fs, "/other");
+        assert_eq!(
+            unpack_objects(&other, path, &pack, false).unwrap(),
+            ids.len()
+        );
+        for id in &ids {
+            assert!(fs.exists(&other.path_for_object(id)));
+            assert_eq!(
+                other.read_object(id).unwrap(),
+                repo.read_object(id).unwrap()
+            );
+        }
+
+        // a thin pack's deltas can be against objects already there
+        let base = b"hello world, this is the base object\n".to_vec();
+        let derived = b"hello world, this is a delta\n".to_vec();
+        let refd = entry(
+            7,
+            blob_id(&base).as_bytes(),
+            &make_delta(&base, 21, b"a delta\n"),
+        );
+        let (thin, _, _) = build_pack(vec![(blob_id(&derived), refd)]);
+        assert!(unpack_objects(&other, path, &thin, false).is_err());
+        other.store(&Blob::new(base)).unwrap();
+        assert_eq!(unpack_objects(&other, path, &thin, true).unwrap(), 1);
+        assert!(!other.has_id(&blob_id(&derived)));
+        assert_eq!(unpack_objects(&other, path, &thin, false).unwrap(), 1);
+        assert_eq!(
+            other.read_object(&blob_id(&derived)).unwrap(),
+            (ObjectType::Blob, derived)
+        );
+    }
+
+    #[test]
+    fn test_thin_pack() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let mut ids = Vec::new();
+        let mut content = Vec::new();
+        for n in 0..20 {
+            content.extend(format!("this is line number {} of the file\n", n).into_bytes());
+            ids.push(repo.store(&Blob::new(content.clone())).unwrap());
+        }
+        // the receiver has an older version
+        let (bases, objects) = (&ids[14..15], &ids[15..]);
+        let mut thin = Vec::new();
+        write_thin_pack(
+            &repo,
+            &blobs(objects, "file.txt"),
+            &blobs(bases, "file.txt"),
+            &mut thin,
+        )
+        .unwrap();
+        let mut whole = Vec::new();
+        write_pack(&repo, &blobs(objects, "file.txt"), &mut whole).unwrap();
+        assert!(thin.len() < whole.len());
+        assert_eq!(&thin[8..12], &[0, 0, 0, 5]);
+
+        // the pack is found in a stream with more after it
+        let mut stream = thin.clone();
+        stream.extend_from_slice(b"more");
+        let mut input = &stream[..];
+        let path = Path::new("test.pack");
+        assert_eq!(read_pack_stream(path, &mut input).unwrap(), thin);
+        assert_eq!(input, b"more");
+        assert!(read_pack_stream(path, &mut &thin[..40]).is_err());
+
+        let other = Repo::in_memory_at(&fs, "/other");
+        assert!(unpack_objects(&other, path, &thin, false).is_err());
+        for id in bases {
+            let (objtype, content) = repo.read_object(id).unwrap();
+            other.store_raw(objtype, &content).unwrap();
+        }
+        assert_eq!(unpack_objects(&other, path, &thin, false).unwrap(), 5);
+        for id in objects {
+            assert_eq!(
+                other.read_object(id).unwrap(),
+                repo.read_object(id).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_store_pack() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        let hello = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let big = repo.store(&Blob::new(vec![b'x'; 1000])).unwrap();
+
+        let name = store_pack(&repo, &blobs(&[hello, big], "")).unwrap();
+        let idx_path = repo
+            .objects_dir()
+            .join("pack")
+            .join(format!("pack-{}.idx", name));
+        let pack = Pack::open(&idx_path, &*fs).unwrap();
+        let mut ids = vec![hello, big];
+        ids.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(pack.ids().collect::<Vec<_>>(), ids);
+        assert_eq!(pack.find_offset(&hello), Some(12));
+
+        // the pack is used once the loose objects are gone
+        fs.remove_file(&repo.path_for_object(&hello)).unwrap();
+        assert_eq!(
+            repo.read_object(&hello).unwrap(),
+            (ObjectType::Blob, b"hello\n".to_vec())
+        );
+    }
+}
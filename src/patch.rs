@@ -0,0 +1,880 @@
+//! Commits as patch emails, the way they are sent to mailing lists with
+//! `git format-patch`. Each email has the commit's author, date and subject in
+//! its headers, then the rest of the message, a diffstat and the diff.
+//!
+//! Emails can also be read back, as `git am` does, from mailboxes or from
+//! single patch files.
+use anyhow::{anyhow, Result};
+use bstr::ByteSlice;
+use chrono::{DateTime, FixedOffset};
+use std::io::Write;
+use thiserror::Error;
+
+use crate::diff::{self, DEFAULT_CONTEXT};
+use crate::num;
+use crate::objects::{Commit, FileMode, Id, NameEntry, Object, Repo};
+use crate::tree::{self, Change};
+use crate::util;
+
+/// Digits of object IDs shown on `index` lines
+const ABBREV: usize = 7;
+
+/// Widest the diffstat gets, like git's for email
+const STAT_WIDTH: usize = 72;
+
+/// Longest subject part of a patch file name
+const NAME_MAX: usize = 52;
+
+/// How much a file changed, for the diffstat
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stat {
+    /// Lines inserted and deleted in a text file
+    Lines(usize, usize),
+    /// Sizes before and after of a binary file
+    Binary(usize, usize),
+}
+
+/// The diff of one file in git's format, from the `diff --git` line on
+#[derive(Debug)]
+pub struct FileDiff {
+    /// The change being shown
+    pub change: Change,
+    /// How much changed
+    pub stat: Stat,
+    /// The text of the diff
+    pub text: Vec<u8>,
+}
+
+/// What a side of a change has in it, which for submodules is the commit
+fn side_content(side: Option<(Id, FileMode)>, repo: &Repo) -> Result<Vec<u8>> {
+    match side {
+        None => Ok(Vec::new()),
+        Some((id, FileMode::Gitlink)) => Ok(format!("Subproject commit {}\n", id).into_bytes()),
+        Some((id, _)) => Ok(repo.read_object(&id)?.1),
+    }
+}
+
+fn abbrev(side: Option<(Id, FileMode)>) -> String {
+    match side {
+        Some((id, _)) => id.to_string()[..ABBREV].to_owned(),
+        None => "0".repeat(ABBREV),
+    }
+}
+
+impl FileDiff {
+    /// Diffs the two sides of a change
+    pub fn new(change: Change, repo: &Repo) -> Result<FileDiff> {
+        let mut text = Vec::new();
+        let path = &change.path;
+        text.extend_from_slice(b"diff --git a/");
+        text.extend_from_slice(path);
+        text.extend_from_slice(b" b/");
+        text.extend_from_slice(path);
+        text.push(b'\n');
+
+        let mode = |side: Option<(Id, FileMode)>| side.map(|(_, mode)| u32::from(mode));
+        let (old_mode, new_mode) = (mode(change.old), mode(change.new));
+        match (old_mode, new_mode) {
+            (None, Some(new)) => writeln!(text, "new file mode {:o}", new)?,
+            (Some(old), None) => writeln!(text, "deleted file mode {:o}", old)?,
+            (Some(old), Some(new)) if old != new => {
+                writeln!(text, "old mode {:o}\nnew mode {:o}", old, new)?
+            }
+            _ => (),
+        }
+
+        let same_content = change.old.map(|(id, _)| id) == change.new.map(|(id, _)| id);
+        if same_content {
+            // only the mode changed
+            return Ok(FileDiff {
+                change,
+                stat: Stat::Lines(0, 0),
+                text,
+            });
+        }
+        write!(text, "index {}..{}", abbrev(change.old), abbrev(change.new))?;
+        match (old_mode, new_mode) {
+            (Some(old), Some(new)) if old == new => writeln!(text, " {:o}", old)?,
+            _ => writeln!(text)?,
+        }
+
+        let old = side_content(change.old, repo)?;
+        let new = side_content(change.new, repo)?;
+        let name = |side: Option<_>, prefix: &[u8]| match side {
+            Some(_) => [prefix, path.as_slice()].concat(),
+            None => b"/dev/null".to_vec(),
+        };
+        let (old_name, new_name) = (name(change.old, b"a/"), name(change.new, b"b/"));
+
+        if diff::is_binary(&old) || diff::is_binary(&new) {
+            text.extend_from_slice(b"Binary files ");
+            text.extend_from_slice(&old_name);
+            text.extend_from_slice(b" and ");
+            text.extend_from_slice(&new_name);
+            text.extend_from_slice(b" differ\n");
+            return Ok(FileDiff {
+                change,
+                stat: Stat::Binary(old.len(), new.len()),
+                text,
+            });
+        }
+
+        text.extend_from_slice(b"--- ");
+        text.extend_from_slice(&old_name);
+        text.extend_from_slice(b"\n+++ ");
+        text.extend_from_slice(&new_name);
+        text.push(b'\n');
+        let (old_lines, new_lines) = (diff::lines(&old), diff::lines(&new));
+        let edits = diff::myers_diff(&old_lines, &new_lines);
+        for hunk in diff::hunks(&edits, DEFAULT_CONTEXT) {
+            hunk.write_to(&mut text)?;
+        }
+        let (ins, del) = diff::count_changes(&edits);
+        Ok(FileDiff {
+            change,
+            stat: Stat::Lines(ins, del),
+            text,
+        })
+    }
+}
+
+/// Diffs every file that differs between two trees
+pub fn diff_trees(old: Option<&Id>, new: &Id, repo: &Repo) -> Result<Vec<FileDiff>> {
+    tree::changed_files(old, Some(new), repo)?
+        .into_iter()
+        .map(|change| FileDiff::new(change, repo))
+        .collect()
+}
+
+fn plural(n: usize, one: &str, many: &str) -> String {
+    format!("{} {}", n, if n == 1 { one } else { many })
+}
+
+/// Writes a diffstat like git's: a line per file with a bar of `+` and `-`,
+/// then a summary, then any files created, deleted or changing mode
+pub fn write_diffstat(diffs: &[FileDiff], out: &mut dyn Write) -> Result<()> {
+    let name_width = diffs
+        .iter()
+        .map(|d| String::from_utf8_lossy(&d.change.path).chars().count())
+        .max()
+        .unwrap_or(0);
+    let changed = |d: &FileDiff| match d.stat {
+        Stat::Lines(ins, del) => ins + del,
+        Stat::Binary(..) => 0,
+    };
+    let max_change = diffs.iter().map(changed).max().unwrap_or(0);
+    // binary files say "Bin" where the count would go
+    let has_binary = diffs.iter().any(|d| matches!(d.stat, Stat::Binary(..)));
+    let number_width = max_change
+        .to_string()
+        .len()
+        .max(if has_binary { 3 } else { 0 });
+    // " name | count " and the bar
+    let bar_width = STAT_WIDTH
+        .saturating_sub(name_width + number_width + 4)
+        .max(6);
+    // like git, anything that changed gets at least one character
+    let scale = |n: usize| {
+        if max_change <= bar_width || n == 0 {
+            n
+        } else {
+            1 + n * (bar_width - 1) / max_change
+        }
+    };
+
+    let (mut total_ins, mut total_del) = (0, 0);
+    for d in diffs {
+        let name = String::from_utf8_lossy(&d.change.path);
+        match d.stat {
+            Stat::Lines(ins, del) => {
+                total_ins += ins;
+                total_del += del;
+                let (plus, minus) = match scale(ins + del) {
+                    0 => (0, 0),
+                    width => {
+                        let plus = scale(ins).min(width);
+                        (plus, width - plus)
+                    }
+                };
+                let line = format!(
+                    " {:name_width$} | {:>number_width$} {}{}",
+                    name,
+                    ins + del,
+                    "+".repeat(plus),
+                    "-".repeat(minus),
+                    name_width = name_width,
+                    number_width = number_width
+                );
+                // files that only changed mode have no bar
+                writeln!(out, "{}", line.trim_end())?;
+            }
+            Stat::Binary(old, new) => writeln!(
+                out,
+                " {:name_width$} | Bin {} -> {} bytes",
+                name,
+                old,
+                new,
+                name_width = name_width
+            )?,
+        }
+    }
+
+    write!(out, " {} changed", plural(diffs.len(), "file", "files"))?;
+    if total_ins > 0 || total_del == 0 {
+        write!(out, ", {}(+)", plural(total_ins, "insertion", "insertions"))?;
+    }
+    if total_del > 0 || total_ins == 0 {
+        write!(out, ", {}(-)", plural(total_del, "deletion", "deletions"))?;
+    }
+    writeln!(out)?;
+
+    for d in diffs {
+        let name = String::from_utf8_lossy(&d.change.path);
+        let mode = |side: Option<(Id, FileMode)>| side.map(|(_, mode)| u32::from(mode));
+        match (mode(d.change.old), mode(d.change.new)) {
+            (None, Some(new)) => writeln!(out, " create mode {:o} {}", new, name)?,
+            (Some(old), None) => writeln!(out, " delete mode {:o} {}", old, name)?,
+            (Some(old), Some(new)) if old != new => {
+                writeln!(out, " mode change {:o} => {:o} {}", old, new, name)?
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Splits a commit message into its subject, the first paragraph joined into
+/// one line, and the rest
+pub fn split_message(message: &str) -> (String, &str) {
+    let message = message.trim_start_matches('\n');
+    let (subject, body) = match message.find("\n\n") {
+        Some(end) => (&message[..end], &message[end + 2..]),
+        None => (message, ""),
+    };
+    let subject = subject.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+    (subject, body.trim_start_matches('\n'))
+}
+
+/// Encodes a header value as an RFC 2047 encoded word if it isn't ASCII
+pub fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_owned();
+    }
+    let mut encoded = String::from("=?UTF-8?q?");
+    for &b in value.as_bytes() {
+        match b {
+            b' ' => encoded.push('_'),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'.' | b'/' => {
+                encoded.push(char::from(b))
+            }
+            _ => encoded.push_str(&format!("={:02X}", b)),
+        }
+    }
+    encoded.push_str("?=");
+    encoded
+}
+
+/// Formats a name and address for a From header, quoting the name if it has
+/// characters with special meaning in addresses
+fn format_address(who: &NameEntry) -> String {
+    let name = if !who.name.is_ascii() {
+        encode_header(&who.name)
+    } else if who.name.contains(|c| "()<>[]:;@\\,.\"".contains(c)) {
+        format!(
+            "\"{}\"",
+            who.name.replace('\\', "\\\\").replace('"', "\\\"")
+        )
+    } else {
+        who.name.clone()
+    };
+    format!("{} <{}>", name, who.email)
+}
+
+/// Formats a date as email headers do
+pub fn format_date(date: &DateTime<FixedOffset>) -> String {
+    date.format("%a, %-d %b %Y %H:%M:%S %z").to_string()
+}
+
+/// The name of the file for a patch: its number and a version of its subject
+/// that is safe in file names, such as `0001-Fix-the-thing.patch`
+pub fn patch_file_name(number: usize, subject: &str) -> String {
+    let mut name = String::new();
+    for c in subject.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            name.push(c);
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+        if name.len() >= NAME_MAX {
+            break;
+        }
+    }
+    let name = name.trim_end_matches(&['-', '.'][..]);
+    format!("{:04}-{}.patch", number, name)
+}
+
+/// Formats a commit as a patch email. `number` and `total` give its place in
+/// the series for the `[PATCH n/m]` prefix, which is only `[PATCH]` for a
+/// series of one.
+pub fn format_email(id: &Id, number: usize, total: usize, repo: &Repo) -> Result<Vec<u8>> {
+    let commit: Commit = match repo.open(id)? {
+        Object::Commit(commit) => commit,
+        _ => return Err(anyhow!("{} is not a commit", id)),
+    };
+    let parent = match commit.parents.len() {
+        0 => None,
+        1 => {
+            let parent = repo.open(&commit.parents[0])?;
+            Some(
+                parent
+                    .commit()
+                    .ok_or_else(|| anyhow!("parent of {} is not a commit", id))?,
+            )
+        }
+        _ => {
+            return Err(anyhow!(
+                "{} is a merge, which can't be made into a patch",
+                id
+            ))
+        }
+    };
+    let diffs = diff_trees(parent.as_ref().map(|p| &p.tree), &commit.tree, repo)?;
+
+    let (subject, body) = split_message(&commit.message);
+    let prefix = if total > 1 {
+        format!("[PATCH {}/{}]", number, total)
+    } else {
+        "[PATCH]".to_string()
+    };
+
+    let mut out = Vec::new();
+    // the date is fixed so that mail programs can recognize patches
+    writeln!(out, "From {} Mon Sep 17 00:00:00 2001", id)?;
+    writeln!(out, "From: {}", format_address(&commit.author))?;
+    writeln!(out, "Date: {}", format_date(&commit.author.time))?;
+    writeln!(out, "Subject: {} {}", prefix, encode_header(&subject))?;
+    if !commit.message.is_ascii() {
+        writeln!(out, "MIME-Version: 1.0")?;
+        writeln!(out, "Content-Type: text/plain; charset=UTF-8")?;
+        writeln!(out, "Content-Transfer-Encoding: 8bit")?;
+    }
+    writeln!(out)?;
+    if !body.is_empty() {
+        out.extend_from_slice(body.as_bytes());
+        if !body.ends_with('\n') {
+            writeln!(out)?;
+        }
+    }
+    writeln!(out, "---")?;
+    write_diffstat(&diffs, &mut out)?;
+    writeln!(out)?;
+    for d in &diffs {
+        out.extend_from_slice(&d.text);
+    }
+    writeln!(out, "-- \nrgit {}\n", env!("CARGO_PKG_VERSION"))?;
+    Ok(out)
+}
+
+/// Errors that can arise while reading patch emails
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum MailError {
+    /// The email is missing a header needed to make a commit from it
+    #[error("Patch email has no {0} header")]
+    MissingHeader(&'static str),
+
+    /// A header couldn't be understood
+    #[error("Could not understand {header} header {value:?}")]
+    BadHeader {
+        /// Which header
+        header: &'static str,
+        /// What it said
+        value: String,
+    },
+}
+
+/// A patch email, read back into what is needed to commit it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    /// Who wrote the patch and when, from the From and Date headers
+    pub author: NameEntry,
+    /// Commit message: the subject without its `[PATCH]` prefix, then the
+    /// body up to the `---` line
+    pub message: String,
+    /// Everything after the message, which holds the diff
+    pub patch: Vec<u8>,
+}
+
+/// Whether a line is the `From ` line that starts a message in a mailbox,
+/// like `From <sender> Mon Sep 17 00:00:00 2001`. Lines in a message that
+/// just happen to start with "From " don't end in a time and a year.
+fn is_from_line(line: &[u8]) -> bool {
+    let line = line.trim_end();
+    if line.len() < 20 || !line.starts_with(b"From ") {
+        return false;
+    }
+    let colon = match line.rfind_byte(b':') {
+        Some(colon) if colon >= 9 && colon + 3 < line.len() => colon,
+        _ => return false,
+    };
+    let digits = |range: std::ops::Range<usize>| line[range].iter().all(u8::is_ascii_digit);
+    if !digits(colon - 5..colon - 3) || line[colon - 3] != b':' || !digits(colon - 2..colon) {
+        return false;
+    }
+    if !digits(colon + 1..colon + 3) {
+        return false;
+    }
+    let year = line[colon + 3..].trim_start();
+    let end = year
+        .iter()
+        .position(|c| !c.is_ascii_digit())
+        .unwrap_or(year.len());
+    std::str::from_utf8(&year[..end])
+        .ok()
+        .and_then(|year| year.parse::<u32>().ok())
+        .map_or(false, |year| year > 90)
+}
+
+/// Splits a mailbox into its messages, each starting at a `From ` line. Text
+/// without any such line, such as a lone patch email, is one message.
+pub fn split_mbox(data: &[u8]) -> Vec<&[u8]> {
+    let mut starts: Vec<usize> = Vec::new();
+    let mut pos = 0;
+    for line in diff::lines(data) {
+        if is_from_line(line) {
+            starts.push(pos);
+        }
+        pos += line.len();
+    }
+    if starts.first() != Some(&0) {
+        starts.insert(0, 0);
+    }
+    starts.push(data.len());
+    starts
+        .windows(2)
+        .map(|w| &data[w[0]..w[1]])
+        .filter(|msg| !msg.trim().is_empty())
+        .collect()
+}
+
+/// Decodes base64, ignoring line breaks. None if it isn't base64.
+fn decode_base64(text: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &c in text {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' | b'\t' => continue,
+            _ => return None,
+        };
+        acc = acc << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+/// Decodes quoted-printable text. In headers, `_` also stands for a space.
+fn decode_quoted_printable(text: &[u8], in_header: bool) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    let mut i = 0;
+    while i < text.len() {
+        match text[i] {
+            b'_' if in_header => out.push(b' '),
+            b'=' => {
+                let rest = &text[i + 1..];
+                // soft line breaks join lines
+                if rest.starts_with(b"\r\n") {
+                    i += 3;
+                    continue;
+                }
+                if rest.starts_with(b"\n") {
+                    i += 2;
+                    continue;
+                }
+                match rest.get(..2).and_then(num::parse_hex) {
+                    Some(byte) => {
+                        out.extend_from_slice(&byte);
+                        i += 3;
+                        continue;
+                    }
+                    None => out.push(b'='),
+                }
+            }
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+/// Decodes one RFC 2047 encoded word at the start of `word`, returning the
+/// text and how much of `word` it took up
+fn decode_word(word: &str) -> Option<(String, usize)> {
+    let mut parts = word.get(2..)?.splitn(3, '?');
+    let charset = parts.next()?;
+    let encoding = parts.next()?;
+    let rest = parts.next()?;
+    let end = rest.find("?=")?;
+    let text = &rest.as_bytes()[..end];
+    let len = charset.len() + encoding.len() + end + 6;
+    let bytes = match encoding {
+        "q" | "Q" => decode_quoted_printable(text, true),
+        "b" | "B" => decode_base64(text)?,
+        _ => return None,
+    };
+    // the charset may be followed by a language, as in `UTF-8*en`
+    let charset = charset.split('*').next().unwrap_or("").to_ascii_lowercase();
+    let decoded = if charset == "iso-8859-1" || charset == "latin1" {
+        bytes.iter().map(|&b| char::from(b)).collect()
+    } else {
+        String::from_utf8_lossy(&bytes).into_owned()
+    };
+    Some((decoded, len))
+}
+
+/// Decodes the RFC 2047 encoded words in a header, such as
+/// `=?UTF-8?q?Zo=C3=AB?=`. Whitespace between two encoded words is dropped.
+pub fn decode_header(value: &str) -> String {
+    let mut out = String::new();
+    let mut rest = value;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let (before, word) = rest.split_at(start);
+        match decode_word(word) {
+            Some((text, len)) => {
+                if !(after_word && before.trim().is_empty()) {
+                    out.push_str(before);
+                }
+                out.push_str(&text);
+                rest = &word[len..];
+                after_word = true;
+            }
+            None => {
+                out.push_str(before);
+                out.push_str("=?");
+                rest = &word[2..];
+                after_word = false;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Parses an address such as `"Name" <email>`, or a bare email, which is used
+/// as the name too
+fn parse_address(value: &str) -> Option<(String, String)> {
+    let value = value.trim();
+    match (value.rfind('<'), value.rfind('>')) {
+        (Some(open), Some(close)) if open < close => {
+            let email = value[open + 1..close].trim();
+            let mut name = value[..open].trim();
+            if name.len() >= 2 && name.starts_with('"') && name.ends_with('"') {
+                name = &name[1..name.len() - 1];
+            }
+            let name = name.replace("\\\"", "\"").replace("\\\\", "\\");
+            let name = if name.is_empty() {
+                email.to_string()
+            } else {
+                name
+            };
+            Some((name, email.to_string()))
+        }
+        (None, None) if value.contains('@') && !value.contains(' ') => {
+            Some((value.to_string(), value.to_string()))
+        }
+        _ => None,
+    }
+}
+
+/// Takes `Re:` and bracketed prefixes such as `[PATCH 1/2]` off a subject
+fn strip_subject_prefix(subject: &str) -> &str {
+    let mut subject = subject.trim();
+    loop {
+        if subject.len() >= 3 && subject[..3].eq_ignore_ascii_case("re:") {
+            subject = subject[3..].trim_start();
+        } else if subject.starts_with('[') {
+            match subject.find(']') {
+                Some(close) => subject = subject[close + 1..].trim_start(),
+                None => return subject,
+            }
+        } else {
+            return subject;
+        }
+    }
+}
+
+/// Reads headers from the start of `lines`, unfolding continued ones,
+/// returning them with lowercase names and the number of lines they took up
+/// including the blank line after them
+fn parse_headers(lines: &[&[u8]]) -> (Vec<(String, String)>, usize) {
+    let mut headers: Vec<(String, String)> = Vec::new();
+    let mut n = 0;
+    while n < lines.len() {
+        let line = lines[n].trim_end_with(|c| c == '\n' || c == '\r');
+        n += 1;
+        if line.is_empty() {
+            break;
+        }
+        let line = String::from_utf8_lossy(line);
+        if line.starts_with(&[' ', '\t'][..]) {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            headers.push((
+                line[..colon].trim().to_ascii_lowercase(),
+                line[colon + 1..].trim().to_string(),
+            ));
+        } else {
+            // not a header after all
+            n -= 1;
+            break;
+        }
+    }
+    (headers, n)
+}
+
+/// Reads a patch email. `From:`, `Date:` and `Subject:` lines at the very
+/// start of the body take the place of the email's own headers, as they do
+/// for patches sent on behalf of someone else.
+pub fn parse_mail(data: &[u8]) -> Result<Mail, MailError> {
+    let lines = diff::lines(data);
+    let skip = if lines.first().map_or(false, |l| l.starts_with(b"From ")) {
+        1
+    } else {
+        0
+    };
+    let (mut headers, len) = parse_headers(&lines[skip..]);
+    let header = |headers: &[(String, String)], name: &str| {
+        headers
+            .iter()
+            .rev()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.clone())
+    };
+
+    let raw_body = lines[skip + len..].concat();
+    let encoding = header(&headers, "content-transfer-encoding").map(|e| e.to_ascii_lowercase());
+    let body = match encoding.as_deref() {
+        Some("quoted-printable") => decode_quoted_printable(&raw_body, false),
+        Some("base64") => decode_base64(&raw_body).ok_or_else(|| MailError::BadHeader {
+            header: "Content-Transfer-Encoding",
+            value: "base64".to_string(),
+        })?,
+        _ => raw_body,
+    };
+
+    let mut body_lines = diff::lines(&body);
+    let in_body = body_lines.first().map_or(false, |l| {
+        l.starts_with(b"From:") || l.starts_with(b"Date:") || l.starts_with(b"Subject:")
+    });
+    if in_body {
+        let (extra, len) = parse_headers(&body_lines);
+        headers.extend(extra);
+        body_lines.drain(..len);
+    }
+
+    let from = header(&headers, "from").ok_or(MailError::MissingHeader("From"))?;
+    let (name, email) =
+        parse_address(&decode_header(&from)).ok_or_else(|| MailError::BadHeader {
+            header: "From",
+            value: from.clone(),
+        })?;
+    let date = header(&headers, "date").ok_or(MailError::MissingHeader("Date"))?;
+    let time = DateTime::parse_from_rfc2822(&date).map_err(|_| MailError::BadHeader {
+        header: "Date",
+        value: date.clone(),
+    })?;
+    let subject = decode_header(&header(&headers, "subject").unwrap_or_default());
+    let subject = strip_subject_prefix(&subject);
+
+    // the message ends at the `---` line, or where the diff starts
+    let end = body_lines
+        .iter()
+        .position(|l| {
+            let l = l.trim_end();
+            l == b"---" || l.starts_with(b"diff --git ") || l.starts_with(b"Index: ")
+        })
+        .unwrap_or(body_lines.len());
+    let text = String::from_utf8_lossy(&body_lines[..end].concat()).into_owned();
+    let message = util::stripspace(&format!("{}\n\n{}", subject, text), false);
+
+    Ok(Mail {
+        author: NameEntry { name, email, time },
+        message,
+        patch: body_lines[end..].concat(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_header, encode_header, format_email, is_from_line, parse_mail, patch_file_name,
+        split_mbox, split_message,
+    };
+    use crate::objects::{Blob, Commit, File, FileMode, NameEntry, Repo, Tree};
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("Subject\n"), ("Subject".to_string(), ""));
+        assert_eq!(
+            split_message("Long\nsubject\n\nBody\n\nMore\n"),
+            ("Long subject".to_string(), "Body\n\nMore\n")
+        );
+    }
+
+    #[test]
+    fn test_patch_file_name() {
+        assert_eq!(
+            patch_file_name(1, "Fix the thing (again)"),
+            "0001-Fix-the-thing-again.patch"
+        );
+        assert_eq!(patch_file_name(12, "v1.2."), "0012-v1.2.patch");
+        assert_eq!(encode_header("plain"), "plain");
+        assert_eq!(encode_header("Zoë"), "=?UTF-8?q?Zo=C3=AB?=");
+    }
+
+    #[test]
+    fn test_format_email() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("A U Thor <a@example.com> 1586391037 -0700").unwrap();
+        let tree = |content: &[u8]| {
+            let blob = repo.store(&Blob::new(content.to_vec())).unwrap();
+            repo.store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Regular,
+                    name: "hello".into(),
+                    id: blob,
+                }],
+            })
+            .unwrap()
+        };
+        let first = repo
+            .store(&Commit {
+                tree: tree(b"hello\n"),
+                parents: vec![],
+                author: who.clone(),
+                committer: who.clone(),
+                message: "Add hello\n".to_string(),
+            })
+            .unwrap();
+        let second = repo
+            .store(&Commit {
+                tree: tree(b"goodbye\n"),
+                parents: vec![first],
+                author: who.clone(),
+                committer: who,
+                message: "Say goodbye\n\nIt was time.\n".to_string(),
+            })
+            .unwrap();
+
+        let email = String::from_utf8(format_email(&second, 2, 2, &repo).unwrap()).unwrap();
+        let expected = format!(
+            "From {} Mon Sep 17 00:00:00 2001\n\
+             From: A U Thor <a@example.com>\n\
+             Date: Wed, 8 Apr 2020 17:10:37 -0700\n\
+             Subject: [PATCH 2/2] Say goodbye\n\
+             \n\
+             It was time.\n\
+             ---\n \
+             hello | 2 +-\n \
+             1 file changed, 1 insertion(+), 1 deletion(-)\n\
+             \n\
+             diff --git a/hello b/hello\n\
+             index ce01362..dd7e1c6 100644\n\
+             --- a/hello\n\
+             +++ b/hello\n\
+             @@ -1 +1 @@\n\
+             -hello\n\
+             +goodbye\n\
+             -- \n\
+             rgit {}\n\n",
+            second,
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(email, expected);
+
+        let email = String::from_utf8(format_email(&first, 1, 1, &repo).unwrap()).unwrap();
+        assert!(email.contains("Subject: [PATCH] Add hello\n\n---\n"));
+        assert!(email.contains(" create mode 100644 hello\n"));
+        assert!(email.contains("new file mode 100644\nindex 0000000..ce01362\n--- /dev/null\n"));
+
+        // and back again
+        let mail = parse_mail(format_email(&second, 2, 2, &repo).unwrap().as_slice()).unwrap();
+        assert_eq!(
+            mail.author,
+            repo.open(&second).unwrap().commit().unwrap().author
+        );
+        assert_eq!(mail.message, "Say goodbye\n\nIt was time.\n");
+        assert!(mail.patch.starts_with(b"---\n hello | 2 +-\n"));
+    }
+
+    #[test]
+    fn test_parse_mail() {
+        assert_eq!(
+            decode_header("=?UTF-8?q?Zo=C3=AB?= <z@example.com>"),
+            "Zoë <z@example.com>"
+        );
+        assert_eq!(
+            decode_header("=?utf-8?B?Wm/Dqw==?= =?UTF-8?q?_x?="),
+            "Zoë x"
+        );
+        assert_eq!(decode_header("a =? b"), "a =? b");
+
+        let mbox = b"From 1234 Mon Sep 17 00:00:00 2001\n\
+                     From: \"Thor, A U\" <a@example.com>\n\
+                     Date: Wed, 8 Apr 2020 17:10:37 -0700\n\
+                     Subject: Re: [PATCH v2 1/2] Fix the\n \
+                     thing\n\
+                     Content-Transfer-Encoding: quoted-printable\n\
+                     \n\
+                     It was br=\n\
+                     oken =3D bad.\n\
+                     \n\
+                     From now on, it isn't broken.\n\
+                     diff --git a/a b/a\n\
+                     From 5678 Mon Sep 17 00:00:00 2001\n\
+                     From: b@example.com\n\
+                     Date: Wed, 8 Apr 2020 17:10:37 -0700\n\
+                     Subject: [PATCH 2/2] Other\n\
+                     \n\
+                     From: C <c@example.com>\n\
+                     \n\
+                     ---\n";
+        let mails = split_mbox(mbox);
+        assert_eq!(mails.len(), 2);
+
+        let first = parse_mail(mails[0]).unwrap();
+        assert_eq!(first.author.name, "Thor, A U");
+        assert_eq!(first.author.email, "a@example.com");
+        assert_eq!(first.author.time.to_rfc3339(), "2020-04-08T17:10:37-07:00");
+        assert_eq!(
+            first.message,
+            "Fix the thing\n\nIt was broken = bad.\n\nFrom now on, it isn't broken.\n"
+        );
+        assert_eq!(first.patch, b"diff --git a/a b/a\n");
+
+        let second = parse_mail(mails[1]).unwrap();
+        assert_eq!(second.author.name, "C");
+        assert_eq!(second.message, "Other\n");
+        assert_eq!(second.patch, b"---\n");
+
+        assert!(parse_mail(b"Subject: no author\n\n").is_err());
+        assert_eq!(split_mbox(b"Subject: lone\n\n").len(), 1);
+        assert!(is_from_line(b"From abc Sat, 8 Apr 2020 17:10:37 2020\n"));
+        assert!(!is_from_line(b"From here on, it's fixed\n"));
+        assert!(!is_from_line(b"From the log: 1:2:3 2020\n"));
+    }
+}
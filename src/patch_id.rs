@@ -0,0 +1,385 @@
+//! Patch IDs, which identify a change by its diff rather than by the commit
+//! it is in. The diff is hashed without its whitespace, line numbers or
+//! `index` lines, so the same change has the same patch ID after being
+//! rebased, cherry-picked or sent as an email and applied again.
+//!
+//! This is what `git patch-id` computes, and what `cherry` uses to tell which
+//! commits on a branch are already upstream.
+use anyhow::{anyhow, Result};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+
+use crate::objects::{Id, Object, Repo};
+use crate::patch;
+use crate::revwalk::{CommitFilter, RevWalk};
+
+/// The sum of the hashes of the files in a patch, for stable patch IDs
+#[derive(Default)]
+struct Sum([u8; 20]);
+
+impl Sum {
+    /// Adds the hash of what `hasher` has seen, as a 20-byte little-endian
+    /// number, and starts it over
+    fn flush(&mut self, hasher: &mut Sha1) {
+        let hash = hasher.result_reset();
+        let mut carry = 0u16;
+        for (byte, add) in self.0.iter_mut().zip(hash.iter()) {
+            carry += u16::from(*byte) + u16::from(*add);
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+    }
+}
+
+/// Drops the whitespace from a line
+fn remove_space(line: &[u8]) -> Vec<u8> {
+    line.iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .copied()
+        .collect()
+}
+
+/// Reads how many old and new lines there are from a hunk header such as
+/// `@@ -1,5 +1,6 @@`. A count that is left out is 1.
+fn scan_hunk_header(line: &[u8]) -> Option<(i64, i64)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut ranges = line["@@ -".len()..].split(' ');
+    let count = |range: &str| -> Option<i64> {
+        match range.find(',') {
+            Some(comma) => range[comma + 1..].parse().ok(),
+            None => range.parse::<i64>().ok().map(|_| 1),
+        }
+    };
+    let before = count(ranges.next()?)?;
+    let after = count(ranges.next()?.trim_start_matches('+'))?;
+    Some((before, after))
+}
+
+/// Where a line that starts a new patch says which commit the patch is from,
+/// as `commit <id>` from `log -p` or `From <id>` from `format-patch`
+fn commit_line(line: &[u8]) -> Option<Id> {
+    let rest = [&b"diff-tree "[..], b"commit ", b"From "]
+        .iter()
+        .find(|prefix| line.starts_with(prefix))
+        .map_or(line, |prefix| &line[prefix.len()..]);
+    let hex = rest.get(..40)?;
+    Id::from(std::str::from_utf8(hex).ok()?)
+}
+
+/// Reads diffs one at a time from a stream of them, as `log -p` or
+/// `format-patch` print them
+struct PatchReader<'a> {
+    lines: std::iter::Peekable<std::vec::IntoIter<&'a [u8]>>,
+    stable: bool,
+}
+
+impl<'a> PatchReader<'a> {
+    /// Hashes the next patch, stopping at the line saying which commit the
+    /// patch after it is from. Returns the patch ID, how many bytes of the
+    /// patch were hashed and the commit the next patch is from.
+    fn next_patch(&mut self) -> (Id, usize, Option<Id>) {
+        let mut hasher = Sha1::new();
+        let mut sum = Sum::default();
+        let mut hashed = 0;
+        let mut next = None;
+        // lines left in the current hunk, or -1 in a file's header
+        let (mut before, mut after) = (-1i64, -1i64);
+        let mut binary = false;
+        let (mut old_id, mut new_id) = (Vec::new(), Vec::new());
+
+        for line in self.lines.by_ref() {
+            if line.starts_with(b"\\ ") && line.len() > 12 {
+                // "\ No newline at end of file"
+                continue;
+            }
+            if let Some(id) = commit_line(line) {
+                next = Some(id);
+                break;
+            }
+            // the commit message
+            if hashed == 0 && !line.starts_with(b"diff ") {
+                continue;
+            }
+
+            if before == -1 {
+                if line.starts_with(b"GIT binary patch") || line.starts_with(b"Binary files") {
+                    binary = true;
+                    before = 0;
+                    hasher.input(&old_id);
+                    hasher.input(&new_id);
+                    if self.stable {
+                        sum.flush(&mut hasher);
+                    }
+                    continue;
+                } else if line.starts_with(b"index ") {
+                    let ids = &line[b"index ".len()..];
+                    if let Some(dots) = ids.windows(2).position(|w| w == b"..") {
+                        let rest = &ids[dots + 2..];
+                        let end = rest
+                            .iter()
+                            .position(|b| b.is_ascii_whitespace())
+                            .unwrap_or(rest.len());
+                        old_id = ids[..dots].to_vec();
+                        new_id = rest[..end].to_vec();
+                    }
+                    continue;
+                } else if line.starts_with(b"--- ") {
+                    before = 1;
+                    after = 1;
+                } else if !line.first().map_or(false, u8::is_ascii_alphabetic) {
+                    break;
+                }
+            }
+
+            if binary {
+                if line.starts_with(b"diff ") {
+                    binary = false;
+                    before = -1;
+                }
+                continue;
+            }
+
+            if before == 0 && after == 0 {
+                if line.starts_with(b"@@ -") {
+                    // the line numbers don't count
+                    if let Some((b, a)) = scan_hunk_header(line) {
+                        before = b;
+                        after = a;
+                    }
+                    continue;
+                }
+                // anything but another file's diff ends the patch
+                if !line.starts_with(b"diff ") {
+                    break;
+                }
+                if self.stable {
+                    sum.flush(&mut hasher);
+                }
+                before = -1;
+                after = -1;
+            }
+
+            match line.first() {
+                Some(b'-') => before -= 1,
+                Some(b'+') => after -= 1,
+                Some(b' ') => {
+                    before -= 1;
+                    after -= 1;
+                }
+                _ => (),
+            }
+            let line = remove_space(line);
+            hashed += line.len();
+            hasher.input(&line);
+        }
+
+        sum.flush(&mut hasher);
+        (Id::from_bytes(sum.0), hashed, next)
+    }
+}
+
+/// Computes the patch IDs of the diffs in `input`, as `git patch-id` does,
+/// along with the commit each is from if that is given before the diff.
+/// Stable patch IDs don't depend on the order the files are diffed in.
+pub fn patch_ids(input: &[u8], stable: bool) -> Vec<(Id, Option<Id>)> {
+    let lines: Vec<&[u8]> = input.split(|&b| b == b'\n').collect();
+    let mut reader = PatchReader {
+        lines: lines.into_iter().peekable(),
+        stable,
+    };
+    let mut ids = Vec::new();
+    let mut commit = None;
+    while reader.lines.peek().is_some() {
+        let (id, hashed, next) = reader.next_patch();
+        if hashed > 0 {
+            ids.push((id, commit));
+        }
+        commit = next;
+    }
+    ids
+}
+
+/// The patch ID of one diff, or None if there is no diff in it
+pub fn patch_id(diff: &[u8]) -> Option<Id> {
+    patch_ids(diff, false).first().map(|(id, _)| *id)
+}
+
+/// The patch ID of the change a commit makes to its parent, or None for
+/// merges, which aren't one change, and commits that change nothing
+pub fn commit_patch_id(id: &Id, repo: &Repo) -> Result<Option<Id>> {
+    let commit = match repo.open(id)? {
+        Object::Commit(commit) => commit,
+        _ => return Err(anyhow!("{} is not a commit", id)),
+    };
+    let parent_tree = match commit.parents.as_slice() {
+        [] => None,
+        [parent] => match repo.open(parent)? {
+            Object::Commit(parent) => Some(parent.tree),
+            _ => return Err(anyhow!("parent of {} is not a commit", id)),
+        },
+        _ => return Ok(None),
+    };
+    let diff: Vec<u8> = patch::diff_trees(parent_tree.as_ref(), &commit.tree, repo)?
+        .into_iter()
+        .flat_map(|d| d.text)
+        .collect();
+    Ok(patch_id(&diff))
+}
+
+/// Finds the commits in `head` that aren't in `upstream`, oldest first,
+/// leaving out those in `limit` too if it's given. Each comes with whether an
+/// equivalent change, one with the same patch ID, is in `upstream` already.
+pub fn cherry(
+    upstream: &Id,
+    head: &Id,
+    limit: Option<&Id>,
+    repo: &Repo,
+) -> Result<Vec<(Id, bool)>> {
+    let mut upstream_ids = HashSet::new();
+    for id in RevWalk::new(repo, &[*upstream])?.hide(&[*head])? {
+        if let Some(patch_id) = commit_patch_id(&id?, repo)? {
+            upstream_ids.insert(patch_id);
+        }
+    }
+
+    let mut hidden = vec![*upstream];
+    hidden.extend(limit);
+    let filter = CommitFilter {
+        max_parents: Some(1),
+        ..Default::default()
+    };
+    let mut commits = Vec::new();
+    for id in RevWalk::new(repo, &[*head])?
+        .hide(&hidden)?
+        .with_filter(filter)
+    {
+        let id = id?;
+        let applied = match commit_patch_id(&id, repo)? {
+            Some(patch_id) => upstream_ids.contains(&patch_id),
+            None => false,
+        };
+        commits.push((id, applied));
+    }
+    commits.reverse();
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cherry, commit_patch_id, patch_id, patch_ids};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+
+    const DIFF: &[u8] = b"\
+diff --git a/hello b/hello
+index ce01362..94954ab 100644
+--- a/hello
++++ b/hello
+@@ -1 +1,2 @@
+ hello
++world
+diff --git a/other b/other
+new file mode 100644
+index 0000000..257cc56
+--- /dev/null
++++ b/other
+@@ -0,0 +1 @@
++foo
+";
+
+    #[test]
+    fn test_patch_id() {
+        // as given by git patch-id
+        let expected = Id::from("a77aebbcfefc75eeb18ff7889fcd3a147571f3e4").unwrap();
+        assert_eq!(patch_id(DIFF), Some(expected));
+
+        // whitespace and line numbers don't matter
+        let moved = String::from_utf8(DIFF.to_vec())
+            .unwrap()
+            .replace("@@ -1 +1,2 @@", "@@ -10 +10,2 @@")
+            .replace("+world", "+ world");
+        assert_eq!(patch_id(moved.as_bytes()), Some(expected));
+        assert_eq!(patch_id(b"just a message\n"), None);
+
+        let commit = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let log = format!(
+            "commit {}\nAuthor: a\n\n    message\n\n{}",
+            commit,
+            String::from_utf8_lossy(DIFF)
+        );
+        let ids = patch_ids(log.as_bytes(), false);
+        assert_eq!(ids, vec![(expected, Id::from(commit))]);
+
+        // stable patch IDs add up the hashes of each file, so the order of
+        // the files doesn't matter
+        let split = DIFF.windows(5).rposition(|w| w == b"diff ").unwrap();
+        let swapped = [&DIFF[split..], &DIFF[..split]].concat();
+        let stable = Id::from("2b5e303292a9bc20c8b00ca52b9eb9175a2749e1").unwrap();
+        assert_eq!(patch_ids(DIFF, true), vec![(stable, None)]);
+        assert_eq!(patch_ids(&swapped, true), vec![(stable, None)]);
+    }
+
+    #[test]
+    fn test_cherry() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let later = NameEntry::from("b <b@example.com> 1586391099 -0700").unwrap();
+        let commit =
+            |files: &[(&str, &[u8])], parent: Option<Id>, who: &NameEntry, message: &str| {
+                let files = files
+                    .iter()
+                    .map(|(name, content)| File {
+                        mode: FileMode::Regular,
+                        name: name.to_string().into(),
+                        id: repo.store(&Blob::new(content.to_vec())).unwrap(),
+                    })
+                    .collect();
+                let tree = repo.store(&Tree { files }).unwrap();
+                repo.store(&Commit {
+                    tree,
+                    parents: parent.into_iter().collect(),
+                    author: who.clone(),
+                    committer: who.clone(),
+                    message: message.to_string(),
+                })
+                .unwrap()
+            };
+
+        let base = commit(&[("a", b"a\n")], None, &who, "base\n");
+        // two local changes, one of which was picked upstream on top of
+        // another change
+        let fix = commit(&[("a", b"a\n"), ("b", b"fix\n")], Some(base), &who, "fix\n");
+        let feature = commit(
+            &[("a", b"a\nfeature\n"), ("b", b"fix\n")],
+            Some(fix),
+            &who,
+            "feature\n",
+        );
+        let other = commit(
+            &[("a", b"a\n"), ("c", b"c\n")],
+            Some(base),
+            &later,
+            "other\n",
+        );
+        let picked = commit(
+            &[("a", b"a\n"), ("b", b"fix\n"), ("c", b"c\n")],
+            Some(other),
+            &later,
+            "fix\n",
+        );
+        assert_ne!(picked, fix);
+        assert_eq!(
+            commit_patch_id(&picked, &repo).unwrap(),
+            commit_patch_id(&fix, &repo).unwrap()
+        );
+
+        assert_eq!(
+            cherry(&picked, &feature, None, &repo).unwrap(),
+            vec![(fix, true), (feature, false)]
+        );
+        assert_eq!(
+            cherry(&picked, &feature, Some(&fix), &repo).unwrap(),
+            vec![(feature, false)]
+        );
+        assert_eq!(cherry(&feature, &feature, None, &repo).unwrap(), vec![]);
+    }
+}
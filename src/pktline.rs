@@ -0,0 +1,117 @@
+//! The pkt-line framing used by git's network protocols. Each packet is its
+//! length as four hex digits, counting the digits themselves, followed by its
+//! data. A length of `0000` is a flush packet, which marks the end of a
+//! section rather than carrying data.
+use std::io::{self, Read, Write};
+use thiserror::Error;
+
+use crate::num;
+
+/// Largest packet git will send or accept, including the length
+pub const MAX_LEN: usize = 65520;
+
+/// Errors that can arise while reading packets
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PktLineError {
+    /// The length prefix isn't hex, or is too small to include itself
+    #[error("Bad pkt-line length {0:?}")]
+    BadLength(String),
+
+    /// The stream ended partway through a packet
+    #[error("Connection closed in the middle of a pkt-line")]
+    Truncated,
+}
+
+/// A packet read off the wire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    /// A packet carrying data
+    Data(Vec<u8>),
+    /// A flush packet, `0000`
+    Flush,
+}
+
+impl Packet {
+    /// The data in the packet without its trailing newline, which text packets
+    /// have but are not required to. Flush packets have no data.
+    pub fn line(&self) -> Option<&[u8]> {
+        match self {
+            Packet::Data(data) if data.ends_with(b"\n") => Some(&data[..data.len() - 1]),
+            Packet::Data(data) => Some(data),
+            Packet::Flush => None,
+        }
+    }
+}
+
+/// Reads one packet. Returns None if the stream ended between packets.
+pub fn read(input: &mut impl Read) -> io::Result<Option<Packet>> {
+    let mut len = [0u8; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let bad_len = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            PktLineError::BadLength(String::from_utf8_lossy(&len).into_owned()),
+        )
+    };
+    let digits = num::parse_hex(&len).ok_or_else(bad_len)?;
+    let len = usize::from(digits[0]) << 8 | usize::from(digits[1]);
+    match len {
+        0 => return Ok(Some(Packet::Flush)),
+        1..=4 => return Err(bad_len()),
+        _ => (),
+    }
+
+    let mut data = vec![0u8; len - 4];
+    input.read_exact(&mut data).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            io::Error::new(io::ErrorKind::UnexpectedEof, PktLineError::Truncated)
+        } else {
+            e
+        }
+    })?;
+    Ok(Some(Packet::Data(data)))
+}
+
+/// Writes a data packet
+pub fn write(out: &mut impl Write, data: &[u8]) -> io::Result<()> {
+    assert!(data.len() + 4 <= MAX_LEN, "pkt-line too long");
+    write!(out, "{:04x}", data.len() + 4)?;
+    out.write_all(data)
+}
+
+/// Writes a flush packet
+pub fn flush(out: &mut impl Write) -> io::Result<()> {
+    out.write_all(b"0000")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{flush, read, write, Packet};
+
+    #[test]
+    fn test_pktline_roundtrip() {
+        let mut buf = Vec::new();
+        write(&mut buf, b"want abc\n").unwrap();
+        flush(&mut buf).unwrap();
+        write(&mut buf, b"done").unwrap();
+        assert_eq!(&buf[..], &b"000dwant abc\n00000008done"[..]);
+
+        let mut input = &buf[..];
+        let first = read(&mut input).unwrap().unwrap();
+        assert_eq!(first.line(), Some(&b"want abc"[..]));
+        assert_eq!(read(&mut input).unwrap(), Some(Packet::Flush));
+        assert_eq!(
+            read(&mut input).unwrap().unwrap().line(),
+            Some(&b"done"[..])
+        );
+        assert_eq!(read(&mut input).unwrap(), None);
+
+        assert!(read(&mut &b"0003"[..]).is_err());
+        assert!(read(&mut &b"zzzz"[..]).is_err());
+        assert!(read(&mut &b"0010short"[..]).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! Formatting commits for people to read, as `log --pretty` and `show` do.
+//! There are the built-in formats git has, from `oneline` to `full`, and
+//! templates with `%` placeholders such as `format:%h %s`.
+//!
+//! A `format:` template goes between commits, so the last one has no newline
+//! after it, where a `tformat:` template ends each of them. A template given
+//! without either, such as `%h %s`, is taken as `tformat:`.
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::objects::{Commit, Id, NameEntry, Repo};
+use crate::patch;
+use crate::rev;
+
+/// Shortest abbreviated object ID shown
+const ABBREV: usize = 7;
+
+/// How dates are shown, like git's default
+const DATE_FORMAT: &str = "%a %b %-d %H:%M:%S %Y %z";
+
+/// Errors from reading a format
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PrettyError {
+    /// The format isn't a built-in one or a template
+    #[error("invalid --pretty format: {0}")]
+    UnknownFormat(String),
+}
+
+/// How to show a commit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// The ID and subject on one line
+    Oneline,
+    /// The ID, author and subject
+    Short,
+    /// The ID, author, date and message. This is the default.
+    Medium,
+    /// The ID, author, committer and message
+    Full,
+    /// A template with placeholders
+    Custom {
+        /// The template
+        template: String,
+        /// Whether every commit ends in a newline, as for `tformat:`, rather
+        /// than there being one between commits
+        terminate: bool,
+    },
+}
+
+impl FromStr for Format {
+    type Err = PrettyError;
+
+    fn from_str(s: &str) -> Result<Format, PrettyError> {
+        if let Some(template) = s.strip_prefix("format:") {
+            return Ok(Format::Custom {
+                template: template.to_owned(),
+                terminate: false,
+            });
+        }
+        if let Some(template) = s.strip_prefix("tformat:") {
+            return Ok(Format::Custom {
+                template: template.to_owned(),
+                terminate: true,
+            });
+        }
+        match s {
+            "oneline" => Ok(Format::Oneline),
+            "short" => Ok(Format::Short),
+            "medium" => Ok(Format::Medium),
+            "full" => Ok(Format::Full),
+            _ if s.contains('%') => Ok(Format::Custom {
+                template: s.to_owned(),
+                terminate: true,
+            }),
+            _ => Err(PrettyError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// The refs pointing at each commit, as shown by `%d`, in the order git shows
+/// them: HEAD first, then the rest by name from last to first
+fn decorations(repo: &Repo) -> Result<HashMap<Id, Vec<String>>> {
+    let mut decorations: HashMap<Id, Vec<String>> = HashMap::new();
+    let head = match rev::read_head(repo) {
+        Ok(rev::Head::Commit(id)) => Some(id),
+        _ => None,
+    };
+    let branch = rev::head_branch(repo)?;
+    if let Some(head) = head {
+        let name = match &branch {
+            Some(branch) => format!("HEAD -> {}", short_refname(branch)),
+            None => "HEAD".to_owned(),
+        };
+        decorations.entry(head).or_default().push(name);
+    }
+
+    for (name, id) in rev::list_refs(repo)?.into_iter().rev() {
+        if Some(&name) == branch.as_ref() {
+            continue;
+        }
+        // annotated tags decorate what they tag
+        let id = rev::peel_tags(id, repo)?;
+        let name = match name.strip_prefix("refs/tags/") {
+            Some(tag) => format!("tag: {}", tag),
+            None => short_refname(&name).to_owned(),
+        };
+        decorations.entry(id).or_default().push(name);
+    }
+    Ok(decorations)
+}
+
+/// Leaves `refs/heads/` or `refs/remotes/` off a refname
+fn short_refname(name: &str) -> &str {
+    name.strip_prefix("refs/heads/")
+        .or_else(|| name.strip_prefix("refs/remotes/"))
+        .unwrap_or(name)
+}
+
+/// Writes commits out in a format
+pub struct Formatter<'a> {
+    repo: &'a Repo,
+    format: Format,
+    /// What `%d` shows, only looked up if the format has it
+    decorations: HashMap<Id, Vec<String>>,
+    /// How many commits have been written
+    written: usize,
+}
+
+impl<'a> Formatter<'a> {
+    /// Makes a formatter for commits in `repo`
+    pub fn new(repo: &'a Repo, format: Format) -> Result<Formatter<'a>> {
+        let decorations = match &format {
+            Format::Custom { template, .. } if template.contains("%d") => decorations(repo)?,
+            _ => HashMap::new(),
+        };
+        Ok(Formatter {
+            repo,
+            format,
+            decorations,
+            written: 0,
+        })
+    }
+
+    /// Writes a commit, with whatever goes between it and the one before
+    pub fn write(&mut self, id: &Id, commit: &Commit, out: &mut dyn Write) -> Result<()> {
+        let first = self.written == 0;
+        self.written += 1;
+        match &self.format {
+            Format::Oneline => {
+                let (subject, _) = patch::split_message(&commit.message);
+                writeln!(out, "{} {}", id, subject)?;
+            }
+            Format::Custom {
+                template,
+                terminate,
+            } => {
+                if !first && !terminate {
+                    writeln!(out)?;
+                }
+                out.write_all(self.expand(template, id, commit)?.as_bytes())?;
+                if *terminate {
+                    writeln!(out)?;
+                }
+            }
+            format => {
+                if !first {
+                    writeln!(out)?;
+                }
+                self.write_header(format, id, commit, out)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes one of the multi-line built-in formats
+    fn write_header(
+        &self,
+        format: &Format,
+        id: &Id,
+        commit: &Commit,
+        out: &mut dyn Write,
+    ) -> Result<()> {
+        let ident = |who: &NameEntry| format!("{} <{}>", who.name, who.email);
+        writeln!(out, "commit {}", id)?;
+        if commit.parents.len() > 1 {
+            let parents: Vec<_> = commit
+                .parents
+                .iter()
+                .map(|p| p.to_string()[..ABBREV].to_owned())
+                .collect();
+            writeln!(out, "Merge: {}", parents.join(" "))?;
+        }
+        writeln!(out, "Author: {}", ident(&commit.author))?;
+        match format {
+            Format::Medium => writeln!(out, "Date:   {}", commit.author.time.format(DATE_FORMAT))?,
+            Format::Full => writeln!(out, "Commit: {}", ident(&commit.committer))?,
+            _ => (),
+        }
+        writeln!(out)?;
+        if *format == Format::Short {
+            let (subject, _) = patch::split_message(&commit.message);
+            writeln!(out, "    {}", subject)?;
+        } else {
+            for line in commit.message.lines() {
+                writeln!(out, "    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Abbreviates an ID as far as it stays unique
+    fn abbrev(&self, id: &Id) -> Result<String> {
+        let len = rev::abbrev_len(id, ABBREV, self.repo)?;
+        Ok(id.to_string()[..len].to_owned())
+    }
+
+    /// Fills in the placeholders of a template. Those that aren't known are
+    /// left as they are.
+    pub fn expand(&self, template: &str, id: &Id, commit: &Commit) -> Result<String> {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(percent) = rest.find('%') {
+            out.push_str(&rest[..percent]);
+            rest = &rest[percent + 1..];
+            let mut chars = rest.chars();
+            let (expanded, len) = match (chars.next(), chars.next()) {
+                (Some('H'), _) => (id.to_string(), 1),
+                (Some('h'), _) => (self.abbrev(id)?, 1),
+                (Some('T'), _) => (commit.tree.to_string(), 1),
+                (Some('t'), _) => (self.abbrev(&commit.tree)?, 1),
+                (Some('P'), _) => {
+                    let parents: Vec<_> = commit.parents.iter().map(Id::to_string).collect();
+                    (parents.join(" "), 1)
+                }
+                (Some('p'), _) => {
+                    let parents = commit
+                        .parents
+                        .iter()
+                        .map(|p| self.abbrev(p))
+                        .collect::<Result<Vec<_>>>()?;
+                    (parents.join(" "), 1)
+                }
+                (Some('a'), Some(field)) => match person(&commit.author, field) {
+                    Some(expanded) => (expanded, 2),
+                    None => ("%a".to_owned(), 1),
+                },
+                (Some('c'), Some(field)) => match person(&commit.committer, field) {
+                    Some(expanded) => (expanded, 2),
+                    None => ("%c".to_owned(), 1),
+                },
+                (Some('s'), _) => (patch::split_message(&commit.message).0, 1),
+                (Some('b'), _) => {
+                    let (_, body) = patch::split_message(&commit.message);
+                    let mut body = body.to_owned();
+                    if !body.is_empty() && !body.ends_with('\n') {
+                        body.push('\n');
+                    }
+                    (body, 1)
+                }
+                (Some('d'), _) => match self.decorations.get(id) {
+                    Some(names) => (format!(" ({})", names.join(", ")), 1),
+                    None => (String::new(), 1),
+                },
+                (Some('n'), _) => ("\n".to_owned(), 1),
+                (Some('%'), _) => ("%".to_owned(), 1),
+                _ => ("%".to_owned(), 0),
+            };
+            out.push_str(&expanded);
+            rest = &rest[len..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// Expands the second letter of an author or committer placeholder
+fn person(who: &NameEntry, field: char) -> Option<String> {
+    match field {
+        'n' => Some(who.name.clone()),
+        'e' => Some(who.email.clone()),
+        'd' => Some(who.time.format(DATE_FORMAT).to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, Formatter, PrettyError};
+    use crate::objects::{Commit, Id, NameEntry, Repo, Tag, Tree};
+    use crate::rev;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("short".parse(), Ok(Format::Short));
+        assert_eq!(
+            "format:%h".parse(),
+            Ok(Format::Custom {
+                template: "%h".to_owned(),
+                terminate: false,
+            })
+        );
+        assert_eq!(
+            "%h %s".parse(),
+            Ok(Format::Custom {
+                template: "%h %s".to_owned(),
+                terminate: true,
+            })
+        );
+        assert_eq!(
+            "fancy".parse::<Format>(),
+            Err(PrettyError::UnknownFormat("fancy".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_formats() {
+        let repo = Repo::in_memory();
+        let author = NameEntry::from("A U Thor <author@example.com> 1586391037 -0700").unwrap();
+        let committer = NameEntry::from("C O Mitter <c@example.com> 1586391100 -0700").unwrap();
+        let tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let commit = |parents, message: &str| Commit {
+            tree,
+            parents,
+            author: author.clone(),
+            committer: committer.clone(),
+            message: message.to_owned(),
+        };
+        let root = commit(vec![], "root\n");
+        let root_id = repo.store(&root).unwrap();
+        let head = commit(
+            vec![root_id],
+            "Add a thing\nin two lines\n\nBody.\n\nMore.\n",
+        );
+        let head_id = repo.store(&head).unwrap();
+        rev::update_ref(Path::new("refs/heads/master"), &head_id, "", &repo).unwrap();
+        rev::update_ref(Path::new("refs/heads/old"), &root_id, "", &repo).unwrap();
+        let tag = repo
+            .store(&Tag {
+                object: root_id,
+                kind: crate::objects::ObjectType::Commit,
+                name: "v1".to_owned(),
+                tagger: Some(author.clone()),
+                message: "v1\n".to_owned(),
+            })
+            .unwrap();
+        rev::update_ref(Path::new("refs/tags/v1"), &tag, "", &repo).unwrap();
+
+        let show = |format: &str, commits: &[(&Id, &Commit)]| {
+            let mut formatter = Formatter::new(&repo, format.parse().unwrap()).unwrap();
+            let mut out = Vec::new();
+            for (id, commit) in commits {
+                formatter.write(id, commit, &mut out).unwrap();
+            }
+            String::from_utf8(out).unwrap()
+        };
+        let both = [(&head_id, &head), (&root_id, &root)];
+
+        assert_eq!(
+            show("medium", &both[..1]),
+            format!(
+                "commit {}\nAuthor: A U Thor <author@example.com>\n\
+                 Date:   Wed Apr 8 17:10:37 2020 -0700\n\n    Add a thing\n    in two lines\n    \n    Body.\n    \n    More.\n",
+                head_id
+            )
+        );
+        assert_eq!(
+            show("short", &both),
+            format!(
+                "commit {}\nAuthor: A U Thor <author@example.com>\n\n    Add a thing in two lines\n\n\
+                 commit {}\nAuthor: A U Thor <author@example.com>\n\n    root\n",
+                head_id, root_id
+            )
+        );
+        assert!(show("full", &both[..1]).contains("Commit: C O Mitter <c@example.com>\n"));
+        assert_eq!(
+            show("oneline", &both),
+            format!("{} Add a thing in two lines\n{} root\n", head_id, root_id)
+        );
+
+        let short = |id: &Id| id.to_string()[..7].to_owned();
+        assert_eq!(
+            show("format:%h %an <%ae>%d", &both),
+            format!(
+                "{} A U Thor <author@example.com> (HEAD -> master)\n\
+                 {} A U Thor <author@example.com> (tag: v1, old)",
+                short(&head_id),
+                short(&root_id)
+            )
+        );
+        assert_eq!(
+            show("%s|%b|%p|%cn|%x%%", &both[..1]),
+            format!(
+                "Add a thing in two lines|Body.\n\nMore.\n|{}|C O Mitter|%x%\n",
+                short(&root_id)
+            )
+        );
+    }
+}
@@ -0,0 +1,698 @@
+//! The client side of push: version 0 of git's pack protocol, as served by
+//! [`receive_pack`](crate::receive_pack) and `git receive-pack`. The remote
+//! advertises its refs, we say which of them to change, send a pack of the
+//! objects it is missing, and it reports on each ref with report-status.
+//!
+//! The pack is thin, with deltas against objects at the remote's old tips
+//! where those are worth it. A ref is only moved to somewhere that contains
+//! where it was unless the refspec or `--force` says otherwise; the remote
+//! may refuse updates of its own accord, too. A refspec with nothing before
+//! the colon deletes the remote ref.
+use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::fetch::{self, Refspec, UpdateKind};
+use crate::merge;
+use crate::objects::{Id, ObjectType, Repo};
+use crate::packs;
+use crate::pktline::{self, Packet};
+use crate::remote::Callbacks;
+use crate::rev;
+use crate::revwalk;
+use crate::trace;
+
+/// Errors that can arise while pushing
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The remote sent something other than what the protocol allows here
+    #[error("Protocol error: expected {expected}, got {got:?}")]
+    Protocol {
+        /// What was expected
+        expected: &'static str,
+        /// The offending line
+        got: String,
+    },
+
+    /// The remote reported an error
+    #[error("remote error: {0}")]
+    Remote(String),
+
+    /// The remote couldn't store the pack we sent
+    #[error("remote unpack failed: {0}")]
+    Unpack(String),
+
+    /// The remote hung up partway through
+    #[error("The remote end hung up unexpectedly")]
+    Disconnected,
+
+    /// There is no URL configured for the remote
+    #[error("No URL configured for remote {0}")]
+    NoUrl(String),
+
+    /// A refspec can't be understood
+    #[error("Bad refspec {0:?}")]
+    BadRefspec(String),
+
+    /// The source of a refspec names nothing we have
+    #[error("src refspec {0} does not match any")]
+    NoMatch(String),
+
+    /// Nothing was given to push and HEAD isn't on a branch
+    #[error("You are not currently on a branch")]
+    Detached,
+
+    /// A refspec deletes a ref the remote doesn't have
+    #[error("unable to delete '{0}': remote ref does not exist")]
+    NoRemoteRef(String),
+}
+
+/// A ref updated, or not, by a push
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushUpdate {
+    /// What was pushed, as given in the refspec
+    pub local: String,
+    /// The ref it was pushed to on the remote
+    pub remote: String,
+    /// Where the remote ref was before
+    pub old: Option<Id>,
+    /// Where it was pushed to, or None if it was deleted
+    pub new: Option<Id>,
+    /// What was asked of the remote, which is nothing if the update was
+    /// rejected before it was sent
+    pub kind: UpdateKind,
+    /// Why the remote refused the update, if it did
+    pub refused: Option<String>,
+}
+
+impl PushUpdate {
+    /// Whether the remote ref is now where it was pushed to
+    pub fn succeeded(&self) -> bool {
+        self.kind != UpdateKind::Rejected && self.refused.is_none()
+    }
+}
+
+/// Parses a push refspec of the form `[+]<src>[:<dst>]`, where the source is
+/// anything [`rev::parse`] takes and the destination is the same as the
+/// source if it isn't given. An empty source, as in `:<dst>`, deletes the
+/// destination.
+pub fn parse_refspec(spec: &str) -> Option<Refspec> {
+    let (force, rest) = match spec.strip_prefix('+') {
+        Some(rest) => (true, rest),
+        None => (false, spec),
+    };
+    let (src, dst) = match rest.find(':') {
+        Some(colon) => (&rest[..colon], &rest[colon + 1..]),
+        None => (rest, rest),
+    };
+    let stars = |s: &str| s.matches('*').count();
+    let stars_match = if src.is_empty() {
+        stars(dst) == 0
+    } else {
+        stars(src) <= 1 && stars(src) == stars(dst)
+    };
+    if dst.is_empty() || !stars_match {
+        return None;
+    }
+    Some(Refspec {
+        force,
+        src: src.to_owned(),
+        dst: dst.to_owned(),
+    })
+}
+
+/// The full name of our ref `name`, if it is a branch or tag, or the branch
+/// HEAD is on
+fn expand_local(name: &str, repo: &Repo) -> Result<Option<String>> {
+    if name == "HEAD" {
+        return rev::head_branch(repo);
+    }
+    if name.starts_with("refs/") {
+        return Ok(Some(name.to_owned()));
+    }
+    let refs = rev::list_refs(repo)?;
+    Ok(["refs/heads/", "refs/tags/"]
+        .iter()
+        .map(|prefix| format!("{}{}", prefix, name))
+        .find(|full| refs.iter().any(|(name, _)| name == full)))
+}
+
+/// The full name of the remote ref `name` is pushed to. Names that don't
+/// start with `refs/` are the remote's branch or tag of that name if it has
+/// one, or else go wherever the source is.
+fn expand_remote(name: &str, local: Option<&str>, remote_refs: &HashMap<String, Id>) -> String {
+    if name.starts_with("refs/") {
+        return name.to_owned();
+    }
+    let existing = ["refs/heads/", "refs/tags/"]
+        .iter()
+        .map(|prefix| format!("{}{}", prefix, name))
+        .find(|full| remote_refs.contains_key(full));
+    match existing {
+        Some(full) => full,
+        None if local
+            .filter(|local| local.starts_with("refs/tags/"))
+            .is_some() =>
+        {
+            format!("refs/tags/{}", name)
+        }
+        None => format!("refs/heads/{}", name),
+    }
+}
+
+/// What a refspec pushes where: the source as given, our object, or None to
+/// delete, and the remote ref, along with whether it may be forced
+type Resolved = (String, Option<Id>, String, bool);
+
+/// Works out what each refspec pushes where
+fn resolve_refspecs(
+    repo: &Repo,
+    refspecs: &[Refspec],
+    remote_refs: &HashMap<String, Id>,
+) -> Result<Vec<Resolved>> {
+    let mut resolved = Vec::new();
+    for refspec in refspecs {
+        if refspec.src.contains('*') {
+            for (name, id) in rev::list_refs(repo)? {
+                if let Some(dst) = refspec.map(&name) {
+                    resolved.push((name, Some(id), dst, refspec.force));
+                }
+            }
+            continue;
+        }
+        if refspec.src.is_empty() {
+            let dst = expand_remote(&refspec.dst, None, remote_refs);
+            if !remote_refs.contains_key(&dst) {
+                return Err(PushError::NoRemoteRef(refspec.dst.clone()).into());
+            }
+            resolved.push((String::new(), None, dst, refspec.force));
+            continue;
+        }
+        let local = expand_local(&refspec.src, repo)?;
+        let id = rev::parse(local.as_deref().unwrap_or(&refspec.src), repo)
+            .map_err(|_| PushError::NoMatch(refspec.src.clone()))?;
+        let dst = match &local {
+            // HEAD on its own goes to the branch of the same name
+            Some(local) if refspec.dst == "HEAD" => local.clone(),
+            _ => expand_remote(&refspec.dst, local.as_deref(), remote_refs),
+        };
+        resolved.push((refspec.src.clone(), Some(id), dst, refspec.force));
+    }
+    Ok(resolved)
+}
+
+/// Objects at the remote's old tips that are at the same paths as objects
+/// being sent, which are likely to make good delta bases
+fn thin_bases(
+    repo: &Repo,
+    objects: &[revwalk::FoundObject],
+    old_tips: &[Id],
+) -> Result<Vec<revwalk::FoundObject>> {
+    let mut commits = Vec::new();
+    for id in old_tips {
+        let id = rev::peel_tags(*id, repo)?;
+        if repo.object_info(&id)?.kind == ObjectType::Commit {
+            commits.push(id);
+        }
+    }
+    let paths: HashSet<_> = objects
+        .iter()
+        .filter(|obj| obj.kind == ObjectType::Blob || obj.kind == ObjectType::Tree)
+        .map(|obj| &obj.path)
+        .collect();
+    let mut bases = revwalk::tree_objects(repo, &commits, &[])?;
+    bases.retain(|obj| paths.contains(&obj.path));
+    Ok(bases)
+}
+
+/// Reads the data of each packet up to a flush
+fn read_packets(input: &mut impl Read) -> Result<Vec<Vec<u8>>> {
+    let mut packets = Vec::new();
+    loop {
+        match pktline::read(input)?.ok_or(PushError::Disconnected)? {
+            Packet::Data(data) => packets.push(data),
+            Packet::Flush => return Ok(packets),
+        }
+    }
+}
+
+/// Reads what the remote sends after the pack: its report, which comes in
+/// band 1 of the side-band if that was asked for, with progress messages in
+/// band 2 and errors in band 3
+fn read_report(
+    side_band: bool,
+    input: &mut impl Read,
+    callbacks: &mut Callbacks,
+) -> Result<Vec<String>> {
+    let packets = if side_band {
+        let mut report = Vec::new();
+        for packet in read_packets(input)? {
+            match packet.split_first() {
+                Some((1, data)) => report.extend_from_slice(data),
+                Some((2, message)) => {
+                    callbacks.report_progress(&String::from_utf8_lossy(message))?
+                }
+                Some((3, message)) => {
+                    return Err(Error::new(PushError::Remote(
+                        String::from_utf8_lossy(message).trim_end().to_owned(),
+                    )))
+                }
+                _ => return Err(protocol_error("a side-band packet", &packet)),
+            }
+        }
+        read_packets(&mut &report[..])?
+    } else {
+        read_packets(input)?
+    };
+    Ok(packets
+        .iter()
+        .map(|packet| {
+            String::from_utf8_lossy(packet)
+                .trim_end_matches('\n')
+                .to_owned()
+        })
+        .collect())
+}
+
+fn protocol_error(expected: &'static str, got: &[u8]) -> Error {
+    Error::new(PushError::Protocol {
+        expected,
+        got: String::from_utf8_lossy(got).into_owned(),
+    })
+}
+
+/// Pushes to the remote on the other end of `input` and `output`, which has
+/// just been connected to its receive-pack, updating its refs through
+/// `refspecs`. With `force`, every update may lose commits.
+pub fn push_to(
+    repo: &mut Repo,
+    refspecs: &[Refspec],
+    force: bool,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    callbacks: &mut Callbacks,
+) -> Result<Vec<PushUpdate>> {
+    let _region = trace::region("transport", "push");
+    // the remote gets history as it really is, like gc and repack would pack
+    repo.set_replace_objects(false);
+    let advertisement = fetch::read_advertisement(input)?;
+    let remote_refs: HashMap<String, Id> = advertisement.refs.iter().cloned().collect();
+
+    // we can only tell whether commits would be lost if we have the remote's
+    let is_commit = |id: &Id| -> Result<bool> {
+        Ok(repo.has_id(id) && repo.object_info(id)?.kind == ObjectType::Commit)
+    };
+    let mut updates = Vec::new();
+    for (local, new, remote, forced) in resolve_refspecs(repo, refspecs, &remote_refs)? {
+        let old = remote_refs.get(&remote).copied();
+        let mut refused = None;
+        let kind = match (old, new) {
+            (_, None) if !advertisement.offers("delete-refs") => {
+                refused = Some("remote does not support deleting refs".to_owned());
+                UpdateKind::Deleted
+            }
+            (_, None) => UpdateKind::Deleted,
+            (None, _) => UpdateKind::New,
+            (Some(old), Some(new)) if old == new => UpdateKind::UpToDate,
+            (Some(old), Some(new))
+                if is_commit(&old)?
+                    && is_commit(&new)?
+                    && merge::merge_bases(&old, &new, repo)?.contains(&old) =>
+            {
+                UpdateKind::FastForward
+            }
+            (Some(_), _) if force || forced => UpdateKind::Forced,
+            (Some(_), _) => UpdateKind::Rejected,
+        };
+        updates.push(PushUpdate {
+            local,
+            remote,
+            old,
+            new,
+            kind,
+            refused,
+        });
+    }
+
+    let sent: Vec<usize> = (0..updates.len())
+        .filter(|&n| match updates[n].kind {
+            UpdateKind::New | UpdateKind::FastForward | UpdateKind::Forced => true,
+            UpdateKind::Deleted => updates[n].refused.is_none(),
+            UpdateKind::Rejected | UpdateKind::UpToDate => false,
+        })
+        .collect();
+    if sent.is_empty() {
+        pktline::flush(output)?;
+        output.flush()?;
+        return Ok(updates);
+    }
+
+    let report_status = advertisement.offers("report-status");
+    let side_band = report_status && advertisement.offers("side-band-64k");
+    let capabilities = ["report-status", "side-band-64k", "ofs-delta"]
+        .iter()
+        .filter(|capability| advertisement.offers(capability))
+        .map(|capability| capability.to_string())
+        .chain(
+            advertisement
+                .capabilities
+                .iter()
+                .find(|capability| capability.starts_with("agent="))
+                .map(|_| format!("agent=rgit/{}", env!("CARGO_PKG_VERSION"))),
+        )
+        .collect::<Vec<_>>()
+        .join(" ");
+    let zero = Id::from_bytes([0; 20]);
+    for (n, &update) in sent.iter().enumerate() {
+        let update = &updates[update];
+        let mut line = format!(
+            "{} {} {}",
+            update.old.unwrap_or(zero),
+            update.new.unwrap_or(zero),
+            update.remote
+        );
+        if n == 0 && !capabilities.is_empty() {
+            line.push('\0');
+            line.push_str(&capabilities);
+        }
+        line.push('\n');
+        pktline::write(output, line.as_bytes())?;
+    }
+    pktline::flush(output)?;
+
+    // the remote has everything reachable from its refs that we have too
+    let have: Vec<Id> = advertisement
+        .refs
+        .iter()
+        .map(|(_, id)| *id)
+        .filter(|id| repo.has_id(id))
+        .collect();
+    let tips: Vec<Id> = sent.iter().filter_map(|&n| updates[n].new).collect();
+    // a push that only deletes sends no pack
+    if !tips.is_empty() {
+        let objects = revwalk::reachable_objects(repo, &tips, &have)?;
+        let bases = if advertisement.offers("no-thin") {
+            Vec::new()
+        } else {
+            let old_tips: Vec<Id> = sent
+                .iter()
+                .filter(|&&n| updates[n].new.is_some())
+                .filter_map(|&n| updates[n].old)
+                .filter(|id| repo.has_id(id))
+                .collect();
+            thin_bases(repo, &objects, &old_tips)?
+        };
+        trace::message(format_args!(
+            "push: {} objects, {} thin bases",
+            objects.len(),
+            bases.len()
+        ));
+        packs::write_thin_pack(repo, &objects, &bases, &mut *output)?;
+    }
+    output.flush()?;
+
+    if !report_status {
+        return Ok(updates);
+    }
+    let report = read_report(side_band, input, callbacks)?;
+    let mut lines = report.iter();
+    let unpack = lines.next().map(String::as_str).unwrap_or_default();
+    match unpack.strip_prefix("unpack ") {
+        Some("ok") => {}
+        Some(message) => return Err(Error::new(PushError::Unpack(message.to_owned()))),
+        None => return Err(protocol_error("unpack status", unpack.as_bytes())),
+    }
+    let mut statuses = HashMap::new();
+    for text in lines {
+        if let Some(name) = text.strip_prefix("ok ") {
+            statuses.insert(name.to_owned(), None);
+        } else if let Some(rest) = text.strip_prefix("ng ") {
+            let (name, reason) = match rest.find(' ') {
+                Some(space) => (&rest[..space], &rest[space + 1..]),
+                None => (rest, ""),
+            };
+            statuses.insert(name.to_owned(), Some(reason.to_owned()));
+        } else {
+            return Err(protocol_error("ok or ng", text.as_bytes()));
+        }
+    }
+    for &n in &sent {
+        let update = &mut updates[n];
+        update.refused = match statuses.remove(&update.remote) {
+            Some(refused) => refused,
+            None => Some("no status reported".to_owned()),
+        };
+    }
+    Ok(updates)
+}
+
+/// The refspecs to push to `remote` with when none are given: those in
+/// `remote.<remote>.push` in the config, or else the current branch to the
+/// branch of the same name
+pub fn default_refspecs(remote: &str, repo: &Repo) -> Result<Vec<Refspec>> {
+    let config = repo.config()?;
+    let configured = config
+        .get_all(&format!("remote.{}.push", remote))
+        .map(|spec| {
+            parse_refspec(spec).ok_or_else(|| PushError::BadRefspec(spec.to_owned()).into())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if !configured.is_empty() {
+        return Ok(configured);
+    }
+    let branch = rev::head_branch(repo)?.ok_or(PushError::Detached)?;
+    Ok(vec![Refspec {
+        force: false,
+        src: branch.clone(),
+        dst: branch,
+    }])
+}
+
+/// Pushes to the remote named `remote`, at `remote.<remote>.url` in the
+/// config, then moves our remote-tracking refs for the refs that were
+/// updated
+#[cfg(not(target_arch = "wasm32"))]
+pub fn push(
+    repo: &mut Repo,
+    remote: &str,
+    refspecs: &[Refspec],
+    force: bool,
+    callbacks: &mut Callbacks,
+) -> Result<Vec<PushUpdate>> {
+    let url = repo
+        .config()?
+        .get(&format!("remote.{}.url", remote))
+        .map(str::to_owned)
+        .ok_or_else(|| PushError::NoUrl(remote.to_owned()))?;
+    let mut connection = crate::transport::connect(&url, "git-receive-pack", callbacks)?;
+    let updates = push_to(
+        repo,
+        refspecs,
+        force,
+        &mut connection.input,
+        &mut connection.output,
+        callbacks,
+    )?;
+    connection.close()?;
+
+    let tracking = fetch::refspecs_for(remote, repo)?;
+    for update in &updates {
+        if update.kind == UpdateKind::UpToDate || !update.succeeded() {
+            continue;
+        }
+        if let Some(local) = tracking
+            .iter()
+            .find_map(|refspec| refspec.map(&update.remote))
+        {
+            match update.new {
+                Some(new) => rev::update_ref(Path::new(&local), &new, "update by push", repo)?,
+                None => rev::delete_ref(&local, repo)?,
+            }
+        }
+    }
+    Ok(updates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_refspec, push_to, PushUpdate};
+    use crate::fetch::{Refspec, UpdateKind};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, Repo, Tree};
+    use crate::receive_pack::receive_pack;
+    use crate::remote::Callbacks;
+    use crate::rev;
+    use crate::transport::pipe;
+    use std::io::BufReader;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// A repository that takes pushes to any branch, having no worktree
+    fn bare_repo() -> Repo {
+        let repo = Repo::in_memory();
+        repo.vfs()
+            .write(Path::new("/repo/.git/config"), b"[core]\n\tbare = true\n")
+            .unwrap();
+        repo
+    }
+
+    fn commit(repo: &Repo, content: &str, parents: Vec<Id>) -> Id {
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "file".into(),
+                id: repo.store(&Blob::new(content.as_bytes().to_vec())).unwrap(),
+            }],
+        };
+        repo.store(&Commit {
+            tree: repo.store(&tree).unwrap(),
+            parents,
+            author: who.clone(),
+            committer: who,
+            message: format!("{}\n", content),
+        })
+        .unwrap()
+    }
+
+    /// Pushes to `remote`, served by receive_pack on a thread
+    fn push(repo: &mut Repo, remote: Arc<Repo>, specs: &[&str], force: bool) -> Vec<PushUpdate> {
+        let (to_server, server_input) = pipe();
+        let (mut server_output, mut from_server) = pipe();
+        let server = thread::spawn(move || {
+            receive_pack(
+                &remote,
+                &mut BufReader::new(server_input),
+                &mut server_output,
+            )
+            .unwrap();
+        });
+        let mut to_server = to_server;
+        let refspecs: Vec<Refspec> = specs.iter().map(|s| parse_refspec(s).unwrap()).collect();
+        let updates = push_to(
+            repo,
+            &refspecs,
+            force,
+            &mut from_server,
+            &mut to_server,
+            &mut Callbacks::new(),
+        )
+        .unwrap();
+        drop(to_server);
+        server.join().unwrap();
+        updates
+    }
+
+    fn kinds(updates: &[PushUpdate]) -> Vec<(&str, UpdateKind, Option<&str>)> {
+        updates
+            .iter()
+            .map(|u| (u.remote.as_str(), u.kind, u.refused.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_refspec() {
+        let spec = parse_refspec("master").unwrap();
+        assert_eq!((spec.src.as_str(), spec.dst.as_str()), ("master", "master"));
+        let spec = parse_refspec("+HEAD:refs/heads/other").unwrap();
+        assert!(spec.force);
+        assert_eq!(spec.src, "HEAD");
+        assert_eq!(spec.dst, "refs/heads/other");
+        let spec = parse_refspec(":gone").unwrap();
+        assert_eq!((spec.src.as_str(), spec.dst.as_str()), ("", "gone"));
+        assert_eq!(parse_refspec(":refs/heads/*"), None);
+        assert_eq!(parse_refspec(":"), None);
+        assert_eq!(parse_refspec("refs/heads/*:main"), None);
+    }
+
+    #[test]
+    fn test_push() {
+        let mut repo = Repo::in_memory();
+        let first = commit(&repo, "first", vec![]);
+        let second = commit(&repo, "second", vec![first]);
+        rev::update_ref(Path::new("refs/heads/master"), &second, "", &repo).unwrap();
+        let remote = Arc::new(bare_repo());
+        // the remote gets the real first commit, not its replacement
+        let fake = commit(&repo, "fake", vec![]);
+        let replace_ref = format!("refs/replace/{}", first);
+        rev::update_ref(Path::new(&replace_ref), &fake, "", &repo).unwrap();
+
+        let updates = push(&mut repo, remote.clone(), &["master", "HEAD~1:old"], false);
+        assert_eq!(
+            kinds(&updates),
+            vec![
+                ("refs/heads/master", UpdateKind::New, None),
+                ("refs/heads/old", UpdateKind::New, None),
+            ]
+        );
+        assert_eq!(rev::parse("refs/heads/master", &remote).unwrap(), second);
+        assert_eq!(rev::parse("refs/heads/old", &remote).unwrap(), first);
+        assert!(remote.has_id(&first));
+
+        // moving back would lose a commit, unless forced
+        let third = commit(&repo, "third", vec![second]);
+        rev::update_ref(Path::new("refs/heads/master"), &third, "", &repo).unwrap();
+        let updates = push(
+            &mut repo,
+            remote.clone(),
+            &["master", "master~2:master"],
+            false,
+        );
+        assert_eq!(updates[0].kind, UpdateKind::FastForward);
+        assert_eq!(updates[1].kind, UpdateKind::Rejected);
+        assert_eq!(rev::parse("refs/heads/master", &remote).unwrap(), third);
+        assert_eq!(
+            kinds(&push(&mut repo, remote.clone(), &["master~2:master"], true)),
+            vec![("refs/heads/master", UpdateKind::Forced, None)]
+        );
+        assert_eq!(rev::parse("refs/heads/master", &remote).unwrap(), first);
+
+        // commits the remote has that we don't can't be checked, so they
+        // can only be replaced by force
+        let elsewhere = commit(&remote, "elsewhere", vec![]);
+        rev::update_ref(Path::new("refs/heads/old"), &elsewhere, "", &remote).unwrap();
+        assert_eq!(
+            kinds(&push(
+                &mut repo,
+                remote.clone(),
+                &["master:old", "+master"],
+                false
+            )),
+            vec![
+                ("refs/heads/old", UpdateKind::Rejected, None),
+                ("refs/heads/master", UpdateKind::FastForward, None),
+            ]
+        );
+        assert_eq!(
+            kinds(&push(&mut repo, remote.clone(), &["master"], false)),
+            vec![("refs/heads/master", UpdateKind::UpToDate, None)]
+        );
+
+        // and the remote can refuse updates itself
+        remote
+            .vfs()
+            .write(
+                Path::new("/repo/.git/config"),
+                b"[core]\n\tbare = true\n[receive]\n\tdenyNonFastForwards = true\n",
+            )
+            .unwrap();
+        assert_eq!(
+            kinds(&push(&mut repo, remote.clone(), &["master~1:master"], true)),
+            vec![(
+                "refs/heads/master",
+                UpdateKind::Forced,
+                Some("non-fast-forward")
+            )]
+        );
+        assert_eq!(rev::parse("refs/heads/master", &remote).unwrap(), third);
+
+        // an empty source deletes the remote ref
+        assert_eq!(
+            kinds(&push(&mut repo, remote.clone(), &[":old"], false)),
+            vec![("refs/heads/old", UpdateKind::Deleted, None)]
+        );
+        assert!(rev::parse("refs/heads/old", &remote).is_err());
+    }
+}
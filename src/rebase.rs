@@ -0,0 +1,225 @@
+//! The todo list of a rebase: which commits to replay, in what order, and
+//! what to do with each. It is written out for `rebase -i` to be edited as
+//! text, one step to a line:
+//!
+//! ```text
+//! pick 1a2b3c4 Add a thing
+//! fixup 5d6e7f8 Fix the thing
+//! ```
+//!
+//! Blank lines and lines starting with `#` are ignored, and each action can
+//! be given as just its first letter.
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::objects::{Id, Repo};
+use crate::patch;
+use crate::rev;
+
+/// Shortest abbreviated object ID written in a todo list
+const ABBREV: usize = 7;
+
+/// What goes under the steps when the todo list is edited
+pub const TODO_HELP: &str = "\
+# Commands:
+# p, pick <commit> = use commit
+# r, reword <commit> = use commit, but edit the commit message
+# s, squash <commit> = use commit, but meld into previous commit
+# f, fixup <commit> = like \"squash\", but discard this commit's log message
+# d, drop <commit> = remove commit
+#
+# These lines can be re-ordered; they are executed from top to bottom.
+#
+# If you remove a line here THAT COMMIT WILL BE LOST.
+#
+# However, if you remove everything, the rebase will be aborted.
+";
+
+/// Errors from reading a todo list
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TodoError {
+    /// The line doesn't start with an action there is
+    #[error("unknown rebase action {0:?}")]
+    UnknownAction(String),
+
+    /// The line has an action but no commit
+    #[error("no commit given to {0}")]
+    MissingCommit(Action),
+}
+
+/// What to do with a commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Replay it as it is
+    Pick,
+    /// Replay it, then edit its message
+    Reword,
+    /// Meld it into the commit before, editing the messages of both together
+    Squash,
+    /// Meld it into the commit before, keeping only that one's message
+    Fixup,
+    /// Leave it out
+    Drop,
+}
+
+impl Action {
+    /// Whether the commit goes into the one before rather than being its own
+    pub fn melds(self) -> bool {
+        self == Action::Squash || self == Action::Fixup
+    }
+}
+
+impl FromStr for Action {
+    type Err = TodoError;
+
+    fn from_str(s: &str) -> Result<Action, TodoError> {
+        match s {
+            "p" | "pick" => Ok(Action::Pick),
+            "r" | "reword" => Ok(Action::Reword),
+            "s" | "squash" => Ok(Action::Squash),
+            "f" | "fixup" => Ok(Action::Fixup),
+            "d" | "drop" => Ok(Action::Drop),
+            _ => Err(TodoError::UnknownAction(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Action::Pick => "pick",
+            Action::Reword => "reword",
+            Action::Squash => "squash",
+            Action::Fixup => "fixup",
+            Action::Drop => "drop",
+        })
+    }
+}
+
+/// One line of a todo list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    /// What to do
+    pub action: Action,
+    /// The commit to do it with
+    pub commit: Id,
+}
+
+/// Reads a todo list, looking up the commits in `repo`. Anything after the
+/// commit on a line, usually its subject, is only there for people to read.
+pub fn parse_todo(text: &str, repo: &Repo) -> Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut words = line.split_whitespace();
+        let action = words.next().unwrap_or_default().parse::<Action>()?;
+        let commit = words.next().ok_or(TodoError::MissingCommit(action))?;
+        let commit = rev::peel_tags(rev::parse(commit, repo)?, repo)?;
+        steps.push(Step { action, commit });
+    }
+    Ok(steps)
+}
+
+/// Writes out a todo list with abbreviated IDs and the subject of each commit
+pub fn format_todo(steps: &[Step], repo: &Repo) -> Result<String> {
+    let mut out = String::new();
+    for step in steps {
+        let commit = repo
+            .open(&step.commit)?
+            .commit()
+            .ok_or_else(|| anyhow!("{} is not a commit", step.commit))?;
+        let len = rev::abbrev_len(&step.commit, ABBREV, repo)?;
+        let (subject, _) = patch::split_message(&commit.message);
+        out.push_str(&format!(
+            "{} {} {}\n",
+            step.action,
+            &step.commit.to_string()[..len],
+            subject
+        ));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_todo, parse_todo, Action, Step, TodoError};
+    use crate::objects::{Commit, Id, NameEntry, Repo, Tree};
+
+    #[test]
+    fn test_todo() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("A U Thor <author@example.com> 1586391037 -0700").unwrap();
+        let tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let commit = |message: &str| {
+            repo.store(&Commit {
+                tree,
+                parents: vec![],
+                author: who.clone(),
+                committer: who.clone(),
+                message: message.to_owned(),
+            })
+            .unwrap()
+        };
+        let a = commit("Add a thing\n");
+        let b = commit("Fix the thing\n\nIt was broken.\n");
+        let steps = vec![
+            Step {
+                action: Action::Pick,
+                commit: a,
+            },
+            Step {
+                action: Action::Fixup,
+                commit: b,
+            },
+        ];
+
+        let todo = format_todo(&steps, &repo).unwrap();
+        let short = |id: &Id| id.to_string()[..7].to_owned();
+        assert_eq!(
+            todo,
+            format!(
+                "pick {} Add a thing\nfixup {} Fix the thing\n",
+                short(&a),
+                short(&b)
+            )
+        );
+        assert_eq!(parse_todo(&todo, &repo).unwrap(), steps);
+
+        let edited = format!(
+            "# comment\n\n  r {} whatever\nd {}\n{}",
+            a,
+            short(&b),
+            super::TODO_HELP
+        );
+        assert_eq!(
+            parse_todo(&edited, &repo).unwrap(),
+            vec![
+                Step {
+                    action: Action::Reword,
+                    commit: a,
+                },
+                Step {
+                    action: Action::Drop,
+                    commit: b,
+                },
+            ]
+        );
+
+        let error = |text: &str| {
+            parse_todo(text, &repo)
+                .unwrap_err()
+                .downcast::<TodoError>()
+                .unwrap()
+        };
+        assert_eq!(
+            error("edit 1234567"),
+            TodoError::UnknownAction("edit".to_owned())
+        );
+        assert_eq!(error("squash"), TodoError::MissingCommit(Action::Squash));
+        assert!(parse_todo("pick nonsense", &repo).is_err());
+    }
+}
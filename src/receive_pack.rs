@@ -0,0 +1,451 @@
+//! The server side of push: version 0 of git's pack protocol, as spoken over
+//! ssh. We advertise our refs, the client says which of them to change and
+//! sends a pack of the objects we are missing, then we report on each ref
+//! with report-status.
+//!
+//! Each ref is checked again before it is changed, so an update loses if the
+//! ref has moved since it was advertised. Updates that lose commits are let
+//! through unless receive.denyNonFastForwards is set, since the client checks
+//! those itself, but the branch checked out in a repository with a worktree
+//! is never changed. Branches can be deleted unless receive.denyDeletes is
+//! set, but HEAD's branch can't be. A ref is only moved to an object whose
+//! history is all here.
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+use thiserror::Error;
+
+use crate::merge;
+use crate::objects::{Id, ObjectType, Repo};
+use crate::packs;
+use crate::pktline::{self, Packet};
+use crate::rev;
+use crate::revwalk;
+use crate::trace;
+
+/// Errors that can arise while serving a push
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReceivePackError {
+    /// The client sent something other than what the protocol allows here
+    #[error("Protocol error: expected {expected}, got {got:?}")]
+    Protocol {
+        /// What was expected
+        expected: &'static str,
+        /// The offending line
+        got: String,
+    },
+
+    /// The client hung up before it was done asking
+    #[error("Client disconnected before sending its commands")]
+    Disconnected,
+}
+
+/// Capabilities sent with the first ref
+fn capabilities() -> String {
+    format!(
+        "report-status delete-refs ofs-delta agent=rgit/{}",
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+/// Sends the ref advertisement, returning the refs as advertised
+fn advertise(repo: &Repo, output: &mut impl Write) -> Result<HashMap<String, Id>> {
+    let refs = rev::list_refs(repo)?;
+    if refs.is_empty() {
+        let line = format!(
+            "{} capabilities^{{}}\0{}\n",
+            Id::from_bytes([0; 20]),
+            capabilities()
+        );
+        pktline::write(output, line.as_bytes())?;
+    }
+    for (n, (name, id)) in refs.iter().enumerate() {
+        let line = if n == 0 {
+            format!("{} {}\0{}\n", id, name, capabilities())
+        } else {
+            format!("{} {}\n", id, name)
+        };
+        pktline::write(output, line.as_bytes())?;
+    }
+    pktline::flush(output)?;
+    output.flush()?;
+    Ok(refs.into_iter().collect())
+}
+
+/// A change to a ref that the client asked for
+#[derive(Debug, PartialEq, Eq)]
+struct Command {
+    /// Where the client thinks the ref is, or None if it thinks the ref
+    /// doesn't exist
+    old: Option<Id>,
+    /// Where it should go, or None to delete it
+    new: Option<Id>,
+    name: String,
+}
+
+/// Parses an ID in a command, where all zeroes stands for no object
+fn parse_command_id(id: &str) -> Option<Option<Id>> {
+    let id = Id::from(id)?;
+    Some(if id == Id::from_bytes([0; 20]) {
+        None
+    } else {
+        Some(id)
+    })
+}
+
+/// Reads the client's commands, along with the capabilities it asked for.
+/// There are none if the client only wanted to list refs, in which case it
+/// may just hang up.
+fn read_commands(input: &mut impl BufRead) -> Result<(Vec<Command>, Vec<String>)> {
+    let mut commands = Vec::new();
+    let mut capabilities = Vec::new();
+    loop {
+        let packet = match pktline::read(input)? {
+            None if commands.is_empty() => return Ok((commands, capabilities)),
+            None => return Err(Error::new(ReceivePackError::Disconnected)),
+            Some(Packet::Flush) => return Ok((commands, capabilities)),
+            Some(packet) => packet,
+        };
+        let line = packet.line().unwrap();
+        // the first command also carries the client's capabilities
+        let line = match line.iter().position(|&b| b == 0) {
+            Some(nul) => {
+                capabilities = String::from_utf8_lossy(&line[nul + 1..])
+                    .split(' ')
+                    .filter(|capability| !capability.is_empty())
+                    .map(str::to_owned)
+                    .collect();
+                &line[..nul]
+            }
+            None => line,
+        };
+
+        let bad = || {
+            Error::new(ReceivePackError::Protocol {
+                expected: "a command",
+                got: String::from_utf8_lossy(line).into_owned(),
+            })
+        };
+        let text = std::str::from_utf8(line).map_err(|_| bad())?;
+        let mut parts = text.splitn(3, ' ');
+        let old = parts.next().and_then(parse_command_id).ok_or_else(bad)?;
+        let new = parts.next().and_then(parse_command_id).ok_or_else(bad)?;
+        let name = parts.next().ok_or_else(bad)?;
+        commands.push(Command {
+            old,
+            new,
+            name: name.to_owned(),
+        });
+    }
+}
+
+/// Whether everything reachable from `new` is here, so a ref moved there
+/// won't point into a broken history. The objects of the advertised refs are
+/// taken to be here already. Blobs are never read while walking, so they are
+/// looked for separately.
+fn is_connected(repo: &Repo, new: Id, refs: &HashMap<String, Id>) -> bool {
+    let have: Vec<Id> = refs.values().copied().collect();
+    match revwalk::reachable_objects(repo, &[new], &have) {
+        Ok(objects) => objects
+            .iter()
+            .all(|obj| obj.kind != ObjectType::Blob || repo.has_id(&obj.id)),
+        Err(_) => false,
+    }
+}
+
+/// Whether a receive.* setting that refuses something unless it is `ignore`,
+/// `warn` or `false` refuses it
+fn denies(value: Option<&str>) -> bool {
+    !matches!(value, Some("ignore") | Some("warn") | Some("false"))
+}
+
+/// Checks whether a command may be carried out, returning why not if it may
+/// not
+fn check(
+    repo: &Repo,
+    command: &Command,
+    refs: &HashMap<String, Id>,
+) -> Result<Option<&'static str>> {
+    if !command.name.starts_with("refs/") || !rev::is_valid_refname(&command.name, false) {
+        return Ok(Some("funny refname"));
+    }
+    if let Some(new) = command.new {
+        if !is_connected(repo, new, refs) {
+            return Ok(Some("missing necessary objects"));
+        }
+    }
+    if refs.get(&command.name) != command.old.as_ref() {
+        return Ok(Some("failed to lock"));
+    }
+
+    let config = repo.config()?;
+    let bare = config.get_bool("core.bare")?.unwrap_or(false);
+    let is_head = rev::head_branch(repo)?.as_deref() == Some(&*command.name);
+    if !bare && is_head && denies(config.get("receive.denyCurrentBranch")) {
+        return Ok(Some("branch is currently checked out"));
+    }
+
+    let new = match command.new {
+        Some(new) => new,
+        None if command.name.starts_with("refs/heads/")
+            && config.get_bool("receive.denyDeletes")?.unwrap_or(false) =>
+        {
+            return Ok(Some("deletion prohibited"))
+        }
+        None if is_head && denies(config.get("receive.denyDeleteCurrent")) => {
+            return Ok(Some("deletion of the current branch prohibited"))
+        }
+        None => return Ok(None),
+    };
+    if let Some(old) = command.old {
+        let is_commit =
+            |id| -> Result<bool> { Ok(repo.object_info(id)?.kind == ObjectType::Commit) };
+        if config
+            .get_bool("receive.denyNonFastForwards")?
+            .unwrap_or(false)
+            && is_commit(&old)?
+            && is_commit(&new)?
+            && !merge::merge_bases(&old, &new, repo)?.contains(&old)
+        {
+            return Ok(Some("non-fast-forward"));
+        }
+    }
+    Ok(None)
+}
+
+/// Serves one push to `repo`, reading the client's commands and pack from
+/// `input` and answering on `output`
+pub fn receive_pack(repo: &Repo, input: &mut impl BufRead, output: &mut impl Write) -> Result<()> {
+    let _region = trace::region("transport", "receive_pack");
+    let refs = advertise(repo, output)?;
+
+    let (commands, capabilities) = read_commands(input)?;
+    if commands.is_empty() {
+        return Ok(());
+    }
+    trace::message(format_args!("receive-pack: {} commands", commands.len()));
+
+    // a push that only deletes comes without a pack
+    let unpacked = if commands.iter().any(|command| command.new.is_some()) {
+        let path = Path::new("<push>");
+        packs::read_pack_stream(path, input)
+            .and_then(|pack| packs::unpack_objects(repo, path, &pack, false))
+            .map(|_| ())
+    } else {
+        Ok(())
+    };
+
+    let mut report = Vec::new();
+    match &unpacked {
+        Ok(()) => report.push("unpack ok\n".to_owned()),
+        Err(e) => report.push(format!("unpack {:#}\n", e)),
+    }
+    for command in &commands {
+        let refused = match (&unpacked, command.new) {
+            (Err(_), _) => Some("unpacker error"),
+            (Ok(()), new) => match check(repo, command, &refs)? {
+                Some(reason) => Some(reason),
+                None => {
+                    match new {
+                        Some(new) => rev::update_ref(Path::new(&command.name), &new, "push", repo)?,
+                        None => rev::delete_ref(&command.name, repo)?,
+                    }
+                    None
+                }
+            },
+        };
+        match refused {
+            Some(reason) => report.push(format!("ng {} {}\n", command.name, reason)),
+            None => report.push(format!("ok {}\n", command.name)),
+        }
+    }
+
+    if capabilities
+        .iter()
+        .any(|capability| capability == "report-status")
+    {
+        for line in &report {
+            pktline::write(output, line.as_bytes())?;
+        }
+        pktline::flush(output)?;
+        output.flush()?;
+    }
+    unpacked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::receive_pack;
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, ObjectType, Repo, Tree};
+    use crate::packs;
+    use crate::pktline::{self, Packet};
+    use crate::rev;
+    use crate::revwalk;
+    use crate::vfs::{MemoryFs, Vfs};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    /// The lines of the report, with the ref advertisement skipped
+    fn report(output: &[u8]) -> Vec<String> {
+        let mut reply = output;
+        while pktline::read(&mut reply).unwrap() != Some(Packet::Flush) {}
+        let mut lines = Vec::new();
+        while let Some(Packet::Data(line)) = pktline::read(&mut reply).unwrap() {
+            lines.push(String::from_utf8(line).unwrap());
+        }
+        lines
+    }
+
+    #[test]
+    fn test_receive_pack() {
+        let fs = Arc::new(MemoryFs::new());
+        let client = Repo::in_memory_at(&fs, "/client");
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "hello".into(),
+                id: client.store(&Blob::new(b"hello\n".to_vec())).unwrap(),
+            }],
+        };
+        let commit = client
+            .store(&Commit {
+                tree: client.store(&tree).unwrap(),
+                parents: vec![],
+                author: who.clone(),
+                committer: who.clone(),
+                message: "hello\n".to_string(),
+            })
+            .unwrap();
+        let objects = revwalk::reachable_objects(&client, &[commit], &[]).unwrap();
+        let zero = Id::from_bytes([0; 20]);
+        // a commit sent without its blob
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "hello".into(),
+                id: client.store(&Blob::new(b"missing\n".to_vec())).unwrap(),
+            }],
+        };
+        let broken = client
+            .store(&Commit {
+                tree: client.store(&tree).unwrap(),
+                parents: vec![commit],
+                author: who.clone(),
+                committer: who,
+                message: "broken\n".to_string(),
+            })
+            .unwrap();
+        let mut sent = objects.clone();
+        sent.extend(
+            revwalk::reachable_objects(&client, &[broken], &[commit])
+                .unwrap()
+                .into_iter()
+                .filter(|obj| obj.kind != ObjectType::Blob),
+        );
+
+        let server = Repo::in_memory_at(&fs, "/server");
+        fs.write(Path::new("/server/.git/config"), b"[core]\n\tbare = true\n")
+            .unwrap();
+        let mut input = Vec::new();
+        pktline::write(
+            &mut input,
+            format!("{} {} refs/heads/master\0report-status\n", zero, commit).as_bytes(),
+        )
+        .unwrap();
+        pktline::write(
+            &mut input,
+            format!("{} {} refs/heads/gone\n", zero, commit).as_bytes(),
+        )
+        .unwrap();
+        pktline::write(
+            &mut input,
+            format!("{} {} refs/heads/stale\n", commit, commit).as_bytes(),
+        )
+        .unwrap();
+        pktline::write(
+            &mut input,
+            format!("{} {} refs/heads/broken\n", zero, broken).as_bytes(),
+        )
+        .unwrap();
+        pktline::flush(&mut input).unwrap();
+        packs::write_pack(&client, &sent, &mut input).unwrap();
+
+        let mut output = Vec::new();
+        receive_pack(&server, &mut &input[..], &mut output).unwrap();
+        assert_eq!(
+            report(&output),
+            vec![
+                "unpack ok\n",
+                "ok refs/heads/master\n",
+                "ok refs/heads/gone\n",
+                "ng refs/heads/stale failed to lock\n",
+                "ng refs/heads/broken missing necessary objects\n",
+            ]
+        );
+        assert_eq!(rev::parse("refs/heads/master", &server).unwrap(), commit);
+        assert!(server.has_id(&objects[2].id));
+        assert!(rev::parse("refs/heads/broken", &server).is_err());
+
+        // deletions come without a pack, and HEAD's branch can't be deleted
+        let delete = |name: &str, server: &Repo| {
+            let mut input = Vec::new();
+            pktline::write(
+                &mut input,
+                format!("{} {} {}\0report-status\n", commit, zero, name).as_bytes(),
+            )
+            .unwrap();
+            pktline::flush(&mut input).unwrap();
+            let mut output = Vec::new();
+            receive_pack(server, &mut &input[..], &mut output).unwrap();
+            report(&output)
+        };
+        assert_eq!(
+            delete("refs/heads/master", &server),
+            vec![
+                "unpack ok\n",
+                "ng refs/heads/master deletion of the current branch prohibited\n"
+            ]
+        );
+        fs.write(
+            Path::new("/server/.git/config"),
+            b"[core]\n\tbare = true\n[receive]\n\tdenyDeletes = true\n",
+        )
+        .unwrap();
+        assert_eq!(
+            delete("refs/heads/gone", &server),
+            vec!["unpack ok\n", "ng refs/heads/gone deletion prohibited\n"]
+        );
+        fs.write(Path::new("/server/.git/config"), b"[core]\n\tbare = true\n")
+            .unwrap();
+        assert_eq!(
+            delete("refs/heads/gone", &server),
+            vec!["unpack ok\n", "ok refs/heads/gone\n"]
+        );
+        assert!(rev::parse("refs/heads/gone", &server).is_err());
+
+        // the checked out branch of a repository with a worktree is left alone
+        let server = Repo::in_memory_at(&fs, "/work");
+        let mut input = Vec::new();
+        pktline::write(
+            &mut input,
+            format!("{} {} refs/heads/master\0report-status\n", zero, commit).as_bytes(),
+        )
+        .unwrap();
+        pktline::flush(&mut input).unwrap();
+        packs::write_pack(&client, &objects, &mut input).unwrap();
+        let mut output = Vec::new();
+        receive_pack(&server, &mut &input[..], &mut output).unwrap();
+        assert_eq!(
+            report(&output),
+            vec![
+                "unpack ok\n",
+                "ng refs/heads/master branch is currently checked out\n"
+            ]
+        );
+
+        // listing refs only
+        assert!(receive_pack(&server, &mut &b""[..], &mut Vec::new()).is_ok());
+    }
+}
@@ -0,0 +1,244 @@
+//! Reflogs, the history of where refs have pointed. A ref that is logged has
+//! a file under `logs/`, named like the ref, with a line for each update,
+//! oldest first:
+//!
+//! ```text
+//! <old id> <new id> <name> <<email>> <time> <zone>\t<message>
+//! ```
+//!
+//! Whether a ref is logged is up to `core.logAllRefUpdates`, which by default
+//! covers HEAD and branches in repositories that aren't bare. Once a ref has
+//! a reflog, it is always added to. Repositories with reftables keep their
+//! reflogs in the tables, which [`crate::reftable`] doesn't write, so nothing
+//! is logged for them.
+use anyhow::{anyhow, Result};
+use std::io;
+use std::path::PathBuf;
+
+use crate::objects::{Id, NameEntry, Repo};
+use crate::rev::{self, RefStorage};
+use crate::util;
+
+/// The refs besides HEAD that are logged by default
+const LOGGED_PREFIXES: &[&str] = &["refs/heads/", "refs/remotes/", "refs/notes/"];
+
+/// A line of a reflog
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReflogEntry {
+    /// Where the ref pointed before, or all zeroes if it didn't exist
+    pub old: Id,
+    /// Where the ref points now
+    pub new: Id,
+    /// Who moved the ref, and when
+    pub who: NameEntry,
+    /// Why the ref moved, such as `commit: Add a thing`
+    pub message: String,
+}
+
+impl ReflogEntry {
+    /// Parses a line of a reflog, with or without its newline
+    pub fn parse(line: &[u8]) -> Option<ReflogEntry> {
+        let line = std::str::from_utf8(line).ok()?;
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let mut parts = line.splitn(2, '\t');
+        let header = parts.next()?;
+        let message = parts.next().unwrap_or_default().to_owned();
+        if header.get(40..41)? != " " || header.get(81..82)? != " " {
+            return None;
+        }
+        Some(ReflogEntry {
+            old: Id::from(header.get(..40)?)?,
+            new: Id::from(header.get(41..81)?)?,
+            who: NameEntry::from(header.get(82..)?)?,
+            message,
+        })
+    }
+
+    /// Turns the entry into a line of a reflog, newline included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut line = format!("{} {} ", self.old, self.new).into_bytes();
+        line.extend(self.who.encode());
+        line.push(b'\t');
+        line.extend(self.message.as_bytes());
+        line.push(b'\n');
+        line
+    }
+}
+
+fn log_path(name: &str, repo: &Repo) -> PathBuf {
+    repo.root.join("logs").join(name)
+}
+
+/// Whether updates to the ref `name` are logged
+fn is_logged(name: &str, repo: &Repo) -> Result<bool> {
+    if repo.vfs().exists(&log_path(name, repo)) {
+        return Ok(true);
+    }
+    let config = repo.config()?;
+    let default = !config.get_bool("core.bare")?.unwrap_or(false);
+    let log_refs = match config.get("core.logAllRefUpdates") {
+        Some(value) if value.eq_ignore_ascii_case("always") => return Ok(true),
+        Some(_) => config.get_bool("core.logAllRefUpdates")?.unwrap_or(default),
+        None => default,
+    };
+    Ok(log_refs && (name == "HEAD" || LOGGED_PREFIXES.iter().any(|p| name.starts_with(p))))
+}
+
+/// Who to say moved a ref: user.name and user.email from the config, or the
+/// user the process runs as if those aren't set
+fn committer(repo: &Repo) -> Result<NameEntry> {
+    let config = repo.config()?;
+    let who = match (config.get("user.name"), config.get("user.email")) {
+        (Some(name), Some(email)) => format!("{} <{}>", name, email),
+        _ => {
+            let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned());
+            format!("{} <{}@localhost>", user, user)
+        }
+    };
+    NameEntry::with_time(&who, util::now())
+        .ok_or_else(|| anyhow!("invalid identity {:?} for the reflog", who))
+}
+
+/// Adds an entry to the reflog of `name` for it moving from `old`, if it
+/// existed, to `new`, if the ref is logged. Whitespace in the message,
+/// newlines included, is collapsed into single spaces.
+pub fn append(name: &str, old: Option<Id>, new: &Id, message: &str, repo: &Repo) -> Result<()> {
+    if rev::ref_storage(repo)? == RefStorage::Reftable || !is_logged(name, repo)? {
+        return Ok(());
+    }
+    let entry = ReflogEntry {
+        old: old.unwrap_or_else(|| Id::from_bytes([0; 20])),
+        new: *new,
+        who: committer(repo)?,
+        message: message.split_whitespace().collect::<Vec<_>>().join(" "),
+    };
+
+    let vfs = repo.vfs();
+    let path = log_path(name, repo);
+    let mut content = match vfs.read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Some(parent) = path.parent() {
+                vfs.create_dir_all(parent)?;
+            }
+            Vec::new()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    content.extend(entry.encode());
+    repo.replace_file(&path, &content)
+}
+
+/// Reads the reflog of `name`, oldest entry first. A ref without a reflog has
+/// no entries, and lines that can't be parsed are skipped.
+pub fn read(name: &str, repo: &Repo) -> Result<Vec<ReflogEntry>> {
+    let content = match repo.vfs().read(&log_path(name, repo)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(content
+        .split(|&b| b == b'\n')
+        .filter_map(ReflogEntry::parse)
+        .collect())
+}
+
+/// Removes the reflog of `name`, such as when the ref is deleted
+pub fn remove(name: &str, repo: &Repo) -> Result<()> {
+    match repo.vfs().remove_file(&log_path(name, repo)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReflogEntry;
+    use crate::objects::{Blob, Id, NameEntry, Repo};
+    use crate::rev;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_entry() {
+        let line = "0000000000000000000000000000000000000000 \
+                    8ab686eafeb1f44702738c8b0f24f2567c36da6d \
+                    A U Thor <author@example.com> 1586391037 -0700\tcommit (initial): a\n";
+        let entry = ReflogEntry::parse(line.as_bytes()).unwrap();
+        assert_eq!(
+            entry,
+            ReflogEntry {
+                old: Id::from_bytes([0; 20]),
+                new: Id::from("8ab686eafeb1f44702738c8b0f24f2567c36da6d").unwrap(),
+                who: NameEntry::from("A U Thor <author@example.com> 1586391037 -0700").unwrap(),
+                message: "commit (initial): a".to_owned(),
+            }
+        );
+        assert_eq!(entry.encode(), line.as_bytes());
+        assert_eq!(ReflogEntry::parse(b"nonsense"), None);
+    }
+
+    #[test]
+    fn test_ref_updates_are_logged() {
+        let repo = Repo::in_memory();
+        repo.vfs()
+            .write(
+                &repo.root.join("config"),
+                b"[user]\n\tname = A U Thor\n\temail = author@example.com\n",
+            )
+            .unwrap();
+        let a = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b".to_vec())).unwrap();
+
+        // moving the branch HEAD is on logs both
+        repo.set_head(&a, "first\nline").unwrap();
+        rev::update_ref(Path::new("refs/heads/master"), &b, "second", &repo).unwrap();
+        let log = |name: &str| {
+            super::read(name, &repo)
+                .unwrap()
+                .into_iter()
+                .map(|entry| (entry.old, entry.new, entry.message))
+                .collect::<Vec<_>>()
+        };
+        let zero = Id::from_bytes([0; 20]);
+        let expected = vec![
+            (zero, a, "first line".to_owned()),
+            (a, b, "second".to_owned()),
+        ];
+        assert_eq!(log("refs/heads/master"), expected);
+        assert_eq!(log("HEAD"), expected);
+        let entry = &super::read("HEAD", &repo).unwrap()[0];
+        assert_eq!(entry.who.name, "A U Thor");
+        assert_eq!(entry.who.email, "author@example.com");
+
+        // tags aren't logged, and a detached HEAD is logged on its own
+        rev::update_ref(Path::new("refs/tags/v1"), &a, "tag", &repo).unwrap();
+        assert_eq!(log("refs/tags/v1"), vec![]);
+        rev::detach_head(&a, "detach", &repo).unwrap();
+        rev::update_ref(Path::new("HEAD"), &b, "detached", &repo).unwrap();
+        assert_eq!(log("refs/heads/master").len(), 2);
+        assert_eq!(
+            &log("HEAD")[2..],
+            &[(b, a, "detach".to_owned()), (a, b, "detached".to_owned()),]
+        );
+        rev::set_head_ref("refs/heads/master", "back", &repo).unwrap();
+        assert_eq!(log("HEAD")[4], (b, b, "back".to_owned()));
+
+        // unless they're all logged
+        repo.vfs()
+            .write(
+                &repo.root.join("config"),
+                b"[core]\n\tlogAllRefUpdates = always\n",
+            )
+            .unwrap();
+        rev::update_ref(Path::new("refs/tags/v1"), &b, "tag", &repo).unwrap();
+        assert_eq!(log("refs/tags/v1"), vec![(a, b, "tag".to_owned())]);
+        repo.vfs()
+            .write(
+                &repo.root.join("config"),
+                b"[core]\n\tlogAllRefUpdates = false\n",
+            )
+            .unwrap();
+        rev::update_ref(Path::new("refs/heads/other"), &b, "other", &repo).unwrap();
+        assert_eq!(log("refs/heads/other"), vec![]);
+    }
+}
@@ -0,0 +1,699 @@
+//! Reftable, a ref storage format that keeps refs in a stack of sorted binary
+//! tables rather than a file per ref. An update writes a small table with just
+//! the refs it changed and adds it to the top of the stack, so it costs the
+//! same however many refs there are. Tables are merged now and again to keep
+//! the stack short.
+//!
+//! The stack is listed, oldest table first, in `reftable/tables.list`, and a
+//! ref's value is the one in the newest table that has it. Only ref blocks
+//! are read and written: reflogs and the object and ref indexes that git may
+//! add are skipped, since lookups here scan the tables whole.
+use anyhow::{Context, Result};
+use flate2::Crc;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use thiserror::Error;
+
+use crate::objects::{Id, Repo};
+use crate::util;
+
+const MAGIC: &[u8; 4] = b"REFT";
+const HEADER_LEN: usize = 24;
+const FOOTER_LEN: usize = 68;
+/// Version 2 headers and footers have a hash function ID in them as well
+const HASH_ID_LEN: usize = 4;
+const BLOCK_SIZE: usize = 4096;
+/// How often a record is written with its whole name, as a point that
+/// searches can start from
+const RESTART_INTERVAL: usize = 16;
+const REF_BLOCK: u8 = b'r';
+
+/// Errors from reading reftables
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ReftableError {
+    /// The file doesn't start with the reftable magic number
+    #[error("Not a reftable")]
+    BadMagic,
+
+    /// A version other than 1 or 2
+    #[error("Unsupported reftable version {0}")]
+    UnsupportedVersion(u8),
+
+    /// A hash function other than SHA-1
+    #[error("Unsupported reftable hash function {0:?}")]
+    UnsupportedHash(String),
+
+    /// Something in the table points outside of it or makes no sense
+    #[error("Corrupt reftable: {0}")]
+    Corrupt(&'static str),
+}
+
+/// What a ref record says about a ref
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RefValue {
+    /// The ref was deleted, hiding it in older tables
+    Deleted,
+    /// The ref points at an object
+    Id(Id),
+    /// The ref points at a tag, which peels to the second ID
+    Peeled(Id, Id),
+    /// The ref points at another ref
+    Symref(String),
+}
+
+/// A ref as stored in a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefRecord {
+    /// Full name of the ref, such as `refs/heads/master`
+    pub name: String,
+    /// Which update set the ref
+    pub update_index: u64,
+    /// What the ref is
+    pub value: RefValue,
+}
+
+/// The refs in a reftable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Table {
+    /// Update index of the oldest update in the table
+    pub min_update_index: u64,
+    /// Update index of the newest update in the table
+    pub max_update_index: u64,
+    /// Ref records, sorted by name
+    pub refs: Vec<RefRecord>,
+}
+
+/// Reads a varint as reftable writes them, which is the same as the offsets of
+/// pack deltas: seven bits a byte, most significant first, with one added to
+/// each continued group so that no number has two encodings
+fn get_varint(data: &[u8], pos: &mut usize) -> Result<u64, ReftableError> {
+    let corrupt = || ReftableError::Corrupt("truncated varint");
+    let mut byte = *data.get(*pos).ok_or_else(corrupt)?;
+    *pos += 1;
+    let mut value = u64::from(byte & 0x7f);
+    while byte & 0x80 != 0 {
+        byte = *data.get(*pos).ok_or_else(corrupt)?;
+        *pos += 1;
+        value = ((value + 1) << 7) | u64::from(byte & 0x7f);
+    }
+    Ok(value)
+}
+
+/// Writes a varint, see `get_varint`
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    let mut buf = [0; 10];
+    let mut pos = buf.len() - 1;
+    buf[pos] = (value & 0x7f) as u8;
+    value >>= 7;
+    while value != 0 {
+        value -= 1;
+        pos -= 1;
+        buf[pos] = 0x80 | (value & 0x7f) as u8;
+        value >>= 7;
+    }
+    out.extend_from_slice(&buf[pos..]);
+}
+
+fn get_uint(data: &[u8], at: usize, len: usize) -> Result<u64, ReftableError> {
+    let bytes = data
+        .get(at..at + len)
+        .ok_or(ReftableError::Corrupt("truncated"))?;
+    Ok(bytes.iter().fold(0, |acc, &b| acc << 8 | u64::from(b)))
+}
+
+fn get_id(data: &[u8], pos: &mut usize) -> Result<Id, ReftableError> {
+    let bytes = data
+        .get(*pos..*pos + 20)
+        .ok_or(ReftableError::Corrupt("truncated object ID"))?;
+    let mut id = [0; 20];
+    id.copy_from_slice(bytes);
+    *pos += 20;
+    Ok(Id::from_bytes(id))
+}
+
+/// Reads the ref records of a block, which span `records`, appending them to
+/// `refs`
+fn read_ref_block(
+    data: &[u8],
+    records: std::ops::Range<usize>,
+    min_update_index: u64,
+    refs: &mut Vec<RefRecord>,
+) -> Result<(), ReftableError> {
+    let mut pos = records.start;
+    let mut last_name: Vec<u8> = Vec::new();
+    while pos < records.end {
+        let prefix_len = get_varint(data, &mut pos)? as usize;
+        let suffix_and_type = get_varint(data, &mut pos)?;
+        let suffix_len = (suffix_and_type >> 3) as usize;
+        if prefix_len > last_name.len() || pos + suffix_len > records.end {
+            return Err(ReftableError::Corrupt("bad ref name"));
+        }
+        let mut name = last_name[..prefix_len].to_vec();
+        name.extend_from_slice(&data[pos..pos + suffix_len]);
+        pos += suffix_len;
+
+        let update_index = min_update_index + get_varint(data, &mut pos)?;
+        let value = match suffix_and_type & 0x7 {
+            0 => RefValue::Deleted,
+            1 => RefValue::Id(get_id(data, &mut pos)?),
+            2 => RefValue::Peeled(get_id(data, &mut pos)?, get_id(data, &mut pos)?),
+            3 => {
+                let len = get_varint(data, &mut pos)? as usize;
+                let target = data
+                    .get(pos..pos + len)
+                    .ok_or(ReftableError::Corrupt("truncated symref"))?;
+                pos += len;
+                RefValue::Symref(String::from_utf8_lossy(target).into_owned())
+            }
+            _ => return Err(ReftableError::Corrupt("unknown ref value type")),
+        };
+        refs.push(RefRecord {
+            name: String::from_utf8_lossy(&name).into_owned(),
+            update_index,
+            value,
+        });
+        last_name = name;
+    }
+    Ok(())
+}
+
+/// Reads the refs out of a reftable file
+pub fn read_table(data: &[u8]) -> Result<Table, ReftableError> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        return Err(ReftableError::BadMagic);
+    }
+    let header_len = match data[4] {
+        1 => HEADER_LEN,
+        2 => {
+            let hash = data.get(24..28).unwrap_or_default();
+            if hash != b"sha1" {
+                return Err(ReftableError::UnsupportedHash(
+                    String::from_utf8_lossy(hash).into_owned(),
+                ));
+            }
+            HEADER_LEN + HASH_ID_LEN
+        }
+        version => return Err(ReftableError::UnsupportedVersion(version)),
+    };
+    let footer_len = FOOTER_LEN + header_len - HEADER_LEN;
+    if data.len() < header_len + footer_len {
+        return Err(ReftableError::Corrupt("too short for a footer"));
+    }
+    let min_update_index = get_uint(data, 8, 8)?;
+    let max_update_index = get_uint(data, 16, 8)?;
+
+    // ref blocks come first, and end where something else starts
+    let footer = data.len() - footer_len;
+    let mut end = footer;
+    let ref_index = get_uint(data, footer + header_len, 8)? as usize;
+    let obj = (get_uint(data, footer + header_len + 8, 8)? >> 5) as usize;
+    let log = get_uint(data, footer + header_len + 24, 8)? as usize;
+    for &start in &[ref_index, obj, log] {
+        if start != 0 && start < end {
+            end = start;
+        }
+    }
+
+    let mut refs = Vec::new();
+    let mut block = 0;
+    let mut pos = header_len;
+    while pos < end && data[pos] == REF_BLOCK {
+        // the first block's length includes the file header
+        let block_len = get_uint(data, pos + 1, 3)? as usize;
+        let block_end = block + block_len;
+        if block_end > end || block_end < pos + 4 + 2 {
+            return Err(ReftableError::Corrupt("bad block length"));
+        }
+        let restarts = get_uint(data, block_end - 2, 2)? as usize;
+        let records_end = block_end
+            .checked_sub(2 + restarts * 3)
+            .filter(|&e| e >= pos + 4)
+            .ok_or(ReftableError::Corrupt("bad restart count"))?;
+        read_ref_block(data, pos + 4..records_end, min_update_index, &mut refs)?;
+
+        // blocks may be padded out to the block size with zeroes
+        block = block_end;
+        while block < end && data[block] == 0 {
+            block += 1;
+        }
+        pos = block;
+    }
+    Ok(Table {
+        min_update_index,
+        max_update_index,
+        refs,
+    })
+}
+
+/// Encodes a ref record, sharing as much of its name as it can with the
+/// record before it
+fn encode_ref(record: &RefRecord, last_name: &[u8], min_update_index: u64) -> Vec<u8> {
+    let name = record.name.as_bytes();
+    let prefix = name
+        .iter()
+        .zip(last_name)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let value_type = match record.value {
+        RefValue::Deleted => 0,
+        RefValue::Id(_) => 1,
+        RefValue::Peeled(..) => 2,
+        RefValue::Symref(_) => 3,
+    };
+
+    let mut out = Vec::new();
+    put_varint(&mut out, prefix as u64);
+    put_varint(&mut out, ((name.len() - prefix) << 3 | value_type) as u64);
+    out.extend_from_slice(&name[prefix..]);
+    put_varint(&mut out, record.update_index - min_update_index);
+    match &record.value {
+        RefValue::Deleted => (),
+        RefValue::Id(id) => out.extend_from_slice(id.as_bytes()),
+        RefValue::Peeled(id, peeled) => {
+            out.extend_from_slice(id.as_bytes());
+            out.extend_from_slice(peeled.as_bytes());
+        }
+        RefValue::Symref(target) => {
+            put_varint(&mut out, target.len() as u64);
+            out.extend_from_slice(target.as_bytes());
+        }
+    }
+    out
+}
+
+/// Appends a ref block to `out`. `block_start` is where the block starts,
+/// which for the first block is before the file header.
+fn finish_block(out: &mut Vec<u8>, block_start: usize, records: &[u8], restarts: &[usize]) {
+    let len = out.len() - block_start + 4 + records.len() + restarts.len() * 3 + 2;
+    out.push(REF_BLOCK);
+    out.extend_from_slice(&(len as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(records);
+    for restart in restarts {
+        out.extend_from_slice(&(*restart as u32).to_be_bytes()[1..]);
+    }
+    out.extend_from_slice(&(restarts.len() as u16).to_be_bytes());
+}
+
+/// Writes a version 1 reftable. The refs must be sorted by name.
+pub fn write_table(table: &Table) -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(1);
+    header.extend_from_slice(&(BLOCK_SIZE as u32).to_be_bytes()[1..]);
+    header.extend_from_slice(&table.min_update_index.to_be_bytes());
+    header.extend_from_slice(&table.max_update_index.to_be_bytes());
+
+    let mut out = header.clone();
+    let mut block_start = 0;
+    let mut records = Vec::new();
+    let mut restarts = Vec::new();
+    let mut count = 0;
+    let mut last_name: &[u8] = b"";
+    for record in &table.refs {
+        // offsets in a block count from its start
+        let records_start = out.len() - block_start + 4;
+        let mut restart = count % RESTART_INTERVAL == 0;
+        let mut encoded = encode_ref(
+            record,
+            if restart { b"" } else { last_name },
+            table.min_update_index,
+        );
+        let used = records_start
+            + records.len()
+            + encoded.len()
+            + (restarts.len() + restart as usize) * 3
+            + 2;
+        if used > BLOCK_SIZE && count > 0 {
+            finish_block(&mut out, block_start, &records, &restarts);
+            let padded = (block_start + BLOCK_SIZE).max(out.len());
+            out.resize(padded, 0);
+            block_start = out.len();
+            records.clear();
+            restarts.clear();
+            count = 0;
+            restart = true;
+            encoded = encode_ref(record, b"", table.min_update_index);
+        }
+        if restart {
+            restarts.push(out.len() - block_start + 4 + records.len());
+        }
+        records.extend_from_slice(&encoded);
+        count += 1;
+        last_name = record.name.as_bytes();
+    }
+    // padding only goes between blocks, so the last one is left short
+    if count > 0 {
+        finish_block(&mut out, block_start, &records, &restarts);
+    }
+
+    // the footer repeats the header, then says where the indexes and logs
+    // are, of which there are none
+    let footer_start = out.len();
+    out.extend_from_slice(&header);
+    out.extend_from_slice(&[0; 40]);
+    let mut crc = Crc::new();
+    crc.update(&out[footer_start..]);
+    out.extend_from_slice(&crc.sum().to_be_bytes());
+    out
+}
+
+/// A table in the stack, with the name of its file
+#[derive(Debug)]
+struct StackEntry {
+    name: String,
+    /// Size of the file, for deciding what to merge
+    size: usize,
+    table: Table,
+}
+
+/// The stack of reftables holding a repository's refs
+pub struct Stack<'a> {
+    repo: &'a Repo,
+    tables: Vec<StackEntry>,
+}
+
+impl<'a> Stack<'a> {
+    fn dir(repo: &Repo) -> PathBuf {
+        repo.root.join("reftable")
+    }
+
+    /// Reads the stack of a repository. A repository with no
+    /// `reftable/tables.list` has no refs yet.
+    pub fn open(repo: &'a Repo) -> Result<Stack<'a>> {
+        let vfs = repo.vfs();
+        let dir = Stack::dir(repo);
+        let list = match vfs.read(&dir.join("tables.list")) {
+            Ok(list) => list,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut tables = Vec::new();
+        for name in String::from_utf8_lossy(&list).lines() {
+            let name = name.trim();
+            if name.is_empty() {
+                continue;
+            }
+            let data = vfs
+                .read(&dir.join(name))
+                .with_context(|| format!("failed to read reftable {}", name))?;
+            let table = read_table(&data).with_context(|| format!("in reftable {}", name))?;
+            tables.push(StackEntry {
+                name: name.to_owned(),
+                size: data.len(),
+                table,
+            });
+        }
+        Ok(Stack { repo, tables })
+    }
+
+    /// The update index the next update gets
+    fn next_update_index(&self) -> u64 {
+        self.tables
+            .last()
+            .map_or(1, |entry| entry.table.max_update_index + 1)
+    }
+
+    /// Merges the records of the tables from `start` on, newest winning.
+    /// Deletions are kept unless there are no older tables for them to hide
+    /// refs in.
+    fn merged(&self, start: usize) -> Vec<RefRecord> {
+        let mut refs: BTreeMap<&str, &RefRecord> = BTreeMap::new();
+        for entry in &self.tables[start..] {
+            for record in &entry.table.refs {
+                refs.insert(&record.name, record);
+            }
+        }
+        refs.values()
+            .filter(|record| start > 0 || record.value != RefValue::Deleted)
+            .map(|&record| record.clone())
+            .collect()
+    }
+
+    /// Finds the value of a ref, which is never `RefValue::Deleted`
+    pub fn read(&self, name: &str) -> Option<RefValue> {
+        self.tables
+            .iter()
+            .rev()
+            .find_map(|entry| {
+                entry
+                    .table
+                    .refs
+                    .binary_search_by(|record| record.name.as_str().cmp(name))
+                    .ok()
+                    .map(|n| entry.table.refs[n].value.clone())
+            })
+            .filter(|value| *value != RefValue::Deleted)
+    }
+
+    /// Lists every ref, sorted by name
+    pub fn refs(&self) -> Vec<(String, RefValue)> {
+        self.merged(0)
+            .into_iter()
+            .map(|record| (record.name, record.value))
+            .collect()
+    }
+
+    /// Writes a table file, returning its name and size
+    fn write_table_file(&self, table: &Table) -> Result<(String, usize)> {
+        let vfs = self.repo.vfs();
+        let dir = Stack::dir(self.repo);
+        vfs.create_dir_all(&dir)?;
+        let nanos = util::now().timestamp_subsec_nanos();
+        let name = format!(
+            "0x{:012x}-0x{:012x}-{:08x}.ref",
+            table.min_update_index,
+            table.max_update_index,
+            nanos ^ util::process_id()
+        );
+        let data = write_table(table);
+        let temp = self
+            .repo
+            .temp_file(dir.join(format!("tmp_table_{}", util::process_id())));
+        vfs.write(temp.path(), &data)?;
+        vfs.rename(temp.path(), &dir.join(&name))?;
+        temp.keep();
+        Ok((name, data.len()))
+    }
+
+    /// Replaces `tables.list` with the tables now in the stack
+    fn write_list(&self) -> Result<()> {
+        let mut list = String::new();
+        for entry in &self.tables {
+            list.push_str(&entry.name);
+            list.push('\n');
+        }
+        self.repo
+            .replace_file(&Stack::dir(self.repo).join("tables.list"), list.as_bytes())
+    }
+
+    /// Sets refs, or deletes them with `RefValue::Deleted`, in one update
+    pub fn add(&mut self, updates: &[(String, RefValue)]) -> Result<()> {
+        let update_index = self.next_update_index();
+        let mut refs: Vec<RefRecord> = updates
+            .iter()
+            .map(|(name, value)| RefRecord {
+                name: name.clone(),
+                update_index,
+                value: value.clone(),
+            })
+            .collect();
+        refs.sort_by(|a, b| a.name.cmp(&b.name));
+        // the last update of a ref wins
+        refs.reverse();
+        refs.dedup_by(|a, b| a.name == b.name);
+        refs.reverse();
+
+        let table = Table {
+            min_update_index: update_index,
+            max_update_index: update_index,
+            refs,
+        };
+        let (name, size) = self.write_table_file(&table)?;
+        self.tables.push(StackEntry { name, size, table });
+        self.write_list()?;
+        self.auto_compact()
+    }
+
+    /// Merges the tables from `start` on into one
+    fn compact_from(&mut self, start: usize) -> Result<()> {
+        let table = Table {
+            min_update_index: self.tables[start].table.min_update_index,
+            max_update_index: self.tables.last().unwrap().table.max_update_index,
+            refs: self.merged(start),
+        };
+        let (name, size) = self.write_table_file(&table)?;
+        let old = self
+            .tables
+            .split_off(start)
+            .into_iter()
+            .map(|entry| entry.name);
+        self.tables.push(StackEntry { name, size, table });
+        self.write_list()?;
+
+        let dir = Stack::dir(self.repo);
+        for name in old {
+            // anyone reading the stack as it was may still have these open,
+            // which is fine on unix and harmless to fail at elsewhere
+            if let Err(e) = self.repo.vfs().remove_file(&dir.join(&name)) {
+                warn!("failed to remove old reftable {}: {}", name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges tables at the top of the stack until each table is at least
+    /// twice the size of the one above it, which keeps the stack logarithmic
+    /// in the number of updates
+    fn auto_compact(&mut self) -> Result<()> {
+        let mut start = self.tables.len();
+        let mut above = 0;
+        while start > 0 {
+            let size = self.tables[start - 1].size;
+            if start < self.tables.len() && size >= 2 * above {
+                break;
+            }
+            above += size;
+            start -= 1;
+        }
+        if self.tables.len() - start < 2 {
+            return Ok(());
+        }
+        self.compact_from(start)
+    }
+
+    /// Merges the whole stack into one table, dropping deletions
+    pub fn compact(&mut self) -> Result<()> {
+        let compacted = match &self.tables[..] {
+            [] => true,
+            [only] => only.table.refs.iter().all(|r| r.value != RefValue::Deleted),
+            _ => false,
+        };
+        if compacted {
+            return Ok(());
+        }
+        self.compact_from(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_varint, put_varint, read_table, write_table, RefRecord, RefValue, Stack, Table,
+    };
+    use crate::objects::{Id, Repo};
+    use std::path::Path;
+
+    fn id(n: u8) -> Id {
+        Id::from_bytes([n; 20])
+    }
+
+    #[test]
+    fn test_varint() {
+        for &value in &[0, 1, 127, 128, 255, 16511, 16512, 1 << 40, u64::max_value()] {
+            let mut out = Vec::new();
+            put_varint(&mut out, value);
+            let mut pos = 0;
+            assert_eq!(get_varint(&out, &mut pos), Ok(value));
+            assert_eq!(pos, out.len());
+        }
+        let mut out = Vec::new();
+        put_varint(&mut out, 128);
+        assert_eq!(out, [0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_table() {
+        // enough refs to need several blocks
+        let mut refs: Vec<RefRecord> = (0..500)
+            .map(|n| RefRecord {
+                name: format!("refs/heads/branch-{:04}", n),
+                update_index: 3 + n % 2,
+                value: RefValue::Id(id(n as u8)),
+            })
+            .collect();
+        refs.insert(
+            0,
+            RefRecord {
+                name: "HEAD".to_string(),
+                update_index: 3,
+                value: RefValue::Symref("refs/heads/branch-0000".to_string()),
+            },
+        );
+        refs.push(RefRecord {
+            name: "refs/tags/v1".to_string(),
+            update_index: 4,
+            value: RefValue::Peeled(id(1), id(2)),
+        });
+        refs.push(RefRecord {
+            name: "refs/tags/v2".to_string(),
+            update_index: 4,
+            value: RefValue::Deleted,
+        });
+        let table = Table {
+            min_update_index: 3,
+            max_update_index: 4,
+            refs,
+        };
+
+        let data = write_table(&table);
+        assert_eq!(&data[..5], b"REFT\x01");
+        assert!(data.len() > 2 * 4096);
+        assert_eq!(data[4096], b'r');
+        assert_eq!(read_table(&data), Ok(table));
+
+        let empty = Table {
+            min_update_index: 1,
+            max_update_index: 1,
+            refs: Vec::new(),
+        };
+        assert_eq!(write_table(&empty).len(), 24 + 68);
+        assert_eq!(read_table(&write_table(&empty)), Ok(empty));
+        assert!(read_table(b"not a reftable at all").is_err());
+    }
+
+    #[test]
+    fn test_stack() {
+        let repo = Repo::in_memory();
+
+        let mut stack = Stack::open(&repo).unwrap();
+        assert_eq!(stack.read("HEAD"), None);
+        let head = (
+            "HEAD".to_string(),
+            RefValue::Symref("refs/heads/master".to_string()),
+        );
+        stack.add(&[head]).unwrap();
+        for n in 0..20 {
+            let name = format!("refs/heads/b{}", n % 4);
+            stack.add(&[(name, RefValue::Id(id(n)))]).unwrap();
+        }
+        stack
+            .add(&[("refs/heads/b0".to_string(), RefValue::Deleted)])
+            .unwrap();
+
+        // merging keeps the stack short
+        let stack = Stack::open(&repo).unwrap();
+        assert!(stack.tables.len() < 8, "{} tables", stack.tables.len());
+        assert_eq!(stack.tables.last().unwrap().table.max_update_index, 22);
+        assert_eq!(stack.read("refs/heads/b0"), None);
+        assert_eq!(stack.read("refs/heads/b1"), Some(RefValue::Id(id(17))));
+        let names: Vec<String> = stack.refs().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(
+            names,
+            ["HEAD", "refs/heads/b1", "refs/heads/b2", "refs/heads/b3"]
+        );
+
+        let mut stack = stack;
+        stack.compact().unwrap();
+        let reftable_dir = Path::new("/repo/.git/reftable");
+        // the list and one table
+        assert_eq!(repo.vfs().read_dir(reftable_dir).unwrap().len(), 2);
+        let stack = Stack::open(&repo).unwrap();
+        assert_eq!(stack.tables.len(), 1);
+        assert!(stack.tables[0]
+            .table
+            .refs
+            .iter()
+            .all(|r| r.value != RefValue::Deleted));
+        assert_eq!(stack.refs().len(), 4);
+    }
+}
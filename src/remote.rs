@@ -0,0 +1,227 @@
+//! Hooks that let library consumers follow and steer remote operations such as
+//! fetch, push and clone, in the spirit of libgit2's RemoteCallbacks. Nothing
+//! in this module prints: anything a user should see is handed to a callback.
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+/// Errors that callbacks can cause a remote operation to fail with
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CallbackError {
+    /// A progress callback asked for the operation to stop
+    #[error("Operation cancelled by callback")]
+    Cancelled,
+
+    /// Authentication was required but no credentials were provided
+    #[error("No credentials available for {0}")]
+    NoCredentials(String),
+
+    /// The server's certificate was rejected
+    #[error("Certificate for {0} was rejected")]
+    CertificateRejected(String),
+}
+
+/// Statistics on a pack being transferred
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TransferProgress {
+    /// Number of objects in the pack, once known
+    pub total_objects: usize,
+    /// Objects received so far
+    pub received_objects: usize,
+    /// Objects written to the database so far
+    pub indexed_objects: usize,
+    /// Bytes of pack data received so far
+    pub received_bytes: u64,
+}
+
+/// Credentials to authenticate to a remote with
+#[derive(Clone, PartialEq, Eq)]
+pub enum Credential {
+    /// Plain username and password (or token) as used by HTTP basic auth
+    UserPass {
+        /// User name
+        username: String,
+        /// Password or access token
+        password: String,
+    },
+}
+
+/// A server certificate presented to the certificate check callback
+#[derive(Debug)]
+pub struct Certificate<'a> {
+    /// Host the certificate was presented for
+    pub host: &'a str,
+    /// DER encoded certificate
+    pub data: &'a [u8],
+    /// Whether the transport's own verification accepted it
+    pub valid: bool,
+}
+
+type ProgressFn<'a> = dyn FnMut(&str) -> bool + Send + 'a;
+type CredentialsFn = dyn FnMut(&str, Option<&str>) -> Option<Credential> + Send;
+type CertificateFn = dyn FnMut(&Certificate) -> bool + Send;
+type TransferFn<'a> = dyn FnMut(&TransferProgress) -> bool + Send + 'a;
+
+/// Callbacks accepted by fetch, push and clone. Every callback is optional;
+/// the defaults stay quiet, never authenticate and trust the transport's
+/// certificate verification.
+#[derive(Default)]
+pub struct Callbacks<'a> {
+    progress: Option<Box<ProgressFn<'a>>>,
+    connection: ConnectionCallbacks,
+    transfer_progress: Option<Box<TransferFn<'a>>>,
+}
+
+/// The callbacks a connection keeps for as long as it is open, rather than
+/// only while it is being set up: over HTTP, any request can be the one the
+/// server wants credentials for, and each one makes a new TLS connection.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionCallbacks {
+    credentials: Option<Arc<Mutex<CredentialsFn>>>,
+    certificate_check: Option<Arc<Mutex<CertificateFn>>>,
+}
+
+impl ConnectionCallbacks {
+    /// Gets credentials for a URL
+    pub fn get_credentials(
+        &self,
+        url: &str,
+        username: Option<&str>,
+    ) -> Result<Credential, CallbackError> {
+        self.credentials
+            .as_ref()
+            .and_then(|f| (f.lock().expect("credentials callback panicked"))(url, username))
+            .ok_or_else(|| CallbackError::NoCredentials(url.to_owned()))
+    }
+
+    /// Checks whether a certificate should be trusted
+    pub fn check_certificate(&self, cert: &Certificate) -> Result<(), CallbackError> {
+        let accepted = match &self.certificate_check {
+            Some(f) => (f.lock().expect("certificate check callback panicked"))(cert),
+            None => cert.valid,
+        };
+        if accepted {
+            Ok(())
+        } else {
+            Err(CallbackError::CertificateRejected(cert.host.to_owned()))
+        }
+    }
+}
+
+impl<'a> Callbacks<'a> {
+    /// Makes a set of callbacks that all do the default thing
+    pub fn new() -> Callbacks<'a> {
+        Default::default()
+    }
+
+    /// Receives progress text sent by the remote (e.g. "Counting objects").
+    /// Return false to cancel the operation.
+    pub fn progress(&mut self, f: impl FnMut(&str) -> bool + Send + 'a) -> &mut Self {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Asked for credentials for a URL, with the username from the URL if it
+    /// had one. Return None to give up. This can be asked for as long as the
+    /// connection is open, so unlike the other callbacks it can't borrow.
+    pub fn credentials(
+        &mut self,
+        f: impl FnMut(&str, Option<&str>) -> Option<Credential> + Send + 'static,
+    ) -> &mut Self {
+        self.connection.credentials = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    /// Decides whether to trust a server certificate, given whether the
+    /// transport's own verification accepted it. Return true to connect.
+    /// Like `credentials`, this can't borrow.
+    pub fn certificate_check(
+        &mut self,
+        f: impl FnMut(&Certificate) -> bool + Send + 'static,
+    ) -> &mut Self {
+        self.connection.certificate_check = Some(Arc::new(Mutex::new(f)));
+        self
+    }
+
+    /// Receives pack transfer statistics as objects arrive. Return false to
+    /// cancel the operation.
+    pub fn transfer_progress(
+        &mut self,
+        f: impl FnMut(&TransferProgress) -> bool + Send + 'a,
+    ) -> &mut Self {
+        self.transfer_progress = Some(Box::new(f));
+        self
+    }
+
+    /// Reports progress text from the remote
+    pub fn report_progress(&mut self, msg: &str) -> Result<(), CallbackError> {
+        if let Some(f) = &mut self.progress {
+            if !f(msg) {
+                return Err(CallbackError::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports pack transfer statistics
+    pub fn report_transfer(&mut self, stats: &TransferProgress) -> Result<(), CallbackError> {
+        if let Some(f) = &mut self.transfer_progress {
+            if !f(stats) {
+                return Err(CallbackError::Cancelled);
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets credentials for a URL
+    pub fn get_credentials(
+        &mut self,
+        url: &str,
+        username: Option<&str>,
+    ) -> Result<Credential, CallbackError> {
+        self.connection.get_credentials(url, username)
+    }
+
+    /// Checks whether a certificate should be trusted
+    pub fn check_certificate(&mut self, cert: &Certificate) -> Result<(), CallbackError> {
+        self.connection.check_certificate(cert)
+    }
+
+    /// The callbacks for a connection to keep
+    pub(crate) fn connection(&self) -> ConnectionCallbacks {
+        self.connection.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CallbackError, Callbacks, Certificate, TransferProgress};
+
+    #[test]
+    fn test_callbacks() {
+        let mut seen = Vec::new();
+        {
+            let mut cbs = Callbacks::new();
+            cbs.transfer_progress(|stats| {
+                seen.push(stats.received_objects);
+                stats.received_objects < 2
+            });
+
+            let mut stats = TransferProgress::default();
+            stats.received_objects = 1;
+            assert_eq!(cbs.report_transfer(&stats), Ok(()));
+            stats.received_objects = 2;
+            assert_eq!(cbs.report_transfer(&stats), Err(CallbackError::Cancelled));
+
+            // defaults
+            assert_eq!(cbs.report_progress("Counting objects"), Ok(()));
+            assert!(cbs.get_credentials("https://example.com", None).is_err());
+            let cert = Certificate {
+                host: "example.com",
+                data: b"",
+                valid: false,
+            };
+            assert!(cbs.check_certificate(&cert).is_err());
+        }
+        assert_eq!(seen, vec![1, 2]);
+    }
+}
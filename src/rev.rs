@@ -1,12 +1,20 @@
 //! An implementation of git rev-parse
-use std::fs;
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset};
+use regex::RegexBuilder;
 use thiserror::Error;
 
-use crate::objects::{Id, Repo};
-use crate::util::GitPath;
+use crate::date::approxidate;
+use crate::merge;
+use crate::objects::{Id, Object, ObjectType, Repo, Tag};
+use crate::reflog::{self, ReflogEntry};
+use crate::reftable::{RefValue, Stack};
+use crate::revwalk::RevWalk;
+use crate::tree;
+use crate::util::{self, GitPath};
+use crate::vfs::{FileType, Vfs};
 
 /// Errors that can be encountered while working with revs
 #[derive(Debug, Error)]
@@ -23,6 +31,72 @@ pub enum RevError {
     /// `man git-check-ref-format`
     #[error("Invalid rev {0}")]
     Invalid(PathBuf),
+
+    /// There is no reflog for the ref
+    #[error("No reflog for {0}")]
+    NoReflog(String),
+
+    /// `<ref>@{<n>}` goes back further than the reflog does
+    #[error("Log for {0} only has {1} entries")]
+    ReflogTooShort(String, usize),
+
+    /// No commit has a message matching `:/<text>`
+    #[error("No commit message matches {0}")]
+    NoMessageMatch(String),
+
+    /// `<rev>:<path>` names a path that isn't in the rev's tree
+    #[error("Path '{1}' does not exist in '{0}'")]
+    NoSuchPath(String, String),
+
+    /// `extensions.refStorage` names a format we don't know
+    #[error("Unknown ref storage format {0}")]
+    UnknownRefStorage(String),
+}
+
+/// How many symrefs deep a ref may be
+const MAX_SYMREF_DEPTH: usize = 16;
+
+/// Where a repository keeps its refs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefStorage {
+    /// A file for each ref under refs/, and `packed-refs`
+    Files,
+    /// A stack of reftables under reftable/, see [`crate::reftable`]
+    Reftable,
+}
+
+/// Finds how a repository stores its refs, from `extensions.refStorage`
+pub fn ref_storage(repo: &Repo) -> Result<RefStorage> {
+    match repo.config()?.get("extensions.refStorage") {
+        None | Some("files") => Ok(RefStorage::Files),
+        Some("reftable") => Ok(RefStorage::Reftable),
+        Some(other) => Err(RevError::UnknownRefStorage(other.to_owned()).into()),
+    }
+}
+
+/// The refs that `name` could be short for, in the order they are tried
+fn dwim_refnames(name: &str) -> Vec<String> {
+    vec![
+        name.to_owned(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ]
+}
+
+/// Finds the object a ref in a reftable stack points to, following symrefs
+fn resolve_in_stack(stack: &Stack<'_>, name: &str) -> Option<Id> {
+    let mut name = name.to_owned();
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match stack.read(&name)? {
+            RefValue::Id(id) | RefValue::Peeled(id, _) => return Some(id),
+            RefValue::Symref(target) => name = target,
+            RefValue::Deleted => return None,
+        }
+    }
+    None
 }
 
 /// check if a given string *could* be a SHA1
@@ -33,6 +107,133 @@ fn is_valid_sha1(s: &str) -> bool {
         && s.chars().all(|c| c.is_ascii_hexdigit())
 }
 
+/// Finds the object whose ID starts with the given hex digits, if there is
+/// exactly one
+fn find_abbrev(abbrev: &str, repo: &Repo) -> Result<Option<Id>> {
+    let abbrev = abbrev.to_ascii_lowercase();
+    // the smallest ID that could start with the abbreviation
+    let lowest = Id::from(&format!("{:0<40}", abbrev)).expect("abbreviation is not hex");
+
+    let ids = repo.all_ids()?;
+    let start = ids
+        .binary_search_by(|id| id.as_bytes().cmp(lowest.as_bytes()))
+        .unwrap_or_else(|pos| pos);
+    let mut matches = ids[start..]
+        .iter()
+        .take(2)
+        .filter(|id| id.to_string().starts_with(&abbrev));
+    match (matches.next(), matches.next()) {
+        (Some(_), Some(_)) => Err(RevError::Ambiguous(abbrev).into()),
+        (found, _) => Ok(found.copied()),
+    }
+}
+
+/// Counts how many hex digits two IDs have in common at the start
+fn common_hex_digits(a: &Id, b: &Id) -> usize {
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(n) if a[n] >> 4 == b[n] >> 4 => n * 2 + 1,
+        Some(n) => n * 2,
+        None => 40,
+    }
+}
+
+/// Works out how many hex digits of `id` are needed to refer to it without
+/// ambiguity, using at least `min_len`
+pub fn abbrev_len(id: &Id, min_len: usize, repo: &Repo) -> Result<usize> {
+    let ids = repo.all_ids()?;
+    let pos = ids.binary_search_by(|other| other.as_bytes().cmp(id.as_bytes()));
+    // only the IDs either side of this one in sorted order can share a longer
+    // prefix with it than any other
+    let (before, after) = match pos {
+        Ok(pos) => (pos.checked_sub(1), pos + 1),
+        Err(pos) => (pos.checked_sub(1), pos),
+    };
+    let shared = before
+        .into_iter()
+        .chain(Some(after))
+        .filter_map(|n| ids.get(n))
+        .map(|other| common_hex_digits(id, other))
+        .max()
+        .unwrap_or(0);
+    Ok((shared + 1).max(min_len).min(40))
+}
+
+/// What HEAD points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Head {
+    /// A commit, either directly or through a branch
+    Commit(Id),
+    /// A branch with no commits yet, as in a new repository. The first commit
+    /// creates it.
+    Unborn(String),
+}
+
+/// Finds what HEAD points to, telling a branch that doesn't exist yet apart
+/// from a HEAD that is broken
+pub fn read_head(repo: &Repo) -> Result<Head> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        let stack = Stack::open(repo)?;
+        return match stack.read("HEAD") {
+            Some(RefValue::Symref(target)) => match resolve_in_stack(&stack, &target) {
+                Some(id) => Ok(Head::Commit(id)),
+                None if stack.read(&target).is_none() => Ok(Head::Unborn(target)),
+                None => Err(RevError::Dangling("HEAD".to_owned()).into()),
+            },
+            Some(RefValue::Id(id)) | Some(RefValue::Peeled(id, _)) => Ok(Head::Commit(id)),
+            _ => Err(RevError::Dangling("HEAD".to_owned()).into()),
+        };
+    }
+
+    let vfs = repo.vfs();
+    let head_path = repo.root.join("HEAD");
+    match parse_id_from(&head_path, vfs) {
+        Some(RevParseResult::Id(id)) => Ok(Head::Commit(id)),
+        Some(RevParseResult::Symref(target)) => match find_refname(&target, repo)? {
+            Some(id) => Ok(Head::Commit(id)),
+            None if !vfs.exists(&repo.root.join(&target)) => Ok(Head::Unborn(target)),
+            None => Err(RevError::Dangling("HEAD".to_owned()).into()),
+        },
+        // old versions of git made HEAD a symlink to the branch
+        None => match vfs.symlink_metadata(&head_path) {
+            Ok(meta) if meta.file_type == FileType::Symlink && !vfs.exists(&head_path) => {
+                let target = vfs.read_link(&head_path)?;
+                Ok(Head::Unborn(target.to_string_lossy().into_owned()))
+            }
+            _ => Err(RevError::Dangling("HEAD".to_owned()).into()),
+        },
+    }
+}
+
+/// Finds the branch HEAD points to, such as `refs/heads/master`, which may
+/// not exist yet. Returns None if HEAD is detached.
+pub fn head_branch(repo: &Repo) -> Result<Option<String>> {
+    read_symref("HEAD", repo)
+}
+
+/// Finds the ref a symref such as HEAD points to, which may not exist yet.
+/// Returns None if `name` is a ref to an ID instead.
+pub fn read_symref(name: &str, repo: &Repo) -> Result<Option<String>> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        return match Stack::open(repo)?.read(name) {
+            Some(RefValue::Symref(target)) => Ok(Some(target)),
+            Some(_) => Ok(None),
+            None => Err(RevError::Dangling(name.to_owned()).into()),
+        };
+    }
+    let path = repo.root.join(name);
+    match parse_id_from(&path, repo.vfs()) {
+        Some(RevParseResult::Symref(target)) => Ok(Some(target)),
+        Some(RevParseResult::Id(_)) => Ok(None),
+        // old versions of git made HEAD a symlink to the branch
+        None => match repo.vfs().read_link(&path) {
+            Ok(target) => Ok(Some(target.to_string_lossy().into_owned())),
+            Err(_) => Err(RevError::Dangling(name.to_owned()).into()),
+        },
+    }
+}
+
 /// Parsing a rev file can either produce a symref pointer or an Id
 enum RevParseResult {
     Symref(String),
@@ -41,9 +242,9 @@ enum RevParseResult {
 
 /// Parse an Id from the given path. Caller should verify that symrefs *only*
 /// occur in HEAD.
-fn parse_id_from(path: &Path) -> Option<RevParseResult> {
+fn parse_id_from(path: &Path, vfs: &dyn Vfs) -> Option<RevParseResult> {
     // we choose to steamroll through *all* OS errors and just return None
-    let read = fs::read(path).ok()?;
+    let read = vfs.read(path).ok()?;
     let read = String::from_utf8(read).ok()?;
     if let Some(target) = read.strip_prefix("ref:") {
         // symref
@@ -53,8 +254,9 @@ fn parse_id_from(path: &Path) -> Option<RevParseResult> {
     }
 }
 
-/// Checks if a refname is valid. See `man git-check-ref-format`.
-fn is_valid_refname(name: &str, allow_onelevel: bool) -> bool {
+/// Checks if a refname is valid. See `man git-check-ref-format`. Names with one
+/// component, such as `HEAD`, are only valid if `allow_onelevel` is set.
+pub fn is_valid_refname(name: &str, allow_onelevel: bool) -> bool {
     // cannot be @
     if name == "@" {
         return false;
@@ -93,6 +295,30 @@ fn is_valid_refname(name: &str, allow_onelevel: bool) -> bool {
     true
 }
 
+/// Checks if a ref can be written where its name says in the git directory,
+/// like git's `refname_is_safe`: it has to be under refs/, or be all capitals
+/// and underscores like `HEAD` and `ORIG_HEAD`. Other names with one
+/// component, such as `config` or `index`, are files git keeps there.
+fn is_safe_refname(name: &str) -> bool {
+    if name.starts_with("refs/") {
+        return is_valid_refname(name, false);
+    }
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+}
+
+/// Tidies up a refname the way `git check-ref-format --normalize` does, by
+/// taking out leading slashes and runs of slashes. It may still be invalid.
+pub fn normalize_refname(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for ch in name.chars() {
+        if ch == '/' && (normalized.is_empty() || normalized.ends_with('/')) {
+            continue;
+        }
+        normalized.push(ch);
+    }
+    normalized
+}
+
 #[derive(Debug, Error)]
 enum FollowSymlinkError {
     /// Too many symlinks were followed resolving this ref
@@ -112,18 +338,16 @@ enum FollowSymlinkError {
 /// Follows symlink refs with targets of the format refs/... and return the ref
 /// we finally hit. The resulting ref may not exist. Returns a PathBuf or a
 /// RevError. Please give this function a .git-relative path.
-fn follow_symlink_refs(
-    p: &Path,
-    dotgit: &Path,
-) -> std::result::Result<PathBuf, FollowSymlinkError> {
-    const MAX_DEPTH: usize = 16;
+fn follow_symlink_refs(p: &Path, repo: &Repo) -> std::result::Result<PathBuf, FollowSymlinkError> {
+    let dotgit = &repo.root;
+    let vfs = repo.vfs();
 
     assert!(
         !p.starts_with(dotgit),
         "follow_symlink_refs given an absolute path"
     );
     let mut path = p.to_owned();
-    for depth in 0..MAX_DEPTH {
+    for depth in 0..MAX_SYMREF_DEPTH {
         let absolute = dotgit.join(&path);
         let stringified = path
             .to_git_path()
@@ -138,37 +362,45 @@ fn follow_symlink_refs(
 
         // if the path does not exist but is otherwise valid, we can still write
         // there
-        if !absolute.exists() {
+        if !vfs.exists(&absolute) {
             return Ok(path);
         }
 
         // allow errors in getting the path metadata to halt evaluation
-        let typ = absolute
-            .symlink_metadata()
+        let typ = vfs
+            .symlink_metadata(&absolute)
             .map_err(|e| FollowSymlinkError::IOError(e))?
-            .file_type();
+            .file_type;
 
-        if !typ.is_symlink() {
+        if typ != FileType::Symlink {
             return Ok(path);
         }
 
-        path = absolute
-            .read_link()
+        path = vfs
+            .read_link(&absolute)
             .map_err(|e| FollowSymlinkError::IOError(e))?;
     }
     Err(FollowSymlinkError::DepthExceeded(p.to_owned()).into())
 }
 
 /// Updates the given reference to the new value. Follows symrefs in HEAD.
-pub fn update_ref(target_ref: &Path, new_id: &Id, dotgit: &Path) -> Result<()> {
+/// The update is added to the reflog of the ref with `message`, and to
+/// HEAD's too if HEAD points to the ref.
+pub fn update_ref(target_ref: &Path, new_id: &Id, message: &str, repo: &Repo) -> Result<()> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        return update_reftable_ref(target_ref, new_id, repo);
+    }
+    let dotgit = &repo.root;
+    let vfs = repo.vfs();
+
     // handle symrefs in HEAD
     let target_ref = if target_ref == Path::new("HEAD") {
         let head_path = dotgit.join("HEAD");
-        let head_is_linkref = head_path.symlink_metadata()?.file_type().is_symlink();
+        let head_is_linkref = vfs.symlink_metadata(&head_path)?.file_type == FileType::Symlink;
         if head_is_linkref {
-            follow_symlink_refs(&head_path, dotgit)?
+            follow_symlink_refs(target_ref, repo)?
         } else {
-            match parse_id_from(&head_path) {
+            match parse_id_from(&head_path, vfs) {
                 // if there is an id in HEAD, we can continue to overwrite it
                 Some(RevParseResult::Id(_)) => target_ref.to_owned(),
                 // *however* if HEAD points to another reference, we should try
@@ -190,6 +422,7 @@ pub fn update_ref(target_ref: &Path, new_id: &Id, dotgit: &Path) -> Result<()> {
         return Err(RevError::Invalid(target_ref.clone()).into());
     }
 
+    let packed = read_packed_refs(repo);
     let try_paths = [
         ("", ""),
         ("refs/", ""),
@@ -208,7 +441,10 @@ pub fn update_ref(target_ref: &Path, new_id: &Id, dotgit: &Path) -> Result<()> {
             relative.push(after);
         }
         let absolute = dotgit.join(&relative);
-        if !absolute.exists() {
+        if !relative.to_str().map_or(false, is_safe_refname) {
+            continue;
+        }
+        if !vfs.exists(&absolute) && !packed.iter().any(|r| Path::new(&r.name) == relative) {
             continue;
         }
 
@@ -217,46 +453,293 @@ pub fn update_ref(target_ref: &Path, new_id: &Id, dotgit: &Path) -> Result<()> {
 
         // follow link refs, may just get us p again. It is also acceptable if
         // the target does not exist here.
-        let target = follow_symlink_refs(&relative, dotgit)?;
-        // TODO: safe replacement of the file
+        let target = follow_symlink_refs(&relative, repo)?;
+        let old = ref_value(&target, repo);
         debug!("overwriting reference, writing to {}", absolute.display());
-        fs::write(dotgit.join(target), format!("{}", new_id))?;
-        return Ok(());
+        repo.replace_file(&dotgit.join(&target), format!("{}\n", new_id).as_bytes())?;
+        return log_update(&target, old, new_id, message, repo);
     }
     // if we fail to find somewhere to put the ref, assume it is new and
     // goes in .git.
-    let absolute = dotgit.join(target_ref);
+    if !is_safe_refname(stringified) {
+        return Err(RevError::Invalid(target_ref.clone()).into());
+    }
+    let absolute = dotgit.join(&target_ref);
     debug!("new reference, writing to {}", absolute.display());
-    fs::write(&absolute, format!("{}", new_id))?;
+    if let Some(parent) = absolute.parent() {
+        // refs/tags and friends aren't made by init
+        vfs.create_dir_all(parent)?;
+    }
+    repo.replace_file(&absolute, format!("{}\n", new_id).as_bytes())?;
+    log_update(&target_ref, None, new_id, message, repo)
+}
+
+/// The ID a ref, given by its full name, points to, if it exists
+fn ref_value(name: &Path, repo: &Repo) -> Option<Id> {
+    find_refname(name.to_str()?, repo).ok().flatten()
+}
+
+/// Adds an update of a loose ref to its reflog, and to HEAD's if HEAD points
+/// to it
+fn log_update(name: &Path, old: Option<Id>, new: &Id, message: &str, repo: &Repo) -> Result<()> {
+    let name = name
+        .to_str()
+        .ok_or_else(|| RevError::Invalid(name.to_owned()))?;
+    reflog::append(name, old, new, message, repo)?;
+    if name != "HEAD" && head_branch(repo)?.as_deref() == Some(name) {
+        reflog::append("HEAD", old, new, message, repo)?;
+    }
+    Ok(())
+}
+
+/// Deletes the ref with the full name `name`, along with its reflog. A packed
+/// ref is taken out of `packed-refs` too, and with reftables a deletion is
+/// added to the stack. Deleting a ref that doesn't exist does nothing.
+pub fn delete_ref(name: &str, repo: &Repo) -> Result<()> {
+    if !is_safe_refname(name) {
+        return Err(RevError::Invalid(PathBuf::from(name)).into());
+    }
+    if ref_storage(repo)? == RefStorage::Reftable {
+        return Stack::open(repo)?.add(&[(name.to_owned(), RefValue::Deleted)]);
+    }
+
+    let vfs = repo.vfs();
+    let path = repo.root.join(name);
+    if vfs.exists(&path) {
+        vfs.remove_file(&path)?;
+    }
+    reflog::remove(name, repo)?;
+    if read_packed_refs(repo)
+        .iter()
+        .any(|packed| packed.name == name)
+    {
+        let packed_path = repo.root.join("packed-refs");
+        let content = vfs.read(&packed_path)?;
+        let mut kept = String::new();
+        let mut deleting = false;
+        for line in String::from_utf8_lossy(&content).lines() {
+            // the peeled line after a tag goes with it
+            if !line.starts_with('^') {
+                deleting = line.splitn(2, ' ').nth(1).map(str::trim) == Some(name);
+            }
+            if !deleting {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        repo.replace_file(&packed_path, kept.as_bytes())?;
+    }
     Ok(())
 }
 
+/// Points HEAD at a branch, such as `refs/heads/master`, which needn't exist
+/// yet. The next commit then creates it. If it does exist, the move is added
+/// to HEAD's reflog with `message`.
+pub fn set_head_ref(refname: &str, message: &str, repo: &Repo) -> Result<()> {
+    let old = ref_value(Path::new("HEAD"), repo);
+    set_symref("HEAD", refname, repo)?;
+    match ref_value(Path::new(refname), repo) {
+        Some(new) => reflog::append("HEAD", old, &new, message, repo),
+        None => Ok(()),
+    }
+}
+
+/// Makes `name` a symref to `refname`, such as `refs/heads/master`, which
+/// needn't exist yet
+pub fn set_symref(name: &str, refname: &str, repo: &Repo) -> Result<()> {
+    if !is_safe_refname(name) {
+        return Err(RevError::Invalid(PathBuf::from(name)).into());
+    }
+    if !refname.starts_with("refs/") || !is_valid_refname(refname, false) {
+        return Err(RevError::Invalid(PathBuf::from(refname)).into());
+    }
+    if ref_storage(repo)? == RefStorage::Reftable {
+        return Stack::open(repo)?.add(&[(name.to_owned(), RefValue::Symref(refname.to_owned()))]);
+    }
+    let path = repo.root.join(name);
+    if let Some(parent) = path.parent() {
+        repo.vfs().create_dir_all(parent)?;
+    }
+    repo.replace_file(&path, format!("ref: {}\n", refname).as_bytes())
+}
+
+/// Points HEAD straight at a commit rather than at a branch, adding the move
+/// to HEAD's reflog with `message`
+pub fn detach_head(id: &Id, message: &str, repo: &Repo) -> Result<()> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        return Stack::open(repo)?.add(&[("HEAD".to_owned(), RefValue::Id(*id))]);
+    }
+    let old = ref_value(Path::new("HEAD"), repo);
+    repo.replace_file(&repo.root.join("HEAD"), format!("{}\n", id).as_bytes())?;
+    reflog::append("HEAD", old, id, message, repo)
+}
+
+/// Updates a ref in a repository that keeps its refs in reftables. Symrefs,
+/// such as HEAD pointing at a branch, are followed.
+fn update_reftable_ref(target_ref: &Path, new_id: &Id, repo: &Repo) -> Result<()> {
+    let name = target_ref
+        .to_str()
+        .filter(|name| is_valid_refname(name, true))
+        .ok_or_else(|| RevError::Invalid(target_ref.to_owned()))?;
+
+    let mut stack = Stack::open(repo)?;
+    // a ref that doesn't exist yet is made with the name as given
+    let mut target = dwim_refnames(name)
+        .into_iter()
+        .find(|candidate| stack.read(candidate).is_some())
+        .unwrap_or_else(|| name.to_owned());
+    for _ in 0..MAX_SYMREF_DEPTH {
+        match stack.read(&target) {
+            Some(RefValue::Symref(next)) => target = next,
+            _ => break,
+        }
+    }
+    debug!("updating {} in reftable", target);
+    stack.add(&[(target, RefValue::Id(*new_id))])
+}
+
+/// A ref in `packed-refs`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PackedRef {
+    name: String,
+    id: Id,
+    /// What an annotated tag finally points to, from the `^` line after it
+    peeled: Option<Id>,
+}
+
+/// Reads the refs in `packed-refs`, where `pack-refs` puts refs so there
+/// needn't be a file for each. A loose ref file overrides its packed value.
+/// Unreadable lines are skipped, and so are peeled values that don't follow
+/// a ref.
+fn read_packed_refs(repo: &Repo) -> Vec<PackedRef> {
+    let content = match repo.vfs().read(&repo.root.join("packed-refs")) {
+        Ok(content) => content,
+        Err(_) => return Vec::new(),
+    };
+    let mut refs: Vec<PackedRef> = Vec::new();
+    for line in String::from_utf8_lossy(&content).lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        if let Some(peeled) = line.strip_prefix('^') {
+            if let (Some(last), Some(id)) = (refs.last_mut(), Id::from(peeled.trim())) {
+                last.peeled = Some(id);
+            }
+            continue;
+        }
+        let mut parts = line.splitn(2, ' ');
+        let id = match parts.next().and_then(Id::from) {
+            Some(id) => id,
+            None => continue,
+        };
+        if let Some(name) = parts.next() {
+            refs.push(PackedRef {
+                name: name.trim().to_owned(),
+                id,
+                peeled: None,
+            });
+        }
+    }
+    refs
+}
+
+/// Moves the refs under refs/ into `packed-refs`, removing the loose files.
+/// Unless `all` is set, only tags and refs that are already packed are, as
+/// branches tend to move again soon. With reftables, the stack is merged into
+/// one table instead. Returns how many refs were packed.
+pub fn pack_refs(all: bool, repo: &Repo) -> Result<usize> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        let mut stack = Stack::open(repo)?;
+        stack.compact()?;
+        return Ok(stack.refs().len());
+    }
+
+    let vfs = repo.vfs();
+    let packed = read_packed_refs(repo);
+    let refs: Vec<_> = list_refs(repo)?
+        .into_iter()
+        .filter(|(name, _)| {
+            all || name.starts_with("refs/tags/") || packed.iter().any(|r| r.name == *name)
+        })
+        .collect();
+    // fully-peeled promises a peeled line after every annotated tag, so
+    // readers needn't look at the objects of the others
+    let mut content = String::from("# pack-refs with: peeled fully-peeled sorted \n");
+    for (name, id) in &refs {
+        content.push_str(&format!("{} {}\n", id, name));
+        let is_tag = repo
+            .object_info(id)
+            .map_or(false, |info| info.kind == ObjectType::Tag);
+        if is_tag {
+            content.push_str(&format!("^{}\n", peel_tags(*id, repo)?));
+        }
+    }
+    repo.replace_file(&repo.root.join("packed-refs"), content.as_bytes())?;
+
+    for (name, id) in &refs {
+        let path = repo.root.join(name);
+        // symlinked refs stay where they are, and so do refs that changed
+        // since they were listed
+        let is_file = vfs
+            .symlink_metadata(&path)
+            .map_or(false, |meta| meta.file_type == FileType::File);
+        if is_file {
+            if let Some(RevParseResult::Id(current)) = parse_id_from(&path, vfs) {
+                if current == *id {
+                    vfs.remove_file(&path)?;
+                }
+            }
+        }
+    }
+    Ok(refs.len())
+}
+
 /// Find the value of a refname in the .git directory
-fn find_refname(rev: &str, dotgit: &Path) -> Option<Id> {
+fn find_refname(rev: &str, repo: &Repo) -> Result<Option<Id>> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        let stack = Stack::open(repo)?;
+        return Ok(dwim_refnames(rev)
+            .iter()
+            .find_map(|name| resolve_in_stack(&stack, name)));
+    }
+
     // TODO: verify the rev name to ensure it doesn't have evil in it (see
     // `man git-check-ref-format`). Function implemented for this. Also should follow
     // symlinks properly.
     trace!("finding ref: {}", rev);
+    let dotgit = &repo.root;
     let try_paths = ["", "refs", "refs/tags", "refs/heads", "refs/remotes"];
+    let packed = read_packed_refs(repo);
     for &path in try_paths.iter() {
         let mut p = dotgit.join(path);
         p.push(rev);
         trace!("=> trying {}", &p.display());
 
-        return match parse_id_from(&p) {
+        let found = match parse_id_from(&p, repo.vfs()) {
             Some(RevParseResult::Id(id)) => Some(id),
             Some(RevParseResult::Symref(symref)) => {
                 // Symrefs are invalid in any cases except if the rev is HEAD
                 // This prevents infinite loops.
                 if rev == "HEAD" {
                     trace!("=> found symref to {}", &symref);
-                    find_refname(&symref, dotgit)
+                    find_refname(&symref, repo)?
                 } else {
                     None
                 }
             }
-            None => continue,
+            None => {
+                let name = if path.is_empty() {
+                    rev.to_owned()
+                } else {
+                    format!("{}/{}", path, rev)
+                };
+                match packed.iter().find(|packed| packed.name == name) {
+                    Some(packed) => Some(packed.id),
+                    None => continue,
+                }
+            }
         };
+        return Ok(found);
     }
 
     // special case: refs/remotes/<refname>/HEAD
@@ -264,52 +747,112 @@ fn find_refname(rev: &str, dotgit: &Path) -> Option<Id> {
     p.push(rev);
     p.push("HEAD");
     // This can't be a refname since it is not HEAD
-    match parse_id_from(&p) {
-        Some(RevParseResult::Id(id)) => Some(id),
-        _ => None,
+    match parse_id_from(&p, repo.vfs()) {
+        Some(RevParseResult::Id(id)) => Ok(Some(id)),
+        _ => Ok(None),
+    }
+}
+
+/// Lists all the refs under refs/ with the ids they point to, sorted by name
+pub fn list_refs(repo: &Repo) -> Result<Vec<(String, Id)>> {
+    if ref_storage(repo)? == RefStorage::Reftable {
+        let refs = Stack::open(repo)?
+            .refs()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("refs/"))
+            .filter_map(|(name, value)| match value {
+                RefValue::Id(id) | RefValue::Peeled(id, _) => Some((name, id)),
+                _ => None,
+            })
+            .collect();
+        return Ok(refs);
     }
+
+    let mut refs = Vec::new();
+    list_refs_in("refs", repo, &mut refs)?;
+    for PackedRef { name, id, .. } in read_packed_refs(repo) {
+        if name.starts_with("refs/") && !refs.iter().any(|(loose, _)| *loose == name) {
+            refs.push((name, id));
+        }
+    }
+    refs.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(refs)
+}
+
+/// Recursively finds refs in the given .git-relative directory
+fn list_refs_in(dir: &str, repo: &Repo, refs: &mut Vec<(String, Id)>) -> Result<()> {
+    let vfs = repo.vfs();
+    let absolute = repo.root.join(dir);
+    if !vfs.is_dir(&absolute) {
+        return Ok(());
+    }
+
+    for name in vfs.read_dir(&absolute)? {
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => {
+                warn!("skipping ref with non UTF-8 name {:?} in {}", name, dir);
+                continue;
+            }
+        };
+        let refname = format!("{}/{}", dir, name);
+        let path = repo.root.join(&refname);
+
+        if vfs.is_dir(&path) {
+            list_refs_in(&refname, repo, refs)?;
+        } else if let Some(RevParseResult::Id(id)) = parse_id_from(&path, vfs) {
+            refs.push((refname, id));
+        }
+    }
+    Ok(())
 }
 
 /// Parse a revision identifier to attempt to find a unique id
 pub fn parse(rev: &str, repo: &Repo) -> Result<Id> {
-    if is_valid_sha1(rev) {
+    if rev.len() == 40 {
+        // a whole SHA1 can be looked up directly, without listing every object
+        if let Some(id) = Id::from(rev).filter(|id| repo.has_id(id)) {
+            return Ok(id);
+        }
+    } else if is_valid_sha1(rev) {
         // first, look for the SHA1 if it could be one
-        let objectsdir = repo.root.join("objects");
-        let firsttwodir = objectsdir.join(&rev[..=1]);
-        if firsttwodir.is_dir() {
-            // first two digits are present in the local objects directory
-
-            let mut candidate = None;
-            for file in firsttwodir.read_dir()? {
-                // name decode errors mean that the file definitely doesn't
-                // match the rev
-                let file = file?;
-                if file
-                    .file_name()
-                    .to_str()
-                    .map(|name| name.starts_with(&rev[2..]))
-                    .unwrap_or(false)
-                {
-                    // already found a candidate, so this must be ambiguous
-                    if candidate.is_some() {
-                        return Err(RevError::Ambiguous(rev.to_owned()).into());
-                    }
-                    // recreate the full hash
-                    candidate = Some(rev[..=1].to_owned() + file.file_name().to_str().unwrap());
-                }
-            }
-            if let Some(ret) = candidate {
-                // this is not a reasonably catchable error by consumers and is
-                // a case of invalid stuff happening with the repo
-                return Id::from(&ret).with_context(|| format!("invalid id filename {}", ret));
-            }
+        if let Some(id) = find_abbrev(rev, repo)? {
+            return Ok(id);
         }
     }
 
     // TODO: § <describeOutput> https://git-scm.com/docs/git-rev-parse
 
+    // :/<text>
+    if let Some(pattern) = rev.strip_prefix(":/") {
+        return find_by_message(pattern, repo)?
+            .ok_or_else(|| RevError::NoMessageMatch(pattern.to_owned()).into());
+    }
+
+    // <rev>:<path>, which comes first since the path could have anything in it
+    if let Some((tree_rev, path)) = split_tree_path(rev) {
+        let tree = peel_to_tree(parse(tree_rev, repo)?, repo)?;
+        return match tree::entry_at(&tree, path.as_bytes(), repo)? {
+            Some((id, _)) => Ok(id),
+            None => Err(RevError::NoSuchPath(tree_rev.to_owned(), path.to_owned()).into()),
+        };
+    }
+
+    // <rev>~<n> and <rev>^<n>, any number of them. Neither can be in a ref
+    // name, so the first one starts the suffixes.
+    if let Some(start) = rev.find(&['~', '^'][..]) {
+        if let Some(steps) = parse_ancestry(&rev[start..]) {
+            let mut id = peel_tags(parse(&rev[..start], repo)?, repo)?;
+            for (step, n) in steps {
+                id = ancestor(id, step, n, repo)?
+                    .ok_or_else(|| RevError::Dangling(rev.to_owned()))?;
+            }
+            return Ok(id);
+        }
+    }
+
     // <refname>
-    if let Some(id) = find_refname(rev, &repo.root) {
+    if let Some(id) = find_refname(rev, repo)? {
         return Ok(id);
     }
 
@@ -318,11 +861,809 @@ pub fn parse(rev: &str, repo: &Repo) -> Result<Id> {
         return repo.head();
     }
 
+    // <refname>@{<n>} and <refname>@{<date>}
+    if let Some((name, selector)) = split_reflog_rev(rev) {
+        let refname = reflog_refname(name, repo)?;
+        let entries = reflog::read(&refname, repo)?;
+        return match selector.parse::<usize>() {
+            Ok(n) => entries
+                .iter()
+                .rev()
+                .nth(n)
+                .map(|entry| entry.new)
+                .ok_or_else(|| RevError::ReflogTooShort(name.to_owned(), entries.len()).into()),
+            Err(_) if entries.is_empty() => Err(RevError::NoReflog(refname).into()),
+            Err(_) => {
+                let time = approxidate(selector, util::now())?;
+                Ok(reflog_value_at(&entries, time, &refname))
+            }
+        };
+    }
+
     Err(RevError::Dangling(rev.to_owned()).into())
 }
 
+/// Finds the youngest commit reachable from any ref whose message matches the
+/// regex `pattern`. As in git, `!-<pattern>` finds one that doesn't match,
+/// and a pattern that starts with `!` otherwise is written `!!`.
+fn find_by_message(pattern: &str, repo: &Repo) -> Result<Option<Id>> {
+    let (pattern, negate) = match pattern.strip_prefix('!') {
+        None => (pattern, false),
+        Some(rest) if rest.starts_with('!') => (rest, false),
+        Some(rest) if rest.starts_with('-') => (&rest[1..], true),
+        Some(_) => return Err(anyhow!("unknown :/! modifier in :/{}", pattern)),
+    };
+    // ^ and $ match at each line, as in git
+    let re = RegexBuilder::new(pattern).multi_line(true).build()?;
+
+    let mut tips = Vec::new();
+    let refs = list_refs(repo)?.into_iter().map(|(_, id)| id);
+    for id in repo.head().ok().into_iter().chain(refs) {
+        let id = peel_tags(id, repo)?;
+        if repo.object_info(&id)?.kind == ObjectType::Commit {
+            tips.push(id);
+        }
+    }
+    for id in RevWalk::new(repo, &tips)? {
+        let id = id?;
+        if let Object::Commit(commit) = &*repo.open_shared(&id)? {
+            if re.is_match(&commit.message) != negate {
+                return Ok(Some(id));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Splits `<rev>:<path>` at the first colon outside of a `@{<date>}`, which
+/// may have a time in it
+fn split_tree_path(rev: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in rev.char_indices() {
+        match c {
+            '{' if rev[..i].ends_with('@') => depth += 1,
+            '}' if depth > 0 => depth -= 1,
+            ':' if depth == 0 => return Some((&rev[..i], &rev[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Parses a run of `~<n>` and `^<n>` suffixes, where a missing n is 1
+fn parse_ancestry(suffixes: &str) -> Option<Vec<(char, usize)>> {
+    let mut steps = Vec::new();
+    let mut rest = suffixes;
+    while let Some(step) = rest.chars().next() {
+        if step != '~' && step != '^' {
+            return None;
+        }
+        rest = &rest[1..];
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = match &rest[..digits] {
+            "" => 1,
+            n => n.parse().ok()?,
+        };
+        steps.push((step, n));
+        rest = &rest[digits..];
+    }
+    Some(steps)
+}
+
+/// Takes a step of `~<n>` or `^<n>` from the commit `id`: the nth
+/// first-parent ancestor, or the nth parent. With n of 0, that's the commit
+/// itself. Returns None if there is no such commit.
+fn ancestor(id: Id, step: char, n: usize, repo: &Repo) -> Result<Option<Id>> {
+    let parents_of = |id: &Id| match repo.open(id)? {
+        Object::Commit(commit) => Ok(commit.parents),
+        _ => Err(anyhow!("{} is not a commit", id)),
+    };
+    let mut id = id;
+    let mut parents = parents_of(&id)?;
+    if step == '^' {
+        return Ok(if n == 0 {
+            Some(id)
+        } else {
+            parents.get(n - 1).copied()
+        });
+    }
+    for _ in 0..n {
+        id = match parents.first() {
+            Some(&parent) => parent,
+            None => return Ok(None),
+        };
+        parents = parents_of(&id)?;
+    }
+    Ok(Some(id))
+}
+
+/// Splits `<refname>@{<selector>}` into the ref and the selector, which is
+/// how many moves back to go or a date
+fn split_reflog_rev(rev: &str) -> Option<(&str, &str)> {
+    let at = rev.rfind("@{")?;
+    let selector = rev[at + 2..].strip_suffix('}')?;
+    Some((&rev[..at], selector))
+}
+
+/// Finds where a ref pointed at `time` from its reflog, which mustn't be
+/// empty. Before the reflog starts, that is where the oldest entry moved it
+/// from, or to if it was created then.
+fn reflog_value_at(entries: &[ReflogEntry], time: DateTime<FixedOffset>, refname: &str) -> Id {
+    // entries are added as time goes on, so the ones in effect by `time`
+    // come first
+    let (mut low, mut high) = (0, entries.len());
+    while low < high {
+        let mid = (low + high) / 2;
+        if entries[mid].who.time <= time {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    match low.checked_sub(1) {
+        Some(last) => entries[last].new,
+        None => {
+            let first = &entries[0];
+            warn!("log for {} only goes back to {}", refname, first.who.time);
+            if first.old == Id::from_bytes([0; 20]) {
+                first.new
+            } else {
+                first.old
+            }
+        }
+    }
+}
+
+/// Finds the full name of the ref whose reflog `name` means: the branch HEAD
+/// is on if `name` is empty, or else the first ref `name` could be short for
+/// that has a reflog
+pub fn reflog_refname(name: &str, repo: &Repo) -> Result<String> {
+    if name.is_empty() {
+        return Ok(head_branch(repo)?.unwrap_or_else(|| "HEAD".to_owned()));
+    }
+    if !is_valid_refname(name, true) {
+        return Err(RevError::Invalid(PathBuf::from(name)).into());
+    }
+    for candidate in dwim_refnames(name) {
+        // a directory such as logs/refs/heads isn't a reflog
+        if reflog::read(&candidate, repo).map_or(false, |entries| !entries.is_empty()) {
+            return Ok(candidate);
+        }
+    }
+    Err(RevError::NoReflog(name.to_owned()).into())
+}
+
+/// Follows annotated tags to what they tag
+pub fn peel_tags(mut id: Id, repo: &Repo) -> Result<Id> {
+    while repo.object_header(&id)?.0 == ObjectType::Tag {
+        id = Tag::load(&repo.read_object(&id)?.1)?.object;
+    }
+    Ok(id)
+}
+
+/// Finds the tree of a commit, following annotated tags to it. A tree is its
+/// own tree.
+fn peel_to_tree(id: Id, repo: &Repo) -> Result<Id> {
+    let id = peel_tags(id, repo)?;
+    match repo.object_info(&id)?.kind {
+        ObjectType::Tree => Ok(id),
+        ObjectType::Commit => Ok(repo.open(&id)?.commit().unwrap().tree),
+        kind => Err(anyhow!("{} is a {}, not a tree or commit", id, kind)),
+    }
+}
+
+/// Parses revisions as `log` and `rev-list` take them: a commit to start
+/// from, `^<rev>` to leave out everything reachable from a commit,
+/// `<a>..<b>` for the commits reachable from b but not a, or `<a>...<b>` for
+/// those reachable from either but not both, where either side of a range
+/// defaults to HEAD. Returns the commits to start from and those to hide,
+/// with annotated tags peeled to what they tag.
+pub fn parse_revs<S: AsRef<str>>(revs: &[S], repo: &Repo) -> Result<(Vec<Id>, Vec<Id>)> {
+    let parse = |rev: &str| peel_tags(parse(rev, repo)?, repo);
+    let parse_or_head = |rev: &str| parse(if rev.is_empty() { "HEAD" } else { rev });
+    let mut tips = Vec::new();
+    let mut hidden = Vec::new();
+    for rev in revs {
+        let rev = rev.as_ref();
+        if let Some(rev) = rev.strip_prefix('^') {
+            hidden.push(parse(rev)?);
+            continue;
+        }
+        let (from, to, symmetric) = match split_range(rev) {
+            Some(range) => range,
+            None => {
+                tips.push(parse(rev)?);
+                continue;
+            }
+        };
+        let (from, to) = match (parse_or_head(from), parse_or_head(to)) {
+            (Ok(from), Ok(to)) => (from, to),
+            // like git, something like HEAD:a..b that isn't a range is one
+            // revision
+            (Err(e), _) | (_, Err(e)) => {
+                tips.push(parse(rev).map_err(|_| e)?);
+                continue;
+            }
+        };
+        if symmetric {
+            // everything both can reach is behind their merge bases
+            hidden.extend(merge::merge_bases(&from, &to, repo)?);
+            tips.push(from);
+        } else {
+            hidden.push(from);
+        }
+        tips.push(to);
+    }
+    Ok((tips, hidden))
+}
+
+/// Splits `<a>..<b>` or `<a>...<b>` into its sides, and whether it has three
+/// dots
+fn split_range(rev: &str) -> Option<(&str, &str, bool)> {
+    let dots = rev.find("..")?;
+    match rev[dots + 2..].strip_prefix('.') {
+        Some(to) => Some((&rev[..dots], to, true)),
+        None => Some((&rev[..dots], &rev[dots + 2..], false)),
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::{Head, RefStorage};
+    use crate::objects::{
+        Blob, Commit, File, FileMode, Id, NameEntry, ObjectType, Repo, Tag, Tree,
+    };
+    use crate::reflog::ReflogEntry;
+    use crate::reftable::{RefValue, Stack};
+    use chrono::DateTime;
+    use std::path::Path;
+
+    #[test]
+    fn test_parse_in_memory_repo() {
+        let repo = Repo::in_memory();
+        let id = repo.store(&Blob::new(b"hello".to_vec())).unwrap();
+
+        // HEAD is a symref to a branch that doesn't exist yet
+        assert!(super::parse("HEAD", &repo).is_err());
+        assert_eq!(
+            super::read_head(&repo).unwrap(),
+            Head::Unborn("refs/heads/master".to_string())
+        );
+        repo.set_head(&id, "").unwrap();
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(id));
+        assert_eq!(super::parse("HEAD", &repo).unwrap(), id);
+        assert_eq!(super::parse("master", &repo).unwrap(), id);
+
+        // abbreviated ids are found in the objects directory
+        let short = format!("{}", id);
+        assert_eq!(super::parse(&short[..7], &repo).unwrap(), id);
+        assert_eq!(super::abbrev_len(&id, 4, &repo).unwrap(), 4);
+
+        super::update_ref(Path::new("refs/tags/v1"), &id, "", &repo).unwrap();
+        assert_eq!(
+            super::list_refs(&repo).unwrap(),
+            vec![
+                ("refs/heads/master".to_string(), id),
+                ("refs/tags/v1".to_string(), id)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_packed_refs() {
+        let repo = Repo::in_memory();
+        let a = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b".to_vec())).unwrap();
+        repo.set_head(&a, "").unwrap();
+        super::update_ref(Path::new("refs/tags/v1"), &a, "", &repo).unwrap();
+
+        // branches are left loose without `all`
+        assert_eq!(super::pack_refs(false, &repo).unwrap(), 1);
+        assert!(repo.vfs().exists(&repo.root.join("refs/heads/master")));
+        assert!(!repo.vfs().exists(&repo.root.join("refs/tags/v1")));
+        assert_eq!(super::parse("v1", &repo).unwrap(), a);
+        assert_eq!(super::list_refs(&repo).unwrap().len(), 2);
+
+        assert_eq!(super::pack_refs(true, &repo).unwrap(), 2);
+        assert!(!repo.vfs().exists(&repo.root.join("refs/heads/master")));
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(a));
+        assert_eq!(super::parse("v1", &repo).unwrap(), a);
+        assert_eq!(super::list_refs(&repo).unwrap().len(), 2);
+
+        // a branch that only exists packed isn't unborn
+        super::set_head_ref("refs/tags/v1", "", &repo).unwrap();
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(a));
+        super::set_head_ref("refs/heads/orphan", "", &repo).unwrap();
+        assert_eq!(
+            super::read_head(&repo).unwrap(),
+            Head::Unborn("refs/heads/orphan".to_string())
+        );
+        assert!(super::set_head_ref("master", "", &repo).is_err());
+        super::set_head_ref("refs/heads/master", "", &repo).unwrap();
+
+        // loose refs win over packed ones
+        super::update_ref(Path::new("master"), &b, "", &repo).unwrap();
+        // written the way git writes it
+        assert_eq!(
+            repo.vfs()
+                .read(&repo.root.join("refs/heads/master"))
+                .unwrap(),
+            format!("{}\n", b).into_bytes()
+        );
+        assert_eq!(super::parse("HEAD", &repo).unwrap(), b);
+        assert_eq!(
+            super::list_refs(&repo).unwrap(),
+            vec![
+                ("refs/heads/master".to_string(), b),
+                ("refs/tags/v1".to_string(), a)
+            ]
+        );
+
+        // a detached HEAD doesn't move the branch
+        assert_eq!(
+            super::head_branch(&repo).unwrap().as_deref(),
+            Some("refs/heads/master")
+        );
+        super::detach_head(&a, "", &repo).unwrap();
+        assert_eq!(super::head_branch(&repo).unwrap(), None);
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(a));
+        repo.set_head(&b, "").unwrap();
+        assert_eq!(super::parse("HEAD", &repo).unwrap(), b);
+        assert_eq!(super::parse("master", &repo).unwrap(), b);
+        super::detach_head(&a, "", &repo).unwrap();
+        assert_eq!(super::parse("master", &repo).unwrap(), b);
+
+        // other symrefs work like HEAD
+        super::set_symref("refs/remotes/origin/HEAD", "refs/heads/master", &repo).unwrap();
+        assert_eq!(
+            super::read_symref("refs/remotes/origin/HEAD", &repo)
+                .unwrap()
+                .as_deref(),
+            Some("refs/heads/master")
+        );
+        assert_eq!(
+            super::read_symref("refs/heads/master", &repo).unwrap(),
+            None
+        );
+        assert!(super::read_symref("refs/heads/nope", &repo).is_err());
+        assert!(super::set_symref("refs/heads/a..b", "refs/heads/master", &repo).is_err());
+        // files in the git directory can't be overwritten as refs
+        super::set_symref("ORIG_HEAD", "refs/heads/master", &repo).unwrap();
+        for name in &["config", "index", "packed-refs", "Head"] {
+            assert!(super::set_symref(name, "refs/heads/master", &repo).is_err());
+            assert!(super::update_ref(Path::new(name), &a, "", &repo).is_err());
+            assert!(super::delete_ref(name, &repo).is_err());
+        }
+
+        // deleting a ref takes out both its loose and packed values
+        super::delete_ref("refs/heads/master", &repo).unwrap();
+        assert!(!repo.vfs().exists(&repo.root.join("refs/heads/master")));
+        assert!(!repo.vfs().exists(&repo.root.join("logs/refs/heads/master")));
+        assert_eq!(
+            super::list_refs(&repo).unwrap(),
+            vec![("refs/tags/v1".to_string(), a)]
+        );
+        super::delete_ref("refs/heads/master", &repo).unwrap();
+    }
+
+    #[test]
+    fn test_packed_peeled_refs() {
+        let repo = Repo::in_memory();
+        let blob = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let tag = repo
+            .store(&Tag {
+                object: blob,
+                kind: ObjectType::Blob,
+                name: "v1".to_string(),
+                tagger: None,
+                message: "v1\n".to_string(),
+            })
+            .unwrap();
+
+        // as C git writes it
+        let packed = format!(
+            "# pack-refs with: peeled fully-peeled sorted \n\
+             {blob} refs/heads/master\n\
+             {tag} refs/tags/v1\n\
+             ^{blob}\n\
+             ^{blob}\n\
+             nonsense\n",
+            blob = blob,
+            tag = tag
+        );
+        let path = repo.root.join("packed-refs");
+        repo.vfs().write(&path, packed.as_bytes()).unwrap();
+        let expected = vec![
+            super::PackedRef {
+                name: "refs/heads/master".to_string(),
+                id: blob,
+                peeled: None,
+            },
+            super::PackedRef {
+                name: "refs/tags/v1".to_string(),
+                id: tag,
+                peeled: Some(blob),
+            },
+        ];
+        assert_eq!(super::read_packed_refs(&repo), expected);
+        assert_eq!(super::parse("v1", &repo).unwrap(), tag);
+        assert_eq!(super::parse("refs/tags/v1", &repo).unwrap(), tag);
+        assert_eq!(super::parse("HEAD", &repo).unwrap(), blob);
+
+        // and reads back the same after packing again
+        assert_eq!(super::pack_refs(true, &repo).unwrap(), 2);
+        assert_eq!(super::read_packed_refs(&repo), expected);
+        let content = repo.vfs().read(&path).unwrap();
+        assert!(String::from_utf8(content)
+            .unwrap()
+            .contains(&format!("{} refs/tags/v1\n^{}\n", tag, blob)));
+    }
+
+    #[test]
+    fn test_reflog_revs() {
+        let repo = Repo::in_memory();
+        let a = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b".to_vec())).unwrap();
+        let c = repo.store(&Blob::new(b"c".to_vec())).unwrap();
+        repo.set_head(&a, "a").unwrap();
+        repo.set_head(&b, "b").unwrap();
+        super::update_ref(Path::new("refs/heads/other"), &c, "c", &repo).unwrap();
+        super::detach_head(&c, "c", &repo).unwrap();
+
+        assert_eq!(super::parse("HEAD@{0}", &repo).unwrap(), c);
+        assert_eq!(super::parse("HEAD@{1}", &repo).unwrap(), b);
+        assert_eq!(super::parse("master@{1}", &repo).unwrap(), a);
+        assert_eq!(super::parse("refs/heads/master@{0}", &repo).unwrap(), b);
+        assert_eq!(super::parse("other@{0}", &repo).unwrap(), c);
+        // with HEAD detached, it's HEAD's own log
+        assert_eq!(super::parse("@{2}", &repo).unwrap(), a);
+        super::set_head_ref("refs/heads/master", "back", &repo).unwrap();
+        assert_eq!(super::parse("@{1}", &repo).unwrap(), a);
+
+        let error = |rev: &str| super::parse(rev, &repo).unwrap_err().to_string();
+        assert_eq!(error("master@{2}"), "Log for master only has 2 entries");
+        assert_eq!(error("nope@{0}"), "No reflog for nope");
+        assert!(super::parse("master@{not a date}", &repo).is_err());
+
+        // the reflog was all written just now
+        assert_eq!(super::parse("master@{now}", &repo).unwrap(), b);
+        assert_eq!(super::parse("master@{yesterday}", &repo).unwrap(), a);
+        assert_eq!(super::parse("HEAD@{1 week ago}", &repo).unwrap(), a);
+    }
+
+    #[test]
+    fn test_reflog_value_at() {
+        let id = |n: u8| Id::from_bytes([n; 20]);
+        let entry = |old: Id, new: Id, time: &str| ReflogEntry {
+            old,
+            new,
+            who: NameEntry::from(&format!("A U Thor <author@example.com> {} +0000", time)).unwrap(),
+            message: String::new(),
+        };
+        let time = |s: &str| DateTime::parse_from_str(&format!("{} +0000", s), "%s %z").unwrap();
+        let entries = vec![
+            entry(id(0), id(1), "100"),
+            entry(id(1), id(2), "200"),
+            entry(id(2), id(3), "200"),
+            entry(id(3), id(4), "300"),
+        ];
+        let at = |t: &str| super::reflog_value_at(&entries, time(t), "refs/heads/master");
+        assert_eq!(at("50"), id(1));
+        assert_eq!(at("100"), id(1));
+        assert_eq!(at("150"), id(1));
+        assert_eq!(at("200"), id(3));
+        assert_eq!(at("299"), id(3));
+        assert_eq!(at("1000"), id(4));
+        // a ref that existed before its reflog starts
+        assert_eq!(
+            super::reflog_value_at(&entries[1..], time("50"), "refs/heads/master"),
+            id(1)
+        );
+    }
+
+    #[test]
+    fn test_ancestry() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let tree = repo.store(&Tree { files: Vec::new() }).unwrap();
+        let commit = |parents: Vec<Id>, message: &str| {
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who.clone(),
+                message: message.to_string(),
+            })
+            .unwrap()
+        };
+        // root - a - merge - c
+        //     \- b -/
+        let root = commit(vec![], "root\n");
+        let a = commit(vec![root], "a\n");
+        let b = commit(vec![root], "b\n");
+        let merge = commit(vec![a, b], "merge\n");
+        let c = commit(vec![merge], "c\n");
+        repo.set_head(&c, "").unwrap();
+        let tag = repo
+            .store(&Tag {
+                object: c,
+                kind: ObjectType::Commit,
+                name: "v1".to_string(),
+                tagger: None,
+                message: "v1\n".to_string(),
+            })
+            .unwrap();
+        super::update_ref(Path::new("refs/tags/v1"), &tag, "", &repo).unwrap();
+
+        let parse = |rev: &str| super::parse(rev, &repo).unwrap();
+        assert_eq!(parse("HEAD~"), merge);
+        assert_eq!(parse("HEAD^"), merge);
+        assert_eq!(parse("HEAD~2"), a);
+        assert_eq!(parse("master~3"), root);
+        assert_eq!(parse("HEAD^^2"), b);
+        assert_eq!(parse("HEAD~1^2~1"), root);
+        assert_eq!(parse("@~~"), a);
+        assert_eq!(parse("HEAD^0"), c);
+        assert_eq!(parse("HEAD~0"), c);
+        // tags are peeled to the commit first
+        assert_eq!(parse("v1^0"), c);
+        assert_eq!(parse("v1~1"), merge);
+        let abbrev = c.to_string();
+        assert_eq!(parse(&format!("{}^", &abbrev[..7])), merge);
+
+        let error = |rev: &str| super::parse(rev, &repo).unwrap_err().to_string();
+        assert_eq!(error("HEAD~4"), "Failed to find value of rev HEAD~4");
+        assert_eq!(error("HEAD^2"), "Failed to find value of rev HEAD^2");
+        assert_eq!(error("HEAD^^3"), "Failed to find value of rev HEAD^^3");
+        assert!(super::parse("HEAD~x", &repo).is_err());
+        assert!(super::parse(&format!("{}^", tree), &repo).is_err());
+    }
+
+    #[test]
+    fn test_message_search() {
+        let repo = Repo::in_memory();
+        let tree = repo.store(&Tree { files: Vec::new() }).unwrap();
+        let commit = |parents: Vec<Id>, message: &str, time: i64| {
+            let who = NameEntry::from(&format!("a <a@example.com> {} +0000", time)).unwrap();
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: message.to_string(),
+            })
+            .unwrap()
+        };
+        let root = commit(vec![], "root\n", 1);
+        let one = commit(vec![root], "fix bug one\n", 2);
+        let two = commit(vec![one], "fix bug two\n", 4);
+        let three = commit(vec![one], "!important\n", 3);
+        repo.set_head(&three, "").unwrap();
+        super::update_ref(Path::new("refs/heads/side"), &two, "", &repo).unwrap();
+
+        let parse = |rev: &str| super::parse(rev, &repo).unwrap();
+        // the youngest from any ref, not just HEAD
+        assert_eq!(parse(":/fix bug"), two);
+        assert_eq!(parse(":/one$"), one);
+        assert_eq!(parse(":/!-fix"), three);
+        assert_eq!(parse(":/!!imp"), three);
+        assert_eq!(parse(":/"), two);
+
+        let error = |rev: &str| super::parse(rev, &repo).unwrap_err().to_string();
+        assert_eq!(error(":/nothing"), "No commit message matches nothing");
+        assert!(super::parse(":/!x", &repo).is_err());
+        assert!(super::parse(":/(", &repo).is_err());
+    }
+
+    #[test]
+    fn test_tree_paths() {
+        let repo = Repo::in_memory();
+        let one = repo.store(&Blob::new(b"1\n".to_vec())).unwrap();
+        let two = repo.store(&Blob::new(b"2\n".to_vec())).unwrap();
+        let file = |name: &str, mode, id| File {
+            mode,
+            name: name.into(),
+            id,
+        };
+        let sub = repo
+            .store(&Tree {
+                files: vec![file("a~b:c", FileMode::Regular, two)],
+            })
+            .unwrap();
+        let tree = repo
+            .store(&Tree {
+                files: vec![
+                    file("dir", FileMode::Directory, sub),
+                    file("one", FileMode::Regular, one),
+                ],
+            })
+            .unwrap();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let commit = repo
+            .store(&Commit {
+                tree,
+                parents: vec![],
+                author: who.clone(),
+                committer: who,
+                message: "a\n".to_string(),
+            })
+            .unwrap();
+        repo.set_head(&commit, "").unwrap();
+        let tag = repo
+            .store(&Tag {
+                object: commit,
+                kind: ObjectType::Commit,
+                name: "v1".to_string(),
+                tagger: None,
+                message: "v1\n".to_string(),
+            })
+            .unwrap();
+        super::update_ref(Path::new("refs/tags/v1"), &tag, "", &repo).unwrap();
+
+        let parse = |rev: &str| super::parse(rev, &repo).unwrap();
+        assert_eq!(parse("HEAD:one"), one);
+        assert_eq!(parse("master:dir"), sub);
+        assert_eq!(parse("v1:dir/a~b:c"), two);
+        assert_eq!(parse("HEAD:"), tree);
+        assert_eq!(parse("HEAD@{0}:one"), one);
+        assert_eq!(parse(&format!("{}:dir/", tree)), sub);
+
+        let error = |rev: &str| super::parse(rev, &repo).unwrap_err().to_string();
+        assert_eq!(error("HEAD:nope"), "Path 'nope' does not exist in 'HEAD'");
+        assert_eq!(
+            error("HEAD:one/two"),
+            "Path 'one/two' does not exist in 'HEAD'"
+        );
+        assert!(super::parse(&format!("{}:one", one), &repo).is_err());
+        assert_eq!(super::split_tree_path("HEAD"), None);
+        assert_eq!(
+            super::split_tree_path("master@{2020-04-08 17:00}:a:b"),
+            Some(("master@{2020-04-08 17:00}", "a:b"))
+        );
+    }
+
+    #[test]
+    fn test_reftable_refs() {
+        let repo = Repo::in_memory();
+        repo.vfs()
+            .write(
+                &repo.root.join("config"),
+                b"[core]\n\trepositoryformatversion = 1\n[extensions]\n\trefStorage = reftable\n",
+            )
+            .unwrap();
+        assert_eq!(super::ref_storage(&repo).unwrap(), RefStorage::Reftable);
+        // git keeps HEAD in the tables, leaving a HEAD file that points nowhere
+        Stack::open(&repo)
+            .unwrap()
+            .add(&[(
+                "HEAD".to_string(),
+                RefValue::Symref("refs/heads/master".to_string()),
+            )])
+            .unwrap();
+        let a = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b".to_vec())).unwrap();
+
+        assert_eq!(
+            super::read_head(&repo).unwrap(),
+            Head::Unborn("refs/heads/master".to_string())
+        );
+        repo.set_head(&a, "").unwrap();
+        assert!(!repo.vfs().exists(&repo.root.join("refs/heads/master")));
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(a));
+        assert_eq!(super::parse("master", &repo).unwrap(), a);
+
+        super::update_ref(Path::new("refs/tags/v1"), &a, "", &repo).unwrap();
+        super::update_ref(Path::new("v1"), &b, "", &repo).unwrap();
+        assert_eq!(super::parse("v1", &repo).unwrap(), b);
+        assert_eq!(
+            super::list_refs(&repo).unwrap(),
+            vec![
+                ("refs/heads/master".to_string(), a),
+                ("refs/tags/v1".to_string(), b)
+            ]
+        );
+        assert_eq!(super::pack_refs(true, &repo).unwrap(), 3);
+        assert_eq!(super::parse("HEAD", &repo).unwrap(), a);
+        super::delete_ref("refs/tags/v1", &repo).unwrap();
+        assert!(super::parse("v1", &repo).is_err());
+
+        super::set_head_ref("refs/heads/pages", "", &repo).unwrap();
+        assert_eq!(
+            super::read_head(&repo).unwrap(),
+            Head::Unborn("refs/heads/pages".to_string())
+        );
+        super::detach_head(&b, "", &repo).unwrap();
+        assert_eq!(super::read_head(&repo).unwrap(), Head::Commit(b));
+    }
+
+    #[test]
+    fn test_parse_revs() {
+        let repo = Repo::in_memory();
+        let a = repo.store(&Blob::new(b"a".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"b".to_vec())).unwrap();
+        repo.set_head(&b, "").unwrap();
+        super::update_ref(Path::new("refs/tags/a"), &a, "", &repo).unwrap();
+
+        let parse = |revs: &[&str]| super::parse_revs(revs, &repo).unwrap();
+        assert_eq!(parse(&["master", "^a"]), (vec![b], vec![a]));
+        assert_eq!(parse(&["a..master"]), (vec![b], vec![a]));
+        assert_eq!(parse(&["a.."]), (vec![b], vec![a]));
+        assert_eq!(parse(&["..a"]), (vec![a], vec![b]));
+        // merge bases need commits
+        assert!(super::parse_revs(&["a...master"], &repo).is_err());
+
+        // annotated tags stand for what they tag
+        let tag = repo
+            .store(&Tag {
+                object: b,
+                kind: ObjectType::Blob,
+                name: "v1".to_string(),
+                tagger: None,
+                message: "v1\n".to_string(),
+            })
+            .unwrap();
+        super::update_ref(Path::new("refs/tags/v1"), &tag, "", &repo).unwrap();
+        assert_eq!(parse(&["v1", "^a"]), (vec![b], vec![a]));
+        assert_eq!(parse(&["v1..a"]), (vec![a], vec![b]));
+
+        // root - base - one
+        //           \- two
+        let tree = repo.store(&Tree { files: Vec::new() }).unwrap();
+        let commit = |parents: Vec<Id>, message: &str| {
+            let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+            repo.store(&Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: message.to_string(),
+            })
+            .unwrap()
+        };
+        let root = commit(vec![], "root\n");
+        let base = commit(vec![root], "base\n");
+        let one = commit(vec![base], "one\n");
+        let two = commit(vec![base], "two\n");
+        repo.set_head(&one, "").unwrap();
+        super::update_ref(Path::new("refs/heads/two"), &two, "", &repo).unwrap();
+        assert_eq!(parse(&["master...two"]), (vec![one, two], vec![base]));
+        assert_eq!(parse(&["...two"]), (vec![one, two], vec![base]));
+        assert_eq!(parse(&["two..."]), (vec![two, one], vec![base]));
+        assert_eq!(parse(&["HEAD~1...HEAD"]), (vec![base, one], vec![base]));
+        assert_eq!(parse(&["two..HEAD"]), (vec![one], vec![two]));
+        // a path with dots in it isn't a range
+        let blob = repo.store(&Blob::new(b"dots".to_vec())).unwrap();
+        let tree = repo
+            .store(&Tree {
+                files: vec![File {
+                    mode: FileMode::Regular,
+                    name: "a..b".into(),
+                    id: blob,
+                }],
+            })
+            .unwrap();
+        assert_eq!(parse(&[&format!("{}:a..b", tree)]), (vec![blob], vec![]));
+        assert!(super::parse_revs(&["nope..two"], &repo).is_err());
+    }
+
+    #[test]
+    fn test_abbrev() {
+        let repo = Repo::in_memory();
+        // these are 5b2b55035c... and 5b2b555c51...
+        let a = repo.store(&Blob::new(b"1100".to_vec())).unwrap();
+        let b = repo.store(&Blob::new(b"1340".to_vec())).unwrap();
+
+        assert!(super::parse("5b2b55", &repo).is_err());
+        assert_eq!(super::parse("5b2b550", &repo).unwrap(), a);
+        assert_eq!(super::parse("5B2B555", &repo).unwrap(), b);
+        assert_eq!(super::parse(&a.to_string(), &repo).unwrap(), a);
+        assert!(super::parse(&"0".repeat(40), &repo).is_err());
+        assert_eq!(super::abbrev_len(&a, 4, &repo).unwrap(), 7);
+        assert_eq!(super::abbrev_len(&b, 10, &repo).unwrap(), 10);
+    }
+
     #[test]
     fn test_validate_refname() {
         let expected_responses = [
@@ -342,5 +1683,8 @@ mod tests {
             assert_eq!(super::is_valid_refname(inp, false), *expect);
         }
         assert_eq!(super::is_valid_refname("abc", true), true);
+
+        assert_eq!(super::normalize_refname("//refs//heads/x"), "refs/heads/x");
+        assert_eq!(super::normalize_refname("refs/heads/"), "refs/heads/");
     }
 }
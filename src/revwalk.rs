@@ -0,0 +1,794 @@
+//! Walking the object graph: listing commits newest first as `log` does, or
+//! topologically, optionally only those changing some paths, and finding
+//! every object reachable from some starting points, which is what decides
+//! the objects that go into a pack
+use anyhow::{anyhow, Context, Result};
+use bstr::BString;
+use chrono::{DateTime, FixedOffset};
+use regex::Regex;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::diff;
+use crate::objects::{Commit, FileMode, Id, NameEntry, Object, ObjectType, Repo};
+use crate::tree;
+use crate::util::join_git_path;
+
+/// Finds the target of an annotated tag from its content
+pub(crate) fn tag_target(content: &[u8]) -> Option<Id> {
+    let first = content.split(|&b| b == b'\n').next()?;
+    if !first.starts_with(b"object ") {
+        return None;
+    }
+    Id::from(std::str::from_utf8(&first[7..]).ok()?)
+}
+
+/// An object found while walking, along with the path it was first found at.
+/// Pack writers use the path as a hint for which blobs are likely to be
+/// versions of each other and worth storing as deltas.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundObject {
+    /// ID of the object
+    pub id: Id,
+    /// What kind of object it is
+    pub kind: ObjectType,
+    /// Path of the object in the tree it was found in. Empty for commits, tags
+    /// and the root trees of commits.
+    pub path: BString,
+}
+
+/// An object waiting to be looked at. The type is known for anything found
+/// in a commit or tree.
+type Pending = (Id, Option<ObjectType>, BString);
+
+/// Adds everything reachable from `stack` that isn't in `seen` to both `seen`
+/// and `found`. The parents of commits are only followed if `follow_parents`
+/// is set.
+fn walk(
+    repo: &Repo,
+    mut stack: Vec<Pending>,
+    follow_parents: bool,
+    seen: &mut HashSet<Id>,
+    found: &mut Vec<FoundObject>,
+) -> Result<()> {
+    while let Some((id, kind, path)) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+
+        let kind = match kind {
+            Some(kind) => kind,
+            None => repo.object_header(&id)?.0,
+        };
+        match kind {
+            // blobs can be large and have nothing to follow, so never read them
+            ObjectType::Blob => (),
+            ObjectType::Tag => {
+                let (_, content) = repo.read_object(&id)?;
+                let target =
+                    tag_target(&content).with_context(|| format!("tag {} has no target", id))?;
+                stack.push((target, None, BString::from("")));
+            }
+            ObjectType::Commit | ObjectType::Tree => match &*repo.open_shared(&id)? {
+                Object::Commit(commit) => {
+                    stack.push((commit.tree, Some(ObjectType::Tree), BString::from("")));
+                    if follow_parents {
+                        stack.extend(
+                            commit
+                                .parents
+                                .iter()
+                                .map(|&p| (p, Some(ObjectType::Commit), BString::from(""))),
+                        );
+                    }
+                }
+                Object::Tree(tree) => {
+                    // pushed in reverse so they come off the stack in order
+                    for file in tree.files.iter().rev() {
+                        let kind = match file.mode {
+                            FileMode::Directory => ObjectType::Tree,
+                            // submodule commits live in another repository
+                            FileMode::Gitlink => continue,
+                            _ => ObjectType::Blob,
+                        };
+                        stack.push((file.id, Some(kind), join_git_path(&path, &file.name)));
+                    }
+                }
+                Object::Blob(_) => return Err(anyhow!("{} is a blob, not a {}", id, kind)),
+            },
+        }
+        found.push(FoundObject { id, kind, path });
+    }
+    Ok(())
+}
+
+/// Makes a stack of objects to walk from, so the first comes off first
+fn start_from(ids: &[Id]) -> Vec<Pending> {
+    ids.iter()
+        .rev()
+        .map(|&id| (id, None, BString::from("")))
+        .collect()
+}
+
+/// Finds every object reachable from `tips` but not from `exclude`, such as
+/// what has to be sent to someone who already has `exclude`. Each object is
+/// listed once, starting with the tips.
+pub fn reachable_objects(repo: &Repo, tips: &[Id], exclude: &[Id]) -> Result<Vec<FoundObject>> {
+    let mut seen = HashSet::new();
+    walk(repo, start_from(exclude), true, &mut seen, &mut Vec::new())?;
+    let mut found = Vec::new();
+    walk(repo, start_from(tips), true, &mut seen, &mut found)?;
+    Ok(found)
+}
+
+/// Finds the trees and blobs in the given commits that aren't in the commits
+/// in `exclude`, in the order they are first found. Only the trees of the
+/// commits themselves are looked at, not their parents.
+pub fn tree_objects(repo: &Repo, commits: &[Id], exclude: &[Id]) -> Result<Vec<FoundObject>> {
+    let mut seen = HashSet::new();
+    walk(repo, start_from(exclude), false, &mut seen, &mut Vec::new())?;
+    let mut found = Vec::new();
+    for commit in commits {
+        walk(repo, start_from(&[*commit]), false, &mut seen, &mut found)?;
+    }
+    found.retain(|obj| obj.kind != ObjectType::Commit);
+    Ok(found)
+}
+
+/// Which commits a [`RevWalk`] yields. Commits that don't match are still
+/// walked through to reach their parents.
+#[derive(Debug, Default)]
+pub struct CommitFilter {
+    /// Only commits made at or after this time
+    pub since: Option<DateTime<FixedOffset>>,
+    /// Only commits made at or before this time
+    pub until: Option<DateTime<FixedOffset>>,
+    /// Only commits whose `Name <email>` author matches
+    pub author: Option<Regex>,
+    /// Only commits whose `Name <email>` committer matches
+    pub committer: Option<Regex>,
+    /// Only commits whose message matches any of these
+    pub grep: Vec<Regex>,
+    /// Only commits with at least this many parents. 2 shows only merges.
+    pub min_parents: usize,
+    /// Only commits with at most this many parents. 1 hides merges.
+    pub max_parents: Option<usize>,
+}
+
+impl CommitFilter {
+    /// Does this commit pass the filter? Dates are commit dates rather than
+    /// author dates, as in git.
+    pub fn matches(&self, commit: &Commit) -> bool {
+        let when = commit.committer.time;
+        let who = |re: &Regex, entry: &NameEntry| {
+            re.is_match(&format!("{} <{}>", entry.name, entry.email))
+        };
+        let parents = commit.parents.len();
+        parents >= self.min_parents
+            && self.max_parents.map_or(true, |max| parents <= max)
+            && self.since.map_or(true, |since| when >= since)
+            && self.until.map_or(true, |until| when <= until)
+            && self
+                .author
+                .as_ref()
+                .map_or(true, |re| who(re, &commit.author))
+            && self
+                .committer
+                .as_ref()
+                .map_or(true, |re| who(re, &commit.committer))
+            && (self.grep.is_empty() || self.grep.iter().any(|re| re.is_match(&commit.message)))
+    }
+}
+
+/// A commit waiting to be visited. The newest is visited first, and of those
+/// made at the same time, the first found.
+struct Queued {
+    time: i64,
+    order: usize,
+    id: Id,
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Queued) -> Ordering {
+        self.time
+            .cmp(&other.time)
+            .then_with(|| other.order.cmp(&self.order))
+    }
+}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Queued) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Queued) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+/// Lists the commits reachable from some starting points, newest first by
+/// commit date, like `git log`. Each commit is listed once.
+pub struct RevWalk<'a> {
+    repo: &'a Repo,
+    queue: BinaryHeap<Queued>,
+    seen: HashSet<Id>,
+    filter: CommitFilter,
+    /// only follow the first parent of merges
+    first_parent: bool,
+    /// number of commits queued so far, which breaks ties in the queue
+    queued: usize,
+    /// whether to list commits in topological order
+    topo: bool,
+    /// with `topo`, the sorted commits left to list, once they are known
+    sorted: Option<std::vec::IntoIter<Id>>,
+    /// only list commits changing these paths, unless empty
+    paths: Vec<BString>,
+    /// follow the one path in `paths` through renames
+    follow: bool,
+}
+
+impl<'a> RevWalk<'a> {
+    /// Starts a walk from the given commits
+    pub fn new(repo: &'a Repo, tips: &[Id]) -> Result<RevWalk<'a>> {
+        let mut walk = RevWalk {
+            repo,
+            queue: BinaryHeap::new(),
+            seen: HashSet::new(),
+            filter: Default::default(),
+            first_parent: false,
+            queued: 0,
+            topo: false,
+            sorted: None,
+            paths: Vec::new(),
+            follow: false,
+        };
+        for tip in tips {
+            walk.enqueue(*tip)?;
+        }
+        Ok(walk)
+    }
+
+    /// Only yields commits that pass `filter`
+    pub fn with_filter(mut self, filter: CommitFilter) -> RevWalk<'a> {
+        self.filter = filter;
+        self
+    }
+
+    /// Leaves out the given commits and everything reachable from them, as
+    /// with `^<commit>` on the command line
+    pub fn hide(mut self, ids: &[Id]) -> Result<RevWalk<'a>> {
+        let mut hidden = HashSet::new();
+        let mut stack = ids.to_vec();
+        while let Some(id) = stack.pop() {
+            if !hidden.insert(id) {
+                continue;
+            }
+            if let Object::Commit(commit) = &*self.commit(&id)? {
+                stack.extend(&commit.parents);
+            }
+        }
+
+        let queue = std::mem::replace(&mut self.queue, BinaryHeap::new());
+        self.queue = queue
+            .into_iter()
+            .filter(|queued| !hidden.contains(&queued.id))
+            .collect();
+        self.seen.extend(hidden);
+        Ok(self)
+    }
+
+    /// Only follows the first parent of each merge, which is the branch that
+    /// was merged into. On a branch that only has other work merged into it,
+    /// such as a release branch, that is the history of the branch itself.
+    pub fn first_parent_only(mut self) -> RevWalk<'a> {
+        self.first_parent = true;
+        self
+    }
+
+    /// Lists no commit until all of its children have been, as with
+    /// `--topo-order`, so that lines of history aren't mixed together where
+    /// their dates overlap. After a merge, the history merged in comes first.
+    /// Everything has to be walked before the first commit can be listed.
+    pub fn topo_order(mut self) -> RevWalk<'a> {
+        self.topo = true;
+        self
+    }
+
+    /// Only yields commits that change one of these paths, which are files or
+    /// directories from the root of the tree. Like git, a merge that has the
+    /// paths as one of its parents does isn't listed, and only that parent is
+    /// followed, since that is where their history is.
+    pub fn limit_to_paths(mut self, paths: Vec<BString>) -> RevWalk<'a> {
+        self.paths = paths;
+        self
+    }
+
+    /// Follows a single file back through renames, as with `--follow`. A file
+    /// that appears in a commit was renamed from one deleted by it if they
+    /// have at least half of their lines in common.
+    pub fn follow_renames(mut self) -> RevWalk<'a> {
+        self.follow = true;
+        self
+    }
+
+    /// Walks everything left in date order, then sorts it topologically
+    fn topo_sort(&mut self) -> Result<Vec<Id>> {
+        let mut walked = Vec::new();
+        let mut shown = HashSet::new();
+        while let Some(Queued { id, .. }) = self.queue.pop() {
+            if self.visit(id)? {
+                shown.insert(id);
+            }
+            walked.push(id);
+        }
+
+        // hidden parents aren't listed, so they don't count
+        let listed = walked.iter().copied().collect::<HashSet<_>>();
+        // how many children of each commit are yet to be listed
+        let mut parents = HashMap::with_capacity(walked.len());
+        let mut children = HashMap::<Id, usize>::with_capacity(walked.len());
+        for id in &walked {
+            let obj = self.commit(id)?;
+            let commit = match &*obj {
+                Object::Commit(commit) => commit,
+                _ => unreachable!(),
+            };
+            let follow = if self.first_parent {
+                1
+            } else {
+                commit.parents.len()
+            };
+            let followed = commit
+                .parents
+                .iter()
+                .take(follow)
+                .copied()
+                .filter(|parent| listed.contains(parent))
+                .collect::<Vec<_>>();
+            for parent in &followed {
+                *children.entry(*parent).or_default() += 1;
+            }
+            parents.insert(*id, followed);
+        }
+
+        // like git, the most recently freed commit goes next, which keeps
+        // each line of history together
+        let mut ready = walked
+            .iter()
+            .rev()
+            .filter(|id| !children.contains_key(id))
+            .copied()
+            .collect::<Vec<_>>();
+        let mut sorted = Vec::with_capacity(shown.len());
+        while let Some(id) = ready.pop() {
+            if shown.contains(&id) {
+                sorted.push(id);
+            }
+            for parent in &parents[&id] {
+                let left = children.get_mut(parent).expect("parent was never counted");
+                *left -= 1;
+                if *left == 0 {
+                    ready.push(*parent);
+                }
+            }
+        }
+        Ok(sorted)
+    }
+
+    fn commit(&self, id: &Id) -> Result<Arc<Object>> {
+        let obj = self.repo.open_shared(id)?;
+        match &*obj {
+            Object::Commit(_) => Ok(obj),
+            _ => Err(anyhow!("{} is not a commit", id)),
+        }
+    }
+
+    fn enqueue(&mut self, id: Id) -> Result<()> {
+        if !self.seen.insert(id) {
+            return Ok(());
+        }
+        let time = match &*self.commit(&id)? {
+            Object::Commit(commit) => commit.committer.time.timestamp(),
+            _ => unreachable!(),
+        };
+        self.queue.push(Queued {
+            time,
+            order: self.queued,
+            id,
+        });
+        self.queued += 1;
+        Ok(())
+    }
+
+    fn visit(&mut self, id: Id) -> Result<bool> {
+        let obj = self.commit(&id)?;
+        let commit = match &*obj {
+            Object::Commit(commit) => commit,
+            _ => unreachable!(),
+        };
+        let follow = if self.first_parent {
+            1
+        } else {
+            commit.parents.len()
+        };
+        let mut parents = commit.parents.iter().take(follow).copied().collect();
+        let changed = self.paths.is_empty() || self.changes_paths(commit, &mut parents)?;
+        for parent in parents {
+            self.enqueue(parent)?;
+        }
+        Ok(changed && self.filter.matches(commit))
+    }
+
+    fn tree_of(&self, id: &Id) -> Result<Id> {
+        match &*self.commit(id)? {
+            Object::Commit(commit) => Ok(commit.tree),
+            _ => unreachable!(),
+        }
+    }
+
+    /// What is at each of the paths in a tree
+    fn entries(&self, tree: &Id) -> Result<Vec<Option<(Id, FileMode)>>> {
+        self.paths
+            .iter()
+            .map(|path| tree::entry_at(tree, path, self.repo))
+            .collect()
+    }
+
+    /// Does this commit change any of the paths? If one of its parents has
+    /// them the same, that is the only parent left in `parents`.
+    fn changes_paths(&mut self, commit: &Commit, parents: &mut Vec<Id>) -> Result<bool> {
+        let ours = self.entries(&commit.tree)?;
+        let mut first_tree = None;
+        for parent in parents.iter() {
+            let tree = self.tree_of(parent)?;
+            if self.entries(&tree)? == ours {
+                *parents = vec![*parent];
+                return Ok(false);
+            }
+            first_tree.get_or_insert(tree);
+        }
+        let parent_tree = match first_tree {
+            Some(tree) => tree,
+            // a root commit changes whatever it has
+            None => return Ok(ours.iter().any(Option::is_some)),
+        };
+
+        let added_file = match ours[0] {
+            Some((id, mode)) if !mode.is_dir() => Some(id),
+            _ => None,
+        };
+        if let (true, 1, Some(id)) = (self.follow, parents.len(), added_file) {
+            if self.entries(&parent_tree)?[0].is_none() {
+                if let Some(old) = self.find_rename(&id, &parent_tree, &commit.tree)? {
+                    self.paths = vec![old];
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Finds which file deleted between two trees the blob `id` is most like,
+    /// if any are enough alike for it to have been renamed
+    fn find_rename(&self, id: &Id, old_tree: &Id, new_tree: &Id) -> Result<Option<BString>> {
+        let deleted = tree::changed_files(Some(old_tree), Some(new_tree), self.repo)?
+            .into_iter()
+            .filter_map(|change| match (change.old, change.new) {
+                (Some((old, mode)), None) if mode != FileMode::Gitlink => Some((change.path, old)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if let Some((path, _)) = deleted.iter().find(|(_, old)| old == id) {
+            return Ok(Some(path.clone()));
+        }
+
+        let content = self.repo.read_object(id)?.1;
+        let mut best = None;
+        for (path, old) in deleted {
+            let score = diff::similarity(&self.repo.read_object(&old)?.1, &content);
+            if score >= 50 && best.as_ref().map_or(true, |(best, _)| score > *best) {
+                best = Some((score, path));
+            }
+        }
+        Ok(best.map(|(_, path)| path))
+    }
+}
+
+impl Iterator for RevWalk<'_> {
+    type Item = Result<Id>;
+
+    fn next(&mut self) -> Option<Result<Id>> {
+        if self.topo && self.sorted.is_none() {
+            match self.topo_sort() {
+                Ok(sorted) => self.sorted = Some(sorted.into_iter()),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        if let Some(sorted) = &mut self.sorted {
+            return sorted.next().map(Ok);
+        }
+        while let Some(Queued { id, .. }) = self.queue.pop() {
+            match self.visit(id) {
+                Ok(true) => return Some(Ok(id)),
+                Ok(false) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reachable_objects, tree_objects, CommitFilter, FoundObject, RevWalk};
+    use crate::objects::{Blob, Commit, File, FileMode, Id, NameEntry, ObjectType, Repo, Tree};
+    use chrono::DateTime;
+    use regex::Regex;
+
+    #[test]
+    fn test_reachable_objects() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+
+        let blob = repo.store(&Blob::new(b"one".to_vec())).unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "file".into(),
+                id: blob,
+            }],
+        };
+        let tree = repo.store(&tree).unwrap();
+        let first = Commit {
+            tree,
+            parents: vec![],
+            author: who.clone(),
+            committer: who.clone(),
+            message: "first\n".to_string(),
+        };
+        let first = repo.store(&first).unwrap();
+
+        let blob2 = repo.store(&Blob::new(b"two".to_vec())).unwrap();
+        let tree2 = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "file".into(),
+                id: blob2,
+            }],
+        };
+        let tree2 = repo.store(&tree2).unwrap();
+        let second = Commit {
+            tree: tree2,
+            parents: vec![first],
+            author: who.clone(),
+            committer: who,
+            message: "second\n".to_string(),
+        };
+        let second = repo.store(&second).unwrap();
+
+        let all = reachable_objects(&repo, &[second], &[]).unwrap();
+        assert_eq!(all.len(), 6);
+        assert_eq!(all[0].id, second);
+
+        let mut new: Vec<_> = reachable_objects(&repo, &[second], &[first])
+            .unwrap()
+            .into_iter()
+            .map(|obj| obj.id)
+            .collect();
+        new.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        let mut expected = vec![second, tree2, blob2];
+        expected.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        assert_eq!(new, expected);
+
+        assert_eq!(
+            tree_objects(&repo, &[second], &[first]).unwrap(),
+            vec![
+                FoundObject {
+                    id: tree2,
+                    kind: ObjectType::Tree,
+                    path: "".into(),
+                },
+                FoundObject {
+                    id: blob2,
+                    kind: ObjectType::Blob,
+                    path: "file".into(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rev_walk() {
+        let repo = Repo::in_memory();
+        let tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let commit = |parents: Vec<Id>, who: &str, time: u32, message: &str| {
+            let who = NameEntry::from(&format!("{} {} +0000", who, time)).unwrap();
+            let commit = Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: message.to_string(),
+            };
+            repo.store(&commit).unwrap()
+        };
+
+        // a merge of a side branch with an older commit on it
+        let root = commit(vec![], "a <a@example.com>", 100, "root\n");
+        let side = commit(vec![root], "b <b@example.com>", 150, "fix bug\n");
+        let main = commit(vec![root], "a <a@example.com>", 200, "add feature\n");
+        let merge = commit(vec![main, side], "a <a@example.com>", 300, "merge\n");
+
+        let walk = |filter| -> Vec<Id> {
+            RevWalk::new(&repo, &[merge])
+                .unwrap()
+                .with_filter(filter)
+                .collect::<anyhow::Result<_>>()
+                .unwrap()
+        };
+        assert_eq!(walk(Default::default()), vec![merge, main, side, root]);
+        let hidden = RevWalk::new(&repo, &[merge])
+            .unwrap()
+            .hide(&[main])
+            .unwrap();
+        assert_eq!(
+            hidden.collect::<anyhow::Result<Vec<_>>>().unwrap(),
+            vec![merge, side]
+        );
+        let first_parent = RevWalk::new(&repo, &[merge]).unwrap().first_parent_only();
+        assert_eq!(
+            first_parent.collect::<anyhow::Result<Vec<_>>>().unwrap(),
+            vec![merge, main, root]
+        );
+        assert_eq!(
+            walk(CommitFilter {
+                min_parents: 2,
+                ..Default::default()
+            }),
+            vec![merge]
+        );
+        assert_eq!(
+            walk(CommitFilter {
+                max_parents: Some(1),
+                ..Default::default()
+            }),
+            vec![main, side, root]
+        );
+        assert_eq!(
+            walk(CommitFilter {
+                author: Some(Regex::new("^b ").unwrap()),
+                ..Default::default()
+            }),
+            vec![side]
+        );
+        assert_eq!(
+            walk(CommitFilter {
+                grep: vec![Regex::new("feature").unwrap(), Regex::new("^root").unwrap()],
+                ..Default::default()
+            }),
+            vec![main, root]
+        );
+        assert_eq!(
+            walk(CommitFilter {
+                since: Some(DateTime::parse_from_rfc3339("1970-01-01T00:02:30Z").unwrap()),
+                until: Some(DateTime::parse_from_rfc3339("1970-01-01T00:04:00Z").unwrap()),
+                ..Default::default()
+            }),
+            vec![main, side]
+        );
+    }
+
+    #[test]
+    fn test_topo_order() {
+        let repo = Repo::in_memory();
+        let tree = repo.store(&Tree { files: vec![] }).unwrap();
+        let commit = |parents: Vec<Id>, time: u32, message: &str| {
+            let who = NameEntry::from(&format!("a <a@example.com> {} +0000", time)).unwrap();
+            let commit = Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: message.to_string(),
+            };
+            repo.store(&commit).unwrap()
+        };
+
+        // two lines of history made at the same time, then merged
+        let root = commit(vec![], 100, "root\n");
+        let side1 = commit(vec![root], 110, "side 1\n");
+        let main1 = commit(vec![root], 150, "main 1\n");
+        let side2 = commit(vec![side1], 210, "side 2\n");
+        let main2 = commit(vec![main1], 250, "main 2\n");
+        let merge = commit(vec![main2, side2], 300, "merge\n");
+
+        let walk = |tips: &[Id]| RevWalk::new(&repo, tips).unwrap();
+        let list = |walk: RevWalk| walk.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(
+            list(walk(&[merge])),
+            vec![merge, main2, side2, main1, side1, root]
+        );
+        assert_eq!(
+            list(walk(&[merge]).topo_order()),
+            vec![merge, side2, side1, main2, main1, root]
+        );
+        assert_eq!(
+            list(walk(&[main2, side2]).topo_order()),
+            vec![main2, main1, side2, side1, root]
+        );
+        assert_eq!(
+            list(walk(&[merge]).hide(&[side1]).unwrap().topo_order()),
+            vec![merge, side2, main2, main1]
+        );
+        let filter = CommitFilter {
+            max_parents: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            list(
+                walk(&[merge])
+                    .first_parent_only()
+                    .with_filter(filter)
+                    .topo_order()
+            ),
+            vec![main2, main1, root]
+        );
+    }
+
+    #[test]
+    fn test_limit_to_paths() {
+        let repo = Repo::in_memory();
+        let blob = |content: &str| repo.store(&Blob::new(content.as_bytes().to_vec())).unwrap();
+        let tree = |files: &[(&str, Id)]| {
+            let files = files
+                .iter()
+                .map(|(name, id)| File {
+                    mode: FileMode::Regular,
+                    name: (*name).into(),
+                    id: *id,
+                })
+                .collect();
+            repo.store(&Tree { files }).unwrap()
+        };
+        let commit = |parents: Vec<Id>, time: u32, tree: Id| {
+            let who = NameEntry::from(&format!("a <a@example.com> {} +0000", time)).unwrap();
+            let commit = Commit {
+                tree,
+                parents,
+                author: who.clone(),
+                committer: who,
+                message: "message\n".to_string(),
+            };
+            repo.store(&commit).unwrap()
+        };
+
+        let (v1, v2) = (blob("1\n2\n3\n4\n"), blob("1\n2\n3\n4\n5\n"));
+        let (v3, other) = (blob("1\n2\nthree\n4\n5\n"), blob("other\n"));
+        let root = commit(vec![], 100, tree(&[("old", v1)]));
+        let unrelated = commit(vec![root], 200, tree(&[("old", v1), ("x", other)]));
+        let change = commit(vec![unrelated], 300, tree(&[("old", v2), ("x", other)]));
+        // renamed and changed a little at the same time
+        let rename = commit(vec![change], 400, tree(&[("new", v3), ("x", other)]));
+        let side = commit(vec![change], 350, tree(&[("old", v2)]));
+        // has the file as it is on the first parent
+        let merge = commit(vec![rename, side], 500, tree(&[("new", v3)]));
+
+        let walk = |paths: &[&str]| {
+            let paths = paths.iter().map(|path| (*path).into()).collect();
+            RevWalk::new(&repo, &[merge]).unwrap().limit_to_paths(paths)
+        };
+        let list = |walk: RevWalk| walk.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(list(walk(&["old"])), vec![rename, change, root]);
+        assert_eq!(list(walk(&["new"])), vec![rename]);
+        // the merge has x as side does, which took it out
+        assert_eq!(list(walk(&["x"])), vec![side, unrelated]);
+        assert_eq!(
+            list(walk(&["new"]).follow_renames()),
+            vec![rename, change, root]
+        );
+        assert_eq!(list(walk(&["nothing"])), vec![]);
+    }
+}
@@ -0,0 +1,299 @@
+//! Tracing that works like git's, configured with the same environment
+//! variables so existing tooling for reading git traces can read ours:
+//!
+//! - `GIT_TRACE` gets messages about what is going on, such as hooks being run
+//! - `GIT_TRACE_PERFORMANCE` gets how long each region of work took
+//! - `GIT_TRACE2_EVENT` gets trace2 style JSON events, one per line
+//!
+//! Each may be `1`, `2` or `true` to write to stderr, or an absolute path to
+//! append to. Anything else, including `0`, leaves it off. Regions are made
+//! with [`region`] and last until the returned guard is dropped, so they are
+//! reported even when the work fails.
+use lazy_static::lazy_static;
+use serde_json::{json, Value};
+use std::cell::Cell;
+use std::env;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::util::{self, Stopwatch};
+
+/// Version of the trace2 event format we write
+const EVENT_VERSION: &str = "3";
+
+/// Where a kind of trace goes, as given in its environment variable
+#[derive(Debug, PartialEq, Eq)]
+enum Destination {
+    Stderr,
+    File(String),
+}
+
+impl Destination {
+    fn parse(value: &str) -> Option<Destination> {
+        match value.to_lowercase().as_str() {
+            "" | "0" | "false" => None,
+            "1" | "2" | "true" => Some(Destination::Stderr),
+            _ if value.starts_with('/') => Some(Destination::File(value.to_owned())),
+            _ => {
+                warn!("unsupported trace destination {:?}, ignoring it", value);
+                None
+            }
+        }
+    }
+}
+
+/// An open trace destination
+enum Target {
+    Stderr,
+    File(Mutex<File>),
+}
+
+impl Target {
+    fn from_env(var: &str) -> Option<Target> {
+        let value = env::var(var).ok()?;
+        match Destination::parse(&value)? {
+            Destination::Stderr => Some(Target::Stderr),
+            Destination::File(path) => {
+                match OpenOptions::new().create(true).append(true).open(&path) {
+                    Ok(file) => Some(Target::File(Mutex::new(file))),
+                    Err(e) => {
+                        warn!("could not open {} for {}: {}", path, var, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes one line. Lines are written in one call so that lines from
+    /// different threads and processes don't interleave.
+    fn write_line(&self, line: &str) {
+        let line = format!("{}\n", line);
+        // losing a trace line isn't worth failing over
+        let _ = match self {
+            Target::Stderr => io::stderr().write_all(line.as_bytes()),
+            Target::File(file) => file
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_all(line.as_bytes()),
+        };
+    }
+}
+
+/// Everything tracing needs, set up from the environment on first use
+struct Tracer {
+    trace: Option<Target>,
+    perf: Option<Target>,
+    event: Option<Target>,
+    /// when tracing started, which stands in for when the process started
+    start: Stopwatch,
+    /// trace2 session ID, which is unique to this process
+    sid: String,
+}
+
+lazy_static! {
+    /// None if no tracing is turned on, which is nearly always
+    static ref TRACER: Option<Tracer> = Tracer::from_env();
+}
+
+thread_local! {
+    /// how many regions the current thread is inside
+    static NESTING: Cell<usize> = Cell::new(0);
+}
+
+/// Time of day as git puts it at the start of trace lines
+fn time_of_day() -> String {
+    util::utc_now().format("%H:%M:%S%.6f").to_string()
+}
+
+impl Tracer {
+    fn from_env() -> Option<Tracer> {
+        let trace = Target::from_env("GIT_TRACE");
+        let perf = Target::from_env("GIT_TRACE_PERFORMANCE");
+        let event = Target::from_env("GIT_TRACE2_EVENT");
+        if trace.is_none() && perf.is_none() && event.is_none() {
+            return None;
+        }
+
+        let sid = format!(
+            "{}-P{:08x}",
+            util::utc_now().format("%Y%m%dT%H%M%S%.6fZ"),
+            util::process_id()
+        );
+        Some(Tracer {
+            trace,
+            perf,
+            event,
+            start: Stopwatch::start(),
+            sid,
+        })
+    }
+
+    /// Writes a trace2 event, adding the fields every event has
+    fn event(&self, name: &str, mut fields: Value) {
+        let target = match &self.event {
+            Some(target) => target,
+            None => return,
+        };
+        let thread = std::thread::current();
+        let common = json!({
+            "event": name,
+            "sid": self.sid,
+            "thread": thread.name().unwrap_or("unnamed"),
+            "time": util::utc_now().format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string(),
+        });
+        if let (Value::Object(fields), Value::Object(common)) = (&mut fields, common) {
+            fields.extend(common);
+        }
+        target.write_line(&fields.to_string());
+    }
+
+    fn elapsed(&self) -> f64 {
+        as_secs(self.start.elapsed())
+    }
+}
+
+fn as_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+/// Reports a message to `GIT_TRACE`. Take `args` from `format_args!`, so
+/// nothing is formatted unless tracing is on.
+pub fn message(args: fmt::Arguments) {
+    if let Some(Tracer {
+        trace: Some(target),
+        ..
+    }) = &*TRACER
+    {
+        target.write_line(&format!("{} trace: {}", time_of_day(), args));
+    }
+}
+
+/// Records the start of the process, with the arguments it was run with
+pub fn start(argv: &[String]) {
+    if let Some(tracer) = &*TRACER {
+        tracer.event(
+            "version",
+            json!({ "evt": EVENT_VERSION, "exe": env!("CARGO_PKG_VERSION") }),
+        );
+        tracer.event("start", json!({ "t_abs": tracer.elapsed(), "argv": argv }));
+        message(format_args!(
+            "built-in: git {}",
+            argv.get(1..).unwrap_or(&[]).join(" ")
+        ));
+    }
+}
+
+/// Records the process finishing with exit `code`, and how long it ran
+pub fn exit(code: i32) {
+    if let Some(tracer) = &*TRACER {
+        let elapsed = tracer.elapsed();
+        if let Some(perf) = &tracer.perf {
+            perf.write_line(&format!(
+                "{} performance: {:.9} s: git command",
+                time_of_day(),
+                elapsed
+            ));
+        }
+        tracer.event("exit", json!({ "t_abs": elapsed, "code": code }));
+    }
+}
+
+/// A region of work being timed. It ends when this is dropped.
+pub struct Region {
+    category: &'static str,
+    label: &'static str,
+    /// None if tracing is off
+    started: Option<Stopwatch>,
+}
+
+/// Starts a region, such as reading the index. `category` is the area of the
+/// code, such as `index`, and `label` what is being done there.
+pub fn region(category: &'static str, label: &'static str) -> Region {
+    let tracer = match &*TRACER {
+        Some(tracer) => tracer,
+        None => {
+            return Region {
+                category,
+                label,
+                started: None,
+            }
+        }
+    };
+    let nesting = NESTING.with(|n| {
+        n.set(n.get() + 1);
+        n.get()
+    });
+    tracer.event(
+        "region_enter",
+        json!({
+            "t_abs": tracer.elapsed(),
+            "nesting": nesting,
+            "category": category,
+            "label": label,
+        }),
+    );
+    Region {
+        category,
+        label,
+        started: Some(Stopwatch::start()),
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let (tracer, started) = match (&*TRACER, self.started) {
+            (Some(tracer), Some(started)) => (tracer, started),
+            _ => return,
+        };
+        let t_rel = as_secs(started.elapsed());
+        let nesting = NESTING.with(|n| {
+            let nesting = n.get();
+            n.set(nesting.saturating_sub(1));
+            nesting
+        });
+        if let Some(perf) = &tracer.perf {
+            perf.write_line(&format!(
+                "{} performance: {:.9} s: {}{}: {}",
+                time_of_day(),
+                t_rel,
+                // nested regions are indented under the one they are part of
+                "| ".repeat(nesting - 1),
+                self.category,
+                self.label
+            ));
+        }
+        tracer.event(
+            "region_leave",
+            json!({
+                "t_abs": tracer.elapsed(),
+                "t_rel": t_rel,
+                "nesting": nesting,
+                "category": self.category,
+                "label": self.label,
+            }),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Destination;
+
+    #[test]
+    fn test_destination() {
+        assert_eq!(Destination::parse("1"), Some(Destination::Stderr));
+        assert_eq!(Destination::parse("True"), Some(Destination::Stderr));
+        assert_eq!(Destination::parse("0"), None);
+        assert_eq!(Destination::parse(""), None);
+        assert_eq!(
+            Destination::parse("/tmp/trace"),
+            Some(Destination::File("/tmp/trace".to_owned()))
+        );
+        // relative paths aren't allowed, like in git
+        assert_eq!(Destination::parse("trace.log"), None);
+    }
+}
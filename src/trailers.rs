@@ -0,0 +1,324 @@
+//! Trailers: the `Token: value` lines such as `Signed-off-by:` at the end of
+//! commit messages, following git's rules for finding them.
+//!
+//! The trailer block is the last paragraph of the message, not counting
+//! trailing blank lines, comments and anything from a `---` line on, where a
+//! patch may start. It is only a trailer block if it consists entirely of
+//! trailers, or if at least a quarter of it is and one of those is one git
+//! generates itself. The first paragraph is the title, so is never a trailer
+//! block.
+
+/// Trailers that git adds by itself, which are enough to mark a paragraph as a
+/// trailer block even with other lines in it
+const GIT_GENERATED: [&str; 2] = ["Signed-off-by: ", "(cherry picked from commit "];
+
+/// A `Token: value` line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailer {
+    /// What the trailer is, such as `Signed-off-by`
+    pub token: String,
+    /// What it says, with any continuation lines
+    pub value: String,
+}
+
+impl Trailer {
+    /// Parses a trailer line, which is a token of letters, digits and `-`
+    /// followed by one of `separators`
+    pub fn parse(line: &str, separators: &str) -> Option<Trailer> {
+        let sep = line.find(|c| separators.contains(c))?;
+        let token = line[..sep].trim_end();
+        let is_token =
+            !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if !is_token {
+            return None;
+        }
+        Some(Trailer {
+            token: token.to_string(),
+            value: line[sep + 1..].trim().to_string(),
+        })
+    }
+
+    /// Whether this is the same trailer as `other`. Tokens are compared
+    /// without regard to case.
+    fn same_as(&self, other: &Trailer) -> bool {
+        self.token.eq_ignore_ascii_case(&other.token) && self.value == other.value
+    }
+}
+
+/// What to do when adding a trailer whose token is already in the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IfExists {
+    /// Add it unless the trailer next to where it would go is the same
+    AddIfDifferentNeighbor,
+    /// Add it unless the same trailer is anywhere in the block
+    AddIfDifferent,
+    /// Always add it
+    Add,
+    /// Replace the last trailer with the same token
+    Replace,
+    /// Leave the message as it is
+    DoNothing,
+}
+
+impl Default for IfExists {
+    fn default() -> IfExists {
+        IfExists::AddIfDifferentNeighbor
+    }
+}
+
+/// A line of the trailer block, with its continuation lines
+#[derive(Debug, Clone)]
+struct Item {
+    /// The trailer on the line, if it is one
+    trailer: Option<Trailer>,
+    /// The line as written
+    text: String,
+}
+
+/// A message split around its trailer block
+#[derive(Debug)]
+struct Split<'a> {
+    /// Everything before the trailer block, or before where one would go
+    body: &'a str,
+    /// The trailer block
+    items: Vec<Item>,
+    /// Blank lines, comments and patch after the trailer block
+    rest: &'a str,
+}
+
+/// Splits text into lines, keeping their line endings
+fn lines_with_ends(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let end = rest.find('\n').map_or(rest.len(), |pos| pos + 1);
+        lines.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    lines
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+/// Finds the trailer block of a message
+fn split(message: &str) -> Split<'_> {
+    let lines = lines_with_ends(message);
+    let offset = |n: usize| lines[..n].iter().map(|l| l.len()).sum::<usize>();
+
+    // a patch after the message is no part of it
+    let mut end = lines
+        .iter()
+        .position(|l| {
+            l.starts_with("---") && l[3..].starts_with(|c: char| c.is_whitespace())
+                || l.trim_end() == "---"
+        })
+        .unwrap_or(lines.len());
+    while end > 0 && (is_blank(lines[end - 1]) || lines[end - 1].starts_with('#')) {
+        end -= 1;
+    }
+
+    let no_block = Split {
+        body: &message[..offset(end)],
+        items: Vec::new(),
+        rest: &message[offset(end)..],
+    };
+    let start = match lines[..end].iter().rposition(|l| is_blank(l)) {
+        Some(blank) => blank + 1,
+        None => return no_block,
+    };
+    // the title is never a trailer block
+    if lines[..start].iter().all(|l| is_blank(l)) {
+        return no_block;
+    }
+
+    let mut items: Vec<Item> = Vec::new();
+    let (mut trailers, mut others) = (0, 0);
+    let mut recognized = false;
+    for &line in &lines[start..end] {
+        if line.starts_with(|c: char| c.is_whitespace()) {
+            if let Some(last) = items.last_mut() {
+                last.text.push_str(line);
+                if let Some(trailer) = &mut last.trailer {
+                    trailer.value.push(' ');
+                    trailer.value.push_str(line.trim());
+                }
+                continue;
+            }
+        }
+        let trailer = Trailer::parse(line.trim_end(), ":");
+        if trailer.is_some() {
+            trailers += 1;
+            recognized |= GIT_GENERATED.iter().any(|p| line.starts_with(p));
+        } else {
+            others += 1;
+        }
+        items.push(Item {
+            trailer,
+            text: line.to_string(),
+        });
+    }
+
+    let is_block = (trailers > 0 && others == 0) || (recognized && trailers * 3 >= others);
+    if !is_block {
+        return no_block;
+    }
+    // the last line may be missing its line ending
+    if let Some(last) = items.last_mut() {
+        if !last.text.ends_with('\n') {
+            last.text.push('\n');
+        }
+    }
+    Split {
+        body: &message[..offset(start)],
+        items,
+        rest: &message[offset(end)..],
+    }
+}
+
+/// Gets the trailers of a message
+pub fn parse(message: &str) -> Vec<Trailer> {
+    split(message)
+        .items
+        .into_iter()
+        .filter_map(|item| item.trailer)
+        .collect()
+}
+
+/// Adds trailers to the end of the trailer block of a message, starting one if
+/// there isn't one. `if_exists` says what to do about those with tokens
+/// already in the block. With `trim_empty`, trailers with empty values are
+/// removed.
+pub fn add(message: &str, new: &[Trailer], if_exists: IfExists, trim_empty: bool) -> String {
+    let Split {
+        body,
+        mut items,
+        rest,
+    } = split(message);
+    let had_block = !items.is_empty();
+
+    for trailer in new {
+        let existing = items.iter().rposition(|item| {
+            item.trailer
+                .as_ref()
+                .map_or(false, |t| t.token.eq_ignore_ascii_case(&trailer.token))
+        });
+        let add = match (existing, if_exists) {
+            (None, _) | (Some(_), IfExists::Add) => true,
+            (Some(_), IfExists::DoNothing) => false,
+            (Some(_), IfExists::AddIfDifferentNeighbor) => items
+                .last()
+                .and_then(|item| item.trailer.as_ref())
+                .map_or(true, |last| !last.same_as(trailer)),
+            (Some(_), IfExists::AddIfDifferent) => !items
+                .iter()
+                .filter_map(|item| item.trailer.as_ref())
+                .any(|t| t.same_as(trailer)),
+            (Some(n), IfExists::Replace) => {
+                items.remove(n);
+                true
+            }
+        };
+        if add {
+            items.push(Item {
+                text: format!("{}: {}\n", trailer.token, trailer.value),
+                trailer: Some(trailer.clone()),
+            });
+        }
+    }
+    if trim_empty {
+        items.retain(|item| item.trailer.as_ref().map_or(true, |t| !t.value.is_empty()));
+    }
+
+    let mut out = body.to_string();
+    if !items.is_empty() && !had_block {
+        if !out.is_empty() && !out.ends_with('\n') {
+            out.push('\n');
+        }
+        // a new block is a paragraph of its own
+        if !out.trim().is_empty() {
+            out.push('\n');
+        }
+    }
+    for item in &items {
+        out.push_str(&item.text);
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, parse, IfExists, Trailer};
+
+    #[test]
+    fn test_parse() {
+        let trailer = |token: &str, value: &str| Trailer {
+            token: token.to_string(),
+            value: value.to_string(),
+        };
+        assert_eq!(
+            parse("Title\n\nBody\n\nFixes: #12\nAcked-by: a\n  and b\n\n# comment\n"),
+            vec![trailer("Fixes", "#12"), trailer("Acked-by", "a and b")]
+        );
+        // the title isn't a trailer block
+        assert_eq!(parse("Fixes: #12\n"), vec![]);
+        // a quarter trailers is enough with a sign-off
+        assert_eq!(
+            parse("Title\n\nnot one\nnor this\nSigned-off-by: a\n"),
+            vec![trailer("Signed-off-by", "a")]
+        );
+        assert_eq!(parse("Title\n\nnot one\nFixes: #12\n"), vec![]);
+        assert_eq!(
+            Trailer::parse("Closes = #1", "=:"),
+            Some(trailer("Closes", "#1"))
+        );
+        assert_eq!(Trailer::parse("not a token: x", ":"), None);
+    }
+
+    #[test]
+    fn test_add() {
+        let signoff = [Trailer {
+            token: "Signed-off-by".to_string(),
+            value: "A <a@example.com>".to_string(),
+        }];
+        let add_default = |message| add(message, &signoff, IfExists::default(), false);
+
+        assert_eq!(
+            add_default("Title\n"),
+            "Title\n\nSigned-off-by: A <a@example.com>\n"
+        );
+        assert_eq!(
+            add_default("Title\n\nBody\n\nFixes: #12\n"),
+            "Title\n\nBody\n\nFixes: #12\nSigned-off-by: A <a@example.com>\n"
+        );
+        // no duplicate next to itself, but after someone else it's fine
+        let signed = "Title\n\nSigned-off-by: A <a@example.com>\n";
+        assert_eq!(add_default(signed), signed);
+        let other = "Title\n\nSigned-off-by: A <a@example.com>\nSigned-off-by: B <b@example.com>\n";
+        assert_eq!(
+            add_default(other),
+            format!("{}Signed-off-by: A <a@example.com>\n", other)
+        );
+        assert_eq!(add(other, &signoff, IfExists::AddIfDifferent, false), other);
+        assert_eq!(
+            add(other, &signoff, IfExists::Replace, false),
+            "Title\n\nSigned-off-by: A <a@example.com>\nSigned-off-by: A <a@example.com>\n"
+        );
+
+        // trailers go before comments and patches
+        assert_eq!(
+            add_default("Title\n\n# comment\n"),
+            "Title\n\nSigned-off-by: A <a@example.com>\n\n# comment\n"
+        );
+        assert_eq!(
+            add_default("Title\n---\n a | 1 +\n"),
+            "Title\n\nSigned-off-by: A <a@example.com>\n---\n a | 1 +\n"
+        );
+        assert_eq!(
+            add("Title\n\nCc:\n", &[], IfExists::default(), true),
+            "Title\n\n"
+        );
+    }
+}
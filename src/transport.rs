@@ -0,0 +1,446 @@
+//! Connections to remote repositories, over which fetch and push speak git's
+//! pack protocol. Where the repository is decides how to get there:
+//!
+//! - `git://host[:port]/path` connects to a `git daemon` over TCP
+//! - `http://` and `https://` URLs speak git's smart HTTP protocol, through
+//!   [`http`](crate::http)
+//! - `ssh://[user@]host[:port]/path` and `[user@]host:path` run the service
+//!   on the host through `ssh`
+//! - `file:///path` and plain paths are served in this process, on a thread
+use anyhow::{anyhow, Context, Result};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use thiserror::Error;
+
+use crate::daemon::{is_git_dir, DEFAULT_PORT};
+use crate::http;
+use crate::objects::Repo;
+use crate::pktline;
+use crate::receive_pack::receive_pack;
+use crate::remote::Callbacks;
+use crate::upload_pack::upload_pack;
+use crate::vfs::{RealFs, Vfs};
+
+/// Errors that can arise while connecting to a remote
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum TransportError {
+    /// The URL is for a protocol we don't speak
+    #[error("Unsupported URL {0}")]
+    Unsupported(String),
+
+    /// A local path doesn't have a repository at it
+    #[error("{0} does not appear to be a git repository")]
+    NotARepo(String),
+
+    /// The service can't be run on a local repository in this process
+    #[error("{0} can't be run on a local repository")]
+    NoLocalService(String),
+
+    /// An ssh host that would be taken as an option by `ssh`
+    #[error("strange hostname '{0}' blocked")]
+    BadHost(String),
+}
+
+/// Where a remote repository is, and how to get to it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Location {
+    /// A `git daemon`, reached over TCP
+    Git {
+        /// Host name, with no port
+        host: String,
+        /// Port to connect to
+        port: u16,
+        /// Path of the repository on the host
+        path: String,
+    },
+    /// A smart HTTP server, by the URL of the repository
+    Http(String),
+    /// A host reached through `ssh`
+    Ssh {
+        /// Host name, with the user to log in as if there is one
+        host: String,
+        /// Port to connect to, if not ssh's default
+        port: Option<u16>,
+        /// Path of the repository on the host
+        path: String,
+    },
+    /// A repository on this machine
+    Local(PathBuf),
+}
+
+impl Location {
+    /// Works out where the repository at `url` is
+    pub fn parse(url: &str) -> Result<Location, TransportError> {
+        let unsupported = || TransportError::Unsupported(url.to_owned());
+        if let Some(rest) = url.strip_prefix("git://") {
+            let (authority, path) = split_authority(rest).ok_or_else(unsupported)?;
+            let (host, port) = split_port(authority).ok_or_else(unsupported)?;
+            return Ok(Location::Git {
+                host: host.to_owned(),
+                port: port.unwrap_or(DEFAULT_PORT),
+                path: path.to_owned(),
+            });
+        }
+        if let Some(rest) = url.strip_prefix("ssh://") {
+            let (authority, path) = split_authority(rest).ok_or_else(unsupported)?;
+            let (host, port) = split_port(authority).ok_or_else(unsupported)?;
+            // like git, ssh://host/~/repo is passed on as ~/repo, which the
+            // service on the host takes as relative to the home directory
+            let path = if path.starts_with("/~") {
+                &path[1..]
+            } else {
+                path
+            };
+            return ssh(host, port, path);
+        }
+        if url.starts_with("http://") || url.starts_with("https://") {
+            return Ok(Location::Http(url.to_owned()));
+        }
+        if let Some(path) = url.strip_prefix("file://") {
+            return Ok(Location::Local(PathBuf::from(path)));
+        }
+        if url.contains("://") {
+            return Err(unsupported());
+        }
+        // scp-like syntax: a colon before any slash, unless it is after a
+        // drive letter as in C:\repo
+        match url.find(':') {
+            Some(colon) if !url[..colon].contains('/') && !is_drive_path(url) => {
+                ssh(&url[..colon], None, &url[colon + 1..])
+            }
+            _ => Ok(Location::Local(PathBuf::from(url))),
+        }
+    }
+}
+
+/// An ssh location, if the host can't be mistaken for an option to `ssh`
+fn ssh(host: &str, port: Option<u16>, path: &str) -> Result<Location, TransportError> {
+    if host.starts_with('-') {
+        return Err(TransportError::BadHost(host.to_owned()));
+    }
+    Ok(Location::Ssh {
+        host: host.to_owned(),
+        port,
+        path: path.to_owned(),
+    })
+}
+
+/// Whether `url` is a Windows path starting with a drive letter
+fn is_drive_path(url: &str) -> bool {
+    let bytes = url.as_bytes();
+    bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && (bytes[2] == b'\\' || bytes[2] == b'/')
+}
+
+/// Splits `host[:port]/path` into the part before the path and the path
+fn split_authority(rest: &str) -> Option<(&str, &str)> {
+    let slash = rest.find('/')?;
+    Some((&rest[..slash], &rest[slash..]))
+}
+
+/// Splits `host[:port]` into the host and the port
+fn split_port(authority: &str) -> Option<(&str, Option<u16>)> {
+    match authority.rfind(':') {
+        Some(colon) => Some((
+            &authority[..colon],
+            Some(authority[colon + 1..].parse().ok()?),
+        )),
+        None => Some((authority, None)),
+    }
+}
+
+/// What is on the other end of a connection
+enum Server {
+    Socket,
+    Process(Child),
+    Thread(JoinHandle<Result<()>>),
+}
+
+/// A connection to a service, such as `git-upload-pack`, for a remote
+/// repository
+pub struct Connection {
+    /// What the service sends
+    pub input: Box<dyn Read + Send>,
+    /// What is sent to the service
+    pub output: Box<dyn Write + Send>,
+    server: Server,
+}
+
+impl Connection {
+    /// Hangs up, then waits for the service to finish and checks that it
+    /// succeeded
+    pub fn close(self) -> Result<()> {
+        let Connection {
+            input,
+            output,
+            server,
+        } = self;
+        drop(output);
+        drop(input);
+        match server {
+            Server::Socket => Ok(()),
+            Server::Process(mut child) => {
+                let status = child.wait()?;
+                if status.success() {
+                    Ok(())
+                } else {
+                    Err(anyhow!("the remote end exited with {}", status))
+                }
+            }
+            Server::Thread(thread) => thread
+                .join()
+                .map_err(|_| anyhow!("the local service panicked"))?,
+        }
+    }
+}
+
+/// Connects to `service` for the repository at `url`. Credentials are asked
+/// for through `callbacks` if the remote wants them.
+pub fn connect(url: &str, service: &str, callbacks: &mut Callbacks) -> Result<Connection> {
+    match Location::parse(url)? {
+        Location::Git { host, port, path } => {
+            let stream = TcpStream::connect((host.as_str(), port))
+                .with_context(|| format!("failed to connect to {}:{}", host, port))?;
+            let mut output = BufWriter::new(stream.try_clone()?);
+            let request = if port == DEFAULT_PORT {
+                format!("{} {}\0host={}\0", service, path, host)
+            } else {
+                format!("{} {}\0host={}:{}\0", service, path, host, port)
+            };
+            pktline::write(&mut output, request.as_bytes())?;
+            output.flush()?;
+            Ok(Connection {
+                input: Box::new(stream),
+                output: Box::new(output),
+                server: Server::Socket,
+            })
+        }
+        Location::Http(url) => {
+            let (input, output) = http::connect(&url, service, callbacks)?;
+            Ok(Connection {
+                input,
+                output,
+                server: Server::Socket,
+            })
+        }
+        Location::Ssh { host, port, path } => {
+            let mut command = Command::new("ssh");
+            if let Some(port) = port {
+                command.arg("-p").arg(port.to_string());
+            }
+            // the path is quoted for the remote shell
+            let remote = format!("{} '{}'", service, path.replace('\'', "'\\''"));
+            // the host can't start with a dash, but make sure ssh takes it
+            // as the host regardless
+            let mut child = command
+                .arg("--")
+                .arg(&host)
+                .arg(remote)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .context("failed to run ssh")?;
+            let input = child.stdout.take().expect("stdout is piped");
+            let output = child.stdin.take().expect("stdin is piped");
+            Ok(Connection {
+                input: Box::new(input),
+                output: Box::new(BufWriter::new(output)),
+                server: Server::Process(child),
+            })
+        }
+        Location::Local(path) => connect_local(&path, service),
+    }
+}
+
+/// Serves a local repository on a thread
+fn connect_local(path: &Path, service: &str) -> Result<Connection> {
+    let dir = find_git_dir(path, &RealFs)
+        .ok_or_else(|| TransportError::NotARepo(path.display().to_string()))?;
+    let mut repo = Repo::with_vfs(dir, Arc::new(RealFs));
+    repo.set_replace_objects(false);
+
+    let (to_server, mut server_input) = pipe();
+    let (server_output, from_server) = pipe();
+    let thread = match service {
+        "git-upload-pack" => thread::spawn(move || {
+            let mut server_output = BufWriter::new(server_output);
+            upload_pack(&repo, &mut server_input, &mut server_output)?;
+            server_output.flush()?;
+            Ok(())
+        }),
+        "git-receive-pack" => thread::spawn(move || {
+            let mut server_output = BufWriter::new(server_output);
+            receive_pack(&repo, &mut BufReader::new(server_input), &mut server_output)?;
+            server_output.flush()?;
+            Ok(())
+        }),
+        _ => return Err(TransportError::NoLocalService(service.to_owned()).into()),
+    };
+    Ok(Connection {
+        input: Box::new(from_server),
+        output: Box::new(BufWriter::new(to_server)),
+        server: Server::Thread(thread),
+    })
+}
+
+/// The .git directory of the repository at `path`, which may be its working
+/// tree or a bare repository
+fn find_git_dir(path: &Path, vfs: &dyn Vfs) -> Option<PathBuf> {
+    [path.join(".git"), path.to_owned()]
+        .iter()
+        .find(|dir| is_git_dir(dir, vfs))
+        .cloned()
+}
+
+/// The writing end of a [`pipe`]
+pub struct PipeWriter(Sender<Vec<u8>>);
+
+/// The reading end of a [`pipe`], which ends once the writer is dropped
+pub struct PipeReader {
+    chunks: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+/// Makes a pipe between threads, for serving a repository in this process
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, chunks) = channel();
+    let reader = PipeReader {
+        chunks,
+        chunk: Vec::new(),
+        pos: 0,
+    };
+    (PipeWriter(sender), reader)
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        // an empty chunk would look like the end of the pipe
+        if data.is_empty() {
+            return Ok(0);
+        }
+        self.0
+            .send(data.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "pipe reader went away"))?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.chunk.len() - self.pos);
+        out[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pipe, Location, TransportError};
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    use std::thread;
+
+    #[test]
+    fn test_parse_location() {
+        let git = |host: &str, port, path: &str| Location::Git {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        };
+        let ssh = |host: &str, port, path: &str| Location::Ssh {
+            host: host.to_owned(),
+            port,
+            path: path.to_owned(),
+        };
+        let cases = vec![
+            (
+                "git://example.com/repo.git",
+                git("example.com", 9418, "/repo.git"),
+            ),
+            (
+                "git://example.com:1234/a/b",
+                git("example.com", 1234, "/a/b"),
+            ),
+            (
+                "ssh://me@example.com/repo",
+                ssh("me@example.com", None, "/repo"),
+            ),
+            (
+                "ssh://example.com:22/~/repo",
+                ssh("example.com", Some(22), "~/repo"),
+            ),
+            (
+                "me@example.com:repo.git",
+                ssh("me@example.com", None, "repo.git"),
+            ),
+            (
+                "file:///srv/repo",
+                Location::Local(PathBuf::from("/srv/repo")),
+            ),
+            (
+                "https://example.com/a/repo.git",
+                Location::Http("https://example.com/a/repo.git".to_owned()),
+            ),
+            ("../repo", Location::Local(PathBuf::from("../repo"))),
+            ("./a:b", Location::Local(PathBuf::from("./a:b"))),
+            ("C:\\repo", Location::Local(PathBuf::from("C:\\repo"))),
+            ("c:/a/repo", Location::Local(PathBuf::from("c:/a/repo"))),
+            ("c:repo", ssh("c", None, "repo")),
+        ];
+        for (url, location) in cases {
+            assert_eq!(Location::parse(url), Ok(location), "{}", url);
+        }
+        assert_eq!(
+            Location::parse("ftp://example.com/repo"),
+            Err(TransportError::Unsupported(
+                "ftp://example.com/repo".to_owned()
+            ))
+        );
+        assert!(Location::parse("git://example.com:port/repo").is_err());
+        // hosts that ssh would take as options
+        assert_eq!(
+            Location::parse("-oProxyCommand=evil:repo"),
+            Err(TransportError::BadHost("-oProxyCommand=evil".to_owned()))
+        );
+        assert_eq!(
+            Location::parse("ssh://-oProxyCommand=evil/repo"),
+            Err(TransportError::BadHost("-oProxyCommand=evil".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_pipe() {
+        let (mut writer, mut reader) = pipe();
+        let thread = thread::spawn(move || {
+            writer.write_all(b"hello ").unwrap();
+            writer.write_all(b"").unwrap();
+            writer.write_all(b"there").unwrap();
+        });
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).unwrap();
+        thread.join().unwrap();
+        assert_eq!(read, b"hello there");
+    }
+}
@@ -1,12 +1,15 @@
 //! Functions for handling git trees as tree structures
 use anyhow::{Context, Result};
+use bstr::{BString, ByteSlice};
 use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::mem;
+use std::sync::Arc;
 use thiserror::Error;
 
-use crate::index::{Index, IndexEntry};
-use crate::objects::{File, Id, Object, Repo, Tree};
+use crate::index::{CacheTree, Index, IndexEntry, IndexMeta};
+use crate::objects::{File, FileMode, Id, Object, Repo, Tree};
+use crate::util::join_git_path;
 
 /// Errors that can arise when working with a tree
 #[derive(Error, Debug)]
@@ -18,13 +21,13 @@ pub enum TreeError {
 
 /// A structure representing a level of a Git tree, with some parts in memory and
 /// some parts in the database
-pub type SubTree = BTreeMap<String, TreeEntry>;
+pub type SubTree = BTreeMap<BString, TreeEntry>;
 
 /// A recursive tree structure based on BTreeMap to represent a repository tree
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum TreeEntry {
-    /// Reference to a Blob stored in the database
-    Blob(Id),
+    /// Reference to a Blob stored in the database, with its file mode
+    Blob(Id, FileMode),
     /// Reference to a Tree stored in the database
     Tree(Id),
     /// A tree in memory that could be stored in the database if it has no
@@ -52,11 +55,10 @@ impl TreeEntry {
 
     /// Generates the expected permissions for a file or directory in git.
     /// Panics if you call it on an unflattened subtree.
-    pub fn perms(&self) -> (&Id, u32) {
-        // TODO: support executable files and symlinks
+    pub fn perms(&self) -> (&Id, FileMode) {
         match self {
-            TreeEntry::Blob(id) => (id, 0o100644),
-            TreeEntry::Tree(id) => (id, 0o040000),
+            TreeEntry::Blob(id, mode) => (id, *mode),
+            TreeEntry::Tree(id) => (id, FileMode::Directory),
             _ => unreachable!("asked for permissions on an unflattened tree {:?}", self),
         }
     }
@@ -80,12 +82,14 @@ where
 /// Finds the differences between two flat, sorted file list iterators. Caller is
 /// expected to ensure they are sorted to avoid unexpected results. Takes a
 /// comparator function to compare the two Ts. This allows avoiding copying or
-/// double-iteration if the thing to be compared is inside the T.
-pub fn diff_file_lists<'name, 'item, A>(
-    left: &mut dyn Iterator<Item = (&'name str, &'item A)>,
-    right: &mut dyn Iterator<Item = (&'name str, &'item A)>,
-) -> Vec<(&'name str, Diff<&'item A, &'item A>)>
+/// double-iteration if the thing to be compared is inside the T. Names can be
+/// anything ordered, such as `&str` or `&BStr`.
+pub fn diff_file_lists<'item, N, A>(
+    left: &mut dyn Iterator<Item = (N, &'item A)>,
+    right: &mut dyn Iterator<Item = (N, &'item A)>,
+) -> Vec<(N, Diff<&'item A, &'item A>)>
 where
+    N: Ord + Copy,
     A: PartialEq + Eq,
 {
     let mut diffs = Vec::new();
@@ -117,7 +121,7 @@ where
             // A:1 A:1
             // ?:? ?:?
             (Some((l, li)), Some((r, ri))) => {
-                match l.cmp(r) {
+                match l.cmp(&r) {
                     // A:1 A:1
                     // B:2 B:2
                     Ordering::Equal if li == ri => {
@@ -174,8 +178,8 @@ pub fn diff_trees(a: &Id, b: &Id, base_path: &str, repo: &Repo) -> Result<Vec<Di
     let ret = Vec::new();
     let a = open_tree(a, repo)?;
     let b = open_tree(b, repo)?;
-    let mut aiter = a.files.iter().map(|file| (file.name.as_str(), file));
-    let mut biter = b.files.iter().map(|file| (file.name.as_str(), file));
+    let mut aiter = a.files.iter().map(|file| (file.name.as_bstr(), file));
+    let mut biter = b.files.iter().map(|file| (file.name.as_bstr(), file));
 
     let diffs = diff_file_lists(&mut aiter, &mut biter);
     for (fname, diff) in diffs {
@@ -191,6 +195,133 @@ pub fn diff_trees(a: &Id, b: &Id, base_path: &str, repo: &Repo) -> Result<Vec<Di
     Ok(ret)
 }
 
+/// A file which differs between two trees
+#[derive(Debug, PartialEq, Eq)]
+pub struct Change {
+    /// Path of the file from the root of the tree
+    pub path: BString,
+    /// The file's ID and mode on the left, or None if it was added
+    pub old: Option<(Id, FileMode)>,
+    /// The file's ID and mode on the right, or None if it was deleted
+    pub new: Option<(Id, FileMode)>,
+}
+
+/// Reads the entries of a tree by plain name, or none if there is no tree.
+/// Trees sort directories as if they had a trailing slash, which
+/// diff_file_lists wouldn't expect.
+fn entries_by_name(id: Option<&Id>, repo: &Repo) -> Result<BTreeMap<BString, (Id, FileMode)>> {
+    let mut entries = BTreeMap::new();
+    if let Some(id) = id {
+        if let Object::Tree(tree) = &*tree_or_err(id, repo)? {
+            for file in &tree.files {
+                entries.insert(file.name.clone(), (file.id, file.mode));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+fn changed_files_into(
+    a: Option<&Id>,
+    b: Option<&Id>,
+    base_path: &[u8],
+    repo: &Repo,
+    changes: &mut Vec<Change>,
+) -> Result<()> {
+    let a = entries_by_name(a, repo)?;
+    let b = entries_by_name(b, repo)?;
+    let mut aiter = a.iter().map(|(name, file)| (name.as_bstr(), file));
+    let mut biter = b.iter().map(|(name, file)| (name.as_bstr(), file));
+
+    for (name, diff) in diff_file_lists(&mut aiter, &mut biter) {
+        let path = join_git_path(base_path, name);
+        let (old, new) = match diff {
+            Diff::Different(l, r) => (Some(*l), Some(*r)),
+            Diff::ExtraInLeft(l) => (Some(*l), None),
+            Diff::ExtraInRight(r) => (None, Some(*r)),
+        };
+        let is_dir = |side: Option<(Id, FileMode)>| side.filter(|(_, mode)| mode.is_dir());
+        let (old_dir, new_dir) = (is_dir(old), is_dir(new));
+        if old_dir.is_some() || new_dir.is_some() {
+            let old_id = old_dir.map(|(id, _)| id);
+            let new_id = new_dir.map(|(id, _)| id);
+            changed_files_into(old_id.as_ref(), new_id.as_ref(), &path, repo, changes)?;
+        }
+        // a file replaced by a directory or the other way around is also a
+        // deletion or addition of the file
+        let old = old.filter(|_| old_dir.is_none());
+        let new = new.filter(|_| new_dir.is_none());
+        if old.is_some() || new.is_some() {
+            changes.push(Change { path, old, new });
+        }
+    }
+    Ok(())
+}
+
+/// Finds the files which differ between two trees, looking into
+/// subdirectories. Either tree may be None to compare against an empty tree, as
+/// for the first commit. Changes are sorted by path.
+pub fn changed_files(a: Option<&Id>, b: Option<&Id>, repo: &Repo) -> Result<Vec<Change>> {
+    let mut changes = Vec::new();
+    changed_files_into(a, b, b"", repo, &mut changes)?;
+    changes.sort_by(|x, y| x.path.cmp(&y.path));
+    Ok(changes)
+}
+
+/// Finds the entry at a slash separated path in a tree, which is a subtree
+/// for a directory. The empty path is the tree itself.
+pub fn entry_at(tree: &Id, path: &[u8], repo: &Repo) -> Result<Option<(Id, FileMode)>> {
+    let mut entry = (*tree, FileMode::Directory);
+    for name in path.split_str("/").filter(|name| !name.is_empty()) {
+        if !entry.1.is_dir() {
+            return Ok(None);
+        }
+        let obj = tree_or_err(&entry.0, repo)?;
+        let files = match &*obj {
+            Object::Tree(tree) => &tree.files,
+            _ => unreachable!(),
+        };
+        entry = match files.iter().find(|file| file.name == name) {
+            Some(file) => (file.id, file.mode),
+            None => return Ok(None),
+        };
+    }
+    Ok(Some(entry))
+}
+
+/// Puts an entry into a SubTree at a slash separated path, making SubTrees for
+/// the directories along the way. Returns false without inserting anything if
+/// some part of the path is already something other than a SubTree.
+pub fn insert_path(root: &mut SubTree, path: &[u8], entry: TreeEntry) -> bool {
+    let mut inserting_into = root;
+
+    let mut parts = path.split(|&b| b == b'/').peekable();
+    let filename;
+
+    // Get a reference to the SubTree of the last directory in the path
+    // XXX: are there symlink bugs?
+    loop {
+        let part = parts.next().unwrap();
+        // Exclude the last element
+        if parts.peek().is_none() {
+            filename = part;
+            break;
+        }
+
+        inserting_into = match inserting_into
+            .entry(part.into())
+            .or_insert_with(|| TreeEntry::SubTree(SubTree::new()))
+            .subtree_mut()
+        {
+            Some(st) => st,
+            None => return false,
+        };
+    }
+
+    inserting_into.insert(filename.into(), entry);
+    true
+}
+
 /// Makes a SubTree object out of the tree in the index
 pub fn index_to_tree(index: &Index) -> SubTree {
     let mut root_st = SubTree::new();
@@ -200,38 +331,43 @@ pub fn index_to_tree(index: &Index) -> SubTree {
         meta: entry,
     } in index
     {
-        let mut inserting_into = &mut root_st;
-
-        let mut parts = path.split("/").peekable();
-        let filename;
-
-        // Get a reference to the SubTree of the last directory in the path
-        // XXX: are there symlink bugs?
-        loop {
-            let part = parts.next().unwrap();
-            // Exclude the last element
-            if parts.peek().is_none() {
-                filename = part;
-                break;
-            }
-
-            inserting_into = inserting_into
-                .entry(part.to_string())
-                .or_insert_with(|| TreeEntry::SubTree(SubTree::new()))
-                .subtree_mut()
-                .expect("component was not a directory?!");
-        }
-
-        inserting_into.insert(filename.to_string(), TreeEntry::Blob(entry.id));
+        let inserted = insert_path(
+            &mut root_st,
+            path,
+            TreeEntry::Blob(entry.id, entry.file_mode()),
+        );
+        assert!(inserted, "component was not a directory?!");
     }
     root_st
 }
 
-/// Load a tree by ID, ensuring that it is in fact a tree
-fn tree_or_err(id: &Id, repo: &Repo) -> Result<Tree> {
-    let obj = repo.open(id)?;
-    match obj {
-        Object::Tree(tree) => Ok(tree),
+/// Makes an index holding the files of a tree. Entries in `old` for files
+/// with the same content and mode keep their stats, so that files which
+/// haven't changed on disk still look up to date.
+pub fn tree_to_index(tree: &Tree, old: &Index, repo: &Repo) -> Result<Index> {
+    let mut files = Vec::new();
+    load_tree_from_disk(tree, repo, b"", &mut files)?;
+    let entries = files
+        .into_iter()
+        .map(|(name, id, mode)| {
+            let meta = match old.binary_search_by(|e| e.name.cmp(&name)) {
+                Ok(n) if old[n].meta.id == id && old[n].meta.file_mode() == mode => {
+                    old[n].meta.clone()
+                }
+                _ => IndexMeta::for_blob(&name, id, mode),
+            };
+            IndexEntry { name, meta }
+        })
+        .collect::<Vec<_>>();
+    Ok(entries.into())
+}
+
+/// Load a tree by ID through the repo's cache, ensuring that it is in fact a
+/// tree
+fn tree_or_err(id: &Id, repo: &Repo) -> Result<Arc<Object>> {
+    let obj = repo.open_shared(id)?;
+    match *obj {
+        Object::Tree(_) => Ok(obj),
         _ => Err(anyhow::Error::new(TreeError::BadId(id.clone()))),
     }
 }
@@ -239,33 +375,135 @@ fn tree_or_err(id: &Id, repo: &Repo) -> Result<Tree> {
 /// Loads a Tree from the database and turns it into a realized SubTree structure
 /// for processing
 pub fn load_tree_from_disk(
-    tree: Tree,
+    tree: &Tree,
     repo: &Repo,
-    base_path: &str,
-    filelist: &mut Vec<(String, Id)>,
+    base_path: &[u8],
+    filelist: &mut Vec<(BString, Id, FileMode)>,
 ) -> Result<()> {
     // TODO: probably should limit stack depth
 
-    for item in tree.files {
+    for item in &tree.files {
         let is_dir = item.is_dir();
 
-        let path = if base_path == "" {
-            item.name
-        } else {
-            [base_path, &item.name].join("/")
-        };
+        let path = join_git_path(base_path, &item.name);
 
         if is_dir {
             // if it's a directory we should recurse down and grab all its files
-            load_tree_from_disk(tree_or_err(&item.id, repo)?, repo, &path, filelist)?;
+            let subtree = tree_or_err(&item.id, repo)?;
+            if let Object::Tree(subtree) = &*subtree {
+                load_tree_from_disk(subtree, repo, &path, filelist)?;
+            }
         } else {
             // we can stuff the file straight into the file list
-            filelist.push((path, item.id));
+            filelist.push((path, item.id, item.mode));
         }
     }
     Ok(())
 }
 
+/// Like `load_tree_from_disk`, but skips directories whose tree ID matches the
+/// valid cached tree for the same directory in the index, since the index holds
+/// exactly the files of that tree. The paths of skipped directories are put in
+/// `skipped`.
+pub fn load_tree_from_disk_cached(
+    tree: &Tree,
+    repo: &Repo,
+    base_path: &[u8],
+    cache: Option<&CacheTree>,
+    filelist: &mut Vec<(BString, Id, FileMode)>,
+    skipped: &mut Vec<BString>,
+) -> Result<()> {
+    for item in &tree.files {
+        let path = join_git_path(base_path, &item.name);
+
+        if !item.is_dir() {
+            filelist.push((path, item.id, item.mode));
+            continue;
+        }
+
+        let cached = cache.and_then(|c| c.find(&item.name));
+        if cached.and_then(|c| c.id) == Some(item.id) {
+            skipped.push(path);
+            continue;
+        }
+        let subtree = tree_or_err(&item.id, repo)?;
+        if let Object::Tree(subtree) = &*subtree {
+            load_tree_from_disk_cached(subtree, repo, &path, cached, filelist, skipped)?;
+        }
+    }
+    Ok(())
+}
+
+/// Saves the tree described by the index, reusing the IDs of directories that
+/// the index's cached tree says haven't changed rather than writing them again.
+/// The cached tree is updated with everything that was written, so it should
+/// be saved with the index afterwards.
+pub fn write_index_tree(index: &mut Index, repo: &Repo) -> Result<Id> {
+    let Index {
+        entries,
+        cache_tree,
+    } = index;
+    let cache = cache_tree.get_or_insert_with(Default::default);
+    write_cached_subtree(entries, 0, cache, repo)
+}
+
+/// Saves the directory holding `entries`, which all start with the same
+/// `prefix_len` bytes of directory path
+fn write_cached_subtree(
+    entries: &[IndexEntry],
+    prefix_len: usize,
+    cache: &mut CacheTree,
+    repo: &Repo,
+) -> Result<Id> {
+    if let Some(id) = cache.id {
+        if cache.entry_count as usize == entries.len() {
+            return Ok(id);
+        }
+    }
+
+    let mut st = SubTree::new();
+    let mut rest = entries;
+    while let Some(first) = rest.first() {
+        let name = &first.name[prefix_len..];
+        match name.find_byte(b'/') {
+            None => {
+                st.insert(
+                    name.into(),
+                    TreeEntry::Blob(first.meta.id, first.meta.file_mode()),
+                );
+                rest = &rest[1..];
+            }
+            Some(slash) => {
+                // the index is sorted, so everything in this directory is
+                // right here together
+                let dir = &name[..=slash];
+                let count = rest
+                    .iter()
+                    .take_while(|e| e.name[prefix_len..].starts_with(dir))
+                    .count();
+                let dir = &name[..slash];
+                let id = write_cached_subtree(
+                    &rest[..count],
+                    prefix_len + slash + 1,
+                    cache.child_mut(dir),
+                    repo,
+                )?;
+                st.insert(dir.into(), TreeEntry::Tree(id));
+                rest = &rest[count..];
+            }
+        }
+    }
+
+    let id = save_subtree_to_disk(&st, repo)?;
+    cache.id = Some(id);
+    cache.entry_count = entries.len() as u32;
+    // forget about directories that no longer exist
+    cache
+        .children
+        .retain(|(name, _)| matches!(st.get(name), Some(TreeEntry::Tree(_))));
+    Ok(id)
+}
+
 /// Saves a *flattened* tree to disk
 /// Warning: it will panic if the tree is not flat!
 pub fn save_subtree_to_disk(st: &SubTree, repo: &Repo) -> Result<Id> {
@@ -283,28 +521,219 @@ pub fn save_subtree_to_disk(st: &SubTree, repo: &Repo) -> Result<Id> {
     repo.store(&tree).context("error storing file in repo")
 }
 
-/// Saves an unflattened subtree to disk
+/// Removes SubTrees that have nothing in them, or nothing but other empty
+/// SubTrees, since git never puts empty trees inside others
+fn prune_empty(st: &mut SubTree) {
+    let empty = st
+        .iter_mut()
+        .filter_map(|(name, entry)| match entry {
+            TreeEntry::SubTree(child) => {
+                prune_empty(child);
+                if child.is_empty() {
+                    Some(name.clone())
+                } else {
+                    None
+                }
+            }
+            TreeEntry::Blob(..) | TreeEntry::Tree(_) => None,
+        })
+        .collect::<Vec<_>>();
+    for name in empty {
+        st.remove(&name);
+    }
+}
+
+/// Saves an unflattened subtree to disk. Directories left with no files in
+/// them are dropped rather than saved as empty trees.
 pub fn save_subtree(subtree: &mut TreeEntry, repo: &Repo) -> Result<Id> {
-    for (_, st) in subtree.subtree_mut().unwrap() {
+    let st = subtree.subtree_mut().unwrap();
+    prune_empty(st);
+    save_pruned_subtree(st, repo)
+}
+
+/// Saves a subtree that has no empty SubTrees in it
+fn save_pruned_subtree(subtree: &mut SubTree, repo: &Repo) -> Result<Id> {
+    for (_, st) in subtree.iter_mut() {
         match st {
-            TreeEntry::SubTree(_) => {
-                let saved = TreeEntry::Tree(save_subtree(st, repo)?);
+            TreeEntry::SubTree(child) => {
+                let saved = TreeEntry::Tree(save_pruned_subtree(child, repo)?);
                 mem::replace(st, saved);
             }
-            TreeEntry::Blob(_) | TreeEntry::Tree(_) => {
+            TreeEntry::Blob(..) | TreeEntry::Tree(_) => {
                 // we don't need to save these
             }
         }
     }
     // if we've escaped this loop, there are no more subtrees in our subtree. We
     // may save it now
-    save_subtree_to_disk(subtree.subtree().unwrap(), repo)
+    save_subtree_to_disk(subtree, repo)
 }
 
 #[cfg(test)]
 mod test {
-    use super::Diff;
-    use crate::objects::Id;
+    use super::{
+        changed_files, entry_at, index_to_tree, save_subtree, tree_to_index, Change, Diff,
+        TreeEntry,
+    };
+    use crate::index::{self, IndexEntry};
+    use crate::objects::{Blob, File, FileMode, Id, Object, Repo, Tree};
+    use crate::vfs::{MemoryFs, Vfs};
+    use bstr::ByteSlice;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_prune_empty_dirs() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        fs.create_dir_all(Path::new("/repo/a/b")).unwrap();
+        for name in &["a/b/deep", "a/shallow", "top"] {
+            fs.write(&Path::new("/repo").join(name), name.as_bytes())
+                .unwrap();
+        }
+
+        let mut idx = repo.index().unwrap();
+        for name in &["a/b/deep", "a/shallow", "top"] {
+            let meta = index::IndexMeta::new_from_file(name.as_bytes(), &repo).unwrap();
+            index::insert_entry(
+                &mut idx,
+                IndexEntry {
+                    name: (*name).into(),
+                    meta,
+                },
+            );
+        }
+
+        // removing the last file in a/b leaves a with only a file in it
+        index::remove_entry(&mut idx, b"a/b/deep");
+        let mut st = TreeEntry::SubTree(index_to_tree(&idx));
+        let a = st.subtree_mut().unwrap().get_mut(b"a".as_bstr()).unwrap();
+        // an empty directory left over from editing the tree in memory
+        a.subtree_mut()
+            .unwrap()
+            .insert("b".into(), TreeEntry::SubTree(Default::default()));
+        let id = save_subtree(&mut st, &repo).unwrap();
+        let tree = repo.open(&id).unwrap().tree().unwrap();
+        let a = match repo.open(&tree.files[0].id).unwrap() {
+            Object::Tree(t) => t,
+            _ => panic!("a is not a tree"),
+        };
+        assert_eq!(a.files.len(), 1);
+        assert_eq!(a.files[0].name, "shallow");
+
+        // removing everything under a removes a too
+        index::remove_entry(&mut idx, b"a/shallow");
+        let mut st = TreeEntry::SubTree(index_to_tree(&idx));
+        st.subtree_mut()
+            .unwrap()
+            .insert("empty".into(), TreeEntry::SubTree(Default::default()));
+        let id = save_subtree(&mut st, &repo).unwrap();
+        let tree = repo.open(&id).unwrap().tree().unwrap();
+        assert_eq!(tree.files.len(), 1);
+        assert_eq!(tree.files[0].name, "top");
+        let top = repo.store(&Blob::new(b"top".to_vec())).unwrap();
+        assert_eq!(tree.files[0].id, top);
+    }
+
+    #[test]
+    fn test_tree_to_index() {
+        let fs = Arc::new(MemoryFs::new());
+        let repo = Repo::in_memory_at(&fs, "/repo");
+        fs.create_dir(Path::new("/repo/dir")).unwrap();
+        fs.write(Path::new("/repo/dir/same"), b"same\n").unwrap();
+        fs.write(Path::new("/repo/top"), b"old\n").unwrap();
+        let mut old = repo.index().unwrap();
+        for name in &["dir/same", "top"] {
+            index::add_to_index(&mut old, name.as_bytes(), &repo).unwrap();
+        }
+
+        let new = repo.store(&Blob::new(b"new\n".to_vec())).unwrap();
+        let mut st = index_to_tree(&old);
+        st.insert("top".into(), TreeEntry::Blob(new, FileMode::Executable));
+        let id = save_subtree(&mut TreeEntry::SubTree(st), &repo).unwrap();
+        let tree = repo.open(&id).unwrap().tree().unwrap();
+
+        let index = tree_to_index(&tree, &old, &repo).unwrap();
+        assert_eq!(index.len(), 2);
+        // the unchanged file is still up to date
+        assert_eq!(index[0], old[0]);
+        assert_eq!(index[1].name, "top");
+        assert_eq!(index[1].meta.id, new);
+        assert_eq!(index[1].meta.file_mode(), FileMode::Executable);
+        assert!(!index[1].is_same_as_tree(&repo).unwrap());
+        let mut st = TreeEntry::SubTree(index_to_tree(&index));
+        assert_eq!(save_subtree(&mut st, &repo).unwrap(), id);
+    }
+
+    #[test]
+    fn test_changed_files() {
+        let repo = Repo::in_memory();
+        let one = repo.store(&Blob::new(b"1\n".to_vec())).unwrap();
+        let two = repo.store(&Blob::new(b"2\n".to_vec())).unwrap();
+        let file = |name: &str, mode, id| File {
+            mode,
+            name: name.into(),
+            id,
+        };
+
+        let sub = repo
+            .store(&Tree {
+                files: vec![file("inner", FileMode::Regular, one)],
+            })
+            .unwrap();
+        let before = repo
+            .store(&Tree {
+                files: vec![
+                    file("a", FileMode::Regular, one),
+                    file("b", FileMode::Regular, one),
+                    file("dir", FileMode::Directory, sub),
+                ],
+            })
+            .unwrap();
+        let after = repo
+            .store(&Tree {
+                files: vec![
+                    file("a", FileMode::Executable, one),
+                    file("b", FileMode::Directory, sub),
+                    file("c", FileMode::Regular, two),
+                ],
+            })
+            .unwrap();
+
+        let changes = changed_files(Some(&before), Some(&after), &repo).unwrap();
+        let change = |path: &str, old, new| Change {
+            path: path.into(),
+            old,
+            new,
+        };
+        assert_eq!(
+            changes,
+            vec![
+                change(
+                    "a",
+                    Some((one, FileMode::Regular)),
+                    Some((one, FileMode::Executable))
+                ),
+                change("b", Some((one, FileMode::Regular)), None),
+                change("b/inner", None, Some((one, FileMode::Regular))),
+                change("c", None, Some((two, FileMode::Regular))),
+                change("dir/inner", Some((one, FileMode::Regular)), None),
+            ]
+        );
+
+        let everything = changed_files(None, Some(&before), &repo).unwrap();
+        assert_eq!(everything.len(), 3);
+        assert!(changed_files(Some(&after), Some(&after), &repo)
+            .unwrap()
+            .is_empty());
+
+        let at = |path: &str| entry_at(&before, path.as_bytes(), &repo).unwrap();
+        assert_eq!(at("dir/inner"), Some((one, FileMode::Regular)));
+        assert_eq!(at("dir/"), Some((sub, FileMode::Directory)));
+        assert_eq!(at(""), Some((before, FileMode::Directory)));
+        assert_eq!(at("a/inner"), None);
+        assert_eq!(at("c"), None);
+    }
 
     #[test]
     fn test_tree_comparison() {
@@ -0,0 +1,241 @@
+//! The server side of fetch and clone: version 0 of git's pack protocol, as
+//! spoken over `git://` and ssh. We advertise our refs, the client says which
+//! it wants and which commits it has, and we send a pack of what it is missing.
+//!
+//! No capabilities are offered besides our agent, so clients fall back to the
+//! simplest form of the protocol: one round of negotiation without multi_ack,
+//! and the pack sent as is rather than multiplexed with progress messages.
+use anyhow::{Error, Result};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use thiserror::Error;
+
+use crate::objects::{Id, Repo};
+use crate::packs;
+use crate::pktline::{self, Packet};
+use crate::rev::{self, Head};
+use crate::revwalk;
+use crate::trace;
+
+/// Errors that can arise while serving a fetch
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum UploadPackError {
+    /// The client sent something other than what the protocol allows here
+    #[error("Protocol error: expected {expected}, got {got:?}")]
+    Protocol {
+        /// What was expected
+        expected: &'static str,
+        /// The offending line
+        got: String,
+    },
+
+    /// The client asked for an object that isn't at the tip of a ref
+    #[error("Not our ref {0}")]
+    NotOurRef(Id),
+
+    /// The client hung up before it was done asking
+    #[error("Client disconnected during negotiation")]
+    Disconnected,
+}
+
+/// Capabilities sent with the first ref
+fn capabilities() -> String {
+    format!("agent=rgit/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Sends the ref advertisement, returning the IDs that may be asked for
+fn advertise(repo: &Repo, output: &mut impl Write) -> Result<HashSet<Id>> {
+    let mut refs = Vec::new();
+    if let Head::Commit(id) = rev::read_head(repo)? {
+        refs.push(("HEAD".to_string(), id));
+    }
+    refs.extend(rev::list_refs(repo)?);
+
+    if refs.is_empty() {
+        // an empty repository still needs somewhere to put the capabilities
+        let line = format!(
+            "{} capabilities^{{}}\0{}\n",
+            Id::from_bytes([0; 20]),
+            capabilities()
+        );
+        pktline::write(output, line.as_bytes())?;
+    }
+    for (n, (name, id)) in refs.iter().enumerate() {
+        let line = if n == 0 {
+            format!("{} {}\0{}\n", id, name, capabilities())
+        } else {
+            format!("{} {}\n", id, name)
+        };
+        pktline::write(output, line.as_bytes())?;
+    }
+    pktline::flush(output)?;
+    output.flush()?;
+    Ok(refs.into_iter().map(|(_, id)| id).collect())
+}
+
+/// Parses a line of the form `<command> <id>[ <anything>]`
+fn parse_id_line(line: &[u8], command: &'static str) -> Result<Id> {
+    let bad = || {
+        Error::new(UploadPackError::Protocol {
+            expected: command,
+            got: String::from_utf8_lossy(line).into_owned(),
+        })
+    };
+    let line = std::str::from_utf8(line).map_err(|_| bad())?;
+    let mut parts = line.split(' ');
+    if parts.next() != Some(command) {
+        return Err(bad());
+    }
+    parts.next().and_then(Id::from).ok_or_else(bad)
+}
+
+/// Reads the client's wants. There are none if the client only wanted to list
+/// refs, in which case it may just hang up.
+fn read_wants(input: &mut impl Read) -> Result<Vec<Id>> {
+    let mut wants = Vec::new();
+    loop {
+        match pktline::read(input)? {
+            None if wants.is_empty() => return Ok(wants),
+            None => return Err(Error::new(UploadPackError::Disconnected)),
+            Some(Packet::Flush) => return Ok(wants),
+            // the first want also carries the client's capabilities
+            Some(packet) => wants.push(parse_id_line(packet.line().unwrap(), "want")?),
+        }
+    }
+}
+
+/// Reads the client's haves until it says it's done, acknowledging the first
+/// one we have as well. Returns the haves that we have.
+fn negotiate(repo: &Repo, input: &mut impl Read, output: &mut impl Write) -> Result<Vec<Id>> {
+    let mut common = Vec::new();
+    loop {
+        let packet = pktline::read(input)?.ok_or(UploadPackError::Disconnected)?;
+        let line = match packet.line() {
+            Some(line) => line,
+            // the end of a batch of haves
+            None => {
+                if common.is_empty() {
+                    pktline::write(output, b"NAK\n")?;
+                    output.flush()?;
+                }
+                continue;
+            }
+        };
+        if line == b"done" {
+            if common.is_empty() {
+                pktline::write(output, b"NAK\n")?;
+            }
+            return Ok(common);
+        }
+
+        let id = parse_id_line(line, "have")?;
+        if repo.has_id(&id) {
+            if common.is_empty() {
+                pktline::write(output, format!("ACK {}\n", id).as_bytes())?;
+                output.flush()?;
+            }
+            common.push(id);
+        }
+    }
+}
+
+/// Serves one fetch from `repo`, reading the client's requests from `input`
+/// and answering on `output`
+pub fn upload_pack(repo: &Repo, input: &mut impl Read, output: &mut impl Write) -> Result<()> {
+    let _region = trace::region("transport", "upload_pack");
+    let advertised = advertise(repo, output)?;
+
+    let wants = read_wants(input)?;
+    if wants.is_empty() {
+        return Ok(());
+    }
+    if let Some(want) = wants.iter().find(|id| !advertised.contains(id)) {
+        let err = UploadPackError::NotOurRef(*want);
+        pktline::write(output, format!("ERR upload-pack: {}", err).as_bytes())?;
+        return Err(err.into());
+    }
+    let common = {
+        let _region = trace::region("transport", "negotiate");
+        negotiate(repo, input, output)?
+    };
+    trace::message(format_args!(
+        "upload-pack: {} wants, {} common",
+        wants.len(),
+        common.len()
+    ));
+
+    let objects = revwalk::reachable_objects(repo, &wants, &common)?;
+    packs::write_pack(repo, &objects, &mut *output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::upload_pack;
+    use crate::objects::{Blob, Commit, File, FileMode, NameEntry, Repo, Tree};
+    use crate::pktline::{self, Packet};
+
+    #[test]
+    fn test_upload_pack() {
+        let repo = Repo::in_memory();
+        let who = NameEntry::from("a <a@example.com> 1586391037 -0700").unwrap();
+        let blob = repo.store(&Blob::new(b"hello\n".to_vec())).unwrap();
+        let tree = Tree {
+            files: vec![File {
+                mode: FileMode::Regular,
+                name: "hello".into(),
+                id: blob,
+            }],
+        };
+        let commit = Commit {
+            tree: repo.store(&tree).unwrap(),
+            parents: vec![],
+            author: who.clone(),
+            committer: who,
+            message: "hello\n".to_string(),
+        };
+        let commit = repo.store(&commit).unwrap();
+        repo.set_head(&commit, "").unwrap();
+
+        // a clone: one want, no haves
+        let mut input = Vec::new();
+        pktline::write(
+            &mut input,
+            format!("want {} agent=git/2.28\n", commit).as_bytes(),
+        )
+        .unwrap();
+        pktline::flush(&mut input).unwrap();
+        pktline::write(&mut input, b"done\n").unwrap();
+
+        let mut output = Vec::new();
+        upload_pack(&repo, &mut &input[..], &mut output).unwrap();
+
+        let mut reply = &output[..];
+        let first = pktline::read(&mut reply).unwrap().unwrap();
+        assert!(first
+            .line()
+            .unwrap()
+            .starts_with(format!("{} HEAD\0agent=rgit/", commit).as_bytes()));
+        let second = pktline::read(&mut reply).unwrap().unwrap();
+        assert_eq!(
+            second.line().unwrap(),
+            format!("{} refs/heads/master", commit).as_bytes()
+        );
+        assert_eq!(pktline::read(&mut reply).unwrap(), Some(Packet::Flush));
+        assert_eq!(
+            pktline::read(&mut reply).unwrap().unwrap().line(),
+            Some(&b"NAK"[..])
+        );
+        // commit, tree and blob
+        assert_eq!(&reply[..12], b"PACK\0\0\0\x02\0\0\0\x03");
+
+        // only refs may be asked for
+        let mut input = Vec::new();
+        pktline::write(&mut input, format!("want {}\n", blob).as_bytes()).unwrap();
+        pktline::flush(&mut input).unwrap();
+        assert!(upload_pack(&repo, &mut &input[..], &mut Vec::new()).is_err());
+
+        // listing refs only
+        assert!(upload_pack(&repo, &mut &b""[..], &mut Vec::new()).is_ok());
+    }
+}
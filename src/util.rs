@@ -1,8 +1,23 @@
 //! Helpers for simplifying commonly-used patterns in Git
+use bstr::{BString, ByteSlice, ByteVec};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::Serializer;
 use std::ascii;
-use std::path::Path;
+use std::borrow::Cow;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+use thiserror::Error;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
 
-/// A path in Git format: UTF-8 with forward slash as delimiter
+/// The line after which `--cleanup=scissors` drops everything, which git puts
+/// above the diff in `commit --verbose`
+pub const SCISSORS: &str = "# ------------------------ >8 ------------------------";
+
+/// A path in Git format: bytes with forward slash as delimiter
 pub trait GitPath {
     /// Stringifies a path in Git format
     ///
@@ -17,6 +32,12 @@ pub trait GitPath {
     ///
     /// This function copies the path into a new String.
     fn to_git_path(&self) -> Option<String>;
+
+    /// Converts a path to the bytes git stores for it, which is how file names
+    /// are kept in trees and the index. This accepts any path on Unix, where
+    /// paths are just bytes. Elsewhere, paths that aren't valid Unicode get
+    /// replacement characters.
+    fn to_git_bytes(&self) -> BString;
 }
 
 impl GitPath for Path {
@@ -28,6 +49,296 @@ impl GitPath for Path {
         }
         Some(parts.join("/"))
     }
+
+    fn to_git_bytes(&self) -> BString {
+        let mut bytes = Vec::new();
+        for part in self.iter() {
+            if !bytes.is_empty() {
+                bytes.push(b'/');
+            }
+            bytes.extend_from_slice(&Vec::from_os_str_lossy(part));
+        }
+        bytes.into()
+    }
+}
+
+/// Converts a repo-relative path as git stores it into a platform path. This is
+/// the reverse of [`GitPath::to_git_bytes`]. On Windows, slashes become
+/// backslashes, since some ways of naming files there only accept those.
+pub fn to_platform_path(path: &[u8]) -> Cow<'_, Path> {
+    if cfg!(windows) && path.contains(&b'/') {
+        return Cow::Owned(path.replace("/", "\\").to_path_lossy().into_owned());
+    }
+    path.to_path_lossy()
+}
+
+/// Joins a file name onto a repo-relative directory path, which may be empty
+/// for the top of the tree
+pub fn join_git_path(base: &[u8], name: &[u8]) -> BString {
+    let mut joined = Vec::with_capacity(base.len() + name.len() + 1);
+    if !base.is_empty() {
+        joined.extend_from_slice(base);
+        joined.push(b'/');
+    }
+    joined.extend_from_slice(name);
+    joined.into()
+}
+
+/// Converts a file name to Unicode normalization form C, which is how git
+/// stores names. macOS hands out names in decomposed form, so without this a
+/// file added from there doesn't match its name in trees made elsewhere. Names
+/// that aren't UTF-8 are left alone.
+pub fn precompose_unicode(name: BString) -> BString {
+    match name.to_str() {
+        Ok(s) if !s.is_ascii() && !is_nfc(s) => s.nfc().collect::<String>().into(),
+        _ => name,
+    }
+}
+
+/// Cleans up a commit or tag message the way git does: trailing whitespace is
+/// trimmed from every line, runs of blank lines become one, blank lines at the
+/// start and end are dropped and the result ends in a newline. With
+/// `strip_comments`, lines starting with `#` are dropped first.
+pub fn stripspace(text: &str, strip_comments: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+    for line in text.lines() {
+        if strip_comments && line.starts_with('#') {
+            continue;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            blank = true;
+            continue;
+        }
+        if blank && !out.is_empty() {
+            out.push('\n');
+        }
+        blank = false;
+        out.push_str(line);
+        out.push('\n');
+    }
+    out
+}
+
+/// How a commit message is cleaned up before committing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cleanup {
+    /// `Strip` if the message was edited, otherwise `Whitespace`
+    Default,
+    /// Clean up whitespace and drop `#` comments
+    Strip,
+    /// Clean up whitespace but keep comments
+    Whitespace,
+    /// Leave the message exactly as it is
+    Verbatim,
+    /// Like `Whitespace`, but if the message was edited, drop everything from
+    /// the scissors line on
+    Scissors,
+}
+
+/// A cleanup mode that isn't one
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid cleanup mode {0}")]
+pub struct InvalidCleanup(String);
+
+impl FromStr for Cleanup {
+    type Err = InvalidCleanup;
+
+    fn from_str(s: &str) -> Result<Cleanup, InvalidCleanup> {
+        match s {
+            "default" => Ok(Cleanup::Default),
+            "strip" => Ok(Cleanup::Strip),
+            "whitespace" => Ok(Cleanup::Whitespace),
+            "verbatim" => Ok(Cleanup::Verbatim),
+            "scissors" => Ok(Cleanup::Scissors),
+            _ => Err(InvalidCleanup(s.to_string())),
+        }
+    }
+}
+
+impl Cleanup {
+    /// Works out what `Default` means for a message that was or wasn't edited
+    pub fn resolve(self, edited: bool) -> Cleanup {
+        match self {
+            Cleanup::Default if edited => Cleanup::Strip,
+            Cleanup::Default => Cleanup::Whitespace,
+            Cleanup::Scissors if !edited => Cleanup::Whitespace,
+            mode => mode,
+        }
+    }
+
+    /// Cleans up a message. `Default` cleans up as if it wasn't edited.
+    pub fn apply(self, message: &str) -> String {
+        match self {
+            Cleanup::Strip => stripspace(message, true),
+            Cleanup::Default | Cleanup::Whitespace => stripspace(message, false),
+            Cleanup::Verbatim => message.to_string(),
+            Cleanup::Scissors => {
+                let end = message
+                    .lines()
+                    .scan(0, |at, line| {
+                        let start = *at;
+                        *at += line.len() + 1;
+                        Some((start, line))
+                    })
+                    .find(|(_, line)| *line == SCISSORS)
+                    .map_or(message.len(), |(start, _)| start);
+                stripspace(&message[..end], false)
+            }
+        }
+    }
+}
+
+/// The current time in the local time zone
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> DateTime<FixedOffset> {
+    let now = chrono::Local::now();
+    now.with_timezone(now.offset())
+}
+
+/// There's no clock to ask on wasm32, so this is the epoch in UTC. Anything
+/// that cares about the time there has to take it from the caller.
+#[cfg(target_arch = "wasm32")]
+pub fn now() -> DateTime<FixedOffset> {
+    use chrono::TimeZone;
+    FixedOffset::east(0).timestamp(0, 0)
+}
+
+/// The current time in UTC, which on wasm32 is the epoch like `now`
+pub fn utc_now() -> DateTime<Utc> {
+    now().with_timezone(&Utc)
+}
+
+/// Measures how long something takes. There's no clock on wasm32, so there
+/// everything takes no time at all.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    #[cfg(not(target_arch = "wasm32"))]
+    started: Instant,
+}
+
+impl Stopwatch {
+    /// Starts timing from now
+    pub fn start() -> Stopwatch {
+        Stopwatch {
+            #[cfg(not(target_arch = "wasm32"))]
+            started: Instant::now(),
+        }
+    }
+
+    /// How long it has been since the stopwatch was started
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// How long it has been since the stopwatch was started
+    #[cfg(target_arch = "wasm32")]
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+/// ID of this process, to keep the temporary files of processes working on
+/// the same repo apart
+#[cfg(not(target_arch = "wasm32"))]
+pub fn process_id() -> u32 {
+    std::process::id()
+}
+
+/// There's only the one process on wasm32
+#[cfg(target_arch = "wasm32")]
+pub fn process_id() -> u32 {
+    0
+}
+
+/// Expands a leading `~/` in a path from the config to the home directory
+pub fn expand_user_path(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Quotes a path the way git does in its output when it has control
+/// characters, quotes, backslashes or bytes outside ASCII in it: in double
+/// quotes, with C escapes. None if it doesn't need quoting.
+pub fn quote_c_style(path: &[u8]) -> Option<String> {
+    let needs_quoting = |c: u8| c < 0x20 || c >= 0x7f || c == b'"' || c == b'\\';
+    if !path.iter().any(|&c| needs_quoting(c)) {
+        return None;
+    }
+    let mut quoted = String::with_capacity(path.len() + 2);
+    quoted.push('"');
+    for &c in path {
+        match c {
+            b'\x07' => quoted.push_str("\\a"),
+            b'\x08' => quoted.push_str("\\b"),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            b'\x0b' => quoted.push_str("\\v"),
+            b'\x0c' => quoted.push_str("\\f"),
+            b'\r' => quoted.push_str("\\r"),
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            c if needs_quoting(c) => quoted.push_str(&format!("\\{:03o}", c)),
+            c => quoted.push(c as char),
+        }
+    }
+    quoted.push('"');
+    Some(quoted)
+}
+
+/// Reads a path quoted by [`quote_c_style`] from the start of `text`, which
+/// starts with the opening quote. Returns the path and how many bytes of
+/// `text` it took up, or None if it isn't quoted properly.
+pub fn unquote_c_style(text: &[u8]) -> Option<(BString, usize)> {
+    if text.first() != Some(&b'"') {
+        return None;
+    }
+    let mut path = BString::from(Vec::new());
+    let mut i = 1;
+    loop {
+        match *text.get(i)? {
+            b'"' => return Some((path, i + 1)),
+            b'\\' => {
+                let c = *text.get(i + 1)?;
+                i += 2;
+                path.push(match c {
+                    b'a' => b'\x07',
+                    b'b' => b'\x08',
+                    b't' => b'\t',
+                    b'n' => b'\n',
+                    b'v' => b'\x0b',
+                    b'f' => b'\x0c',
+                    b'r' => b'\r',
+                    b'"' | b'\\' => c,
+                    b'0'..=b'3' => {
+                        let digits = text.get(i..i + 2)?;
+                        if !digits.iter().all(|d| (b'0'..=b'7').contains(d)) {
+                            return None;
+                        }
+                        i += 2;
+                        (c - b'0') << 6 | (digits[0] - b'0') << 3 | (digits[1] - b'0')
+                    }
+                    _ => return None,
+                });
+            }
+            c => {
+                path.push(c);
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Serializes bytes as a string, replacing invalid UTF-8
+pub(crate) fn serialize_lossy<S: Serializer>(
+    bytes: &[u8],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&bytes.to_str_lossy())
 }
 
 /// Prints a bytes string with all non-ascii characters in escaped format
@@ -52,4 +363,86 @@ mod test {
         let path = Path::new("a");
         assert_eq!(path.to_git_path().unwrap(), "a");
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_git_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"a/b\xff\xfe"));
+        let bytes = path.to_git_bytes();
+        assert_eq!(bytes, &b"a/b\xff\xfe"[..]);
+        assert_eq!(super::to_platform_path(&bytes), path);
+        assert_eq!(super::join_git_path(b"", b"a"), "a");
+        assert_eq!(super::join_git_path(b"a", b"b"), "a/b");
+    }
+
+    #[test]
+    fn test_precompose_unicode() {
+        use super::precompose_unicode;
+        // "é" as e with a combining acute accent
+        let decomposed = "caf\u{0065}\u{0301}/x";
+        assert_eq!(precompose_unicode(decomposed.into()), "caf\u{e9}/x");
+        assert_eq!(precompose_unicode("caf\u{e9}".into()), "caf\u{e9}");
+        assert_eq!(precompose_unicode(b"a\xff"[..].into()), &b"a\xff"[..]);
+    }
+
+    #[test]
+    fn test_quote_c_style() {
+        use super::quote_c_style;
+        assert_eq!(quote_c_style(b"a b/c"), None);
+        assert_eq!(
+            quote_c_style(b"tab\there \"q\" \\"),
+            Some(r#""tab\there \"q\" \\""#.to_string())
+        );
+        assert_eq!(
+            quote_c_style("caf\u{e9}\x01".as_bytes()),
+            Some(r#""caf\303\251\001""#.to_string())
+        );
+    }
+
+    #[test]
+    fn test_unquote_c_style() {
+        use super::{quote_c_style, unquote_c_style};
+        let path = "tab\tq\"\\ caf\u{e9}\x7f".as_bytes();
+        let quoted = quote_c_style(path).unwrap();
+        let text = format!("{} rest", quoted);
+        assert_eq!(
+            unquote_c_style(text.as_bytes()),
+            Some((path.into(), quoted.len()))
+        );
+        assert_eq!(unquote_c_style(b"\"unterminated"), None);
+        assert_eq!(unquote_c_style(b"\"bad \\q\""), None);
+        assert_eq!(unquote_c_style(b"\"short \\30\""), None);
+        assert_eq!(unquote_c_style(b"unquoted"), None);
+    }
+
+    #[test]
+    fn test_stripspace() {
+        use super::stripspace;
+        let message = "\n\nTitle  \n\n\n\nBody\t\n# comment\n\n";
+        assert_eq!(stripspace(message, false), "Title\n\nBody\n# comment\n");
+        assert_eq!(stripspace(message, true), "Title\n\nBody\n");
+        assert_eq!(stripspace("no newline", false), "no newline\n");
+        assert_eq!(stripspace(" \n# only a comment\n", true), "");
+    }
+
+    #[test]
+    fn test_cleanup() {
+        use super::{Cleanup, SCISSORS};
+        let message = format!("Title \n\n# comment\n{}\ndiff\n", SCISSORS);
+        assert_eq!(Cleanup::Strip.apply(&message), "Title\n\ndiff\n");
+        assert_eq!(
+            Cleanup::Whitespace.apply(&message),
+            format!("Title\n\n# comment\n{}\ndiff\n", SCISSORS)
+        );
+        assert_eq!(Cleanup::Verbatim.apply(&message), message);
+        assert_eq!(Cleanup::Scissors.apply(&message), "Title\n\n# comment\n");
+        assert_eq!(Cleanup::Default.resolve(true), Cleanup::Strip);
+        assert_eq!(Cleanup::Default.resolve(false), Cleanup::Whitespace);
+        assert_eq!(Cleanup::Scissors.resolve(false), Cleanup::Whitespace);
+        assert_eq!("verbatim".parse::<Cleanup>(), Ok(Cleanup::Verbatim));
+        assert!("none".parse::<Cleanup>().is_err());
+    }
 }
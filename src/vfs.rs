@@ -0,0 +1,697 @@
+//! A filesystem abstraction so repositories can live somewhere other than the
+//! real disk, for instance in memory for tests
+#[cfg(not(target_arch = "wasm32"))]
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+use std::io::{self, Cursor, Read, Write};
+use std::ops::Deref;
+use std::path::{Component, Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What kind of thing is at a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    /// Ordinary file
+    File,
+    /// Directory
+    Dir,
+    /// Symbolic link
+    Symlink,
+}
+
+/// The parts of file metadata that git cares about
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// What kind of file this is
+    pub file_type: FileType,
+    /// Size in bytes
+    pub len: u64,
+    /// Last modification time
+    pub modified: SystemTime,
+    /// Last time the file's metadata changed (ctime) on Unix. Windows has no
+    /// such thing, so like git for Windows we use the creation time there.
+    pub changed: SystemTime,
+    /// Does the file have its executable bit set? Always false on Windows.
+    pub executable: bool,
+}
+
+/// The whole content of a file, possibly memory-mapped
+pub type FileData = Box<dyn Deref<Target = [u8]> + Send + Sync>;
+
+/// File operations used by the repository code. Paths given to these are
+/// absolute paths in the filesystem being abstracted.
+pub trait Vfs: Send + Sync {
+    /// Opens a file for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Reads the entire content of a file
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Gets the entire content of a file without necessarily copying it, for
+    /// large files that are accessed randomly. The file must not be modified
+    /// while the returned data is alive.
+    fn map(&self, path: &Path) -> io::Result<FileData> {
+        Ok(Box::new(self.read(path)?))
+    }
+
+    /// Creates or replaces a file with the given content. The parent directory
+    /// must exist.
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()>;
+
+    /// Like `write`, but doesn't return until the content is on the disk, so
+    /// that it survives a crash
+    fn write_synced(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        self.write(path, content)
+    }
+
+    /// Creates or replaces a file and opens it for writing, for content too
+    /// large to have in memory all at once. The parent directory must exist.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>>;
+
+    /// Creates a single directory. The parent directory must exist.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates a directory and all of its missing parents
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Lists the names of the entries in a directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Deletes a file
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Moves a file, replacing the destination if it exists
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Creates a symlink at `path` pointing at `target`
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Sets whether the file at `path` is executable. Does nothing on
+    /// platforms without an executable bit.
+    fn set_executable(&self, path: &Path, executable: bool) -> io::Result<()>;
+
+    /// Gets the metadata of a file, following symlinks
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Gets the metadata of a file without following symlinks
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Gets the target of a symlink
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Makes a path absolute with all symlinks resolved
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Does anything exist at this path?
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    /// Is there a directory at this path?
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path)
+            .map(|m| m.file_type == FileType::Dir)
+            .unwrap_or(false)
+    }
+}
+
+/// The real filesystem
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default)]
+pub struct RealFs;
+
+#[cfg(not(target_arch = "wasm32"))]
+impl RealFs {
+    fn convert_metadata(meta: fs::Metadata) -> io::Result<Metadata> {
+        let file_type = meta.file_type();
+        let file_type = if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_dir() {
+            FileType::Dir
+        } else {
+            FileType::File
+        };
+
+        #[cfg(unix)]
+        let executable = {
+            use std::os::unix::fs::PermissionsExt;
+            meta.permissions().mode() & 0o111 != 0
+        };
+        #[cfg(not(unix))]
+        let executable = false;
+
+        let modified = meta.modified()?;
+        #[cfg(unix)]
+        let changed = {
+            use std::os::unix::fs::MetadataExt;
+            let nanos = Duration::from_nanos(meta.ctime_nsec() as u64);
+            if meta.ctime() >= 0 {
+                UNIX_EPOCH + Duration::from_secs(meta.ctime() as u64) + nanos
+            } else {
+                UNIX_EPOCH - Duration::from_secs((-meta.ctime()) as u64) + nanos
+            }
+        };
+        #[cfg(not(unix))]
+        let changed = meta.created().unwrap_or(modified);
+
+        Ok(Metadata {
+            file_type,
+            len: meta.len(),
+            modified,
+            changed,
+            executable,
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Vfs for RealFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(fs::File::open(long_path(path))?))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(long_path(path))
+    }
+
+    fn map(&self, path: &Path) -> io::Result<FileData> {
+        let file = fs::File::open(long_path(path))?;
+        // mapping an empty file fails on some platforms
+        if file.metadata()?.len() == 0 {
+            return Ok(Box::new(Vec::new()));
+        }
+        // git replaces files like the index and packs by renaming new ones
+        // over them rather than modifying them, so the mapping stays valid
+        Ok(Box::new(unsafe { memmap::Mmap::map(&file)? }))
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        fs::write(long_path(path), content)
+    }
+
+    fn write_synced(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let mut file = fs::File::create(long_path(path))?;
+        file.write_all(content)?;
+        file.sync_all()
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>> {
+        Ok(Box::new(io::BufWriter::new(fs::File::create(long_path(
+            path,
+        ))?)))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(long_path(path))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(long_path(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(long_path(path))?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect()
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(long_path(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(long_path(from), long_path(to))
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        make_symlink(target, &long_path(path))
+    }
+
+    fn set_executable(&self, path: &Path, executable: bool) -> io::Result<()> {
+        change_executable(&long_path(path), executable)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        RealFs::convert_metadata(fs::metadata(long_path(path))?)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        RealFs::convert_metadata(fs::symlink_metadata(long_path(path))?)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(long_path(path))
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+/// Windows limits paths to 260 characters unless they are given in the
+/// extended `\\?\` form, which turns off all other path processing. Long
+/// absolute paths are converted to that form, cleaning them up first since
+/// Windows won't do it any more.
+#[cfg(windows)]
+fn long_path(path: &Path) -> Cow<'_, Path> {
+    // directories need room left for an 8.3 file name in them
+    const MAX_DIR_PATH: usize = 260 - 12;
+    let s = match path.to_str() {
+        Some(s) if s.len() >= MAX_DIR_PATH && path.is_absolute() => s,
+        _ => return Cow::Borrowed(path),
+    };
+    if s.starts_with(r"\\?\") {
+        return Cow::Borrowed(path);
+    }
+    let cleaned = normalize(Path::new(&s.replace('/', "\\")));
+    let cleaned = cleaned.to_string_lossy();
+    Cow::Owned(PathBuf::from(match cleaned.strip_prefix(r"\\") {
+        // network shares are \\server\share\...
+        Some(share) => format!(r"\\?\UNC\{}", share),
+        None => format!(r"\\?\{}", cleaned),
+    }))
+}
+
+/// Elsewhere, paths can be as long as they like
+#[cfg(all(not(windows), not(target_arch = "wasm32")))]
+fn long_path(path: &Path) -> Cow<'_, Path> {
+    Cow::Borrowed(path)
+}
+
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+/// Without symlinks, links are made as plain files holding their target, like
+/// git does with core.symlinks=false
+#[cfg(all(not(unix), not(target_arch = "wasm32")))]
+fn make_symlink(target: &Path, path: &Path) -> io::Result<()> {
+    fs::write(path, target.to_string_lossy().as_bytes())
+}
+
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+fn change_executable(path: &Path, executable: bool) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut perms = fs::metadata(path)?.permissions();
+    let mode = perms.mode();
+    // like git, anyone who can read the file gets to execute it
+    perms.set_mode(if executable {
+        mode | (mode & 0o444) >> 2
+    } else {
+        mode & !0o111
+    });
+    fs::set_permissions(path, perms)
+}
+
+#[cfg(all(not(unix), not(target_arch = "wasm32")))]
+fn change_executable(_path: &Path, _executable: bool) -> io::Result<()> {
+    Ok(())
+}
+
+/// A node in the in-memory filesystem
+#[derive(Debug, Clone)]
+enum Node {
+    File {
+        content: Vec<u8>,
+        executable: bool,
+        modified: SystemTime,
+    },
+    /// modified whenever an entry is added or removed, like on real
+    /// filesystems
+    Dir(SystemTime),
+    Symlink(PathBuf),
+}
+
+/// A file being written in a MemoryFs. Content goes into the filesystem as it
+/// is written.
+struct MemoryFile<'a> {
+    fs: &'a MemoryFs,
+    path: PathBuf,
+}
+
+impl Write for MemoryFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut nodes = self.fs.nodes.write().unwrap();
+        match nodes.get_mut(&self.path) {
+            Some(Node::File { content, .. }) => {
+                content.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            _ => Err(not_found(&self.path)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A filesystem entirely in memory. Timestamps come from a counter that ticks
+/// one second on each write, so they are deterministic.
+#[derive(Debug)]
+pub struct MemoryFs {
+    nodes: RwLock<BTreeMap<PathBuf, Node>>,
+    clock: RwLock<u64>,
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+/// Removes `.` and `..` components from an absolute path without touching the
+/// filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+impl MemoryFs {
+    /// Makes a new, empty filesystem containing only the root directory
+    pub fn new() -> MemoryFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(PathBuf::from("/"), Node::Dir(UNIX_EPOCH));
+        MemoryFs {
+            nodes: RwLock::new(nodes),
+            clock: RwLock::new(0),
+        }
+    }
+
+    fn tick(&self) -> SystemTime {
+        let mut clock = self.clock.write().unwrap();
+        *clock += 1;
+        UNIX_EPOCH + Duration::from_secs(*clock)
+    }
+
+    /// Adds a node, checking that its parent is a directory
+    fn insert(&self, path: &Path, node: Node) -> io::Result<()> {
+        let path = normalize(path);
+        let parent = path.parent().ok_or_else(|| not_found(&path))?;
+        if !self.is_dir(parent) {
+            return Err(not_found(parent));
+        }
+        let is_new = self
+            .nodes
+            .write()
+            .unwrap()
+            .insert(path.clone(), node)
+            .is_none();
+        if is_new {
+            self.touch_parent(&path);
+        }
+        Ok(())
+    }
+
+    /// Updates the modification time of the directory containing `path`
+    fn touch_parent(&self, path: &Path) {
+        let parent = match path.parent() {
+            Some(parent) => parent,
+            None => return,
+        };
+        let now = self.tick();
+        if let Some(Node::Dir(modified)) = self.nodes.write().unwrap().get_mut(parent) {
+            *modified = now;
+        }
+    }
+
+    /// Follows symlinks until reaching something that isn't one
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        const MAX_DEPTH: usize = 40;
+
+        let mut path = normalize(path);
+        for _ in 0..MAX_DEPTH {
+            let nodes = self.nodes.read().unwrap();
+            match nodes.get(&path) {
+                Some(Node::Symlink(target)) => {
+                    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+                    path = normalize(&parent.join(target));
+                }
+                Some(_) => return Ok(path),
+                None => return Err(not_found(&path)),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("too many levels of symlinks at {}", path.display()),
+        ))
+    }
+
+    fn node_metadata(node: &Node) -> Metadata {
+        match node {
+            Node::File {
+                content,
+                executable,
+                modified,
+            } => Metadata {
+                file_type: FileType::File,
+                len: content.len() as u64,
+                modified: *modified,
+                changed: *modified,
+                executable: *executable,
+            },
+            Node::Dir(modified) => Metadata {
+                file_type: FileType::Dir,
+                len: 0,
+                modified: *modified,
+                changed: *modified,
+                executable: false,
+            },
+            Node::Symlink(target) => Metadata {
+                file_type: FileType::Symlink,
+                len: target.as_os_str().len() as u64,
+                modified: UNIX_EPOCH,
+                changed: UNIX_EPOCH,
+                executable: false,
+            },
+        }
+    }
+}
+
+impl Default for MemoryFs {
+    fn default() -> MemoryFs {
+        MemoryFs::new()
+    }
+}
+
+impl Vfs for MemoryFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(Cursor::new(self.read(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let path = self.resolve(path)?;
+        match self.nodes.read().unwrap().get(&path) {
+            Some(Node::File { content, .. }) => Ok(content.clone()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is not a file", path.display()),
+            )),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn write(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        // writing through a symlink writes its target
+        let path = self.resolve(path).unwrap_or_else(|_| normalize(path));
+        let executable = match self.nodes.read().unwrap().get(&path) {
+            Some(Node::File { executable, .. }) => *executable,
+            Some(Node::Dir(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} is a directory", path.display()),
+                ))
+            }
+            _ => false,
+        };
+        let modified = self.tick();
+        self.insert(
+            &path,
+            Node::File {
+                content: content.to_vec(),
+                executable,
+                modified,
+            },
+        )
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>> {
+        self.write(path, b"")?;
+        Ok(Box::new(MemoryFile {
+            fs: self,
+            path: self.resolve(path)?,
+        }))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        if self.symlink_metadata(path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            ));
+        }
+        let modified = self.tick();
+        self.insert(path, Node::Dir(modified))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut so_far = PathBuf::new();
+        for component in path.components() {
+            so_far.push(component);
+            if !self.is_dir(&so_far) {
+                self.create_dir(&so_far)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let path = self.resolve(path)?;
+        let nodes = self.nodes.read().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Dir(_)) => (),
+            _ => return Err(not_found(&path)),
+        }
+        Ok(nodes
+            .range(path.clone()..)
+            .skip(1)
+            .take_while(|(p, _)| p.starts_with(&path))
+            .filter(|(p, _)| p.parent() == Some(path.as_path()))
+            .filter_map(|(p, _)| p.file_name().map(|n| n.to_owned()))
+            .collect())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut nodes = self.nodes.write().unwrap();
+        match nodes.get(&path) {
+            Some(Node::Dir(_)) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is a directory", path.display()),
+            )),
+            Some(_) => {
+                nodes.remove(&path);
+                drop(nodes);
+                self.touch_parent(&path);
+                Ok(())
+            }
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = normalize(from);
+        let node = self
+            .nodes
+            .write()
+            .unwrap()
+            .remove(&from)
+            .ok_or_else(|| not_found(&from))?;
+        self.touch_parent(&from);
+        self.insert(to, node)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        self.insert(path, Node::Symlink(target.to_owned()))
+    }
+
+    fn set_executable(&self, path: &Path, executable: bool) -> io::Result<()> {
+        let path = self.resolve(path)?;
+        let mut nodes = self.nodes.write().unwrap();
+        match nodes.get_mut(&path) {
+            Some(Node::File { executable: e, .. }) => {
+                *e = executable;
+                Ok(())
+            }
+            _ => Err(not_found(&path)),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = self.resolve(path)?;
+        self.symlink_metadata(&path)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = normalize(path);
+        self.nodes
+            .read()
+            .unwrap()
+            .get(&path)
+            .map(MemoryFs::node_metadata)
+            .ok_or_else(|| not_found(&path))
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        match self.nodes.read().unwrap().get(&path) {
+            Some(Node::Symlink(target)) => Ok(target.clone()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is not a symlink", path.display()),
+            )),
+            None => Err(not_found(&path)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        self.resolve(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileType, MemoryFs, Vfs};
+    use std::path::Path;
+
+    #[test]
+    fn test_memory_fs() {
+        let fs = MemoryFs::new();
+        let file = Path::new("/a/b/file");
+
+        // parents must exist
+        assert!(fs.write(file, b"hello").is_err());
+        fs.create_dir_all(Path::new("/a/b")).unwrap();
+        fs.write(file, b"hello").unwrap();
+        assert_eq!(fs.read(file).unwrap(), b"hello");
+        assert_eq!(fs.metadata(file).unwrap().len, 5);
+        assert!(fs.is_dir(Path::new("/a")));
+
+        fs.symlink(Path::new("b/file"), Path::new("/a/link"))
+            .unwrap();
+        assert_eq!(fs.read(Path::new("/a/link")).unwrap(), b"hello");
+        assert_eq!(
+            fs.symlink_metadata(Path::new("/a/link")).unwrap().file_type,
+            FileType::Symlink
+        );
+
+        let mut names = fs.read_dir(Path::new("/a")).unwrap();
+        names.sort();
+        assert_eq!(names, vec!["b", "link"]);
+
+        fs.rename(file, Path::new("/a/moved")).unwrap();
+        assert!(!fs.exists(file));
+        fs.remove_file(Path::new("/a/moved")).unwrap();
+        assert!(!fs.exists(Path::new("/a/link")));
+    }
+}